@@ -0,0 +1,105 @@
+/*
+ * The C ABI for the payments engine (see src/ffi.rs), built into the library
+ * with the `ffi` feature, e.g.
+ *
+ *     cargo rustc --lib --release --features ffi --crate-type staticlib
+ *
+ * An engine is created by challenge_engine_new and belongs to the caller until
+ * it's handed to challenge_engine_free. Every other function only borrows it
+ * for the call. Pointers passed in must be null or valid for the call, and
+ * strings are NUL-terminated UTF-8. Nothing that comes out needs freeing.
+ *
+ * Every function returns a challenge_status. Anything other than CHALLENGE_OK
+ * comes with a message from challenge_engine_last_error, which lasts until the
+ * next call with the engine. An engine can move between threads, but only one
+ * can use it at a time.
+ */
+
+#ifndef CHALLENGE_H
+#define CHALLENGE_H
+
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* Room for any amount as a string, with its NUL. */
+#define CHALLENGE_AMOUNT_LEN 32
+
+typedef enum challenge_status {
+    CHALLENGE_OK = 0,
+    /* The event was processed, and rejected. */
+    CHALLENGE_REJECTED = 1,
+    /* A null pointer, a string that isn't UTF-8, an unknown format or the like. */
+    CHALLENGE_INVALID_ARGUMENT = 2,
+    /* The event didn't parse, so it wasn't processed. */
+    CHALLENGE_PARSE_ERROR = 3,
+    /* There's no such client. */
+    CHALLENGE_NOT_FOUND = 4,
+    /* The report didn't fit, and how big it is has been written instead. */
+    CHALLENGE_BUFFER_TOO_SMALL = 5,
+    /* The engine's been finished, so it won't take any more events. */
+    CHALLENGE_FINISHED = 6,
+    /* Anything else. The engine may not be usable after this. */
+    CHALLENGE_FAILED = 7,
+    /* Something panicked, and the engine can only be freed. */
+    CHALLENGE_PANICKED = 8,
+} challenge_status;
+
+/* A client's balance in a currency, as strings at the report's scale. */
+typedef struct challenge_balance {
+    char available[CHALLENGE_AMOUNT_LEN];
+    char held[CHALLENGE_AMOUNT_LEN];
+    char total[CHALLENGE_AMOUNT_LEN];
+    bool locked;
+} challenge_balance;
+
+typedef struct ChallengeEngine challenge_engine;
+
+/*
+ * Creates an engine that reports in the given format (one of the names
+ * --output-format takes), or CSV if it's null.
+ */
+challenge_status challenge_engine_new(const char *report_format, challenge_engine **engine_out);
+
+void challenge_engine_free(challenge_engine *engine);
+
+/*
+ * Processes an event given as a line of JSON, like the ones POST /events
+ * takes, e.g. {"type":"deposit","client":1,"tx":1,"amount":"2.5"}.
+ */
+challenge_status challenge_engine_submit(challenge_engine *engine, const char *event);
+
+/*
+ * Writes where a client stands in a currency (the unnamed one if it's null or
+ * empty) into balance_out.
+ */
+challenge_status challenge_engine_balance(challenge_engine *engine, uint16_t client_id,
+                                          const char *currency,
+                                          challenge_balance *balance_out);
+
+/*
+ * Processes whatever's still queued. The engine won't take any more events
+ * after this, but its balances and report can still be had.
+ */
+challenge_status challenge_engine_finish(challenge_engine *engine);
+
+/*
+ * Writes the report into buffer, which isn't NUL-terminated, and its size into
+ * written. If it doesn't fit, written says how big it is and nothing's written
+ * to buffer, so a null buffer with no capacity asks how big a buffer it needs.
+ */
+challenge_status challenge_engine_report(challenge_engine *engine, uint8_t *buffer,
+                                         size_t capacity, size_t *written);
+
+/* Why the last call with the engine failed, or null if it didn't. */
+const char *challenge_engine_last_error(const challenge_engine *engine);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
@@ -0,0 +1,837 @@
+use crate::{
+    format::{self, ErrorFormat, InputFormat, OutputFormat, ReportConfig},
+    model::{Amount, Client, ClientID, SourcedEvent},
+    system::{
+        finish_processing, process_sourced_event, ChargebackLimitAction, ClosedAccountPolicy,
+        DeltaTracker, DuplicateTransactionPolicy, EngineConfig, EventCounts, FeeSchedule,
+        FinalState, Journal, LockedAccountPolicy, LockedDepositPolicy, MemoryStore,
+        ProcessEventsError, Processor, ProcessorObserver, RejectionLogger, ReorderBuffer,
+        ReplayPoint, ResourceLimits, SnapshotInterval, SnapshotTimer, StateDelta, StateStore,
+        UndisputedChargebackPolicy, WithdrawalDisputePolicy,
+    },
+};
+
+#[cfg(feature = "tokio")]
+use std::pin::pin;
+use std::{
+    collections::{BTreeMap, HashMap},
+    error::Error,
+    io::{self, Read, Write},
+    mem,
+    time::Duration,
+};
+#[cfg(feature = "tokio")]
+use tokio::io::{AsyncRead, AsyncWrite};
+#[cfg(feature = "tokio")]
+use tokio_stream::{Stream, StreamExt};
+
+type TakeSnapshot<'a> =
+    Box<dyn FnMut(&HashMap<ClientID, Client>, &EventCounts) -> Result<(), Box<dyn Error>> + 'a>;
+
+// Everything needed to process a run of events and report on it, set up once
+// with `Engine::builder()` rather than threaded through as loose arguments.
+// Events can be fed in one at a time (`process_event`), from an iterator
+// (`process_events`), or straight from a CSV file (`process_csv`), and the
+// clients can be looked at or reported on at any point along the way.
+pub struct Engine<'a> {
+    processor: Processor,
+    config: EngineConfig,
+    report_config: ReportConfig,
+    event_counts: EventCounts,
+    rejection_logger: Box<dyn RejectionLogger + 'a>,
+    // Whether events read from CSV keep their raw record, which is only worth
+    // the allocation if the rejection logger is going to write it.
+    keep_records: bool,
+    observer: Box<dyn ProcessorObserver + 'a>,
+    snapshots: Option<(SnapshotTimer, TakeSnapshot<'a>)>,
+}
+
+// Builds an `Engine`. Anything that isn't set is left at its default, which
+// processes events the way the spec describes, discards rejections and reports
+// as CSV.
+pub struct EngineBuilder<'a> {
+    config: EngineConfig,
+    report_config: ReportConfig,
+    rejection_logger: Box<dyn RejectionLogger + 'a>,
+    keep_records: bool,
+    observer: Box<dyn ProcessorObserver + 'a>,
+    snapshots: Option<(SnapshotInterval, TakeSnapshot<'a>)>,
+    store: Box<dyn StateStore>,
+    journal: Option<Journal>,
+}
+
+impl<'a> Engine<'a> {
+    pub fn builder() -> EngineBuilder<'a> {
+        EngineBuilder {
+            config: EngineConfig::default(),
+            report_config: ReportConfig::default(),
+            rejection_logger: Box::new(io::sink()),
+            keep_records: false,
+            observer: Box::new(()),
+            snapshots: None,
+            store: Box::new(MemoryStore::default()),
+            journal: None,
+        }
+    }
+
+    // Processes a single event, along with anything it unlocks. Rejections
+    // are logged rather than returned, so the only errors are from logging or
+    // from going over `EngineConfig::limits` (a `LimitExceeded`).
+    pub fn process_event(&mut self, event: impl Into<SourcedEvent>) -> io::Result<()> {
+        process_sourced_event(
+            &mut self.processor,
+            &mut self.event_counts,
+            self.rejection_logger.as_mut(),
+            self.observer.as_mut(),
+            event.into(),
+        )
+    }
+
+    // Like `process_event`, but also returns how each client it changed went
+    // from before to after (see `Processor::process_event_with_deltas`),
+    // including through anything it unlocked, which is only ever queued for
+    // the client it unlocked. A rejected event comes back with nothing.
+    pub fn process_event_with_deltas(
+        &mut self,
+        event: impl Into<SourcedEvent>,
+    ) -> io::Result<Vec<StateDelta>> {
+        let event = event.into();
+        let mut tracker = DeltaTracker::default();
+        let client_ids = self.processor.touched_client_ids(&event.event);
+        tracker.capture(self.processor.clients_by_id(), &client_ids);
+
+        self.process_event(event)?;
+        Ok(tracker.finish(self.processor.clients_by_id()))
+    }
+
+    // Processes every event from the iterator, putting them back in order
+    // first if the config allows for that, and taking snapshots along the way
+    // if asked to. Stops at the first error from the iterator, which can be
+    // anything that converts to a `ProcessEventsError` (see
+    // `system::process_events`).
+    pub fn process_events<E: Into<SourcedEvent>, Err: Into<ProcessEventsError>>(
+        &mut self,
+        events_iter: impl Iterator<Item = Result<E, Err>>,
+    ) -> Result<(), ProcessEventsError> {
+        let events_iter = ReorderBuffer::new(
+            events_iter.map(|event| event.map(Into::into).map_err(Into::into)),
+            self.config.reorder_window,
+        );
+        for event in events_iter {
+            self.process_event_with_snapshots(event?)?;
+        }
+
+        Ok(())
+    }
+
+    // Like `process_events`, but hands what each event changed (see
+    // `process_event_with_deltas`) to `on_deltas` as it goes, stopping at the
+    // first error from that too.
+    pub fn process_events_with_deltas<E: Into<SourcedEvent>, Err: Into<ProcessEventsError>>(
+        &mut self,
+        events_iter: impl Iterator<Item = Result<E, Err>>,
+        mut on_deltas: impl FnMut(Vec<StateDelta>) -> Result<(), Box<dyn Error>>,
+    ) -> Result<(), ProcessEventsError> {
+        let events_iter = ReorderBuffer::new(
+            events_iter.map(|event| event.map(Into::into).map_err(Into::into)),
+            self.config.reorder_window,
+        );
+        for event in events_iter {
+            let deltas = self.process_event_with_deltas(event?)?;
+            self.take_snapshot_if_due()?;
+            on_deltas(deltas)?;
+        }
+
+        Ok(())
+    }
+
+    // Like `process_events`, for events that arrive asynchronously, e.g. over
+    // the network. Processing itself never waits on anything, so the only
+    // time this yields is while waiting for the next event.
+    #[cfg(feature = "tokio")]
+    pub async fn process_event_stream<E: Into<SourcedEvent>, Err: Into<ProcessEventsError>>(
+        &mut self,
+        events: impl Stream<Item = Result<E, Err>>,
+    ) -> Result<(), ProcessEventsError> {
+        let mut reorder_buffer = ReorderBuffer::new((), self.config.reorder_window);
+        let mut events = pin!(events);
+        while let Some(event) = events.next().await {
+            reorder_buffer.push(event.map_err(Into::into)?.into());
+            if reorder_buffer.is_full() {
+                if let Some(event) = reorder_buffer.pop() {
+                    self.process_event_with_snapshots(event)?;
+                }
+            }
+        }
+        while let Some(event) = reorder_buffer.pop() {
+            self.process_event_with_snapshots(event)?;
+        }
+
+        Ok(())
+    }
+
+    // Like `process_input`, for input that's read asynchronously (see
+    // `format::async_io::parse_events`), so that an async service doesn't need
+    // to hand the whole run to a blocking thread.
+    #[cfg(feature = "tokio")]
+    pub async fn process_events_async(
+        &mut self,
+        format: InputFormat,
+        input: impl AsyncRead,
+    ) -> Result<(), ProcessEventsError> {
+        let events = format::async_io::parse_events(format, input, self.keep_records);
+        self.process_event_stream(events).await
+    }
+
+    fn process_event_with_snapshots(
+        &mut self,
+        event: SourcedEvent,
+    ) -> Result<(), ProcessEventsError> {
+        self.process_event(event)?;
+        self.take_snapshot_if_due()
+    }
+
+    fn take_snapshot_if_due(&mut self) -> Result<(), ProcessEventsError> {
+        if let Some((snapshot_timer, take_snapshot)) = self.snapshots.as_mut() {
+            if snapshot_timer.tick() {
+                take_snapshot(self.processor.clients_by_id(), &self.event_counts)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // Gets back to the state a journal was written in, before processing
+    // anything new (see `Processor::replay_journal`). Replayed events aren't
+    // counted, logged or reported to the observer, since they were the first
+    // time round.
+    pub fn replay_journal(&mut self, journal_reader: impl Read) -> io::Result<u64> {
+        self.processor.replay_journal(journal_reader)
+    }
+
+    // Like `replay_journal`, but only up to the given point, to see how the
+    // clients stood back then (see `Processor::replay_journal_until`).
+    pub fn replay_journal_until(
+        &mut self,
+        journal_reader: impl Read,
+        until: ReplayPoint,
+    ) -> io::Result<u64> {
+        self.processor.replay_journal_until(journal_reader, until)
+    }
+
+    pub fn process_csv(&mut self, input: impl Read) -> Result<(), ProcessEventsError> {
+        self.process_input(InputFormat::Csv, input)
+    }
+
+    // Like `process_csv`, for input in any of the formats we can read.
+    pub fn process_input(
+        &mut self,
+        format: InputFormat,
+        input: impl Read,
+    ) -> Result<(), ProcessEventsError> {
+        self.process_events(format::parse_events(format, input, self.keep_records))
+    }
+
+    // Makes sure every event accepted so far is in the journal, if there is
+    // one, rather than waiting for the end of the run. A long-running caller
+    // can do this whenever it's about to say the events were accepted.
+    pub fn flush_journal(&mut self) -> io::Result<()> {
+        self.processor.flush_journal()
+    }
+
+    pub fn processor(&self) -> &Processor {
+        &self.processor
+    }
+
+    pub fn event_counts(&self) -> &EventCounts {
+        &self.event_counts
+    }
+
+    // Writes the clients as they currently stand, in the configured format.
+    pub fn write_report(&self, output: &mut impl Write) -> Result<(), Box<dyn Error>> {
+        format::write_report(
+            self.processor.clients_by_id(),
+            &self.event_counts,
+            output,
+            &self.report_config,
+        )
+    }
+
+    // Like `write_report`, for writing to something async.
+    #[cfg(feature = "tokio")]
+    pub async fn write_report_async(&self, output: impl AsyncWrite) -> Result<(), Box<dyn Error>> {
+        format::async_io::write_report(
+            self.processor.clients_by_id(),
+            &self.event_counts,
+            output,
+            &self.report_config,
+        )
+        .await
+    }
+
+    // Writes a snapshot of the state as it stands (see `Processor::snapshot`),
+    // for `EngineBuilder::build_resumed` to carry on from, without finishing.
+    // Anything queued behind a lock is saved as queued rather than rejected,
+    // so it's still waiting once the state's resumed.
+    pub fn save_state(&mut self, state_writer: impl Write) -> io::Result<()> {
+        self.processor.snapshot(state_writer)
+    }
+
+    // Expected to be called once there are no more events, hence taking
+    // ownership of `self`.
+    pub fn finish(mut self) -> Result<FinalState, Box<dyn Error>> {
+        self.finish_processing()?;
+        Ok(self.processor.into_final_state(self.event_counts)?)
+    }
+
+    // Like `finish`, but also writes a snapshot of the state (see
+    // `Processor::snapshot`) for the next run to pick up from with
+    // `EngineBuilder::build_resumed`. It's taken after anything still queued
+    // has been rejected, so the next run starts from what this one reported.
+    pub fn finish_saving_state(
+        mut self,
+        state_writer: impl Write,
+    ) -> Result<FinalState, Box<dyn Error>> {
+        self.finish_processing()?;
+        self.processor.snapshot(state_writer)?;
+        Ok(self.processor.into_final_state(self.event_counts)?)
+    }
+
+    fn finish_processing(&mut self) -> io::Result<()> {
+        finish_processing(
+            &mut self.processor,
+            &mut self.event_counts,
+            self.rejection_logger.as_mut(),
+            self.observer.as_mut(),
+        )
+    }
+}
+
+// Several engines side by side, each keyed by a tenant (a business unit, say,
+// or a dataset) and each with its own clients, transactions and policies, so
+// that one process can keep several ledgers without them ever touching. An
+// event only ever goes to the tenant it's given for, so the same client or
+// transaction ID can mean something different to each of them.
+#[derive(Default)]
+pub struct Tenants<'a> {
+    engines: BTreeMap<String, Engine<'a>>,
+}
+
+impl<'a> Tenants<'a> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Adds a tenant, replacing (and returning) any engine it already had.
+    pub fn insert(&mut self, tenant: impl Into<String>, engine: Engine<'a>) -> Option<Engine<'a>> {
+        self.engines.insert(tenant.into(), engine)
+    }
+
+    pub fn get(&self, tenant: &str) -> Option<&Engine<'a>> {
+        self.engines.get(tenant)
+    }
+
+    pub fn get_mut(&mut self, tenant: &str) -> Option<&mut Engine<'a>> {
+        self.engines.get_mut(tenant)
+    }
+
+    // The tenants in order, with their engines.
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (&str, &mut Engine<'a>)> {
+        self.engines
+            .iter_mut()
+            .map(|(tenant, engine)| (tenant.as_str(), engine))
+    }
+
+    pub fn tenants(&self) -> impl Iterator<Item = &str> {
+        self.engines.keys().map(String::as_str)
+    }
+
+    // Processes an event for a tenant (see `Engine::process_event`). A tenant
+    // that isn't there is a `NotFound`.
+    pub fn process_event(
+        &mut self,
+        tenant: &str,
+        event: impl Into<SourcedEvent>,
+    ) -> io::Result<()> {
+        self.get_mut(tenant)
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("There's no tenant {}.", tenant),
+                )
+            })?
+            .process_event(event)
+    }
+
+    // Finishes every tenant's engine (see `Engine::finish`).
+    pub fn finish(self) -> Result<BTreeMap<String, FinalState>, Box<dyn Error>> {
+        self.engines
+            .into_iter()
+            .map(|(tenant, engine)| Ok((tenant, engine.finish()?)))
+            .collect()
+    }
+}
+
+impl<'a> EngineBuilder<'a> {
+    // Replaces every processing policy at once, e.g. with one parsed from the
+    // command line.
+    pub fn config(mut self, config: EngineConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    pub fn fee_schedule(mut self, fee_schedule: FeeSchedule) -> Self {
+        self.config.fee_schedule = fee_schedule;
+        self
+    }
+
+    pub fn withdrawal_disputes(mut self, policy: WithdrawalDisputePolicy) -> Self {
+        self.config.withdrawal_disputes = policy;
+        self
+    }
+
+    pub fn allow_redispute(mut self, allow_redispute: bool) -> Self {
+        self.config.allow_redispute = allow_redispute;
+        self
+    }
+
+    pub fn unlock_on_chargeback_reversal(mut self, unlock: bool) -> Self {
+        self.config.unlock_on_chargeback_reversal = unlock;
+        self
+    }
+
+    pub fn locked_account_disputes(mut self, policy: LockedAccountPolicy) -> Self {
+        self.config.locked_account_disputes = policy;
+        self
+    }
+
+    pub fn locked_account_deposits(mut self, policy: LockedDepositPolicy) -> Self {
+        self.config.locked_account_deposits = policy;
+        self
+    }
+
+    pub fn undisputed_chargebacks(mut self, policy: UndisputedChargebackPolicy) -> Self {
+        self.config.undisputed_chargebacks = policy;
+        self
+    }
+
+    pub fn credit_limits(mut self, credit_limits: HashMap<ClientID, Amount>) -> Self {
+        self.config.credit_limits = credit_limits;
+        self
+    }
+
+    pub fn closed_accounts(mut self, policy: ClosedAccountPolicy) -> Self {
+        self.config.closed_accounts = policy;
+        self
+    }
+
+    pub fn duplicate_transactions(mut self, policy: DuplicateTransactionPolicy) -> Self {
+        self.config.duplicate_transactions = policy;
+        self
+    }
+
+    pub fn check_invariants(mut self, check_invariants: bool) -> Self {
+        self.config.check_invariants = check_invariants;
+        self
+    }
+
+    pub fn chargeback_limit(mut self, limit: u32, action: ChargebackLimitAction) -> Self {
+        self.config.chargeback_limit = Some(limit);
+        self.config.chargeback_limit_action = action;
+        self
+    }
+
+    pub fn limits(mut self, limits: ResourceLimits) -> Self {
+        self.config.limits = limits;
+        self
+    }
+
+    pub fn dispute_window(mut self, dispute_window: Duration) -> Self {
+        self.config.dispute_window = Some(dispute_window);
+        self
+    }
+
+    pub fn dispute_expiry(mut self, dispute_expiry: Duration) -> Self {
+        self.config.dispute_expiry = Some(dispute_expiry);
+        self
+    }
+
+    pub fn reorder_window(mut self, reorder_window: usize) -> Self {
+        self.config.reorder_window = reorder_window;
+        self
+    }
+
+    pub fn disputable_only(mut self, disputable_only: bool) -> Self {
+        self.config.disputable_only = disputable_only;
+        self
+    }
+
+    pub fn report_config(mut self, report_config: ReportConfig) -> Self {
+        self.report_config = report_config;
+        self
+    }
+
+    pub fn output_format(mut self, format: OutputFormat) -> Self {
+        self.report_config.format = format;
+        self
+    }
+
+    // How many decimal places amounts are reported with.
+    pub fn scale(mut self, scale: u32) -> Self {
+        self.report_config.scale = scale;
+        self
+    }
+
+    // Logs rejected events to the writer in the given format. Without this
+    // they're discarded.
+    pub fn errors(mut self, writer: impl Write + 'a, format: ErrorFormat) -> Self {
+        self.rejection_logger = format::rejection_logger(format, writer);
+        self.keep_records = format == ErrorFormat::Json;
+        self
+    }
+
+    // For anything `errors` doesn't cover. Raw records aren't kept for a
+    // custom logger.
+    pub fn rejection_logger(mut self, logger: impl RejectionLogger + 'a) -> Self {
+        self.rejection_logger = Box::new(logger);
+        self.keep_records = false;
+        self
+    }
+
+    pub fn observer(mut self, observer: impl ProcessorObserver + 'a) -> Self {
+        self.observer = Box::new(observer);
+        self
+    }
+
+    // Hands the clients as they currently stand to `take_snapshot` every so
+    // often while events are processed from an iterator.
+    pub fn snapshots(
+        mut self,
+        interval: SnapshotInterval,
+        take_snapshot: impl FnMut(&HashMap<ClientID, Client>, &EventCounts) -> Result<(), Box<dyn Error>>
+            + 'a,
+    ) -> Self {
+        self.snapshots = Some((interval, Box::new(take_snapshot)));
+        self
+    }
+
+    // Where the clients and transactions are kept. Without this they're kept
+    // in memory.
+    pub fn state_store(mut self, store: impl StateStore + 'static) -> Self {
+        self.store = Box::new(store);
+        self
+    }
+
+    // Where to write every accepted event, so that the state can be rebuilt
+    // from it with `Engine::replay_journal`.
+    pub fn journal(mut self, journal: Journal) -> Self {
+        self.journal = Some(journal);
+        self
+    }
+
+    pub fn build(mut self) -> Engine<'a> {
+        let store = mem::replace(&mut self.store, Box::new(MemoryStore::default()));
+        let processor = Processor::with_store(&self.config, store);
+        self.build_with(processor)
+    }
+
+    // Builds an engine that carries on from a snapshot written by a previous
+    // run (see `Engine::finish_saving_state`), loading its transactions into
+    // the configured store. The config isn't part of the snapshot, so it
+    // should be the same one the previous run used. Event counts start again
+    // from zero.
+    pub fn build_resumed(mut self, state_reader: impl Read) -> io::Result<Engine<'a>> {
+        let store = mem::replace(&mut self.store, Box::new(MemoryStore::default()));
+        let processor = Processor::restore_with_store(&self.config, store, state_reader)?;
+        Ok(self.build_with(processor))
+    }
+
+    fn build_with(self, mut processor: Processor) -> Engine<'a> {
+        if let Some(journal) = self.journal {
+            processor.set_journal(journal);
+        }
+        Engine {
+            processor,
+            config: self.config,
+            report_config: self.report_config,
+            event_counts: EventCounts::default(),
+            rejection_logger: self.rejection_logger,
+            keep_records: self.keep_records,
+            observer: self.observer,
+            snapshots: self
+                .snapshots
+                .map(|(interval, take_snapshot)| (SnapshotTimer::new(interval), take_snapshot)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::model::{Currency, DisputeStepKind, Event, TransactionKind};
+    use crate::system::AccountState;
+    use pretty_assertions::assert_eq;
+    use rust_decimal_macros::dec;
+
+    #[test]
+    fn test_engine() {
+        let mut errors = Vec::new();
+        let mut engine = Engine::builder()
+            .withdrawal_disputes(WithdrawalDisputePolicy::Reject)
+            .scale(2)
+            .errors(&mut errors, ErrorFormat::Text)
+            .build();
+
+        let input = concat!(
+            "type,client,tx,amount\n",
+            "deposit,1,1,10\n",
+            "withdrawal,1,2,4\n",
+        );
+        engine
+            .process_csv(input.as_bytes())
+            .expect("Expected no errors.");
+        engine
+            .process_event(Event::DisputeStep {
+                kind: DisputeStepKind::Dispute,
+                client_id: 1,
+                transaction_id: 2,
+                amount: None,
+            })
+            .expect("Expected no errors.");
+
+        let mut report = Vec::new();
+        engine
+            .write_report(&mut report)
+            .expect("Expected no errors.");
+        assert_eq!(
+            concat!(
+                "client,available,held,total,locked\n",
+                "1,6.00,0.00,6.00,false\n",
+            ),
+            String::from_utf8(report).expect("Not UTF-8")
+        );
+
+        engine
+            .process_event(Event::Transaction {
+                kind: TransactionKind::Deposit,
+                client_id: 2,
+                transaction_id: 3,
+                currency: Currency::default(),
+                amount: dec!(1),
+                counterparty: None,
+            })
+            .expect("Expected no errors.");
+        assert_eq!(2, engine.processor().clients().count());
+
+        let final_state = engine.finish().expect("Expected no errors.");
+        assert_eq!(4, final_state.event_counts.processed);
+        assert_eq!(1, final_state.event_counts.rejected);
+        assert_eq!(
+            "Only deposits can be disputed.\n",
+            String::from_utf8(errors).expect("Not UTF-8")
+        );
+    }
+
+    #[test]
+    fn test_process_event_with_deltas() {
+        let mut engine = Engine::builder().build();
+        let deposit = |client_id, transaction_id| Event::Transaction {
+            kind: TransactionKind::Deposit,
+            client_id,
+            transaction_id,
+            currency: Currency::default(),
+            amount: dec!(10),
+            counterparty: None,
+        };
+        engine
+            .process_event(deposit(1, 1))
+            .expect("Expected no errors.");
+
+        let deltas = engine
+            .process_event_with_deltas(Event::Transfer {
+                transaction_id: 2,
+                from_client_id: 1,
+                to_client_id: 2,
+                currency: Currency::default(),
+                amount: dec!(4),
+            })
+            .expect("Expected no errors.");
+        assert_eq!(
+            vec![(1, dec!(10), dec!(6)), (2, dec!(0), dec!(4))],
+            deltas
+                .iter()
+                .map(|delta| {
+                    let total = |state: &AccountState| {
+                        state
+                            .balances
+                            .get(&Currency::default())
+                            .map_or(dec!(0), |balance| balance.total())
+                    };
+                    (delta.client_id, total(&delta.before), total(&delta.after))
+                })
+                .collect::<Vec<_>>()
+        );
+
+        // a rejected event changes nothing
+        let deltas = engine
+            .process_event_with_deltas(deposit(3, 1))
+            .expect("Expected no errors.");
+        assert_eq!(Vec::<StateDelta>::new(), deltas);
+        assert_eq!(1, engine.event_counts().rejected);
+    }
+
+    #[test]
+    fn test_tenants() {
+        let mut tenants = Tenants::new();
+        tenants.insert("cards", Engine::builder().build());
+        tenants.insert(
+            "wallets",
+            Engine::builder()
+                .withdrawal_disputes(WithdrawalDisputePolicy::Reject)
+                .build(),
+        );
+        assert_eq!(
+            vec!["cards", "wallets"],
+            tenants.tenants().collect::<Vec<_>>()
+        );
+
+        // the same events, under each tenant's own policies
+        for tenant in ["cards", "wallets"] {
+            for event in [
+                Event::Transaction {
+                    kind: TransactionKind::Deposit,
+                    client_id: 1,
+                    transaction_id: 1,
+                    currency: Currency::default(),
+                    amount: dec!(10),
+                    counterparty: None,
+                },
+                Event::Transaction {
+                    kind: TransactionKind::Withdrawal,
+                    client_id: 1,
+                    transaction_id: 2,
+                    currency: Currency::default(),
+                    amount: dec!(4),
+                    counterparty: None,
+                },
+                Event::DisputeStep {
+                    kind: DisputeStepKind::Dispute,
+                    client_id: 1,
+                    transaction_id: 2,
+                    amount: None,
+                },
+            ] {
+                tenants
+                    .process_event(tenant, event)
+                    .expect("Expected no errors.");
+            }
+        }
+        let error = tenants
+            .process_event(
+                "loans",
+                Event::DisputeStep {
+                    kind: DisputeStepKind::Resolve,
+                    client_id: 1,
+                    transaction_id: 2,
+                    amount: None,
+                },
+            )
+            .expect_err("Expected an error.");
+        assert_eq!(io::ErrorKind::NotFound, error.kind());
+
+        let final_states = tenants.finish().expect("Expected no errors.");
+        let held = |tenant: &str| {
+            final_states[tenant].clients_by_id[&1]
+                .balance(Currency::default())
+                .held()
+        };
+        assert_eq!(dec!(4), held("cards"));
+        assert_eq!(dec!(0), held("wallets"));
+        assert_eq!(1, final_states["wallets"].event_counts.rejected);
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn test_process_event_stream() {
+        let deposit = |transaction_id, timestamp| {
+            Ok::<_, io::Error>(SourcedEvent {
+                event: Event::Transaction {
+                    kind: TransactionKind::Deposit,
+                    client_id: 1,
+                    transaction_id,
+                    currency: Currency::default(),
+                    amount: dec!(5),
+                    counterparty: None,
+                },
+                source: None,
+                timestamp: Some(timestamp),
+            })
+        };
+        let dispute = Ok(SourcedEvent {
+            event: Event::DisputeStep {
+                kind: DisputeStepKind::Dispute,
+                client_id: 1,
+                transaction_id: 2,
+                amount: None,
+            },
+            source: None,
+            timestamp: Some(30),
+        });
+
+        let mut engine = Engine::builder().reorder_window(1).build();
+        // the dispute arrives before its deposit, but is put back in order
+        engine
+            .process_event_stream(tokio_stream::iter(vec![
+                deposit(1, 10),
+                dispute,
+                deposit(2, 20),
+            ]))
+            .await
+            .expect("Expected no errors.");
+
+        let client = engine.processor().client(1).expect("Expected client 1.");
+        assert_eq!(dec!(5), client.balance(Currency::default()).held());
+        assert_eq!(dec!(10), client.balance(Currency::default()).total());
+        assert_eq!(0, engine.event_counts().rejected);
+
+        let failing = tokio_stream::iter(vec![Err(io::Error::other("Connection reset."))]);
+        let error = engine
+            .process_event_stream::<SourcedEvent, _>(failing)
+            .await
+            .expect_err("Expected an error.");
+        assert_eq!("Connection reset.", error.to_string());
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn test_process_events_async() {
+        let input = concat!(
+            "type,client,tx,amount\n",
+            "deposit,1,1,5\n",
+            "withdrawal,1,2,7\n",
+            "withdrawal,1,3,2\n",
+        );
+        let mut errors = Vec::new();
+        let mut engine = Engine::builder()
+            .errors(&mut errors, ErrorFormat::Text)
+            .build();
+        engine
+            .process_events_async(InputFormat::Csv, input.as_bytes())
+            .await
+            .expect("Expected no errors.");
+
+        let mut output = Vec::new();
+        engine
+            .write_report_async(&mut output)
+            .await
+            .expect("Expected no errors.");
+        drop(engine);
+        assert_eq!(
+            "client,available,held,total,locked\n1,3.0000,0.0000,3.0000,false\n",
+            String::from_utf8(output).expect("Not UTF-8")
+        );
+        assert!(String::from_utf8(errors)
+            .expect("Not UTF-8")
+            .contains("line 3"));
+    }
+}
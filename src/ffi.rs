@@ -0,0 +1,540 @@
+// A C ABI for embedding the engine in something that isn't Rust, declared in
+// `include/challenge.h`. It's deliberately small: create an engine, submit
+// events to it one at a time, look up a client's balance, finish it and get
+// the report out.
+//
+// Ownership is kept as simple as C allows. An engine is created by
+// `challenge_engine_new` and belongs to the caller until it's handed to
+// `challenge_engine_free`; every other function only borrows it for the
+// length of the call. Strings going in are borrowed, NUL-terminated UTF-8.
+// Nothing coming out needs freeing: balances are written into the caller's
+// struct, the report into the caller's buffer, and the last error belongs to
+// the engine (and lasts until the next call with it).
+//
+// Every function returns a `ChallengeStatus`, and anything other than
+// `Ok` comes with a message from `challenge_engine_last_error`, if there's an
+// engine to keep it. A panic is caught rather than unwound into C, after which
+// the engine can only be freed, since there's no telling what state it was
+// left in. An engine can move between threads, but only one can use it at a
+// time.
+
+// The safety contract is the same for every function (the pointers are null
+// or valid for the call, and engines came from `challenge_engine_new`), so
+// it's spelt out once above and in the header rather than on each of them.
+#![allow(clippy::missing_safety_doc)]
+
+use std::{
+    cell::RefCell,
+    ffi::{c_char, CStr, CString},
+    io,
+    panic::{self, AssertUnwindSafe},
+    ptr,
+    rc::Rc,
+    slice,
+};
+
+use crate::{
+    format::{self, normalize_amount, OutputFormat, ReportConfig},
+    model::{Amount, Client, ClientID, Currency, Source},
+    system::{FinalState, Rejection, RejectionLogger},
+    Engine,
+};
+
+// Room for any amount as a string (29 digits, a sign and a point) and its NUL.
+pub const CHALLENGE_AMOUNT_LEN: usize = 32;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChallengeStatus {
+    Ok = 0,
+    // The event was processed, and rejected.
+    Rejected = 1,
+    // A null pointer, a string that isn't UTF-8, an unknown report format or
+    // the like.
+    InvalidArgument = 2,
+    // The event didn't parse, so it wasn't processed.
+    ParseError = 3,
+    // There's no such client.
+    NotFound = 4,
+    // The report didn't fit, and how big it is has been written instead.
+    BufferTooSmall = 5,
+    // The engine's been finished, so it won't take any more events.
+    Finished = 6,
+    // Anything else, e.g. a limit being exceeded. The engine may not be
+    // usable after this.
+    Failed = 7,
+    // Something panicked, and the engine can only be freed.
+    Panicked = 8,
+}
+
+// A client's balance in a currency, as strings at the report's scale, since C
+// has nothing that holds a decimal.
+#[repr(C)]
+pub struct ChallengeBalance {
+    pub available: [c_char; CHALLENGE_AMOUNT_LEN],
+    pub held: [c_char; CHALLENGE_AMOUNT_LEN],
+    pub total: [c_char; CHALLENGE_AMOUNT_LEN],
+    pub locked: bool,
+}
+
+pub struct ChallengeEngine {
+    state: State,
+    report_config: ReportConfig,
+    // How many events have been submitted, which is what each is said to have
+    // been read from, so that its rejection can be told apart from those of
+    // any queued events it let through.
+    submitted: u64,
+    rejections: Rc<RefCell<Vec<(u64, String)>>>,
+    last_error: Option<CString>,
+}
+
+enum State {
+    Running(Box<Engine<'static>>),
+    Finished(Box<FinalState>),
+    // why it can't be used any more
+    Unusable(&'static str),
+}
+
+// Why a call didn't go to plan.
+struct Failure(ChallengeStatus, String);
+
+impl Failure {
+    fn new(status: ChallengeStatus, message: impl ToString) -> Self {
+        Self(status, message.to_string())
+    }
+}
+
+struct SubmittedRejections(Rc<RefCell<Vec<(u64, String)>>>);
+
+impl RejectionLogger for SubmittedRejections {
+    fn log_rejection(&mut self, rejection: &Rejection) -> io::Result<()> {
+        let submitted = rejection.source.map_or(0, |source| source.line);
+        self.0
+            .borrow_mut()
+            .push((submitted, rejection.error.to_string()));
+        Ok(())
+    }
+
+    fn flush_rejections(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl ChallengeEngine {
+    fn new(format: OutputFormat) -> Self {
+        let report_config = ReportConfig {
+            format,
+            ..ReportConfig::default()
+        };
+        let rejections = Rc::new(RefCell::new(Vec::new()));
+        let engine = Engine::builder()
+            .report_config(report_config.clone())
+            .rejection_logger(SubmittedRejections(Rc::clone(&rejections)))
+            .build();
+        Self {
+            state: State::Running(Box::new(engine)),
+            report_config,
+            submitted: 0,
+            rejections,
+            last_error: None,
+        }
+    }
+
+    fn client(&self, client_id: ClientID) -> Result<&Client, Failure> {
+        let client = match &self.state {
+            State::Running(engine) => engine.processor().client(client_id),
+            State::Finished(final_state) => final_state.clients_by_id.get(&client_id),
+            State::Unusable(reason) => return Err(Failure::new(ChallengeStatus::Failed, reason)),
+        };
+        client.ok_or_else(|| {
+            Failure::new(
+                ChallengeStatus::NotFound,
+                format!("There's no client {}.", client_id),
+            )
+        })
+    }
+}
+
+// Runs `call` with the engine, keeping the message if it fails and catching
+// it if it panics.
+unsafe fn with_engine(
+    engine: *mut ChallengeEngine,
+    call: impl FnOnce(&mut ChallengeEngine) -> Result<(), Failure>,
+) -> ChallengeStatus {
+    let Some(engine) = engine.as_mut() else {
+        return ChallengeStatus::InvalidArgument;
+    };
+    engine.last_error = None;
+    let (status, message) = match panic::catch_unwind(AssertUnwindSafe(|| call(engine))) {
+        Ok(Ok(())) => return ChallengeStatus::Ok,
+        Ok(Err(Failure(status, message))) => (status, message),
+        Err(_) => {
+            engine.state = State::Unusable("The engine panicked, so it can only be freed.");
+            (
+                ChallengeStatus::Panicked,
+                String::from("The engine panicked."),
+            )
+        }
+    };
+    // a message can't have a NUL in the middle of it in C
+    engine.last_error = CString::new(message.replace('\0', "")).ok();
+    status
+}
+
+unsafe fn str_arg<'a>(arg: *const c_char, name: &str) -> Result<&'a str, Failure> {
+    if arg.is_null() {
+        return Err(Failure::new(
+            ChallengeStatus::InvalidArgument,
+            format!("The {} is null.", name),
+        ));
+    }
+    CStr::from_ptr(arg).to_str().map_err(|_| {
+        Failure::new(
+            ChallengeStatus::InvalidArgument,
+            format!("The {} isn't UTF-8.", name),
+        )
+    })
+}
+
+// Creates an engine that processes events the way the spec describes, and
+// reports in the given format (one of the names `--output-format` takes), or
+// CSV if that's null.
+#[no_mangle]
+pub unsafe extern "C" fn challenge_engine_new(
+    report_format: *const c_char,
+    engine_out: *mut *mut ChallengeEngine,
+) -> ChallengeStatus {
+    if engine_out.is_null() {
+        return ChallengeStatus::InvalidArgument;
+    }
+    let created = panic::catch_unwind(|| {
+        let format = match report_format.is_null() {
+            true => OutputFormat::Csv,
+            false => match str_arg(report_format, "report format").map(str::parse) {
+                Ok(Ok(format)) => format,
+                _ => return ptr::null_mut(),
+            },
+        };
+        Box::into_raw(Box::new(ChallengeEngine::new(format)))
+    });
+    match created {
+        Ok(engine) if engine.is_null() => ChallengeStatus::InvalidArgument,
+        Ok(engine) => {
+            *engine_out = engine;
+            ChallengeStatus::Ok
+        }
+        Err(_) => ChallengeStatus::Panicked,
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn challenge_engine_free(engine: *mut ChallengeEngine) {
+    if !engine.is_null() {
+        // nothing can be done about a panic here, but it mustn't reach C
+        let _ = panic::catch_unwind(AssertUnwindSafe(|| drop(Box::from_raw(engine))));
+    }
+}
+
+// Processes an event, given as a line of JSON like the ones `POST /events`
+// takes, e.g. `{"type":"deposit","client":1,"tx":1,"amount":"2.5"}`. A
+// rejection comes back as `Rejected`, with why in the last error.
+#[no_mangle]
+pub unsafe extern "C" fn challenge_engine_submit(
+    engine: *mut ChallengeEngine,
+    event: *const c_char,
+) -> ChallengeStatus {
+    with_engine(engine, |engine| {
+        let event = str_arg(event, "event")?;
+        let State::Running(running) = &mut engine.state else {
+            return Err(match engine.state {
+                State::Unusable(reason) => Failure::new(ChallengeStatus::Failed, reason),
+                _ => Failure::new(
+                    ChallengeStatus::Finished,
+                    "The engine's been finished, so it won't take any more events.",
+                ),
+            });
+        };
+
+        let source = Source {
+            line: engine.submitted + 1,
+            byte: 0,
+            record: None,
+        };
+        let event = format::json::input::parse_record(event.trim(), source, false)
+            .map_err(|e| Failure::new(ChallengeStatus::ParseError, e))?;
+        engine.submitted += 1;
+        engine.rejections.borrow_mut().clear();
+        running
+            .process_event(event)
+            .map_err(|e| Failure::new(ChallengeStatus::Failed, e))?;
+
+        let submitted = engine.submitted;
+        match engine
+            .rejections
+            .take()
+            .into_iter()
+            .find(|(line, _)| *line == submitted)
+        {
+            Some((_, message)) => Err(Failure::new(ChallengeStatus::Rejected, message)),
+            None => Ok(()),
+        }
+    })
+}
+
+// Writes where a client stands in a currency (the unnamed one if it's null or
+// empty) into `balance_out`. A client with nothing in that currency has a
+// zero balance in it, whereas one that's never been seen is `NotFound`.
+#[no_mangle]
+pub unsafe extern "C" fn challenge_engine_balance(
+    engine: *mut ChallengeEngine,
+    client_id: ClientID,
+    currency: *const c_char,
+    balance_out: *mut ChallengeBalance,
+) -> ChallengeStatus {
+    with_engine(engine, |engine| {
+        let currency = match currency.is_null() {
+            true => Currency::default(),
+            false => str_arg(currency, "currency")?
+                .parse()
+                .map_err(|e| Failure::new(ChallengeStatus::InvalidArgument, e))?,
+        };
+        let Some(balance_out) = balance_out.as_mut() else {
+            return Err(Failure::new(
+                ChallengeStatus::InvalidArgument,
+                "The balance is null.",
+            ));
+        };
+
+        let client = engine.client(client_id)?;
+        let balance = client.balance(currency);
+        let scale = engine.report_config.scale;
+        write_amount(&mut balance_out.available, balance.available(), scale)?;
+        write_amount(&mut balance_out.held, balance.held(), scale)?;
+        write_amount(&mut balance_out.total, balance.total(), scale)?;
+        balance_out.locked = client.locked();
+        Ok(())
+    })
+}
+
+fn write_amount(
+    out: &mut [c_char; CHALLENGE_AMOUNT_LEN],
+    amount: Amount,
+    scale: u32,
+) -> Result<(), Failure> {
+    let amount = normalize_amount(amount, scale).to_string();
+    if amount.len() >= CHALLENGE_AMOUNT_LEN {
+        return Err(Failure::new(
+            ChallengeStatus::Failed,
+            format!("{} doesn't fit in a balance.", amount),
+        ));
+    }
+    for (out, byte) in out.iter_mut().zip(amount.bytes().chain([0])) {
+        *out = byte as c_char;
+    }
+    Ok(())
+}
+
+// Processes whatever's still queued (see `Engine::finish`), after which the
+// engine won't take any more events, but its balances and report can still be
+// had. Finishing a finished engine does nothing.
+#[no_mangle]
+pub unsafe extern "C" fn challenge_engine_finish(engine: *mut ChallengeEngine) -> ChallengeStatus {
+    with_engine(engine, |engine| {
+        let state = std::mem::replace(
+            &mut engine.state,
+            State::Unusable("The engine failed to finish, so it can only be freed."),
+        );
+        engine.state = match state {
+            State::Running(running) => State::Finished(Box::new(
+                (*running)
+                    .finish()
+                    .map_err(|e| Failure::new(ChallengeStatus::Failed, e))?,
+            )),
+            state => state,
+        };
+        match engine.state {
+            State::Unusable(reason) => Err(Failure::new(ChallengeStatus::Failed, reason)),
+            _ => Ok(()),
+        }
+    })
+}
+
+// Writes the report as the clients stand (so normally once the engine's
+// finished) into `buffer`, and how many bytes that took into `written`. It
+// isn't NUL-terminated, since not every format is text. If it doesn't fit in
+// `capacity` bytes, nothing's written to `buffer`, `written` says how big a
+// buffer it needs and it's `BufferTooSmall`, so passing a null buffer with no
+// capacity is a way to find out.
+#[no_mangle]
+pub unsafe extern "C" fn challenge_engine_report(
+    engine: *mut ChallengeEngine,
+    buffer: *mut u8,
+    capacity: usize,
+    written: *mut usize,
+) -> ChallengeStatus {
+    with_engine(engine, |engine| {
+        let Some(written) = written.as_mut() else {
+            return Err(Failure::new(
+                ChallengeStatus::InvalidArgument,
+                "The written size is null.",
+            ));
+        };
+        let mut report = Vec::new();
+        let result = match &engine.state {
+            State::Running(running) => running.write_report(&mut report),
+            State::Finished(final_state) => format::write_report(
+                &final_state.clients_by_id,
+                &final_state.event_counts,
+                &mut report,
+                &engine.report_config,
+            ),
+            State::Unusable(reason) => return Err(Failure::new(ChallengeStatus::Failed, reason)),
+        };
+        result.map_err(|e| Failure::new(ChallengeStatus::Failed, e))?;
+
+        *written = report.len();
+        if report.len() > capacity || (buffer.is_null() && !report.is_empty()) {
+            return Err(Failure::new(
+                ChallengeStatus::BufferTooSmall,
+                format!("The report needs {} bytes.", report.len()),
+            ));
+        }
+        if !report.is_empty() {
+            slice::from_raw_parts_mut(buffer, report.len()).copy_from_slice(&report);
+        }
+        Ok(())
+    })
+}
+
+// Why the last call with the engine failed, or null if it didn't. It belongs
+// to the engine, and only lasts until the next call with it.
+#[no_mangle]
+pub unsafe extern "C" fn challenge_engine_last_error(
+    engine: *const ChallengeEngine,
+) -> *const c_char {
+    engine
+        .as_ref()
+        .and_then(|engine| engine.last_error.as_ref())
+        .map_or(ptr::null(), |message| message.as_ptr())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn last_error(engine: *const ChallengeEngine) -> Option<String> {
+        let message = unsafe { challenge_engine_last_error(engine) };
+        (!message.is_null()).then(|| {
+            unsafe { CStr::from_ptr(message) }
+                .to_string_lossy()
+                .into_owned()
+        })
+    }
+
+    fn submit(engine: *mut ChallengeEngine, event: &str) -> ChallengeStatus {
+        let event = CString::new(event).expect("Expected no NULs.");
+        unsafe { challenge_engine_submit(engine, event.as_ptr()) }
+    }
+
+    fn amount(amount: &[c_char]) -> String {
+        unsafe { CStr::from_ptr(amount.as_ptr()) }
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    #[test]
+    fn test_engine() {
+        let mut engine = ptr::null_mut();
+        let format = CString::new("csv").expect("Expected no NULs.");
+        assert_eq!(ChallengeStatus::Ok, unsafe {
+            challenge_engine_new(format.as_ptr(), &mut engine)
+        });
+
+        assert_eq!(
+            ChallengeStatus::Ok,
+            submit(
+                engine,
+                r#"{"type":"deposit","client":1,"tx":1,"amount":"2.5"}"#
+            )
+        );
+        assert_eq!(None, last_error(engine));
+        assert_eq!(
+            ChallengeStatus::Rejected,
+            submit(
+                engine,
+                r#"{"type":"withdrawal","client":1,"tx":2,"amount":"3"}"#
+            )
+        );
+        assert_eq!(
+            Some(String::from("Insufficient funds.")),
+            last_error(engine)
+        );
+        assert_eq!(ChallengeStatus::ParseError, submit(engine, "oops"));
+
+        let mut balance = ChallengeBalance {
+            available: [0; CHALLENGE_AMOUNT_LEN],
+            held: [0; CHALLENGE_AMOUNT_LEN],
+            total: [0; CHALLENGE_AMOUNT_LEN],
+            locked: true,
+        };
+        assert_eq!(ChallengeStatus::Ok, unsafe {
+            challenge_engine_balance(engine, 1, ptr::null(), &mut balance)
+        });
+        assert_eq!("2.5000", amount(&balance.available));
+        assert_eq!("0.0000", amount(&balance.held));
+        assert_eq!("2.5000", amount(&balance.total));
+        assert!(!balance.locked);
+        assert_eq!(ChallengeStatus::NotFound, unsafe {
+            challenge_engine_balance(engine, 2, ptr::null(), &mut balance)
+        });
+
+        assert_eq!(ChallengeStatus::Ok, unsafe {
+            challenge_engine_finish(engine)
+        });
+        assert_eq!(
+            ChallengeStatus::Finished,
+            submit(
+                engine,
+                r#"{"type":"deposit","client":1,"tx":3,"amount":"1"}"#
+            )
+        );
+
+        let mut written = 0;
+        assert_eq!(ChallengeStatus::BufferTooSmall, unsafe {
+            challenge_engine_report(engine, ptr::null_mut(), 0, &mut written)
+        });
+        let mut buffer = vec![0; written];
+        assert_eq!(ChallengeStatus::Ok, unsafe {
+            challenge_engine_report(engine, buffer.as_mut_ptr(), buffer.len(), &mut written)
+        });
+        assert_eq!(
+            "client,available,held,total,locked\n1,2.5000,0.0000,2.5000,false\n",
+            String::from_utf8(buffer).expect("Not UTF-8")
+        );
+
+        unsafe { challenge_engine_free(engine) };
+    }
+
+    #[test]
+    fn test_invalid_arguments() {
+        let mut engine = ptr::null_mut();
+        let format = CString::new("nope").expect("Expected no NULs.");
+        assert_eq!(ChallengeStatus::InvalidArgument, unsafe {
+            challenge_engine_new(format.as_ptr(), &mut engine)
+        });
+        assert!(engine.is_null());
+        assert_eq!(ChallengeStatus::InvalidArgument, unsafe {
+            challenge_engine_finish(ptr::null_mut())
+        });
+
+        assert_eq!(ChallengeStatus::Ok, unsafe {
+            challenge_engine_new(ptr::null(), &mut engine)
+        });
+        assert_eq!(ChallengeStatus::InvalidArgument, unsafe {
+            challenge_engine_submit(engine, ptr::null())
+        });
+        assert_eq!(Some(String::from("The event is null.")), last_error(engine));
+        unsafe { challenge_engine_free(engine) };
+    }
+}
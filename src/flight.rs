@@ -0,0 +1,208 @@
+// Serves the balances `challenge serve` is keeping over Arrow Flight, so that
+// BI tools can pull the live state as Arrow rather than go through a CSV
+// export. There's one flight, `balances`, which is the report as the Arrow
+// output would have it (see `format::arrow::output`), as things stand when
+// it's asked for.
+//
+// Flight is gRPC, which means tonic and a tokio runtime, so the server gets a
+// thread of its own with a runtime of its own. The engine stays where it is:
+// whenever the balances are asked for, the request takes its turn with
+// everything else on the thread answering requests (see `serve::Service`),
+// which turns them into record batches and sends them back to be streamed.
+
+// tonic's `Status` is what every Flight call fails with, however big it is.
+#![allow(clippy::result_large_err)]
+
+use arrow_flight::{
+    encode::FlightDataEncoderBuilder,
+    flight_service_server::{FlightService, FlightServiceServer},
+    Action, ActionType, Criteria, Empty, FlightData, FlightDescriptor, FlightEndpoint, FlightInfo,
+    HandshakeRequest, HandshakeResponse, PollInfo, PutResult, SchemaAsIpc, SchemaResult, Ticket,
+};
+use arrow_ipc::writer::IpcWriteOptions;
+use arrow_schema::SchemaRef;
+use challenge::format::{arrow::output::schema, ReportConfig};
+use log::warn;
+use std::{error::Error, net::TcpListener, pin::Pin, sync::Arc};
+use tokio::sync::oneshot;
+use tokio_stream::{wrappers::TcpListenerStream, Stream, StreamExt};
+use tonic::{transport::Server, Request, Response, Status, Streaming};
+
+// The name of the one flight there is, which is also its ticket.
+const BALANCES: &str = "balances";
+
+// Where the balances go once they've been turned into record batches, or why
+// they couldn't be.
+pub type BalancesReply = oneshot::Sender<Result<Vec<arrow_array::RecordBatch>, String>>;
+
+type BoxStream<T> = Pin<Box<dyn Stream<Item = Result<T, Status>> + Send>>;
+
+// Answers Flight requests on `listener` until the service stops, asking for
+// the balances with `request_balances`, which says whether it could.
+pub fn serve(
+    listener: &TcpListener,
+    report_config: &ReportConfig,
+    request_balances: impl Fn(BalancesReply) -> bool + Send + Sync + 'static,
+) {
+    if let Err(e) = try_serve(listener, report_config, request_balances) {
+        warn!("Stopped serving Arrow Flight: {}", e);
+    }
+}
+
+fn try_serve(
+    listener: &TcpListener,
+    report_config: &ReportConfig,
+    request_balances: impl Fn(BalancesReply) -> bool + Send + Sync + 'static,
+) -> Result<(), Box<dyn Error>> {
+    let service = BalancesService {
+        schema: Arc::new(schema(report_config)?),
+        request_balances,
+    };
+    let listener = listener.try_clone()?;
+    listener.set_nonblocking(true)?;
+
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    runtime.block_on(async {
+        let incoming = TcpListenerStream::new(tokio::net::TcpListener::from_std(listener)?);
+        Server::builder()
+            .add_service(FlightServiceServer::new(service))
+            .serve_with_incoming(incoming)
+            .await?;
+        Ok(())
+    })
+}
+
+struct BalancesService<F> {
+    schema: SchemaRef,
+    request_balances: F,
+}
+
+impl<F> BalancesService<F> {
+    fn flight_info(&self) -> Result<FlightInfo, Status> {
+        Ok(FlightInfo::new()
+            .try_with_schema(&self.schema)
+            .map_err(|e| Status::internal(e.to_string()))?
+            .with_descriptor(FlightDescriptor::new_path(vec![String::from(BALANCES)]))
+            .with_endpoint(FlightEndpoint::new().with_ticket(Ticket::new(BALANCES)))
+            .with_ordered(true))
+    }
+}
+
+fn check_descriptor(descriptor: &FlightDescriptor) -> Result<(), Status> {
+    if descriptor.path != [BALANCES] {
+        return Err(Status::not_found(format!(
+            "There's no flight {:?}, only {:?}.",
+            descriptor.path, BALANCES
+        )));
+    }
+    Ok(())
+}
+
+#[tonic::async_trait]
+impl<F> FlightService for BalancesService<F>
+where
+    F: Fn(BalancesReply) -> bool + Send + Sync + 'static,
+{
+    type HandshakeStream = BoxStream<HandshakeResponse>;
+    type ListFlightsStream = BoxStream<FlightInfo>;
+    type DoGetStream = BoxStream<FlightData>;
+    type DoPutStream = BoxStream<PutResult>;
+    type DoExchangeStream = BoxStream<FlightData>;
+    type DoActionStream = BoxStream<arrow_flight::Result>;
+    type ListActionsStream = BoxStream<ActionType>;
+
+    async fn list_flights(
+        &self,
+        _request: Request<Criteria>,
+    ) -> Result<Response<Self::ListFlightsStream>, Status> {
+        let flights = tokio_stream::iter([self.flight_info()]);
+        Ok(Response::new(Box::pin(flights)))
+    }
+
+    async fn get_flight_info(
+        &self,
+        request: Request<FlightDescriptor>,
+    ) -> Result<Response<FlightInfo>, Status> {
+        check_descriptor(request.get_ref())?;
+        Ok(Response::new(self.flight_info()?))
+    }
+
+    async fn get_schema(
+        &self,
+        request: Request<FlightDescriptor>,
+    ) -> Result<Response<SchemaResult>, Status> {
+        check_descriptor(request.get_ref())?;
+        let schema = SchemaAsIpc::new(&self.schema, &IpcWriteOptions::default())
+            .try_into()
+            .map_err(|e: arrow_schema::ArrowError| Status::internal(e.to_string()))?;
+        Ok(Response::new(schema))
+    }
+
+    async fn do_get(
+        &self,
+        request: Request<Ticket>,
+    ) -> Result<Response<Self::DoGetStream>, Status> {
+        if request.get_ref().ticket != BALANCES.as_bytes() {
+            return Err(Status::not_found("The only ticket is \"balances\"."));
+        }
+
+        let (reply, batches) = oneshot::channel();
+        if !(self.request_balances)(reply) {
+            return Err(Status::unavailable("The service has stopped."));
+        }
+        let batches = batches
+            .await
+            .map_err(|_| Status::unavailable("The service has stopped."))?
+            .map_err(Status::internal)?;
+
+        let data = FlightDataEncoderBuilder::new()
+            .with_schema(self.schema.clone())
+            .build(tokio_stream::iter(batches.into_iter().map(Ok)))
+            .map(|data| data.map_err(Status::from));
+        Ok(Response::new(Box::pin(data)))
+    }
+
+    async fn handshake(
+        &self,
+        _request: Request<Streaming<HandshakeRequest>>,
+    ) -> Result<Response<Self::HandshakeStream>, Status> {
+        Err(Status::unimplemented("There's no authentication."))
+    }
+
+    async fn poll_flight_info(
+        &self,
+        _request: Request<FlightDescriptor>,
+    ) -> Result<Response<PollInfo>, Status> {
+        Err(Status::unimplemented("The balances are always ready."))
+    }
+
+    async fn do_put(
+        &self,
+        _request: Request<Streaming<FlightData>>,
+    ) -> Result<Response<Self::DoPutStream>, Status> {
+        Err(Status::unimplemented("The balances are read-only."))
+    }
+
+    async fn do_exchange(
+        &self,
+        _request: Request<Streaming<FlightData>>,
+    ) -> Result<Response<Self::DoExchangeStream>, Status> {
+        Err(Status::unimplemented("The balances are read-only."))
+    }
+
+    async fn do_action(
+        &self,
+        _request: Request<Action>,
+    ) -> Result<Response<Self::DoActionStream>, Status> {
+        Err(Status::unimplemented("There are no actions."))
+    }
+
+    async fn list_actions(
+        &self,
+        _request: Request<Empty>,
+    ) -> Result<Response<Self::ListActionsStream>, Status> {
+        Ok(Response::new(Box::pin(tokio_stream::empty())))
+    }
+}
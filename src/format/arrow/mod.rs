@@ -0,0 +1,4 @@
+// Everything Arrow-related lives here. This is behind the `arrow` feature
+// because the Arrow crates are hefty and most runs don't need them.
+
+pub mod output;
@@ -0,0 +1,227 @@
+use arrow_array::{
+    ArrayRef, BooleanArray, Decimal128Array, RecordBatch, StringArray, UInt16Array, UInt32Array,
+};
+use arrow_ipc::writer::StreamWriter;
+use arrow_schema::{
+    ArrowError, DataType, Field as ArrowField, Schema, SchemaRef, DECIMAL128_MAX_PRECISION,
+};
+use std::{collections::HashMap, error::Error, io::Write, iter, sync::Arc};
+
+use crate::{
+    format::{columns::Field, normalize_amount, ordered_rows, ReportConfig, ReportRow},
+    model::{Balance, Client, ClientID, ClientProfile},
+};
+
+// How many rows go into each record batch. Batching keeps memory bounded
+// while still giving readers reasonably sized chunks to work with.
+const BATCH_SIZE: usize = 64 * 1024;
+
+// Takes the resultant clients after processing events, and writes them to the
+// given writer as an Arrow IPC stream with one column per configured column.
+// Amounts are written as decimal128s with the configured scale rather than as
+// floats, so nothing is lost on the way into a notebook.
+pub fn write_report(
+    clients_by_id: &HashMap<ClientID, Client>,
+    writer: impl Write,
+    config: &ReportConfig,
+) -> Result<(), Box<dyn Error>> {
+    let schema = Arc::new(schema(config)?);
+    let mut stream_writer = StreamWriter::try_new(writer, &schema)?;
+    for batch in record_batches(clients_by_id, schema, config) {
+        stream_writer.write(&batch?)?;
+    }
+
+    stream_writer.finish()?;
+    stream_writer.into_inner()?.flush()?;
+
+    Ok(())
+}
+
+// The report as record batches of the given schema (see `schema`), for
+// anything that wants them as they are rather than written out, e.g. serve's
+// Arrow Flight endpoint.
+pub fn record_batches<'a>(
+    clients_by_id: &'a HashMap<ClientID, Client>,
+    schema: SchemaRef,
+    config: &'a ReportConfig,
+) -> impl Iterator<Item = Result<RecordBatch, ArrowError>> + 'a {
+    let mut rows_iter = ordered_rows(clients_by_id, config);
+    iter::from_fn(move || {
+        let rows = rows_iter.by_ref().take(BATCH_SIZE).collect::<Vec<_>>();
+        if rows.is_empty() {
+            return None;
+        }
+
+        let columns = config
+            .columns
+            .iter()
+            .map(|column| array(column.field, &rows, config.scale))
+            .collect();
+        Some(RecordBatch::try_new(schema.clone(), columns))
+    })
+}
+
+// The schema the report's written with, a field per configured column.
+pub fn schema(config: &ReportConfig) -> Result<Schema, Box<dyn Error>> {
+    // rust_decimal can't go past 28 decimal places anyway
+    let scale = i8::try_from(config.scale)
+        .ok()
+        .filter(|scale| *scale as u8 <= DECIMAL128_MAX_PRECISION)
+        .ok_or_else(|| format!("Scale {} is too large for Arrow output.", config.scale))?;
+
+    let fields = config.columns.iter().map(|column| {
+        let data_type = match column.field {
+            Field::Client => DataType::UInt16,
+            Field::Available
+            | Field::Held
+            | Field::Total
+            | Field::Fees
+            | Field::Interest
+            | Field::Pending
+            | Field::Overdrawn => DataType::Decimal128(DECIMAL128_MAX_PRECISION, scale),
+            Field::Locked | Field::Flagged => DataType::Boolean,
+            Field::Currency | Field::Status | Field::Name | Field::Tier | Field::HomeCurrency => {
+                DataType::Utf8
+            }
+            Field::DisputedCount | Field::ChargebackCount | Field::LastTxId => DataType::UInt32,
+        };
+        let nullable = matches!(
+            column.field,
+            Field::LastTxId | Field::Name | Field::Tier | Field::HomeCurrency
+        );
+        ArrowField::new(&column.header, data_type, nullable)
+    });
+
+    Ok(Schema::new(fields.collect::<Vec<_>>()))
+}
+
+fn array(field: Field, rows: &[ReportRow], scale: u32) -> ArrayRef {
+    // normalizing gives every amount exactly `scale` decimal places, so its
+    // mantissa is what Arrow expects for a decimal128 of that scale
+    let amounts = |amount: fn(&Balance) -> rust_decimal::Decimal| {
+        let mantissas = rows
+            .iter()
+            .map(|row| normalize_amount(amount(row.balance), scale).mantissa());
+        Arc::new(
+            Decimal128Array::from_iter_values(mantissas)
+                .with_precision_and_scale(DECIMAL128_MAX_PRECISION, scale as i8)
+                .expect("Scale was checked when building the schema"),
+        ) as ArrayRef
+    };
+    let clients = || rows.iter().map(|row| row.client);
+
+    match field {
+        Field::Client => Arc::new(UInt16Array::from_iter_values(
+            rows.iter().map(|row| row.client_id),
+        )),
+        Field::Available => amounts(Balance::available),
+        Field::Held => amounts(Balance::held),
+        Field::Total => amounts(Balance::total),
+        Field::Fees => amounts(Balance::fees),
+        Field::Interest => amounts(Balance::interest),
+        Field::Pending => amounts(Balance::pending),
+        Field::Overdrawn => amounts(Balance::overdrawn),
+        Field::Locked => Arc::new(BooleanArray::from_iter(
+            clients().map(|client| Some(client.locked())),
+        )),
+        Field::Flagged => Arc::new(BooleanArray::from_iter(
+            clients().map(|client| Some(client.flagged())),
+        )),
+        Field::DisputedCount => Arc::new(UInt32Array::from_iter_values(
+            clients().map(Client::disputed_count),
+        )),
+        Field::ChargebackCount => Arc::new(UInt32Array::from_iter_values(
+            clients().map(Client::chargeback_count),
+        )),
+        Field::LastTxId => Arc::new(UInt32Array::from_iter(
+            clients().map(Client::last_transaction_id),
+        )),
+        Field::Currency => Arc::new(StringArray::from_iter_values(
+            rows.iter().map(|row| row.currency.to_string()),
+        )),
+        Field::Status => Arc::new(StringArray::from_iter_values(
+            clients().map(|client| client.status().name()),
+        )),
+        Field::Name => Arc::new(StringArray::from_iter(
+            clients().map(|client| client.profile().map(ClientProfile::name)),
+        )),
+        Field::Tier => Arc::new(StringArray::from_iter(
+            clients().map(|client| client.profile().and_then(ClientProfile::tier)),
+        )),
+        Field::HomeCurrency => Arc::new(StringArray::from_iter(clients().map(|client| {
+            client
+                .profile()
+                .map(|profile| profile.currency().to_string())
+        }))),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::format::columns::parse_columns;
+    use arrow_array::Array;
+    use arrow_ipc::reader::StreamReader;
+    use pretty_assertions::assert_eq;
+    use rust_decimal_macros::dec;
+
+    #[test]
+    fn test_write_report() {
+        let mut writer = Vec::new();
+        let clients_by_id = HashMap::from([
+            (2, Client::create(dec!(6), dec!(7.12345), false)),
+            (1, Client::create(dec!(20), dec!(100), true)),
+        ]);
+        let config = ReportConfig {
+            columns: parse_columns("client:id,total,locked").expect("Valid columns"),
+            ..ReportConfig::default()
+        };
+
+        write_report(&clients_by_id, &mut writer, &config).expect("Expected no errors.");
+
+        let batches = StreamReader::try_new(writer.as_slice(), None)
+            .expect("Expected a valid stream")
+            .collect::<Result<Vec<_>, _>>()
+            .expect("Expected valid batches");
+        assert_eq!(1, batches.len());
+        let batch = &batches[0];
+
+        let schema = batch.schema();
+        let names = schema
+            .fields()
+            .iter()
+            .map(|field| field.name().as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(vec!["id", "total", "locked"], names);
+
+        let ids = batch
+            .column(0)
+            .as_any()
+            .downcast_ref::<UInt16Array>()
+            .expect("Expected client IDs");
+        assert_eq!(vec![1, 2], ids.values().to_vec());
+
+        let totals = batch
+            .column(1)
+            .as_any()
+            .downcast_ref::<Decimal128Array>()
+            .expect("Expected decimals");
+        assert_eq!(4, totals.scale());
+        assert_eq!(
+            vec!["100.0000", "7.1234"],
+            (0..totals.len())
+                .map(|i| totals.value_as_string(i))
+                .collect::<Vec<_>>()
+        );
+
+        let locked = batch
+            .column(2)
+            .as_any()
+            .downcast_ref::<BooleanArray>()
+            .expect("Expected booleans");
+        assert_eq!(
+            vec![Some(true), Some(false)],
+            locked.iter().collect::<Vec<_>>()
+        );
+    }
+}
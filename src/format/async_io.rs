@@ -0,0 +1,314 @@
+use std::{
+    collections::HashMap,
+    error::Error,
+    io, mem,
+    pin::{pin, Pin},
+    task::{ready, Context, Poll},
+};
+use tokio::io::{AsyncBufRead, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio_stream::Stream;
+
+use super::{csv, error::ParseError, json, InputFormat, ReportConfig};
+use crate::{
+    model::{Client, ClientID, Source, SourcedEvent},
+    system::EventCounts,
+};
+
+// Like `format::parse_events`, but reading from something async (a socket, a
+// request body, an object store download) without blocking the runtime while
+// it waits. Events come out the same as they would from the same bytes read
+// the blocking way, with the line and byte each one started at, except that
+// blank lines in CSV are counted rather than skipped over.
+//
+// Only reading waits on anything, so each record is read a line at a time and
+// then parsed on its own. A CSV record carries on over the next line as long
+// as it's in the middle of a quoted field.
+pub fn parse_events<'a>(
+    format: InputFormat,
+    reader: impl AsyncRead + 'a,
+    keep_records: bool,
+) -> impl Stream<Item = Result<SourcedEvent, ParseError>> + 'a {
+    EventStream {
+        reader: Box::pin(BufReader::new(reader)),
+        format,
+        keep_records,
+        record: Vec::new(),
+        in_line: false,
+        lines_read: 0,
+        bytes_read: 0,
+        record_line: 1,
+        record_byte: 0,
+        headers: None,
+        done: false,
+    }
+}
+
+// Like `format::write_report`, but writing to something async. The report's
+// worked out in memory first, since only writing it has anything to wait on.
+pub async fn write_report(
+    clients_by_id: &HashMap<ClientID, Client>,
+    event_counts: &EventCounts,
+    writer: impl AsyncWrite,
+    config: &ReportConfig,
+) -> Result<(), Box<dyn Error>> {
+    let mut report = Vec::new();
+    super::write_report(clients_by_id, event_counts, &mut report, config)?;
+
+    let mut writer = pin!(writer);
+    writer.write_all(&report).await?;
+    writer.flush().await?;
+
+    Ok(())
+}
+
+struct EventStream<'a> {
+    reader: Pin<Box<dyn AsyncBufRead + 'a>>,
+    format: InputFormat,
+    keep_records: bool,
+    // what's been read of the current record so far, and whether that stops
+    // partway through a line (i.e. the reader's waiting on the rest of it)
+    record: Vec<u8>,
+    in_line: bool,
+    lines_read: u64,
+    bytes_read: u64,
+    // where the current record started
+    record_line: u64,
+    record_byte: u64,
+    // CSV's, once they've been read
+    headers: Option<::csv::StringRecord>,
+    done: bool,
+}
+
+impl EventStream<'_> {
+    // Reads up to the end of the next line onto the record, saying whether
+    // there was anything left to read.
+    fn poll_read_line(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<bool>> {
+        loop {
+            let available = ready!(self.reader.as_mut().poll_fill_buf(cx))?;
+            if available.is_empty() {
+                // the last line doesn't need a line break
+                let ended = mem::take(&mut self.in_line);
+                if ended {
+                    self.lines_read += 1;
+                }
+                return Poll::Ready(Ok(ended));
+            }
+
+            let (used, ended) = match available.iter().position(|&byte| byte == b'\n') {
+                Some(end) => (end + 1, true),
+                None => (available.len(), false),
+            };
+            self.record.extend_from_slice(&available[..used]);
+            self.reader.as_mut().consume(used);
+            self.bytes_read += used as u64;
+            self.in_line = !ended;
+            if ended {
+                self.lines_read += 1;
+                return Poll::Ready(Ok(true));
+            }
+        }
+    }
+
+    // Parses a whole record, or takes it as CSV's header if it's the first
+    // one. Blank lines don't parse as anything.
+    fn parse(
+        &mut self,
+        record: Vec<u8>,
+        source: Source,
+    ) -> Option<Result<SourcedEvent, ParseError>> {
+        let record = match String::from_utf8(record) {
+            Ok(record) => record,
+            Err(e) => return Some(Err(io::Error::new(io::ErrorKind::InvalidData, e).into())),
+        };
+        let record = record.trim();
+        if record.is_empty() {
+            return None;
+        }
+
+        match self.format {
+            InputFormat::JsonLines => {
+                Some(json::input::parse_record(record, source, self.keep_records))
+            }
+            InputFormat::Csv => {
+                let record = match read_csv_record(record) {
+                    Ok(record) => record,
+                    Err(e) => return Some(Err(e)),
+                };
+                let Some(headers) = &self.headers else {
+                    self.headers = Some(record);
+                    return None;
+                };
+                if record.len() != headers.len() {
+                    return Some(Err(ParseError::FieldCount {
+                        line: source.line,
+                        expected: headers.len(),
+                        found: record.len(),
+                    }));
+                }
+                Some(csv::input::parse_record_at(
+                    &record,
+                    headers,
+                    source,
+                    self.keep_records,
+                ))
+            }
+        }
+    }
+}
+
+impl Stream for EventStream<'_> {
+    type Item = Result<SourcedEvent, ParseError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let stream = &mut *self;
+        while !stream.done {
+            let more = match ready!(stream.poll_read_line(cx)) {
+                Ok(more) => more,
+                Err(e) => {
+                    stream.done = true;
+                    return Poll::Ready(Some(Err(e.into())));
+                }
+            };
+            if !more {
+                stream.done = true;
+                if stream.record.is_empty() {
+                    break;
+                }
+            } else if stream.format == InputFormat::Csv && in_quoted_field(&stream.record) {
+                continue;
+            }
+
+            let source = Source {
+                line: stream.record_line,
+                byte: stream.record_byte,
+                record: None,
+            };
+            let record = mem::take(&mut stream.record);
+            stream.record_line = stream.lines_read + 1;
+            stream.record_byte = stream.bytes_read;
+            if let Some(event) = stream.parse(record, source) {
+                return Poll::Ready(Some(event));
+            }
+        }
+
+        Poll::Ready(None)
+    }
+}
+
+// Whether a CSV record stops partway through a quoted field, going by whether
+// it's opened more quotes than it's closed. An escaped quote is two of them,
+// so it doesn't count either way.
+fn in_quoted_field(record: &[u8]) -> bool {
+    record.iter().filter(|&&byte| byte == b'"').count() % 2 == 1
+}
+
+fn read_csv_record(record: &str) -> Result<::csv::StringRecord, ParseError> {
+    let mut csv_record = ::csv::StringRecord::new();
+    ::csv::ReaderBuilder::new()
+        .trim(::csv::Trim::All)
+        .has_headers(false)
+        .from_reader(record.as_bytes())
+        .read_record(&mut csv_record)?;
+    Ok(csv_record)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{format::OutputFormat, model::Event};
+    use pretty_assertions::assert_eq;
+    use rust_decimal_macros::dec;
+    use tokio_stream::StreamExt;
+
+    async fn parse(format: InputFormat, input: &str) -> Vec<Result<SourcedEvent, String>> {
+        // a tiny buffer, so that records get read in pieces
+        let reader = BufReader::with_capacity(4, input.as_bytes());
+        parse_events(format, reader, true)
+            .map(|event| event.map_err(|e| e.to_string()))
+            .collect()
+            .await
+    }
+
+    #[tokio::test]
+    async fn test_parse_csv_events() {
+        let input = concat!(
+            "type, client, tx, amount, counterparty\n",
+            "deposit, 1, 1, 1.5,\"Acme\n Ltd\"\r\n",
+            "\n",
+            "withdrawal,1,2,0.5,\n",
+            "withdrawal,1,3\n",
+            "deposit,2,4,1,",
+        );
+
+        let events = parse(InputFormat::Csv, input).await;
+        assert_eq!(4, events.len());
+        let event = events[0].as_ref().expect("Expected a valid event");
+        assert_eq!(
+            Some(Source {
+                line: 2,
+                byte: 39,
+                record: Some(String::from("deposit,1,1,1.5,Acme\n Ltd")),
+            }),
+            event.source
+        );
+        assert!(matches!(
+            &event.event,
+            Event::Transaction { amount, counterparty: Some(counterparty), .. }
+                if *amount == dec!(1.5) && counterparty == "Acme\n Ltd"
+        ));
+        let source = |event: &Result<SourcedEvent, String>| {
+            let source = event.as_ref().ok()?.source.as_ref()?;
+            Some((source.line, source.byte))
+        };
+        assert_eq!(Some((5, 72)), source(&events[1]));
+        assert_eq!(
+            Err(String::from("Expected 5 fields on line 6 but found 3.")),
+            events[2].as_ref().map(|_| ()).map_err(Clone::clone)
+        );
+        assert_eq!(Some((7, 107)), source(&events[3]));
+    }
+
+    #[tokio::test]
+    async fn test_parse_json_events_like_blocking() {
+        let input = concat!(
+            r#"{"type":"deposit","client":1,"tx":1,"amount":"2"}"#,
+            "\n\n",
+            r#"{"type":"dispute","client":1,"tx":1}"#,
+            "\n",
+        );
+
+        let events = parse(InputFormat::JsonLines, input)
+            .await
+            .into_iter()
+            .map(|event| event.expect("Expected a valid event"))
+            .collect::<Vec<_>>();
+        let blocking = super::super::parse_events(InputFormat::JsonLines, input.as_bytes(), true)
+            .map(|event| event.expect("Expected a valid event"))
+            .collect::<Vec<_>>();
+        assert_eq!(blocking, events);
+    }
+
+    #[tokio::test]
+    async fn test_write_report() {
+        let clients_by_id = HashMap::from([(1, Client::create(dec!(0), dec!(2), false))]);
+        let config = ReportConfig {
+            format: OutputFormat::Csv,
+            ..ReportConfig::default()
+        };
+
+        let mut writer = Vec::new();
+        write_report(
+            &clients_by_id,
+            &EventCounts::default(),
+            &mut writer,
+            &config,
+        )
+        .await
+        .expect("Expected no errors.");
+
+        assert_eq!(
+            "client,available,held,total,locked\n1,2.0000,0.0000,2.0000,false\n",
+            String::from_utf8(writer).expect("Not UTF-8")
+        );
+    }
+}
@@ -0,0 +1,261 @@
+use serde::{Serialize, Serializer};
+use std::{fmt, str::FromStr};
+
+use super::{normalize_amount, ReportRow};
+use crate::model::{AccountStatus, Amount, ClientID, ClientProfile, Currency, TransactionID};
+
+// Which columns a report includes, in order, and what their headers say. This
+// lets us feed reports into systems with fixed header expectations without
+// post-processing them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Column {
+    pub field: Field,
+    pub header: String,
+}
+
+// The values a report column can hold.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Field {
+    Client,
+    Available,
+    Held,
+    Total,
+    Locked,
+    DisputedCount,
+    ChargebackCount,
+    Flagged,
+    LastTxId,
+    Fees,
+    Interest,
+    Pending,
+    Overdrawn,
+    Currency,
+    Status,
+    Name,
+    Tier,
+    HomeCurrency,
+}
+
+// A single value in a report row. Formats decide how to render these: text
+// formats use `Display` and JSON uses `Serialize`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Cell<'a> {
+    ClientId(ClientID),
+    Amount(Amount),
+    Bool(bool),
+    Count(u32),
+    // empty for clients with no successful deposits or withdrawals
+    TransactionId(Option<TransactionID>),
+    // empty for the profile's currency when the client was never registered
+    Currency(Option<Currency>),
+    Status(AccountStatus),
+    // likewise, empty for clients that were never registered or gave no tier
+    Text(Option<&'a str>),
+}
+
+impl Column {
+    pub fn new(field: Field) -> Self {
+        Self {
+            field,
+            header: String::from(field.name()),
+        }
+    }
+}
+
+// The columns from the spec, which is what we write unless told otherwise.
+pub fn default_columns() -> Vec<Column> {
+    [
+        Field::Client,
+        Field::Available,
+        Field::Held,
+        Field::Total,
+        Field::Locked,
+    ]
+    .into_iter()
+    .map(Column::new)
+    .collect()
+}
+
+// Parses a comma-separated list of columns, each optionally renamed with a
+// colon, e.g. `client,available:funds,total`.
+pub fn parse_columns(spec: &str) -> Result<Vec<Column>, String> {
+    spec.split(',')
+        .map(|column| {
+            let (field, header) = match column.split_once(':') {
+                Some((field, header)) => (field.trim(), Some(header.trim())),
+                None => (column.trim(), None),
+            };
+            let field = field.parse::<Field>()?;
+
+            Ok(Column {
+                field,
+                header: String::from(header.unwrap_or_else(|| field.name())),
+            })
+        })
+        .collect()
+}
+
+impl Field {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Field::Client => "client",
+            Field::Available => "available",
+            Field::Held => "held",
+            Field::Total => "total",
+            Field::Locked => "locked",
+            Field::DisputedCount => "disputed_count",
+            Field::ChargebackCount => "chargeback_count",
+            Field::Flagged => "flagged",
+            Field::LastTxId => "last_tx_id",
+            Field::Fees => "fees",
+            Field::Interest => "interest",
+            Field::Pending => "pending",
+            Field::Overdrawn => "overdrawn",
+            Field::Currency => "currency",
+            Field::Status => "status",
+            Field::Name => "name",
+            Field::Tier => "tier",
+            Field::HomeCurrency => "home_currency",
+        }
+    }
+
+    // Whether the values are numbers, which some formats align differently.
+    pub fn is_numeric(&self) -> bool {
+        !matches!(
+            self,
+            Field::Locked
+                | Field::Flagged
+                | Field::Currency
+                | Field::Status
+                | Field::Name
+                | Field::Tier
+                | Field::HomeCurrency
+        )
+    }
+
+    pub fn value<'a>(&self, row: &ReportRow<'a>, scale: u32) -> Cell<'a> {
+        let amount = |amount| Cell::Amount(normalize_amount(amount, scale));
+        let profile = row.client.profile();
+        match self {
+            Field::Client => Cell::ClientId(row.client_id),
+            Field::Available => amount(row.balance.available()),
+            Field::Held => amount(row.balance.held()),
+            Field::Total => amount(row.balance.total()),
+            Field::Locked => Cell::Bool(row.client.locked()),
+            Field::DisputedCount => Cell::Count(row.client.disputed_count()),
+            Field::ChargebackCount => Cell::Count(row.client.chargeback_count()),
+            Field::Flagged => Cell::Bool(row.client.flagged()),
+            Field::LastTxId => Cell::TransactionId(row.client.last_transaction_id()),
+            Field::Fees => amount(row.balance.fees()),
+            Field::Interest => amount(row.balance.interest()),
+            Field::Pending => amount(row.balance.pending()),
+            Field::Overdrawn => amount(row.balance.overdrawn()),
+            Field::Currency => Cell::Currency(Some(row.currency)),
+            Field::Status => Cell::Status(row.client.status()),
+            Field::Name => Cell::Text(profile.map(ClientProfile::name)),
+            Field::Tier => Cell::Text(profile.and_then(ClientProfile::tier)),
+            Field::HomeCurrency => Cell::Currency(profile.map(ClientProfile::currency)),
+        }
+    }
+}
+
+impl FromStr for Field {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "client" => Ok(Field::Client),
+            "available" => Ok(Field::Available),
+            "held" => Ok(Field::Held),
+            "total" => Ok(Field::Total),
+            "locked" => Ok(Field::Locked),
+            "disputed_count" => Ok(Field::DisputedCount),
+            "chargeback_count" => Ok(Field::ChargebackCount),
+            "flagged" => Ok(Field::Flagged),
+            "last_tx_id" => Ok(Field::LastTxId),
+            "fees" => Ok(Field::Fees),
+            "interest" => Ok(Field::Interest),
+            "pending" => Ok(Field::Pending),
+            "overdrawn" => Ok(Field::Overdrawn),
+            "currency" => Ok(Field::Currency),
+            "status" => Ok(Field::Status),
+            "name" => Ok(Field::Name),
+            "tier" => Ok(Field::Tier),
+            "home_currency" => Ok(Field::HomeCurrency),
+            _ => Err(format!("Unknown column: {}.", s)),
+        }
+    }
+}
+
+impl fmt::Display for Cell<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Cell::ClientId(client_id) => client_id.fmt(f),
+            Cell::Amount(amount) => amount.fmt(f),
+            Cell::Bool(value) => value.fmt(f),
+            Cell::Count(count) => count.fmt(f),
+            Cell::TransactionId(Some(transaction_id)) => transaction_id.fmt(f),
+            Cell::TransactionId(None) => Ok(()),
+            Cell::Currency(Some(currency)) => currency.fmt(f),
+            Cell::Currency(None) => Ok(()),
+            Cell::Status(status) => f.write_str(status.name()),
+            Cell::Text(text) => f.write_str(text.unwrap_or_default()),
+        }
+    }
+}
+
+// Amounts are serialized as strings by rust_decimal, which spares consumers
+// from parsing them as floats and losing precision.
+impl Serialize for Cell<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Cell::ClientId(client_id) => client_id.serialize(serializer),
+            Cell::Amount(amount) => Serialize::serialize(amount, serializer),
+            Cell::Bool(value) => value.serialize(serializer),
+            Cell::Count(count) => count.serialize(serializer),
+            Cell::TransactionId(transaction_id) => transaction_id.serialize(serializer),
+            Cell::Currency(currency) => currency.serialize(serializer),
+            Cell::Status(status) => serializer.serialize_str(status.name()),
+            Cell::Text(text) => text.serialize(serializer),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_parse_columns() {
+        assert_eq!(
+            Ok(vec![
+                Column::new(Field::Client),
+                Column {
+                    field: Field::Available,
+                    header: String::from("Available Funds"),
+                },
+                Column::new(Field::DisputedCount),
+            ]),
+            parse_columns("client, available:Available Funds ,disputed_count"),
+        );
+    }
+
+    #[test]
+    fn test_parse_columns_unknown_field() {
+        assert_eq!(
+            Err(String::from("Unknown column: balance.")),
+            parse_columns("client,balance")
+        );
+    }
+
+    #[test]
+    fn test_last_tx_id_cell() {
+        assert_eq!("7", Cell::TransactionId(Some(7)).to_string());
+        assert_eq!("", Cell::TransactionId(None).to_string());
+        assert_eq!(
+            "null",
+            serde_json::to_string(&Cell::TransactionId(None)).expect("Expected valid JSON")
+        );
+    }
+}
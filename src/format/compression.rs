@@ -0,0 +1,137 @@
+use flate2::write::GzEncoder;
+use std::{
+    io::{self, Write},
+    path::Path,
+    str::FromStr,
+};
+
+// Compression applied on top of whichever output format we're writing. Large
+// reports are always compressed straight afterwards anyway, so we may as well
+// do it while writing them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    // Infers the compression from a file extension, e.g. `report.csv.gz`.
+    pub fn from_path(path: impl AsRef<Path>) -> Self {
+        match path.as_ref().extension().and_then(|ext| ext.to_str()) {
+            Some("gz") => Compression::Gzip,
+            Some("zst") => Compression::Zstd,
+            _ => Compression::None,
+        }
+    }
+}
+
+impl FromStr for Compression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Compression::None),
+            "gzip" | "gz" => Ok(Compression::Gzip),
+            "zstd" | "zst" => Ok(Compression::Zstd),
+            _ => Err(format!("Unknown compression: {}.", s)),
+        }
+    }
+}
+
+// Wraps a writer, compressing whatever is written to it. `finish` must be
+// called once writing is done so that the compressor can write its trailer;
+// we don't rely on dropping for that because errors would be swallowed.
+pub enum CompressedWriter<W: Write> {
+    None(W),
+    Gzip(GzEncoder<W>),
+    Zstd(zstd::Encoder<'static, W>),
+}
+
+impl<W: Write> CompressedWriter<W> {
+    pub fn new(writer: W, compression: Compression) -> io::Result<Self> {
+        let compressed_writer = match compression {
+            Compression::None => CompressedWriter::None(writer),
+            Compression::Gzip => {
+                CompressedWriter::Gzip(GzEncoder::new(writer, flate2::Compression::default()))
+            }
+            Compression::Zstd => CompressedWriter::Zstd(zstd::Encoder::new(writer, 0)?),
+        };
+
+        Ok(compressed_writer)
+    }
+
+    pub fn finish(self) -> io::Result<W> {
+        match self {
+            CompressedWriter::None(mut writer) => {
+                writer.flush()?;
+                Ok(writer)
+            }
+            CompressedWriter::Gzip(encoder) => encoder.finish(),
+            CompressedWriter::Zstd(encoder) => encoder.finish(),
+        }
+    }
+}
+
+impl<W: Write> Write for CompressedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            CompressedWriter::None(writer) => writer.write(buf),
+            CompressedWriter::Gzip(encoder) => encoder.write(buf),
+            CompressedWriter::Zstd(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            CompressedWriter::None(writer) => writer.flush(),
+            CompressedWriter::Gzip(encoder) => encoder.flush(),
+            CompressedWriter::Zstd(encoder) => encoder.flush(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use flate2::read::GzDecoder;
+    use pretty_assertions::assert_eq;
+    use std::io::Read;
+
+    #[test]
+    fn test_compression_from_path() {
+        assert_eq!(Compression::Gzip, Compression::from_path("report.csv.gz"));
+        assert_eq!(Compression::Zstd, Compression::from_path("dir/report.zst"));
+        assert_eq!(Compression::None, Compression::from_path("report.csv"));
+        assert_eq!(Compression::None, Compression::from_path("report"));
+    }
+
+    #[test]
+    fn test_gzip_round_trip() {
+        let mut writer =
+            CompressedWriter::new(Vec::new(), Compression::Gzip).expect("Expected no errors.");
+        writer
+            .write_all(b"client,available\n")
+            .expect("Expected no errors.");
+        let compressed = writer.finish().expect("Expected no errors.");
+
+        let mut output = String::new();
+        GzDecoder::new(compressed.as_slice())
+            .read_to_string(&mut output)
+            .expect("Expected valid gzip.");
+        assert_eq!("client,available\n", output);
+    }
+
+    #[test]
+    fn test_zstd_round_trip() {
+        let mut writer =
+            CompressedWriter::new(Vec::new(), Compression::Zstd).expect("Expected no errors.");
+        writer
+            .write_all(b"client,available\n")
+            .expect("Expected no errors.");
+        let compressed = writer.finish().expect("Expected no errors.");
+
+        let output = zstd::decode_all(compressed.as_slice()).expect("Expected valid zstd.");
+        assert_eq!(b"client,available\n".to_vec(), output);
+    }
+}
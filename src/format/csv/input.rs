@@ -1,79 +1,387 @@
 use core::str::FromStr;
 
 use serde::Deserialize;
-use std::{error::Error, io::Read};
+use std::{collections::HashMap, error::Error, io::Read, iter};
 
-use crate::model::{Amount, ClientID, DisputeStepKind, Event, TransactionID, TransactionKind};
+use crate::{
+    format::error::ParseError,
+    model::{
+        Amount, ClientID, Currency, DisputeStepKind, Event, Source, SourcedEvent, Timestamp,
+        TransactionID, TransactionKind,
+    },
+    system::{ReportKey, ReportedClient},
+};
 
 #[derive(Deserialize)]
-// intermediary struct for deserializing CSV
+// intermediary struct for deserializing CSV, and JSON lines too since they have
+// the same fields
 pub struct CsvEvent {
     #[serde(rename = "type")]
     kind: String,
+    // Account closures and client registrations aren't about any one
+    // transaction, so they can leave this empty.
     #[serde(rename = "tx")]
-    transaction_id: TransactionID,
+    transaction_id: Option<TransactionID>,
     #[serde(rename = "client")]
     client_id: ClientID,
     // We could use a custom deserializer that works with the rust decimal library's serde
     // deserializer, but it's pretty hairy to have that gracefully deal with empty strings, so
     // I'm just having serde treat this as a string and then I'm manually mapping to a decimal
-    // afterwards.
+    // afterwards. Dispute steps and the like don't have one, and JSON lines
+    // can leave it out for those.
+    #[serde(default)]
     amount: String,
+    // Only transfers have a receiving client, and inputs without any transfers
+    // can leave the column out entirely.
+    #[serde(rename = "to_client", default)]
+    to_client_id: Option<ClientID>,
+    // Likewise, inputs in a single currency can leave this out, in which case
+    // everything is in the unnamed currency. Dispute steps act on whatever
+    // currency their transaction was in, so it's ignored for those.
+    #[serde(default)]
+    currency: String,
+    // Only conversions have these, converting from `currency` into
+    // `to_currency` at `rate`, except that interest has a rate too. Rates are
+    // kept as strings for the same reason as amounts.
+    #[serde(default)]
+    to_currency: String,
+    #[serde(default)]
+    rate: String,
+    // Only adjustments have a reason code.
+    #[serde(default)]
+    reason: String,
+    // Deposits and withdrawals can say who was on the other side of them, or
+    // leave it empty.
+    #[serde(default)]
+    counterparty: String,
+    // Only client registrations have these, and the tier can be left empty.
+    #[serde(default)]
+    name: String,
+    #[serde(default)]
+    tier: String,
+    // When the event happened, in seconds since the Unix epoch. Inputs that
+    // don't need it can leave the column out.
+    #[serde(default)]
+    timestamp: Option<Timestamp>,
 }
 
-// Returns an iterator which itself yields Events. It takes a reader that
-// reads a CSV file.
-pub fn parse_events(reader: impl Read) -> impl Iterator<Item = Result<Event, Box<dyn Error>>> {
-    csv::ReaderBuilder::new()
+// intermediary struct for deserializing a previously written report. Amounts in
+// a report are never empty so the rust decimal library's deserializer does the
+// job here.
+#[derive(Deserialize)]
+struct CsvReportedClient {
+    client: ClientID,
+    // only there for reports with balances in named currencies
+    #[serde(default)]
+    currency: Currency,
+    available: Amount,
+    held: Amount,
+    total: Amount,
+    locked: bool,
+}
+
+// intermediary struct for deserializing a client's credit limit
+#[derive(Deserialize)]
+struct CsvCreditLimit {
+    client: ClientID,
+    credit_limit: Amount,
+}
+
+// Reads each client's credit limit, i.e. how far their withdrawals may take
+// them below zero.
+pub fn parse_credit_limits(reader: impl Read) -> Result<HashMap<ClientID, Amount>, Box<dyn Error>> {
+    let mut csv_reader = csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .from_reader(reader);
+
+    csv_reader
+        .deserialize::<CsvCreditLimit>()
+        .map(|row| {
+            let row = row?;
+            if row.credit_limit.is_sign_negative() {
+                return Err(ParseError::NegativeCreditLimit(row.client).into());
+            }
+            Ok((row.client, row.credit_limit))
+        })
+        .collect()
+}
+
+// Reads a report written with the default columns back in, keyed by client ID
+// and currency. Unlike the events, reports are small enough to read all at
+// once.
+pub fn parse_report(
+    reader: impl Read,
+) -> Result<HashMap<ReportKey, ReportedClient>, Box<dyn Error>> {
+    let mut csv_reader = csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .from_reader(reader);
+
+    csv_reader
+        .deserialize::<CsvReportedClient>()
+        .map(|row| {
+            let row = row?;
+            Ok((
+                (row.client, row.currency),
+                ReportedClient {
+                    available: row.available,
+                    held: row.held,
+                    total: row.total,
+                    locked: row.locked,
+                },
+            ))
+        })
+        .collect()
+}
+
+// Returns an iterator which itself yields Events, each with the line it was
+// read from. It takes a reader that reads a CSV file.
+pub fn parse_events(reader: impl Read) -> impl Iterator<Item = Result<SourcedEvent, ParseError>> {
+    parse_sourced_events(reader, false)
+}
+
+// Like `parse_events`, but each event also keeps a copy of the record it was
+// parsed from, so that rejections can include it. That costs an allocation per
+// event, hence being opt-in.
+pub fn parse_events_keeping_records(
+    reader: impl Read,
+) -> impl Iterator<Item = Result<SourcedEvent, ParseError>> {
+    parse_sourced_events(reader, true)
+}
+
+fn parse_sourced_events(
+    reader: impl Read,
+    keep_records: bool,
+) -> impl Iterator<Item = Result<SourcedEvent, ParseError>> {
+    let mut csv_reader = csv::ReaderBuilder::new()
         .trim(csv::Trim::All) // this handles whitespace for us
-        .from_reader(reader)
-        .into_deserialize()
-        .map(|result| parse_csv_event(result.map_err(|e| e.to_string())?))
+        .from_reader(reader);
+    // we read the headers up front so that we can deserialize each record
+    // ourselves; this is what `into_deserialize` would do for us, but doing it
+    // by hand means we get to hold onto the record's position and contents.
+    let (headers, mut header_error) = match csv_reader.headers() {
+        Ok(headers) => (Some(headers.clone()), None),
+        Err(e) => (None, Some(ParseError::Header(e))),
+    };
+    let mut records = csv_reader.into_records();
+
+    iter::from_fn(move || {
+        if let Some(e) = header_error.take() {
+            return Some(Err(e));
+        }
+        let headers = headers.as_ref()?;
+        let record = match records.next()? {
+            Ok(record) => record,
+            Err(e) => return Some(Err(e.into())),
+        };
+
+        Some(parse_record(&record, headers, keep_records))
+    })
+}
+
+fn parse_record(
+    record: &csv::StringRecord,
+    headers: &csv::StringRecord,
+    keep_records: bool,
+) -> Result<SourcedEvent, ParseError> {
+    let source = Source {
+        // note that the CSV reader skips blank lines without counting them, so
+        // line numbers after a blank line are off by one
+        line: record.position().map_or(0, csv::Position::line),
+        byte: record.position().map_or(0, csv::Position::byte),
+        record: None,
+    };
+    parse_record_at(record, headers, source, keep_records)
+}
+
+// Like `parse_record`, for a record that was read on its own (see
+// `format::async_io`), and so doesn't know where it was in the input.
+pub(crate) fn parse_record_at(
+    record: &csv::StringRecord,
+    headers: &csv::StringRecord,
+    source: Source,
+    keep_records: bool,
+) -> Result<SourcedEvent, ParseError> {
+    let csv_event: CsvEvent = record.deserialize(Some(headers))?;
+    let source = Source {
+        // the fields have already been trimmed, so this is the record as we
+        // understood it rather than byte-for-byte what was in the file
+        record: keep_records.then(|| record.iter().collect::<Vec<_>>().join(",")),
+        ..source
+    };
+
+    let timestamp = csv_event.timestamp;
+    Ok(SourcedEvent {
+        event: parse_csv_event(csv_event)?,
+        source: Some(source),
+        timestamp,
+    })
+}
+
+impl CsvEvent {
+    pub(crate) fn timestamp(&self) -> Option<Timestamp> {
+        self.timestamp
+    }
 }
 
-fn parse_csv_event(csv_event: CsvEvent) -> Result<Event, Box<dyn Error>> {
+pub(crate) fn parse_csv_event(csv_event: CsvEvent) -> Result<Event, ParseError> {
+    if csv_event.kind == "close_account" {
+        return Ok(Event::AccountClosure {
+            client_id: csv_event.client_id,
+        });
+    }
+    if csv_event.kind == "client" {
+        return Ok(Event::ClientRegistration {
+            client_id: csv_event.client_id,
+            name: match csv_event.name.as_str() {
+                "" => return Err(ParseError::MissingName),
+                _ => csv_event.name,
+            },
+            tier: Some(csv_event.tier).filter(|tier| !tier.is_empty()),
+            currency: parse_currency(&csv_event.currency)?,
+        });
+    }
+
+    let transaction_id = csv_event
+        .transaction_id
+        .ok_or(ParseError::MissingTransactionId)?;
+    let currency = parse_currency(&csv_event.currency)?;
+    let counterparty = Some(csv_event.counterparty).filter(|counterparty| !counterparty.is_empty());
     let event = match csv_event.kind.as_ref() {
         "deposit" => Event::Transaction {
             kind: TransactionKind::Deposit,
-            transaction_id: csv_event.transaction_id,
+            transaction_id,
             client_id: csv_event.client_id,
+            currency,
             amount: parse_amount(&csv_event.amount)?,
+            counterparty: counterparty.clone(),
         },
         "withdrawal" => Event::Transaction {
             kind: TransactionKind::Withdrawal,
-            transaction_id: csv_event.transaction_id,
+            transaction_id,
             client_id: csv_event.client_id,
+            currency,
             amount: parse_amount(&csv_event.amount)?,
+            counterparty: counterparty.clone(),
         },
         "dispute" => Event::DisputeStep {
             kind: DisputeStepKind::Dispute,
-            transaction_id: csv_event.transaction_id,
+            transaction_id,
             client_id: csv_event.client_id,
+            amount: if csv_event.amount.is_empty() {
+                None
+            } else {
+                Some(parse_amount(&csv_event.amount)?)
+            },
         },
         "resolve" => Event::DisputeStep {
             kind: DisputeStepKind::Resolve,
-            transaction_id: csv_event.transaction_id,
+            transaction_id,
             client_id: csv_event.client_id,
+            amount: None,
         },
         "chargeback" => Event::DisputeStep {
             kind: DisputeStepKind::Chargeback,
-            transaction_id: csv_event.transaction_id,
+            transaction_id,
+            client_id: csv_event.client_id,
+            amount: None,
+        },
+        "deposit_pending" => Event::PendingDeposit {
+            transaction_id,
+            client_id: csv_event.client_id,
+            currency,
+            amount: parse_amount(&csv_event.amount)?,
+            counterparty: counterparty.clone(),
+        },
+        "settle" => Event::Settlement {
+            transaction_id,
+            client_id: csv_event.client_id,
+        },
+        "reversal" => Event::Reversal {
+            transaction_id,
+            client_id: csv_event.client_id,
+        },
+        "chargeback_reversal" => Event::ChargebackReversal {
+            transaction_id,
             client_id: csv_event.client_id,
         },
-        _ => return Err(format!("Unknown event kind: {}.", csv_event.kind).into()),
+        "transfer" => Event::Transfer {
+            transaction_id,
+            from_client_id: csv_event.client_id,
+            to_client_id: csv_event.to_client_id.ok_or(ParseError::MissingRecipient)?,
+            currency,
+            amount: parse_amount(&csv_event.amount)?,
+        },
+        "fee" => Event::Fee {
+            transaction_id,
+            client_id: csv_event.client_id,
+            currency,
+            amount: parse_amount(&csv_event.amount)?,
+        },
+        "convert" => Event::Conversion {
+            transaction_id,
+            client_id: csv_event.client_id,
+            from_currency: currency,
+            to_currency: parse_currency(&csv_event.to_currency)?,
+            amount: parse_amount(&csv_event.amount)?,
+            rate: parse_rate(&csv_event.rate)?,
+        },
+        "adjustment" => Event::Adjustment {
+            transaction_id,
+            client_id: csv_event.client_id,
+            currency,
+            amount: parse_signed_amount(&csv_event.amount)?,
+            reason: match csv_event.reason.as_str() {
+                "" => return Err(ParseError::MissingReason),
+                _ => csv_event.reason,
+            },
+        },
+        "interest" => Event::Interest {
+            transaction_id,
+            client_id: csv_event.client_id,
+            currency,
+            rate: parse_rate(&csv_event.rate)?,
+        },
+        _ => return Err(ParseError::UnknownEventKind(csv_event.kind)),
     };
 
     Ok(event)
 }
 
-fn parse_amount(amount: &str) -> Result<Amount, Box<dyn Error>> {
+fn parse_amount(amount: &str) -> Result<Amount, ParseError> {
+    let parsed = parse_signed_amount(amount)?;
+    if parsed <= Amount::ZERO {
+        return Err(ParseError::NonPositiveAmount(amount.to_string()));
+    }
+
+    Ok(parsed)
+}
+
+// Only adjustments can take money away with a negative amount.
+fn parse_signed_amount(amount: &str) -> Result<Amount, ParseError> {
     if amount.is_empty() {
-        return Err("Missing amount.".into());
+        return Err(ParseError::MissingAmount);
     }
 
     Ok(Amount::from_str(amount)?)
 }
 
+fn parse_rate(rate: &str) -> Result<Amount, ParseError> {
+    if rate.is_empty() {
+        return Err(ParseError::MissingRate);
+    }
+
+    let parsed = Amount::from_str(rate)?;
+    if parsed <= Amount::ZERO {
+        return Err(ParseError::InvalidRate(rate.to_string()));
+    }
+
+    Ok(parsed)
+}
+
+fn parse_currency(currency: &str) -> Result<Currency, ParseError> {
+    currency.parse().map_err(ParseError::InvalidCurrency)
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -87,6 +395,46 @@ mod test {
         assert!(events_iter.next().is_none());
     }
 
+    #[test]
+    fn test_parse_transfer() {
+        let input = concat!(
+            "type,client,tx,amount,to_client\n",
+            "deposit,1,1,10,\n",
+            "transfer,1,2,2.5,3\n",
+            "transfer,1,3,2.5,\n",
+        );
+
+        let events = parse_events(input.as_bytes())
+            .map(|result| {
+                result
+                    .map(|sourced_event| sourced_event.event)
+                    .map_err(|e| e.to_string())
+            })
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            vec![
+                Ok(Event::Transaction {
+                    kind: TransactionKind::Deposit,
+                    client_id: 1,
+                    transaction_id: 1,
+                    currency: Currency::default(),
+                    amount: dec!(10),
+                    counterparty: None,
+                }),
+                Ok(Event::Transfer {
+                    transaction_id: 2,
+                    from_client_id: 1,
+                    to_client_id: 3,
+                    currency: Currency::default(),
+                    amount: dec!(2.5),
+                }),
+                Err(String::from("Missing receiving client for transfer.")),
+            ],
+            events
+        );
+    }
+
     #[test]
     fn test_parse_events_all_event_types() {
         let input = concat!(
@@ -96,10 +444,16 @@ mod test {
             "dispute,7,8,\n",
             "resolve,9,10,\n",
             "chargeback,11,12,\n",
+            "fee,13,14,0.5\n",
+            "reversal,15,16,\n",
+            "chargeback_reversal,17,18,\n",
+            "deposit_pending,19,20,7.5\n",
+            "settle,21,22,\n",
         );
 
         let events_iter = parse_events(input.as_bytes());
         let result = events_iter
+            .map(|result| result.map(|sourced_event| sourced_event.event))
             .collect::<Result<Vec<_>, _>>()
             .expect("Expected no errors.");
 
@@ -109,34 +463,297 @@ mod test {
                     kind: TransactionKind::Deposit,
                     client_id: 1,
                     transaction_id: 2,
+                    currency: Currency::default(),
                     amount: dec!(3.12345),
+                    counterparty: None,
                 },
                 Event::Transaction {
                     kind: TransactionKind::Withdrawal,
                     client_id: 4,
                     transaction_id: 5,
+                    currency: Currency::default(),
                     amount: dec!(6),
+                    counterparty: None,
                 },
                 Event::DisputeStep {
                     kind: DisputeStepKind::Dispute,
                     client_id: 7,
                     transaction_id: 8,
+                    amount: None,
                 },
                 Event::DisputeStep {
                     kind: DisputeStepKind::Resolve,
                     client_id: 9,
                     transaction_id: 10,
+                    amount: None,
                 },
                 Event::DisputeStep {
                     kind: DisputeStepKind::Chargeback,
                     client_id: 11,
                     transaction_id: 12,
-                }
+                    amount: None,
+                },
+                Event::Fee {
+                    client_id: 13,
+                    transaction_id: 14,
+                    currency: Currency::default(),
+                    amount: dec!(0.5),
+                },
+                Event::Reversal {
+                    client_id: 15,
+                    transaction_id: 16,
+                },
+                Event::ChargebackReversal {
+                    client_id: 17,
+                    transaction_id: 18,
+                },
+                Event::PendingDeposit {
+                    client_id: 19,
+                    transaction_id: 20,
+                    currency: Currency::default(),
+                    amount: dec!(7.5),
+                    counterparty: None,
+                },
+                Event::Settlement {
+                    client_id: 21,
+                    transaction_id: 22,
+                },
+            ],
+            result,
+        );
+    }
+
+    #[test]
+    fn test_parse_currency() {
+        let input = concat!(
+            "type,client,tx,amount,currency\n",
+            "deposit,1,1,2.5,eur\n",
+            "deposit,1,2,2.5,\n",
+            "deposit,1,3,2.5,euro\n",
+        );
+
+        let result = parse_events(input.as_bytes())
+            .map(|result| {
+                result
+                    .map(|sourced_event| sourced_event.event)
+                    .map_err(|e| e.to_string())
+            })
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            vec![
+                Ok(Event::Transaction {
+                    kind: TransactionKind::Deposit,
+                    client_id: 1,
+                    transaction_id: 1,
+                    currency: "EUR".parse().expect("Expected a valid currency."),
+                    amount: dec!(2.5),
+                    counterparty: None,
+                }),
+                Ok(Event::Transaction {
+                    kind: TransactionKind::Deposit,
+                    client_id: 1,
+                    transaction_id: 2,
+                    currency: Currency::default(),
+                    amount: dec!(2.5),
+                    counterparty: None,
+                }),
+                Err(String::from("Invalid currency: euro.")),
             ],
             result,
         );
     }
 
+    #[test]
+    fn test_parse_conversion() {
+        let input = concat!(
+            "type,client,tx,amount,currency,to_currency,rate\n",
+            "convert,1,1,10,eur,usd,1.085\n",
+            "convert,1,2,10,eur,usd,\n",
+            "convert,1,3,10,eur,usd,0\n",
+        );
+
+        let result = parse_events(input.as_bytes())
+            .map(|result| {
+                result
+                    .map(|sourced_event| sourced_event.event)
+                    .map_err(|e| e.to_string())
+            })
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            vec![
+                Ok(Event::Conversion {
+                    transaction_id: 1,
+                    client_id: 1,
+                    from_currency: "EUR".parse().expect("Expected a valid currency."),
+                    to_currency: "USD".parse().expect("Expected a valid currency."),
+                    amount: dec!(10),
+                    rate: dec!(1.085),
+                }),
+                Err(String::from("Missing rate.")),
+                Err(String::from("Invalid rate: 0.")),
+            ],
+            result,
+        );
+    }
+
+    #[test]
+    fn test_parse_partial_dispute() {
+        let input = concat!(
+            "type,client,tx,amount\n",
+            "dispute,1,1,2.5\n",
+            "dispute,1,2,\n",
+        );
+
+        let amounts = parse_events(input.as_bytes())
+            .map(|result| {
+                result.map(|sourced_event| match sourced_event.event {
+                    Event::DisputeStep { amount, .. } => amount,
+                    event => panic!("Unexpected event: {:?}", event),
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()
+            .expect("Expected no errors.");
+        assert_eq!(vec![Some(dec!(2.5)), None], amounts);
+    }
+
+    #[test]
+    fn test_parse_account_closure() {
+        let input = concat!(
+            "type,client,tx,amount\n",
+            "close_account,1,,\n",
+            "deposit,1,,1\n",
+        );
+
+        let result = parse_events(input.as_bytes())
+            .map(|result| {
+                result
+                    .map(|sourced_event| sourced_event.event)
+                    .map_err(|e| e.to_string())
+            })
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            vec![
+                Ok(Event::AccountClosure { client_id: 1 }),
+                Err(String::from("Missing transaction ID.")),
+            ],
+            result,
+        );
+    }
+
+    #[test]
+    fn test_parse_client_registration() {
+        let input = concat!(
+            "type,client,tx,amount,currency,name,tier\n",
+            "client,1,,,EUR,Ada Lovelace,gold\n",
+            "client,2,,,,Charles Babbage,\n",
+            "client,3,,,,,gold\n",
+        );
+
+        let result = parse_events(input.as_bytes())
+            .map(|result| {
+                result
+                    .map(|sourced_event| sourced_event.event)
+                    .map_err(|e| e.to_string())
+            })
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            vec![
+                Ok(Event::ClientRegistration {
+                    client_id: 1,
+                    name: String::from("Ada Lovelace"),
+                    tier: Some(String::from("gold")),
+                    currency: "EUR".parse().expect("Expected a valid currency."),
+                }),
+                Ok(Event::ClientRegistration {
+                    client_id: 2,
+                    name: String::from("Charles Babbage"),
+                    tier: None,
+                    currency: Currency::default(),
+                }),
+                Err(String::from("Missing name for client.")),
+            ],
+            result,
+        );
+    }
+
+    #[test]
+    fn test_parse_adjustment() {
+        let input = concat!(
+            "type,client,tx,amount,reason\n",
+            "adjustment,1,1,-2.5,write_off\n",
+            "adjustment,1,2,2.5,\n",
+        );
+
+        let result = parse_events(input.as_bytes())
+            .map(|result| {
+                result
+                    .map(|sourced_event| sourced_event.event)
+                    .map_err(|e| e.to_string())
+            })
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            vec![
+                Ok(Event::Adjustment {
+                    transaction_id: 1,
+                    client_id: 1,
+                    currency: Currency::default(),
+                    amount: dec!(-2.5),
+                    reason: String::from("write_off"),
+                }),
+                Err(String::from("Missing reason for adjustment.")),
+            ],
+            result,
+        );
+    }
+
+    #[test]
+    fn test_parse_non_positive_amount() {
+        let input = concat!(
+            "type,client,tx,amount\n",
+            "deposit,1,1,-5\n",
+            "withdrawal,1,2,0\n",
+            "fee,1,3,-1\n",
+        );
+
+        let result = parse_events(input.as_bytes())
+            .map(|result| result.map(|_| ()).map_err(|e| e.to_string()))
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            vec![
+                Err(String::from("Amount must be positive: -5.")),
+                Err(String::from("Amount must be positive: 0.")),
+                Err(String::from("Amount must be positive: -1.")),
+            ],
+            result,
+        );
+    }
+
+    #[test]
+    fn test_parse_interest() {
+        let input = concat!("type,client,tx,amount,rate\n", "interest,1,1,,0.0001\n");
+
+        let result = parse_events(input.as_bytes())
+            .map(|result| result.map(|sourced_event| sourced_event.event))
+            .collect::<Result<Vec<_>, _>>()
+            .expect("Expected no errors.");
+
+        assert_eq!(
+            vec![Event::Interest {
+                transaction_id: 1,
+                client_id: 1,
+                currency: Currency::default(),
+                rate: dec!(0.0001),
+            }],
+            result,
+        );
+    }
+
     #[test]
     fn test_parse_events_malformed_row() {
         let input = concat!(
@@ -149,15 +766,17 @@ mod test {
         let result = events_iter.collect::<Vec<_>>();
         assert_eq!(3, result.len());
 
-        match result.get(0) {
+        match result.first() {
             Some(Ok(event)) => assert_eq!(
                 Event::Transaction {
                     kind: TransactionKind::Deposit,
                     client_id: 1,
                     transaction_id: 1,
+                    currency: Currency::default(),
                     amount: dec!(1),
+                    counterparty: None,
                 },
-                *event,
+                event.event,
             ),
             Some(Err(err)) => panic!("Unexpected error: {}", err),
             None => panic!("Expected Some"),
@@ -171,9 +790,11 @@ mod test {
                     kind: TransactionKind::Deposit,
                     client_id: 2,
                     transaction_id: 2,
+                    currency: Currency::default(),
                     amount: dec!(2),
+                    counterparty: None,
                 },
-                *event,
+                event.event,
             ),
             Some(Err(err)) => panic!("Unexpected error: {}", err),
             None => panic!("Expected Some"),
@@ -202,9 +823,161 @@ mod test {
         assert_eq!(1, result.len());
 
         match result.first() {
-            Some(Err(err)) => assert_eq!("Missing amount.", err.to_string()),
+            Some(Err(err)) => {
+                assert_eq!("Missing amount.", err.to_string());
+                assert!(matches!(err, ParseError::MissingAmount));
+            }
             Some(Ok(_)) => panic!("Expected failed event parse"),
             None => panic!("Expected Some"),
         };
     }
+
+    #[test]
+    fn test_parse_events_sources() {
+        let input = concat!(
+            "type,client,tx,    amount\n",
+            "deposit, 1,1,  1.5\n",
+            "dispute,1,1,\n",
+        );
+
+        let sources = parse_events(input.as_bytes())
+            .map(|result| result.map(|sourced_event| sourced_event.source))
+            .collect::<Result<Vec<_>, _>>()
+            .expect("Expected no errors.");
+        assert_eq!(
+            vec![
+                Some(Source {
+                    line: 2,
+                    byte: 26,
+                    record: None
+                }),
+                Some(Source {
+                    line: 3,
+                    byte: 45,
+                    record: None
+                }),
+            ],
+            sources,
+        );
+
+        let sources = parse_events_keeping_records(input.as_bytes())
+            .map(|result| result.map(|sourced_event| sourced_event.source))
+            .collect::<Result<Vec<_>, _>>()
+            .expect("Expected no errors.");
+        assert_eq!(
+            vec![
+                Some(Source {
+                    line: 2,
+                    byte: 26,
+                    record: Some(String::from("deposit,1,1,1.5")),
+                }),
+                Some(Source {
+                    line: 3,
+                    byte: 45,
+                    record: Some(String::from("dispute,1,1,")),
+                }),
+            ],
+            sources,
+        );
+    }
+
+    #[test]
+    fn test_parse_events_timestamps() {
+        let input = concat!(
+            "type,client,tx,amount,timestamp\n",
+            "deposit,1,1,1.5,1700000000\n",
+            "dispute,1,1,,\n",
+        );
+
+        let timestamps = parse_events(input.as_bytes())
+            .map(|result| result.map(|sourced_event| sourced_event.timestamp))
+            .collect::<Result<Vec<_>, _>>()
+            .expect("Expected no errors.");
+        assert_eq!(vec![Some(1700000000), None], timestamps);
+    }
+
+    #[test]
+    fn test_parse_report() {
+        let input = concat!(
+            "client,available,held,total,locked\n",
+            "1,1.6111,0.0000,1.6111,false\n",
+            "2, 2.0000, 1.0000, 3.0000, true\n"
+        );
+
+        let report = parse_report(input.as_bytes()).expect("Expected no errors.");
+
+        assert_eq!(
+            HashMap::from([
+                (
+                    (1, Currency::default()),
+                    ReportedClient {
+                        available: dec!(1.6111),
+                        held: dec!(0),
+                        total: dec!(1.6111),
+                        locked: false,
+                    }
+                ),
+                (
+                    (2, Currency::default()),
+                    ReportedClient {
+                        available: dec!(2),
+                        held: dec!(1),
+                        total: dec!(3),
+                        locked: true,
+                    }
+                ),
+            ]),
+            report
+        );
+
+        let input = concat!(
+            "client,currency,available,held,total,locked\n",
+            "1,,1.0000,0.0000,1.0000,false\n",
+            "1,EUR,2.0000,0.0000,2.0000,false\n",
+        );
+
+        let report = parse_report(input.as_bytes()).expect("Expected no errors.");
+
+        assert_eq!(
+            HashMap::from([
+                (
+                    (1, Currency::default()),
+                    ReportedClient {
+                        available: dec!(1),
+                        held: dec!(0),
+                        total: dec!(1),
+                        locked: false,
+                    }
+                ),
+                (
+                    (1, "EUR".parse().expect("Expected a valid currency.")),
+                    ReportedClient {
+                        available: dec!(2),
+                        held: dec!(0),
+                        total: dec!(2),
+                        locked: false,
+                    }
+                ),
+            ]),
+            report
+        );
+    }
+
+    #[test]
+    fn test_parse_credit_limits() {
+        let input = concat!("client,credit_limit\n", "1,100\n", "2, 0.5\n");
+
+        assert_eq!(
+            HashMap::from([(1, dec!(100)), (2, dec!(0.5))]),
+            parse_credit_limits(input.as_bytes()).expect("Expected no errors.")
+        );
+
+        let input = concat!("client,credit_limit\n", "1,-100\n");
+        assert_eq!(
+            "Credit limit for client 1 cannot be negative.",
+            parse_credit_limits(input.as_bytes())
+                .expect_err("Expected an error.")
+                .to_string()
+        );
+    }
 }
@@ -1,52 +1,193 @@
 use serde::Serialize;
 use std::{collections::HashMap, error::Error, io::Write};
 
-use crate::model::{Amount, Client, ClientID};
+use crate::{
+    format::{normalize_amount, ordered_rows, report_columns, ReportConfig},
+    model::{Amount, Client, ClientID, Currency, DisputeStatus, Transaction, TransactionID},
+    system::{ClientDelta, CounterpartyExposure, Reconciliation},
+};
 
-// Intermediary representation of a client for serialization.
+// Intermediary representation of a disputed or charged back transaction for
+// serialization.
 #[derive(Serialize)]
-struct CsvClient {
+struct CsvDisputedTransaction {
+    tx: TransactionID,
     client: ClientID,
-    available: Amount,
-    held: Amount,
-    total: Amount,
-    locked: bool,
+    #[serde(rename = "type")]
+    kind: &'static str,
+    amount: Amount,
+    currency: Currency,
+    status: &'static str,
+    // how much of the amount is currently disputed and how much has been
+    // charged back, which can each be less than all of it
+    disputed: Amount,
+    charged_back: Amount,
+    // the events it was last disputed, resolved and charged back by, empty
+    // if it never was
+    disputed_at: Option<u64>,
+    resolved_at: Option<u64>,
+    charged_back_at: Option<u64>,
+}
+
+// Intermediary representation of a reconciliation for serialization.
+#[derive(Serialize)]
+struct CsvReconciliation {
+    currency: Currency,
+    deposits: Amount,
+    withdrawals: Amount,
+    charged_back: Amount,
+    reversed: Amount,
+    fees: Amount,
+    interest: Amount,
+    adjusted: Amount,
+    converted: Amount,
+    expected_total: Amount,
+    actual_total: Amount,
+    discrepancy: Amount,
+}
+
+// Intermediary representation of a counterparty's exposure for serialization.
+#[derive(Serialize)]
+struct CsvCounterpartyExposure<'a> {
+    counterparty: &'a str,
+    currency: Currency,
+    transactions: u64,
+    volume: Amount,
+    disputed_transactions: u64,
+    disputed: Amount,
+    charged_back_transactions: u64,
+    charged_back: Amount,
+}
+
+// Intermediary representation of how a client changed between two reports for
+// serialization.
+#[derive(Serialize)]
+struct CsvClientDelta {
+    client: ClientID,
+    // only written when some balance is in a named currency, like the report
+    #[serde(skip_serializing_if = "Option::is_none")]
+    currency: Option<Currency>,
+    available_change: Amount,
+    held_change: Amount,
+    total_change: Amount,
+    newly_locked: bool,
 }
 
 // Takes the resultant clients after processing events, and writes them to the
-// given writer in CSV form.
+// given writer in CSV form with the configured columns.
 pub fn write_report(
-    clients_by_id: HashMap<ClientID, Client>,
+    clients_by_id: &HashMap<ClientID, Client>,
     writer: impl Write,
+    config: &ReportConfig,
 ) -> Result<(), Box<dyn Error>> {
-    let csv_clients_iter = convert_to_csv_clients(clients_by_id);
-    write_csv_clients(csv_clients_iter, writer)
+    let columns = report_columns(clients_by_id, config);
+    let mut wtr = csv::Writer::from_writer(writer);
+
+    wtr.write_record(columns.iter().map(|column| &column.header))?;
+
+    for row in ordered_rows(clients_by_id, config) {
+        wtr.write_record(
+            columns
+                .iter()
+                .map(|column| column.field.value(&row, config.scale).to_string()),
+        )?;
+    }
+
+    wtr.flush()?;
+
+    Ok(())
+}
+
+// Takes the resultant transactions after processing events, and writes those
+// that have ever been disputed or have been reversed to the given writer in CSV
+// form, ordered by transaction ID. Transactions that never were are left out.
+// Both sides of a transfer share an ID, so a transfer disputed on both sides
+// shows up twice.
+pub fn write_dispute_report<'a>(
+    transactions: impl Iterator<Item = (TransactionID, &'a Transaction)>,
+    writer: impl Write,
+    config: &ReportConfig,
+) -> Result<(), Box<dyn Error>> {
+    let mut entries = transactions
+        .filter_map(|(transaction_id, transaction)| {
+            csv_disputed_transaction_from_transaction(transaction_id, transaction, config.scale)
+        })
+        .collect::<Vec<_>>();
+    entries.sort_by_key(|entry| (entry.tx, entry.client));
+
+    let mut wtr = csv::Writer::from_writer(writer);
+
+    for entry in entries {
+        wtr.serialize(entry)?;
+    }
+
+    wtr.flush()?;
+
+    Ok(())
 }
 
-fn convert_to_csv_clients(
-    clients_by_id: HashMap<ClientID, Client>,
-) -> impl Iterator<Item = CsvClient> {
-    let mut entries: Vec<(ClientID, Client)> = clients_by_id.into_iter().collect();
-    // This sorting is admittedly mostly for the sake of making testing easier,
-    // though I assume that actually producing a report is a small part that happens
-    // at the end of a long process of processing events, and I also assume that
-    // it's convenient to order records by client ID despite the spec being
-    // indifferent. If this assumption proves invalid we can ditch the sorting
-    // and just update the test.
-    entries.sort_by(|(a, _), (b, _)| a.cmp(b));
-    entries
-        .into_iter()
-        .map(|(client_id, client)| csv_client_from_client(client_id, client))
+fn csv_disputed_transaction_from_transaction(
+    transaction_id: TransactionID,
+    transaction: &Transaction,
+    scale: u32,
+) -> Option<CsvDisputedTransaction> {
+    // transactions that were never disputed aren't reported
+    if transaction.dispute_status() == DisputeStatus::Undisputed
+        && transaction.dispute_history().disputed_at.is_none()
+    {
+        return None;
+    }
+    let status = transaction.dispute_status().name();
+    let kind = transaction.kind().name();
+
+    let history = transaction.dispute_history();
+
+    Some(CsvDisputedTransaction {
+        tx: transaction_id,
+        client: transaction.client_id(),
+        kind,
+        amount: normalize_amount(transaction.amount(), scale),
+        currency: transaction.currency(),
+        status,
+        disputed: normalize_amount(
+            if transaction.dispute_status() == DisputeStatus::Disputed {
+                transaction.disputed_amount()
+            } else {
+                Amount::ZERO
+            },
+            scale,
+        ),
+        charged_back: normalize_amount(transaction.charged_back_amount(), scale),
+        disputed_at: history.disputed_at,
+        resolved_at: history.resolved_at,
+        charged_back_at: history.charged_back_at,
+    })
 }
 
-fn write_csv_clients(
-    csv_clients: impl Iterator<Item = CsvClient>,
+// Writes the reconciliation as a CSV row per currency, so that a non-zero
+// discrepancy can be picked up without parsing anything fancier.
+pub fn write_reconciliation(
+    reconciliations: &[Reconciliation],
     writer: impl Write,
+    config: &ReportConfig,
 ) -> Result<(), Box<dyn Error>> {
     let mut wtr = csv::Writer::from_writer(writer);
 
-    for client in csv_clients {
-        wtr.serialize(client)?;
+    for reconciliation in reconciliations {
+        wtr.serialize(CsvReconciliation {
+            currency: reconciliation.currency,
+            deposits: normalize_amount(reconciliation.deposits, config.scale),
+            withdrawals: normalize_amount(reconciliation.withdrawals, config.scale),
+            charged_back: normalize_amount(reconciliation.charged_back, config.scale),
+            reversed: normalize_amount(reconciliation.reversed, config.scale),
+            fees: normalize_amount(reconciliation.fees, config.scale),
+            interest: normalize_amount(reconciliation.interest, config.scale),
+            adjusted: normalize_amount(reconciliation.adjusted, config.scale),
+            converted: normalize_amount(reconciliation.converted, config.scale),
+            expected_total: normalize_amount(reconciliation.expected_total(), config.scale),
+            actual_total: normalize_amount(reconciliation.actual_total, config.scale),
+            discrepancy: normalize_amount(reconciliation.discrepancy(), config.scale),
+        })?;
     }
 
     wtr.flush()?;
@@ -54,21 +195,68 @@ fn write_csv_clients(
     Ok(())
 }
 
-fn csv_client_from_client(client_id: ClientID, client: Client) -> CsvClient {
-    CsvClient {
-        client: client_id,
-        available: client.available(),
-        held: client.held(),
-        total: client.total(),
-        locked: client.locked(),
+// Writes a row per counterparty per currency, for spotting the counterparties
+// whose transactions get disputed the most.
+pub fn write_counterparty_report(
+    exposures: &[CounterpartyExposure],
+    writer: impl Write,
+    config: &ReportConfig,
+) -> Result<(), Box<dyn Error>> {
+    let mut wtr = csv::Writer::from_writer(writer);
+
+    for exposure in exposures {
+        wtr.serialize(CsvCounterpartyExposure {
+            counterparty: &exposure.counterparty,
+            currency: exposure.currency,
+            transactions: exposure.transactions,
+            volume: normalize_amount(exposure.volume, config.scale),
+            disputed_transactions: exposure.disputed_transactions,
+            disputed: normalize_amount(exposure.disputed, config.scale),
+            charged_back_transactions: exposure.charged_back_transactions,
+            charged_back: normalize_amount(exposure.charged_back, config.scale),
+        })?;
+    }
+
+    wtr.flush()?;
+
+    Ok(())
+}
+
+// Writes how each client changed between two reports.
+pub fn write_diff(
+    deltas: &[ClientDelta],
+    writer: impl Write,
+    config: &ReportConfig,
+) -> Result<(), Box<dyn Error>> {
+    let multi_currency = deltas
+        .iter()
+        .any(|delta| delta.currency != Currency::default());
+    let mut wtr = csv::Writer::from_writer(writer);
+
+    for delta in deltas {
+        wtr.serialize(CsvClientDelta {
+            client: delta.client_id,
+            currency: multi_currency.then_some(delta.currency),
+            available_change: normalize_amount(delta.available, config.scale),
+            held_change: normalize_amount(delta.held, config.scale),
+            total_change: normalize_amount(delta.total, config.scale),
+            newly_locked: delta.newly_locked,
+        })?;
     }
+
+    wtr.flush()?;
+
+    Ok(())
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::format::{columns::parse_columns, ReportOrder};
+    use crate::model::TransactionKind;
     use pretty_assertions::assert_eq;
     use rust_decimal_macros::dec;
+    use std::collections::HashSet;
 
     #[test]
     fn test_write_reports() {
@@ -78,14 +266,334 @@ mod test {
             (2, Client::create(dec!(6), dec!(7), false)),
         ]);
 
-        write_report(result, &mut writer).expect("Expected no errors.");
+        write_report(&result, &mut writer, &ReportConfig::default()).expect("Expected no errors.");
+
+        let output = String::from_utf8(writer).expect("Not UTF-8");
+        assert_eq!(
+            concat!(
+                "client,available,held,total,locked\n",
+                "1,80.0000,20.0000,100.0000,true\n",
+                "2,1.0000,6.0000,7.0000,false\n"
+            ),
+            output,
+        );
+    }
+
+    #[test]
+    fn test_write_reports_with_scale() {
+        let mut writer = Vec::new();
+        let result = HashMap::from([(1, Client::create(dec!(0.125), dec!(1.61111), false))]);
+        let config = ReportConfig {
+            scale: 2,
+            ..ReportConfig::default()
+        };
+
+        write_report(&result, &mut writer, &config).expect("Expected no errors.");
+
+        let output = String::from_utf8(writer).expect("Not UTF-8");
+        assert_eq!(
+            concat!(
+                "client,available,held,total,locked\n",
+                "1,1.49,0.12,1.61,false\n"
+            ),
+            output,
+        );
+    }
+
+    #[test]
+    fn test_write_reports_for_some_clients() {
+        let mut writer = Vec::new();
+        let result = HashMap::from([
+            (1, Client::create(dec!(0), dec!(1), false)),
+            (2, Client::create(dec!(0), dec!(2), false)),
+            (3, Client::create(dec!(0), dec!(3), false)),
+        ]);
+        let config = ReportConfig {
+            clients: Some(HashSet::from([1, 3, 4])),
+            ..ReportConfig::default()
+        };
+
+        write_report(&result, &mut writer, &config).expect("Expected no errors.");
 
         let output = String::from_utf8(writer).expect("Not UTF-8");
         assert_eq!(
             concat!(
                 "client,available,held,total,locked\n",
-                "1,80,20,100,true\n",
-                "2,1,6,7,false\n"
+                "1,1.0000,0.0000,1.0000,false\n",
+                "3,3.0000,0.0000,3.0000,false\n"
+            ),
+            output,
+        );
+    }
+
+    #[test]
+    fn test_write_reports_with_columns() {
+        let mut writer = Vec::new();
+        let result = HashMap::from([(
+            1,
+            Client::create(dec!(20), dec!(100), false).with_disputed_count(2),
+        )]);
+        let config = ReportConfig {
+            columns: parse_columns("client:id,available,disputed_count:disputes")
+                .expect("Expected valid columns."),
+            ..ReportConfig::default()
+        };
+
+        write_report(&result, &mut writer, &config).expect("Expected no errors.");
+
+        let output = String::from_utf8(writer).expect("Not UTF-8");
+        assert_eq!(concat!("id,available,disputes\n", "1,80.0000,2\n"), output);
+    }
+
+    #[test]
+    fn test_write_reports_with_currencies() {
+        let mut writer = Vec::new();
+        let result = HashMap::from([
+            (
+                1,
+                Client::create(dec!(0), dec!(1), false).with_balance(
+                    "EUR".parse().expect("Expected a valid currency."),
+                    dec!(0),
+                    dec!(2),
+                ),
+            ),
+            (2, Client::new()),
+        ]);
+        let config = ReportConfig {
+            columns: parse_columns("client,currency,total").expect("Expected valid columns."),
+            ..ReportConfig::default()
+        };
+
+        write_report(&result, &mut writer, &config).expect("Expected no errors.");
+
+        let output = String::from_utf8(writer).expect("Not UTF-8");
+        assert_eq!(
+            concat!(
+                "client,currency,total\n",
+                "1,,1.0000\n",
+                "1,EUR,2.0000\n",
+                "2,,0.0000\n"
+            ),
+            output
+        );
+    }
+
+    #[test]
+    fn test_write_reports_with_currencies_by_default() {
+        let mut writer = Vec::new();
+        let result = HashMap::from([(
+            1,
+            Client::create(dec!(0), dec!(1), false).with_balance(
+                "EUR".parse().expect("Expected a valid currency."),
+                dec!(0),
+                dec!(2),
+            ),
+        )]);
+
+        write_report(&result, &mut writer, &ReportConfig::default()).expect("Expected no errors.");
+
+        let output = String::from_utf8(writer).expect("Not UTF-8");
+        assert_eq!(
+            concat!(
+                "client,currency,available,held,total,locked\n",
+                "1,,1.0000,0.0000,1.0000,false\n",
+                "1,EUR,2.0000,0.0000,2.0000,false\n",
+            ),
+            output
+        );
+    }
+
+    #[test]
+    fn test_write_reports_with_status() {
+        let mut writer = Vec::new();
+        let result = HashMap::from([
+            (1, Client::create(dec!(0), dec!(1), false)),
+            (2, Client::create(dec!(0), dec!(0), true)),
+            (3, Client::create(dec!(0), dec!(0), true).with_closed(true)),
+        ]);
+        let config = ReportConfig {
+            columns: parse_columns("client,locked,status").expect("Expected valid columns."),
+            ..ReportConfig::default()
+        };
+
+        write_report(&result, &mut writer, &config).expect("Expected no errors.");
+
+        let output = String::from_utf8(writer).expect("Not UTF-8");
+        assert_eq!(
+            concat!(
+                "client,locked,status\n",
+                "1,false,active\n",
+                "2,true,locked\n",
+                "3,true,closed\n"
+            ),
+            output
+        );
+    }
+
+    #[test]
+    fn test_write_reports_unsorted() {
+        let mut writer = Vec::new();
+        let result = HashMap::from([
+            (1, Client::create(dec!(0), dec!(1), false)),
+            (2, Client::create(dec!(0), dec!(2), false)),
+            (3, Client::create(dec!(0), dec!(3), false)),
+        ]);
+        let config = ReportConfig {
+            order: ReportOrder::Unsorted,
+            ..ReportConfig::default()
+        };
+
+        write_report(&result, &mut writer, &config).expect("Expected no errors.");
+
+        let output = String::from_utf8(writer).expect("Not UTF-8");
+        let mut lines = output.lines().collect::<Vec<_>>();
+        assert_eq!("client,available,held,total,locked", lines.remove(0));
+        lines.sort_unstable();
+        assert_eq!(
+            vec![
+                "1,1.0000,0.0000,1.0000,false",
+                "2,2.0000,0.0000,2.0000,false",
+                "3,3.0000,0.0000,3.0000,false",
+            ],
+            lines,
+        );
+    }
+
+    #[test]
+    fn test_write_dispute_report() {
+        let mut writer = Vec::new();
+        let euros = "EUR".parse().expect("Expected a valid currency.");
+        let mut disputed =
+            Transaction::new(1, Currency::default(), dec!(10), TransactionKind::Deposit);
+        disputed.dispute(dec!(10), 4);
+        let mut charged_back = Transaction::new(2, euros, dec!(2.5), TransactionKind::Withdrawal);
+        charged_back.dispute(dec!(2.5), 8);
+        charged_back.charge_back(10);
+        let mut partially_charged_back =
+            Transaction::new(3, Currency::default(), dec!(8), TransactionKind::Deposit);
+        partially_charged_back.dispute(dec!(3), 11);
+        partially_charged_back.charge_back(12);
+        partially_charged_back.dispute(dec!(1), 13);
+        let undisputed =
+            Transaction::new(1, Currency::default(), dec!(3), TransactionKind::Deposit);
+        let mut resolved =
+            Transaction::new(1, Currency::default(), dec!(4), TransactionKind::Deposit);
+        resolved.dispute(dec!(4), 6);
+        resolved.resolve(9);
+        let transactions_by_id = HashMap::from([
+            (7, charged_back),
+            (3, disputed),
+            (5, undisputed),
+            (6, resolved),
+            (9, partially_charged_back),
+        ]);
+
+        write_dispute_report(
+            transactions_by_id
+                .iter()
+                .map(|(transaction_id, transaction)| (*transaction_id, transaction)),
+            &mut writer,
+            &ReportConfig::default(),
+        )
+        .expect("Expected no errors.");
+
+        let output = String::from_utf8(writer).expect("Not UTF-8");
+        assert_eq!(
+            concat!(
+                "tx,client,type,amount,currency,status,disputed,charged_back,disputed_at,resolved_at,charged_back_at\n",
+                "3,1,deposit,10.0000,,disputed,10.0000,0.0000,4,,\n",
+                "6,1,deposit,4.0000,,undisputed,0.0000,0.0000,6,9,\n",
+                "7,2,withdrawal,2.5000,EUR,charged_back,0.0000,2.5000,8,,10\n",
+                "9,3,deposit,8.0000,,disputed,1.0000,3.0000,13,,12\n"
+            ),
+            output,
+        );
+    }
+
+    #[test]
+    fn test_write_reconciliation() {
+        let mut writer = Vec::new();
+        let reconciliations = [
+            Reconciliation {
+                currency: Currency::default(),
+                deposits: dec!(120),
+                withdrawals: dec!(30.5),
+                charged_back: dec!(20),
+                reversed: dec!(0),
+                fees: dec!(1),
+                interest: dec!(0.5),
+                adjusted: dec!(0),
+                converted: dec!(-2),
+                actual_total: dec!(70),
+            },
+            Reconciliation {
+                currency: "EUR".parse().expect("Expected a valid currency."),
+                deposits: dec!(5),
+                withdrawals: dec!(0),
+                charged_back: dec!(0),
+                reversed: dec!(0),
+                fees: dec!(0),
+                interest: dec!(0),
+                adjusted: dec!(-1),
+                converted: dec!(2),
+                actual_total: dec!(5),
+            },
+        ];
+
+        write_reconciliation(&reconciliations, &mut writer, &ReportConfig::default())
+            .expect("Expected no errors.");
+
+        let output = String::from_utf8(writer).expect("Not UTF-8");
+        assert_eq!(
+            concat!(
+                "currency,deposits,withdrawals,charged_back,reversed,fees,interest,adjusted,converted,expected_total,actual_total,discrepancy\n",
+                ",120.0000,30.5000,20.0000,0.0000,1.0000,0.5000,0.0000,-2.0000,67.0000,70.0000,3.0000\n",
+                "EUR,5.0000,0.0000,0.0000,0.0000,0.0000,0.0000,-1.0000,2.0000,6.0000,5.0000,-1.0000\n"
+            ),
+            output,
+        );
+    }
+
+    #[test]
+    fn test_write_diff() {
+        let mut writer = Vec::new();
+        let deltas = [ClientDelta {
+            client_id: 3,
+            currency: Currency::default(),
+            available: dec!(0),
+            held: dec!(-3),
+            total: dec!(-3),
+            newly_locked: true,
+        }];
+
+        write_diff(&deltas, &mut writer, &ReportConfig::default()).expect("Expected no errors.");
+
+        let output = String::from_utf8(writer).expect("Not UTF-8");
+        assert_eq!(
+            concat!(
+                "client,available_change,held_change,total_change,newly_locked\n",
+                "3,0.0000,-3.0000,-3.0000,true\n"
+            ),
+            output,
+        );
+
+        let mut writer = Vec::new();
+        let deltas = [
+            deltas[0],
+            ClientDelta {
+                currency: "EUR".parse().expect("Expected a valid currency."),
+                ..deltas[0]
+            },
+        ];
+
+        write_diff(&deltas, &mut writer, &ReportConfig::default()).expect("Expected no errors.");
+
+        let output = String::from_utf8(writer).expect("Not UTF-8");
+        assert_eq!(
+            concat!(
+                "client,currency,available_change,held_change,total_change,newly_locked\n",
+                "3,,0.0000,-3.0000,-3.0000,true\n",
+                "3,EUR,0.0000,-3.0000,-3.0000,true\n",
             ),
             output,
         );
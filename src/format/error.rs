@@ -0,0 +1,69 @@
+use crate::model::ClientID;
+
+use std::io;
+use thiserror::Error;
+
+// Why an input couldn't be read. Unlike processing errors, these abort the
+// run, since we can't tell what the input meant. They're concrete (and `Send`)
+// rather than boxed, so that something counting bad rows rather than stopping
+// at them (see `system::input_stats`) doesn't allocate for each one.
+#[derive(Debug, Error)]
+pub enum ParseError {
+    // the input itself couldn't be read, as opposed to a row in it not making
+    // sense
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    // likewise, there's no making sense of any of the rows without it
+    #[error(transparent)]
+    Header(csv::Error),
+    #[error(transparent)]
+    Csv(#[from] csv::Error),
+    // serde_json counts lines from the start of the one it was given, so we
+    // say which line of the input that was
+    #[error("Invalid JSON on line {line}: {source}.")]
+    Json {
+        line: u64,
+        source: serde_json::Error,
+    },
+    // only for CSV that's read a record at a time, since otherwise the CSV
+    // reader checks this itself
+    #[error("Expected {expected} fields on line {line} but found {found}.")]
+    FieldCount {
+        line: u64,
+        expected: usize,
+        found: usize,
+    },
+    #[error("Missing transaction ID.")]
+    MissingTransactionId,
+    #[error("Missing amount.")]
+    MissingAmount,
+    #[error(transparent)]
+    InvalidAmount(#[from] rust_decimal::Error),
+    #[error("Amount must be positive: {0}.")]
+    NonPositiveAmount(String),
+    #[error("Missing rate.")]
+    MissingRate,
+    #[error("Invalid rate: {0}.")]
+    InvalidRate(String),
+    // the currency's own message, which says what was wrong with it
+    #[error("{0}")]
+    InvalidCurrency(String),
+    #[error("Missing receiving client for transfer.")]
+    MissingRecipient,
+    #[error("Missing reason for adjustment.")]
+    MissingReason,
+    #[error("Missing name for client.")]
+    MissingName,
+    #[error("Unknown event kind: {0}.")]
+    UnknownEventKind(String),
+    #[error("Credit limit for client {0} cannot be negative.")]
+    NegativeCreditLimit(ClientID),
+}
+
+impl ParseError {
+    // Whether it's just the one row that's wrong, so that the rest of the
+    // input might still be read.
+    pub fn is_malformed_row(&self) -> bool {
+        !matches!(self, ParseError::Io(_) | ParseError::Header(_))
+    }
+}
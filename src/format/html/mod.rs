@@ -0,0 +1,3 @@
+// Everything HTML-related lives here.
+
+pub mod output;
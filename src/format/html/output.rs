@@ -0,0 +1,151 @@
+use std::{collections::HashMap, error::Error, io::Write};
+
+use crate::{
+    format::{ordered_rows, ReportConfig},
+    model::{Client, ClientID},
+    system::EventCounts,
+};
+
+// Kept inline so that the report is a single file that can be emailed around
+// or opened straight from disk.
+const STYLE: &str = "\
+body { font-family: sans-serif; margin: 2em; color: #222; }
+table { border-collapse: collapse; }
+th, td { padding: 0.3em 0.8em; border-bottom: 1px solid #ddd; }
+th { text-align: left; background: #f4f4f4; }
+td.number { text-align: right; font-variant-numeric: tabular-nums; }
+tr.locked { background: #fdecea; }
+dt { font-weight: bold; }
+dd { margin: 0 0 0.5em 0; }";
+
+// Takes the resultant clients after processing events, and writes them to the
+// given writer as a standalone HTML page with a summary of the run followed by
+// a table with the configured columns. This is meant for sharing results with
+// people who'd otherwise paste the CSV into a spreadsheet.
+pub fn write_report(
+    clients_by_id: &HashMap<ClientID, Client>,
+    event_counts: &EventCounts,
+    mut writer: impl Write,
+    config: &ReportConfig,
+) -> Result<(), Box<dyn Error>> {
+    let locked_count = clients_by_id
+        .values()
+        .filter(|client| client.locked())
+        .count();
+
+    writeln!(writer, "<!DOCTYPE html>")?;
+    writeln!(writer, "<html>")?;
+    writeln!(writer, "<head>")?;
+    writeln!(writer, "<meta charset=\"utf-8\">")?;
+    writeln!(writer, "<title>Client report</title>")?;
+    writeln!(writer, "<style>\n{}\n</style>", STYLE)?;
+    writeln!(writer, "</head>")?;
+    writeln!(writer, "<body>")?;
+    writeln!(writer, "<h1>Client report</h1>")?;
+
+    writeln!(writer, "<dl>")?;
+    for (term, value) in [
+        ("Events processed", event_counts.processed.to_string()),
+        ("Events rejected", event_counts.rejected.to_string()),
+        ("Clients", clients_by_id.len().to_string()),
+        ("Locked accounts", locked_count.to_string()),
+    ] {
+        writeln!(writer, "<dt>{}</dt><dd>{}</dd>", term, value)?;
+    }
+    writeln!(writer, "</dl>")?;
+
+    writeln!(writer, "<table>")?;
+    write!(writer, "<thead><tr>")?;
+    for column in &config.columns {
+        write!(writer, "<th>{}</th>", escape(&column.header))?;
+    }
+    writeln!(writer, "</tr></thead>")?;
+    writeln!(writer, "<tbody>")?;
+    for row in ordered_rows(clients_by_id, config) {
+        if row.client.locked() {
+            write!(writer, "<tr class=\"locked\">")?;
+        } else {
+            write!(writer, "<tr>")?;
+        }
+        for column in &config.columns {
+            let value = column.field.value(&row, config.scale);
+            if !column.field.is_numeric() {
+                write!(writer, "<td>{}</td>", value)?;
+            } else {
+                write!(writer, "<td class=\"number\">{}</td>", value)?;
+            }
+        }
+        writeln!(writer, "</tr>")?;
+    }
+    writeln!(writer, "</tbody>")?;
+    writeln!(writer, "</table>")?;
+
+    writeln!(writer, "</body>")?;
+    writeln!(writer, "</html>")?;
+
+    writer.flush()?;
+
+    Ok(())
+}
+
+// The cells are all numbers or booleans, but headers can be renamed to
+// anything.
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::format::columns::parse_columns;
+    use pretty_assertions::assert_eq;
+    use rust_decimal_macros::dec;
+
+    #[test]
+    fn test_write_report() {
+        let mut writer = Vec::new();
+        let clients_by_id = HashMap::from([
+            (2, Client::create(dec!(6), dec!(7), false)),
+            (1, Client::create(dec!(20), dec!(100), true)),
+        ]);
+        let event_counts = EventCounts {
+            processed: 10,
+            rejected: 3,
+            ..EventCounts::default()
+        };
+        let config = ReportConfig {
+            columns: parse_columns("client,total:Total <all>,locked").expect("Valid columns"),
+            ..ReportConfig::default()
+        };
+
+        write_report(&clients_by_id, &event_counts, &mut writer, &config)
+            .expect("Expected no errors.");
+
+        let output = String::from_utf8(writer).expect("Not UTF-8");
+        let body = output.split_once("<body>\n").expect("Expected a body").1;
+        assert_eq!(
+            concat!(
+                "<h1>Client report</h1>\n",
+                "<dl>\n",
+                "<dt>Events processed</dt><dd>10</dd>\n",
+                "<dt>Events rejected</dt><dd>3</dd>\n",
+                "<dt>Clients</dt><dd>2</dd>\n",
+                "<dt>Locked accounts</dt><dd>1</dd>\n",
+                "</dl>\n",
+                "<table>\n",
+                "<thead><tr><th>client</th><th>Total &lt;all&gt;</th><th>locked</th></tr></thead>\n",
+                "<tbody>\n",
+                "<tr class=\"locked\"><td class=\"number\">1</td><td class=\"number\">100.0000</td><td>true</td></tr>\n",
+                "<tr><td class=\"number\">2</td><td class=\"number\">7.0000</td><td>false</td></tr>\n",
+                "</tbody>\n",
+                "</table>\n",
+                "</body>\n",
+                "</html>\n",
+            ),
+            body,
+        );
+    }
+}
@@ -0,0 +1,108 @@
+use serde::Serialize;
+use std::{collections::BTreeSet, error::Error, io::Write};
+
+use crate::{
+    format::{normalize_amount, ReportConfig},
+    model::{Amount, Balance, ClientID, Currency},
+    system::StateDelta,
+};
+
+// Intermediary representations of a delta for serialization. Only how the
+// client stands afterwards is written, plus whether that locked or unlocked
+// them, since that's all anything reacting to it has needed so far.
+#[derive(Serialize)]
+struct JsonDelta {
+    client: ClientID,
+    locked: bool,
+    lock_changed: bool,
+    balances: Vec<JsonBalance>,
+}
+
+// Amounts are strings here like everywhere else in our JSON.
+#[derive(Serialize)]
+pub(super) struct JsonBalance {
+    pub(super) currency: Currency,
+    pub(super) available: Amount,
+    pub(super) held: Amount,
+    pub(super) total: Amount,
+}
+
+// Writes a delta as a single line of JSON, with amounts at the report's scale.
+// A currency the client had before but not after is still written, with
+// nothing in it, so that whoever's mirroring the balances knows to zero it.
+pub fn write_delta(
+    delta: &StateDelta,
+    mut writer: impl Write,
+    config: &ReportConfig,
+) -> Result<(), Box<dyn Error>> {
+    let currencies = delta
+        .before
+        .balances
+        .keys()
+        .chain(delta.after.balances.keys())
+        .copied()
+        .collect::<BTreeSet<_>>();
+    let json_delta = JsonDelta {
+        client: delta.client_id,
+        locked: delta.after.locked,
+        lock_changed: delta.after.locked != delta.before.locked,
+        balances: currencies
+            .into_iter()
+            .map(|currency| {
+                let balance = delta.after.balances.get(&currency);
+                let amount = |of: fn(&Balance) -> Amount| {
+                    normalize_amount(balance.map_or(Amount::ZERO, of), config.scale)
+                };
+                JsonBalance {
+                    currency,
+                    available: amount(Balance::available),
+                    held: amount(Balance::held),
+                    total: amount(Balance::total),
+                }
+            })
+            .collect(),
+    };
+
+    serde_json::to_writer(&mut writer, &json_delta)?;
+    writer.write_all(b"\n")?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{model::Client, system::AccountState};
+    use pretty_assertions::assert_eq;
+    use rust_decimal_macros::dec;
+
+    #[test]
+    fn test_write_delta() {
+        let euros = "EUR".parse().expect("Expected a valid currency.");
+        let before = Client::create(dec!(0), dec!(5), false).with_balance(euros, dec!(0), dec!(1));
+        let after = Client::create(dec!(2), dec!(5), true);
+        let delta = StateDelta {
+            client_id: 3,
+            before: AccountState::of(Some(&before)),
+            after: AccountState::of(Some(&after)),
+        };
+
+        let mut writer = Vec::new();
+        write_delta(&delta, &mut writer, &ReportConfig::default()).expect("Expected no errors.");
+
+        let output: serde_json::Value =
+            serde_json::from_slice(&writer).expect("Expected valid JSON");
+        assert_eq!(
+            serde_json::json!({
+                "client": 3,
+                "locked": true,
+                "lock_changed": true,
+                "balances": [
+                    { "currency": "", "available": "3.0000", "held": "2.0000", "total": "5.0000" },
+                    { "currency": "EUR", "available": "0.0000", "held": "0.0000", "total": "0.0000" }
+                ]
+            }),
+            output
+        );
+    }
+}
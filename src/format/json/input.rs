@@ -0,0 +1,168 @@
+use std::{
+    io::{BufRead, BufReader, Read},
+    iter,
+};
+
+use crate::{
+    format::{
+        csv::input::{parse_csv_event, CsvEvent},
+        error::ParseError,
+    },
+    model::{Source, SourcedEvent},
+};
+
+// Returns an iterator which itself yields Events read from JSON lines, i.e. one
+// object per line with the same fields as the CSV columns (`type`, `client`,
+// `tx`, `amount` and so on). Like in our JSON reports, amounts and rates are
+// strings rather than numbers, so that nothing goes through a float on its way
+// in. Fields an event doesn't need can be left out, and blank lines are
+// skipped.
+pub fn parse_events(reader: impl Read) -> impl Iterator<Item = Result<SourcedEvent, ParseError>> {
+    parse_sourced_events(reader, false)
+}
+
+// Like `parse_events`, but each event also keeps a copy of the line it was
+// parsed from (see `csv::input::parse_events_keeping_records`).
+pub fn parse_events_keeping_records(
+    reader: impl Read,
+) -> impl Iterator<Item = Result<SourcedEvent, ParseError>> {
+    parse_sourced_events(reader, true)
+}
+
+fn parse_sourced_events(
+    reader: impl Read,
+    keep_records: bool,
+) -> impl Iterator<Item = Result<SourcedEvent, ParseError>> {
+    let mut reader = BufReader::new(reader);
+    let mut line = String::new();
+    let mut line_number = 0;
+    let mut byte = 0;
+
+    iter::from_fn(move || loop {
+        line.clear();
+        let read = match reader.read_line(&mut line) {
+            Ok(0) => return None,
+            Ok(read) => read,
+            Err(e) => return Some(Err(e.into())),
+        };
+        line_number += 1;
+        let source = Source {
+            line: line_number,
+            byte,
+            record: None,
+        };
+        byte += read as u64;
+
+        let record = line.trim();
+        if record.is_empty() {
+            continue;
+        }
+        return Some(parse_record(record, source, keep_records));
+    })
+}
+
+pub(crate) fn parse_record(
+    record: &str,
+    source: Source,
+    keep_records: bool,
+) -> Result<SourcedEvent, ParseError> {
+    let json_event: CsvEvent = serde_json::from_str(record).map_err(|e| ParseError::Json {
+        line: source.line,
+        source: e,
+    })?;
+    let timestamp = json_event.timestamp();
+
+    Ok(SourcedEvent {
+        event: parse_csv_event(json_event)?,
+        source: Some(Source {
+            record: keep_records.then(|| String::from(record)),
+            ..source
+        }),
+        timestamp,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::model::{Currency, DisputeStepKind, Event, TransactionKind};
+    use pretty_assertions::assert_eq;
+    use rust_decimal_macros::dec;
+
+    #[test]
+    fn test_parse_events() {
+        let input = concat!(
+            "{\"type\":\"deposit\",\"client\":1,\"tx\":1,\"amount\":\"1.5\",\"timestamp\":100}\n",
+            "\n",
+            "{\"type\":\"dispute\",\"client\":1,\"tx\":1}\n",
+        );
+
+        let result = parse_events_keeping_records(input.as_bytes())
+            .collect::<Result<Vec<_>, _>>()
+            .expect("Expected no errors.");
+
+        assert_eq!(
+            vec![
+                SourcedEvent {
+                    event: Event::Transaction {
+                        kind: TransactionKind::Deposit,
+                        transaction_id: 1,
+                        client_id: 1,
+                        currency: Currency::default(),
+                        amount: dec!(1.5),
+                        counterparty: None,
+                    },
+                    source: Some(Source {
+                        line: 1,
+                        byte: 0,
+                        record: Some(String::from(
+                            "{\"type\":\"deposit\",\"client\":1,\"tx\":1,\"amount\":\"1.5\",\"timestamp\":100}"
+                        )),
+                    }),
+                    timestamp: Some(100),
+                },
+                SourcedEvent {
+                    event: Event::DisputeStep {
+                        kind: DisputeStepKind::Dispute,
+                        transaction_id: 1,
+                        client_id: 1,
+                        amount: None,
+                    },
+                    source: Some(Source {
+                        line: 3,
+                        byte: 69,
+                        record: Some(String::from("{\"type\":\"dispute\",\"client\":1,\"tx\":1}")),
+                    }),
+                    timestamp: None,
+                },
+            ],
+            result
+        );
+    }
+
+    #[test]
+    fn test_parse_events_invalid() {
+        let input = concat!(
+            "{\"type\":\"deposit\",\"client\":1,\"tx\":1,\"amount\":1.5}\n",
+            "{\"type\":\"deposit\",\"client\":1,\"tx\":2}\n",
+            "not json\n",
+        );
+
+        let result = parse_events(input.as_bytes())
+            .map(|result| result.map_err(|e| e.to_string()))
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            vec![
+                Err(String::from(
+                    "Invalid JSON on line 1: invalid type: floating point `1.5`, expected a string at line 1 column 48."
+                )),
+                Err(String::from("Missing amount.")),
+                Err(String::from(
+                    "Invalid JSON on line 3: expected ident at line 1 column 2."
+                )),
+            ],
+            result
+        );
+    }
+}
@@ -0,0 +1,295 @@
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    error::Error,
+    io::Write,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    format::ReportConfig,
+    model::{Amount, ClientID},
+    system::{EngineConfig, EventCounts, Fee},
+};
+
+// Everything we record about how a report came to be, so that a published
+// report can be traced back to exactly what produced it.
+pub struct Manifest<'a> {
+    pub input_path: &'a str,
+    pub input_sha256: &'a str,
+    // See `FinalState::state_digest`.
+    pub state_sha256: &'a str,
+    pub event_counts: &'a EventCounts,
+    pub report_config: &'a ReportConfig,
+    pub engine_config: &'a EngineConfig,
+    pub started_at: SystemTime,
+    pub duration: Duration,
+}
+
+// Intermediary representations of the manifest for serialization.
+#[derive(Serialize)]
+struct JsonManifest<'a> {
+    engine_version: &'static str,
+    input: JsonInput<'a>,
+    state: JsonState<'a>,
+    rows: JsonRows,
+    config: JsonConfig<'a>,
+    timings: JsonTimings,
+}
+
+#[derive(Serialize)]
+struct JsonInput<'a> {
+    path: &'a str,
+    sha256: &'a str,
+}
+
+#[derive(Serialize)]
+struct JsonState<'a> {
+    sha256: &'a str,
+}
+
+#[derive(Serialize)]
+struct JsonRows {
+    processed: u64,
+    rejected: u64,
+}
+
+#[derive(Serialize)]
+struct JsonConfig<'a> {
+    output_format: &'static str,
+    scale: u32,
+    order: &'static str,
+    columns: Vec<JsonColumn<'a>>,
+    engine: JsonEngineConfig,
+}
+
+#[derive(Serialize)]
+struct JsonColumn<'a> {
+    field: &'static str,
+    header: &'a str,
+}
+
+// Everything that changes how events are processed, since the same input can
+// come out differently under different policies. Durations are in seconds like
+// the timings, and anything that's off is null.
+#[derive(Serialize)]
+struct JsonEngineConfig {
+    deposit_fee: Option<JsonFee>,
+    withdrawal_fee: Option<JsonFee>,
+    withdrawal_disputes: &'static str,
+    allow_redispute: bool,
+    unlock_on_chargeback_reversal: bool,
+    locked_account_disputes: &'static str,
+    locked_account_deposits: &'static str,
+    undisputed_chargebacks: &'static str,
+    // ordered so that the same config always comes out the same
+    credit_limits: BTreeMap<ClientID, Amount>,
+    closed_accounts: &'static str,
+    dispute_window_seconds: Option<f64>,
+    dispute_expiry_seconds: Option<f64>,
+    reorder_window: usize,
+    disputable_only: bool,
+    duplicate_transactions: &'static str,
+    check_invariants: bool,
+    max_clients: Option<usize>,
+    max_transactions: Option<usize>,
+    max_errors: Option<u64>,
+    chargeback_limit: Option<u32>,
+    chargeback_limit_action: &'static str,
+}
+
+#[derive(Serialize)]
+struct JsonFee {
+    flat: Amount,
+    percent: Amount,
+}
+
+impl From<Fee> for JsonFee {
+    fn from(fee: Fee) -> Self {
+        Self {
+            flat: fee.flat,
+            percent: fee.percent,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct JsonTimings {
+    started_at: f64,
+    duration_seconds: f64,
+}
+
+// Writes the manifest as a single pretty-printed JSON document, since it's as
+// likely to be read by a person as by a machine.
+pub fn write_manifest(manifest: &Manifest, mut writer: impl Write) -> Result<(), Box<dyn Error>> {
+    let config = manifest.report_config;
+    let json_manifest = JsonManifest {
+        engine_version: env!("CARGO_PKG_VERSION"),
+        input: JsonInput {
+            path: manifest.input_path,
+            sha256: manifest.input_sha256,
+        },
+        state: JsonState {
+            sha256: manifest.state_sha256,
+        },
+        rows: JsonRows {
+            processed: manifest.event_counts.processed,
+            rejected: manifest.event_counts.rejected,
+        },
+        config: JsonConfig {
+            output_format: config.format.name(),
+            scale: config.scale,
+            order: config.order.name(),
+            columns: config
+                .columns
+                .iter()
+                .map(|column| JsonColumn {
+                    field: column.field.name(),
+                    header: &column.header,
+                })
+                .collect(),
+            engine: json_engine_config(manifest.engine_config),
+        },
+        timings: JsonTimings {
+            started_at: manifest
+                .started_at
+                .duration_since(UNIX_EPOCH)?
+                .as_secs_f64(),
+            duration_seconds: manifest.duration.as_secs_f64(),
+        },
+    };
+
+    serde_json::to_writer_pretty(&mut writer, &json_manifest)?;
+    writer.write_all(b"\n")?;
+    writer.flush()?;
+
+    Ok(())
+}
+
+fn json_engine_config(config: &EngineConfig) -> JsonEngineConfig {
+    JsonEngineConfig {
+        deposit_fee: config.fee_schedule.deposit.map(JsonFee::from),
+        withdrawal_fee: config.fee_schedule.withdrawal.map(JsonFee::from),
+        withdrawal_disputes: config.withdrawal_disputes.name(),
+        allow_redispute: config.allow_redispute,
+        unlock_on_chargeback_reversal: config.unlock_on_chargeback_reversal,
+        locked_account_disputes: config.locked_account_disputes.name(),
+        locked_account_deposits: config.locked_account_deposits.name(),
+        undisputed_chargebacks: config.undisputed_chargebacks.name(),
+        credit_limits: config
+            .credit_limits
+            .iter()
+            .map(|(client_id, limit)| (*client_id, *limit))
+            .collect(),
+        closed_accounts: config.closed_accounts.name(),
+        dispute_window_seconds: config.dispute_window.map(|window| window.as_secs_f64()),
+        dispute_expiry_seconds: config.dispute_expiry.map(|expiry| expiry.as_secs_f64()),
+        reorder_window: config.reorder_window,
+        disputable_only: config.disputable_only,
+        duplicate_transactions: config.duplicate_transactions.name(),
+        check_invariants: config.check_invariants,
+        max_clients: config.limits.max_clients,
+        max_transactions: config.limits.max_transactions,
+        max_errors: config.limits.max_errors,
+        chargeback_limit: config.chargeback_limit,
+        chargeback_limit_action: config.chargeback_limit_action.name(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        format::columns::parse_columns,
+        system::{FeeSchedule, UndisputedChargebackPolicy},
+    };
+    use pretty_assertions::assert_eq;
+    use rust_decimal_macros::dec;
+
+    #[test]
+    fn test_write_manifest() {
+        let mut writer = Vec::new();
+        let event_counts = EventCounts {
+            processed: 10,
+            rejected: 2,
+            ..EventCounts::default()
+        };
+        let report_config = ReportConfig {
+            columns: parse_columns("client:id,total").expect("Valid columns"),
+            ..ReportConfig::default()
+        };
+        let engine_config = EngineConfig {
+            fee_schedule: FeeSchedule {
+                deposit: Some(Fee {
+                    flat: dec!(0.5),
+                    percent: dec!(1),
+                }),
+                withdrawal: None,
+            },
+            undisputed_chargebacks: UndisputedChargebackPolicy::ImplicitDispute,
+            credit_limits: [(2, dec!(100)), (1, dec!(50))].into(),
+            dispute_window: Some(Duration::from_secs(86_400)),
+            ..EngineConfig::default()
+        };
+
+        write_manifest(
+            &Manifest {
+                input_path: "events.csv",
+                input_sha256: "abc123",
+                state_sha256: "def456",
+                event_counts: &event_counts,
+                report_config: &report_config,
+                engine_config: &engine_config,
+                started_at: UNIX_EPOCH + Duration::from_secs(1_700_000_000),
+                duration: Duration::from_millis(250),
+            },
+            &mut writer,
+        )
+        .expect("Expected no errors.");
+
+        let output: serde_json::Value =
+            serde_json::from_slice(&writer).expect("Expected valid JSON");
+        assert_eq!(
+            serde_json::json!({
+                "engine_version": env!("CARGO_PKG_VERSION"),
+                "input": { "path": "events.csv", "sha256": "abc123" },
+                "state": { "sha256": "def456" },
+                "rows": { "processed": 10, "rejected": 2 },
+                "config": {
+                    "output_format": "csv",
+                    "scale": 4,
+                    "order": "client_id",
+                    "columns": [
+                        { "field": "client", "header": "id" },
+                        { "field": "total", "header": "total" }
+                    ],
+                    "engine": {
+                        "deposit_fee": { "flat": "0.5", "percent": "1" },
+                        "withdrawal_fee": null,
+                        "withdrawal_disputes": "hold",
+                        "allow_redispute": false,
+                        "unlock_on_chargeback_reversal": false,
+                        "locked_account_disputes": "process",
+                        "locked_account_deposits": "reject",
+                        "undisputed_chargebacks": "implicit-dispute",
+                        "credit_limits": { "1": "50", "2": "100" },
+                        "closed_accounts": "reject",
+                        "dispute_window_seconds": 86400.0,
+                        "dispute_expiry_seconds": null,
+                        "reorder_window": 0,
+                        "disputable_only": false,
+                        "duplicate_transactions": "reject",
+                        "check_invariants": false,
+                        "max_clients": null,
+                        "max_transactions": null,
+                        "max_errors": null,
+                        "chargeback_limit": null,
+                        "chargeback_limit_action": "lock"
+                    }
+                },
+                "timings": { "started_at": 1700000000.0, "duration_seconds": 0.25 }
+            }),
+            output
+        );
+    }
+}
@@ -0,0 +1,9 @@
+// Everything JSON-related lives here.
+
+pub mod delta;
+pub mod input;
+pub mod manifest;
+pub mod notification;
+pub mod output;
+pub mod rejections;
+pub mod stats;
@@ -0,0 +1,161 @@
+use serde::Serialize;
+use std::{error::Error, io::Write};
+
+use super::delta::JsonBalance;
+use crate::{
+    format::{normalize_amount, ReportConfig},
+    model::{Amount, Client, ClientID, Currency, Timestamp, Transaction, TransactionID},
+};
+
+// Something that happened to a client that someone wants to be told about
+// straight away rather than find in the report, along with the number of the
+// event that did it (the one the dispute report goes by) and when that
+// happened, if known.
+pub struct Notification<'a> {
+    pub event_number: u64,
+    pub timestamp: Option<Timestamp>,
+    pub kind: NotificationKind<'a>,
+}
+
+pub enum NotificationKind<'a> {
+    Chargeback {
+        client_id: ClientID,
+        transaction_id: TransactionID,
+        transaction: &'a Transaction,
+    },
+    AccountLocked {
+        client_id: ClientID,
+        client: &'a Client,
+    },
+}
+
+// Intermediary representation of a notification for serialization, tagged
+// with what kind it is so that a receiver can tell them apart.
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum JsonNotification {
+    Chargeback {
+        event: u64,
+        timestamp: Option<Timestamp>,
+        client: ClientID,
+        tx: TransactionID,
+        currency: Currency,
+        amount: Amount,
+        charged_back: Amount,
+    },
+    AccountLocked {
+        event: u64,
+        timestamp: Option<Timestamp>,
+        client: ClientID,
+        chargebacks: u32,
+        balances: Vec<JsonBalance>,
+    },
+}
+
+// Writes a notification as a single JSON object, with amounts at the report's
+// scale. A chargeback says how much of the transaction has been charged back
+// all told, since it can be less than all of it, and a locked account says
+// where the client stands now it's locked.
+pub fn write_notification(
+    notification: &Notification,
+    mut writer: impl Write,
+    config: &ReportConfig,
+) -> Result<(), Box<dyn Error>> {
+    let amount = |amount| normalize_amount(amount, config.scale);
+    let json_notification = match notification.kind {
+        NotificationKind::Chargeback {
+            client_id,
+            transaction_id,
+            transaction,
+        } => JsonNotification::Chargeback {
+            event: notification.event_number,
+            timestamp: notification.timestamp,
+            client: client_id,
+            tx: transaction_id,
+            currency: transaction.currency(),
+            amount: amount(transaction.amount()),
+            charged_back: amount(transaction.charged_back_amount()),
+        },
+        NotificationKind::AccountLocked { client_id, client } => JsonNotification::AccountLocked {
+            event: notification.event_number,
+            timestamp: notification.timestamp,
+            client: client_id,
+            chargebacks: client.chargeback_count(),
+            balances: client
+                .balances()
+                .map(|(currency, balance)| JsonBalance {
+                    currency,
+                    available: amount(balance.available()),
+                    held: amount(balance.held()),
+                    total: amount(balance.total()),
+                })
+                .collect(),
+        },
+    };
+
+    serde_json::to_writer(&mut writer, &json_notification)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::model::TransactionKind;
+    use pretty_assertions::assert_eq;
+    use rust_decimal_macros::dec;
+
+    #[test]
+    fn test_write_notification() {
+        let mut transaction =
+            Transaction::new(1, Currency::default(), dec!(2.5), TransactionKind::Deposit);
+        transaction.dispute(dec!(2.5), 2);
+        transaction.charge_back(3);
+        let client = Client::create(dec!(0), dec!(1), true);
+
+        let write = |kind| {
+            let mut writer = Vec::new();
+            let notification = Notification {
+                event_number: 3,
+                timestamp: Some(1_760_000_000),
+                kind,
+            };
+            write_notification(&notification, &mut writer, &ReportConfig::default())
+                .expect("Expected no errors.");
+            serde_json::from_slice::<serde_json::Value>(&writer).expect("Expected valid JSON")
+        };
+
+        assert_eq!(
+            serde_json::json!({
+                "type": "chargeback",
+                "event": 3,
+                "timestamp": 1_760_000_000,
+                "client": 1,
+                "tx": 7,
+                "currency": "",
+                "amount": "2.5000",
+                "charged_back": "2.5000"
+            }),
+            write(NotificationKind::Chargeback {
+                client_id: 1,
+                transaction_id: 7,
+                transaction: &transaction,
+            })
+        );
+        assert_eq!(
+            serde_json::json!({
+                "type": "account_locked",
+                "event": 3,
+                "timestamp": 1_760_000_000,
+                "client": 1,
+                "chargebacks": 0,
+                "balances": [
+                    { "currency": "", "available": "1.0000", "held": "0.0000", "total": "1.0000" }
+                ]
+            }),
+            write(NotificationKind::AccountLocked {
+                client_id: 1,
+                client: &client,
+            })
+        );
+    }
+}
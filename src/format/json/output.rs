@@ -0,0 +1,236 @@
+use serde::{ser::SerializeMap, Serialize, Serializer};
+use std::{borrow::Cow, collections::HashMap, error::Error, io::Write};
+
+use crate::{
+    format::{
+        columns::{Column, Field},
+        ordered_rows, report_columns, ReportConfig, ReportRow,
+    },
+    model::{Client, ClientID, Currency},
+};
+
+// How the clients are laid out in the JSON report: either as an array of
+// objects (mirroring the CSV rows), as an object keyed by client ID, or as
+// JSON lines with an object per line. In the map layout a client's balances in
+// named currencies are keyed by `<client ID>:<currency>` instead, so that every
+// key is unique.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JsonLayout {
+    Array,
+    Map,
+    Lines,
+}
+
+// Intermediary representation of a client for serialization: an object with
+// one entry per configured column.
+struct JsonClient<'a> {
+    row: ReportRow<'a>,
+    columns: &'a [Column],
+    // in the map layout the client ID is already the key so we don't repeat it
+    // in the value
+    include_client_id: bool,
+    scale: u32,
+}
+
+impl Serialize for JsonClient<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let columns = self
+            .columns
+            .iter()
+            .filter(|column| self.include_client_id || column.field != Field::Client);
+
+        let mut map = serializer.serialize_map(None)?;
+        for column in columns {
+            let value = column.field.value(&self.row, self.scale);
+            map.serialize_entry(&column.header, &value)?;
+        }
+        map.end()
+    }
+}
+
+// Takes the resultant clients after processing events, and writes them to the
+// given writer as a single JSON document, or as a line of JSON per client.
+pub fn write_report(
+    clients_by_id: &HashMap<ClientID, Client>,
+    mut writer: impl Write,
+    layout: JsonLayout,
+    config: &ReportConfig,
+) -> Result<(), Box<dyn Error>> {
+    // the map layout's keys already say which currency each row is in
+    let columns = match layout {
+        JsonLayout::Map => Cow::Borrowed(config.columns.as_slice()),
+        JsonLayout::Array | JsonLayout::Lines => report_columns(clients_by_id, config),
+    };
+    let json_client = |row, include_client_id| JsonClient {
+        row,
+        columns: &columns,
+        include_client_id,
+        scale: config.scale,
+    };
+    let rows_iter = ordered_rows(clients_by_id, config);
+    // we stream the clients into the serializer rather than collecting them
+    // into a vector or map first
+    match layout {
+        JsonLayout::Array => serde_json::Serializer::new(&mut writer)
+            .collect_seq(rows_iter.map(|row| json_client(row, true)))?,
+        JsonLayout::Map => serde_json::Serializer::new(&mut writer)
+            .collect_map(rows_iter.map(|row| (map_key(&row), json_client(row, false))))?,
+        JsonLayout::Lines => {
+            for row in rows_iter {
+                serde_json::to_writer(&mut writer, &json_client(row, true))?;
+                writer.write_all(b"\n")?;
+            }
+        }
+    }
+
+    // each line already ends in one
+    if layout != JsonLayout::Lines {
+        writer.write_all(b"\n")?;
+    }
+    writer.flush()?;
+
+    Ok(())
+}
+
+// Writes a single client's rows as a JSON array, the same as the array layout
+// would for them, e.g. for looking one client up.
+pub fn write_client(
+    client_id: ClientID,
+    client: &Client,
+    mut writer: impl Write,
+    config: &ReportConfig,
+) -> Result<(), Box<dyn Error>> {
+    let rows = client
+        .balances()
+        .map(|(currency, balance)| ReportRow {
+            client_id,
+            client,
+            currency,
+            balance,
+        })
+        .map(|row| JsonClient {
+            row,
+            columns: &config.columns,
+            include_client_id: true,
+            scale: config.scale,
+        });
+    serde_json::Serializer::new(&mut writer).collect_seq(rows)?;
+    writer.write_all(b"\n")?;
+    writer.flush()?;
+
+    Ok(())
+}
+
+fn map_key(row: &ReportRow) -> String {
+    if row.currency == Currency::default() {
+        row.client_id.to_string()
+    } else {
+        format!("{}:{}", row.client_id, row.currency)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use rust_decimal_macros::dec;
+
+    fn clients_by_id() -> HashMap<ClientID, Client> {
+        HashMap::from([
+            (2, Client::create(dec!(6), dec!(7), false)),
+            (1, Client::create(dec!(20), dec!(100), true)),
+        ])
+    }
+
+    #[test]
+    fn test_write_report_array() {
+        let mut writer = Vec::new();
+
+        write_report(
+            &clients_by_id(),
+            &mut writer,
+            JsonLayout::Array,
+            &ReportConfig::default(),
+        )
+        .expect("Expected no errors.");
+
+        let output = String::from_utf8(writer).expect("Not UTF-8");
+        assert_eq!(
+            concat!(
+                r#"[{"client":1,"available":"80.0000","held":"20.0000","total":"100.0000","locked":true},"#,
+                r#"{"client":2,"available":"1.0000","held":"6.0000","total":"7.0000","locked":false}]"#,
+                "\n",
+            ),
+            output,
+        );
+    }
+
+    #[test]
+    fn test_write_report_map() {
+        let mut writer = Vec::new();
+
+        write_report(
+            &clients_by_id(),
+            &mut writer,
+            JsonLayout::Map,
+            &ReportConfig::default(),
+        )
+        .expect("Expected no errors.");
+
+        let output = String::from_utf8(writer).expect("Not UTF-8");
+        assert_eq!(
+            concat!(
+                r#"{"1":{"available":"80.0000","held":"20.0000","total":"100.0000","locked":true},"#,
+                r#""2":{"available":"1.0000","held":"6.0000","total":"7.0000","locked":false}}"#,
+                "\n",
+            ),
+            output,
+        );
+    }
+
+    #[test]
+    fn test_write_client() {
+        let mut writer = Vec::new();
+
+        write_client(
+            2,
+            &Client::create(dec!(6), dec!(7), false),
+            &mut writer,
+            &ReportConfig::default(),
+        )
+        .expect("Expected no errors.");
+
+        let output = String::from_utf8(writer).expect("Not UTF-8");
+        assert_eq!(
+            concat!(
+                r#"[{"client":2,"available":"1.0000","held":"6.0000","total":"7.0000","locked":false}]"#,
+                "\n",
+            ),
+            output,
+        );
+    }
+
+    #[test]
+    fn test_write_report_lines() {
+        let mut writer = Vec::new();
+
+        write_report(
+            &clients_by_id(),
+            &mut writer,
+            JsonLayout::Lines,
+            &ReportConfig::default(),
+        )
+        .expect("Expected no errors.");
+
+        let output = String::from_utf8(writer).expect("Not UTF-8");
+        assert_eq!(
+            concat!(
+                r#"{"client":1,"available":"80.0000","held":"20.0000","total":"100.0000","locked":true}"#,
+                "\n",
+                r#"{"client":2,"available":"1.0000","held":"6.0000","total":"7.0000","locked":false}"#,
+                "\n",
+            ),
+            output,
+        );
+    }
+}
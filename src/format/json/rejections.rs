@@ -0,0 +1,100 @@
+use serde::{Serialize, Serializer};
+use std::io::{self, Write};
+
+use crate::{
+    model::ProcessingError,
+    system::{Rejection, RejectionLogger},
+};
+
+// Writes each rejected event as a JSON object on its own line (i.e. JSON
+// Lines), so that rejections can be triaged automatically rather than by
+// reading free text.
+pub struct JsonRejectionLogger<W: Write> {
+    writer: W,
+}
+
+// Intermediary representation of a rejection for serialization.
+#[derive(Serialize)]
+struct JsonRejection<'a> {
+    line: Option<u64>,
+    byte: Option<u64>,
+    record: Option<&'a str>,
+    code: &'a str,
+    // written straight into the output rather than formatted first
+    #[serde(serialize_with = "serialize_message")]
+    message: &'a ProcessingError,
+}
+
+fn serialize_message<S: Serializer>(
+    error: &&ProcessingError,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(error)
+}
+
+impl<W: Write> JsonRejectionLogger<W> {
+    pub fn new(writer: W) -> Self {
+        Self { writer }
+    }
+}
+
+impl<W: Write> RejectionLogger for JsonRejectionLogger<W> {
+    fn log_rejection(&mut self, rejection: &Rejection) -> io::Result<()> {
+        let json_rejection = JsonRejection {
+            line: rejection.source.map(|source| source.line),
+            byte: rejection.source.map(|source| source.byte),
+            record: rejection.source.and_then(|source| source.record.as_deref()),
+            code: rejection.code(),
+            message: rejection.error,
+        };
+
+        serde_json::to_writer(&mut self.writer, &json_rejection)?;
+        self.writer.write_all(b"\n")
+    }
+
+    fn flush_rejections(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::model::Source;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_log_rejection() {
+        let mut writer = Vec::new();
+        let mut logger = JsonRejectionLogger::new(&mut writer);
+        let source = Source {
+            line: 7,
+            byte: 84,
+            record: Some(String::from("withdrawal,1,2,5")),
+        };
+
+        logger
+            .log_rejection(&Rejection {
+                source: Some(&source),
+                error: &ProcessingError::InsufficientFunds,
+            })
+            .expect("Expected no errors.");
+        logger
+            .log_rejection(&Rejection {
+                source: None,
+                error: &ProcessingError::TransactionNotFound { id: 3 },
+            })
+            .expect("Expected no errors.");
+
+        let output = String::from_utf8(writer).expect("Not UTF-8");
+        assert_eq!(
+            concat!(
+                r#"{"line":7,"byte":84,"record":"withdrawal,1,2,5","code":"insufficient_funds","message":"Insufficient funds."}"#,
+                "\n",
+                r#"{"line":null,"byte":null,"record":null,"code":"transaction_not_found","message":"Transaction 3 not found."}"#,
+                "\n",
+            ),
+            output,
+        );
+    }
+}
@@ -0,0 +1,156 @@
+use serde::Serialize;
+use std::{error::Error, io::Write};
+
+use crate::{
+    model::{Amount, TransactionID},
+    system::{InputStats, AMOUNT_BUCKET_BOUNDS},
+};
+
+// Intermediary representations of the stats for serialization.
+#[derive(Serialize)]
+struct JsonStats<'a> {
+    events: u64,
+    events_by_kind: Vec<JsonKindCount>,
+    malformed: u64,
+    clients: usize,
+    transaction_ids: Option<JsonRange>,
+    amounts: JsonAmounts<'a>,
+}
+
+#[derive(Serialize)]
+struct JsonKindCount {
+    kind: &'static str,
+    count: u64,
+}
+
+#[derive(Serialize)]
+struct JsonRange {
+    min: TransactionID,
+    max: TransactionID,
+}
+
+// Amounts are strings here like everywhere else in our JSON.
+#[derive(Serialize)]
+struct JsonAmounts<'a> {
+    count: u64,
+    min: Option<&'a Amount>,
+    max: Option<&'a Amount>,
+    mean: Option<Amount>,
+    buckets: Vec<JsonBucket>,
+}
+
+// Counts the amounts at least `from` (by size) and below `to`, where the last
+// bucket has no `to`.
+#[derive(Serialize)]
+struct JsonBucket {
+    from: u64,
+    to: Option<u64>,
+    count: u64,
+}
+
+// Writes the stats as a single pretty-printed JSON document, since like the
+// manifest it's as likely to be read by a person as by a machine.
+pub fn write_stats(stats: &InputStats, mut writer: impl Write) -> Result<(), Box<dyn Error>> {
+    let amounts = &stats.amounts;
+    let json_stats = JsonStats {
+        events: stats.events,
+        events_by_kind: stats
+            .events_by_kind
+            .iter()
+            .map(|&(kind, count)| JsonKindCount { kind, count })
+            .collect(),
+        malformed: stats.malformed,
+        clients: stats.clients,
+        transaction_ids: stats
+            .transaction_ids
+            .map(|(min, max)| JsonRange { min, max }),
+        amounts: JsonAmounts {
+            count: amounts.count,
+            min: amounts.min.as_ref(),
+            max: amounts.max.as_ref(),
+            // rounded to the same places as everything else, rather than
+            // however many the division left us with, and without trailing
+            // zeros like the amounts it came from
+            mean: amounts.mean().map(|mean| mean.round_dp(4).normalize()),
+            buckets: amounts
+                .buckets
+                .iter()
+                .enumerate()
+                .map(|(index, &count)| JsonBucket {
+                    from: index
+                        .checked_sub(1)
+                        .map_or(0, |previous| AMOUNT_BUCKET_BOUNDS[previous]),
+                    to: AMOUNT_BUCKET_BOUNDS.get(index).copied(),
+                    count,
+                })
+                .collect(),
+        },
+    };
+
+    serde_json::to_writer_pretty(&mut writer, &json_stats)?;
+    writer.write_all(b"\n")?;
+    writer.flush()?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::system::AmountStats;
+    use pretty_assertions::assert_eq;
+    use rust_decimal_macros::dec;
+
+    #[test]
+    fn test_write_stats() {
+        let mut writer = Vec::new();
+        let stats = InputStats {
+            events: 3,
+            events_by_kind: vec![("deposit", 2), ("dispute", 1)],
+            malformed: 1,
+            clients: 2,
+            transaction_ids: Some((1, 2)),
+            amounts: AmountStats {
+                count: 2,
+                min: Some(dec!(1)),
+                max: Some(dec!(2)),
+                sum: dec!(3),
+                buckets: [0, 2, 0, 0, 0, 0, 0, 0],
+            },
+        };
+
+        write_stats(&stats, &mut writer).expect("Expected no errors.");
+
+        let output: serde_json::Value =
+            serde_json::from_slice(&writer).expect("Expected valid JSON");
+        assert_eq!(
+            serde_json::json!({
+                "events": 3,
+                "events_by_kind": [
+                    { "kind": "deposit", "count": 2 },
+                    { "kind": "dispute", "count": 1 }
+                ],
+                "malformed": 1,
+                "clients": 2,
+                "transaction_ids": { "min": 1, "max": 2 },
+                "amounts": {
+                    "count": 2,
+                    "min": "1",
+                    "max": "2",
+                    "mean": "1.5",
+                    "buckets": [
+                        { "from": 0, "to": 1, "count": 0 },
+                        { "from": 1, "to": 10, "count": 2 },
+                        { "from": 10, "to": 100, "count": 0 },
+                        { "from": 100, "to": 1000, "count": 0 },
+                        { "from": 1000, "to": 10000, "count": 0 },
+                        { "from": 10000, "to": 100000, "count": 0 },
+                        { "from": 100000, "to": 1000000, "count": 0 },
+                        { "from": 1000000, "to": null, "count": 0 }
+                    ]
+                }
+            }),
+            output
+        );
+    }
+}
@@ -1,3 +1,388 @@
-// It's arguably overkill for this to be its own module but the idea is that
-// we could have other formats we want to support (e.g. JSON).
+// Each format we support gets its own module here, so that the business logic
+// never needs to know what the input or output looks like.
+#[cfg(feature = "arrow")]
+pub mod arrow;
+#[cfg(feature = "tokio")]
+pub mod async_io;
+pub mod columns;
+pub mod compression;
 pub mod csv;
+pub mod error;
+pub mod html;
+pub mod json;
+pub mod partition;
+#[cfg(feature = "postgres")]
+pub mod postgres;
+pub mod prometheus;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+pub mod table;
+pub mod xml;
+
+use std::{
+    borrow::Cow,
+    collections::{HashMap, HashSet},
+    error::Error,
+    io::{Read, Write},
+    str::FromStr,
+};
+
+use crate::{
+    model::{Amount, Balance, Client, ClientID, Currency, SourcedEvent},
+    system::{EventCounts, RejectionLogger},
+};
+use columns::{default_columns, Column, Field};
+use error::ParseError;
+use partition::Partition;
+
+// The scale amounts are written with unless configured otherwise. The spec
+// promises at most four decimal places so this keeps every amount intact.
+pub const DEFAULT_SCALE: u32 = 4;
+
+// The formats we can read events from.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum InputFormat {
+    #[default]
+    Csv,
+    JsonLines,
+}
+
+impl FromStr for InputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(InputFormat::Csv),
+            "jsonl" => Ok(InputFormat::JsonLines),
+            _ => Err(format!("Unknown input format: {}.", s)),
+        }
+    }
+}
+
+// Reads events in the given format, each along with where it came from and,
+// if `keep_records` is set, a copy of the record itself.
+pub fn parse_events<'a>(
+    format: InputFormat,
+    reader: impl Read + 'a,
+    keep_records: bool,
+) -> Box<dyn Iterator<Item = Result<SourcedEvent, ParseError>> + 'a> {
+    match (format, keep_records) {
+        (InputFormat::Csv, false) => Box::new(csv::input::parse_events(reader)),
+        (InputFormat::Csv, true) => Box::new(csv::input::parse_events_keeping_records(reader)),
+        (InputFormat::JsonLines, false) => Box::new(json::input::parse_events(reader)),
+        (InputFormat::JsonLines, true) => {
+            Box::new(json::input::parse_events_keeping_records(reader))
+        }
+    }
+}
+
+// The formats we can write the final report in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    Csv,
+    Json(json::output::JsonLayout),
+    Table,
+    Xml,
+    Html,
+    #[cfg(feature = "arrow")]
+    Arrow,
+}
+
+impl OutputFormat {
+    // The name this format is chosen by, as accepted by `from_str`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            OutputFormat::Csv => "csv",
+            OutputFormat::Json(json::output::JsonLayout::Array) => "json",
+            OutputFormat::Json(json::output::JsonLayout::Map) => "json-map",
+            OutputFormat::Json(json::output::JsonLayout::Lines) => "jsonl",
+            OutputFormat::Table => "table",
+            OutputFormat::Xml => "xml",
+            OutputFormat::Html => "html",
+            #[cfg(feature = "arrow")]
+            OutputFormat::Arrow => "arrow",
+        }
+    }
+
+    // The file extension reports in this format are written with, when we're
+    // the ones choosing the file name.
+    pub fn extension(&self) -> &'static str {
+        match self {
+            OutputFormat::Csv => "csv",
+            OutputFormat::Json(json::output::JsonLayout::Lines) => "jsonl",
+            OutputFormat::Json(_) => "json",
+            OutputFormat::Table => "txt",
+            OutputFormat::Xml => "xml",
+            OutputFormat::Html => "html",
+            #[cfg(feature = "arrow")]
+            OutputFormat::Arrow => "arrows",
+        }
+    }
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(OutputFormat::Csv),
+            "json" => Ok(OutputFormat::Json(json::output::JsonLayout::Array)),
+            "json-map" => Ok(OutputFormat::Json(json::output::JsonLayout::Map)),
+            "jsonl" => Ok(OutputFormat::Json(json::output::JsonLayout::Lines)),
+            "table" => Ok(OutputFormat::Table),
+            "xml" => Ok(OutputFormat::Xml),
+            "html" => Ok(OutputFormat::Html),
+            #[cfg(feature = "arrow")]
+            "arrow" => Ok(OutputFormat::Arrow),
+            _ => Err(format!("Unknown output format: {}.", s)),
+        }
+    }
+}
+
+// The formats we can log rejected events in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorFormat {
+    Text,
+    Json,
+}
+
+impl FromStr for ErrorFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(ErrorFormat::Text),
+            "json" => Ok(ErrorFormat::Json),
+            _ => Err(format!("Unknown error format: {}.", s)),
+        }
+    }
+}
+
+// Returns a logger that writes rejected events to the given writer in the
+// given format.
+pub fn rejection_logger<'a>(
+    format: ErrorFormat,
+    writer: impl Write + 'a,
+) -> Box<dyn RejectionLogger + 'a> {
+    match format {
+        ErrorFormat::Text => Box::new(writer),
+        ErrorFormat::Json => Box::new(json::rejections::JsonRejectionLogger::new(writer)),
+    }
+}
+
+// Options controlling how reports are written, independent of the format.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReportConfig {
+    pub format: OutputFormat,
+    // Every amount is written with exactly this many decimal places so that
+    // reports can be diffed reliably (e.g. `2.0000` rather than `2.0` or `2`).
+    pub scale: u32,
+    pub order: ReportOrder,
+    pub columns: Vec<Column>,
+    // If set, only the clients in this partition are written.
+    pub partition: Option<Partition>,
+    // If set, only these clients are written, e.g. to look into one of them
+    // without wading through everyone else.
+    pub clients: Option<HashSet<ClientID>>,
+}
+
+// The order clients are listed in. Sorting means collecting every client
+// into a vector at the end of the run, which for tens of millions of clients
+// is a sizeable allocation, so callers that don't care about the order can
+// have the clients streamed straight out of the map instead.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReportOrder {
+    ClientId,
+    Unsorted,
+}
+
+impl ReportOrder {
+    // The name this order is chosen by, as accepted by `from_str`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            ReportOrder::ClientId => "client_id",
+            ReportOrder::Unsorted => "unsorted",
+        }
+    }
+}
+
+impl FromStr for ReportOrder {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "client_id" => Ok(ReportOrder::ClientId),
+            "unsorted" => Ok(ReportOrder::Unsorted),
+            _ => Err(format!("Unknown report order: {}.", s)),
+        }
+    }
+}
+
+impl Default for ReportConfig {
+    fn default() -> Self {
+        Self {
+            format: OutputFormat::Csv,
+            scale: DEFAULT_SCALE,
+            order: ReportOrder::ClientId,
+            columns: columns::default_columns(),
+            partition: None,
+            clients: None,
+        }
+    }
+}
+
+// Writes the final report in the configured format. Only some formats have
+// room for a summary of the run alongside the clients, hence the event counts.
+pub fn write_report(
+    clients_by_id: &HashMap<ClientID, Client>,
+    event_counts: &EventCounts,
+    writer: impl Write,
+    config: &ReportConfig,
+) -> Result<(), Box<dyn Error>> {
+    match config.format {
+        OutputFormat::Csv => csv::output::write_report(clients_by_id, writer, config),
+        OutputFormat::Json(layout) => {
+            json::output::write_report(clients_by_id, writer, layout, config)
+        }
+        OutputFormat::Table => table::output::write_report(clients_by_id, writer, config),
+        OutputFormat::Xml => xml::output::write_report(clients_by_id, writer, config),
+        OutputFormat::Html => {
+            html::output::write_report(clients_by_id, event_counts, writer, config)
+        }
+        #[cfg(feature = "arrow")]
+        OutputFormat::Arrow => arrow::output::write_report(clients_by_id, writer, config),
+    }
+}
+
+// Rounds (half to even) and pads an amount so that it has exactly `scale`
+// decimal places.
+pub(crate) fn normalize_amount(amount: Amount, scale: u32) -> Amount {
+    let mut normalized = amount.round_dp(scale);
+    normalized.rescale(scale);
+    normalized
+}
+
+// One row of a client report: what a client holds in one currency, along
+// with everything that applies to the client as a whole.
+#[derive(Debug, Clone, Copy)]
+pub struct ReportRow<'a> {
+    pub client_id: ClientID,
+    pub client: &'a Client,
+    pub currency: Currency,
+    pub balance: &'a Balance,
+}
+
+// The columns to write the clients with. A client's rows can't be told apart
+// without their currency, so when the columns are the default ones and anyone
+// has a balance in a named currency, the currency goes in after the client.
+// Everyone is checked rather than just the clients being written, so that
+// every partition of a run has the same columns.
+pub(crate) fn report_columns<'a>(
+    clients_by_id: &HashMap<ClientID, Client>,
+    config: &'a ReportConfig,
+) -> Cow<'a, [Column]> {
+    let multi_currency = || {
+        clients_by_id.values().any(|client| {
+            client
+                .balances()
+                .any(|(currency, _)| currency != Currency::default())
+        })
+    };
+    if config.columns != default_columns() || !multi_currency() {
+        return Cow::Borrowed(&config.columns);
+    }
+
+    let mut columns = config.columns.clone();
+    columns.insert(1, Column::new(Field::Currency));
+    Cow::Owned(columns)
+}
+
+// Shared by the report writers so that every format lists the same rows in the
+// same order. Each client gets a row per currency, in currency order.
+fn ordered_rows<'a>(
+    clients_by_id: &'a HashMap<ClientID, Client>,
+    config: &'a ReportConfig,
+) -> Box<dyn Iterator<Item = ReportRow<'a>> + 'a> {
+    let partition = config.partition;
+    let only_clients = config.clients.as_ref();
+    let clients = clients_by_id
+        .iter()
+        .map(|(client_id, client)| (*client_id, client))
+        .filter(move |(client_id, _)| {
+            partition.is_none_or(|partition| partition.contains(*client_id))
+                && only_clients.is_none_or(|only_clients| only_clients.contains(client_id))
+        });
+    let rows = |(client_id, client): (ClientID, &'a Client)| {
+        client.balances().map(move |(currency, balance)| ReportRow {
+            client_id,
+            client,
+            currency,
+            balance,
+        })
+    };
+
+    match config.order {
+        ReportOrder::ClientId => {
+            let mut entries: Vec<(ClientID, &Client)> = clients.collect();
+            // I assume that actually producing a report is a small part that
+            // happens at the end of a long process of processing events, and
+            // that it's convenient to order records by client ID despite the
+            // spec being indifferent, hence this being the default.
+            entries.sort_by_key(|(client_id, _)| *client_id);
+            Box::new(entries.into_iter().flat_map(rows))
+        }
+        ReportOrder::Unsorted => Box::new(clients.flat_map(rows)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use rust_decimal_macros::dec;
+
+    #[test]
+    fn test_normalize_amount() {
+        assert_eq!("2.0000", normalize_amount(dec!(2), 4).to_string());
+        assert_eq!("2.0000", normalize_amount(dec!(2.0), 4).to_string());
+        assert_eq!("1.6111", normalize_amount(dec!(1.61111), 4).to_string());
+        assert_eq!("0.12", normalize_amount(dec!(0.125), 2).to_string());
+        assert_eq!("0.14", normalize_amount(dec!(0.135), 2).to_string());
+        assert_eq!("-3", normalize_amount(dec!(-3.0), 0).to_string());
+    }
+
+    #[test]
+    fn test_output_format_from_str() {
+        assert_eq!(Ok(OutputFormat::Csv), "csv".parse());
+        assert_eq!(
+            Ok(OutputFormat::Json(json::output::JsonLayout::Array)),
+            "json".parse()
+        );
+        assert_eq!(
+            Ok(OutputFormat::Json(json::output::JsonLayout::Map)),
+            "json-map".parse()
+        );
+        assert_eq!(
+            Ok(OutputFormat::Json(json::output::JsonLayout::Lines)),
+            "jsonl".parse()
+        );
+        assert_eq!(Ok(OutputFormat::Table), "table".parse());
+        assert_eq!(Ok(OutputFormat::Xml), "xml".parse());
+        assert_eq!(Ok(OutputFormat::Html), "html".parse());
+        assert_eq!(
+            Err(String::from("Unknown output format: yaml.")),
+            "yaml".parse::<OutputFormat>()
+        );
+    }
+
+    #[test]
+    fn test_report_order_from_str() {
+        for order in [ReportOrder::ClientId, ReportOrder::Unsorted] {
+            assert_eq!(Ok(order), order.name().parse());
+        }
+        assert_eq!(
+            Err(String::from("Unknown report order: random.")),
+            "random".parse::<ReportOrder>()
+        );
+    }
+}
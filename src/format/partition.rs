@@ -0,0 +1,97 @@
+use std::str::FromStr;
+
+use crate::model::ClientID;
+
+// How clients are spread across the files of a partitioned report.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Partitioning {
+    // Contiguous ranges of client IDs, so that each file covers e.g. clients
+    // 0-16383, 16384-32767, and so on. The ranges cover every possible ID, so
+    // this only evens things out when the IDs in use do too.
+    Range,
+    // Clients are scattered by a hash of their ID, which evens things out
+    // when IDs are clustered.
+    Hash,
+}
+
+// One file's share of a partitioned report.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Partition {
+    pub partitioning: Partitioning,
+    pub index: usize,
+    pub count: usize,
+}
+
+impl Partition {
+    pub fn contains(&self, client_id: ClientID) -> bool {
+        partition_of(client_id, self.partitioning, self.count) == self.index
+    }
+}
+
+// Which of `count` partitions a client belongs in. This needs to be stable
+// across runs (and builds) so that downstream loaders can rely on it, which
+// rules out the standard library's randomly seeded hasher.
+pub fn partition_of(client_id: ClientID, partitioning: Partitioning, count: usize) -> usize {
+    match partitioning {
+        Partitioning::Range => client_id as usize * count / (ClientID::MAX as usize + 1),
+        Partitioning::Hash => {
+            // Fibonacci hashing: multiplying by 2^32 / φ scatters neighbouring
+            // IDs far apart, but only in the high bits of the product, so
+            // that's what picks the partition (scaled to `count` rather than
+            // shifted, so that it needn't be a power of two)
+            let hash = (client_id as u32).wrapping_mul(2_654_435_769);
+            ((u64::from(hash) * count as u64) >> 32) as usize
+        }
+    }
+}
+
+impl FromStr for Partitioning {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "range" => Ok(Partitioning::Range),
+            "hash" => Ok(Partitioning::Hash),
+            _ => Err(format!("Unknown partitioning: {}.", s)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_range_partitioning() {
+        assert_eq!(0, partition_of(0, Partitioning::Range, 4));
+        assert_eq!(0, partition_of(16383, Partitioning::Range, 4));
+        assert_eq!(1, partition_of(16384, Partitioning::Range, 4));
+        assert_eq!(3, partition_of(ClientID::MAX, Partitioning::Range, 4));
+    }
+
+    #[test]
+    fn test_hash_partitioning() {
+        // every client lands somewhere, and neighbouring IDs get spread out
+        let partitions = (0..8)
+            .map(|client_id| partition_of(client_id, Partitioning::Hash, 4))
+            .collect::<Vec<_>>();
+
+        assert!(partitions.iter().all(|partition| *partition < 4));
+        for partition in 0..4 {
+            assert!(partitions.contains(&partition));
+        }
+
+        // small, sequential IDs are what most inputs have, and they should
+        // still come out about even
+        let mut sizes = [0; 3];
+        for client_id in 1..=300 {
+            sizes[partition_of(client_id, Partitioning::Hash, 3)] += 1;
+        }
+        assert!(
+            sizes.iter().all(|size| (90..=110).contains(size)),
+            "{:?}",
+            sizes
+        );
+    }
+}
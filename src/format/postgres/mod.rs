@@ -0,0 +1,4 @@
+// Writing the report into Postgres rather than a file. This is behind the
+// `postgres` feature since most runs have nowhere to put it.
+
+pub mod output;
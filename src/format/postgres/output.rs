@@ -0,0 +1,191 @@
+use postgres::{types::ToSql, GenericClient, Statement};
+use std::{collections::HashMap, error::Error};
+
+use crate::{
+    format::{normalize_amount, ordered_rows, ReportConfig, ReportRow},
+    model::{Amount, Balance, Client, ClientID},
+};
+
+// How many parameters each row takes up in an `INSERT`.
+const ROW_PARAMS: usize = 6;
+
+// Postgres numbers a statement's parameters with 16 bits, which caps how many
+// rows fit in one `INSERT`.
+pub const MAX_BATCH_SIZE: usize = u16::MAX as usize / ROW_PARAMS;
+
+// Takes the resultant clients after processing events, and upserts a row per
+// client per currency into the given table (creating it if it isn't there),
+// `batch_size` rows to an `INSERT`. It's all done in one transaction, so a run
+// that fails partway leaves the table as it was. Rows are keyed by client and
+// currency, so a client that's already there from an earlier run is updated,
+// and one that isn't in this run is left alone. Amounts are numerics at the
+// report's scale. The table can be qualified with its schema, e.g.
+// `ledger.balances`. Returns how many rows were upserted.
+pub fn write_report(
+    clients_by_id: &HashMap<ClientID, Client>,
+    client: &mut impl GenericClient,
+    table: &str,
+    batch_size: usize,
+    config: &ReportConfig,
+) -> Result<u64, Box<dyn Error>> {
+    if !(1..=MAX_BATCH_SIZE).contains(&batch_size) {
+        return Err(format!(
+            "Batch size {} is out of range for Postgres (1 to {}).",
+            batch_size, MAX_BATCH_SIZE
+        )
+        .into());
+    }
+    let table = quote_table(table)?;
+
+    let mut transaction = client.transaction()?;
+    transaction.batch_execute(&create_table_statement(&table))?;
+
+    // every batch but the last is full, so they can share a statement
+    let mut full_batch_statement: Option<Statement> = None;
+    let mut upserted = 0;
+    let mut rows_iter = ordered_rows(clients_by_id, config);
+    loop {
+        let rows = rows_iter.by_ref().take(batch_size).collect::<Vec<_>>();
+        if rows.is_empty() {
+            break;
+        }
+
+        let statement = match &full_batch_statement {
+            Some(statement) if rows.len() == batch_size => statement.clone(),
+            _ => {
+                let statement = transaction.prepare(&upsert_statement(&table, rows.len()))?;
+                if rows.len() == batch_size {
+                    full_batch_statement = Some(statement.clone());
+                }
+                statement
+            }
+        };
+        let values = rows
+            .iter()
+            .map(|row| RowValues::of(row, config.scale))
+            .collect::<Vec<_>>();
+        let params = values
+            .iter()
+            .flat_map(RowValues::params)
+            .collect::<Vec<_>>();
+        upserted += transaction.execute(&statement, &params)?;
+    }
+
+    transaction.commit()?;
+    Ok(upserted)
+}
+
+// A row's parameters, owned so that they can be borrowed as `ToSql`s.
+struct RowValues {
+    client: i32,
+    currency: String,
+    available: Amount,
+    held: Amount,
+    total: Amount,
+    locked: bool,
+}
+
+impl RowValues {
+    fn of(row: &ReportRow, scale: u32) -> Self {
+        let amount = |of: fn(&Balance) -> Amount| normalize_amount(of(row.balance), scale);
+        Self {
+            client: i32::from(row.client_id),
+            currency: row.currency.to_string(),
+            available: amount(Balance::available),
+            held: amount(Balance::held),
+            total: amount(Balance::total),
+            locked: row.client.locked(),
+        }
+    }
+
+    // In the order the columns are in `upsert_statement`.
+    fn params(&self) -> [&(dyn ToSql + Sync); ROW_PARAMS] {
+        [
+            &self.client,
+            &self.currency,
+            &self.available,
+            &self.held,
+            &self.total,
+            &self.locked,
+        ]
+    }
+}
+
+// Quotes each part of a (possibly schema qualified) table name, so that
+// whatever it's called it can't be taken for anything but a name.
+fn quote_table(table: &str) -> Result<String, Box<dyn Error>> {
+    let parts = table.split('.').collect::<Vec<_>>();
+    if parts.len() > 2 || parts.iter().any(|part| part.is_empty()) {
+        return Err(format!("Invalid Postgres table name: {}.", table).into());
+    }
+
+    Ok(parts
+        .iter()
+        .map(|part| format!("\"{}\"", part.replace('"', "\"\"")))
+        .collect::<Vec<_>>()
+        .join("."))
+}
+
+fn create_table_statement(table: &str) -> String {
+    format!(
+        "CREATE TABLE IF NOT EXISTS {} (\
+         client integer NOT NULL, \
+         currency text NOT NULL, \
+         available numeric NOT NULL, \
+         held numeric NOT NULL, \
+         total numeric NOT NULL, \
+         locked boolean NOT NULL, \
+         PRIMARY KEY (client, currency))",
+        table
+    )
+}
+
+fn upsert_statement(table: &str, rows: usize) -> String {
+    let values = (0..rows)
+        .map(|row| {
+            let params = (1..=ROW_PARAMS)
+                .map(|param| format!("${}", row * ROW_PARAMS + param))
+                .collect::<Vec<_>>();
+            format!("({})", params.join(", "))
+        })
+        .collect::<Vec<_>>();
+
+    format!(
+        "INSERT INTO {} (client, currency, available, held, total, locked) VALUES {} \
+         ON CONFLICT (client, currency) DO UPDATE SET \
+         available = EXCLUDED.available, \
+         held = EXCLUDED.held, \
+         total = EXCLUDED.total, \
+         locked = EXCLUDED.locked",
+        table,
+        values.join(", ")
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_statements() {
+        assert_eq!(
+            "\"balances\"",
+            quote_table("balances").expect("Expected a valid table name")
+        );
+        assert_eq!(
+            "\"ledger\".\"odd \"\"name\"\"\"",
+            quote_table("ledger.odd \"name\"").expect("Expected a valid table name")
+        );
+        assert!(quote_table("ledger.").is_err());
+        assert!(quote_table("a.b.c").is_err());
+
+        assert_eq!(
+            "INSERT INTO \"balances\" (client, currency, available, held, total, locked) \
+             VALUES ($1, $2, $3, $4, $5, $6), ($7, $8, $9, $10, $11, $12) \
+             ON CONFLICT (client, currency) DO UPDATE SET available = EXCLUDED.available, \
+             held = EXCLUDED.held, total = EXCLUDED.total, locked = EXCLUDED.locked",
+            upsert_statement("\"balances\"", 2)
+        );
+    }
+}
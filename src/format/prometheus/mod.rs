@@ -0,0 +1,3 @@
+// Everything to do with Prometheus' text exposition format lives here.
+
+pub mod output;
@@ -0,0 +1,283 @@
+use std::{collections::HashMap, error::Error, io::Write, time::Duration};
+
+use crate::{
+    model::{Client, ClientID},
+    system::EventCounts,
+};
+
+// Writes metrics about a finished run in the text format that node-exporter's
+// textfile collector reads. Everything is a gauge because each file describes
+// a single run rather than accumulating across runs.
+pub fn write_metrics(
+    clients_by_id: &HashMap<ClientID, Client>,
+    event_counts: &EventCounts,
+    run_duration: Duration,
+    mut writer: impl Write,
+) -> Result<(), Box<dyn Error>> {
+    let locked_count = clients_by_id
+        .values()
+        .filter(|client| client.locked())
+        .count();
+
+    write_header(
+        &mut writer,
+        "challenge_events_processed",
+        "gauge",
+        "Events processed, by kind.",
+    )?;
+    for (kind, count) in sorted(&event_counts.processed_by_kind) {
+        writeln!(
+            writer,
+            "challenge_events_processed{{kind=\"{}\"}} {}",
+            kind, count
+        )?;
+    }
+
+    write_header(
+        &mut writer,
+        "challenge_events_rejected",
+        "gauge",
+        "Events rejected, by reason.",
+    )?;
+    for (code, count) in sorted(&event_counts.rejected_by_code) {
+        writeln!(
+            writer,
+            "challenge_events_rejected{{reason=\"{}\"}} {}",
+            code, count
+        )?;
+    }
+
+    write_header(
+        &mut writer,
+        "challenge_clients",
+        "gauge",
+        "Clients in the report.",
+    )?;
+    writeln!(writer, "challenge_clients {}", clients_by_id.len())?;
+
+    write_header(
+        &mut writer,
+        "challenge_locked_accounts",
+        "gauge",
+        "Locked accounts.",
+    )?;
+    writeln!(writer, "challenge_locked_accounts {}", locked_count)?;
+
+    write_header(
+        &mut writer,
+        "challenge_run_duration_seconds",
+        "gauge",
+        "How long the run took.",
+    )?;
+    writeln!(
+        writer,
+        "challenge_run_duration_seconds {}",
+        run_duration.as_secs_f64()
+    )?;
+
+    writer.flush()?;
+
+    Ok(())
+}
+
+// Writes the metrics a running service is scraped for, e.g. by `challenge
+// serve`'s `GET /metrics`. Unlike a run's, the event counts only ever go up
+// for as long as it's running, so they're counters, and Prometheus works out
+// the events per second from them (e.g. with `rate()`). The rest are gauges
+// of how things stand right now, with the memory left out if it isn't known.
+pub fn write_live_metrics(
+    clients_by_id: &HashMap<ClientID, Client>,
+    event_counts: &EventCounts,
+    uptime: Duration,
+    resident_memory: Option<u64>,
+    mut writer: impl Write,
+) -> Result<(), Box<dyn Error>> {
+    let locked_count = clients_by_id
+        .values()
+        .filter(|client| client.locked())
+        .count();
+
+    write_header(
+        &mut writer,
+        "challenge_events_processed_total",
+        "counter",
+        "Events processed, by kind.",
+    )?;
+    for (kind, count) in sorted(&event_counts.processed_by_kind) {
+        writeln!(
+            writer,
+            "challenge_events_processed_total{{kind=\"{}\"}} {}",
+            kind, count
+        )?;
+    }
+
+    write_header(
+        &mut writer,
+        "challenge_events_rejected_total",
+        "counter",
+        "Events rejected, by reason.",
+    )?;
+    for (code, count) in sorted(&event_counts.rejected_by_code) {
+        writeln!(
+            writer,
+            "challenge_events_rejected_total{{reason=\"{}\"}} {}",
+            code, count
+        )?;
+    }
+
+    write_header(
+        &mut writer,
+        "challenge_clients",
+        "gauge",
+        "Clients being tracked.",
+    )?;
+    writeln!(writer, "challenge_clients {}", clients_by_id.len())?;
+
+    write_header(
+        &mut writer,
+        "challenge_locked_accounts",
+        "gauge",
+        "Locked accounts.",
+    )?;
+    writeln!(writer, "challenge_locked_accounts {}", locked_count)?;
+
+    if let Some(bytes) = resident_memory {
+        write_header(
+            &mut writer,
+            "challenge_resident_memory_bytes",
+            "gauge",
+            "Memory the process has resident.",
+        )?;
+        writeln!(writer, "challenge_resident_memory_bytes {}", bytes)?;
+    }
+
+    write_header(
+        &mut writer,
+        "challenge_uptime_seconds",
+        "gauge",
+        "How long the service has been running.",
+    )?;
+    writeln!(writer, "challenge_uptime_seconds {}", uptime.as_secs_f64())?;
+
+    writer.flush()?;
+
+    Ok(())
+}
+
+fn write_header(
+    writer: &mut impl Write,
+    name: &str,
+    kind: &str,
+    help: &str,
+) -> Result<(), Box<dyn Error>> {
+    writeln!(writer, "# HELP {} {}", name, help)?;
+    writeln!(writer, "# TYPE {} {}", name, kind)?;
+    Ok(())
+}
+
+// The counts are in the order they were first seen, which would make the file
+// churn between runs for no reason.
+fn sorted(counts: &[(&'static str, u64)]) -> Vec<(&'static str, u64)> {
+    let mut counts = counts.to_vec();
+    counts.sort();
+    counts
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use rust_decimal_macros::dec;
+
+    #[test]
+    fn test_write_metrics() {
+        let mut writer = Vec::new();
+        let clients_by_id = HashMap::from([
+            (1, Client::create(dec!(0), dec!(10), true)),
+            (2, Client::create(dec!(0), dec!(5), false)),
+        ]);
+        let event_counts = EventCounts {
+            processed: 4,
+            rejected: 1,
+            processed_by_kind: vec![("withdrawal", 1), ("deposit", 3)],
+            rejected_by_code: vec![("insufficient_funds", 1)],
+        };
+
+        write_metrics(
+            &clients_by_id,
+            &event_counts,
+            Duration::from_millis(1500),
+            &mut writer,
+        )
+        .expect("Expected no errors.");
+
+        let output = String::from_utf8(writer).expect("Not UTF-8");
+        assert_eq!(
+            concat!(
+                "# HELP challenge_events_processed Events processed, by kind.\n",
+                "# TYPE challenge_events_processed gauge\n",
+                "challenge_events_processed{kind=\"deposit\"} 3\n",
+                "challenge_events_processed{kind=\"withdrawal\"} 1\n",
+                "# HELP challenge_events_rejected Events rejected, by reason.\n",
+                "# TYPE challenge_events_rejected gauge\n",
+                "challenge_events_rejected{reason=\"insufficient_funds\"} 1\n",
+                "# HELP challenge_clients Clients in the report.\n",
+                "# TYPE challenge_clients gauge\n",
+                "challenge_clients 2\n",
+                "# HELP challenge_locked_accounts Locked accounts.\n",
+                "# TYPE challenge_locked_accounts gauge\n",
+                "challenge_locked_accounts 1\n",
+                "# HELP challenge_run_duration_seconds How long the run took.\n",
+                "# TYPE challenge_run_duration_seconds gauge\n",
+                "challenge_run_duration_seconds 1.5\n",
+            ),
+            output,
+        );
+    }
+
+    #[test]
+    fn test_write_live_metrics() {
+        let mut writer = Vec::new();
+        let clients_by_id = HashMap::from([(1, Client::create(dec!(0), dec!(10), true))]);
+        let event_counts = EventCounts {
+            processed: 2,
+            rejected: 1,
+            processed_by_kind: vec![("deposit", 2)],
+            rejected_by_code: vec![("insufficient_funds", 1)],
+        };
+
+        write_live_metrics(
+            &clients_by_id,
+            &event_counts,
+            Duration::from_secs(60),
+            Some(4096),
+            &mut writer,
+        )
+        .expect("Expected no errors.");
+
+        let output = String::from_utf8(writer).expect("Not UTF-8");
+        assert_eq!(
+            concat!(
+                "# HELP challenge_events_processed_total Events processed, by kind.\n",
+                "# TYPE challenge_events_processed_total counter\n",
+                "challenge_events_processed_total{kind=\"deposit\"} 2\n",
+                "# HELP challenge_events_rejected_total Events rejected, by reason.\n",
+                "# TYPE challenge_events_rejected_total counter\n",
+                "challenge_events_rejected_total{reason=\"insufficient_funds\"} 1\n",
+                "# HELP challenge_clients Clients being tracked.\n",
+                "# TYPE challenge_clients gauge\n",
+                "challenge_clients 1\n",
+                "# HELP challenge_locked_accounts Locked accounts.\n",
+                "# TYPE challenge_locked_accounts gauge\n",
+                "challenge_locked_accounts 1\n",
+                "# HELP challenge_resident_memory_bytes Memory the process has resident.\n",
+                "# TYPE challenge_resident_memory_bytes gauge\n",
+                "challenge_resident_memory_bytes 4096\n",
+                "# HELP challenge_uptime_seconds How long the service has been running.\n",
+                "# TYPE challenge_uptime_seconds gauge\n",
+                "challenge_uptime_seconds 60\n",
+            ),
+            output,
+        );
+    }
+}
@@ -0,0 +1,257 @@
+use rusqlite::{params, Connection};
+use std::{error::Error, fs, io, path::Path};
+
+use crate::{
+    model::{Event, Timestamp},
+    system::ProcessorObserver,
+};
+
+// How many rows go in before they're committed, so that a run's journal can be
+// looked at while it's still going without a commit per row slowing it down.
+const COMMIT_INTERVAL: usize = 10_000;
+
+const SCHEMA: &str = "
+    CREATE TABLE transactions (
+        event INTEGER NOT NULL,
+        timestamp INTEGER,
+        type TEXT NOT NULL,
+        client INTEGER NOT NULL,
+        tx INTEGER NOT NULL,
+        currency TEXT NOT NULL,
+        amount TEXT NOT NULL,
+        counterparty TEXT
+    );
+    CREATE TABLE dispute_steps (
+        event INTEGER NOT NULL,
+        timestamp INTEGER,
+        type TEXT NOT NULL,
+        client INTEGER NOT NULL,
+        tx INTEGER NOT NULL,
+        amount TEXT,
+        lapsed INTEGER NOT NULL
+    );
+";
+
+// Writes the deposits, withdrawals and dispute steps a run accepts into a
+// SQLite database as they're accepted, so that there's something to query once
+// it's done (or while it's going). Each gets a row in `transactions` or
+// `dispute_steps`, with the number it was processed as (the one the dispute
+// report goes by) and its timestamp, if it had one. Amounts are text, since
+// SQLite would make floats of them. A dispute that lapsed under
+// `EngineConfig::dispute_expiry` gets a resolve with `lapsed` set.
+//
+// It's an observer, so nothing about it can fail the event it's told about.
+// The first error is held onto instead, nothing more is written after it, and
+// `finish` returns it.
+pub struct SqliteJournal {
+    connection: Connection,
+    // about the event that's being processed
+    event_number: u64,
+    timestamp: Option<Timestamp>,
+    uncommitted: usize,
+    error: Option<rusqlite::Error>,
+}
+
+impl SqliteJournal {
+    // Starts a journal in a new database at `path`, replacing whatever was
+    // there, since each one is meant to be just the one run.
+    pub fn create(path: impl AsRef<Path>) -> Result<Self, Box<dyn Error>> {
+        match fs::remove_file(&path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+        Self::new(Connection::open(path)?)
+    }
+
+    fn new(connection: Connection) -> Result<Self, Box<dyn Error>> {
+        connection.execute_batch(SCHEMA)?;
+        connection.execute_batch("BEGIN")?;
+        Ok(Self {
+            connection,
+            event_number: 0,
+            timestamp: None,
+            uncommitted: 0,
+            error: None,
+        })
+    }
+
+    // Commits whatever's left, or returns the error that stopped it.
+    pub fn finish(self) -> Result<(), Box<dyn Error>> {
+        if let Some(e) = self.error {
+            return Err(e.into());
+        }
+        self.connection.execute_batch("COMMIT")?;
+        Ok(())
+    }
+
+    fn record(&mut self, event: &Event, lapsed: bool) {
+        if self.error.is_some() {
+            return;
+        }
+        if let Err(e) = self.insert(event, lapsed) {
+            self.error = Some(e);
+        }
+    }
+
+    fn insert(&mut self, event: &Event, lapsed: bool) -> rusqlite::Result<()> {
+        match event {
+            Event::Transaction {
+                transaction_id,
+                client_id,
+                currency,
+                amount,
+                counterparty,
+                ..
+            } => {
+                self.connection
+                    .prepare_cached(
+                        "INSERT INTO transactions \
+                         (event, timestamp, type, client, tx, currency, amount, counterparty) \
+                         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+                    )?
+                    .execute(params![
+                        self.event_number,
+                        self.timestamp,
+                        event.kind_name(),
+                        client_id,
+                        transaction_id,
+                        currency.to_string(),
+                        amount.to_string(),
+                        counterparty,
+                    ])?;
+            }
+            Event::DisputeStep {
+                transaction_id,
+                client_id,
+                amount,
+                ..
+            } => {
+                self.connection
+                    .prepare_cached(
+                        "INSERT INTO dispute_steps \
+                         (event, timestamp, type, client, tx, amount, lapsed) \
+                         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                    )?
+                    .execute(params![
+                        self.event_number,
+                        self.timestamp,
+                        event.kind_name(),
+                        client_id,
+                        transaction_id,
+                        amount.map(|amount| amount.to_string()),
+                        lapsed,
+                    ])?;
+            }
+            _ => return Ok(()),
+        }
+
+        self.uncommitted += 1;
+        if self.uncommitted == COMMIT_INTERVAL {
+            self.connection.execute_batch("COMMIT; BEGIN")?;
+            self.uncommitted = 0;
+        }
+        Ok(())
+    }
+}
+
+impl ProcessorObserver for SqliteJournal {
+    fn on_event(&mut self, event_number: u64, timestamp: Option<Timestamp>) {
+        self.event_number = event_number;
+        self.timestamp = timestamp;
+    }
+
+    fn on_accepted(&mut self, event: &Event) {
+        self.record(event, false);
+    }
+
+    fn on_dispute_lapsed(&mut self, resolve: &Event) {
+        self.record(resolve, true);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        model::{Currency, DisputeStepKind, TransactionKind},
+        system::EngineConfig,
+        Engine,
+    };
+    use pretty_assertions::assert_eq;
+    use rust_decimal_macros::dec;
+
+    #[test]
+    fn test_sqlite_journal() {
+        let mut journal =
+            SqliteJournal::new(Connection::open_in_memory().expect("Expected no errors."))
+                .expect("Expected no errors.");
+        let deposit = |transaction_id, amount| Event::Transaction {
+            kind: TransactionKind::Deposit,
+            transaction_id,
+            client_id: 1,
+            currency: Currency::default(),
+            amount,
+            counterparty: Some(String::from("shop")),
+        };
+        let mut engine = Engine::builder()
+            .config(EngineConfig::default())
+            .observer(&mut journal)
+            .build();
+        for event in [
+            deposit(1, dec!(1.5)),
+            // rejected, so it isn't journaled
+            deposit(1, dec!(2)),
+            Event::DisputeStep {
+                kind: DisputeStepKind::Dispute,
+                transaction_id: 1,
+                client_id: 1,
+                amount: Some(dec!(0.5)),
+            },
+        ] {
+            engine.process_event(event).expect("Expected no errors.");
+        }
+        drop(engine);
+
+        let transactions = journal
+            .connection
+            .prepare(
+                "SELECT event, type, client, tx, currency, amount, counterparty FROM transactions",
+            )
+            .and_then(|mut statement| {
+                statement
+                    .query_map([], |row| {
+                        Ok(format!(
+                            "{} {} {} {} '{}' {} {}",
+                            row.get::<_, u64>(0)?,
+                            row.get::<_, String>(1)?,
+                            row.get::<_, u16>(2)?,
+                            row.get::<_, u32>(3)?,
+                            row.get::<_, String>(4)?,
+                            row.get::<_, String>(5)?,
+                            row.get::<_, String>(6)?,
+                        ))
+                    })?
+                    .collect::<rusqlite::Result<Vec<_>>>()
+            })
+            .expect("Expected no errors.");
+        assert_eq!(vec!["1 deposit 1 1 '' 1.5 shop"], transactions);
+        let dispute_steps = journal
+            .connection
+            .query_row(
+                "SELECT event, type, amount, lapsed FROM dispute_steps",
+                [],
+                |row| {
+                    Ok(format!(
+                        "{} {} {} {}",
+                        row.get::<_, u64>(0)?,
+                        row.get::<_, String>(1)?,
+                        row.get::<_, String>(2)?,
+                        row.get::<_, bool>(3)?,
+                    ))
+                },
+            )
+            .expect("Expected no errors.");
+        assert_eq!("3 dispute 0.5 false", dispute_steps);
+        journal.finish().expect("Expected no errors.");
+    }
+}
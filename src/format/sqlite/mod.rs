@@ -0,0 +1,5 @@
+// Writing what a run accepted into a SQLite file as it goes, for querying
+// afterwards. This is behind the `sqlite` feature since it builds SQLite from
+// source.
+
+pub mod journal;
@@ -0,0 +1,4 @@
+// A human-readable table, meant for eyeballing small reports in a terminal
+// rather than for feeding into other programs.
+
+pub mod output;
@@ -0,0 +1,214 @@
+use std::{collections::HashMap, error::Error, io::Write};
+
+use crate::{
+    format::{normalize_amount, ordered_rows, ReportConfig},
+    model::{Client, ClientID, Transaction, TransactionID},
+};
+
+// Takes the resultant clients after processing events, and writes them to the
+// given writer as a column-aligned table with the configured columns. Numeric
+// columns are right-aligned so that the decimal points of the amounts line up.
+pub fn write_report(
+    clients_by_id: &HashMap<ClientID, Client>,
+    mut writer: impl Write,
+    config: &ReportConfig,
+) -> Result<(), Box<dyn Error>> {
+    // unlike the other formats we can't stream the rows, because we need to
+    // know how wide each column is before writing the first one
+    let rows = ordered_rows(clients_by_id, config)
+        .map(|row| {
+            config
+                .columns
+                .iter()
+                .map(|column| column.field.value(&row, config.scale).to_string())
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>();
+
+    let headers = config
+        .columns
+        .iter()
+        .map(|column| column.header.clone())
+        .collect::<Vec<_>>();
+    let right_aligned = config
+        .columns
+        .iter()
+        .map(|column| column.field.is_numeric())
+        .collect::<Vec<_>>();
+
+    write_table(&mut writer, headers, &rows, &right_aligned)
+}
+
+// Writes the given transactions (say, a client's open disputes) as a table of
+// how much of each is disputed, in the order given.
+pub fn write_disputes<'a>(
+    transactions: impl IntoIterator<Item = (TransactionID, &'a Transaction)>,
+    mut writer: impl Write,
+    config: &ReportConfig,
+) -> Result<(), Box<dyn Error>> {
+    let rows = transactions
+        .into_iter()
+        .map(|(transaction_id, transaction)| {
+            vec![
+                transaction_id.to_string(),
+                transaction.kind().name().to_string(),
+                transaction.currency().to_string(),
+                normalize_amount(transaction.amount(), config.scale).to_string(),
+                normalize_amount(transaction.disputed_amount(), config.scale).to_string(),
+            ]
+        })
+        .collect::<Vec<_>>();
+    let headers = ["tx", "type", "currency", "amount", "disputed"]
+        .map(String::from)
+        .to_vec();
+
+    write_table(
+        &mut writer,
+        headers,
+        &rows,
+        &[true, false, false, true, true],
+    )
+}
+
+fn write_table(
+    writer: &mut impl Write,
+    headers: Vec<String>,
+    rows: &[Vec<String>],
+    right_aligned: &[bool],
+) -> Result<(), Box<dyn Error>> {
+    let mut widths = headers.iter().map(String::len).collect::<Vec<_>>();
+    for row in rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+
+    let separator = widths.iter().map(|width| "-".repeat(*width)).collect();
+    for row in [&headers, &separator].into_iter().chain(rows) {
+        write_row(writer, row, &widths, right_aligned)?;
+    }
+
+    writer.flush()?;
+
+    Ok(())
+}
+
+fn write_row(
+    writer: &mut impl Write,
+    row: &[String],
+    widths: &[usize],
+    right_aligned: &[bool],
+) -> Result<(), Box<dyn Error>> {
+    let cells = row
+        .iter()
+        .zip(widths)
+        .zip(right_aligned)
+        .map(|((cell, width), right_aligned)| {
+            if *right_aligned {
+                format!("{:>width$}", cell)
+            } else {
+                format!("{:<width$}", cell)
+            }
+        })
+        .collect::<Vec<_>>();
+
+    // left-aligned cells are padded even in the last column, which we don't
+    // want to leave behind as trailing whitespace
+    writeln!(writer, "{}", cells.join("  ").trim_end())?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        format::columns::parse_columns,
+        model::{Currency, TransactionKind},
+    };
+    use pretty_assertions::assert_eq;
+    use rust_decimal_macros::dec;
+
+    #[test]
+    fn test_write_report_with_columns() {
+        let mut writer = Vec::new();
+        let clients_by_id = HashMap::from([(1, Client::create(dec!(20), dec!(100.5), true))]);
+        let config = ReportConfig {
+            columns: parse_columns("locked:frozen,client:id,total")
+                .expect("Expected valid columns."),
+            ..ReportConfig::default()
+        };
+
+        write_report(&clients_by_id, &mut writer, &config).expect("Expected no errors.");
+
+        let output = String::from_utf8(writer).expect("Not UTF-8");
+        assert_eq!(
+            concat!(
+                "frozen  id     total\n",
+                "------  --  --------\n",
+                "true     1  100.5000\n",
+            ),
+            output,
+        );
+    }
+
+    #[test]
+    fn test_write_report() {
+        let mut writer = Vec::new();
+        let clients_by_id = HashMap::from([
+            (1, Client::create(dec!(20), dec!(100.5), true)),
+            (12, Client::create(dec!(6), dec!(7), false)),
+        ]);
+
+        write_report(&clients_by_id, &mut writer, &ReportConfig::default())
+            .expect("Expected no errors.");
+
+        let output = String::from_utf8(writer).expect("Not UTF-8");
+        assert_eq!(
+            concat!(
+                "client  available     held     total  locked\n",
+                "------  ---------  -------  --------  ------\n",
+                "     1    80.5000  20.0000  100.5000  true\n",
+                "    12     1.0000   6.0000    7.0000  false\n",
+            ),
+            output,
+        );
+    }
+
+    #[test]
+    fn test_write_disputes() {
+        let mut writer = Vec::new();
+        let mut deposit = Transaction::new(
+            1,
+            "EUR".parse().expect("Expected a valid currency."),
+            dec!(100),
+            TransactionKind::Deposit,
+        );
+        deposit.dispute(dec!(40), 2);
+        let mut withdrawal = Transaction::new(
+            1,
+            Currency::default(),
+            dec!(2.5),
+            TransactionKind::Withdrawal,
+        );
+        withdrawal.dispute(dec!(2.5), 3);
+
+        write_disputes(
+            [(7, &deposit), (12, &withdrawal)],
+            &mut writer,
+            &ReportConfig::default(),
+        )
+        .expect("Expected no errors.");
+
+        let output = String::from_utf8(writer).expect("Not UTF-8");
+        assert_eq!(
+            concat!(
+                "tx  type        currency    amount  disputed\n",
+                "--  ----------  --------  --------  --------\n",
+                " 7  deposit     EUR       100.0000   40.0000\n",
+                "12  withdrawal              2.5000    2.5000\n",
+            ),
+            output,
+        );
+    }
+}
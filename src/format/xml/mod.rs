@@ -0,0 +1,3 @@
+// Everything XML-related lives here.
+
+pub mod output;
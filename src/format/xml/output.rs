@@ -0,0 +1,130 @@
+use quick_xml::{
+    events::{BytesDecl, BytesEnd, BytesStart, BytesText, Event},
+    Writer,
+};
+use std::{collections::HashMap, error::Error, io::Write};
+
+use crate::{
+    format::{ordered_rows, ReportConfig},
+    model::{Client, ClientID},
+};
+
+// Takes the resultant clients after processing events, and writes them to the
+// given writer as an XML document with one `client` element per client, each
+// holding one element per configured column, named after its header.
+pub fn write_report(
+    clients_by_id: &HashMap<ClientID, Client>,
+    writer: impl Write,
+    config: &ReportConfig,
+) -> Result<(), Box<dyn Error>> {
+    // headers can be renamed to anything, but not everything makes for a valid
+    // element name, and we'd rather find out before writing half a document
+    if let Some(column) = config
+        .columns
+        .iter()
+        .find(|column| !is_valid_element_name(&column.header))
+    {
+        return Err(format!(
+            "Column header {} is not a valid XML element name.",
+            column.header
+        )
+        .into());
+    }
+
+    let mut xml_writer = Writer::new_with_indent(writer, b' ', 2);
+
+    xml_writer.write_event(Event::Decl(BytesDecl::new("1.0", Some("UTF-8"), None)))?;
+    xml_writer.write_event(Event::Start(BytesStart::new("clients")))?;
+
+    for row in ordered_rows(clients_by_id, config) {
+        xml_writer.write_event(Event::Start(BytesStart::new("client")))?;
+        for column in &config.columns {
+            let value = column.field.value(&row, config.scale).to_string();
+            xml_writer.write_event(Event::Start(BytesStart::new(column.header.as_str())))?;
+            xml_writer.write_event(Event::Text(BytesText::new(&value)))?;
+            xml_writer.write_event(Event::End(BytesEnd::new(column.header.as_str())))?;
+        }
+        xml_writer.write_event(Event::End(BytesEnd::new("client")))?;
+    }
+
+    xml_writer.write_event(Event::End(BytesEnd::new("clients")))?;
+
+    let mut writer = xml_writer.into_inner();
+    writer.write_all(b"\n")?;
+    writer.flush()?;
+
+    Ok(())
+}
+
+// A conservative take on XML's rules for names: ASCII letters, digits,
+// hyphens, underscores and dots, not starting with a digit, hyphen or dot.
+fn is_valid_element_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    let starts_well = chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_');
+
+    starts_well && chars.all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::format::columns::parse_columns;
+    use pretty_assertions::assert_eq;
+    use rust_decimal_macros::dec;
+
+    fn clients_by_id() -> HashMap<ClientID, Client> {
+        HashMap::from([
+            (2, Client::create(dec!(6), dec!(7), false)),
+            (1, Client::create(dec!(20), dec!(100), true)),
+        ])
+    }
+
+    #[test]
+    fn test_write_report() {
+        let mut writer = Vec::new();
+
+        write_report(&clients_by_id(), &mut writer, &ReportConfig::default())
+            .expect("Expected no errors.");
+
+        let output = String::from_utf8(writer).expect("Not UTF-8");
+        assert_eq!(
+            concat!(
+                "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n",
+                "<clients>\n",
+                "  <client>\n",
+                "    <client>1</client>\n",
+                "    <available>80.0000</available>\n",
+                "    <held>20.0000</held>\n",
+                "    <total>100.0000</total>\n",
+                "    <locked>true</locked>\n",
+                "  </client>\n",
+                "  <client>\n",
+                "    <client>2</client>\n",
+                "    <available>1.0000</available>\n",
+                "    <held>6.0000</held>\n",
+                "    <total>7.0000</total>\n",
+                "    <locked>false</locked>\n",
+                "  </client>\n",
+                "</clients>\n",
+            ),
+            output,
+        );
+    }
+
+    #[test]
+    fn test_write_report_invalid_header() {
+        let config = ReportConfig {
+            columns: parse_columns("client,available:Available Funds").expect("Valid columns"),
+            ..ReportConfig::default()
+        };
+
+        let result = write_report(&clients_by_id(), &mut Vec::new(), &config);
+
+        assert_eq!(
+            "Column header Available Funds is not a valid XML element name.",
+            result.expect_err("Expected an error").to_string()
+        );
+    }
+}
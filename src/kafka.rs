@@ -0,0 +1,135 @@
+// Publishes the balance changes `challenge serve` makes to a Kafka topic (see
+// `serve::DeltaSink`). Messages are sent in the background, so the only time
+// anything waits on Kafka is when a request or stream event has been processed
+// and the sink's flushed, before it's answered.
+
+use crate::serve::{DeltaSink, DEFAULT_TENANT};
+use challenge::model::ClientID;
+use clap::Args;
+use log::warn;
+use rdkafka::{
+    error::{KafkaError, RDKafkaErrorCode},
+    message::{Header, OwnedHeaders},
+    producer::{BaseProducer, BaseRecord, DeliveryResult, Producer, ProducerContext},
+    ClientConfig, ClientContext,
+};
+use std::{
+    error::Error,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+// How long to wait for the messages a request published to be delivered
+// before giving up on them.
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Args)]
+pub struct KafkaOptions {
+    #[arg(
+        long,
+        value_name = "ADDRESSES",
+        help = "Publish balance changes to Kafka, via these brokers (comma separated)."
+    )]
+    kafka_brokers: Option<String>,
+    #[arg(
+        long,
+        value_name = "TOPIC",
+        default_value = "balance-changes",
+        help = "The topic to publish balance changes to."
+    )]
+    kafka_topic: String,
+}
+
+impl KafkaOptions {
+    // The sink, if there are brokers to publish to.
+    pub fn sink(&self) -> Result<Option<KafkaSink>, Box<dyn Error>> {
+        let Some(brokers) = &self.kafka_brokers else {
+            return Ok(None);
+        };
+
+        let producer = ClientConfig::new()
+            .set("bootstrap.servers", brokers)
+            .create_with_context(DeliveryCounter::default())
+            .map_err(|e| format!("Couldn't connect to Kafka at {}: {}", brokers, e))?;
+        Ok(Some(KafkaSink {
+            producer,
+            topic: self.kafka_topic.clone(),
+        }))
+    }
+}
+
+// Counts the messages that couldn't be delivered, since that's only found out
+// about after they've been sent.
+#[derive(Default)]
+struct DeliveryCounter {
+    failed: AtomicU64,
+}
+
+impl ClientContext for DeliveryCounter {}
+
+impl ProducerContext for DeliveryCounter {
+    type DeliveryOpaque = ();
+
+    fn delivery(&self, delivery_result: &DeliveryResult<'_>, _: Self::DeliveryOpaque) {
+        if let Err((e, _)) = delivery_result {
+            warn!("Couldn't deliver a balance change to Kafka: {}", e);
+            self.failed.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+pub struct KafkaSink {
+    producer: BaseProducer<DeliveryCounter>,
+    topic: String,
+}
+
+impl DeltaSink for KafkaSink {
+    // The key's the client's ID, with the tenant in front for anyone but the
+    // default tenant, so that consumers from before there were tenants see
+    // the same keys as ever. Every message says whose it is in a `tenant`
+    // header too.
+    fn publish(
+        &mut self,
+        tenant: &str,
+        client_id: ClientID,
+        payload: &[u8],
+    ) -> Result<(), Box<dyn Error>> {
+        let key = if tenant == DEFAULT_TENANT {
+            client_id.to_string()
+        } else {
+            format!("{}/{}", tenant, client_id)
+        };
+        let headers = OwnedHeaders::new().insert(Header {
+            key: "tenant",
+            value: Some(tenant),
+        });
+        let mut record = BaseRecord::to(&self.topic)
+            .key(&key)
+            .payload(payload)
+            .headers(headers);
+        loop {
+            match self.producer.send(record) {
+                Ok(()) => return Ok(()),
+                // there are too many messages waiting to go out, so some have
+                // to before this one can join them
+                Err((KafkaError::MessageProduction(RDKafkaErrorCode::QueueFull), returned)) => {
+                    self.producer.poll(Duration::from_millis(100));
+                    record = returned;
+                }
+                Err((e, _)) => return Err(e.into()),
+            }
+        }
+    }
+
+    fn flush(&mut self) -> Result<(), Box<dyn Error>> {
+        self.producer.flush(FLUSH_TIMEOUT)?;
+        let failed = self.producer.context().failed.swap(0, Ordering::Relaxed);
+        if failed > 0 {
+            return Err(
+                format!("{} balance changes couldn't be delivered to Kafka.", failed).into(),
+            );
+        }
+
+        Ok(())
+    }
+}
@@ -2,21 +2,36 @@ use std::{
     error::Error,
     io::{Read, Write},
 };
+pub mod engine;
+// The C ABI, for embedding the engine in something that isn't Rust.
+#[cfg(feature = "ffi")]
+pub mod ffi;
 pub mod format;
 pub mod model;
 pub mod system;
 
+pub use engine::{Engine, EngineBuilder, Tenants};
+// For callers that get their events one at a time (e.g. over RPC) rather than
+// as an iterator, and so drive the processor themselves.
+pub use system::{EngineConfig, Processor, ProcessorObserver};
+
 #[inline]
 pub fn process_csv_events(
     input: &mut impl Read,
     output: &mut impl Write,
     err_output: &mut impl Write,
 ) -> Result<(), Box<dyn Error>> {
-    let events_iter = format::csv::input::parse_events(input);
-
-    let final_state = system::process_events(events_iter, err_output)?;
+    let mut engine = Engine::builder()
+        .errors(err_output, format::ErrorFormat::Text)
+        .build();
+    engine.process_csv(input)?;
+    let final_state = engine.finish()?;
 
-    format::csv::output::write_report(final_state, output)?;
+    format::csv::output::write_report(
+        &final_state.clients_by_id,
+        output,
+        &format::ReportConfig::default(),
+    )?;
 
     Ok(())
 }
@@ -40,8 +55,8 @@ mod test {
         );
         let expected_output = concat!(
             "client,available,held,total,locked\n",
-            "1,1.61111,0,1.61111,false\n",
-            "2,2.0,0,2.0,false\n"
+            "1,1.6111,0.0000,1.6111,false\n",
+            "2,2.0000,0.0000,2.0000,false\n"
         );
 
         let mut output = Vec::new();
@@ -52,4 +67,42 @@ mod test {
 
         assert_eq!(expected_output, output_str);
     }
+
+    #[test]
+    fn test_process_events_one_at_a_time() {
+        use model::{Currency, Event, TransactionKind};
+        use rust_decimal_macros::dec;
+
+        let mut processor = Processor::new(&EngineConfig::default());
+        let transaction = |kind, transaction_id, amount| Event::Transaction {
+            kind,
+            client_id: 1,
+            transaction_id,
+            currency: Currency::default(),
+            amount,
+            counterparty: None,
+        };
+
+        assert!(processor
+            .process_event(
+                transaction(TransactionKind::Deposit, 1, dec!(5)),
+                None,
+                None,
+                &mut ()
+            )
+            .is_ok());
+        assert!(processor
+            .process_event(
+                transaction(TransactionKind::Withdrawal, 2, dec!(8)),
+                None,
+                None,
+                &mut ()
+            )
+            .is_err());
+
+        let total = processor
+            .client(1)
+            .map(|client| client.balance(Currency::default()).total());
+        assert_eq!(Some(dec!(5)), total);
+    }
 }
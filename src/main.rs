@@ -1,25 +1,2525 @@
-use std::{env, error::Error, fs::File, io};
+use challenge::{
+    format::{
+        self,
+        columns::parse_columns,
+        compression::{CompressedWriter, Compression},
+        error::ParseError,
+        partition::{Partition, Partitioning},
+        ErrorFormat, InputFormat, OutputFormat, ReportConfig, ReportOrder,
+    },
+    model::{Amount, Client, ClientID, SourcedEvent, Timestamp},
+    system::{
+        self, ChargebackLimitAction, ClosedAccountPolicy, CompactStore, DuplicateTransactionPolicy,
+        EngineConfig, EventCounts, Fee, FeeSchedule, FinalState, Journal, JournalReader,
+        LockedAccountPolicy, LockedDepositPolicy, ProcessorObserver, RejectionThreshold,
+        ReplayPoint, ResourceLimits, SnapshotInterval, SpillingStore, UndisputedChargebackPolicy,
+        WithdrawalDisputePolicy,
+    },
+    Engine, EngineBuilder,
+};
+use clap::{error::ErrorKind, ArgAction, CommandFactory, Parser, Subcommand};
+use cpu_time::{ProcessTime, ThreadTime};
+use log::{debug, info, warn, LevelFilter, Log, Metadata, Record};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{HashMap, HashSet},
+    env,
+    error::Error,
+    fs::{self, File, OpenOptions},
+    io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    iter, mem,
+    net::TcpListener,
+    num::NonZeroUsize,
+    panic,
+    path::{Path, PathBuf},
+    process::ExitCode,
+    str::FromStr,
+    sync::mpsc::{self, Receiver, SyncSender},
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+    vec,
+};
+use tempfile::NamedTempFile;
+
+#[cfg(feature = "flight")]
+mod flight;
+#[cfg(feature = "kafka")]
+mod kafka;
+#[cfg(feature = "nats")]
+mod nats;
+#[cfg(feature = "s3")]
+mod s3;
+mod serve;
+#[cfg(feature = "webhooks")]
+mod webhook;
 
 // This program takes a command-line argument that points to
 // an input CSV file of events, reads the events from it, and writes the
-// resulting state to an output CSV file.
+// resulting state to stdout or a given output file (as CSV unless another
+// output format is chosen).
+//
+// `challenge diff <old report> <new report>` instead compares two reports and
+// writes how each client changed to stdout, and there are a few more
+// subcommands like it (see `Command`), including `challenge serve`, which
+// takes events over HTTP for as long as it runs.
+//
+// It exits with 1 if the run failed outright, and with 2 if the report was
+// produced but more events were rejected than `--max-rejections` allows.
+
+// Distinct from the 1 that an error exits with, so that callers can tell the
+// two apart.
+const TOO_MANY_REJECTIONS_EXIT_CODE: u8 = 2;
+
+// Errors returned from `main` are printed with `Debug`, which for our error
+// enums would be the name of the variant rather than the message, so this
+// turns them into their message first.
+fn main() -> Result<ExitCode, Box<dyn Error>> {
+    match run(env::args().collect()) {
+        Err(e) if is_broken_pipe(e.as_ref()) => Ok(ExitCode::SUCCESS),
+        result => result.map_err(|e| e.to_string().into()),
+    }
+}
+
+// Whatever was reading our output stopped before the end (say, `challenge
+// --help | head`), which is how pipelines go rather than a failure, so it
+// exits quietly like other command line tools do. The CSV and JSON writers
+// wrap the I/O error without giving it up as the source, so they're asked
+// for it.
+fn is_broken_pipe(e: &(dyn Error + 'static)) -> bool {
+    iter::successors(Some(e), |&e| e.source()).any(|e| {
+        let kind = if let Some(e) = e.downcast_ref::<io::Error>() {
+            Some(e.kind())
+        } else if let Some(e) = e.downcast_ref::<csv::Error>() {
+            match e.kind() {
+                csv::ErrorKind::Io(e) => Some(e.kind()),
+                _ => None,
+            }
+        } else {
+            e.downcast_ref::<serde_json::Error>()
+                .and_then(serde_json::Error::io_error_kind)
+        };
+        kind == Some(io::ErrorKind::BrokenPipe)
+    })
+}
+
+struct Args {
+    // Left out when looking back at the state as it was (`replay_until`), in
+    // which case there's nothing new to process.
+    input_path: Option<String>,
+    input_format: InputFormat,
+    output_path: Option<String>,
+    report_config: ReportConfig,
+    compression: Option<Compression>,
+    dispute_report_path: Option<String>,
+    errors: ErrorDestination,
+    error_format: ErrorFormat,
+    max_rejections: Option<RejectionThreshold>,
+    reconciliation_path: Option<String>,
+    counterparty_report_path: Option<String>,
+    metrics_path: Option<String>,
+    manifest_path: Option<String>,
+    #[cfg(feature = "postgres")]
+    postgres: Option<PostgresOutput>,
+    partitions: Option<usize>,
+    partitioning: Partitioning,
+    snapshot_interval: Option<SnapshotInterval>,
+    snapshot_dir: PathBuf,
+    // In bytes.
+    memory_budget: Option<usize>,
+    compact: bool,
+    // How many threads to split the clients between, if more than one.
+    threads: Option<usize>,
+    resume_from_path: Option<String>,
+    save_state_path: Option<String>,
+    // Where to append the accepted events to, and a journal to replay before
+    // processing the input.
+    journal_path: Option<String>,
+    replay_journal_path: Option<String>,
+    replay_until: Option<ReplayPoint>,
+    // Where to write the accepted transactions and dispute steps as a SQLite
+    // database.
+    #[cfg(feature = "sqlite")]
+    sqlite_journal_path: Option<String>,
+    #[cfg(feature = "webhooks")]
+    webhook: webhook::WebhookOptions,
+    #[cfg(feature = "s3")]
+    s3: s3::S3Options,
+    engine_config: EngineConfig,
+    // If set, the events that don't name any of these clients are dropped as
+    // they're parsed, as if they'd never been in the input.
+    event_clients: Option<HashSet<ClientID>>,
+    // Nothing's written but the rejections, which are what a dry run is for.
+    dry_run: bool,
+    profile: bool,
+}
+
+// Where to upsert the clients' balances, if anywhere besides the report.
+#[cfg(feature = "postgres")]
+struct PostgresOutput {
+    dsn: String,
+    table: String,
+    batch_size: usize,
+}
+
+// Where rejected events get logged.
+#[derive(PartialEq, Eq)]
+enum ErrorDestination {
+    None,
+    Stderr,
+    File(String),
+}
+
+impl ErrorDestination {
+    fn parse(value: &str) -> Self {
+        match value {
+            "none" => ErrorDestination::None,
+            "stderr" => ErrorDestination::Stderr,
+            path => ErrorDestination::File(String::from(path)),
+        }
+    }
+}
+
+// Diagnostics (as opposed to the report and the rejections, which are output
+// in their own right) go through `log`, which writes them to stderr as they
+// are. How many of them get through is up to `-v` and `--quiet`.
+struct StderrLogger;
+
+impl Log for StderrLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            eprintln!("{}", record.args());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: StderrLogger = StderrLogger;
+
+// Quiet only lets through what's gone wrong, and each `-v` lets through more:
+// first progress and a summary, then what each stage is up to.
+fn log_level(verbose: u8, quiet: bool) -> LevelFilter {
+    match (quiet, verbose) {
+        (true, _) => LevelFilter::Error,
+        (false, 0) => LevelFilter::Warn,
+        (false, 1) => LevelFilter::Info,
+        (false, 2) => LevelFilter::Debug,
+        (false, _) => LevelFilter::Trace,
+    }
+}
+
+fn run(args: Vec<String>) -> Result<ExitCode, Box<dyn Error>> {
+    let cli = match with_config_args(args).and_then(Cli::try_parse_from) {
+        Ok(cli) => cli,
+        // `--help` and `--version` end up here too. Anything else is a
+        // mistake in the arguments, which exits with 1 like any other failure
+        // rather than clap's 2, since that means too many rejections.
+        Err(e) => {
+            e.print()?;
+            return Ok(if e.use_stderr() {
+                ExitCode::FAILURE
+            } else {
+                ExitCode::SUCCESS
+            });
+        }
+    };
+    log::set_logger(&LOGGER)?;
+    log::set_max_level(log_level(cli.verbose, cli.quiet));
+
+    match cli.command {
+        Some(Command::Diff { old_path, new_path }) => return run_diff(&old_path, &new_path),
+        Some(Command::Stats {
+            input_path,
+            input_format,
+        }) => return run_stats(&input_path, input_format),
+        Some(Command::Query {
+            client,
+            state,
+            journal,
+        }) => return run_query(client, state, journal),
+        Some(Command::Serve(options)) => {
+            let ServeOptions {
+                listen,
+                tcp,
+                resume_from,
+                journal,
+                save_state,
+                save_state_every,
+                tenants,
+                #[cfg(feature = "flight")]
+                flight,
+                #[cfg(feature = "kafka")]
+                kafka,
+                #[cfg(feature = "nats")]
+                nats,
+                #[cfg(feature = "webhooks")]
+                webhook,
+                #[cfg(feature = "redis")]
+                redis,
+                #[cfg(feature = "redis")]
+                redis_prefix,
+            } = *options;
+            #[cfg(feature = "redis")]
+            let builder = match &redis {
+                Some(url) => Engine::builder().state_store(
+                    system::RedisStore::open(url, &redis_prefix).map_err(|e| {
+                        format!("Couldn't load the state from Redis at {}: {}", url, e)
+                    })?,
+                ),
+                None => Engine::builder(),
+            };
+            #[cfg(not(feature = "redis"))]
+            let builder = Engine::builder();
+            #[cfg(feature = "webhooks")]
+            // serve always reports at the default scale
+            let builder = match webhook.notifier(&ReportConfig::default()) {
+                Some(notifier) => builder.observer(notifier),
+                None => builder,
+            };
+            #[cfg(feature = "kafka")]
+            let delta_sink = kafka
+                .sink()?
+                .map(|sink| Box::new(sink) as Box<dyn serve::DeltaSink>);
+            #[cfg(not(feature = "kafka"))]
+            let delta_sink = None;
+            let listeners = serve::Listeners {
+                tcp: tcp
+                    .as_deref()
+                    .map(|address| listen_tcp(address, "CSV"))
+                    .transpose()?,
+                #[cfg(feature = "flight")]
+                flight: flight
+                    .as_deref()
+                    .map(|address| listen_tcp(address, "Arrow Flight"))
+                    .transpose()?,
+                #[cfg(feature = "nats")]
+                nats: nats.source(),
+            };
+            let snapshots = save_state
+                .as_deref()
+                .map(state_snapshots)
+                .transpose()?
+                .map(|snapshots| (snapshots, Duration::from_secs(save_state_every)));
+            return run_serve(
+                builder,
+                &listen,
+                listeners,
+                &tenants,
+                resume_from,
+                journal,
+                snapshots,
+                delta_sink,
+            );
+        }
+        Some(Command::Explain { code }) => return run_explain(code.as_deref()),
+        None => {}
+    }
+    if let Some(config_path) = &cli.run.config {
+        debug!("Read options from {}.", config_path);
+    }
+
+    let started_at = SystemTime::now();
+    let started = Instant::now();
+    let args = resolve_args(cli.run, cli.quiet)?;
+    // the input is hashed as it's read, but only if there's a manifest to put
+    // the hash in
+    let input: Box<dyn Read + Send> = match &args.input_path {
+        Some(input_path) => Box::new(File::open(input_path)?),
+        None => Box::new(io::empty()),
+    };
+    let mut file = HashingReader::new(input, args.manifest_path.is_some());
+
+    let mut outputs = ReportOutput::create_all(&args)?;
+    let mut side_reports = SideReports::create(&args)?;
+    #[cfg(feature = "s3")]
+    let uploader = args.s3.uploader()?;
+
+    // Errors go to stderr unless told otherwise, since discarding them hid real
+    // problems with the input. Logging them costs time, so `--errors none` is
+    // there for when that matters more. The error file isn't written
+    // atomically: if the run fails, the errors logged up until then are exactly
+    // what we want.
+    let error_writer: Option<Box<dyn Write + Send>> = match &args.errors {
+        ErrorDestination::None => None,
+        ErrorDestination::Stderr => Some(Box::new(BufWriter::new(io::stderr()))),
+        ErrorDestination::File(path) => Some(Box::new(BufWriter::new(File::create(path)?))),
+    };
+
+    let mut profile = Profile::default();
+    let (event_counts, state_sha256) = run_aux(
+        &mut file,
+        &mut outputs,
+        error_writer,
+        &mut side_reports,
+        &args,
+        &mut profile,
+    )?;
+
+    if let Some(manifest_output) = side_reports.manifest.as_mut() {
+        format::json::manifest::write_manifest(
+            &format::json::manifest::Manifest {
+                input_path: args.input_path.as_deref().unwrap_or_default(),
+                input_sha256: &file.hex_digest().unwrap_or_default(),
+                state_sha256: &state_sha256.unwrap_or_default(),
+                event_counts: &event_counts,
+                report_config: &args.report_config,
+                engine_config: &args.engine_config,
+                started_at,
+                duration: started.elapsed(),
+            },
+            manifest_output,
+        )?;
+    }
+
+    // we only get here if everything succeeded, so it's safe to put the files
+    // in place
+    for output in outputs {
+        output.writer.finish()?.commit()?;
+    }
+    side_reports.commit()?;
+    #[cfg(feature = "s3")]
+    if let Some(uploader) = &uploader {
+        uploader.upload(&uploaded_paths(&args))?;
+    }
+    info!(
+        "Processed {} events, rejecting {}, in {:.2}s.",
+        event_counts.processed,
+        event_counts.rejected,
+        started.elapsed().as_secs_f64()
+    );
+    // asked for, so it's not up to the log level
+    if args.profile {
+        profile.write(started.elapsed(), io::stderr().lock())?;
+    }
+
+    if let Some(max_rejections) = args.max_rejections {
+        if max_rejections.is_exceeded(&event_counts) {
+            warn!(
+                "Rejected {} of {} events, which is more than the {} allowed.",
+                event_counts.rejected, event_counts.processed, max_rejections
+            );
+            return Ok(ExitCode::from(TOO_MANY_REJECTIONS_EXIT_CODE));
+        }
+    }
+
+    Ok(ExitCode::SUCCESS)
+}
+
+fn run_aux(
+    input: &mut (impl Read + Send),
+    outputs: &mut [ReportOutput],
+    error_writer: Option<Box<dyn Write + Send>>,
+    side_reports: &mut SideReports,
+    args: &Args,
+    profile: &mut Profile,
+) -> Result<(EventCounts, Option<String>), Box<dyn Error>> {
+    let started = Instant::now();
+    let report_config = &args.report_config;
+
+    let final_state = run_pipeline(
+        input,
+        error_writer,
+        side_reports.state.as_mut(),
+        args,
+        profile,
+    )?;
+    let reporting_started = Instant::now();
+
+    if let Some(reconciliation_output) = side_reports.reconciliation.as_mut() {
+        format::csv::output::write_reconciliation(
+            &system::reconcile(&final_state, &args.engine_config),
+            reconciliation_output,
+            report_config,
+        )?;
+    }
+
+    if let Some(counterparty_output) = side_reports.counterparty.as_mut() {
+        format::csv::output::write_counterparty_report(
+            &system::counterparty_exposures(&final_state),
+            counterparty_output,
+            report_config,
+        )?;
+    }
+
+    for output in outputs {
+        format::write_report(
+            &final_state.clients_by_id,
+            &final_state.event_counts,
+            &mut output.writer,
+            &output.config,
+        )?;
+    }
+
+    if let Some(dispute_output) = side_reports.dispute.as_mut() {
+        format::csv::output::write_dispute_report(
+            final_state.all_transactions(),
+            dispute_output,
+            report_config,
+        )?;
+    }
+
+    #[cfg(feature = "postgres")]
+    if let (Some(postgres), Some(postgres_output)) =
+        (side_reports.postgres.as_mut(), args.postgres.as_ref())
+    {
+        let upserted = format::postgres::output::write_report(
+            &final_state.clients_by_id,
+            postgres,
+            &postgres_output.table,
+            postgres_output.batch_size,
+            report_config,
+        )
+        .map_err(|e| format!("Couldn't write the report to Postgres: {}", e))?;
+        debug!("Upserted {} rows into {}.", upserted, postgres_output.table);
+    }
+
+    // written last so that the duration covers everything else
+    if let Some(metrics_output) = side_reports.metrics.as_mut() {
+        format::prometheus::output::write_metrics(
+            &final_state.clients_by_id,
+            &final_state.event_counts,
+            started.elapsed(),
+            metrics_output,
+        )?;
+    }
+
+    // like the input's hash, this is only worked out if there's a manifest
+    // to put it in
+    let state_sha256 = side_reports
+        .manifest
+        .is_some()
+        .then(|| final_state.state_digest());
+    profile.reporting = reporting_started.elapsed();
+
+    Ok((final_state.event_counts, state_sha256))
+}
+
+// Where a run's time went, for `--profile`. The stages of the pipeline run at
+// the same time as each other, so how long each one ran for would be about the
+// same for all of them. It's how much CPU time each one took instead, which
+// says which one is holding the others up. The reports are written once
+// they're all done, so that's plain wall time.
+#[derive(Default)]
+struct Profile {
+    reading: Duration,
+    parsing: Duration,
+    processing: Duration,
+    writing_rejections: Duration,
+    reporting: Duration,
+}
+
+impl Profile {
+    fn write(&self, total: Duration, mut writer: impl Write) -> io::Result<()> {
+        let rows = [
+            ("Reading", self.reading, "CPU"),
+            ("Parsing", self.parsing, "CPU"),
+            ("Processing", self.processing, "CPU"),
+            ("Writing rejections", self.writing_rejections, "CPU"),
+            ("Writing reports", self.reporting, "wall"),
+            ("Total", total, "wall"),
+        ];
+        for (stage, duration, kind) in rows {
+            writeln!(
+                writer,
+                "{:<20}{:>10.3}s {}",
+                stage,
+                duration.as_secs_f64(),
+                kind
+            )?;
+        }
+        match peak_memory() {
+            Some(bytes) => writeln!(
+                writer,
+                "{:<20}{:>10.1} MiB",
+                "Peak memory",
+                bytes as f64 / (1024.0 * 1024.0)
+            ),
+            None => writeln!(writer, "{:<20}{:>10}", "Peak memory", "unknown"),
+        }
+    }
+}
+
+// The most the process has had resident at once, which only Linux tells us
+// without asking the allocator to keep count.
+fn peak_memory() -> Option<u64> {
+    memory_status("VmHWM:")
+}
+
+// How much the process has resident right now, for serve's metrics.
+fn resident_memory() -> Option<u64> {
+    memory_status("VmRSS:")
+}
+
+fn memory_status(field: &str) -> Option<u64> {
+    let status = fs::read_to_string("/proc/self/status").ok()?;
+    let kibibytes = status
+        .lines()
+        .find_map(|line| line.strip_prefix(field))?
+        .trim()
+        .strip_suffix("kB")?
+        .trim()
+        .parse::<u64>()
+        .ok()?;
+    Some(kibibytes * 1024)
+}
+
+// Reading the input, parsing it, processing the events and writing the
+// rejections each get a thread of their own, connected by bounded channels, so
+// that I/O and parsing overlap with processing rather than taking turns with
+// it. A stage that gets too far ahead waits for the next one to catch up, so
+// memory use stays bounded, and a stage that stops early (e.g. on an error)
+// hangs up on its neighbours, which stops them too.
+fn run_pipeline(
+    input: &mut (impl Read + Send),
+    error_writer: Option<Box<dyn Write + Send>>,
+    state_output: Option<&mut AtomicFile>,
+    args: &Args,
+    profile: &mut Profile,
+) -> Result<FinalState, Box<dyn Error>> {
+    // raw records are only worth keeping if they're going to be logged
+    let keep_records = error_writer.is_some() && args.error_format == ErrorFormat::Json;
+    let started = ProcessTime::now();
+    // each stage says how much CPU time it took once it's done
+    let thread_time = || ThreadTime::now().as_duration();
+
+    thread::scope(|scope| {
+        let (chunk_sender, chunk_receiver) = mpsc::sync_channel(STAGE_QUEUE_LEN);
+        let (batch_sender, batch_receiver) = mpsc::sync_channel(STAGE_QUEUE_LEN);
+        let reader_stage = scope.spawn(|| {
+            read_input(input, chunk_sender);
+            thread_time()
+        });
+        let parser_stage = scope.spawn(move || {
+            parse_input(
+                ChunkReader::new(chunk_receiver),
+                args.input_format,
+                keep_records,
+                args.event_clients.as_ref(),
+                batch_sender,
+            );
+            thread_time()
+        });
+
+        let (error_writer, writer_stage) = match error_writer {
+            Some(error_writer) => {
+                let (sender, receiver) = mpsc::sync_channel(STAGE_QUEUE_LEN);
+                let writer_stage =
+                    scope.spawn(move || (write_output(receiver, error_writer), thread_time()));
+                (Some(ChannelWriter::new(sender)), Some(writer_stage))
+            }
+            None => (None, None),
+        };
 
-fn main() -> Result<(), Box<dyn Error>> {
-    let mut file = get_file_from_cli_arg()?;
+        let events = ParsedEvents::new(batch_receiver);
+        let processed = match args.threads {
+            Some(threads) => process_sharded(events, error_writer, args, threads),
+            None => process(events, error_writer, state_output, args),
+        };
 
-    // `process_csv_events` takes a writer for logging errors but we're skipping
-    // that here because it wasn't in the spec and the faster, the better. We
-    // could easily swap out io::sink for io::stderr
-    challenge::process_csv_events(&mut file, &mut io::stdout(), &mut io::sink())
+        profile.reading = join_stage(reader_stage);
+        profile.parsing = join_stage(parser_stage);
+        // if writing the rejections failed, that's why processing failed too
+        if let Some(writer_stage) = writer_stage {
+            let (written, writing_time) = join_stage(writer_stage);
+            profile.writing_rejections = writing_time;
+            written?;
+        }
+        // whatever else the process spent, which is the main thread (or the
+        // shards, with `--threads`)
+        profile.processing = started
+            .elapsed()
+            .saturating_sub(profile.reading + profile.parsing + profile.writing_rejections);
+        processed
+    })
 }
 
-fn get_file_from_cli_arg() -> Result<File, Box<dyn Error>> {
-    let args: Vec<String> = env::args().collect();
-    if args.len() != 2 {
-        return Err(format!("Usage: {} <filename>", args[0]).into());
+// A stage that panicked takes the run down with it, as it would have if it
+// hadn't been on a thread of its own.
+fn join_stage<T>(stage: thread::ScopedJoinHandle<T>) -> T {
+    stage.join().unwrap_or_else(|e| panic::resume_unwind(e))
+}
+
+fn process(
+    events: ParsedEvents,
+    error_writer: Option<ChannelWriter>,
+    state_output: Option<&mut AtomicFile>,
+    args: &Args,
+) -> Result<FinalState, Box<dyn Error>> {
+    // lent to the engine, so that it can be finished once the engine's done
+    #[cfg(feature = "sqlite")]
+    let mut sqlite_journal = args
+        .sqlite_journal_path
+        .as_ref()
+        .map(|path| {
+            format::sqlite::journal::SqliteJournal::create(path)
+                .map_err(|e| format!("Couldn't create SQLite journal {}: {}", path, e))
+        })
+        .transpose()?;
+    let mut builder = Engine::builder()
+        .config(args.engine_config.clone())
+        .report_config(args.report_config.clone());
+    // whichever of them there are, if any
+    #[allow(unused_mut)]
+    let mut observers: Vec<Box<dyn ProcessorObserver + '_>> = Vec::new();
+    #[cfg(feature = "sqlite")]
+    if let Some(sqlite_journal) = sqlite_journal.as_mut() {
+        observers.push(Box::new(sqlite_journal));
+    }
+    #[cfg(feature = "webhooks")]
+    if let Some(notifier) = args.webhook.notifier(&args.report_config) {
+        observers.push(Box::new(notifier));
+    }
+    builder = builder.observer(observers);
+    if let Some(error_writer) = error_writer {
+        builder = builder.errors(error_writer, args.error_format);
+    }
+    if let Some(snapshot_interval) = args.snapshot_interval {
+        builder = builder.snapshots(snapshot_interval, |clients_by_id, event_counts| {
+            write_snapshot(clients_by_id, event_counts, args)
+        });
+    }
+    // past the budget, transactions are spilled to a temp file
+    if let Some(memory_budget) = args.memory_budget {
+        builder = builder.state_store(SpillingStore::new(memory_budget)?);
+    } else if args.compact {
+        builder = builder.state_store(CompactStore::default());
+    }
+    if let Some(path) = &args.journal_path {
+        builder = builder.journal(
+            open_journal(path).map_err(|e| format!("Couldn't open journal {}: {}", path, e))?,
+        );
+    }
+    // carrying on from where a previous run left off, so only the new events
+    // are in the input
+    let mut engine = match &args.resume_from_path {
+        Some(path) => {
+            debug!("Resuming from {}.", path);
+            builder
+                .build_resumed(io::BufReader::new(File::open(path)?))
+                .map_err(|e| format!("Couldn't resume from {}: {}", path, e))?
+        }
+        None => builder.build(),
+    };
+    if let Some(path) = &args.replay_journal_path {
+        debug!("Replaying journal {}.", path);
+        File::open(path)
+            .and_then(|file| match args.replay_until {
+                Some(until) => engine.replay_journal_until(BufReader::new(file), until),
+                None => engine.replay_journal(BufReader::new(file)),
+            })
+            .map_err(|e| format!("Couldn't replay journal {}: {}", path, e))?;
+    }
+    engine.process_events(events)?;
+    let final_state = match state_output {
+        Some(state_output) => engine.finish_saving_state(state_output),
+        None => engine.finish(),
+    }?;
+    #[cfg(feature = "sqlite")]
+    if let (Some(sqlite_journal), Some(path)) = (sqlite_journal, &args.sqlite_journal_path) {
+        sqlite_journal
+            .finish()
+            .map_err(|e| format!("Couldn't write SQLite journal {}: {}", path, e))?;
+    }
+    Ok(final_state)
+}
+
+// Opens the journal for appending, starting it if it's new. If the last run
+// died partway through writing an entry, that entry is cut off first, since
+// anything appended after it couldn't be read back.
+fn open_journal(path: &str) -> io::Result<Journal> {
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)?;
+    if file.metadata()?.len() == 0 {
+        return Journal::new(BufWriter::new(file));
+    }
+
+    let mut reader = JournalReader::new(BufReader::new(&file))?;
+    for entry in reader.by_ref() {
+        entry?;
+    }
+    let complete_len = reader.complete_len();
+    file.set_len(complete_len)?;
+    file.seek(SeekFrom::Start(complete_len))?;
+
+    Ok(Journal::append_to(BufWriter::new(file)))
+}
+
+fn process_sharded(
+    events: ParsedEvents,
+    error_writer: Option<ChannelWriter>,
+    args: &Args,
+    threads: usize,
+) -> Result<FinalState, Box<dyn Error>> {
+    let mut error_logger = match error_writer {
+        Some(error_writer) => format::rejection_logger(args.error_format, error_writer),
+        None => Box::new(io::sink()),
+    };
+
+    debug!("Processing clients on {} threads.", threads);
+    Ok(system::process_events_sharded(
+        events,
+        error_logger.as_mut(),
+        &args.engine_config,
+        threads,
+    )?)
+}
+
+// How much of the input is read at a time (and how much of the rejections is
+// written at a time), how many events are passed on at a time, and how many
+// of either can be waiting on the next stage before the stage before it has
+// to wait too.
+const CHUNK_LEN: usize = 64 * 1024;
+const EVENT_BATCH_LEN: usize = 1024;
+const STAGE_QUEUE_LEN: usize = 16;
+
+fn read_input(input: &mut impl Read, sender: SyncSender<io::Result<Vec<u8>>>) {
+    loop {
+        let mut chunk = vec![0; CHUNK_LEN];
+        let chunk = match input.read(&mut chunk) {
+            Ok(0) => return,
+            Ok(read) => {
+                chunk.truncate(read);
+                Ok(chunk)
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => Err(e),
+        };
+        let failed = chunk.is_err();
+        if sender.send(chunk).is_err() || failed {
+            return;
+        }
+    }
+}
+
+fn parse_input(
+    reader: ChunkReader,
+    format: InputFormat,
+    keep_records: bool,
+    only_clients: Option<&HashSet<ClientID>>,
+    sender: SyncSender<Result<Vec<SourcedEvent>, ParseError>>,
+) {
+    let events = format::parse_events(format, reader, keep_records);
+
+    let mut batch = Vec::with_capacity(EVENT_BATCH_LEN);
+    for event in events {
+        let event = match event {
+            Ok(event) => event,
+            Err(e) => {
+                // the events before it still get processed
+                let _ = sender.send(Ok(batch));
+                let _ = sender.send(Err(e));
+                return;
+            }
+        };
+        if let Some(only_clients) = only_clients {
+            let client_ids = event.event.client_ids();
+            if !client_ids
+                .iter()
+                .flatten()
+                .any(|id| only_clients.contains(id))
+            {
+                continue;
+            }
+        }
+        batch.push(event);
+        if batch.len() == EVENT_BATCH_LEN
+            && sender
+                .send(Ok(mem::replace(
+                    &mut batch,
+                    Vec::with_capacity(EVENT_BATCH_LEN),
+                )))
+                .is_err()
+        {
+            return;
+        }
+    }
+    let _ = sender.send(Ok(batch));
+}
+
+fn write_output(receiver: Receiver<Vec<u8>>, mut writer: Box<dyn Write + Send>) -> io::Result<()> {
+    for chunk in receiver {
+        writer.write_all(&chunk)?;
+    }
+    writer.flush()
+}
+
+// Reads whatever the reader stage sends, as if it were the input itself.
+struct ChunkReader {
+    receiver: Receiver<io::Result<Vec<u8>>>,
+    chunk: Vec<u8>,
+    offset: usize,
+}
+
+impl ChunkReader {
+    fn new(receiver: Receiver<io::Result<Vec<u8>>>) -> Self {
+        Self {
+            receiver,
+            chunk: Vec::new(),
+            offset: 0,
+        }
+    }
+}
+
+impl Read for ChunkReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.offset == self.chunk.len() {
+            // the reader stage hanging up is the end of the input
+            match self.receiver.recv() {
+                Ok(chunk) => self.chunk = chunk?,
+                Err(_) => return Ok(0),
+            }
+            self.offset = 0;
+        }
+
+        let read = buf.len().min(self.chunk.len() - self.offset);
+        buf[..read].copy_from_slice(&self.chunk[self.offset..self.offset + read]);
+        self.offset += read;
+        Ok(read)
+    }
+}
+
+// How many events go by between progress updates.
+const PROGRESS_INTERVAL: u64 = 1_000_000;
+
+// The events from the parser stage, one at a time.
+struct ParsedEvents {
+    receiver: Receiver<Result<Vec<SourcedEvent>, ParseError>>,
+    batch: vec::IntoIter<SourcedEvent>,
+    // for the progress updates
+    count: u64,
+    started: Instant,
+}
+
+impl ParsedEvents {
+    fn new(receiver: Receiver<Result<Vec<SourcedEvent>, ParseError>>) -> Self {
+        Self {
+            receiver,
+            batch: Vec::new().into_iter(),
+            count: 0,
+            started: Instant::now(),
+        }
+    }
+}
+
+impl Iterator for ParsedEvents {
+    type Item = Result<SourcedEvent, ParseError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(event) = self.batch.next() {
+                self.count += 1;
+                if self.count.is_multiple_of(PROGRESS_INTERVAL) {
+                    info!(
+                        "Read {} events in {:.2}s.",
+                        self.count,
+                        self.started.elapsed().as_secs_f64()
+                    );
+                }
+                return Some(Ok(event));
+            }
+            match self.receiver.recv().ok()? {
+                Ok(batch) => self.batch = batch.into_iter(),
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
+
+// Passes whatever's written on to the writer stage, a chunk at a time.
+// Anything left over is passed on when it's dropped, so that a run that fails
+// still logs everything up until then.
+struct ChannelWriter {
+    sender: SyncSender<Vec<u8>>,
+    buffer: Vec<u8>,
+}
+
+impl ChannelWriter {
+    fn new(sender: SyncSender<Vec<u8>>) -> Self {
+        Self {
+            sender,
+            buffer: Vec::with_capacity(CHUNK_LEN),
+        }
+    }
+
+    fn send(&mut self) -> io::Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+        let chunk = mem::replace(&mut self.buffer, Vec::with_capacity(CHUNK_LEN));
+        self.sender.send(chunk).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "Stopped writing rejections unexpectedly.",
+            )
+        })
+    }
+}
+
+impl Write for ChannelWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        if self.buffer.len() >= CHUNK_LEN {
+            self.send()?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.send()
+    }
+}
+
+impl Drop for ChannelWriter {
+    fn drop(&mut self) {
+        let _ = self.send();
+    }
+}
+
+fn run_diff(old_path: &str, new_path: &str) -> Result<ExitCode, Box<dyn Error>> {
+    let old = format::csv::input::parse_report(File::open(old_path)?)?;
+    let new = format::csv::input::parse_report(File::open(new_path)?)?;
+
+    let mut stdout = io::stdout();
+    format::csv::output::write_diff(
+        &system::diff_reports(&old, &new),
+        &mut stdout,
+        &ReportConfig::default(),
+    )?;
+    stdout.flush()?;
+
+    Ok(ExitCode::SUCCESS)
+}
+
+// Counts what's in the input for sizing up a run before doing it. Rows that
+// don't parse are counted rather than failing it, since they're one of the
+// things worth knowing about.
+fn run_stats(input_path: &str, input_format: InputFormat) -> Result<ExitCode, Box<dyn Error>> {
+    let input = File::open(input_path)?;
+    let stats = system::input_stats(format::parse_events(input_format, input, false))?;
+
+    format::json::stats::write_stats(&stats, io::stdout())?;
+
+    Ok(ExitCode::SUCCESS)
+}
+
+fn run_explain(code: Option<&str>) -> Result<ExitCode, Box<dyn Error>> {
+    let mut stdout = io::stdout().lock();
+    let Some(code) = code else {
+        let width = system::REJECTION_CODES
+            .iter()
+            .map(|explanation| explanation.code.len())
+            .max()
+            .unwrap_or_default();
+        for explanation in system::REJECTION_CODES {
+            writeln!(
+                stdout,
+                "{:<width$}  {}",
+                explanation.code,
+                explanation.meaning,
+                width = width
+            )?;
+        }
+        return Ok(ExitCode::SUCCESS);
+    };
+
+    let explanation = system::explain_code(code).ok_or_else(|| {
+        let codes = system::REJECTION_CODES
+            .iter()
+            .map(|explanation| explanation.code)
+            .collect::<Vec<_>>();
+        format!(
+            "Unknown rejection code: {}. The codes are {}.",
+            code,
+            codes.join(", ")
+        )
+    })?;
+    writeln!(stdout, "{}: {}", explanation.code, explanation.meaning)?;
+    writeln!(stdout)?;
+    writeln!(stdout, "Typical causes:")?;
+    for cause in explanation.causes {
+        writeln!(stdout, "- {}", cause)?;
+    }
+    writeln!(stdout)?;
+    writeln!(stdout, "{}", explanation.behavior)?;
+
+    Ok(ExitCode::SUCCESS)
+}
+
+// Answers questions about one client without rerunning anything, by loading
+// the state a previous run left behind (or replaying its journal) and looking
+// them up. Nothing new is processed, so the default config does.
+fn run_query(
+    client_id: ClientID,
+    state_path: Option<String>,
+    journal_path: Option<String>,
+) -> Result<ExitCode, Box<dyn Error>> {
+    let engine = match (state_path, journal_path) {
+        (Some(path), _) => Engine::builder()
+            .build_resumed(BufReader::new(File::open(&path)?))
+            .map_err(|e| format!("Couldn't load state {}: {}", path, e))?,
+        (None, Some(path)) => {
+            let mut engine = Engine::builder().build();
+            File::open(&path)
+                .and_then(|file| engine.replay_journal(BufReader::new(file)))
+                .map_err(|e| format!("Couldn't replay journal {}: {}", path, e))?;
+            engine
+        }
+        // clap makes sure there's one or the other
+        (None, None) => unreachable!(),
+    };
+    let mut final_state = engine.finish()?;
+
+    let client = final_state
+        .clients_by_id
+        .remove(&client_id)
+        .ok_or_else(|| format!("There's no client {}.", client_id))?;
+    let report_config = ReportConfig::default();
+
+    let mut stdout = io::stdout().lock();
+    format::table::output::write_report(
+        &HashMap::from([(client_id, client)]),
+        &mut stdout,
+        &report_config,
+    )?;
+    writeln!(stdout)?;
+    let open_disputes = final_state.open_disputes(client_id);
+    if open_disputes.is_empty() {
+        writeln!(stdout, "No open disputes.")?;
+    } else {
+        format::table::output::write_disputes(open_disputes, &mut stdout, &report_config)?;
+    }
+    stdout.flush()?;
+
+    Ok(ExitCode::SUCCESS)
+}
+
+// Runs the HTTP API (see `serve.rs`) until the process is stopped. With a
+// journal, a restart picks up where the last one left off: the journal is
+// replayed before anything new is taken, and each request's events are in it
+// before the request is answered. Any other tenants start from their own last
+// snapshots, if there are any, and otherwise from nothing.
+#[allow(clippy::too_many_arguments)]
+fn run_serve(
+    mut builder: EngineBuilder,
+    listen: &str,
+    listeners: serve::Listeners,
+    tenants: &[String],
+    resume_from_path: Option<String>,
+    journal_path: Option<String>,
+    mut snapshots: Option<(Box<dyn serve::StateSnapshots>, Duration)>,
+    delta_sink: Option<Box<dyn serve::DeltaSink>>,
+) -> Result<ExitCode, Box<dyn Error>> {
+    if let Some(path) = &journal_path {
+        builder = builder.journal(
+            open_journal(path).map_err(|e| format!("Couldn't open journal {}: {}", path, e))?,
+        );
+    }
+    // the last snapshot, if there's been one, is where it carries on from
+    let snapshot = last_snapshot(&mut snapshots, serve::DEFAULT_TENANT)?;
+    let state_reader: Option<Box<dyn Read>> = match (snapshot, &resume_from_path) {
+        (Some(snapshot), _) => Some(Box::new(io::Cursor::new(snapshot))),
+        (None, Some(path)) => Some(Box::new(BufReader::new(File::open(path)?))),
+        (None, None) => None,
+    };
+    let mut service = serve::Service::new(builder, state_reader).map_err(|e| {
+        format!(
+            "Couldn't resume from {}: {}",
+            resume_from_path.as_deref().unwrap_or("the last snapshot"),
+            e
+        )
+    })?;
+    let mut added = HashSet::new();
+    for tenant in tenants {
+        let (tenant, config_path) = parse_tenant(tenant)?;
+        if !added.insert(tenant) {
+            return Err(format!("There's more than one --tenant {}.", tenant).into());
+        }
+        let builder = match config_path {
+            Some(path) => Engine::builder().config(tenant_config(path)?),
+            None => Engine::builder(),
+        };
+        let snapshot = last_snapshot(&mut snapshots, tenant)?;
+        service
+            .add_tenant(tenant, builder, snapshot.map(io::Cursor::new))
+            .map_err(|e| format!("Couldn't resume {} from its last snapshot: {}", tenant, e))?;
+    }
+    let (snapshots, snapshot_interval) = snapshots.unzip();
+    let mut service = service
+        .with_delta_sink(delta_sink)
+        .with_snapshots(snapshots, snapshot_interval.unwrap_or_default())
+        .stop_on_signals();
+    if let Some(path) = &journal_path {
+        let replayed = File::open(path)
+            .and_then(|file| service.replay_journal(BufReader::new(file)))
+            .map_err(|e| format!("Couldn't replay journal {}: {}", path, e))?;
+        debug!("Replayed {} events from {}.", replayed, path);
+    }
+
+    let server = tiny_http::Server::http(listen)
+        .map_err(|e| format!("Couldn't listen on {}: {}", listen, e))?;
+    info!("Listening on {}.", listen);
+    service.run(&server, listeners);
+    info!("Stopped.");
+
+    Ok(ExitCode::SUCCESS)
+}
+
+// Splits `--tenant`'s `NAME[=CONFIG]`. The name goes in URLs and snapshots'
+// names, so it's kept to letters, digits, `-` and `_`.
+fn parse_tenant(tenant: &str) -> Result<(&str, Option<&str>), String> {
+    let (name, config_path) = match tenant.split_once('=') {
+        Some((name, config_path)) => (name, Some(config_path)),
+        None => (tenant, None),
+    };
+    let valid = |c: char| c.is_ascii_alphanumeric() || c == '-' || c == '_';
+    if name.is_empty() || !name.chars().all(valid) {
+        return Err(format!(
+            "Invalid tenant {}: it can only have letters, digits, - and _.",
+            name
+        ));
+    }
+    if name == serve::DEFAULT_TENANT {
+        return Err(format!(
+            "There's always a {} tenant, at the root.",
+            serve::DEFAULT_TENANT
+        ));
+    }
+    Ok((name, config_path))
+}
+
+// A tenant's policies, from a file like `--config`'s. Anything in it besides
+// the policies means nothing to serve, so it's ignored.
+fn tenant_config(path: &str) -> Result<EngineConfig, Box<dyn Error>> {
+    let mut args = vec![String::from("challenge")];
+    args.extend(config_args(path)?);
+    // a run needs an input, even though this one's never read
+    args.push(String::from("-"));
+    // clap's own message goes on to the usage, which is the command line's
+    let cli = Cli::try_parse_from(args).map_err(|e| {
+        let e = e.to_string();
+        let e = e.lines().next().unwrap_or_default();
+        format!(
+            "Invalid tenant config {}: {}",
+            path,
+            e.trim_start_matches("error: ")
+        )
+    })?;
+    Ok(resolve_args(cli.run, true)
+        .map_err(|e| format!("Invalid tenant config {}: {}", path, e))?
+        .engine_config)
+}
+
+// A tenant's last snapshot, if there's anywhere they're kept and there's been
+// one.
+fn last_snapshot(
+    snapshots: &mut Option<(Box<dyn serve::StateSnapshots>, Duration)>,
+    tenant: &str,
+) -> Result<Option<Vec<u8>>, String> {
+    match snapshots.as_mut() {
+        Some((snapshots, _)) => snapshots
+            .load(tenant)
+            .map_err(|e| format!("Couldn't load {}'s last snapshot: {}", tenant, e)),
+        None => Ok(None),
+    }
+}
+
+// Where serve keeps snapshots of its state: an S3 object if it's an `s3://`
+// URL, or a file otherwise.
+fn state_snapshots(location: &str) -> Result<Box<dyn serve::StateSnapshots>, Box<dyn Error>> {
+    if location.starts_with("s3://") {
+        #[cfg(feature = "s3")]
+        return Ok(Box::new(s3::S3Snapshots::open(location)?));
+        #[cfg(not(feature = "s3"))]
+        return Err("Keeping snapshots in S3 needs the s3 feature.".into());
+    }
+    Ok(Box::new(FileSnapshots {
+        path: PathBuf::from(location),
+    }))
+}
+
+// Snapshots of serve's state in a file (a file per tenant, see
+// `serve::tenant_location`), which is replaced as a whole each time.
+struct FileSnapshots {
+    path: PathBuf,
+}
+
+impl FileSnapshots {
+    fn tenant_path(&self, tenant: &str) -> PathBuf {
+        let path = self.path.to_string_lossy();
+        PathBuf::from(serve::tenant_location(&path, tenant))
+    }
+}
+
+impl serve::StateSnapshots for FileSnapshots {
+    fn load(&mut self, tenant: &str) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+        match fs::read(self.tenant_path(tenant)) {
+            Ok(snapshot) => Ok(Some(snapshot)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn save(&mut self, tenant: &str, snapshot: &[u8]) -> Result<(), Box<dyn Error>> {
+        let mut file = AtomicFile::create(self.tenant_path(tenant))?;
+        file.write_all(snapshot)?;
+        file.commit()
+    }
+}
+
+// Listens on a TCP address for something serve takes besides HTTP.
+fn listen_tcp(address: &str, what: &str) -> Result<TcpListener, String> {
+    let listener =
+        TcpListener::bind(address).map_err(|e| format!("Couldn't listen on {}: {}", address, e))?;
+    info!("Listening for {} on {}.", what, address);
+    Ok(listener)
+}
+
+// Writes the clients as they stand partway through a run to a new file in the
+// snapshot directory, named after when it was taken and how far in we were.
+fn write_snapshot(
+    clients_by_id: &HashMap<ClientID, Client>,
+    event_counts: &EventCounts,
+    args: &Args,
+) -> Result<(), Box<dyn Error>> {
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis();
+    let file_name = format!(
+        "snapshot-{}-{}.{}",
+        timestamp,
+        event_counts.processed,
+        args.report_config.format.extension()
+    );
+
+    let path = args.snapshot_dir.join(file_name);
+    let mut file = AtomicFile::create(&path)?;
+    format::write_report(clients_by_id, event_counts, &mut file, &args.report_config)?;
+    file.commit()?;
+    debug!("Wrote snapshot {}.", path.display());
+
+    Ok(())
+}
+
+// Somewhere the report is written to, along with how. There's just the one
+// unless the report is partitioned, in which case there's one per partition.
+struct ReportOutput {
+    writer: CompressedWriter<Output>,
+    config: ReportConfig,
+}
+
+impl ReportOutput {
+    fn create_all(args: &Args) -> Result<Vec<Self>, Box<dyn Error>> {
+        if args.dry_run {
+            return Ok(Vec::new());
+        }
+
+        // an explicit flag wins, otherwise we go by the output file's extension
+        let compression = match (args.compression, &args.output_path) {
+            (Some(compression), _) => compression,
+            (None, Some(output_path)) => Compression::from_path(output_path),
+            (None, None) => Compression::None,
+        };
+
+        let (count, output_path) = match (args.partitions, &args.output_path) {
+            (None, _) => {
+                return Ok(vec![Self {
+                    writer: CompressedWriter::new(Output::create(&args.output_path)?, compression)?,
+                    config: args.report_config.clone(),
+                }])
+            }
+            (Some(_), None) => return Err("--partitions needs an --output path.".into()),
+            (Some(count), Some(output_path)) => (count, output_path),
+        };
+
+        (0..count)
+            .map(|index| {
+                let path = partition_path(output_path, index, count);
+                Ok(Self {
+                    writer: CompressedWriter::new(
+                        Output::File(AtomicFile::create(path)?),
+                        compression,
+                    )?,
+                    config: ReportConfig {
+                        partition: Some(Partition {
+                            partitioning: args.partitioning,
+                            index,
+                            count,
+                        }),
+                        ..args.report_config.clone()
+                    },
+                })
+            })
+            .collect()
+    }
+}
+
+// Numbers a partition's file, e.g. `report.csv.gz` becomes `report-03.csv.gz`.
+// The number is padded so that the files sort in order.
+fn partition_path(output_path: &str, index: usize, count: usize) -> PathBuf {
+    let path = Path::new(output_path);
+    let file_name = path
+        .file_name()
+        .map(|file_name| file_name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let (stem, extensions) = match file_name.find('.') {
+        Some(dot) => file_name.split_at(dot),
+        None => (file_name.as_str(), ""),
+    };
+    let width = (count - 1).to_string().len();
+
+    path.with_file_name(format!("{}-{:0width$}{}", stem, index, extensions))
+}
+
+// What gets uploaded to S3 once the run's done: the report (or each of its
+// partitions), the error file and the manifest, whichever of them were
+// written to files.
+#[cfg(feature = "s3")]
+fn uploaded_paths(args: &Args) -> Vec<PathBuf> {
+    let mut paths = match (&args.output_path, args.partitions) {
+        (Some(output_path), Some(count)) => (0..count)
+            .map(|index| partition_path(output_path, index, count))
+            .collect(),
+        (Some(output_path), None) => vec![PathBuf::from(output_path)],
+        (None, _) => Vec::new(),
+    };
+    if let ErrorDestination::File(path) = &args.errors {
+        paths.push(PathBuf::from(path));
+    }
+    paths.extend(args.manifest_path.iter().map(PathBuf::from));
+    paths
+}
+
+// The reports written alongside the main one, if asked for.
+struct SideReports {
+    dispute: Option<AtomicFile>,
+    reconciliation: Option<AtomicFile>,
+    counterparty: Option<AtomicFile>,
+    // The textfile collector may read the file at any moment, so it's just as
+    // important that this one is written atomically.
+    metrics: Option<AtomicFile>,
+    manifest: Option<AtomicFile>,
+    // The state for the next run to resume from. Written atomically so that
+    // a failed run leaves the last good state in place (which may well be the
+    // one this run resumed from).
+    state: Option<AtomicFile>,
+    // Connected to up front so that a bad DSN fails the run before it starts
+    // rather than after. The report goes in one transaction, which is
+    // committed as soon as it's written.
+    #[cfg(feature = "postgres")]
+    postgres: Option<postgres::Client>,
+}
+
+impl SideReports {
+    fn create(args: &Args) -> Result<Self, Box<dyn Error>> {
+        let create = |path: &Option<String>| path.as_ref().map(AtomicFile::create).transpose();
+
+        Ok(Self {
+            dispute: create(&args.dispute_report_path)?,
+            reconciliation: create(&args.reconciliation_path)?,
+            counterparty: create(&args.counterparty_report_path)?,
+            metrics: create(&args.metrics_path)?,
+            manifest: create(&args.manifest_path)?,
+            state: create(&args.save_state_path)?,
+            #[cfg(feature = "postgres")]
+            postgres: args
+                .postgres
+                .as_ref()
+                .map(|postgres_output| {
+                    postgres::Client::connect(&postgres_output.dsn, postgres::NoTls)
+                        .map_err(|e| format!("Couldn't connect to Postgres: {}", e))
+                })
+                .transpose()?,
+        })
+    }
+
+    fn commit(self) -> Result<(), Box<dyn Error>> {
+        for file in [
+            self.dispute,
+            self.reconciliation,
+            self.counterparty,
+            self.metrics,
+            self.manifest,
+            self.state,
+        ]
+        .into_iter()
+        .flatten()
+        {
+            file.commit()?;
+        }
+        Ok(())
+    }
+}
+
+// Passes reads through while (optionally) hashing everything read, so that we
+// can hash the input without reading it twice.
+struct HashingReader<R> {
+    reader: R,
+    hasher: Option<Sha256>,
+}
+
+impl<R: Read> HashingReader<R> {
+    fn new(reader: R, enabled: bool) -> Self {
+        Self {
+            reader,
+            hasher: enabled.then(Sha256::new),
+        }
+    }
+
+    fn hex_digest(&self) -> Option<String> {
+        let hasher = self.hasher.clone()?;
+        Some(format!("{:x}", hasher.finalize()))
+    }
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.reader.read(buf)?;
+        if let Some(hasher) = self.hasher.as_mut() {
+            hasher.update(&buf[..read]);
+        }
+        Ok(read)
+    }
+}
+
+// Where the report goes: stdout unless an output path was given.
+enum Output {
+    Stdout(io::Stdout),
+    File(AtomicFile),
+}
+
+impl Output {
+    fn create(path: &Option<String>) -> io::Result<Self> {
+        match path {
+            Some(path) => Ok(Output::File(AtomicFile::create(path)?)),
+            None => Ok(Output::Stdout(io::stdout())),
+        }
+    }
+
+    fn commit(self) -> Result<(), Box<dyn Error>> {
+        match self {
+            Output::Stdout(mut stdout) => Ok(stdout.flush()?),
+            Output::File(file) => file.commit(),
+        }
+    }
+}
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Output::Stdout(stdout) => stdout.write(buf),
+            Output::File(file) => file.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Output::Stdout(stdout) => stdout.flush(),
+            Output::File(file) => file.flush(),
+        }
+    }
+}
+
+// A file that is written to a temp file in the same directory and only renamed
+// into place on `commit`, so that a failed run never leaves a truncated file
+// behind (and a reader never sees a half-written one). Dropping it without
+// committing deletes the temp file.
+struct AtomicFile {
+    writer: BufWriter<NamedTempFile>,
+    path: PathBuf,
+}
+
+impl AtomicFile {
+    fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref();
+        let mut builder = tempfile::Builder::new();
+        // temp files are only readable by their owner, which the file that
+        // replaces the real one shouldn't be, so it gets whatever mode the
+        // real one already has, or what the umask leaves of 0666 like any
+        // other new file
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+
+            builder.permissions(match fs::metadata(path) {
+                Ok(metadata) => metadata.permissions(),
+                Err(_) => fs::Permissions::from_mode(0o666),
+            });
+        }
+        Ok(Self {
+            writer: BufWriter::new(builder.tempfile_in(Self::dir(path))?),
+            path: path.to_path_buf(),
+        })
+    }
+
+    fn dir(path: &Path) -> &Path {
+        match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        }
+    }
+
+    // The contents are synced before the rename, since otherwise a crash soon
+    // after can leave the rename on disk but not what was written, i.e. an
+    // empty or truncated file under the real name. The directory's synced
+    // after it, so that the rename itself survives a crash too.
+    fn commit(self) -> Result<(), Box<dyn Error>> {
+        let tmp_file = self.writer.into_inner().map_err(|e| e.into_error())?;
+        tmp_file.as_file().sync_all()?;
+        tmp_file.persist(&self.path)?;
+        // directories can't be opened (let alone synced) like this on
+        // Windows, so there it's up to the filesystem
+        #[cfg(unix)]
+        File::open(Self::dir(&self.path))?.sync_all()?;
+        Ok(())
+    }
+}
+
+impl Write for AtomicFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.writer.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+// The command line as clap sees it. Running with an input file is the main
+// thing we do, so its options are at the top level rather than under a
+// subcommand of their own.
+#[derive(Parser)]
+#[command(
+    name = "challenge",
+    version,
+    about = "Processes a CSV of payment events and reports where each client stands.",
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true,
+    args_override_self = true
+)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+    #[command(flatten)]
+    run: RunOptions,
+
+    // These go for the subcommands too, hence being out here.
+    #[arg(
+        short,
+        long,
+        global = true,
+        action = ArgAction::Count,
+        help = "Log progress and a summary to stderr, or with -vv what each stage is up to.",
+        help_heading = "Diagnostics"
+    )]
+    verbose: u8,
+    #[arg(
+        short,
+        long,
+        global = true,
+        conflicts_with = "verbose",
+        help = "Log nothing to stderr but failures, including rejected events unless --errors says otherwise.",
+        help_heading = "Diagnostics"
+    )]
+    quiet: bool,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    #[command(about = "Compares two reports and writes how each client changed to stdout.")]
+    Diff {
+        #[arg(value_name = "OLD_REPORT")]
+        old_path: String,
+        #[arg(value_name = "NEW_REPORT")]
+        new_path: String,
+    },
+    #[command(
+        about = "Profiles an input without processing it, writing what's in it to stdout as JSON."
+    )]
+    Stats {
+        #[arg(value_name = "INPUT")]
+        input_path: String,
+        #[arg(
+            long,
+            value_name = "csv|jsonl",
+            default_value = "csv",
+            help = "What the input is written in."
+        )]
+        input_format: InputFormat,
+    },
+    #[command(
+        about = "Writes where a client stands, and which of their transactions are disputed, \
+                 as of a saved state or journal."
+    )]
+    Query {
+        #[arg(long, value_name = "ID", help = "The client to look up.")]
+        client: ClientID,
+        #[arg(
+            long,
+            value_name = "PATH",
+            required_unless_present = "journal",
+            conflicts_with = "journal",
+            help = "A state written with --save-state."
+        )]
+        state: Option<String>,
+        #[arg(long, value_name = "PATH", help = "A journal written with --journal.")]
+        journal: Option<String>,
+    },
+    // Boxed since with every feature on its options are far bigger than any
+    // other command's.
+    #[command(
+        about = "Takes events over HTTP and answers for the clients' balances until stopped."
+    )]
+    Serve(Box<ServeOptions>),
+    #[command(about = "Writes what a rejection code means, or lists them all.")]
+    Explain {
+        #[arg(value_name = "CODE")]
+        code: Option<String>,
+    },
+}
+
+// Puts the options from the `--config` file, if there is one, in front of the
+// ones on the command line, so that clap deals with both the same way and the
+// command line wins where they overlap (see `args_override_self`). The file
+// has to be found before clap gets going, hence looking for it by hand.
+fn with_config_args(mut args: Vec<String>) -> Result<Vec<String>, clap::Error> {
+    let config_path = args
+        .iter()
+        .enumerate()
+        .find_map(|(index, arg)| match arg.as_str() {
+            "--config" => args.get(index + 1).cloned(),
+            arg => arg.strip_prefix("--config=").map(String::from),
+        });
+    let Some(config_path) = config_path else {
+        return Ok(args);
+    };
+
+    let config_args =
+        config_args(&config_path).map_err(|e| Cli::command().error(ErrorKind::InvalidValue, e))?;
+    args.splice(1..1, config_args);
+    Ok(args)
+}
+
+// Turns a TOML config file into the flags it stands for. Each key is the long
+// name of a flag (with dashes or underscores), e.g. `max_rejections = "1%"`
+// for `--max-rejections 1%`. `true` passes a flag that doesn't take a value,
+// and `false` leaves it out.
+fn config_args(path: &str) -> Result<Vec<String>, String> {
+    let config = fs::read_to_string(path)
+        .map_err(|e| e.to_string())
+        .and_then(|config| config.parse::<toml::Table>().map_err(|e| e.to_string()))
+        .map_err(|e| format!("Couldn't read config {}: {}", path, e))?;
+    // building it is what works out how many values each flag takes
+    let mut command = Cli::command();
+    command.build();
+
+    let mut args = Vec::new();
+    for (key, value) in config {
+        let name = key.replace('_', "-");
+        // the file can't point at another file
+        let arg = command
+            .get_arguments()
+            .find(|arg| arg.get_long() == Some(&name) && name != "config")
+            .ok_or_else(|| format!("Unknown option in config {}: {}.", path, key))?;
+        let takes_value = arg
+            .get_num_args()
+            .is_some_and(|num_args| num_args.takes_values());
+
+        // `=` so that values starting with a dash aren't taken for flags
+        match (value, takes_value) {
+            (toml::Value::Boolean(true), false) => args.push(format!("--{}", name)),
+            (toml::Value::Boolean(false), false) => {}
+            (toml::Value::String(value), true) => args.push(format!("--{}={}", name, value)),
+            (toml::Value::Integer(value), true) => args.push(format!("--{}={}", name, value)),
+            (toml::Value::Float(value), true) => args.push(format!("--{}={}", name, value)),
+            _ => return Err(format!("Invalid value for {} in config {}.", key, path)),
+        }
+    }
+    Ok(args)
+}
+
+// Everything serve takes.
+#[derive(clap::Args)]
+struct ServeOptions {
+    #[arg(
+        long,
+        value_name = "ADDRESS",
+        default_value = "127.0.0.1:8080",
+        help = "Where to listen."
+    )]
+    listen: String,
+    #[arg(
+        long,
+        value_name = "ADDRESS",
+        help = "Also take CSV a line at a time over plain TCP here."
+    )]
+    tcp: Option<String>,
+    #[cfg(feature = "flight")]
+    #[arg(
+        long,
+        value_name = "ADDRESS",
+        help = "Also serve the balances over Arrow Flight here."
+    )]
+    flight: Option<String>,
+    #[arg(
+        long,
+        value_name = "PATH",
+        help = "Carry on from a state written with --save-state."
+    )]
+    resume_from: Option<String>,
+    #[arg(
+        long,
+        value_name = "PATH",
+        help = "Journal every accepted event here, replaying what's already in it first."
+    )]
+    journal: Option<String>,
+    #[arg(
+        long,
+        value_name = "PATH",
+        conflicts_with_all = ["resume_from", "journal"],
+        help = "Save snapshots of the state here (a file, or an s3:// URL), carrying on from the last one."
+    )]
+    save_state: Option<String>,
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        default_value = "60",
+        requires = "save_state",
+        value_parser = clap::value_parser!(u64).range(1..),
+        help = "How often to save a snapshot, besides when stopped."
+    )]
+    save_state_every: u64,
+    #[arg(
+        long = "tenant",
+        value_name = "NAME[=CONFIG]",
+        help = "Also keep a ledger for this tenant under /tenants/NAME/, with the policies in CONFIG (a --config file) if given."
+    )]
+    tenants: Vec<String>,
+    #[cfg(feature = "kafka")]
+    #[command(flatten)]
+    kafka: kafka::KafkaOptions,
+    #[cfg(feature = "nats")]
+    #[command(flatten)]
+    nats: nats::NatsOptions,
+    #[cfg(feature = "webhooks")]
+    #[command(flatten)]
+    webhook: webhook::WebhookOptions,
+    #[cfg(feature = "redis")]
+    #[arg(
+        long,
+        value_name = "URL",
+        conflicts_with_all = ["resume_from", "journal", "save_state"],
+        help = "Keep the clients and transactions in Redis, carrying on from what's there. Only one instance may use the same Redis and prefix at a time."
+    )]
+    redis: Option<String>,
+    #[cfg(feature = "redis")]
+    #[arg(
+        long,
+        value_name = "PREFIX",
+        default_value = "challenge",
+        help = "What the keys in Redis start with."
+    )]
+    redis_prefix: String,
+}
+
+// Everything a normal run takes. These are checked against each other and
+// gathered up into `Args` by `resolve_args`, which is also where the few that
+// open files or need arithmetic are dealt with.
+#[derive(clap::Args)]
+struct RunOptions {
+    #[arg(
+        value_name = "FILE",
+        help = "The CSV of events to process.",
+        required_unless_present_any = ["as_of_event", "as_of_time"],
+        conflicts_with_all = ["as_of_event", "as_of_time"]
+    )]
+    input_path: Option<String>,
+    #[arg(
+        long,
+        value_name = "csv|jsonl",
+        default_value = "csv",
+        help = "What the input is written in."
+    )]
+    input_format: InputFormat,
+    #[arg(
+        long,
+        value_name = "PATH",
+        help = "A TOML file of options, e.g. `compact = true`, which those given here override."
+    )]
+    config: Option<String>,
+    #[arg(
+        long,
+        help = "Process the input and report rejections as usual, but don't write anything else."
+    )]
+    dry_run: bool,
+    #[arg(
+        long,
+        help = "Write how long each stage took, and the peak memory, to stderr at the end.",
+        help_heading = "Diagnostics"
+    )]
+    profile: bool,
+
+    #[arg(
+        long,
+        visible_alias = "format",
+        value_name = "FORMAT",
+        help = "csv, json, json-map, jsonl, table, xml, html (or arrow, if built with it).",
+        help_heading = "Report"
+    )]
+    output_format: Option<OutputFormat>,
+    #[arg(
+        long,
+        conflicts_with = "output_format",
+        help = "Shorthand for --output-format table.",
+        help_heading = "Report"
+    )]
+    pretty: bool,
+    #[arg(
+        long,
+        value_name = "PATH",
+        help = "Write the report here instead of to stdout.",
+        help_heading = "Report"
+    )]
+    output: Option<String>,
+    #[arg(
+        long,
+        value_name = "none|gzip|zstd",
+        help = "Compress the report, which defaults to guessing from the --output extension.",
+        help_heading = "Report"
+    )]
+    compress: Option<Compression>,
+    #[arg(
+        long,
+        value_name = "COLUMN[:HEADER],...",
+        help = "Which columns to report, and what to call them.",
+        help_heading = "Report"
+    )]
+    columns: Option<String>,
+    #[arg(
+        long = "client",
+        value_name = "ID,...",
+        value_delimiter = ',',
+        help = "Only report these clients.",
+        help_heading = "Report"
+    )]
+    clients: Vec<ClientID>,
+    #[arg(
+        long,
+        value_name = "client_id|unsorted",
+        help = "The order clients are listed in, where unsorted saves sorting them all at the end.",
+        help_heading = "Report"
+    )]
+    sort: Option<ReportOrder>,
+    #[arg(
+        long,
+        value_name = "PLACES",
+        value_parser = clap::value_parser!(u32).range(0..=i64::from(Amount::MAX_SCALE)),
+        help = "How many decimal places amounts are written with (4 by default).",
+        help_heading = "Report"
+    )]
+    precision: Option<u32>,
+    #[arg(
+        long,
+        requires = "clients",
+        help = "Only process the events that name one of the --client clients.",
+        help_heading = "Processing"
+    )]
+    client_events_only: bool,
+    #[arg(
+        long,
+        value_name = "N",
+        help = "Split the report into N files (needs --output).",
+        help_heading = "Report"
+    )]
+    partitions: Option<usize>,
+    #[arg(
+        long,
+        value_name = "hash|range",
+        default_value = "hash",
+        help = "How to split clients between partitions.",
+        help_heading = "Report"
+    )]
+    partition_by: Partitioning,
+    #[arg(
+        long,
+        value_name = "N|Ns",
+        help = "Write an interim report every N events or N seconds.",
+        help_heading = "Report"
+    )]
+    snapshot_every: Option<SnapshotInterval>,
+    #[arg(
+        long,
+        value_name = "PATH",
+        default_value = ".",
+        help = "Where to write interim reports.",
+        help_heading = "Report"
+    )]
+    snapshot_dir: PathBuf,
+
+    #[arg(
+        long,
+        value_name = "PATH|stderr|none",
+        help = "Where to log rejected events. [default: stderr, or none with --quiet]",
+        help_heading = "Rejections"
+    )]
+    errors: Option<String>,
+    #[arg(
+        long,
+        value_name = "text|json",
+        default_value = "text",
+        help = "How to log rejected events.",
+        help_heading = "Rejections"
+    )]
+    error_format: ErrorFormat,
+    #[arg(
+        long,
+        value_name = "N|N%",
+        help = "Exit with 2 if more events than this were rejected.",
+        help_heading = "Rejections"
+    )]
+    max_rejections: Option<RejectionThreshold>,
+    #[arg(
+        long,
+        conflicts_with = "max_rejections",
+        help = "Exit with 2 if any events were rejected, i.e. --max-rejections 0.",
+        help_heading = "Rejections"
+    )]
+    strict: bool,
+
+    #[arg(
+        long,
+        value_name = "PATH",
+        help = "Write every transaction that's been disputed or reversed.",
+        help_heading = "Side reports"
+    )]
+    dispute_report: Option<String>,
+    #[arg(
+        long,
+        value_name = "PATH",
+        help = "Write how the money in the report adds up.",
+        help_heading = "Side reports"
+    )]
+    reconciliation: Option<String>,
+    #[arg(
+        long,
+        value_name = "PATH",
+        help = "Write each counterparty's exposure to disputes.",
+        help_heading = "Side reports"
+    )]
+    counterparty_report: Option<String>,
+    #[arg(
+        long,
+        value_name = "PATH",
+        help = "Write metrics about the run in Prometheus' text format.",
+        help_heading = "Side reports"
+    )]
+    metrics: Option<String>,
+    #[arg(
+        long,
+        value_name = "PATH",
+        help = "Write a JSON manifest of how the report came to be.",
+        help_heading = "Side reports"
+    )]
+    manifest: Option<String>,
+    #[cfg(feature = "postgres")]
+    #[arg(
+        long,
+        value_name = "DSN",
+        help = "Also upsert each client's balances into Postgres, connecting with this.",
+        help_heading = "Side reports"
+    )]
+    postgres: Option<String>,
+    #[cfg(feature = "postgres")]
+    #[arg(
+        long,
+        value_name = "TABLE",
+        default_value = "balances",
+        help = "The Postgres table to upsert into, created if it isn't there.",
+        help_heading = "Side reports"
+    )]
+    postgres_table: String,
+    #[cfg(feature = "postgres")]
+    #[arg(
+        long,
+        value_name = "N",
+        default_value = "1000",
+        help = "How many rows to upsert into Postgres at a time.",
+        help_heading = "Side reports"
+    )]
+    postgres_batch_size: usize,
+
+    #[arg(
+        long,
+        value_name = "FEE",
+        help = "Charge a flat fee or a percentage on each deposit.",
+        help_heading = "Processing"
+    )]
+    deposit_fee: Option<Fee>,
+    #[arg(
+        long,
+        value_name = "FEE",
+        help = "Charge a flat fee or a percentage on each withdrawal.",
+        help_heading = "Processing"
+    )]
+    withdrawal_fee: Option<Fee>,
+    #[arg(
+        long,
+        value_name = "hold|reject|credit-held",
+        help = "What disputing a withdrawal does.",
+        help_heading = "Processing"
+    )]
+    withdrawal_disputes: Option<WithdrawalDisputePolicy>,
+    #[arg(
+        long,
+        help = "Let a charged back transaction be disputed again.",
+        help_heading = "Processing"
+    )]
+    allow_redispute: bool,
+    #[arg(
+        long,
+        help = "Unlock the account when its last chargeback is reversed.",
+        help_heading = "Processing"
+    )]
+    unlock_on_chargeback_reversal: bool,
+    #[arg(
+        long,
+        value_name = "N",
+        help = "Act on clients with this many chargebacks.",
+        help_heading = "Processing"
+    )]
+    chargeback_limit: Option<u32>,
+    #[arg(
+        long,
+        value_name = "lock|flag",
+        help = "What happens to clients over the chargeback limit.",
+        help_heading = "Processing"
+    )]
+    chargeback_limit_action: Option<ChargebackLimitAction>,
+    #[arg(
+        long,
+        value_name = "process|queue|reject",
+        help = "What happens to dispute steps on locked accounts.",
+        help_heading = "Processing"
+    )]
+    locked_disputes: Option<LockedAccountPolicy>,
+    #[arg(
+        long,
+        value_name = "reject|accept",
+        help = "Whether locked accounts take deposits.",
+        help_heading = "Processing"
+    )]
+    locked_deposits: Option<LockedDepositPolicy>,
+    #[arg(
+        long,
+        value_name = "reject|implicit-dispute",
+        help = "What a chargeback on an undisputed transaction does.",
+        help_heading = "Processing"
+    )]
+    undisputed_chargebacks: Option<UndisputedChargebackPolicy>,
+    #[arg(
+        long,
+        value_name = "PATH",
+        help = "A CSV of client,credit_limit for clients with overdrafts.",
+        help_heading = "Processing"
+    )]
+    credit_limits: Option<String>,
+    #[arg(
+        long,
+        value_name = "reject|allow-withdrawals",
+        help = "Whether closed accounts can still be withdrawn from.",
+        help_heading = "Processing"
+    )]
+    closed_accounts: Option<ClosedAccountPolicy>,
+    #[arg(
+        long,
+        value_name = "reject|ignore-identical",
+        help = "What happens to a transaction ID that's used twice.",
+        help_heading = "Processing"
+    )]
+    duplicate_transactions: Option<DuplicateTransactionPolicy>,
+    #[arg(
+        long,
+        value_name = "DAYS",
+        help = "Reject disputes of transactions older than this.",
+        help_heading = "Processing"
+    )]
+    dispute_window: Option<u64>,
+    #[arg(
+        long,
+        value_name = "DAYS",
+        help = "Resolve disputes left open for longer than this.",
+        help_heading = "Processing"
+    )]
+    dispute_expiry: Option<u64>,
+    #[arg(
+        long,
+        value_name = "N",
+        help = "Put up to N events back in timestamp order.",
+        help_heading = "Processing"
+    )]
+    reorder_window: Option<usize>,
+    #[arg(
+        long,
+        help = "Only keep the transactions that can be disputed.",
+        help_heading = "Processing"
+    )]
+    disputable_only: bool,
+    #[arg(
+        long,
+        help = "Check the state is consistent after every event.",
+        help_heading = "Processing"
+    )]
+    check_invariants: bool,
+
+    #[arg(
+        long,
+        value_name = "MiB",
+        help = "Spill transactions to disk past this much memory.",
+        help_heading = "Resources"
+    )]
+    memory_budget: Option<usize>,
+    #[arg(
+        long,
+        help = "Keep transactions in a more compact form.",
+        help_heading = "Resources"
+    )]
+    compact: bool,
+    #[arg(
+        long,
+        value_name = "N|auto",
+        help = "Process clients on N threads, or as many as there are CPUs with auto (the default). From the first transfer between clients on different threads the rest of the input is processed on one thread. Options that need every client in one place, like --journal, can't be used with more than one, and keep the default to one.",
+        help_heading = "Resources"
+    )]
+    threads: Option<ThreadCount>,
+    #[arg(
+        long,
+        value_name = "N",
+        help = "Stop with an error past this many clients.",
+        help_heading = "Resources"
+    )]
+    max_clients: Option<usize>,
+    #[arg(
+        long,
+        value_name = "N",
+        help = "Stop with an error past this many transaction IDs.",
+        help_heading = "Resources"
+    )]
+    max_transactions: Option<usize>,
+    #[arg(
+        long,
+        value_name = "N",
+        help = "Stop with an error past this many rejected events.",
+        help_heading = "Resources"
+    )]
+    max_errors: Option<u64>,
+
+    #[arg(
+        long,
+        value_name = "PATH",
+        help = "Carry on from a state saved by an earlier run.",
+        help_heading = "State"
+    )]
+    resume_from: Option<String>,
+    #[arg(
+        long,
+        value_name = "PATH",
+        help = "Save the state at the end of the run.",
+        help_heading = "State"
+    )]
+    save_state: Option<String>,
+    #[arg(
+        long,
+        value_name = "PATH",
+        help = "Append every accepted event to a journal.",
+        help_heading = "State"
+    )]
+    journal: Option<String>,
+    #[arg(
+        long,
+        value_name = "PATH",
+        help = "Replay a journal before processing the input.",
+        help_heading = "State"
+    )]
+    replay_journal: Option<String>,
+    #[cfg(feature = "sqlite")]
+    #[arg(
+        long,
+        value_name = "PATH",
+        help = "Write the accepted transactions and dispute steps to a SQLite database as they go.",
+        help_heading = "State"
+    )]
+    sqlite_journal: Option<String>,
+    #[cfg(feature = "webhooks")]
+    #[command(flatten)]
+    webhook: webhook::WebhookOptions,
+    #[cfg(feature = "s3")]
+    #[command(flatten)]
+    s3: s3::S3Options,
+    #[arg(
+        long,
+        value_name = "N",
+        requires = "replay_journal",
+        conflicts_with = "as_of_time",
+        help = "Report the state as of event N of the journal, with no input.",
+        help_heading = "State"
+    )]
+    as_of_event: Option<u64>,
+    #[arg(
+        long,
+        value_name = "T",
+        requires = "replay_journal",
+        help = "Report the state as of time T (in seconds since the Unix epoch), with no input.",
+        help_heading = "State"
+    )]
+    as_of_time: Option<Timestamp>,
+}
+
+// How many threads to process clients on: either as many as asked for, or
+// `auto` for as many as the machine says it can run at once, which takes CPU
+// quotas into account where it can.
+#[derive(Clone, Copy)]
+enum ThreadCount {
+    Count(usize),
+    Auto,
+}
+
+impl FromStr for ThreadCount {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(ThreadCount::Auto),
+            count => count
+                .parse()
+                .map(ThreadCount::Count)
+                .map_err(|_| format!("Invalid thread count: {}.", s)),
+        }
+    }
+}
+
+// Turns what clap parsed into what the run needs, checking whatever clap
+// can't.
+fn resolve_args(mut options: RunOptions, quiet: bool) -> Result<Args, Box<dyn Error>> {
+    if options.partitions == Some(0) {
+        return Err("--partitions needs to be at least 1.".into());
+    }
+    if let Some(ThreadCount::Count(0)) = options.threads {
+        return Err("--threads needs to be at least 1.".into());
+    }
+    #[cfg(feature = "postgres")]
+    if !(1..=format::postgres::output::MAX_BATCH_SIZE).contains(&options.postgres_batch_size) {
+        return Err(format!(
+            "--postgres-batch-size needs to be between 1 and {}.",
+            format::postgres::output::MAX_BATCH_SIZE
+        )
+        .into());
+    }
+    if options.compact && options.memory_budget.is_some() {
+        return Err("--compact can't be used with --memory-budget.".into());
+    }
+    // each thread keeps its own state in memory, and none of them has all the
+    // clients to snapshot, so these are only asked for one way: none of them
+    // can be used with `--threads`, and any of them keeps the default to one
+    // thread
+    let mut single_threaded = false;
+    #[cfg(feature = "sqlite")]
+    if options.sqlite_journal.is_some() {
+        if options.threads.is_some() {
+            return Err("--threads can't be used with --sqlite-journal.".into());
+        }
+        single_threaded = true;
+    }
+    #[cfg(feature = "webhooks")]
+    if options.webhook.is_enabled() {
+        if options.threads.is_some() {
+            return Err("--threads can't be used with --webhook-url.".into());
+        }
+        single_threaded = true;
+    }
+    if options.compact
+        || options.memory_budget.is_some()
+        || options.snapshot_every.is_some()
+        || options.resume_from.is_some()
+        || options.save_state.is_some()
+        || options.journal.is_some()
+        || options.replay_journal.is_some()
+    {
+        if options.threads.is_some() {
+            return Err(
+                "--threads can't be used with --compact, --memory-budget, --snapshot-every, \
+                 --resume-from, --save-state, --journal or --replay-journal."
+                    .into(),
+            );
+        }
+        single_threaded = true;
+    }
+    let threads = match options.threads {
+        Some(ThreadCount::Count(count)) => count,
+        None if single_threaded => 1,
+        Some(ThreadCount::Auto) | None => {
+            thread::available_parallelism().map_or(1, NonZeroUsize::get)
+        }
+    };
+
+    let mut report_config = ReportConfig::default();
+    if let Some(format) = options.output_format {
+        report_config.format = format;
+    }
+    if options.pretty {
+        report_config.format = OutputFormat::Table;
+    }
+    if let Some(columns) = &options.columns {
+        report_config.columns = parse_columns(columns)?;
+    }
+    if let Some(order) = options.sort {
+        report_config.order = order;
+    }
+    if let Some(precision) = options.precision {
+        report_config.scale = precision;
+    }
+    if !options.clients.is_empty() {
+        report_config.clients = Some(options.clients.iter().copied().collect());
+    }
+
+    let days = |days: u64| Duration::from_secs(days * 24 * 60 * 60);
+    let defaults = EngineConfig::default();
+    let engine_config = EngineConfig {
+        fee_schedule: FeeSchedule {
+            deposit: options.deposit_fee,
+            withdrawal: options.withdrawal_fee,
+        },
+        withdrawal_disputes: options
+            .withdrawal_disputes
+            .unwrap_or(defaults.withdrawal_disputes),
+        allow_redispute: options.allow_redispute,
+        unlock_on_chargeback_reversal: options.unlock_on_chargeback_reversal,
+        locked_account_disputes: options
+            .locked_disputes
+            .unwrap_or(defaults.locked_account_disputes),
+        locked_account_deposits: options
+            .locked_deposits
+            .unwrap_or(defaults.locked_account_deposits),
+        undisputed_chargebacks: options
+            .undisputed_chargebacks
+            .unwrap_or(defaults.undisputed_chargebacks),
+        credit_limits: match &options.credit_limits {
+            Some(path) => format::csv::input::parse_credit_limits(File::open(path)?)?,
+            None => defaults.credit_limits,
+        },
+        closed_accounts: options.closed_accounts.unwrap_or(defaults.closed_accounts),
+        dispute_window: options.dispute_window.map(days),
+        dispute_expiry: options.dispute_expiry.map(days),
+        reorder_window: options.reorder_window.unwrap_or(defaults.reorder_window),
+        disputable_only: options.disputable_only,
+        duplicate_transactions: options
+            .duplicate_transactions
+            .unwrap_or(defaults.duplicate_transactions),
+        check_invariants: options.check_invariants,
+        limits: ResourceLimits {
+            max_clients: options.max_clients,
+            max_transactions: options.max_transactions,
+            max_errors: options.max_errors,
+        },
+        chargeback_limit: options.chargeback_limit,
+        chargeback_limit_action: options
+            .chargeback_limit_action
+            .unwrap_or(defaults.chargeback_limit_action),
+    };
+
+    // A dry run checks what a run would reject (and whether it would exit
+    // with 2 for it) without leaving anything behind, so that the same
+    // options (or config file) can be used with and without it. Everything
+    // that would be written is dropped here rather than refused.
+    let event_clients = options
+        .client_events_only
+        .then(|| report_config.clients.clone())
+        .flatten();
+
+    if options.dry_run {
+        options.output = None;
+        options.dispute_report = None;
+        options.reconciliation = None;
+        options.counterparty_report = None;
+        options.metrics = None;
+        options.manifest = None;
+        #[cfg(feature = "postgres")]
+        {
+            options.postgres = None;
+        }
+        options.snapshot_every = None;
+        options.save_state = None;
+        options.journal = None;
+        #[cfg(feature = "sqlite")]
+        {
+            options.sqlite_journal = None;
+        }
+        #[cfg(feature = "webhooks")]
+        options.webhook.disable();
+        #[cfg(feature = "s3")]
+        options.s3.disable();
     }
 
-    let path = &args[1];
-    let file = File::open(&path)?;
-    Ok(file)
+    Ok(Args {
+        input_path: options.input_path,
+        input_format: options.input_format,
+        output_path: options.output,
+        report_config,
+        compression: options.compress,
+        dispute_report_path: options.dispute_report,
+        // quiet means nothing on stderr unless it's asked for
+        errors: match (&options.errors, quiet) {
+            (Some(errors), _) => ErrorDestination::parse(errors),
+            (None, true) => ErrorDestination::None,
+            (None, false) => ErrorDestination::Stderr,
+        },
+        error_format: options.error_format,
+        max_rejections: if options.strict {
+            Some(RejectionThreshold::Count(0))
+        } else {
+            options.max_rejections
+        },
+        reconciliation_path: options.reconciliation,
+        counterparty_report_path: options.counterparty_report,
+        metrics_path: options.metrics,
+        manifest_path: options.manifest,
+        #[cfg(feature = "postgres")]
+        postgres: options.postgres.map(|dsn| PostgresOutput {
+            dsn,
+            table: options.postgres_table,
+            batch_size: options.postgres_batch_size,
+        }),
+        partitions: options.partitions,
+        partitioning: options.partition_by,
+        snapshot_interval: options.snapshot_every,
+        snapshot_dir: options.snapshot_dir,
+        memory_budget: options
+            .memory_budget
+            .map(|mebibytes| mebibytes.saturating_mul(1024 * 1024)),
+        compact: options.compact,
+        // one thread is just the usual processing, which can do more
+        threads: (threads > 1).then_some(threads),
+        resume_from_path: options.resume_from,
+        save_state_path: options.save_state,
+        journal_path: options.journal,
+        replay_journal_path: options.replay_journal,
+        replay_until: options
+            .as_of_event
+            .map(ReplayPoint::AfterEvent)
+            .or(options.as_of_time.map(ReplayPoint::AtTime)),
+        #[cfg(feature = "sqlite")]
+        sqlite_journal_path: options.sqlite_journal,
+        #[cfg(feature = "webhooks")]
+        webhook: options.webhook,
+        #[cfg(feature = "s3")]
+        s3: options.s3,
+        event_clients,
+        engine_config,
+        dry_run: options.dry_run,
+        profile: options.profile,
+    })
 }
@@ -0,0 +1,43 @@
+use super::{Amount, ClientID, Currency};
+
+use serde::{Deserialize, Serialize};
+
+// Represents a manual correction to a client's balance, e.g. to fix something
+// the reconciliation turned up. The amount is signed: positive amounts credit
+// the client and negative ones debit them. Adjustments can't be disputed, but
+// they're kept, along with why they were made, so that they can be traced.
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Adjustment {
+    client_id: ClientID,
+    currency: Currency,
+    amount: Amount,
+    reason: String,
+}
+
+impl Adjustment {
+    pub fn new(client_id: ClientID, currency: Currency, amount: Amount, reason: String) -> Self {
+        Self {
+            client_id,
+            currency,
+            amount,
+            reason,
+        }
+    }
+
+    pub fn client_id(&self) -> ClientID {
+        self.client_id
+    }
+
+    pub fn currency(&self) -> Currency {
+        self.currency
+    }
+
+    pub fn amount(&self) -> Amount {
+        self.amount
+    }
+
+    // The reason code the adjustment was made with.
+    pub fn reason(&self) -> &str {
+        &self.reason
+    }
+}
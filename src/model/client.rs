@@ -1,16 +1,107 @@
-use super::Amount;
+use super::{round_amount, Amount, Currency, ProcessingError, TransactionID};
 
 // currently getting a false positive 'unused import' error here
 use rust_decimal_macros::dec;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 
 pub type ClientID = u16;
 
-// Represents the current state of a client account.
-#[derive(Debug, PartialEq, Eq)]
-pub struct Client {
+// What a client holds in one currency.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Balance {
     held: Amount,
     total: Amount,
+    // the total this client has been charged in fees in this currency, which
+    // has already been taken out of `total`
+    fees: Amount,
+    // likewise, the total interest credited in this currency, which has
+    // already been added to `total`
+    interest: Amount,
+    // deposited but not yet settled, which is part of `total` but can't be
+    // spent until it settles
+    pending: Amount,
+}
+
+// Where an account stands as a whole. An account that's been closed reports as
+// closed even if it was also locked, since the `locked` column still says so.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccountStatus {
+    Active,
+    Locked,
+    Closed,
+}
+
+impl AccountStatus {
+    pub fn name(&self) -> &'static str {
+        match self {
+            AccountStatus::Active => "active",
+            AccountStatus::Locked => "locked",
+            AccountStatus::Closed => "closed",
+        }
+    }
+}
+
+// Who a client is, as far as whoever registered them told us, so that reports
+// can say more than their ID. The currency is the one they mostly deal in, e.g.
+// the one their statements are in, which doesn't stop them using others.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ClientProfile {
+    name: String,
+    tier: Option<String>,
+    currency: Currency,
+}
+
+impl ClientProfile {
+    pub fn new(name: String, tier: Option<String>, currency: Currency) -> Self {
+        Self {
+            name,
+            tier,
+            currency,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn tier(&self) -> Option<&str> {
+        self.tier.as_deref()
+    }
+
+    pub fn currency(&self) -> Currency {
+        self.currency
+    }
+}
+
+// What clients who haven't got anything in a currency are reported as having.
+static NO_BALANCE: Balance = Balance::new();
+
+// Represents the current state of a client account. Being locked and the audit
+// counters apply to the account as a whole, while the money is kept per
+// currency.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Client {
+    // ordered so that a client's currencies are always reported in the same
+    // order; most clients only ever have the one
+    balances: BTreeMap<Currency, Balance>,
     locked: bool,
+    // closed at the client's request, as opposed to locked after a chargeback
+    closed: bool,
+    // the number of times any of this client's transactions have been
+    // disputed, including disputes that were later resolved
+    disputed_count: u32,
+    chargeback_count: u32,
+    // whether they've reached the chargeback limit, if there is one
+    flagged: bool,
+    // the ID of the most recent deposit or withdrawal that went through
+    last_transaction_id: Option<TransactionID>,
+    // how far below zero withdrawals may take the total in any one currency,
+    // if at all
+    credit_limit: Option<Amount>,
+    // only set once the client has been registered, which most inputs never
+    // do, so it lives behind a pointer to keep unregistered clients small
+    profile: Option<Box<ClientProfile>>,
 }
 
 impl Default for Client {
@@ -19,75 +110,653 @@ impl Default for Client {
     }
 }
 
+impl Balance {
+    const fn new() -> Self {
+        Self {
+            held: Amount::ZERO,
+            total: Amount::ZERO,
+            fees: Amount::ZERO,
+            interest: Amount::ZERO,
+            pending: Amount::ZERO,
+        }
+    }
+
+    pub fn held(&self) -> Amount {
+        self.held
+    }
+
+    pub fn total(&self) -> Amount {
+        self.total
+    }
+
+    pub fn fees(&self) -> Amount {
+        self.fees
+    }
+
+    pub fn interest(&self) -> Amount {
+        self.interest
+    }
+
+    pub fn pending(&self) -> Amount {
+        self.pending
+    }
+
+    pub fn available(&self) -> Amount {
+        self.total - self.held - self.pending
+    }
+
+    // How far the total has gone below zero, which only an overdraft allows.
+    pub fn overdrawn(&self) -> Amount {
+        (-self.total).max(dec!(0))
+    }
+
+    fn changed(&self, change: BalanceChange) -> Result<Self, ProcessingError> {
+        let changed = Self {
+            held: checked_add(self.held, change.held)?,
+            total: checked_add(self.total, change.total)?,
+            fees: checked_add(self.fees, change.fees)?,
+            interest: checked_add(self.interest, change.interest)?,
+            pending: checked_add(self.pending, change.pending)?,
+        };
+        checked_sub(checked_sub(changed.total, changed.held)?, changed.pending)?;
+        Ok(changed)
+    }
+}
+
 impl Client {
     pub fn new() -> Self {
         Self {
-            held: dec!(0),
-            total: dec!(0),
+            balances: BTreeMap::new(),
             locked: false,
+            closed: false,
+            disputed_count: 0,
+            chargeback_count: 0,
+            flagged: false,
+            last_transaction_id: None,
+            credit_limit: None,
+            profile: None,
+        }
+    }
+
+    pub fn with_credit_limit(self, credit_limit: Option<Amount>) -> Self {
+        Self {
+            credit_limit,
+            ..self
         }
     }
 
+    // Builds a client with the given balance in the unnamed currency.
     #[cfg(test)]
     pub fn create(held: Amount, total: Amount, locked: bool) -> Self {
         Self {
-            held,
-            total,
             locked,
+            ..Self::new()
         }
+        .with_balance(Currency::default(), held, total)
     }
 
-    pub fn held(&self) -> Amount {
-        self.held
+    #[cfg(test)]
+    pub fn with_balance(mut self, currency: Currency, held: Amount, total: Amount) -> Self {
+        self.balances.insert(
+            currency,
+            Balance {
+                held,
+                total,
+                fees: dec!(0),
+                interest: dec!(0),
+                pending: dec!(0),
+            },
+        );
+        self
     }
 
-    pub fn total(&self) -> Amount {
-        self.total
+    #[cfg(test)]
+    pub fn with_closed(self, closed: bool) -> Self {
+        Self { closed, ..self }
+    }
+
+    #[cfg(test)]
+    pub fn with_disputed_count(self, disputed_count: u32) -> Self {
+        Self {
+            disputed_count,
+            ..self
+        }
+    }
+
+    #[cfg(test)]
+    pub fn with_chargeback_count(self, chargeback_count: u32) -> Self {
+        Self {
+            chargeback_count,
+            ..self
+        }
+    }
+
+    #[cfg(test)]
+    pub fn with_flagged(self, flagged: bool) -> Self {
+        Self { flagged, ..self }
+    }
+
+    // Sets the fees paid in the unnamed currency.
+    #[cfg(test)]
+    pub fn with_fees(mut self, fees: Amount) -> Self {
+        self.balance_mut(Currency::default()).fees = fees;
+        self
+    }
+
+    // Sets the interest paid in the unnamed currency.
+    #[cfg(test)]
+    pub fn with_interest(mut self, interest: Amount) -> Self {
+        self.balance_mut(Currency::default()).interest = interest;
+        self
+    }
+
+    // Sets what's pending in the unnamed currency, which is added to the total.
+    #[cfg(test)]
+    pub fn with_pending(mut self, pending: Amount) -> Self {
+        let balance = self.balance_mut(Currency::default());
+        balance.pending = pending;
+        balance.total += pending;
+        self
+    }
+
+    #[cfg(test)]
+    pub fn with_profile(mut self, profile: ClientProfile) -> Self {
+        self.register(profile);
+        self
+    }
+
+    #[cfg(test)]
+    pub fn with_last_transaction_id(self, last_transaction_id: TransactionID) -> Self {
+        Self {
+            last_transaction_id: Some(last_transaction_id),
+            ..self
+        }
+    }
+
+    // The client's balance in the given currency, which is all zeros if
+    // they've never had anything in it.
+    pub fn balance(&self, currency: Currency) -> &Balance {
+        self.balances.get(&currency).unwrap_or(&NO_BALANCE)
+    }
+
+    // Every currency the client has a balance in. A client who has never had
+    // a balance at all still has one row in the report, so they get an empty
+    // one in the unnamed currency.
+    pub fn balances(&self) -> impl Iterator<Item = (Currency, &Balance)> {
+        let none = self
+            .balances
+            .is_empty()
+            .then_some((Currency::default(), &NO_BALANCE));
+
+        self.balances
+            .iter()
+            .map(|(currency, balance)| (*currency, balance))
+            .chain(none)
     }
 
     pub fn locked(&self) -> bool {
         self.locked
     }
 
-    pub fn available(&self) -> Amount {
-        self.total - self.held
+    pub fn closed(&self) -> bool {
+        self.closed
     }
 
-    pub fn deposit(&mut self, amount: Amount) -> Result<(), String> {
-        if self.locked {
-            return Err(String::from("Cannot deposit when account is locked."));
+    pub fn status(&self) -> AccountStatus {
+        if self.closed {
+            AccountStatus::Closed
+        } else if self.locked {
+            AccountStatus::Locked
+        } else {
+            AccountStatus::Active
+        }
+    }
+
+    pub fn unlock(&mut self) {
+        self.locked = false;
+    }
+
+    pub fn close(&mut self) -> Result<(), ProcessingError> {
+        if self.closed {
+            return Err(ProcessingError::AccountAlreadyClosed);
+        }
+
+        self.closed = true;
+        Ok(())
+    }
+
+    pub fn disputed_count(&self) -> u32 {
+        self.disputed_count
+    }
+
+    pub fn chargeback_count(&self) -> u32 {
+        self.chargeback_count
+    }
+
+    pub fn flagged(&self) -> bool {
+        self.flagged
+    }
+
+    pub fn flag(&mut self) {
+        self.flagged = true;
+    }
+
+    pub fn last_transaction_id(&self) -> Option<TransactionID> {
+        self.last_transaction_id
+    }
+
+    pub fn profile(&self) -> Option<&ClientProfile> {
+        self.profile.as_deref()
+    }
+
+    // Registering again replaces the whole profile, so a tier that's no longer
+    // given is taken away rather than kept from before.
+    pub fn register(&mut self, profile: ClientProfile) {
+        self.profile = Some(Box::new(profile));
+    }
+
+    fn balance_mut(&mut self, currency: Currency) -> &mut Balance {
+        self.balances.entry(currency).or_insert_with(Balance::new)
+    }
+
+    // Every change to a balance goes through here, so that an amount too big
+    // for a `Decimal` is rejected rather than panicking (which would take the
+    // whole run down with it). Nothing is changed unless all of it fits,
+    // including the available funds, which are worked out when they're needed.
+    fn change_balance(
+        &mut self,
+        currency: Currency,
+        change: BalanceChange,
+    ) -> Result<(), ProcessingError> {
+        let balance = self.balance_mut(currency);
+        *balance = balance.changed(change)?;
+        Ok(())
+    }
+
+    // Deposits, incoming transfers and interest can only be paid into a locked
+    // account if `allow_locked` says so, since whether that's allowed depends
+    // on the payment scheme.
+    pub fn check_can_deposit(
+        &self,
+        currency: Currency,
+        amount: Amount,
+        allow_locked: bool,
+    ) -> Result<(), ProcessingError> {
+        if self.locked && !allow_locked {
+            return Err(ProcessingError::AccountLocked { action: "deposit" });
         }
 
-        self.total += amount;
+        self.check_can_adjust(currency, amount)
+    }
+
+    // Whether `adjust` would go through, for when something else has to
+    // happen first that can't be undone.
+    pub fn check_can_adjust(
+        &self,
+        currency: Currency,
+        amount: Amount,
+    ) -> Result<(), ProcessingError> {
+        self.balance(currency).changed(BalanceChange {
+            total: amount,
+            ..BalanceChange::default()
+        })?;
         Ok(())
     }
 
-    pub fn withdraw(&mut self, amount: Amount) -> Result<(), String> {
+    pub fn deposit(
+        &mut self,
+        currency: Currency,
+        amount: Amount,
+        allow_locked: bool,
+    ) -> Result<(), ProcessingError> {
+        self.deposit_with_fee(currency, amount, dec!(0), allow_locked)
+    }
+
+    pub fn withdraw(&mut self, currency: Currency, amount: Amount) -> Result<(), ProcessingError> {
+        self.withdraw_with_fee(currency, amount, dec!(0))
+    }
+
+    // The fee has to be covered by the available funds along with the amount
+    // itself; we never withdraw without also charging the fee.
+    pub fn withdraw_with_fee(
+        &mut self,
+        currency: Currency,
+        amount: Amount,
+        fee: Amount,
+    ) -> Result<(), ProcessingError> {
         if self.locked {
-            return Err(String::from("Cannot withdraw when account is locked."));
+            return Err(ProcessingError::AccountLocked { action: "withdraw" });
         }
 
-        if self.available() < amount {
-            Err(String::from("Insufficient funds."))
-        } else {
-            self.total -= amount;
-            Ok(())
+        let overdraft = self.credit_limit.unwrap_or(dec!(0));
+        let needed = checked_add(amount, fee)?;
+        // if the funds are too big to add up, they're certainly enough
+        let insufficient = self
+            .balance_mut(currency)
+            .available()
+            .checked_add(overdraft)
+            .is_some_and(|funds| funds < needed);
+        if insufficient {
+            return Err(ProcessingError::InsufficientFunds);
         }
+
+        self.change_balance(
+            currency,
+            BalanceChange {
+                total: -needed,
+                fees: fee,
+                ..BalanceChange::default()
+            },
+        )
     }
 
-    pub fn hold(&mut self, amount: Amount) {
-        self.held += amount;
+    // A fee is charged like a withdrawal, so it needs the funds to cover it and
+    // an unlocked account.
+    pub fn charge_fee(&mut self, currency: Currency, fee: Amount) -> Result<(), ProcessingError> {
+        self.withdraw_with_fee(currency, dec!(0), fee)
     }
 
-    pub fn chargeback_withdrawal(&mut self, amount: Amount) {
-        self.held -= amount;
-        self.total += amount;
-        self.locked = true;
+    // Deposit fees come straight out of the deposit, so unlike `charge_fee`
+    // there's nothing to check.
+    pub fn deposit_with_fee(
+        &mut self,
+        currency: Currency,
+        amount: Amount,
+        fee: Amount,
+        allow_locked: bool,
+    ) -> Result<(), ProcessingError> {
+        let deposited = checked_sub(amount, fee)?;
+        self.check_can_deposit(currency, deposited, allow_locked)?;
+
+        self.change_balance(
+            currency,
+            BalanceChange {
+                total: deposited,
+                fees: fee,
+                ..BalanceChange::default()
+            },
+        )
+    }
+
+    // A deposit that hasn't settled yet goes into the total straight away, but
+    // it's pending until `settle` so it can't be spent. The fee comes out of
+    // it like any other deposit.
+    pub fn deposit_pending(
+        &mut self,
+        currency: Currency,
+        amount: Amount,
+        fee: Amount,
+        allow_locked: bool,
+    ) -> Result<(), ProcessingError> {
+        let deposited = checked_sub(amount, fee)?;
+        self.check_can_deposit(currency, deposited, allow_locked)?;
+
+        self.change_balance(
+            currency,
+            BalanceChange {
+                total: deposited,
+                fees: fee,
+                pending: deposited,
+                ..BalanceChange::default()
+            },
+        )
+    }
+
+    // Makes what was pending available. This isn't at the client's request,
+    // so it goes through whether or not the account is locked.
+    pub fn settle(&mut self, currency: Currency, amount: Amount) -> Result<(), ProcessingError> {
+        self.change_balance(
+            currency,
+            BalanceChange {
+                pending: -amount,
+                ..BalanceChange::default()
+            },
+        )
+    }
+
+    // Undoes a deposit that never settled, e.g. one that was returned.
+    pub fn reverse_pending_deposit(
+        &mut self,
+        currency: Currency,
+        amount: Amount,
+        pending: Amount,
+    ) -> Result<(), ProcessingError> {
+        self.change_balance(
+            currency,
+            BalanceChange {
+                total: -amount,
+                pending: -pending,
+                ..BalanceChange::default()
+            },
+        )
     }
 
-    pub fn chargeback_deposit(&mut self, amount: Amount) {
-        self.held -= amount;
-        self.total -= amount;
+    // Exchanges `amount` in one currency for `converted_amount` in another.
+    // The amount has to be covered like a withdrawal.
+    pub fn convert(
+        &mut self,
+        from_currency: Currency,
+        to_currency: Currency,
+        amount: Amount,
+        converted_amount: Amount,
+    ) -> Result<(), ProcessingError> {
+        if self.locked {
+            return Err(ProcessingError::AccountLocked { action: "convert" });
+        }
+
+        // checked first so that we never do only half of it
+        self.check_can_adjust(to_currency, converted_amount)?;
+        self.withdraw(from_currency, amount)?;
+        self.adjust(to_currency, converted_amount)
+    }
+
+    // Interest is paid on the available funds only, so nothing is paid on held
+    // funds or on an overdraft. Like a deposit, it can't be paid into a locked
+    // account unless `allow_locked` says so.
+    pub fn credit_interest(
+        &mut self,
+        currency: Currency,
+        rate: Amount,
+        allow_locked: bool,
+    ) -> Result<(), ProcessingError> {
+        let interest = self
+            .balance(currency)
+            .available()
+            .max(dec!(0))
+            .checked_mul(rate)
+            .map(round_amount)
+            .ok_or(ProcessingError::AmountOverflow)?;
+        self.check_can_deposit(currency, interest, allow_locked)?;
+
+        self.change_balance(
+            currency,
+            BalanceChange {
+                total: interest,
+                interest,
+                ..BalanceChange::default()
+            },
+        )
+    }
+
+    pub fn hold(&mut self, currency: Currency, amount: Amount) -> Result<(), ProcessingError> {
+        self.change_balance(
+            currency,
+            BalanceChange {
+                held: amount,
+                ..BalanceChange::default()
+            },
+        )
+    }
+
+    pub fn record_dispute(&mut self) {
+        self.disputed_count += 1;
+    }
+
+    pub fn record_transaction(&mut self, transaction_id: TransactionID) {
+        self.last_transaction_id = Some(transaction_id);
+    }
+
+    // Puts money back into the account but holds it, so the available funds
+    // don't change. A negative amount takes it back out again.
+    pub fn credit_held(
+        &mut self,
+        currency: Currency,
+        amount: Amount,
+    ) -> Result<(), ProcessingError> {
+        self.change_balance(
+            currency,
+            BalanceChange {
+                total: amount,
+                held: amount,
+                ..BalanceChange::default()
+            },
+        )
+    }
+
+    pub fn chargeback_withdrawal(
+        &mut self,
+        currency: Currency,
+        amount: Amount,
+    ) -> Result<(), ProcessingError> {
+        self.change_balance(
+            currency,
+            BalanceChange {
+                total: amount,
+                held: -amount,
+                ..BalanceChange::default()
+            },
+        )?;
+        self.lock_for_chargeback();
+        Ok(())
+    }
+
+    pub fn chargeback_deposit(
+        &mut self,
+        currency: Currency,
+        amount: Amount,
+    ) -> Result<(), ProcessingError> {
+        self.change_balance(
+            currency,
+            BalanceChange {
+                total: -amount,
+                held: -amount,
+                ..BalanceChange::default()
+            },
+        )?;
+        self.lock_for_chargeback();
+        Ok(())
+    }
+
+    // Adjustments are corrections, so they go through whatever the client's
+    // balance and whether or not the account is locked.
+    pub fn adjust(&mut self, currency: Currency, amount: Amount) -> Result<(), ProcessingError> {
+        self.change_balance(
+            currency,
+            BalanceChange {
+                total: amount,
+                ..BalanceChange::default()
+            },
+        )
+    }
+
+    // Undoes a deposit, e.g. one made in error. Unlike a chargeback this
+    // doesn't lock the account, but like one it goes through even if the
+    // client has since spent the money, so that a mistake can always be
+    // corrected.
+    pub fn reverse_deposit(
+        &mut self,
+        currency: Currency,
+        amount: Amount,
+    ) -> Result<(), ProcessingError> {
+        self.adjust(currency, -amount)
+    }
+
+    pub fn reverse_withdrawal(
+        &mut self,
+        currency: Currency,
+        amount: Amount,
+    ) -> Result<(), ProcessingError> {
+        self.adjust(currency, amount)
+    }
+
+    // Undoes a deposit's chargeback, giving the client the money back. Unlike
+    // a re-dispute nothing is held, since it's over.
+    pub fn reverse_deposit_chargeback(
+        &mut self,
+        currency: Currency,
+        amount: Amount,
+    ) -> Result<(), ProcessingError> {
+        self.adjust(currency, amount)
+    }
+
+    pub fn reverse_withdrawal_chargeback(
+        &mut self,
+        currency: Currency,
+        amount: Amount,
+    ) -> Result<(), ProcessingError> {
+        self.adjust(currency, -amount)
+    }
+
+    // Puts a charged back deposit back under dispute, by giving the client
+    // the money again but holding it. The account stays locked.
+    pub fn redispute_deposit(
+        &mut self,
+        currency: Currency,
+        amount: Amount,
+    ) -> Result<(), ProcessingError> {
+        self.credit_held(currency, amount)
+    }
+
+    // Puts a charged back withdrawal back under dispute, by taking back what
+    // the chargeback gave the client and holding it again.
+    pub fn redispute_withdrawal(
+        &mut self,
+        currency: Currency,
+        amount: Amount,
+    ) -> Result<(), ProcessingError> {
+        self.change_balance(
+            currency,
+            BalanceChange {
+                total: -amount,
+                held: amount,
+                ..BalanceChange::default()
+            },
+        )
+    }
+
+    // For a chargeback whose money is already in the account's total, so all
+    // that's left is to stop holding it.
+    pub fn chargeback_held(
+        &mut self,
+        currency: Currency,
+        amount: Amount,
+    ) -> Result<(), ProcessingError> {
+        self.hold(currency, -amount)?;
+        self.lock_for_chargeback();
+        Ok(())
+    }
+
+    fn lock_for_chargeback(&mut self) {
         self.locked = true;
+        self.chargeback_count += 1;
     }
 }
+
+// How much each part of a balance changes by. Anything left out doesn't
+// change.
+#[derive(Default)]
+struct BalanceChange {
+    total: Amount,
+    held: Amount,
+    fees: Amount,
+    interest: Amount,
+    pending: Amount,
+}
+
+fn checked_add(a: Amount, b: Amount) -> Result<Amount, ProcessingError> {
+    a.checked_add(b).ok_or(ProcessingError::AmountOverflow)
+}
+
+fn checked_sub(a: Amount, b: Amount) -> Result<Amount, ProcessingError> {
+    a.checked_sub(b).ok_or(ProcessingError::AmountOverflow)
+}
@@ -0,0 +1,85 @@
+use super::{round_amount, Amount, ClientID, Currency, ProcessingError};
+
+use serde::{Deserialize, Serialize};
+
+// Represents an exchange of money from one of a client's currencies into
+// another at a supplied rate. Conversions can't be disputed, but unlike fees
+// they're kept so that the reconciliation can account for money moving
+// between currencies.
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Conversion {
+    client_id: ClientID,
+    from_currency: Currency,
+    to_currency: Currency,
+    amount: Amount,
+    converted_amount: Amount,
+}
+
+impl Conversion {
+    pub fn new(
+        client_id: ClientID,
+        from_currency: Currency,
+        to_currency: Currency,
+        amount: Amount,
+        rate: Amount,
+    ) -> Result<Self, ProcessingError> {
+        let converted_amount = amount
+            .checked_mul(rate)
+            .ok_or(ProcessingError::AmountOverflow)?;
+
+        Ok(Self {
+            client_id,
+            from_currency,
+            to_currency,
+            amount,
+            converted_amount: round_amount(converted_amount),
+        })
+    }
+
+    pub fn client_id(&self) -> ClientID {
+        self.client_id
+    }
+
+    pub fn from_currency(&self) -> Currency {
+        self.from_currency
+    }
+
+    pub fn to_currency(&self) -> Currency {
+        self.to_currency
+    }
+
+    // The amount taken out of `from_currency`.
+    pub fn amount(&self) -> Amount {
+        self.amount
+    }
+
+    // The amount put into `to_currency`.
+    pub fn converted_amount(&self) -> Amount {
+        self.converted_amount
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use rust_decimal_macros::dec;
+
+    #[test]
+    fn test_converted_amount() {
+        let euros = "EUR".parse().expect("Expected a valid currency.");
+        let dollars = "USD".parse().expect("Expected a valid currency.");
+
+        assert_eq!(
+            Ok(dec!(10.85)),
+            Conversion::new(1, euros, dollars, dec!(10), dec!(1.085))
+                .map(|conversion| conversion.converted_amount())
+        );
+        // 0.00005 is half of the smallest unit, which rounds up
+        assert_eq!(
+            Ok(dec!(0.0001)),
+            Conversion::new(1, euros, dollars, dec!(0.0001), dec!(0.5))
+                .map(|conversion| conversion.converted_amount())
+        );
+    }
+}
@@ -0,0 +1,92 @@
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::{fmt, str::FromStr};
+
+// An ISO 4217-style currency code, e.g. `EUR`. Inputs that don't say which
+// currency they're in use the unnamed currency, which is written as an empty
+// string, so single-currency inputs work as they always have.
+//
+// Stored inline rather than as a string so that it's cheap to copy and hash on
+// every event.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Currency([u8; 3]);
+
+impl Currency {
+    // The raw code, for storing it as bytes. The unnamed currency is all zeros.
+    pub fn to_bytes(self) -> [u8; 3] {
+        self.0
+    }
+
+    // The inverse of `to_bytes`, so the bytes are trusted to have come from it.
+    pub fn from_bytes(bytes: [u8; 3]) -> Self {
+        Currency(bytes)
+    }
+
+    fn as_str(&self) -> &str {
+        if *self == Currency::default() {
+            return "";
+        }
+
+        // only ever built from ASCII letters
+        std::str::from_utf8(&self.0).expect("Currency codes are ASCII")
+    }
+}
+
+impl FromStr for Currency {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.is_empty() {
+            return Ok(Currency::default());
+        }
+
+        match s.as_bytes() {
+            [a, b, c] if s.bytes().all(|byte| byte.is_ascii_alphabetic()) => Ok(Currency([
+                a.to_ascii_uppercase(),
+                b.to_ascii_uppercase(),
+                c.to_ascii_uppercase(),
+            ])),
+            _ => Err(format!("Invalid currency: {}.", s)),
+        }
+    }
+}
+
+impl fmt::Display for Currency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl Serialize for Currency {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for Currency {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_from_str() {
+        assert_eq!(Ok(Currency::default()), "".parse());
+        assert_eq!("EUR", "eur".parse::<Currency>().expect("Valid").to_string());
+        assert_eq!("", Currency::default().to_string());
+        assert_eq!(
+            Err(String::from("Invalid currency: EURO.")),
+            "EURO".parse::<Currency>()
+        );
+        assert_eq!(
+            Err(String::from("Invalid currency: E1R.")),
+            "E1R".parse::<Currency>()
+        );
+    }
+}
@@ -0,0 +1,77 @@
+use super::{Amount, ClientID, TransactionID};
+
+use thiserror::Error;
+
+// Why an event couldn't be processed. These are expected in the normal course
+// of things (a client trying to withdraw more than they have, say), so they're
+// cheap to make and only turned into a message if someone logs them.
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum ProcessingError {
+    #[error("Insufficient funds.")]
+    InsufficientFunds,
+    // Too big for a `Decimal`, which only an input trying its luck should get
+    // anywhere near.
+    #[error("Amount is too large to process.")]
+    AmountOverflow,
+    // `action` is what was attempted, e.g. "withdraw" or "dispute"
+    #[error("Cannot {action} when account is locked.")]
+    AccountLocked { action: &'static str },
+    #[error("Cannot process {kind} when account is closed.")]
+    AccountClosed { kind: &'static str },
+    #[error("Account is already closed.")]
+    AccountAlreadyClosed,
+    #[error("Client {client_id} does not exist.")]
+    ClientNotFound { client_id: ClientID },
+    #[error("Client id {client_id} does not match transaction client id {transaction_client_id}.")]
+    ClientMismatch {
+        client_id: ClientID,
+        transaction_client_id: ClientID,
+    },
+    #[error("Transaction already exists with id {id}.")]
+    TransactionExists { id: TransactionID },
+    #[error("Transaction {id} not found.")]
+    TransactionNotFound { id: TransactionID },
+    #[error("Conversion {id} cannot be disputed or reversed.")]
+    ConversionNotDisputable { id: TransactionID },
+    #[error("Adjustment {id} cannot be disputed or reversed.")]
+    AdjustmentNotDisputable { id: TransactionID },
+    #[error("Cannot transfer from client {client_id} to itself.")]
+    SelfTransfer { client_id: ClientID },
+    #[error("Cannot convert a currency into itself.")]
+    SameCurrencyConversion,
+    #[error("Only deposits can be disputed.")]
+    WithdrawalDisputesRejected,
+    #[error("Withdrawal {id} was not kept, so it cannot be disputed or reversed.")]
+    WithdrawalNotKept { id: TransactionID },
+    #[error("Deposit {id} has not settled yet, so it cannot be disputed.")]
+    DepositPending { id: TransactionID },
+    #[error("Transaction {id} is not a pending deposit.")]
+    NotPending { id: TransactionID },
+    #[error("Transaction is not disputed.")]
+    NotDisputed,
+    #[error("Transaction is already disputed.")]
+    AlreadyDisputed,
+    #[error("Transaction has already been charged back.")]
+    AlreadyChargedBack,
+    #[error("Transaction has already been reversed.")]
+    AlreadyReversed,
+    #[error("Transaction has not just been charged back.")]
+    NotChargedBack,
+    #[error("Cannot reverse a transaction while it's disputed.")]
+    ReversalWhileDisputed,
+    #[error("Cannot reverse a transaction that has been partially charged back.")]
+    ReversalAfterPartialChargeback,
+    #[error("Cannot dispute {amount} of the {disputable} left to dispute.")]
+    InvalidDisputeAmount { amount: Amount, disputable: Amount },
+    #[error("A re-dispute has to be for the amount that was charged back.")]
+    RedisputeAmountMismatch,
+    #[error("Transaction {id} is too old to be disputed.")]
+    DisputeWindowExpired { id: TransactionID },
+    // `kind` is the kind of dispute step that was queued, e.g. "dispute"
+    #[error("Client {client_id} was still locked at the end of the run, so the {kind} of transaction {id} was never processed.")]
+    StillLocked {
+        client_id: ClientID,
+        kind: &'static str,
+        id: TransactionID,
+    },
+}
@@ -1,25 +1,213 @@
-use super::{Amount, ClientID, TransactionID, TransactionKind};
+use super::{Amount, ClientID, Currency, Timestamp, TransactionID, TransactionKind};
+
+use serde::{Deserialize, Serialize};
+use std::fmt;
 
 // Represents events in our system. These do not represent successfully
 // processed events, but rather the events that need to be processed.
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum Event {
     Transaction {
         kind: TransactionKind,
         transaction_id: TransactionID,
         client_id: ClientID,
+        currency: Currency,
         amount: Amount,
+        // Who was on the other side of it (e.g. the merchant), if the input
+        // said, so that disputes can be traced back to them.
+        counterparty: Option<String>,
     },
     DisputeStep {
         kind: DisputeStepKind,
         transaction_id: TransactionID,
         client_id: ClientID,
+        // How much of the transaction is disputed, if not all of what's left
+        // of it. Only disputes have one: resolves and chargebacks act on
+        // whatever was disputed.
+        amount: Option<Amount>,
+    },
+    // Moves money from one client to another in one go, rather than as a
+    // withdrawal and a deposit that could succeed or fail independently.
+    Transfer {
+        transaction_id: TransactionID,
+        from_client_id: ClientID,
+        to_client_id: ClientID,
+        currency: Currency,
+        amount: Amount,
+    },
+    // Charges the client a fee, e.g. a monthly account fee. Fees can't be
+    // disputed, so unlike deposits and withdrawals they aren't stored as
+    // transactions.
+    Fee {
+        transaction_id: TransactionID,
+        client_id: ClientID,
+        currency: Currency,
+        amount: Amount,
+    },
+    // Exchanges `amount` of one of the client's currencies for another at the
+    // given rate, in one go rather than as a withdrawal and a deposit.
+    Conversion {
+        transaction_id: TransactionID,
+        client_id: ClientID,
+        from_currency: Currency,
+        to_currency: Currency,
+        amount: Amount,
+        rate: Amount,
+    },
+    // A deposit that takes time to settle, e.g. a bank transfer. It counts
+    // towards the client's total straight away, but can't be spent (or
+    // disputed) until a `Settlement` for it comes in.
+    PendingDeposit {
+        transaction_id: TransactionID,
+        client_id: ClientID,
+        currency: Currency,
+        amount: Amount,
+        counterparty: Option<String>,
+    },
+    // Settles a pending deposit, making it available.
+    Settlement {
+        transaction_id: TransactionID,
+        client_id: ClientID,
+    },
+    // Undoes an earlier deposit, withdrawal or transfer, e.g. one an operator
+    // has found was made in error. This isn't a dispute, so it doesn't count
+    // against the client.
+    Reversal {
+        transaction_id: TransactionID,
+        client_id: ClientID,
+    },
+    // Undoes the most recent chargeback on a transaction, e.g. once the
+    // merchant has won the case. It's not a dispute step, so it goes through on
+    // locked accounts.
+    ChargebackReversal {
+        transaction_id: TransactionID,
+        client_id: ClientID,
+    },
+    // Credits or debits the client outside of the usual deposits and
+    // withdrawals, e.g. to correct something the reconciliation turned up. The
+    // amount is signed, and a debit can take the client below zero.
+    Adjustment {
+        transaction_id: TransactionID,
+        client_id: ClientID,
+        currency: Currency,
+        amount: Amount,
+        reason: String,
+    },
+    // Closes the client's account at their request, which is different from it
+    // being locked after a chargeback.
+    AccountClosure {
+        client_id: ClientID,
+    },
+    // Registers who the client is, or updates it if they've been registered
+    // before. It doesn't move any money, but it does create the client if
+    // there's nothing else about them yet.
+    ClientRegistration {
+        client_id: ClientID,
+        name: String,
+        tier: Option<String>,
+        currency: Currency,
+    },
+    // Credits the client with interest on their available funds in the given
+    // currency, at the given rate for whatever period it covers (e.g. a
+    // nightly rate). Like fees, it can't be disputed.
+    Interest {
+        transaction_id: TransactionID,
+        client_id: ClientID,
+        currency: Currency,
+        rate: Amount,
     },
 }
 
-#[derive(Debug, PartialEq, Eq)]
+impl Event {
+    // A short name for what kind of event this is, e.g. for metrics.
+    pub fn kind_name(&self) -> &'static str {
+        match self {
+            Event::Transaction { kind, .. } => kind.name(),
+            Event::DisputeStep { kind, .. } => match kind {
+                DisputeStepKind::Dispute => "dispute",
+                DisputeStepKind::Resolve => "resolve",
+                DisputeStepKind::Chargeback => "chargeback",
+            },
+            Event::PendingDeposit { .. } => "deposit_pending",
+            Event::Settlement { .. } => "settle",
+            Event::Transfer { .. } => "transfer",
+            Event::Fee { .. } => "fee",
+            Event::Conversion { .. } => "convert",
+            Event::Interest { .. } => "interest",
+            Event::Reversal { .. } => "reversal",
+            Event::ChargebackReversal { .. } => "chargeback_reversal",
+            Event::Adjustment { .. } => "adjustment",
+            Event::AccountClosure { .. } => "close_account",
+            Event::ClientRegistration { .. } => "client",
+        }
+    }
+
+    // The clients the event names, which is two for a transfer and one for
+    // everything else. A dispute step or reversal names the client it's
+    // about, even if the transaction turns out to be someone else's.
+    pub fn client_ids(&self) -> [Option<ClientID>; 2] {
+        match *self {
+            Event::Transfer {
+                from_client_id,
+                to_client_id,
+                ..
+            } => [Some(from_client_id), Some(to_client_id)],
+            Event::Transaction { client_id, .. }
+            | Event::DisputeStep { client_id, .. }
+            | Event::Fee { client_id, .. }
+            | Event::Conversion { client_id, .. }
+            | Event::PendingDeposit { client_id, .. }
+            | Event::Settlement { client_id, .. }
+            | Event::Reversal { client_id, .. }
+            | Event::ChargebackReversal { client_id, .. }
+            | Event::Adjustment { client_id, .. }
+            | Event::AccountClosure { client_id }
+            | Event::ClientRegistration { client_id, .. }
+            | Event::Interest { client_id, .. } => [Some(client_id), None],
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum DisputeStepKind {
     Dispute,
     Resolve,
     Chargeback,
 }
+
+// Where in the input an event was read from, so that rejections can point
+// back at it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Source {
+    pub line: u64,
+    // The offset of the start of the record from the start of the input, which
+    // is quicker to seek to than a line in a very large file.
+    pub byte: u64,
+    // The record as it was read. Keeping this costs an allocation per event so
+    // it's only populated when asked for.
+    pub record: Option<String>,
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {} (byte {})", self.line, self.byte)
+    }
+}
+
+// An event along with where it came from and when it happened, if known.
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SourcedEvent {
+    pub event: Event,
+    pub source: Option<Source>,
+    pub timestamp: Option<Timestamp>,
+}
+
+impl From<Event> for SourcedEvent {
+    fn from(event: Event) -> Self {
+        Self {
+            event,
+            source: None,
+            timestamp: None,
+        }
+    }
+}
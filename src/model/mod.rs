@@ -1,11 +1,19 @@
+pub mod adjustment;
 pub mod client;
+pub mod conversion;
+pub mod currency;
+pub mod error;
 pub mod event;
 pub mod transaction;
+pub use adjustment::*;
 pub use client::*;
+pub use conversion::*;
+pub use currency::*;
+pub use error::*;
 pub use event::*;
 pub use transaction::*;
 
-use rust_decimal::prelude::Decimal;
+use rust_decimal::{prelude::Decimal, RoundingStrategy};
 
 // A quick overview of the modelling here: we have a sequence of Events we need
 // to process. Some events (deposits and withdrawals) create transactions, and
@@ -14,3 +22,19 @@ use rust_decimal::prelude::Decimal;
 // Client.
 
 pub type Amount = Decimal;
+
+// Seconds since the Unix epoch. Inputs don't have to say when each event
+// happened, so this is always optional.
+pub type Timestamp = u64;
+
+// Amounts we work out ourselves (fees, conversions and interest) are rounded to
+// the same number of decimal places as the amounts in the input, so nobody ends
+// up with a fraction of the smallest unit we deal in.
+const AMOUNT_DECIMAL_PLACES: u32 = 4;
+
+pub fn round_amount(amount: Amount) -> Amount {
+    amount.round_dp_with_strategy(
+        AMOUNT_DECIMAL_PLACES,
+        RoundingStrategy::MidpointAwayFromZero,
+    )
+}
@@ -1,45 +1,281 @@
-use super::{Amount, ClientID};
+use super::{Amount, ClientID, Currency, ProcessingError, Timestamp, AMOUNT_DECIMAL_PLACES};
+
+use rust_decimal::prelude::ToPrimitive;
+use serde::{Deserialize, Serialize};
 
 pub type TransactionID = u32;
 
 // Represents a transfer of money (either deposit or withdrawal). This does
 // _not_ represent disputes/resolutions: those are represented by events and act
 // on transactions.
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Transaction {
     client_id: ClientID,
+    currency: Currency,
     amount: Amount,
     kind: TransactionKind,
     dispute_status: DisputeStatus,
+    // how much the current dispute (or the last one, once it's been charged
+    // back) is for, which can be less than the whole amount
+    disputed_amount: Amount,
+    // how much has been charged back so far, across every dispute
+    charged_back_amount: Amount,
+    // when the event that created it happened, if the input said
+    timestamp: Option<Timestamp>,
+    // boxed since most transactions are never disputed, and every one of them
+    // would otherwise pay for it
+    history: Option<Box<DisputeHistory>>,
+}
+
+// Which events (numbered as the processor saw them, from 1) a transaction was
+// most recently disputed, resolved and charged back by, which its status alone
+// doesn't say.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DisputeHistory {
+    pub disputed_at: Option<u64>,
+    pub resolved_at: Option<u64>,
+    pub charged_back_at: Option<u64>,
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum TransactionKind {
     Deposit,
     Withdrawal,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum DisputeStatus {
     Undisputed, // if a dispute is resolves, we go back to this state
     Disputed,
+    // part of it has been charged back, but the rest can still be disputed
+    PartiallyChargedBack,
     ChargedBack,
+    // undone by an operator, e.g. because it was made in error, which isn't a
+    // dispute but is final in the same way a chargeback is
+    Reversed,
 }
 
 use DisputeStatus::*;
 
+impl TransactionKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            TransactionKind::Deposit => "deposit",
+            TransactionKind::Withdrawal => "withdrawal",
+        }
+    }
+}
+
+impl DisputeStatus {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Undisputed => "undisputed",
+            Disputed => "disputed",
+            PartiallyChargedBack => "partially_charged_back",
+            ChargedBack => "charged_back",
+            Reversed => "reversed",
+        }
+    }
+}
+
+// A packed form of a transaction, a quarter of the size, for when there are so
+// many that memory is what matters. The amount is fixed-point with as many
+// decimal places as the input allows, the timestamp is in 32 bits (which lasts
+// until 2106), and the kind, the dispute status and whether there's a
+// timestamp share a byte. There's no room for its dispute history, so only
+// transactions that have never been disputed are packed, which also means
+// there's no disputed or charged back amount to keep. The amount is only
+// aligned to 4 bytes, like everything else, so that it doesn't pad the rest
+// out to 24.
+#[derive(Clone, Copy)]
+#[repr(C, packed(4))]
+pub struct CompactTransaction {
+    amount: i64,
+    timestamp: u32,
+    client_id: ClientID,
+    currency: Currency,
+    // the kind in the lowest bit, then whether there's a timestamp, then the
+    // dispute status
+    flags: u8,
+}
+
+impl TransactionKind {
+    fn to_byte(&self) -> u8 {
+        match self {
+            TransactionKind::Deposit => 0,
+            TransactionKind::Withdrawal => 1,
+        }
+    }
+
+    fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            0 => Some(TransactionKind::Deposit),
+            1 => Some(TransactionKind::Withdrawal),
+            _ => None,
+        }
+    }
+}
+
+impl DisputeStatus {
+    fn to_byte(self) -> u8 {
+        match self {
+            Undisputed => 0,
+            Disputed => 1,
+            PartiallyChargedBack => 2,
+            ChargedBack => 3,
+            Reversed => 4,
+        }
+    }
+
+    fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            0 => Some(Undisputed),
+            1 => Some(Disputed),
+            2 => Some(PartiallyChargedBack),
+            3 => Some(ChargedBack),
+            4 => Some(Reversed),
+            _ => None,
+        }
+    }
+}
+
+fn to_fixed_point(amount: Amount) -> Option<i64> {
+    let scaled = amount.checked_mul(Amount::from(10i64.pow(AMOUNT_DECIMAL_PLACES)))?;
+    if !scaled.fract().is_zero() {
+        return None;
+    }
+    scaled.to_i64()
+}
+
+fn from_fixed_point(amount: i64) -> Amount {
+    Amount::new(amount, AMOUNT_DECIMAL_PLACES)
+}
+
 impl Transaction {
-    pub fn new(client_id: ClientID, amount: Amount, kind: TransactionKind) -> Self {
+    // How many bytes `to_bytes` takes.
+    pub const ENCODED_LEN: usize = 91;
+
+    pub fn new(
+        client_id: ClientID,
+        currency: Currency,
+        amount: Amount,
+        kind: TransactionKind,
+    ) -> Self {
         Self {
             client_id,
+            currency,
             amount,
             kind,
             dispute_status: Undisputed,
+            disputed_amount: Amount::ZERO,
+            charged_back_amount: Amount::ZERO,
+            timestamp: None,
+            history: None,
+        }
+    }
+
+    pub fn with_timestamp(self, timestamp: Option<Timestamp>) -> Self {
+        Self { timestamp, ..self }
+    }
+
+    // A fixed-size binary form of the transaction, for stores that keep
+    // transactions somewhere other than in memory.
+    pub fn to_bytes(&self) -> [u8; Self::ENCODED_LEN] {
+        let history = self.dispute_history();
+        let fields: [&[u8]; 14] = [
+            &self.client_id.to_le_bytes(),
+            &self.currency.to_bytes(),
+            &self.amount.serialize(),
+            &[self.kind.to_byte(), self.dispute_status.to_byte()],
+            &self.disputed_amount.serialize(),
+            &self.charged_back_amount.serialize(),
+            &[u8::from(self.timestamp.is_some())],
+            &self.timestamp.unwrap_or_default().to_le_bytes(),
+            &[u8::from(history.disputed_at.is_some())],
+            &history.disputed_at.unwrap_or_default().to_le_bytes(),
+            &[u8::from(history.resolved_at.is_some())],
+            &history.resolved_at.unwrap_or_default().to_le_bytes(),
+            &[u8::from(history.charged_back_at.is_some())],
+            &history.charged_back_at.unwrap_or_default().to_le_bytes(),
+        ];
+
+        let mut bytes = [0; Self::ENCODED_LEN];
+        let mut offset = 0;
+        for field in fields {
+            bytes[offset..offset + field.len()].copy_from_slice(field);
+            offset += field.len();
+        }
+        bytes
+    }
+
+    // The inverse of `to_bytes`. Returns `None` if the bytes can't have come
+    // from it.
+    pub fn from_bytes(bytes: &[u8; Self::ENCODED_LEN]) -> Option<Self> {
+        let (client_id, rest) = bytes.split_first_chunk::<2>()?;
+        let (currency, rest) = rest.split_first_chunk::<3>()?;
+        let (amount, rest) = rest.split_first_chunk::<16>()?;
+        let ([kind, dispute_status], rest) = rest.split_first_chunk::<2>()?;
+        let (disputed_amount, rest) = rest.split_first_chunk::<16>()?;
+        let (charged_back_amount, rest) = rest.split_first_chunk::<16>()?;
+        let ([has_timestamp], rest) = rest.split_first_chunk::<1>()?;
+        let (timestamp, rest) = rest.split_first_chunk::<8>()?;
+        let (disputed_at, rest) = optional_u64_from_bytes(rest)?;
+        let (resolved_at, rest) = optional_u64_from_bytes(rest)?;
+        let (charged_back_at, _) = optional_u64_from_bytes(rest)?;
+        let history = DisputeHistory {
+            disputed_at,
+            resolved_at,
+            charged_back_at,
+        };
+
+        Some(Self {
+            client_id: ClientID::from_le_bytes(*client_id),
+            currency: Currency::from_bytes(*currency),
+            amount: Amount::deserialize(*amount),
+            kind: TransactionKind::from_byte(*kind)?,
+            dispute_status: DisputeStatus::from_byte(*dispute_status)?,
+            disputed_amount: Amount::deserialize(*disputed_amount),
+            charged_back_amount: Amount::deserialize(*charged_back_amount),
+            timestamp: (*has_timestamp != 0).then(|| Timestamp::from_le_bytes(*timestamp)),
+            history: (history != DisputeHistory::default()).then(|| Box::new(history)),
+        })
+    }
+
+    // Returns `None` if an amount has more decimal places than the input
+    // allows (which a partial dispute could have) or is too big to pack, or if
+    // it's ever been disputed.
+    pub fn to_compact(&self) -> Option<CompactTransaction> {
+        // the amounts only change when it's disputed, which gives it a
+        // history, but they're checked anyway since they'd be lost
+        if self.history.is_some()
+            || !self.disputed_amount.is_zero()
+            || !self.charged_back_amount.is_zero()
+        {
+            return None;
         }
+
+        Some(CompactTransaction {
+            amount: to_fixed_point(self.amount)?,
+            timestamp: match self.timestamp {
+                Some(timestamp) => u32::try_from(timestamp).ok()?,
+                None => 0,
+            },
+            client_id: self.client_id,
+            currency: self.currency,
+            flags: self.kind.to_byte()
+                | u8::from(self.timestamp.is_some()) << 1
+                | self.dispute_status.to_byte() << 2,
+        })
     }
 
     pub fn client_id(&self) -> ClientID {
         self.client_id
     }
 
+    pub fn currency(&self) -> Currency {
+        self.currency
+    }
+
     pub fn amount(&self) -> Amount {
         self.amount
     }
@@ -48,20 +284,146 @@ impl Transaction {
         &self.kind
     }
 
+    pub fn timestamp(&self) -> Option<Timestamp> {
+        self.timestamp
+    }
+
+    pub fn dispute_status(&self) -> DisputeStatus {
+        self.dispute_status
+    }
+
     pub fn set_dispute_status(&mut self, dispute_status: DisputeStatus) {
         self.dispute_status = dispute_status;
     }
 
+    pub fn disputed_amount(&self) -> Amount {
+        self.disputed_amount
+    }
+
+    pub fn charged_back_amount(&self) -> Amount {
+        self.charged_back_amount
+    }
+
+    // Empty if it's never been disputed.
+    pub fn dispute_history(&self) -> DisputeHistory {
+        self.history.as_deref().copied().unwrap_or_default()
+    }
+
+    fn history_mut(&mut self) -> &mut DisputeHistory {
+        self.history.get_or_insert_with(Box::default)
+    }
+
+    // What's left to dispute once earlier chargebacks are taken out.
+    pub fn disputable_amount(&self) -> Amount {
+        self.amount - self.charged_back_amount
+    }
+
+    // A re-dispute takes back the chargeback it follows, so it's for the same
+    // amount.
+    pub fn dispute(&mut self, amount: Amount, event_number: u64) {
+        if self.dispute_status == ChargedBack {
+            self.charged_back_amount -= self.disputed_amount;
+        } else {
+            self.disputed_amount = amount;
+        }
+        self.dispute_status = Disputed;
+        self.history_mut().disputed_at = Some(event_number);
+    }
+
+    pub fn resolve(&mut self, event_number: u64) {
+        self.end_dispute();
+        self.history_mut().resolved_at = Some(event_number);
+    }
+
+    fn end_dispute(&mut self) {
+        self.disputed_amount = Amount::ZERO;
+        self.dispute_status = if self.charged_back_amount.is_zero() {
+            Undisputed
+        } else {
+            PartiallyChargedBack
+        };
+    }
+
+    // Chargeback reversals undo the most recent chargeback, which is the last
+    // thing that can have happened to it.
+    pub fn validate_chargeback_reversal(&self) -> Result<(), ProcessingError> {
+        let charged_back = match self.dispute_status {
+            ChargedBack => true,
+            PartiallyChargedBack => !self.disputed_amount.is_zero(),
+            _ => false,
+        };
+        if !charged_back {
+            return Err(ProcessingError::NotChargedBack);
+        }
+
+        Ok(())
+    }
+
+    // Unlike a re-dispute, this isn't a dispute step, so it doesn't go in the
+    // history.
+    pub fn reverse_chargeback(&mut self) {
+        self.charged_back_amount -= self.disputed_amount;
+        self.end_dispute();
+    }
+
+    pub fn charge_back(&mut self, event_number: u64) {
+        self.charged_back_amount += self.disputed_amount;
+        self.dispute_status = if self.disputable_amount().is_zero() {
+            ChargedBack
+        } else {
+            PartiallyChargedBack
+        };
+        self.history_mut().charged_back_at = Some(event_number);
+    }
+
+    // Some networks allow a charged back transaction to be disputed again (a
+    // second presentment), hence `allow_redispute`. Otherwise a chargeback is
+    // final.
     pub fn validate_dispute_status_transition(
         &self,
         new_dispute_status: DisputeStatus,
-    ) -> Result<(), String> {
+        allow_redispute: bool,
+    ) -> Result<(), ProcessingError> {
         match (&self.dispute_status, new_dispute_status) {
-            (Undisputed, Disputed) | (Disputed, Undisputed) | (Disputed, ChargedBack) => Ok(()),
+            (Undisputed, Disputed) | (Disputed, Undisputed) => Ok(()),
+            (Disputed, ChargedBack | PartiallyChargedBack) => Ok(()),
+            (PartiallyChargedBack, Disputed) => Ok(()),
+            (ChargedBack, Disputed) if allow_redispute => Ok(()),
+            (Undisputed, Reversed) => Ok(()),
+
+            (ChargedBack, _) => Err(ProcessingError::AlreadyChargedBack),
+            (Reversed, _) => Err(ProcessingError::AlreadyReversed),
+            (Disputed, Reversed) => Err(ProcessingError::ReversalWhileDisputed),
+            (PartiallyChargedBack, Reversed) => {
+                Err(ProcessingError::ReversalAfterPartialChargeback)
+            }
+            (Undisputed | PartiallyChargedBack, _) => Err(ProcessingError::NotDisputed),
+            (Disputed, Disputed) => Err(ProcessingError::AlreadyDisputed),
+        }
+    }
+}
 
-            (ChargedBack, _) => Err(String::from("Transaction has already been charged back.")),
-            (Undisputed, _) => Err(String::from("Transaction is not disputed.")),
-            (Disputed, Disputed) => Err(String::from("Transaction is already disputed.")),
+impl CompactTransaction {
+    pub fn expand(&self) -> Transaction {
+        Transaction {
+            client_id: self.client_id,
+            currency: self.currency,
+            amount: from_fixed_point(self.amount),
+            // only ever packed by `to_compact`, so these can't be invalid
+            kind: TransactionKind::from_byte(self.flags & 1).unwrap_or(TransactionKind::Deposit),
+            dispute_status: DisputeStatus::from_byte(self.flags >> 2).unwrap_or(Undisputed),
+            disputed_amount: Amount::ZERO,
+            charged_back_amount: Amount::ZERO,
+            timestamp: (self.flags & 2 != 0).then_some(Timestamp::from(self.timestamp)),
+            history: None,
         }
     }
 }
+
+// Reads what `to_bytes` writes for an optional event number: whether there is
+// one, then the number itself.
+fn optional_u64_from_bytes(bytes: &[u8]) -> Option<(Option<u64>, &[u8])> {
+    let ([is_some], rest) = bytes.split_first_chunk::<1>()?;
+    let (value, rest) = rest.split_first_chunk::<8>()?;
+    Some(((*is_some != 0).then(|| u64::from_le_bytes(*value)), rest))
+}
@@ -0,0 +1,173 @@
+// Takes events for `challenge serve` from a NATS JetStream stream, through a
+// durable pull consumer, for the smaller services that already publish to
+// NATS rather than hold a stream open. Each message is an event, as a line of
+// JSON like the ones `POST /events` takes. A message is only acked once its
+// event has been accepted or rejected (and journalled, if there's a journal),
+// so whatever hadn't been dealt with when the service stopped is delivered
+// again to whichever one next uses the consumer.
+//
+// Like Flight, the client needs a tokio runtime, so it gets a thread of its
+// own with a runtime of its own, and each event takes its turn with everything
+// else on the thread answering requests (see `serve::Service`).
+
+use crate::serve::StreamOutcome;
+use async_nats::jetstream::{
+    self,
+    consumer::{pull, AckPolicy},
+    AckKind,
+};
+use clap::Args;
+use log::{debug, warn};
+use std::{error::Error, sync::Arc, time::Duration};
+use tokio_stream::StreamExt;
+
+// How long to wait before a message whose event was never processed, because
+// the service stopped first, is delivered again.
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+
+#[derive(Args)]
+pub struct NatsOptions {
+    #[arg(
+        long,
+        value_name = "URL",
+        help = "Also take events from a NATS JetStream stream, via this server."
+    )]
+    nats_url: Option<String>,
+    #[arg(
+        long,
+        value_name = "STREAM",
+        default_value = "events",
+        help = "The JetStream stream to take events from."
+    )]
+    nats_stream: String,
+    #[arg(
+        long,
+        value_name = "SUBJECT",
+        help = "Only take events published to this subject, rather than the whole stream."
+    )]
+    nats_subject: Option<String>,
+    #[arg(
+        long,
+        value_name = "NAME",
+        default_value = "challenge",
+        help = "The durable consumer that keeps track of which events have been taken."
+    )]
+    nats_consumer: String,
+}
+
+impl NatsOptions {
+    // The source, if there's a server to take events from.
+    pub fn source(&self) -> Option<NatsSource> {
+        Some(NatsSource {
+            url: self.nats_url.clone()?,
+            stream: self.nats_stream.clone(),
+            subject: self.nats_subject.clone(),
+            consumer: self.nats_consumer.clone(),
+        })
+    }
+}
+
+pub struct NatsSource {
+    url: String,
+    stream: String,
+    subject: Option<String>,
+    consumer: String,
+}
+
+// Hands each message's event to `handle_event` (which blocks until it's been
+// dealt with) and acks it according to how that went, until the service stops
+// or the consumer can't carry on.
+pub fn consume(
+    source: &NatsSource,
+    handle_event: impl Fn(&str) -> Option<StreamOutcome> + Send + Sync + 'static,
+) {
+    if let Err(e) = try_consume(source, handle_event) {
+        warn!("Stopped taking events from NATS at {}: {}", source.url, e);
+    }
+}
+
+fn try_consume(
+    source: &NatsSource,
+    handle_event: impl Fn(&str) -> Option<StreamOutcome> + Send + Sync + 'static,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let handle_event = Arc::new(handle_event);
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    runtime.block_on(async {
+        let client = async_nats::connect(&source.url).await?;
+        let stream = jetstream::new(client).get_stream(&source.stream).await?;
+        let consumer = stream
+            .get_or_create_consumer(
+                &source.consumer,
+                pull::Config {
+                    durable_name: Some(source.consumer.clone()),
+                    filter_subject: source.subject.clone().unwrap_or_default(),
+                    ack_policy: AckPolicy::Explicit,
+                    ..pull::Config::default()
+                },
+            )
+            .await?;
+        debug!(
+            "Taking events from {} as {}.",
+            source.stream, source.consumer
+        );
+
+        let mut messages = consumer.messages().await?;
+        while let Some(message) = messages.next().await {
+            let message = message?;
+            let handle_event = Arc::clone(&handle_event);
+            let payload = message.payload.clone();
+            // the engine's on another thread, so this waits without holding up
+            // the runtime
+            let outcome =
+                tokio::task::spawn_blocking(move || match std::str::from_utf8(&payload) {
+                    Ok(line) => handle_event(line),
+                    Err(e) => Some(StreamOutcome::new("invalid", e)),
+                })
+                .await?;
+            let Some(outcome) = outcome else {
+                // nothing's been applied, so it can safely be delivered again,
+                // to whichever consumer's still there
+                message.ack_with(AckKind::Nak(Some(RETRY_DELAY))).await?;
+                return Ok(());
+            };
+            let ack_kind = ack_kind(&outcome);
+            if !matches!(ack_kind, AckKind::Ack) {
+                let sequence = message.info().map_or(0, |info| info.stream_sequence);
+                warn!(
+                    "Couldn't take message {} from NATS: {}",
+                    sequence,
+                    outcome.to_line(sequence)
+                );
+            }
+            message.ack_with(ack_kind).await?;
+        }
+        Ok(())
+    })
+}
+
+// Accepted and rejected events are done with. One that doesn't parse never
+// will be, so it isn't delivered again. Neither is one that failed, since
+// that's only ever after the event's been applied (say, the journal's disk
+// was full), and applying it again would count it twice.
+fn ack_kind(outcome: &StreamOutcome) -> AckKind {
+    match outcome.status {
+        "accepted" | "rejected" => AckKind::Ack,
+        _ => AckKind::Term,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_ack_kind() {
+        let outcome = |status| StreamOutcome::new(status, "");
+        assert!(matches!(ack_kind(&outcome("accepted")), AckKind::Ack));
+        assert!(matches!(ack_kind(&outcome("rejected")), AckKind::Ack));
+        assert!(matches!(ack_kind(&outcome("invalid")), AckKind::Term));
+        assert!(matches!(ack_kind(&outcome("failed")), AckKind::Term));
+    }
+}
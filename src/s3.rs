@@ -0,0 +1,251 @@
+// Uploads what a run wrote (the report, the error file and the manifest) to
+// an S3 prefix once it's all been written, so that the pipeline doesn't need
+// a script around the binary just to copy them there. Credentials, the region
+// and so on come from the usual `AWS_` environment variables. The files are
+// only uploaded once they're in place, so a run that fails doesn't upload
+// anything, and one whose upload fails fails too.
+//
+// It's also where `challenge serve` can keep snapshots of its state (see
+// `S3Snapshots`).
+
+use crate::serve::{tenant_location, StateSnapshots};
+use clap::{builder::PossibleValuesParser, Args};
+use log::debug;
+use object_store::{
+    aws::{AmazonS3, AmazonS3Builder, AmazonS3ConfigKey},
+    buffered::BufWriter,
+    path::Path as ObjectPath,
+    ObjectStore, PutPayload,
+};
+use std::{
+    error::Error,
+    fs::File,
+    io::Read,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use tokio::io::AsyncWriteExt;
+use tokio::runtime::Runtime;
+
+// How much of a file is read at a time. Anything bigger than a part goes up
+// as a multipart upload.
+const CHUNK_SIZE: usize = 1024 * 1024;
+
+#[derive(Args)]
+pub struct S3Options {
+    #[arg(
+        long,
+        value_name = "s3://BUCKET/PREFIX",
+        help = "Upload the report, error file and manifest here once they're written.",
+        help_heading = "Upload"
+    )]
+    s3_prefix: Option<String>,
+    #[arg(
+        long,
+        value_name = "ALGORITHM",
+        requires = "s3_prefix",
+        value_parser = PossibleValuesParser::new(["AES256", "aws:kms", "aws:kms:dsse"]),
+        help = "Have S3 encrypt the uploads with this, rather than the bucket's default.",
+        help_heading = "Upload"
+    )]
+    s3_sse: Option<String>,
+    #[arg(
+        long,
+        value_name = "KEY_ID",
+        requires = "s3_sse",
+        help = "The KMS key to encrypt the uploads with, rather than the account's default.",
+        help_heading = "Upload"
+    )]
+    s3_sse_kms_key_id: Option<String>,
+}
+
+impl S3Options {
+    // The uploader, if there's somewhere to upload to. It's set up before the
+    // run starts, so that a bad prefix fails it before rather than after.
+    pub fn uploader(&self) -> Result<Option<S3Uploader>, Box<dyn Error>> {
+        let Some(prefix) = &self.s3_prefix else {
+            return Ok(None);
+        };
+        let (bucket, key_prefix) = parse_prefix(prefix)?;
+
+        let mut builder = AmazonS3Builder::from_env().with_bucket_name(bucket);
+        if let Some(sse) = &self.s3_sse {
+            builder = builder.with_config(
+                "aws_server_side_encryption".parse::<AmazonS3ConfigKey>()?,
+                sse,
+            );
+        }
+        if let Some(key_id) = &self.s3_sse_kms_key_id {
+            builder =
+                builder.with_config("aws_sse_kms_key_id".parse::<AmazonS3ConfigKey>()?, key_id);
+        }
+        let store = builder
+            .build()
+            .map_err(|e| format!("Couldn't set up the upload to {}: {}", prefix, e))?;
+
+        Ok(Some(S3Uploader {
+            store: Arc::new(store),
+            bucket: String::from(bucket),
+            key_prefix: String::from(key_prefix),
+        }))
+    }
+
+    // Nothing's uploaded, e.g. for a dry run.
+    pub fn disable(&mut self) {
+        self.s3_prefix = None;
+    }
+}
+
+pub struct S3Uploader {
+    store: Arc<AmazonS3>,
+    bucket: String,
+    key_prefix: String,
+}
+
+impl S3Uploader {
+    // Uploads each file to the prefix under its own name, one after the
+    // other.
+    pub fn upload(&self, paths: &[PathBuf]) -> Result<(), Box<dyn Error>> {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        for path in paths {
+            let key = object_key(&self.key_prefix, path);
+            runtime
+                .block_on(self.upload_file(path, &key))
+                .map_err(|e| {
+                    format!(
+                        "Couldn't upload {} to s3://{}/{}: {}",
+                        path.display(),
+                        self.bucket,
+                        key,
+                        e
+                    )
+                })?;
+            debug!(
+                "Uploaded {} to s3://{}/{}.",
+                path.display(),
+                self.bucket,
+                key
+            );
+        }
+        Ok(())
+    }
+
+    async fn upload_file(&self, path: &Path, key: &ObjectPath) -> Result<(), Box<dyn Error>> {
+        let mut file = File::open(path)?;
+        let mut writer = BufWriter::new(self.store.clone(), key.clone());
+        let mut chunk = vec![0; CHUNK_SIZE];
+        loop {
+            let read = file.read(&mut chunk)?;
+            if read == 0 {
+                break;
+            }
+            writer.write_all(&chunk[..read]).await?;
+        }
+        writer.shutdown().await?;
+        Ok(())
+    }
+}
+
+// Snapshots of serve's state in an S3 object (an object per tenant, see
+// `serve::tenant_location`), which each one replaces. The encryption is
+// whatever `AWS_SERVER_SIDE_ENCRYPTION` and the bucket say.
+pub struct S3Snapshots {
+    store: AmazonS3,
+    key: String,
+    runtime: Runtime,
+}
+
+impl S3Snapshots {
+    // Where `location` is an `s3://bucket/key` URL.
+    pub fn open(location: &str) -> Result<Self, Box<dyn Error>> {
+        let (bucket, key) = parse_prefix(location)?;
+        if key.is_empty() {
+            return Err(format!("{} needs a key, e.g. s3://bucket/state.", location).into());
+        }
+        let store = AmazonS3Builder::from_env()
+            .with_bucket_name(bucket)
+            .build()
+            .map_err(|e| format!("Couldn't set up snapshots in {}: {}", location, e))?;
+
+        Ok(Self {
+            store,
+            key: String::from(key),
+            runtime: tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()?,
+        })
+    }
+}
+
+impl S3Snapshots {
+    fn tenant_key(&self, tenant: &str) -> ObjectPath {
+        ObjectPath::from(tenant_location(&self.key, tenant))
+    }
+}
+
+impl StateSnapshots for S3Snapshots {
+    fn load(&mut self, tenant: &str) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+        let key = self.tenant_key(tenant);
+        self.runtime.block_on(async {
+            match self.store.get(&key).await {
+                Ok(object) => Ok(Some(object.bytes().await?.to_vec())),
+                Err(object_store::Error::NotFound { .. }) => Ok(None),
+                Err(e) => Err(e.into()),
+            }
+        })
+    }
+
+    fn save(&mut self, tenant: &str, snapshot: &[u8]) -> Result<(), Box<dyn Error>> {
+        let payload = PutPayload::from(snapshot.to_vec());
+        let key = self.tenant_key(tenant);
+        self.runtime.block_on(self.store.put(&key, payload))?;
+        Ok(())
+    }
+}
+
+// Splits `s3://bucket/some/prefix` into the bucket and the prefix.
+fn parse_prefix(prefix: &str) -> Result<(&str, &str), String> {
+    let (bucket, key_prefix) = prefix
+        .strip_prefix("s3://")
+        .map(|rest| rest.split_once('/').unwrap_or((rest, "")))
+        .filter(|(bucket, _)| !bucket.is_empty())
+        .ok_or_else(|| format!("Invalid S3 prefix {}, e.g. s3://bucket/prefix.", prefix))?;
+    Ok((bucket, key_prefix))
+}
+
+// Where a file goes: its name, under the prefix.
+fn object_key(key_prefix: &str, path: &Path) -> ObjectPath {
+    let file_name = path
+        .file_name()
+        .map(|file_name| file_name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    ObjectPath::from(format!("{}/{}", key_prefix, file_name))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_object_keys() {
+        assert_eq!(
+            Ok(("reports", "daily/2024-01-02/")),
+            parse_prefix("s3://reports/daily/2024-01-02/")
+        );
+        assert_eq!(Ok(("reports", "")), parse_prefix("s3://reports"));
+        assert!(parse_prefix("s3:///daily").is_err());
+        assert!(parse_prefix("reports/daily").is_err());
+
+        assert_eq!(
+            "daily/2024-01-02/report.csv.gz",
+            object_key("daily/2024-01-02/", Path::new("out/report.csv.gz")).as_ref()
+        );
+        assert_eq!(
+            "errors.csv",
+            object_key("", Path::new("errors.csv")).as_ref()
+        );
+    }
+}
@@ -0,0 +1,1455 @@
+// The HTTP API behind `challenge serve`, which keeps a single engine going for
+// as long as it runs, so that events can be sent as they happen rather than
+// saved up for a batch run. It's the same engine either way, so the same
+// events come out the same, whichever way they went in.
+//
+// Requests are dealt with one at a time, in the order they arrive, which is
+// what keeps the engine's view of the order of events meaningful. Processing
+// is quick enough next to the network that there's not much to gain from
+// doing otherwise. A stream (a WebSocket, or a plain TCP connection for
+// whatever can't speak HTTP) is open for as long as its client wants, so each
+// one gets a thread of its own that parses its events and hands them over one
+// at a time, to be dealt with in turn with everything else.
+//
+// If there's somewhere to keep snapshots of the state, one's taken now and
+// then, and again when the service is stopped with SIGINT or SIGTERM, so that
+// a restart carries on where it left off.
+//
+// Besides the default tenant, whose API is at the root, there can be others
+// (see `Tenants`), each with the same API under `/tenants/<tenant>/` and a
+// ledger of its own. Streams over TCP, NATS and Flight and the journal are all
+// the default tenant's, while the delta sink is told whose each change is.
+
+use challenge::{
+    engine::{Engine, EngineBuilder, Tenants},
+    format::{self, json::output::JsonLayout, InputFormat, OutputFormat, ReportConfig},
+    model::{ClientID, Source, SourcedEvent},
+    system::{ProcessEventsError, Rejection, RejectionLogger},
+};
+use log::{debug, warn};
+use serde::Serialize;
+use signal_hook::{
+    consts::{SIGINT, SIGTERM},
+    iterator::Signals,
+};
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    error::Error,
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{TcpListener, TcpStream},
+    rc::Rc,
+    sync::mpsc::{self, Sender},
+    thread,
+    time::{Duration, Instant},
+};
+use tiny_http::{Header, Method, ReadWrite, Request, Response, Server};
+use tungstenite::{handshake::derive_accept_key, protocol::Role, Message, WebSocket};
+
+// The tenant whose API is at the root, and who's there whether there are any
+// others or not.
+pub const DEFAULT_TENANT: &str = "default";
+
+pub struct Service<'a> {
+    tenants: Tenants<'a>,
+    report_config: ReportConfig,
+    // whatever the events in the current request had rejected, to go back in
+    // the response
+    rejections: Rc<RefCell<Vec<ServedRejection>>>,
+    // How many events have come in over streams, which is where each one is
+    // said to have been read from, so that its rejection can be told apart
+    // from any other's.
+    streamed: u64,
+    // where what each accepted event changed goes, if anywhere
+    delta_sink: Option<Box<dyn DeltaSink>>,
+    // for the metrics' uptime
+    started: Instant,
+    // where to keep snapshots of the state, how often, and how many events
+    // each tenant had processed as of its last one
+    snapshots: Option<(Box<dyn StateSnapshots>, Duration)>,
+    snapshot_processed: HashMap<String, u64>,
+    stop_on_signals: bool,
+}
+
+// Somewhere to keep snapshots of the state (see `Engine::save_state`), e.g. a
+// file or an S3 object (see `s3.rs`), so that the next run can carry on from
+// the last one. Each tenant has snapshots of its own (see `tenant_location`).
+pub trait StateSnapshots {
+    // The tenant's last snapshot saved, if there's been one.
+    fn load(&mut self, tenant: &str) -> Result<Option<Vec<u8>>, Box<dyn Error>>;
+
+    // Replaces the tenant's last snapshot, all at once, so that a snapshot
+    // that's cut short never replaces a whole one.
+    fn save(&mut self, tenant: &str, snapshot: &[u8]) -> Result<(), Box<dyn Error>>;
+}
+
+// Where a tenant's snapshots go, given where the default tenant's do: the same
+// place for the default tenant, so that snapshots from before there were
+// tenants still load, and the same place with `.<tenant>` on the end for the
+// rest.
+pub fn tenant_location(location: &str, tenant: &str) -> String {
+    if tenant == DEFAULT_TENANT {
+        String::from(location)
+    } else {
+        format!("{}.{}", location, tenant)
+    }
+}
+
+// Somewhere to publish balance changes to as they happen, e.g. a Kafka topic
+// (see `kafka.rs`), so that whatever else needs to know doesn't have to keep
+// asking. Each change is a line of JSON (see `format::json::delta`), keyed by
+// the tenant and client it's for so that a client's changes stay in order, and
+// one tenant's client isn't mistaken for another's.
+pub trait DeltaSink {
+    fn publish(
+        &mut self,
+        tenant: &str,
+        client_id: ClientID,
+        payload: &[u8],
+    ) -> Result<(), Box<dyn Error>>;
+
+    // Waits for everything published so far to be delivered.
+    fn flush(&mut self) -> Result<(), Box<dyn Error>>;
+}
+
+// Where the service listens, besides for HTTP.
+#[derive(Default)]
+pub struct Listeners {
+    // for CSV a line at a time (see `stream_tcp_events`)
+    pub tcp: Option<TcpListener>,
+    // for Arrow Flight (see `flight.rs`)
+    #[cfg(feature = "flight")]
+    pub flight: Option<TcpListener>,
+    // not a socket, but somewhere else events come in from (see `nats.rs`)
+    #[cfg(feature = "nats")]
+    pub nats: Option<crate::nats::NatsSource>,
+}
+
+// What the thread answering requests is handed to do next.
+enum Work {
+    Request(Request),
+    // An event from a stream, for a tenant, whose outcome is sent back to it.
+    StreamEvent {
+        tenant: String,
+        event: SourcedEvent,
+        reply: Sender<StreamOutcome>,
+    },
+    // The balances, for Arrow Flight.
+    #[cfg(feature = "flight")]
+    Balances(crate::flight::BalancesReply),
+    // Time for a snapshot of the state, if anything's changed.
+    Snapshot,
+    // Stops taking anything else, after a last snapshot.
+    Stop,
+}
+
+// Intermediary representation of a rejection for serialization, which unlike
+// `Rejection` has to outlive the call to the logger.
+#[derive(Debug, PartialEq, Serialize)]
+struct ServedRejection {
+    // the line of the request's body the event was on
+    line: Option<u64>,
+    code: &'static str,
+    message: String,
+}
+
+struct SharedRejections(Rc<RefCell<Vec<ServedRejection>>>);
+
+impl RejectionLogger for SharedRejections {
+    fn log_rejection(&mut self, rejection: &Rejection) -> io::Result<()> {
+        self.0.borrow_mut().push(ServedRejection {
+            line: rejection.source.map(|source| source.line),
+            code: rejection.code(),
+            message: rejection.error.to_string(),
+        });
+        Ok(())
+    }
+
+    fn flush_rejections(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[derive(Serialize)]
+struct EventsReply {
+    processed: u64,
+    rejected: u64,
+    rejections: Vec<ServedRejection>,
+}
+
+// How a line of a stream went: "accepted", "rejected" (with the code and
+// message it would have been logged with), "invalid" if it didn't parse, or
+// "failed" if processing it did.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct StreamOutcome {
+    pub status: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    code: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    message: Option<String>,
+}
+
+impl StreamOutcome {
+    pub fn new(status: &'static str, message: impl ToString) -> Self {
+        Self {
+            status,
+            code: None,
+            message: Some(message.to_string()),
+        }
+    }
+
+    // The line a TCP stream gets back, e.g. `rejected 2 insufficient_funds:
+    // Insufficient funds.`
+    pub fn to_line(&self, line_number: u64) -> String {
+        let mut line = format!("{} {}", self.status, line_number);
+        if let Some(code) = self.code {
+            line = format!("{} {}", line, code);
+        }
+        if let Some(message) = &self.message {
+            line = format!("{}: {}", line, message);
+        }
+        line
+    }
+}
+
+// What a WebSocket stream gets back for each line.
+#[derive(Serialize)]
+struct StreamAck {
+    line: u64,
+    #[serde(flatten)]
+    outcome: StreamOutcome,
+}
+
+#[derive(Serialize)]
+struct ErrorReply {
+    error: String,
+}
+
+// What a request gets back. Every body is JSON, except for the metrics'.
+#[derive(Debug, PartialEq)]
+pub struct Reply {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: Vec<u8>,
+}
+
+impl Reply {
+    fn json(status: u16, value: &impl Serialize) -> Self {
+        let mut body = serde_json::to_vec(value).expect("Replies always serialize");
+        body.push(b'\n');
+        Self {
+            status,
+            content_type: "application/json",
+            body,
+        }
+    }
+
+    // A body that's already been written as JSON.
+    fn ok_json(body: Vec<u8>) -> Self {
+        Self {
+            status: 200,
+            content_type: "application/json",
+            body,
+        }
+    }
+
+    fn error(status: u16, error: impl ToString) -> Self {
+        Self::json(
+            status,
+            &ErrorReply {
+                error: error.to_string(),
+            },
+        )
+    }
+}
+
+impl<'a> Service<'a> {
+    // Rejections are sent back to whoever sent the events rather than logged,
+    // so the builder's rejection logger is replaced. If there's a state to
+    // carry on from (see `Engine::finish_saving_state`), that's where it
+    // starts. This is the default tenant's.
+    pub fn new(builder: EngineBuilder<'a>, state_reader: Option<impl Read>) -> io::Result<Self> {
+        let report_config = ReportConfig {
+            format: OutputFormat::Json(JsonLayout::Array),
+            ..ReportConfig::default()
+        };
+        let mut service = Self {
+            tenants: Tenants::new(),
+            report_config,
+            rejections: Rc::new(RefCell::new(Vec::new())),
+            streamed: 0,
+            delta_sink: None,
+            started: Instant::now(),
+            snapshots: None,
+            snapshot_processed: HashMap::new(),
+            stop_on_signals: false,
+        };
+        service.add_tenant(DEFAULT_TENANT, builder, state_reader)?;
+        Ok(service)
+    }
+
+    // Adds a tenant, whose API is under `/tenants/<tenant>/`, in the same way
+    // as `new` does the default one.
+    pub fn add_tenant(
+        &mut self,
+        tenant: &str,
+        builder: EngineBuilder<'a>,
+        state_reader: Option<impl Read>,
+    ) -> io::Result<()> {
+        let builder = builder
+            .report_config(self.report_config.clone())
+            .rejection_logger(SharedRejections(Rc::clone(&self.rejections)));
+        let engine = match state_reader {
+            Some(state_reader) => builder.build_resumed(state_reader)?,
+            None => builder.build(),
+        };
+        self.tenants.insert(tenant, engine);
+        Ok(())
+    }
+
+    pub fn with_delta_sink(mut self, delta_sink: Option<Box<dyn DeltaSink>>) -> Self {
+        self.delta_sink = delta_sink;
+        self
+    }
+
+    // Takes a snapshot of the state every `interval`, and when it's stopped.
+    pub fn with_snapshots(
+        mut self,
+        snapshots: Option<Box<dyn StateSnapshots>>,
+        interval: Duration,
+    ) -> Self {
+        self.snapshots = snapshots.map(|snapshots| (snapshots, interval));
+        self
+    }
+
+    // Stops (see `run`) on SIGINT or SIGTERM rather than being killed by it.
+    pub fn stop_on_signals(mut self) -> Self {
+        self.stop_on_signals = true;
+        self
+    }
+
+    // See `Engine::replay_journal`. The journal's the default tenant's.
+    pub fn replay_journal(&mut self, journal_reader: impl Read) -> io::Result<u64> {
+        self.engine_mut(DEFAULT_TENANT)
+            .replay_journal(journal_reader)
+    }
+
+    // Requests only get as far as a tenant's engine once it's known to be
+    // there (see `handle`).
+    fn engine(&self, tenant: &str) -> &Engine<'a> {
+        self.tenants.get(tenant).expect("The tenant is there")
+    }
+
+    fn engine_mut(&mut self, tenant: &str) -> &mut Engine<'a> {
+        self.tenants.get_mut(tenant).expect("The tenant is there")
+    }
+
+    // Answers requests (and streams, including those over TCP if there's a
+    // listener for them, Arrow Flight's if there's one for that, and events
+    // from NATS if there's a source for them) until the server stops, or the
+    // service is stopped by a signal. A client that hangs up before it's been
+    // answered is its own problem, not the server's.
+    //
+    // Only the thread taking requests from the server is waited for, since
+    // that's the only one that can be told to stop. The rest (and anything
+    // still waiting to be dealt with) are left to go with the process.
+    pub fn run(&mut self, server: &Server, listeners: Listeners) {
+        let (sender, receiver) = mpsc::channel();
+        if let Some(tcp) = listeners.tcp {
+            let sender = sender.clone();
+            thread::spawn(move || {
+                for connection in tcp.incoming() {
+                    match connection {
+                        Ok(connection) => {
+                            let sender = sender.clone();
+                            thread::spawn(move || stream_tcp_events(connection, sender));
+                        }
+                        Err(e) => warn!("Couldn't accept a connection: {}", e),
+                    }
+                }
+            });
+        }
+
+        #[cfg(feature = "flight")]
+        if let Some(flight) = listeners.flight {
+            let sender = sender.clone();
+            let report_config = self.report_config.clone();
+            thread::spawn(move || {
+                crate::flight::serve(&flight, &report_config, move |reply| {
+                    sender.send(Work::Balances(reply)).is_ok()
+                })
+            });
+        }
+
+        #[cfg(feature = "nats")]
+        if let Some(nats) = listeners.nats {
+            let sender = sender.clone();
+            thread::spawn(move || {
+                crate::nats::consume(&nats, move |line| {
+                    match parse_line(InputFormat::JsonLines, None, line) {
+                        Ok(event) => submit(&sender, DEFAULT_TENANT, event),
+                        Err(outcome) => Some(outcome),
+                    }
+                })
+            });
+        }
+
+        if let Some((_, interval)) = &self.snapshots {
+            let sender = sender.clone();
+            let interval = *interval;
+            thread::spawn(move || loop {
+                thread::sleep(interval);
+                if sender.send(Work::Snapshot).is_err() {
+                    return;
+                }
+            });
+        }
+
+        if self.stop_on_signals {
+            match Signals::new([SIGINT, SIGTERM]) {
+                Ok(mut signals) => {
+                    let sender = sender.clone();
+                    thread::spawn(move || {
+                        for signal in signals.forever() {
+                            debug!("Stopping on signal {}.", signal);
+                            if sender.send(Work::Stop).is_err() {
+                                return;
+                            }
+                        }
+                    });
+                }
+                Err(e) => warn!("Couldn't listen for signals to stop on: {}", e),
+            }
+        }
+
+        thread::scope(|scope| {
+            let requests = sender.clone();
+            scope.spawn(move || {
+                for request in server.incoming_requests() {
+                    if requests.send(Work::Request(request)).is_err() {
+                        return;
+                    }
+                }
+            });
+
+            for work in &receiver {
+                match work {
+                    Work::Request(request) => match stream_tenant(&request) {
+                        // a tenant that isn't there is told so like any
+                        // other request
+                        Some(tenant) if self.tenants.get(&tenant).is_some() => {
+                            let socket = accept_stream(request);
+                            let sender = sender.clone();
+                            thread::spawn(move || stream_events(socket, tenant, sender));
+                        }
+                        _ => self.respond(request),
+                    },
+                    Work::StreamEvent {
+                        tenant,
+                        event,
+                        reply,
+                    } => {
+                        // the stream will find out it's gone soon enough
+                        let _ = reply.send(self.stream_event(&tenant, event));
+                    }
+                    #[cfg(feature = "flight")]
+                    Work::Balances(reply) => {
+                        let _ = reply.send(self.balance_batches());
+                    }
+                    Work::Snapshot => self.take_snapshot(),
+                    Work::Stop => {
+                        self.take_snapshot();
+                        break;
+                    }
+                }
+            }
+            server.unblock();
+        });
+    }
+
+    // Saves a snapshot of each tenant's state, unless it hasn't processed
+    // anything since its last one. If one can't be saved, the service carries
+    // on regardless, and tries again next time.
+    pub fn take_snapshot(&mut self) {
+        let Some((snapshots, _)) = self.snapshots.as_mut() else {
+            return;
+        };
+        for (tenant, engine) in self.tenants.iter_mut() {
+            let processed = engine.event_counts().processed;
+            if self.snapshot_processed.get(tenant).copied().unwrap_or(0) == processed {
+                continue;
+            }
+
+            let mut snapshot = Vec::new();
+            let saved = engine
+                .save_state(&mut snapshot)
+                .map_err(Into::into)
+                .and_then(|()| snapshots.save(tenant, &snapshot));
+            match saved {
+                Ok(()) => {
+                    self.snapshot_processed
+                        .insert(String::from(tenant), processed);
+                    debug!("Saved a snapshot of {} after {} events.", tenant, processed);
+                }
+                Err(e) => warn!("Couldn't save a snapshot of {}: {}", tenant, e),
+            }
+        }
+    }
+
+    fn respond(&mut self, mut request: Request) {
+        let content_type = header(&request, "Content-Type");
+        let method = request.method().clone();
+        let url = request.url().to_string();
+        let reply = self.handle(&method, &url, content_type.as_deref(), request.as_reader());
+        debug!("{} {} -> {}", method, url, reply.status);
+
+        let content_type =
+            Header::from_bytes("Content-Type", reply.content_type).expect("The header is valid");
+        let response = Response::from_data(reply.body)
+            .with_status_code(reply.status)
+            .with_header(content_type);
+        if let Err(e) = request.respond(response) {
+            warn!("Couldn't reply to {} {}: {}", method, url, e);
+        }
+    }
+
+    // The API itself:
+    // - `POST /events` processes the events in the body, which is JSON lines
+    //   (one event is just one line) unless it's sent as `text/csv`. Nothing
+    //   is processed if any of them fail to parse.
+    // - `GET /clients/<id>` gets a client's balances, a row per currency.
+    // - `GET /report` gets every client's.
+    // - `GET /metrics` gets metrics for Prometheus to scrape.
+    // - `GET /events/stream` is a WebSocket (see `stream_line`), so it's only
+    //   answered here if it isn't asking to be one.
+    //
+    // That's the default tenant's. Any other's is the same, under
+    // `/tenants/<tenant>/`.
+    pub fn handle(
+        &mut self,
+        method: &Method,
+        url: &str,
+        content_type: Option<&str>,
+        body: &mut dyn Read,
+    ) -> Reply {
+        // there's nothing we take in the query string
+        let path = url.split('?').next().unwrap_or_default();
+        let (tenant, segments) = tenant_path(path);
+        if self.tenants.get(tenant).is_none() {
+            return Reply::error(404, format!("There's no tenant {}.", tenant));
+        }
+
+        match (method, segments.as_slice()) {
+            (Method::Post, ["events"]) => self.post_events(tenant, content_type, body),
+            (Method::Get, ["clients", client_id]) => match client_id.parse() {
+                Ok(client_id) => self.get_client(tenant, client_id),
+                Err(_) => Reply::error(400, format!("Invalid client ID: {}.", client_id)),
+            },
+            (Method::Get, ["report"]) => self.get_report(tenant),
+            (Method::Get, ["metrics"]) => self.get_metrics(tenant),
+            (Method::Get, ["events", "stream"]) => {
+                Reply::error(426, "This is a WebSocket, so it needs an upgrade.")
+            }
+            (_, ["events"] | ["events", "stream"] | ["clients", _] | ["report"] | ["metrics"]) => {
+                Reply::error(405, format!("{} isn't allowed on {}.", method, path))
+            }
+            _ => Reply::error(404, format!("There's nothing at {}.", path)),
+        }
+    }
+
+    fn post_events(
+        &mut self,
+        tenant: &str,
+        content_type: Option<&str>,
+        body: &mut dyn Read,
+    ) -> Reply {
+        let format = match content_type {
+            Some(content_type) if content_type.starts_with("text/csv") => InputFormat::Csv,
+            _ => InputFormat::JsonLines,
+        };
+        let events = match format::parse_events(format, body, false).collect::<Result<Vec<_>, _>>()
+        {
+            Ok(events) => events,
+            Err(e) => return Reply::error(400, e),
+        };
+
+        let counts_before = self.engine(tenant).event_counts().clone();
+        self.rejections.borrow_mut().clear();
+        // the events are only as good as accepted once they're in the journal
+        if let Err(e) = self.process(tenant, events) {
+            return Reply::error(500, e);
+        }
+
+        let counts = self.engine(tenant).event_counts();
+        Reply::json(
+            200,
+            &EventsReply {
+                processed: counts.processed - counts_before.processed,
+                rejected: counts.rejected - counts_before.rejected,
+                rejections: self.rejections.take(),
+            },
+        )
+    }
+
+    // Processes an event from a stream on its own and says how it went. A
+    // dispute step that's queued behind a lock is accepted as far as the
+    // stream's concerned: if it's rejected once the account's unlocked, it's
+    // too late to say so.
+    pub fn stream_event(&mut self, tenant: &str, mut event: SourcedEvent) -> StreamOutcome {
+        self.streamed += 1;
+        let streamed = self.streamed;
+        event.source = Some(Source {
+            line: streamed,
+            byte: 0,
+            record: None,
+        });
+        self.rejections.borrow_mut().clear();
+        if let Err(e) = self.process(tenant, vec![event]) {
+            return StreamOutcome::new("failed", e);
+        }
+
+        let rejection = self
+            .rejections
+            .take()
+            .into_iter()
+            .find(|rejection| rejection.line == Some(streamed));
+        match rejection {
+            Some(rejection) => StreamOutcome {
+                status: "rejected",
+                code: Some(rejection.code),
+                message: Some(rejection.message),
+            },
+            None => StreamOutcome {
+                status: "accepted",
+                code: None,
+                message: None,
+            },
+        }
+    }
+
+    // The events are only as good as accepted once they're in the journal, and
+    // their changes have been published once the sink says they've been
+    // delivered. If publishing fails, the events have still been processed,
+    // so the changes are lost to the sink (but not to `GET /clients/<id>`).
+    fn process(&mut self, tenant: &str, events: Vec<SourcedEvent>) -> Result<(), Box<dyn Error>> {
+        let engine = self
+            .tenants
+            .get_mut(tenant)
+            .ok_or_else(|| format!("There's no tenant {}.", tenant))?;
+        let events = events.into_iter().map(Ok::<_, ProcessEventsError>);
+        let Some(delta_sink) = self.delta_sink.as_mut() else {
+            engine.process_events(events)?;
+            return Ok(engine.flush_journal()?);
+        };
+
+        let report_config = &self.report_config;
+        engine.process_events_with_deltas(events, |deltas| {
+            for delta in deltas {
+                let mut payload = Vec::new();
+                format::json::delta::write_delta(&delta, &mut payload, report_config)?;
+                delta_sink.publish(tenant, delta.client_id, &payload)?;
+            }
+            Ok(())
+        })?;
+        engine.flush_journal()?;
+        delta_sink.flush()
+    }
+
+    fn get_client(&self, tenant: &str, client_id: ClientID) -> Reply {
+        let Some(client) = self.engine(tenant).processor().client(client_id) else {
+            return Reply::error(404, format!("There's no client {}.", client_id));
+        };
+
+        let mut body = Vec::new();
+        match format::json::output::write_client(client_id, client, &mut body, &self.report_config)
+        {
+            Ok(()) => Reply::ok_json(body),
+            Err(e) => Reply::error(500, e),
+        }
+    }
+
+    fn get_report(&self, tenant: &str) -> Reply {
+        let mut body = Vec::new();
+        match self.engine(tenant).write_report(&mut body) {
+            Ok(()) => Reply::ok_json(body),
+            Err(e) => Reply::error(500, e),
+        }
+    }
+
+    #[cfg(feature = "flight")]
+    fn balance_batches(&self) -> Result<Vec<arrow_array::RecordBatch>, String> {
+        let schema =
+            format::arrow::output::schema(&self.report_config).map_err(|e| e.to_string())?;
+        format::arrow::output::record_batches(
+            self.engine(DEFAULT_TENANT).processor().clients_by_id(),
+            std::sync::Arc::new(schema),
+            &self.report_config,
+        )
+        .collect::<Result<_, _>>()
+        .map_err(|e| e.to_string())
+    }
+
+    fn get_metrics(&self, tenant: &str) -> Reply {
+        let mut body = Vec::new();
+        let engine = self.engine(tenant);
+        match format::prometheus::output::write_live_metrics(
+            engine.processor().clients_by_id(),
+            engine.event_counts(),
+            self.started.elapsed(),
+            crate::resident_memory(),
+            &mut body,
+        ) {
+            Ok(()) => Reply {
+                status: 200,
+                content_type: "text/plain; version=0.0.4",
+                body,
+            },
+            Err(e) => Reply::error(500, e),
+        }
+    }
+}
+
+fn header(request: &Request, name: &'static str) -> Option<String> {
+    request
+        .headers()
+        .iter()
+        .find(|header| header.field.equiv(name))
+        .map(|header| header.value.to_string())
+}
+
+// Splits a path into the tenant it's for and the rest of it, e.g.
+// `/tenants/cards/report` into `cards` and `report`, and `/report` into the
+// default tenant and `report`.
+fn tenant_path(path: &str) -> (&str, Vec<&str>) {
+    let segments = path
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .collect::<Vec<_>>();
+    match segments.as_slice() {
+        ["tenants", tenant, rest @ ..] => (tenant, rest.to_vec()),
+        _ => (DEFAULT_TENANT, segments),
+    }
+}
+
+// The tenant a request wants to stream events to, if it's asking for a
+// WebSocket at (the tenant's) `/events/stream`.
+fn stream_tenant(request: &Request) -> Option<String> {
+    let upgrade = header(request, "Upgrade")?;
+    if request.method() != &Method::Get || !upgrade.eq_ignore_ascii_case("websocket") {
+        return None;
+    }
+    let path = request.url().split('?').next().unwrap_or_default();
+    let (tenant, segments) = tenant_path(path);
+    (segments == ["events", "stream"]).then(|| String::from(tenant))
+}
+
+// Finishes the WebSocket handshake the request started.
+fn accept_stream(request: Request) -> WebSocket<Box<dyn ReadWrite + Send>> {
+    let key = header(&request, "Sec-WebSocket-Key").unwrap_or_default();
+    let accept = Header::from_bytes("Sec-WebSocket-Accept", derive_accept_key(key.as_bytes()))
+        .expect("The header is valid");
+    let socket = request.upgrade("websocket", Response::empty(101).with_header(accept));
+    WebSocket::from_raw_socket(socket, Role::Server, None)
+}
+
+// Parses a line of a stream on its own. CSV needs the stream's header to go
+// with it.
+fn parse_line(
+    format: InputFormat,
+    header: Option<&str>,
+    line: &str,
+) -> Result<SourcedEvent, StreamOutcome> {
+    let input = match header {
+        Some(header) => format!("{}\n{}\n", header, line),
+        None => String::from(line),
+    };
+    let parsed = format::parse_events(format, input.as_bytes(), false).next();
+    match parsed {
+        Some(Ok(event)) => Ok(event),
+        Some(Err(e)) => Err(StreamOutcome::new("invalid", e)),
+        None => Err(StreamOutcome::new("invalid", "There's no event.")),
+    }
+}
+
+// Hands an event for a tenant to the thread processing them and waits to hear
+// how it went, unless the service has stopped.
+fn submit(sender: &Sender<Work>, tenant: &str, event: SourcedEvent) -> Option<StreamOutcome> {
+    let (reply, outcomes) = mpsc::channel();
+    sender
+        .send(Work::StreamEvent {
+            tenant: String::from(tenant),
+            event,
+            reply,
+        })
+        .ok()?;
+    outcomes.recv().ok()
+}
+
+// Reads events off a WebSocket, each text message having any number of them
+// (one per line, like the body of `POST /events`), and answers each one with
+// a message saying how it went, in order. Lines are numbered from the start of
+// the stream. Unlike `POST /events`, a line that doesn't parse doesn't stop
+// the others, since the client's already moved on by the time it finds out.
+fn stream_events(
+    mut socket: WebSocket<Box<dyn ReadWrite + Send>>,
+    tenant: String,
+    sender: Sender<Work>,
+) {
+    debug!("Opened a stream.");
+    let mut line_number = 0;
+    loop {
+        // pings and closes are answered along the way, and the stream's done
+        // once the close has been
+        let text = match socket.read() {
+            Ok(Message::Text(text)) => text,
+            Ok(_) => continue,
+            Err(_) => break,
+        };
+        for line in text.lines().filter(|line| !line.trim().is_empty()) {
+            line_number += 1;
+            let outcome = match parse_line(InputFormat::JsonLines, None, line) {
+                Ok(event) => match submit(&sender, &tenant, event) {
+                    Some(outcome) => outcome,
+                    None => return,
+                },
+                Err(outcome) => outcome,
+            };
+            let ack = StreamAck {
+                line: line_number,
+                outcome,
+            };
+            let ack = serde_json::to_string(&ack).expect("Acks always serialize");
+            if socket.send(Message::Text(ack)).is_err() {
+                return;
+            }
+        }
+    }
+    debug!("Closed a stream after {} lines.", line_number);
+}
+
+// Reads CSV off a TCP connection, a line at a time, starting with the header
+// like any other CSV input, and answers each line with a line saying how it
+// went (see `StreamOutcome::to_line`). Lines are numbered like the input's
+// would be, so the header is line 1. Anything wrong with the connection as a
+// whole, like it not starting with a header, is answered with `error: ` and
+// why, and then it's closed.
+fn stream_tcp_events(connection: TcpStream, sender: Sender<Work>) {
+    let peer = connection
+        .peer_addr()
+        .map_or_else(|_| String::from("somewhere"), |peer| peer.to_string());
+    debug!("Opened a TCP stream from {}.", peer);
+    let result = stream_csv(&connection, &sender);
+    if let Err(e) = result {
+        debug!("Closed the TCP stream from {}: {}", peer, e);
+        let _ = writeln!(&connection, "error: {}", e);
+    } else {
+        debug!("Closed the TCP stream from {}.", peer);
+    }
+}
+
+fn stream_csv(connection: &TcpStream, sender: &Sender<Work>) -> Result<(), String> {
+    let mut writer = connection;
+    let mut lines = BufReader::new(connection).lines();
+    let header = match lines.next() {
+        Some(header) => header.map_err(|e| e.to_string())?,
+        None => return Ok(()),
+    };
+    if !header.split(',').any(|column| column.trim() == "type") {
+        return Err(String::from(
+            "The first line has to be the CSV header, e.g. type,client,tx,amount.",
+        ));
+    }
+
+    // the header was line 1
+    for (line_number, line) in (2..).zip(lines) {
+        let line = line.map_err(|e| e.to_string())?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let outcome = match parse_line(InputFormat::Csv, Some(&header), &line) {
+            Ok(event) => submit(sender, DEFAULT_TENANT, event).ok_or("The service has stopped.")?,
+            Err(outcome) => outcome,
+        };
+        writeln!(writer, "{}", outcome.to_line(line_number)).map_err(|e| e.to_string())?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use challenge::system::WithdrawalDisputePolicy;
+    use pretty_assertions::assert_eq;
+    use std::{fs::File, net::SocketAddr};
+
+    fn service() -> Service<'static> {
+        Service::new(Engine::builder(), None::<File>).expect("Expected no errors.")
+    }
+
+    fn request(
+        service: &mut Service,
+        method: Method,
+        url: &str,
+        content_type: Option<&str>,
+        body: &str,
+    ) -> (u16, String) {
+        let reply = service.handle(&method, url, content_type, &mut body.as_bytes());
+        (
+            reply.status,
+            String::from_utf8(reply.body).expect("Not UTF-8"),
+        )
+    }
+
+    #[test]
+    fn test_post_events() {
+        let mut service = service();
+
+        let (status, body) = request(
+            &mut service,
+            Method::Post,
+            "/events",
+            None,
+            concat!(
+                r#"{"type":"deposit","client":1,"tx":1,"amount":"10"}"#,
+                "\n",
+                r#"{"type":"withdrawal","client":1,"tx":2,"amount":"20"}"#,
+                "\n",
+            ),
+        );
+        assert_eq!(200, status);
+        assert_eq!(
+            concat!(
+                r#"{"processed":2,"rejected":1,"rejections":"#,
+                r#"[{"line":2,"code":"insufficient_funds","message":"Insufficient funds."}]}"#,
+                "\n"
+            ),
+            body
+        );
+
+        // counted per request, and CSV works too
+        let (status, body) = request(
+            &mut service,
+            Method::Post,
+            "/events",
+            Some("text/csv; charset=utf-8"),
+            "type,client,tx,amount\nwithdrawal,1,3,4\n",
+        );
+        assert_eq!(200, status);
+        assert_eq!("{\"processed\":1,\"rejected\":0,\"rejections\":[]}\n", body);
+
+        assert_eq!(
+            (
+                200,
+                String::from(concat!(
+                    r#"[{"client":1,"available":"6.0000","held":"0.0000","total":"6.0000","locked":false}]"#,
+                    "\n"
+                ))
+            ),
+            request(&mut service, Method::Get, "/clients/1", None, "")
+        );
+        assert_eq!(
+            request(&mut service, Method::Get, "/clients/1", None, "").1,
+            request(&mut service, Method::Get, "/report?pretty", None, "").1
+        );
+    }
+
+    #[test]
+    fn test_bad_requests() {
+        let mut service = service();
+
+        // nothing in a batch is processed if any of it doesn't parse
+        let (status, _) = request(
+            &mut service,
+            Method::Post,
+            "/events",
+            None,
+            concat!(
+                r#"{"type":"deposit","client":1,"tx":1,"amount":"10"}"#,
+                "\n",
+                "oops\n",
+            ),
+        );
+        assert_eq!(400, status);
+        assert_eq!(
+            (404, String::from("{\"error\":\"There's no client 1.\"}\n")),
+            request(&mut service, Method::Get, "/clients/1", None, "")
+        );
+
+        assert_eq!(
+            400,
+            request(&mut service, Method::Get, "/clients/x", None, "").0
+        );
+        assert_eq!(
+            405,
+            request(&mut service, Method::Get, "/events", None, "").0
+        );
+        assert_eq!(404, request(&mut service, Method::Get, "/", None, "").0);
+        assert_eq!(
+            405,
+            request(&mut service, Method::Post, "/metrics", None, "").0
+        );
+    }
+
+    #[test]
+    fn test_tenants() {
+        let mut service = service();
+        service
+            .add_tenant(
+                "wallets",
+                Engine::builder().withdrawal_disputes(WithdrawalDisputePolicy::Reject),
+                None::<File>,
+            )
+            .expect("Expected no errors.");
+
+        let events = concat!(
+            r#"{"type":"deposit","client":1,"tx":1,"amount":"10"}"#,
+            "\n",
+            r#"{"type":"withdrawal","client":1,"tx":2,"amount":"4"}"#,
+            "\n",
+            r#"{"type":"dispute","client":1,"tx":2}"#,
+            "\n",
+        );
+        let (_, body) = request(&mut service, Method::Post, "/events", None, events);
+        assert_eq!("{\"processed\":3,\"rejected\":0,\"rejections\":[]}\n", body);
+        // the same events, under the tenant's own policies
+        let (status, body) = request(
+            &mut service,
+            Method::Post,
+            "/tenants/wallets/events",
+            None,
+            events,
+        );
+        assert_eq!(200, status);
+        assert!(body.contains("\"processed\":3,\"rejected\":1"));
+
+        let (_, default) = request(&mut service, Method::Get, "/clients/1", None, "");
+        let (_, wallets) = request(
+            &mut service,
+            Method::Get,
+            "/tenants/wallets/clients/1",
+            None,
+            "",
+        );
+        assert!(default.contains("\"held\":\"4"));
+        assert!(wallets.contains("\"held\":\"0"));
+
+        assert_eq!(
+            (
+                404,
+                String::from("{\"error\":\"There's no tenant cards.\"}\n")
+            ),
+            request(&mut service, Method::Get, "/tenants/cards/report", None, "")
+        );
+        assert_eq!(
+            404,
+            request(
+                &mut service,
+                Method::Get,
+                "/tenants/wallets/nothing",
+                None,
+                ""
+            )
+            .0
+        );
+    }
+
+    #[test]
+    fn test_get_metrics() {
+        let mut service = service();
+        request(
+            &mut service,
+            Method::Post,
+            "/events",
+            None,
+            concat!(
+                r#"{"type":"deposit","client":1,"tx":1,"amount":"10"}"#,
+                "\n",
+                r#"{"type":"withdrawal","client":1,"tx":2,"amount":"20"}"#,
+                "\n",
+            ),
+        );
+
+        let reply = service.handle(&Method::Get, "/metrics", None, &mut io::empty());
+        assert_eq!(200, reply.status);
+        assert!(reply.content_type.starts_with("text/plain"));
+        let body = String::from_utf8(reply.body).expect("Not UTF-8");
+        for line in [
+            "challenge_events_processed_total{kind=\"deposit\"} 1",
+            "challenge_events_processed_total{kind=\"withdrawal\"} 1",
+            "challenge_events_rejected_total{reason=\"insufficient_funds\"} 1",
+            "challenge_clients 1",
+            "challenge_locked_accounts 0",
+        ] {
+            assert!(
+                body.lines().any(|l| l == line),
+                "{} isn't in {}",
+                line,
+                body
+            );
+        }
+    }
+
+    fn parse_event(line: &str) -> SourcedEvent {
+        parse_line(InputFormat::JsonLines, None, line).expect("Expected a valid event")
+    }
+
+    // Keeps what's published to it where the test can see it.
+    struct PublishedDeltas(Rc<RefCell<Vec<(String, ClientID, String)>>>);
+
+    impl DeltaSink for PublishedDeltas {
+        fn publish(
+            &mut self,
+            tenant: &str,
+            client_id: ClientID,
+            payload: &[u8],
+        ) -> Result<(), Box<dyn Error>> {
+            let payload = String::from_utf8(payload.to_vec())?;
+            self.0
+                .borrow_mut()
+                .push((String::from(tenant), client_id, payload));
+            Ok(())
+        }
+
+        fn flush(&mut self) -> Result<(), Box<dyn Error>> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_publish_deltas() {
+        let published = Rc::new(RefCell::new(Vec::new()));
+        let mut service =
+            service().with_delta_sink(Some(Box::new(PublishedDeltas(Rc::clone(&published)))));
+        service
+            .add_tenant("wallets", Engine::builder(), None::<File>)
+            .expect("Expected no errors.");
+
+        request(
+            &mut service,
+            Method::Post,
+            "/events",
+            None,
+            concat!(
+                r#"{"type":"deposit","client":1,"tx":1,"amount":"10"}"#,
+                "\n",
+                r#"{"type":"withdrawal","client":1,"tx":2,"amount":"20"}"#,
+                "\n",
+                r#"{"type":"dispute","client":1,"tx":1}"#,
+                "\n",
+            ),
+        );
+        // the rejected withdrawal didn't change anything
+        assert_eq!(
+            vec![
+                (
+                    String::from(DEFAULT_TENANT),
+                    1,
+                    concat!(
+                        r#"{"client":1,"locked":false,"lock_changed":false,"balances":"#,
+                        r#"[{"currency":"","available":"10.0000","held":"0.0000","total":"10.0000"}]}"#,
+                        "\n"
+                    )
+                    .to_string()
+                ),
+                (
+                    String::from(DEFAULT_TENANT),
+                    1,
+                    concat!(
+                        r#"{"client":1,"locked":false,"lock_changed":false,"balances":"#,
+                        r#"[{"currency":"","available":"0.0000","held":"10.0000","total":"10.0000"}]}"#,
+                        "\n"
+                    )
+                    .to_string()
+                ),
+            ],
+            published.take()
+        );
+
+        service.stream_event(
+            DEFAULT_TENANT,
+            parse_event(r#"{"type":"chargeback","client":1,"tx":1}"#),
+        );
+        assert_eq!(
+            vec![(
+                String::from(DEFAULT_TENANT),
+                1,
+                concat!(
+                    r#"{"client":1,"locked":true,"lock_changed":true,"balances":"#,
+                    r#"[{"currency":"","available":"0.0000","held":"0.0000","total":"0.0000"}]}"#,
+                    "\n"
+                )
+                .to_string()
+            )],
+            published.take()
+        );
+
+        // another tenant's client 1 is someone else
+        request(
+            &mut service,
+            Method::Post,
+            "/tenants/wallets/events",
+            None,
+            concat!(r#"{"type":"deposit","client":1,"tx":1,"amount":"5"}"#, "\n"),
+        );
+        assert_eq!(
+            vec![(
+                String::from("wallets"),
+                1,
+                concat!(
+                    r#"{"client":1,"locked":false,"lock_changed":false,"balances":"#,
+                    r#"[{"currency":"","available":"5.0000","held":"0.0000","total":"5.0000"}]}"#,
+                    "\n"
+                )
+                .to_string()
+            )],
+            published.take()
+        );
+    }
+
+    #[test]
+    fn test_stream_event() {
+        let mut service = service();
+
+        let outcome = service.stream_event(
+            DEFAULT_TENANT,
+            parse_event(r#"{"type":"deposit","client":1,"tx":1,"amount":"10"}"#),
+        );
+        assert_eq!("accepted 1", outcome.to_line(1));
+        let outcome = service.stream_event(
+            DEFAULT_TENANT,
+            parse_event(r#"{"type":"withdrawal","client":1,"tx":2,"amount":"20"}"#),
+        );
+        assert_eq!(
+            "rejected 2 insufficient_funds: Insufficient funds.",
+            outcome.to_line(2)
+        );
+        service.stream_event(
+            DEFAULT_TENANT,
+            parse_event(r#"{"type":"withdrawal","client":1,"tx":3,"amount":"4"}"#),
+        );
+
+        // CSV has to come with its header
+        let invalid = parse_line(InputFormat::Csv, Some("type,client,tx,amount"), "deposit,x")
+            .expect_err("Expected an invalid line");
+        assert!(invalid.to_line(5).starts_with("invalid 5: "));
+
+        // it's the same engine as the rest of the API
+        assert_eq!(
+            request(&mut service, Method::Get, "/clients/1", None, "").1,
+            concat!(
+                r#"[{"client":1,"available":"6.0000","held":"0.0000","total":"6.0000","locked":false}]"#,
+                "\n"
+            )
+        );
+        assert_eq!(
+            426,
+            request(&mut service, Method::Get, "/events/stream", None, "").0
+        );
+    }
+
+    // Keeps the snapshot where the test can see it.
+    struct SavedSnapshot(Rc<RefCell<Option<Vec<u8>>>>);
+
+    impl StateSnapshots for SavedSnapshot {
+        fn load(&mut self, _tenant: &str) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+            Ok(self.0.borrow().clone())
+        }
+
+        fn save(&mut self, _tenant: &str, snapshot: &[u8]) -> Result<(), Box<dyn Error>> {
+            *self.0.borrow_mut() = Some(snapshot.to_vec());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_take_snapshot() {
+        let saved = Rc::new(RefCell::new(None));
+        let mut service = service().with_snapshots(
+            Some(Box::new(SavedSnapshot(Rc::clone(&saved)))),
+            Duration::from_secs(60),
+        );
+
+        // there's nothing to save yet
+        service.take_snapshot();
+        assert_eq!(None, saved.take());
+
+        request(
+            &mut service,
+            Method::Post,
+            "/events",
+            None,
+            concat!(
+                r#"{"type":"deposit","client":1,"tx":1,"amount":"10"}"#,
+                "
+",
+                r#"{"type":"dispute","client":1,"tx":1}"#,
+                "
+",
+            ),
+        );
+        service.take_snapshot();
+        let snapshot = saved.take().expect("Expected a snapshot");
+        // nor is there now, until something else is processed
+        service.take_snapshot();
+        assert_eq!(None, saved.take());
+
+        let mut resumed = Service::new(Engine::builder(), Some(snapshot.as_slice()))
+            .expect("Expected no errors.");
+        assert_eq!(
+            request(&mut service, Method::Get, "/report", None, ""),
+            request(&mut resumed, Method::Get, "/report", None, "")
+        );
+        // the dispute's still open
+        let (_, body) = request(
+            &mut resumed,
+            Method::Post,
+            "/events",
+            None,
+            r#"{"type":"resolve","client":1,"tx":1}"#,
+        );
+        assert_eq!("{\"processed\":1,\"rejected\":0,\"rejections\":[]}\n", body);
+    }
+
+    // Starts a service on a thread of its own, which runs for as long as the
+    // tests do. The service isn't Send, so it's made on that thread.
+    fn start_service(listeners: Listeners) -> SocketAddr {
+        let server = Server::http("127.0.0.1:0").expect("Expected a server");
+        let address = server
+            .server_addr()
+            .to_ip()
+            .expect("Expected an IP address");
+        thread::spawn(move || service().run(&server, listeners));
+        address
+    }
+
+    #[test]
+    fn test_stream_over_websocket() {
+        let address = start_service(Listeners::default());
+
+        let (mut socket, _) = tungstenite::connect(format!("ws://{}/events/stream", address))
+            .expect("Expected to connect");
+        socket
+            .send(Message::Text(String::from(concat!(
+                r#"{"type":"deposit","client":1,"tx":1,"amount":"10"}"#,
+                "\n",
+                r#"{"type":"withdrawal","client":1,"tx":2,"amount":"20"}"#,
+            ))))
+            .expect("Expected to send");
+
+        let mut acks = Vec::new();
+        for _ in 0..2 {
+            match socket.read().expect("Expected an ack") {
+                Message::Text(ack) => acks.push(ack),
+                message => panic!("Unexpected message: {:?}", message),
+            }
+        }
+        assert_eq!(
+            vec![
+                r#"{"line":1,"status":"accepted"}"#,
+                r#"{"line":2,"status":"rejected","code":"insufficient_funds","message":"Insufficient funds."}"#,
+            ],
+            acks
+        );
+        socket.close(None).expect("Expected to close");
+    }
+
+    #[test]
+    fn test_stream_over_tcp() {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("Expected a listener");
+        let address = listener.local_addr().expect("Expected an address");
+        start_service(Listeners {
+            tcp: Some(listener),
+            #[cfg(feature = "flight")]
+            flight: None,
+            #[cfg(feature = "nats")]
+            nats: None,
+        });
+
+        let connection = TcpStream::connect(address).expect("Expected to connect");
+        write!(
+            &connection,
+            "type,client,tx,amount\ndeposit,1,1,10\nwithdrawal,1,2,20\noops\n"
+        )
+        .expect("Expected to send");
+        let mut replies = BufReader::new(&connection).lines();
+        let mut reply = || {
+            replies
+                .next()
+                .expect("Expected a reply")
+                .expect("Expected to read")
+        };
+        assert_eq!("accepted 2", reply());
+        assert_eq!(
+            "rejected 3 insufficient_funds: Insufficient funds.",
+            reply()
+        );
+        assert!(reply().starts_with("invalid 4: "));
+
+        // a connection that doesn't start with the header is turned away
+        let mut connection = TcpStream::connect(address).expect("Expected to connect");
+        writeln!(connection, "deposit,1,3,10").expect("Expected to send");
+        let mut reply = String::new();
+        connection
+            .read_to_string(&mut reply)
+            .expect("Expected to read");
+        assert_eq!(
+            "error: The first line has to be the CSV header, e.g. type,client,tx,amount.\n",
+            reply
+        );
+    }
+
+    #[cfg(feature = "flight")]
+    #[tokio::test]
+    async fn test_balances_over_flight() {
+        use arrow_array::{cast::AsArray, types::Decimal128Type};
+        use arrow_flight::{FlightClient, FlightDescriptor, Ticket};
+        use tokio_stream::StreamExt;
+
+        let tcp = TcpListener::bind("127.0.0.1:0").expect("Expected a listener");
+        let tcp_address = tcp.local_addr().expect("Expected an address");
+        let flight = TcpListener::bind("127.0.0.1:0").expect("Expected a listener");
+        let flight_address = flight.local_addr().expect("Expected an address");
+        start_service(Listeners {
+            tcp: Some(tcp),
+            flight: Some(flight),
+            #[cfg(feature = "nats")]
+            nats: None,
+        });
+
+        let connection = TcpStream::connect(tcp_address).expect("Expected to connect");
+        write!(&connection, "type,client,tx,amount\ndeposit,1,1,10\n").expect("Expected to send");
+        let mut reply = String::new();
+        BufReader::new(&connection)
+            .read_line(&mut reply)
+            .expect("Expected to read");
+        assert_eq!("accepted 2\n", reply);
+
+        let channel = tonic::transport::Channel::from_shared(format!("http://{}", flight_address))
+            .expect("Expected a valid address")
+            .connect()
+            .await
+            .expect("Expected to connect");
+        let mut client = FlightClient::new(channel);
+        let info = client
+            .get_flight_info(FlightDescriptor::new_path(vec![String::from("balances")]))
+            .await
+            .expect("Expected the flight");
+        let ticket = info.endpoint[0].ticket.clone().expect("Expected a ticket");
+        assert_eq!(Ticket::new("balances"), ticket);
+
+        let batches = client
+            .do_get(ticket)
+            .await
+            .expect("Expected the balances")
+            .collect::<Result<Vec<_>, _>>()
+            .await
+            .expect("Expected the balances");
+        assert_eq!(1, batches.len());
+        let available = batches[0]
+            .column_by_name("available")
+            .expect("Expected an available column")
+            .as_primitive::<Decimal128Type>();
+        assert_eq!(10_0000, available.value(0));
+        assert!(client.do_get(Ticket::new("nope")).await.is_err());
+    }
+}
@@ -0,0 +1,262 @@
+use super::StateStore;
+use crate::model::{Client, ClientID, CompactTransaction, Transaction, TransactionID};
+
+use std::{collections::HashMap, io};
+
+// A store that keeps transactions packed as `CompactTransaction`s, which take
+// 20 bytes each rather than 80 (before the map's own overhead). Anything that
+// can't be packed (one that's been disputed, an amount with more decimal
+// places than the input allows, or a timestamp past 2106) is kept as it is
+// instead.
+//
+// The processor works on a `Transaction`, so whichever one it last looked up
+// is unpacked and held here until the next lookup, when it's packed again.
+#[derive(Default)]
+pub struct CompactStore {
+    clients_by_id: HashMap<ClientID, Client>,
+    compact_by_id: HashMap<TransactionID, CompactTransaction>,
+    unpackable_by_id: HashMap<TransactionID, Transaction>,
+    unpacked: Option<(TransactionID, Transaction)>,
+}
+
+impl CompactStore {
+    fn insert(&mut self, transaction_id: TransactionID, transaction: Transaction) {
+        match transaction.to_compact() {
+            Some(compact) => {
+                self.unpackable_by_id.remove(&transaction_id);
+                self.compact_by_id.insert(transaction_id, compact);
+            }
+            None => {
+                self.compact_by_id.remove(&transaction_id);
+                self.unpackable_by_id.insert(transaction_id, transaction);
+            }
+        }
+    }
+
+    fn pack_unpacked(&mut self) {
+        if let Some((transaction_id, transaction)) = self.unpacked.take() {
+            self.insert(transaction_id, transaction);
+        }
+    }
+
+    fn unpack(&mut self, transaction_id: TransactionID) -> Option<&mut Transaction> {
+        if self
+            .unpacked
+            .as_ref()
+            .is_none_or(|(unpacked_id, _)| *unpacked_id != transaction_id)
+        {
+            self.pack_unpacked();
+            if let Some(compact) = self.compact_by_id.get(&transaction_id) {
+                self.unpacked = Some((transaction_id, compact.expand()));
+            }
+        }
+
+        match self.unpacked.as_mut() {
+            Some((unpacked_id, transaction)) if *unpacked_id == transaction_id => Some(transaction),
+            _ => self.unpackable_by_id.get_mut(&transaction_id),
+        }
+    }
+}
+
+impl StateStore for CompactStore {
+    fn clients(&self) -> &HashMap<ClientID, Client> {
+        &self.clients_by_id
+    }
+
+    fn clients_mut(&mut self) -> &mut HashMap<ClientID, Client> {
+        &mut self.clients_by_id
+    }
+
+    // The unpacked transaction is still in `compact_by_id`, just out of date.
+    fn contains_transaction(&self, transaction_id: TransactionID) -> bool {
+        self.compact_by_id.contains_key(&transaction_id)
+            || self.unpackable_by_id.contains_key(&transaction_id)
+    }
+
+    fn transaction_count(&self) -> usize {
+        self.compact_by_id.len() + self.unpackable_by_id.len()
+    }
+
+    fn transaction_mut(&mut self, transaction_id: TransactionID) -> Option<&mut Transaction> {
+        self.unpack(transaction_id)
+    }
+
+    fn transaction_and_clients_mut(
+        &mut self,
+        transaction_id: TransactionID,
+    ) -> (Option<&mut Transaction>, &mut HashMap<ClientID, Client>) {
+        // `unpack` borrows all of `self`, so it's done first and then the
+        // fields are borrowed separately
+        self.unpack(transaction_id);
+        let transaction = match self.unpacked.as_mut() {
+            Some((unpacked_id, transaction)) if *unpacked_id == transaction_id => Some(transaction),
+            _ => self.unpackable_by_id.get_mut(&transaction_id),
+        };
+        (transaction, &mut self.clients_by_id)
+    }
+
+    fn insert_transaction(&mut self, transaction_id: TransactionID, transaction: Transaction) {
+        if self
+            .unpacked
+            .as_ref()
+            .is_some_and(|(unpacked_id, _)| *unpacked_id == transaction_id)
+        {
+            self.unpacked = None;
+        }
+        self.insert(transaction_id, transaction);
+    }
+
+    fn for_each_transaction(
+        &mut self,
+        f: &mut dyn FnMut(TransactionID, &Transaction) -> io::Result<()>,
+    ) -> io::Result<()> {
+        self.pack_unpacked();
+        for (transaction_id, compact) in &self.compact_by_id {
+            f(*transaction_id, &compact.expand())?;
+        }
+        self.unpackable_by_id
+            .iter()
+            .try_for_each(|(transaction_id, transaction)| f(*transaction_id, transaction))
+    }
+
+    fn into_maps(
+        mut self: Box<Self>,
+    ) -> io::Result<(
+        HashMap<ClientID, Client>,
+        HashMap<TransactionID, Transaction>,
+    )> {
+        self.pack_unpacked();
+        let mut transactions_by_id = self.unpackable_by_id;
+        transactions_by_id.extend(
+            self.compact_by_id
+                .iter()
+                .map(|(transaction_id, compact)| (*transaction_id, compact.expand())),
+        );
+
+        Ok((self.clients_by_id, transactions_by_id))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::model::{Currency, DisputeStatus, TransactionKind};
+    use pretty_assertions::assert_eq;
+    use rust_decimal_macros::dec;
+    use std::mem;
+
+    #[test]
+    fn test_compact_store() {
+        assert_eq!(20, mem::size_of::<CompactTransaction>());
+        assert_eq!(80, mem::size_of::<Transaction>());
+        // which is what each one costs in the map, give or take its overhead
+        assert_eq!(24, mem::size_of::<(TransactionID, CompactTransaction)>());
+
+        let mut store = CompactStore::default();
+        store.insert_transaction(
+            1,
+            Transaction::new(1, Currency::default(), dec!(10), TransactionKind::Deposit)
+                .with_timestamp(Some(1_700_000_000)),
+        );
+        store.insert_transaction(
+            2,
+            Transaction::new(
+                2,
+                Currency::default(),
+                dec!(5.5),
+                TransactionKind::Withdrawal,
+            ),
+        );
+        // too many decimal places to pack
+        store.insert_transaction(
+            3,
+            Transaction::new(
+                1,
+                Currency::default(),
+                dec!(0.00001),
+                TransactionKind::Deposit,
+            ),
+        );
+        // too far in the future to pack
+        store.insert_transaction(
+            5,
+            Transaction::new(1, Currency::default(), dec!(1), TransactionKind::Deposit)
+                .with_timestamp(Some(1 << 32)),
+        );
+        assert_eq!(2, store.compact_by_id.len());
+        assert_eq!(2, store.unpackable_by_id.len());
+
+        // changes to the unpacked transaction are kept once it's packed again,
+        // and one that can no longer be packed is kept as it is
+        store
+            .transaction_mut(1)
+            .expect("Expected transaction 1")
+            .dispute(dec!(2.5), 1);
+        store
+            .transaction_mut(2)
+            .expect("Expected transaction 2")
+            .dispute(dec!(0.00002), 2);
+        store.transaction_mut(3).expect("Expected transaction 3");
+        assert!(store.transaction_mut(4).is_none());
+        assert!([1, 2, 3, 5]
+            .into_iter()
+            .all(|transaction_id| store.contains_transaction(transaction_id)));
+
+        let (_, transactions_by_id) = Box::new(store).into_maps().expect("Expected no errors.");
+        let summary = |transaction_id| {
+            let transaction: &Transaction = &transactions_by_id[&transaction_id];
+            (
+                transaction.client_id(),
+                transaction.amount(),
+                transaction.kind() == &TransactionKind::Deposit,
+                transaction.dispute_status(),
+                transaction.disputed_amount(),
+                transaction.timestamp(),
+            )
+        };
+        assert_eq!(
+            (
+                1,
+                dec!(10),
+                true,
+                DisputeStatus::Disputed,
+                dec!(2.5),
+                Some(1_700_000_000)
+            ),
+            summary(1)
+        );
+        assert_eq!(
+            (
+                2,
+                dec!(5.5),
+                false,
+                DisputeStatus::Disputed,
+                dec!(0.00002),
+                None
+            ),
+            summary(2)
+        );
+        assert_eq!(
+            (
+                1,
+                dec!(0.00001),
+                true,
+                DisputeStatus::Undisputed,
+                dec!(0),
+                None
+            ),
+            summary(3)
+        );
+        assert_eq!(
+            (
+                1,
+                dec!(1),
+                true,
+                DisputeStatus::Undisputed,
+                dec!(0),
+                Some(1 << 32)
+            ),
+            summary(5)
+        );
+    }
+}
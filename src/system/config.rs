@@ -0,0 +1,302 @@
+use super::{FeeSchedule, ResourceLimits};
+use crate::model::{Amount, ClientID};
+
+use std::{collections::HashMap, str::FromStr, time::Duration};
+
+// Settings that change how events are processed, as opposed to how the
+// results are reported.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct EngineConfig {
+    pub fee_schedule: FeeSchedule,
+    pub withdrawal_disputes: WithdrawalDisputePolicy,
+    // Whether a charged back transaction can be disputed again, for networks
+    // with second-presentment cycles.
+    pub allow_redispute: bool,
+    // Whether reversing a chargeback unlocks the account, as long as it has no
+    // other chargebacks standing against it.
+    pub unlock_on_chargeback_reversal: bool,
+    pub locked_account_disputes: LockedAccountPolicy,
+    pub locked_account_deposits: LockedDepositPolicy,
+    pub undisputed_chargebacks: UndisputedChargebackPolicy,
+    // Clients with an authorized overdraft, and how far they may go below
+    // zero. Everyone else has to stay above it.
+    pub credit_limits: HashMap<ClientID, Amount>,
+    pub closed_accounts: ClosedAccountPolicy,
+    // How long after a transaction it can still be disputed, as card networks
+    // require. Only enforced when both events have a timestamp.
+    pub dispute_window: Option<Duration>,
+    // How long a dispute can stay open before it lapses and is resolved in the
+    // client's favour, as networks do with disputes nobody answers. Only
+    // disputes with a timestamp can lapse, and only once an event with a later
+    // timestamp says that long has passed.
+    pub dispute_expiry: Option<Duration>,
+    // How many events can be held back to put them in timestamp order before
+    // they're processed, for sources that don't always deliver them in order.
+    pub reorder_window: usize,
+    // Whether to only keep the transactions that can be disputed, which leaves
+    // out withdrawals when the policy rejects disputing them. They're most of
+    // what's stored otherwise, and there's nothing to look them up for except
+    // a reversal.
+    pub disputable_only: bool,
+    pub duplicate_transactions: DuplicateTransactionPolicy,
+    // Whether to check after every event that the state is still consistent
+    // (no negative held funds, nothing changed on an account that shouldn't
+    // have been, and so on), panicking if it isn't. It costs a few lookups per
+    // event, so it's for tracking down bugs rather than for every run.
+    pub check_invariants: bool,
+    pub limits: ResourceLimits,
+    // How many chargebacks a client can have before `chargeback_limit_action`
+    // is taken against them, for fraud rules like three strikes. Every
+    // chargeback counts, even ones that were later reversed, the same as the
+    // `chargeback_count` column.
+    pub chargeback_limit: Option<u32>,
+    pub chargeback_limit_action: ChargebackLimitAction,
+}
+
+// What disputing a withdrawal does. Payment networks disagree on this, so it
+// depends on where the input came from.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum WithdrawalDisputePolicy {
+    // The withdrawn amount is held on top of what's already held, so the
+    // available funds go down until the dispute is resolved or charged back.
+    #[default]
+    Hold,
+    // Only deposits can be disputed, and disputing a withdrawal is rejected.
+    Reject,
+    // The withdrawn amount is credited back straight away but held, so the
+    // available funds stay the same and a chargeback releases it.
+    CreditHeld,
+}
+
+// What happens to disputes, resolves and chargebacks on a locked account.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LockedAccountPolicy {
+    // They go through as usual, so held funds can still change.
+    #[default]
+    Process,
+    // They're set aside until the account is unlocked. Anything still set
+    // aside at the end of the run is rejected.
+    Queue,
+    // They're rejected.
+    Reject,
+}
+
+// What happens to money paid into a locked account, i.e. deposits, incoming
+// transfers and interest. Whatever the policy, nothing can be taken out.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LockedDepositPolicy {
+    // It's rejected like anything else at the client's request.
+    #[default]
+    Reject,
+    // It goes through, as many payment schemes allow, so that e.g. a refund
+    // doesn't bounce off a frozen account.
+    Accept,
+}
+
+// What happens to a chargeback on a transaction that isn't disputed. Some
+// acquirers send chargebacks without a dispute before them.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum UndisputedChargebackPolicy {
+    // It's rejected, on the assumption that it was sent in error.
+    #[default]
+    Reject,
+    // It's treated as a dispute of whatever hasn't been charged back yet,
+    // followed straight away by the chargeback.
+    ImplicitDispute,
+}
+
+// What a closed account still allows. Dispute steps, reversals and adjustments
+// always go through, since they're about what happened before it was closed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ClosedAccountPolicy {
+    // Nothing else can move money in or out of it.
+    #[default]
+    Reject,
+    // The client can still withdraw whatever was left in it, including by
+    // transferring it to someone else, but nothing else is allowed.
+    AllowWithdrawals,
+}
+
+// What happens to a deposit or withdrawal whose ID is already taken.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DuplicateTransactionPolicy {
+    // It's rejected.
+    #[default]
+    Reject,
+    // If it's exactly the same as the transaction already under that ID (same
+    // client, kind, currency and amount) it's taken to be a re-delivery, e.g.
+    // from a replayed queue, and does nothing. Anything else reusing the ID is
+    // still rejected.
+    IgnoreIdentical,
+}
+
+// What happens to a client once they reach the chargeback limit. Either way
+// they're flagged, which the `flagged` column reports.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ChargebackLimitAction {
+    // Their account is locked for good, so reversing chargebacks doesn't
+    // unlock it even when `unlock_on_chargeback_reversal` is set.
+    #[default]
+    Lock,
+    // Nothing else changes, and it's up to whoever reads the report to act on
+    // it.
+    Flag,
+}
+
+impl WithdrawalDisputePolicy {
+    // The name this policy is chosen by, as accepted by `from_str`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            WithdrawalDisputePolicy::Hold => "hold",
+            WithdrawalDisputePolicy::Reject => "reject",
+            WithdrawalDisputePolicy::CreditHeld => "credit-held",
+        }
+    }
+}
+
+impl LockedAccountPolicy {
+    // The name this policy is chosen by, as accepted by `from_str`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            LockedAccountPolicy::Process => "process",
+            LockedAccountPolicy::Queue => "queue",
+            LockedAccountPolicy::Reject => "reject",
+        }
+    }
+}
+
+impl LockedDepositPolicy {
+    // The name this policy is chosen by, as accepted by `from_str`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            LockedDepositPolicy::Reject => "reject",
+            LockedDepositPolicy::Accept => "accept",
+        }
+    }
+}
+
+impl UndisputedChargebackPolicy {
+    // The name this policy is chosen by, as accepted by `from_str`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            UndisputedChargebackPolicy::Reject => "reject",
+            UndisputedChargebackPolicy::ImplicitDispute => "implicit-dispute",
+        }
+    }
+}
+
+impl ClosedAccountPolicy {
+    // The name this policy is chosen by, as accepted by `from_str`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            ClosedAccountPolicy::Reject => "reject",
+            ClosedAccountPolicy::AllowWithdrawals => "allow-withdrawals",
+        }
+    }
+}
+
+impl DuplicateTransactionPolicy {
+    // The name this policy is chosen by, as accepted by `from_str`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            DuplicateTransactionPolicy::Reject => "reject",
+            DuplicateTransactionPolicy::IgnoreIdentical => "ignore-identical",
+        }
+    }
+}
+
+impl ChargebackLimitAction {
+    // The name this action is chosen by, as accepted by `from_str`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            ChargebackLimitAction::Lock => "lock",
+            ChargebackLimitAction::Flag => "flag",
+        }
+    }
+}
+
+impl FromStr for WithdrawalDisputePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "hold" => Ok(WithdrawalDisputePolicy::Hold),
+            "reject" => Ok(WithdrawalDisputePolicy::Reject),
+            "credit-held" => Ok(WithdrawalDisputePolicy::CreditHeld),
+            _ => Err(format!("Unknown withdrawal dispute policy: {}.", s)),
+        }
+    }
+}
+
+impl FromStr for LockedAccountPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "process" => Ok(LockedAccountPolicy::Process),
+            "queue" => Ok(LockedAccountPolicy::Queue),
+            "reject" => Ok(LockedAccountPolicy::Reject),
+            _ => Err(format!("Unknown locked account policy: {}.", s)),
+        }
+    }
+}
+
+impl FromStr for LockedDepositPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reject" => Ok(LockedDepositPolicy::Reject),
+            "accept" => Ok(LockedDepositPolicy::Accept),
+            _ => Err(format!("Unknown locked deposit policy: {}.", s)),
+        }
+    }
+}
+
+impl FromStr for UndisputedChargebackPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reject" => Ok(UndisputedChargebackPolicy::Reject),
+            "implicit-dispute" => Ok(UndisputedChargebackPolicy::ImplicitDispute),
+            _ => Err(format!("Unknown undisputed chargeback policy: {}.", s)),
+        }
+    }
+}
+
+impl FromStr for ClosedAccountPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reject" => Ok(ClosedAccountPolicy::Reject),
+            "allow-withdrawals" => Ok(ClosedAccountPolicy::AllowWithdrawals),
+            _ => Err(format!("Unknown closed account policy: {}.", s)),
+        }
+    }
+}
+
+impl FromStr for DuplicateTransactionPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reject" => Ok(DuplicateTransactionPolicy::Reject),
+            "ignore-identical" => Ok(DuplicateTransactionPolicy::IgnoreIdentical),
+            _ => Err(format!("Unknown duplicate transaction policy: {}.", s)),
+        }
+    }
+}
+
+impl FromStr for ChargebackLimitAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "lock" => Ok(ChargebackLimitAction::Lock),
+            "flag" => Ok(ChargebackLimitAction::Flag),
+            _ => Err(format!("Unknown chargeback limit action: {}.", s)),
+        }
+    }
+}
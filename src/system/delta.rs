@@ -0,0 +1,80 @@
+use super::invariants::balances;
+use crate::model::{Balance, Client, ClientID, Currency};
+
+use std::collections::{BTreeMap, HashMap};
+
+// A client's account as far as anything downstream is concerned: what's in
+// each currency, and whether it's locked. Currencies with nothing in them are
+// left out.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AccountState {
+    pub balances: BTreeMap<Currency, Balance>,
+    pub locked: bool,
+}
+
+impl AccountState {
+    // A client that doesn't exist yet is treated as having nothing in it.
+    pub fn of(client: Option<&Client>) -> Self {
+        Self {
+            balances: balances(client),
+            locked: client.is_some_and(Client::locked),
+        }
+    }
+}
+
+// How a client's account changed, as it was before and as it is after.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StateDelta {
+    pub client_id: ClientID,
+    pub before: AccountState,
+    pub after: AccountState,
+}
+
+// What came of a batch of events handed to `Processor::process_batch`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BatchResult {
+    pub accepted: u64,
+    pub rejected: u64,
+    // Dispute steps set aside for a locked account, which get counted once
+    // they're finally processed (in whichever batch unlocks the account).
+    pub queued: u64,
+    // How each client the batch changed went from before the batch to after
+    // it, ordered by client ID.
+    pub deltas: Vec<StateDelta>,
+}
+
+// Remembers how each client stood before the first event that touched them,
+// so that the deltas can be worked out at the end.
+#[derive(Default)]
+pub(crate) struct DeltaTracker {
+    before: BTreeMap<ClientID, AccountState>,
+}
+
+impl DeltaTracker {
+    pub(crate) fn capture(
+        &mut self,
+        clients_by_id: &HashMap<ClientID, Client>,
+        client_ids: &[ClientID],
+    ) {
+        for client_id in client_ids {
+            self.before
+                .entry(*client_id)
+                .or_insert_with(|| AccountState::of(clients_by_id.get(client_id)));
+        }
+    }
+
+    // Clients that ended up as they started are left out.
+    pub(crate) fn finish(self, clients_by_id: &HashMap<ClientID, Client>) -> Vec<StateDelta> {
+        self.before
+            .into_iter()
+            .filter_map(|(client_id, before)| {
+                let after = AccountState::of(clients_by_id.get(&client_id));
+                (after != before).then_some(StateDelta {
+                    client_id,
+                    before,
+                    after,
+                })
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,145 @@
+use crate::model::{Amount, ClientID, Currency};
+
+use std::collections::{BTreeSet, HashMap};
+
+// A client as it appears in a previously written report, as opposed to a
+// `Client` in the middle of processing.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ReportedClient {
+    pub available: Amount,
+    pub held: Amount,
+    pub total: Amount,
+    pub locked: bool,
+}
+
+// A report has a row per client per currency, so that's what rows are keyed
+// by.
+pub type ReportKey = (ClientID, Currency);
+
+// How a client's balance in one currency changed between two reports.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClientDelta {
+    pub client_id: ClientID,
+    pub currency: Currency,
+    pub available: Amount,
+    pub held: Amount,
+    pub total: Amount,
+    pub newly_locked: bool,
+}
+
+// Compares two reports (e.g. consecutive nightly runs) and returns how each
+// client's balance in each currency changed, ordered by client ID and then
+// currency. Balances that didn't change are left out, and one missing from
+// one of the reports is treated as having had nothing in it.
+pub fn diff_reports(
+    old: &HashMap<ReportKey, ReportedClient>,
+    new: &HashMap<ReportKey, ReportedClient>,
+) -> Vec<ClientDelta> {
+    let keys = old.keys().chain(new.keys()).collect::<BTreeSet<_>>();
+
+    keys.into_iter()
+        .filter_map(|key| {
+            let before = old.get(key).copied().unwrap_or_default();
+            let after = new.get(key).copied().unwrap_or_default();
+
+            let delta = ClientDelta {
+                client_id: key.0,
+                currency: key.1,
+                available: after.available - before.available,
+                held: after.held - before.held,
+                total: after.total - before.total,
+                newly_locked: after.locked && !before.locked,
+            };
+            let unchanged = delta.available.is_zero()
+                && delta.held.is_zero()
+                && delta.total.is_zero()
+                && !delta.newly_locked;
+
+            (!unchanged).then_some(delta)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use rust_decimal_macros::dec;
+
+    fn reported(available: Amount, held: Amount, locked: bool) -> ReportedClient {
+        ReportedClient {
+            available,
+            held,
+            total: available + held,
+            locked,
+        }
+    }
+
+    #[test]
+    fn test_diff_reports() {
+        let eur = "EUR".parse().expect("Valid currency");
+        let old = HashMap::from([
+            ((1, Currency::default()), reported(dec!(10), dec!(0), false)),
+            ((2, Currency::default()), reported(dec!(5), dec!(0), false)),
+            ((3, Currency::default()), reported(dec!(7), dec!(3), false)),
+            ((3, eur), reported(dec!(1), dec!(0), false)),
+        ]);
+        let new = HashMap::from([
+            ((1, Currency::default()), reported(dec!(10), dec!(0), false)),
+            // a new currency doesn't take anything from the old one
+            ((1, eur), reported(dec!(4), dec!(0), false)),
+            ((3, Currency::default()), reported(dec!(7), dec!(0), true)),
+            ((3, eur), reported(dec!(1), dec!(0), true)),
+            (
+                (4, Currency::default()),
+                reported(dec!(2.5), dec!(0), false),
+            ),
+        ]);
+
+        assert_eq!(
+            vec![
+                ClientDelta {
+                    client_id: 1,
+                    currency: eur,
+                    available: dec!(4),
+                    held: dec!(0),
+                    total: dec!(4),
+                    newly_locked: false,
+                },
+                ClientDelta {
+                    client_id: 2,
+                    currency: Currency::default(),
+                    available: dec!(-5),
+                    held: dec!(0),
+                    total: dec!(-5),
+                    newly_locked: false,
+                },
+                ClientDelta {
+                    client_id: 3,
+                    currency: Currency::default(),
+                    available: dec!(0),
+                    held: dec!(-3),
+                    total: dec!(-3),
+                    newly_locked: true,
+                },
+                ClientDelta {
+                    client_id: 3,
+                    currency: eur,
+                    available: dec!(0),
+                    held: dec!(0),
+                    total: dec!(0),
+                    newly_locked: true,
+                },
+                ClientDelta {
+                    client_id: 4,
+                    currency: Currency::default(),
+                    available: dec!(2.5),
+                    held: dec!(0),
+                    total: dec!(2.5),
+                    newly_locked: false,
+                },
+            ],
+            diff_reports(&old, &new)
+        );
+    }
+}
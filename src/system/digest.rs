@@ -0,0 +1,142 @@
+use super::invariants::balances;
+use crate::model::{Client, ClientID, Transaction, TransactionID};
+
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+
+// A SHA-256 of the state, in hex, which comes out the same for the same state
+// however it was built up and wherever it was kept, so that two runs can be
+// checked for equivalence without diffing their reports. It's taken over lines
+// of text rather than anything internal, so that another implementation can
+// work it out too:
+//
+//   client,<id>,<locked>,<closed>,<flagged>
+//   balance,<client id>,<currency>,<held>,<total>
+//   pending,<client id>,<currency>,<pending>
+//   transaction,<id>,<client id>,<kind>,<currency>,<amount>,<status>,<disputed>
+//
+// each ending in a newline. The clients come first in order of ID, each
+// followed by the balances that aren't all zeroes in order of currency (each
+// followed by what's pending in it, if anything), then
+// the transactions in order of ID, with the receiving side of a transfer
+// straight after the sending side as `transfer_credit` rather than
+// `transaction`. Amounts are written without trailing zeroes, so 1.50 and 1.5
+// are the same.
+pub(crate) fn state_digest(
+    clients_by_id: &HashMap<ClientID, Client>,
+    mut transaction_lines: Vec<(TransactionKey, String)>,
+) -> String {
+    let mut hasher = Sha256::new();
+
+    let mut client_ids = clients_by_id.keys().collect::<Vec<_>>();
+    client_ids.sort();
+    for client_id in client_ids {
+        let client = &clients_by_id[client_id];
+        hasher.update(format!(
+            "client,{},{},{},{}\n",
+            client_id,
+            client.locked(),
+            client.closed(),
+            client.flagged()
+        ));
+        for (currency, balance) in balances(Some(client)) {
+            hasher.update(format!(
+                "balance,{},{},{},{}\n",
+                client_id,
+                currency,
+                balance.held().normalize(),
+                balance.total().normalize()
+            ));
+            if !balance.pending().is_zero() {
+                hasher.update(format!(
+                    "pending,{},{},{}\n",
+                    client_id,
+                    currency,
+                    balance.pending().normalize()
+                ));
+            }
+        }
+    }
+
+    transaction_lines.sort_by_key(|(key, _)| *key);
+    for (_, line) in transaction_lines {
+        hasher.update(line);
+    }
+
+    format!("{:x}", hasher.finalize())
+}
+
+// Orders a transfer's receiving side after its sending side, which shares its
+// ID.
+pub(crate) type TransactionKey = (TransactionID, bool);
+
+pub(crate) fn transaction_line(
+    transaction_id: TransactionID,
+    transaction: &Transaction,
+    transfer_credit: bool,
+) -> (TransactionKey, String) {
+    let line = format!(
+        "{},{},{},{},{},{},{},{}\n",
+        if transfer_credit {
+            "transfer_credit"
+        } else {
+            "transaction"
+        },
+        transaction_id,
+        transaction.client_id(),
+        transaction.kind().name(),
+        transaction.currency(),
+        transaction.amount().normalize(),
+        transaction.dispute_status().name(),
+        transaction.disputed_amount().normalize()
+    );
+    ((transaction_id, transfer_credit), line)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::model::{Currency, TransactionKind};
+    use pretty_assertions::assert_eq;
+    use rust_decimal_macros::dec;
+
+    #[test]
+    fn test_state_digest() {
+        let clients_by_id = HashMap::from([
+            (1, Client::create(dec!(0), dec!(10), false)),
+            (2, Client::create(dec!(5), dec!(5), true)),
+            (3, Client::new().with_pending(dec!(2.50))),
+        ]);
+        let deposit =
+            |amount| Transaction::new(1, Currency::default(), amount, TransactionKind::Deposit);
+        let lines = |amount| {
+            vec![
+                transaction_line(2, &deposit(dec!(5)), true),
+                transaction_line(1, &deposit(amount), false),
+                transaction_line(2, &deposit(dec!(5)), false),
+            ]
+        };
+
+        // it's the same whatever order things come in and however the amounts
+        // are written, and taken over exactly the lines described above
+        let digest = state_digest(&clients_by_id, lines(dec!(10)));
+        assert_eq!(digest, state_digest(&clients_by_id, lines(dec!(10.000))));
+        let mut hasher = Sha256::new();
+        hasher.update(concat!(
+            "client,1,false,false,false\n",
+            "balance,1,,0,10\n",
+            "client,2,true,false,false\n",
+            "balance,2,,5,5\n",
+            "client,3,false,false,false\n",
+            "balance,3,,0,2.5\n",
+            "pending,3,,2.5\n",
+            "transaction,1,1,deposit,,10,undisputed,0\n",
+            "transaction,2,1,deposit,,5,undisputed,0\n",
+            "transfer_credit,2,1,deposit,,5,undisputed,0\n",
+        ));
+        assert_eq!(format!("{:x}", hasher.finalize()), digest);
+
+        assert_ne!(digest, state_digest(&clients_by_id, lines(dec!(10.0001))));
+        assert_ne!(digest, state_digest(&HashMap::new(), lines(dec!(10))));
+    }
+}
@@ -0,0 +1,158 @@
+use super::FinalState;
+use crate::model::{Amount, Currency, DisputeStatus};
+
+use std::collections::BTreeMap;
+
+// How much trouble the transactions with one counterparty (e.g. a merchant)
+// have caused in one currency, so that risk can spot the ones whose customers
+// keep disputing them. Money in different currencies can't be added up, so a
+// counterparty dealt with in several has one of these for each.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CounterpartyExposure {
+    pub counterparty: String,
+    pub currency: Currency,
+    pub transactions: u64,
+    pub volume: Amount,
+    // How many of the transactions have ever been disputed, including those
+    // that were later resolved.
+    pub disputed_transactions: u64,
+    // How much is under dispute right now, which is what could yet be charged
+    // back.
+    pub disputed: Amount,
+    pub charged_back_transactions: u64,
+    pub charged_back: Amount,
+}
+
+impl CounterpartyExposure {
+    fn new(counterparty: &str, currency: Currency) -> Self {
+        Self {
+            counterparty: String::from(counterparty),
+            currency,
+            transactions: 0,
+            volume: Amount::ZERO,
+            disputed_transactions: 0,
+            disputed: Amount::ZERO,
+            charged_back_transactions: 0,
+            charged_back: Amount::ZERO,
+        }
+    }
+}
+
+// Every counterparty's exposure, in order of counterparty and then currency.
+// Transactions that didn't say who the counterparty was are left out.
+pub fn counterparty_exposures(final_state: &FinalState) -> Vec<CounterpartyExposure> {
+    let mut exposures = BTreeMap::new();
+
+    for (transaction_id, counterparty) in &final_state.counterparties_by_id {
+        let Some(transaction) = final_state.transactions_by_id.get(transaction_id) else {
+            continue;
+        };
+        let exposure = exposures
+            .entry((counterparty.as_str(), transaction.currency()))
+            .or_insert_with(|| CounterpartyExposure::new(counterparty, transaction.currency()));
+
+        exposure.transactions += 1;
+        exposure.volume += transaction.amount();
+        if transaction.dispute_history().disputed_at.is_some() {
+            exposure.disputed_transactions += 1;
+        }
+        if transaction.dispute_status() == DisputeStatus::Disputed {
+            exposure.disputed += transaction.disputed_amount();
+        }
+        if !transaction.charged_back_amount().is_zero() {
+            exposure.charged_back_transactions += 1;
+            exposure.charged_back += transaction.charged_back_amount();
+        }
+    }
+
+    exposures.into_values().collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        model::{DisputeStepKind, Event, TransactionKind},
+        system::process_events,
+    };
+    use pretty_assertions::assert_eq;
+    use rust_decimal_macros::dec;
+    use std::{error::Error, io};
+
+    #[test]
+    fn test_counterparty_exposures() {
+        let euros = "EUR".parse().expect("Expected a valid currency.");
+        let deposit = |transaction_id, currency, amount, counterparty: Option<&str>| {
+            Ok(Event::Transaction {
+                kind: TransactionKind::Deposit,
+                client_id: 1,
+                transaction_id,
+                currency,
+                amount,
+                counterparty: counterparty.map(String::from),
+            })
+        };
+        let dispute_step = |kind, transaction_id, amount| {
+            Ok(Event::DisputeStep {
+                kind,
+                client_id: 1,
+                transaction_id,
+                amount,
+            })
+        };
+        let input_events: Vec<Result<Event, Box<dyn Error>>> = vec![
+            deposit(1, Currency::default(), dec!(100), Some("acme")),
+            deposit(2, Currency::default(), dec!(50), Some("acme")),
+            deposit(3, Currency::default(), dec!(20), Some("acme")),
+            deposit(4, euros, dec!(10), Some("acme")),
+            deposit(5, Currency::default(), dec!(30), Some("globex")),
+            deposit(6, Currency::default(), dec!(40), None),
+            // charged back in part
+            dispute_step(DisputeStepKind::Dispute, 1, Some(dec!(60))),
+            dispute_step(DisputeStepKind::Chargeback, 1, None),
+            // resolved, so it was disputed but isn't any more
+            dispute_step(DisputeStepKind::Dispute, 2, None),
+            dispute_step(DisputeStepKind::Resolve, 2, None),
+            dispute_step(DisputeStepKind::Dispute, 3, Some(dec!(5))),
+            dispute_step(DisputeStepKind::Dispute, 6, None),
+        ];
+        let final_state =
+            process_events(input_events.into_iter(), &mut io::sink()).expect("Expected no errors.");
+
+        assert_eq!(
+            vec![
+                CounterpartyExposure {
+                    counterparty: String::from("acme"),
+                    currency: Currency::default(),
+                    transactions: 3,
+                    volume: dec!(170),
+                    disputed_transactions: 3,
+                    disputed: dec!(5),
+                    charged_back_transactions: 1,
+                    charged_back: dec!(60),
+                },
+                CounterpartyExposure {
+                    counterparty: String::from("acme"),
+                    currency: euros,
+                    transactions: 1,
+                    volume: dec!(10),
+                    disputed_transactions: 0,
+                    disputed: dec!(0),
+                    charged_back_transactions: 0,
+                    charged_back: dec!(0),
+                },
+                CounterpartyExposure {
+                    counterparty: String::from("globex"),
+                    currency: Currency::default(),
+                    transactions: 1,
+                    volume: dec!(30),
+                    disputed_transactions: 0,
+                    disputed: dec!(0),
+                    charged_back_transactions: 0,
+                    charged_back: dec!(0),
+                },
+            ],
+            counterparty_exposures(&final_state)
+        );
+    }
+}
@@ -0,0 +1,97 @@
+use crate::model::{round_amount, Amount, ProcessingError};
+
+use std::str::FromStr;
+
+// A fee charged on a deposit or withdrawal: a flat amount, a percentage of the
+// amount, or both added together.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Fee {
+    pub flat: Amount,
+    pub percent: Amount,
+}
+
+impl Fee {
+    pub fn amount_for(&self, amount: Amount) -> Result<Amount, ProcessingError> {
+        (amount / Amount::ONE_HUNDRED)
+            .checked_mul(self.percent)
+            .and_then(|percentage| percentage.checked_add(self.flat))
+            .map(round_amount)
+            .ok_or(ProcessingError::AmountOverflow)
+    }
+}
+
+// Parses a flat fee, a percentage, or both joined with a plus, e.g. `0.5`,
+// `1%` or `0.5+1%`.
+impl FromStr for Fee {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid fee: {}.", s);
+
+        let mut fee = Fee::default();
+        for part in s.split('+').map(str::trim) {
+            let (value, is_percent) = match part.strip_suffix('%') {
+                Some(percent) => (percent.trim(), true),
+                None => (part, false),
+            };
+            let value = Amount::from_str(value).map_err(|_| invalid())?;
+            if value.is_sign_negative() {
+                return Err(invalid());
+            }
+            if is_percent {
+                fee.percent += value;
+            } else {
+                fee.flat += value;
+            }
+        }
+
+        Ok(fee)
+    }
+}
+
+// The fees charged automatically on top of any explicit fee events. Transfers
+// are never charged.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FeeSchedule {
+    pub deposit: Option<Fee>,
+    pub withdrawal: Option<Fee>,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use rust_decimal_macros::dec;
+
+    #[test]
+    fn test_from_str() {
+        assert_eq!(
+            Ok(Fee {
+                flat: dec!(0.5),
+                percent: dec!(0),
+            }),
+            "0.5".parse()
+        );
+        assert_eq!(
+            Ok(Fee {
+                flat: dec!(0.5),
+                percent: dec!(1.5),
+            }),
+            "0.5 + 1.5%".parse()
+        );
+        assert_eq!(Err(String::from("Invalid fee: -1.")), "-1".parse::<Fee>());
+        assert_eq!(Err(String::from("Invalid fee: 1%%.")), "1%%".parse::<Fee>());
+    }
+
+    #[test]
+    fn test_amount_for() {
+        let fee = Fee {
+            flat: dec!(0.25),
+            percent: dec!(1),
+        };
+
+        assert_eq!(Ok(dec!(1.25)), fee.amount_for(dec!(100)));
+        // 1% of 0.005 is half of the smallest unit, which rounds up
+        assert_eq!(Ok(dec!(0.2501)), fee.amount_for(dec!(0.005)));
+    }
+}
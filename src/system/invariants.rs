@@ -0,0 +1,246 @@
+use super::{ClosedAccountPolicy, EngineConfig, LockedAccountPolicy, LockedDepositPolicy};
+use crate::model::{
+    Amount, Balance, Client, ClientID, Currency, Event, Transaction, TransactionKind,
+};
+
+use std::collections::{BTreeMap, HashMap};
+
+// What the clients an event is about looked like before it was processed, so
+// that the invariants can tell what it changed. Clients that didn't exist yet
+// are recorded as having nothing.
+pub(crate) struct ClientsBefore(Vec<(ClientID, ClientBefore)>);
+
+struct ClientBefore {
+    locked: bool,
+    closed: bool,
+    balances: BTreeMap<Currency, Balance>,
+}
+
+impl ClientsBefore {
+    pub(crate) fn capture(
+        clients_by_id: &HashMap<ClientID, Client>,
+        client_ids: &[ClientID],
+    ) -> Self {
+        Self(
+            client_ids
+                .iter()
+                .map(|client_id| {
+                    let client = clients_by_id.get(client_id);
+                    (
+                        *client_id,
+                        ClientBefore {
+                            locked: client.is_some_and(Client::locked),
+                            closed: client.is_some_and(Client::closed),
+                            balances: balances(client),
+                        },
+                    )
+                })
+                .collect(),
+        )
+    }
+}
+
+// Every balance that isn't all zeroes. An empty balance can be left behind by
+// an event that was rejected, which doesn't count as a change.
+pub(crate) fn balances(client: Option<&Client>) -> BTreeMap<Currency, Balance> {
+    client
+        .into_iter()
+        .flat_map(Client::balances)
+        .filter(|(_, balance)| {
+            !(balance.held().is_zero()
+                && balance.total().is_zero()
+                && balance.fees().is_zero()
+                && balance.interest().is_zero()
+                && balance.pending().is_zero())
+        })
+        .map(|(currency, balance)| (currency, *balance))
+        .collect()
+}
+
+// Checks what the processor should never allow, whatever the input, after an
+// event has been processed (or rejected), and describes the first thing that
+// doesn't hold. A violation means there's a bug in the processor rather than
+// anything wrong with the input.
+pub(crate) fn check_invariants(
+    event: &Event,
+    accepted: bool,
+    before: &ClientsBefore,
+    clients_by_id: &HashMap<ClientID, Client>,
+    transactions: &[&Transaction],
+    config: &EngineConfig,
+) -> Result<(), String> {
+    for (client_id, client_before) in &before.0 {
+        let client = clients_by_id.get(client_id);
+        for (currency, balance) in client.into_iter().flat_map(Client::balances) {
+            if balance.held() < Amount::ZERO {
+                return Err(format!(
+                    "client {} has {} held in {:?}, which is negative",
+                    client_id,
+                    balance.held(),
+                    currency.to_string()
+                ));
+            }
+            if balance.pending() < Amount::ZERO {
+                return Err(format!(
+                    "client {} has {} pending in {:?}, which is negative",
+                    client_id,
+                    balance.pending(),
+                    currency.to_string()
+                ));
+            }
+            // `available` is worked out from the others at the moment, but
+            // nothing else should have to rely on that
+            if balance.available() + balance.held() + balance.pending() != balance.total() {
+                return Err(format!(
+                    "client {}'s available, held and pending don't add up to their total in {:?}",
+                    client_id,
+                    currency.to_string()
+                ));
+            }
+        }
+
+        let changed = balances(client) != client_before.balances;
+        if !changed {
+            continue;
+        }
+        if !accepted {
+            return Err(format!(
+                "client {}'s balances changed even though the event was rejected",
+                client_id
+            ));
+        }
+        if client_before.locked && !allowed_on_locked_account(event, *client_id, config) {
+            return Err(format!(
+                "client {}'s balances changed even though their account is locked",
+                client_id
+            ));
+        }
+        if client_before.closed && !allowed_on_closed_account(event, *client_id, config) {
+            return Err(format!(
+                "client {}'s balances changed even though their account is closed",
+                client_id
+            ));
+        }
+    }
+
+    for transaction in transactions {
+        if transaction.disputed_amount() < Amount::ZERO
+            || transaction.charged_back_amount() < Amount::ZERO
+            || transaction.charged_back_amount() > transaction.amount()
+        {
+            return Err(format!(
+                "a transaction of {} has {} disputed and {} charged back",
+                transaction.amount(),
+                transaction.disputed_amount(),
+                transaction.charged_back_amount()
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+// Corrections go through whatever the state of the account, and so do dispute
+// steps unless the policy says otherwise. Anything at the client's request
+// doesn't, apart from money coming in if the policy allows it.
+fn allowed_on_locked_account(event: &Event, client_id: ClientID, config: &EngineConfig) -> bool {
+    let accept_deposits = config.locked_account_deposits == LockedDepositPolicy::Accept;
+    match event {
+        Event::DisputeStep { .. } => config.locked_account_disputes == LockedAccountPolicy::Process,
+        Event::Reversal { .. }
+        | Event::ChargebackReversal { .. }
+        | Event::Adjustment { .. }
+        | Event::Settlement { .. }
+        | Event::ClientRegistration { .. } => true,
+        Event::Transaction {
+            kind: TransactionKind::Deposit,
+            ..
+        }
+        | Event::PendingDeposit { .. }
+        | Event::Interest { .. } => accept_deposits,
+        Event::Transfer { to_client_id, .. } => accept_deposits && *to_client_id == client_id,
+        Event::Transaction { .. }
+        | Event::Fee { .. }
+        | Event::Conversion { .. }
+        | Event::AccountClosure { .. } => false,
+    }
+}
+
+fn allowed_on_closed_account(event: &Event, client_id: ClientID, config: &EngineConfig) -> bool {
+    let allow_withdrawals = config.closed_accounts == ClosedAccountPolicy::AllowWithdrawals;
+    match event {
+        Event::Transaction {
+            kind: TransactionKind::Withdrawal,
+            ..
+        } => allow_withdrawals,
+        Event::Transfer { from_client_id, .. } => allow_withdrawals && *from_client_id == client_id,
+        Event::DisputeStep { .. }
+        | Event::Reversal { .. }
+        | Event::ChargebackReversal { .. }
+        | Event::Adjustment { .. }
+        | Event::Settlement { .. }
+        | Event::ClientRegistration { .. } => true,
+        Event::Transaction { .. }
+        | Event::PendingDeposit { .. }
+        | Event::Fee { .. }
+        | Event::Conversion { .. }
+        | Event::Interest { .. }
+        | Event::AccountClosure { .. } => false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rust_decimal_macros::dec;
+
+    #[test]
+    fn test_check_invariants() {
+        let config = EngineConfig::default();
+        let deposit = Event::Transaction {
+            kind: TransactionKind::Deposit,
+            transaction_id: 1,
+            client_id: 1,
+            currency: Currency::default(),
+            amount: dec!(10),
+            counterparty: None,
+        };
+        let locked = HashMap::from([(1, Client::create(dec!(0), dec!(5), true))]);
+        let before = ClientsBefore::capture(&locked, &[1]);
+
+        // nothing changed, so whether it was accepted doesn't matter
+        assert!(check_invariants(&deposit, true, &before, &locked, &[], &config).is_ok());
+
+        let deposited = HashMap::from([(1, Client::create(dec!(0), dec!(15), true))]);
+        assert_eq!(
+            Err(String::from(
+                "client 1's balances changed even though their account is locked"
+            )),
+            check_invariants(&deposit, true, &before, &deposited, &[], &config)
+        );
+        assert_eq!(
+            Err(String::from(
+                "client 1's balances changed even though the event was rejected"
+            )),
+            check_invariants(&deposit, false, &before, &deposited, &[], &config)
+        );
+
+        let negative_held = HashMap::from([(1, Client::create(dec!(-1), dec!(5), false))]);
+        assert_eq!(
+            Err(String::from(
+                "client 1 has -1 held in \"\", which is negative"
+            )),
+            check_invariants(&deposit, true, &before, &negative_held, &[], &config)
+        );
+
+        // an adjustment goes through on a locked account
+        let adjustment = Event::Adjustment {
+            transaction_id: 2,
+            client_id: 1,
+            currency: Currency::default(),
+            amount: dec!(10),
+            reason: String::from("Correction"),
+        };
+        assert!(check_invariants(&adjustment, true, &before, &deposited, &[], &config).is_ok());
+    }
+}
@@ -0,0 +1,268 @@
+use crate::model::{Event, Timestamp};
+
+use serde::{Deserialize, Serialize};
+use std::io::{self, Read, Write};
+
+// Written at the start of every journal, like the snapshot's magic number, so
+// that anything else handed to `JournalReader` is turned away up front.
+const JOURNAL_MAGIC: &[u8; 8] = b"CHLGJRNL";
+const JOURNAL_VERSION: u32 = 1;
+
+// An accepted event as it was written to the journal, with everything needed
+// to process it again exactly as it was: when it happened, and its number
+// among all the events processed, which is what dispute histories refer to.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct JournalEntry {
+    pub event_number: u64,
+    pub timestamp: Option<Timestamp>,
+    pub event: Event,
+}
+
+// How far to replay a journal, for looking at the state as it was at some
+// point in the past.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReplayPoint {
+    // Up to and including the event with this number.
+    AfterEvent(u64),
+    // Up to the first event that happened after this. Events without a
+    // timestamp are replayed until then, since there's no telling when they
+    // happened.
+    AtTime(Timestamp),
+}
+
+impl ReplayPoint {
+    pub(crate) fn is_past(&self, entry: &JournalEntry) -> bool {
+        match *self {
+            ReplayPoint::AfterEvent(event_number) => entry.event_number > event_number,
+            ReplayPoint::AtTime(time) => entry.timestamp.is_some_and(|timestamp| timestamp > time),
+        }
+    }
+}
+
+// Appends every accepted event to a writer, as a write-ahead log that the
+// state can be rebuilt from with `Processor::replay_journal`. Each entry is
+// its length followed by the entry itself in bincode, so that an entry only
+// partly written when the process died can be told apart and ignored.
+//
+// Entries are appended in the middle of processing an event, where there's
+// nowhere to return an error to, so the first one is held onto until
+// `take_error` is called, like the stores do.
+pub struct Journal {
+    writer: Box<dyn Write>,
+    error: Option<io::Error>,
+}
+
+impl Journal {
+    // Starts a new journal, writing its header. That's flushed straight away,
+    // so that the journal can be read back before anything's appended to it,
+    // like `challenge serve` does on starting up.
+    pub fn new(mut writer: impl Write + 'static) -> io::Result<Self> {
+        writer.write_all(JOURNAL_MAGIC)?;
+        writer.write_all(&JOURNAL_VERSION.to_le_bytes())?;
+        writer.flush()?;
+        Ok(Self::append_to(writer))
+    }
+
+    // Carries on with a journal that already has its header, e.g. one opened
+    // for appending after a previous run.
+    pub fn append_to(writer: impl Write + 'static) -> Self {
+        Self {
+            writer: Box::new(writer),
+            error: None,
+        }
+    }
+
+    // Borrows the event rather than building a `JournalEntry`, which bincode
+    // encodes the same way, so that nothing has to be cloned.
+    pub(crate) fn append(
+        &mut self,
+        event_number: u64,
+        timestamp: Option<Timestamp>,
+        event: &Event,
+    ) {
+        if self.error.is_some() {
+            return;
+        }
+        let result = bincode::serialize(&(event_number, timestamp, event))
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+            .and_then(|entry| {
+                let len = u32::try_from(entry.len())
+                    .map_err(|_| io::Error::other("Journal entry is too large."))?;
+                self.writer.write_all(&len.to_le_bytes())?;
+                self.writer.write_all(&entry)
+            });
+        if let Err(e) = result {
+            self.error = Some(e);
+        }
+    }
+
+    pub(crate) fn take_error(&mut self) -> Option<io::Error> {
+        self.error.take()
+    }
+
+    pub(crate) fn flush(&mut self) -> io::Result<()> {
+        match self.error.take() {
+            Some(e) => Err(e),
+            None => self.writer.flush(),
+        }
+    }
+}
+
+// Reads back the entries of a journal written by `Journal`, in the order they
+// were written. A last entry that was only partly written is taken to be the
+// end of the journal.
+pub struct JournalReader<R> {
+    reader: R,
+    // How much of the journal, including the header, has been read as
+    // whole entries.
+    complete_len: u64,
+}
+
+impl<R: Read> JournalReader<R> {
+    pub fn new(mut reader: R) -> io::Result<Self> {
+        let mut magic = [0; JOURNAL_MAGIC.len()];
+        reader.read_exact(&mut magic)?;
+        if &magic != JOURNAL_MAGIC {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Not an event journal.",
+            ));
+        }
+        let mut version_bytes = [0; 4];
+        reader.read_exact(&mut version_bytes)?;
+        let version = u32::from_le_bytes(version_bytes);
+        if version != JOURNAL_VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "Journal is version {}, but only version {} can be read.",
+                    version, JOURNAL_VERSION
+                ),
+            ));
+        }
+
+        Ok(Self {
+            reader,
+            complete_len: (magic.len() + version_bytes.len()) as u64,
+        })
+    }
+
+    // Once every entry has been read, this is how long the journal should be,
+    // so anything past it is a partly written entry that can be cut off before
+    // appending any more.
+    pub fn complete_len(&self) -> u64 {
+        self.complete_len
+    }
+
+    // Reads as much of `buf` as there is, returning false if the journal ran
+    // out first.
+    fn read_all(&mut self, buf: &mut [u8]) -> io::Result<bool> {
+        match self.reader.read_exact(buf) {
+            Ok(()) => Ok(true),
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn read_entry(&mut self) -> io::Result<Option<JournalEntry>> {
+        let mut len = [0; 4];
+        if !self.read_all(&mut len)? {
+            return Ok(None);
+        }
+        let mut entry = vec![0; u32::from_le_bytes(len) as usize];
+        if !self.read_all(&mut entry)? {
+            return Ok(None);
+        }
+        self.complete_len += (len.len() + entry.len()) as u64;
+
+        bincode::deserialize(&entry)
+            .map(Some)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+}
+
+impl<R: Read> Iterator for JournalReader<R> {
+    type Item = io::Result<JournalEntry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_entry().transpose()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::model::{Currency, TransactionKind};
+    use pretty_assertions::assert_eq;
+    use rust_decimal_macros::dec;
+    use std::{cell::RefCell, rc::Rc};
+
+    // so that what's written can be looked at while the journal still has it
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_journal() {
+        let deposit = |transaction_id| Event::Transaction {
+            kind: TransactionKind::Deposit,
+            client_id: 1,
+            transaction_id,
+            currency: Currency::default(),
+            amount: dec!(10.5),
+            counterparty: None,
+        };
+        let buffer = SharedBuffer::default();
+        let mut journal = Journal::new(buffer.clone()).expect("Expected no errors.");
+        journal.append(1, Some(1_700_000_000), &deposit(1));
+        journal.append(3, None, &deposit(2));
+        journal.flush().expect("Expected no errors.");
+
+        let expected = vec![
+            JournalEntry {
+                event_number: 1,
+                timestamp: Some(1_700_000_000),
+                event: deposit(1),
+            },
+            JournalEntry {
+                event_number: 3,
+                timestamp: None,
+                event: deposit(2),
+            },
+        ];
+        let written = buffer.0.borrow().clone();
+        let read = |bytes: &[u8]| {
+            JournalReader::new(bytes)
+                .expect("Expected a journal.")
+                .collect::<io::Result<Vec<_>>>()
+                .expect("Expected no errors.")
+        };
+        assert_eq!(expected, read(&written));
+
+        // an entry cut off part way through is left out
+        let torn = &written[..written.len() - 1];
+        assert_eq!(expected[..1], read(torn));
+        let mut reader = JournalReader::new(torn).expect("Expected a journal.");
+        reader.by_ref().for_each(drop);
+        let first_entry_end = reader.complete_len() as usize;
+        assert_eq!(expected[..1], read(&written[..first_entry_end]));
+        assert!(first_entry_end < torn.len());
+
+        assert_eq!(
+            io::ErrorKind::InvalidData,
+            JournalReader::new(&b"CHLGSNAP\x01\0\0\0"[..])
+                .err()
+                .expect("Expected an error.")
+                .kind()
+        );
+    }
+}
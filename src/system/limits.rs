@@ -0,0 +1,106 @@
+use crate::model::Source;
+
+use std::{error::Error, fmt};
+
+// Hard caps on how much a run takes on before it gives up, so that a broken
+// input (e.g. one with random client or transaction IDs) stops the run with an
+// error rather than eating all the memory on the host. Anything left as `None`
+// isn't capped. When processing is sharded, they apply to the shards'
+// totals.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ResourceLimits {
+    pub max_clients: Option<usize>,
+    // Counts every transaction ID that's held onto, including conversions,
+    // adjustments and withdrawals that weren't kept.
+    pub max_transactions: Option<usize>,
+    pub max_errors: Option<u64>,
+}
+
+impl ResourceLimits {
+    pub fn check(&self, clients: usize, transactions: usize, errors: u64) -> Result<(), Limit> {
+        if let Some(max_clients) = self.max_clients.filter(|max| clients > *max) {
+            return Err(Limit::Clients(max_clients));
+        }
+        if let Some(max_transactions) = self.max_transactions.filter(|max| transactions > *max) {
+            return Err(Limit::Transactions(max_transactions));
+        }
+        if let Some(max_errors) = self.max_errors.filter(|max| errors > *max) {
+            return Err(Limit::Errors(max_errors));
+        }
+        Ok(())
+    }
+}
+
+// Which limit was gone over, and what it was.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Limit {
+    Clients(usize),
+    Transactions(usize),
+    Errors(u64),
+}
+
+impl fmt::Display for Limit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Limit::Clients(max) => write!(f, "more than the {} clients allowed", max),
+            Limit::Transactions(max) => write!(f, "more than the {} transactions allowed", max),
+            Limit::Errors(max) => write!(f, "more than the {} rejected events allowed", max),
+        }
+    }
+}
+
+// Why a run was stopped part way through, along with where in the input it
+// got to, so that whoever has to look into it knows where to start.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LimitExceeded {
+    pub limit: Limit,
+    pub source: Option<Source>,
+}
+
+impl fmt::Display for LimitExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.source {
+            Some(source) => write!(f, "Stopped at {} with {}.", source, self.limit),
+            None => write!(f, "Stopped with {}.", self.limit),
+        }
+    }
+}
+
+impl Error for LimitExceeded {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_check() {
+        let limits = ResourceLimits {
+            max_clients: Some(2),
+            max_transactions: Some(3),
+            max_errors: Some(0),
+        };
+
+        assert_eq!(Ok(()), limits.check(2, 3, 0));
+        assert_eq!(Err(Limit::Clients(2)), limits.check(3, 3, 0));
+        assert_eq!(Err(Limit::Transactions(3)), limits.check(2, 4, 0));
+        assert_eq!(Err(Limit::Errors(0)), limits.check(2, 3, 1));
+        assert_eq!(Ok(()), ResourceLimits::default().check(100, 100, 100));
+    }
+
+    #[test]
+    fn test_display() {
+        let exceeded = LimitExceeded {
+            limit: Limit::Clients(2),
+            source: Some(Source {
+                line: 4,
+                byte: 60,
+                record: None,
+            }),
+        };
+        assert_eq!(
+            "Stopped at line 4 (byte 60) with more than the 2 clients allowed.",
+            exceeded.to_string()
+        );
+    }
+}
@@ -1,3 +1,49 @@
+mod compact;
+mod config;
+mod delta;
+mod diff;
+mod digest;
+mod exposure;
+mod fees;
+mod invariants;
+mod journal;
+mod limits;
+mod observer;
 mod processing;
 mod processor;
+mod reconciliation;
+#[cfg(feature = "redis")]
+mod redis_store;
+mod rejection;
+mod reorder;
+mod sharding;
+mod snapshot;
+mod spill;
+mod stats;
+mod store;
+mod threshold;
+pub use compact::CompactStore;
+pub use config::*;
+pub(crate) use delta::DeltaTracker;
+pub use delta::{AccountState, BatchResult, StateDelta};
+pub use diff::*;
+pub use exposure::*;
+pub use fees::*;
+pub use journal::{Journal, JournalEntry, JournalReader, ReplayPoint};
+pub use limits::*;
+pub use observer::*;
 pub use processing::*;
+pub use processor::Processor;
+pub(crate) use processor::ProcessorParts;
+pub use reconciliation::*;
+#[cfg(feature = "redis")]
+pub use redis_store::RedisStore;
+pub use rejection::*;
+pub(crate) use reorder::ReorderBuffer;
+pub use sharding::*;
+pub use snapshot::SnapshotInterval;
+pub(crate) use snapshot::SnapshotTimer;
+pub use spill::SpillingStore;
+pub use stats::*;
+pub use store::*;
+pub use threshold::*;
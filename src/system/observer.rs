@@ -0,0 +1,167 @@
+use crate::model::{
+    Client, ClientID, Event, ProcessingError, Timestamp, Transaction, TransactionID,
+};
+
+// Told about what happens as events are processed, for things like metrics or
+// alerting that want to know as it happens rather than from the report. Every
+// method does nothing by default, so implementations only need to pick out
+// what they care about. `()` is an observer that ignores everything.
+pub trait ProcessorObserver {
+    // Called as each event comes up to be processed, before anything else
+    // about it, with the number it's processed as (the one the dispute report
+    // goes by) and when it happened, if known. A queued event comes up again
+    // when it's finally processed, with a new number.
+    fn on_event(&mut self, _event_number: u64, _timestamp: Option<Timestamp>) {}
+
+    fn on_accepted(&mut self, _event: &Event) {}
+
+    fn on_rejected(&mut self, _event: &Event, _error: &ProcessingError) {}
+
+    // Called after `on_accepted` for a chargeback, with the transaction as it
+    // stands afterwards.
+    fn on_chargeback(
+        &mut self,
+        _client_id: ClientID,
+        _transaction_id: TransactionID,
+        _transaction: &Transaction,
+    ) {
+    }
+
+    // Called when a client's account goes from unlocked to locked, after the
+    // `on_chargeback` that locked it.
+    fn on_lock(&mut self, _client_id: ClientID, _client: &Client) {}
+
+    // Called when a client reaches `EngineConfig::chargeback_limit`, after the
+    // `on_chargeback` (and any `on_lock`) for the chargeback that took them
+    // there.
+    fn on_flag(&mut self, _client_id: ClientID, _client: &Client) {}
+
+    // Called instead of `on_accepted` for the resolve of a dispute that lapsed
+    // under `EngineConfig::dispute_expiry`, which no event asked for. It comes
+    // before anything about the event that showed the dispute had lapsed.
+    fn on_dispute_lapsed(&mut self, _resolve: &Event) {}
+}
+
+impl ProcessorObserver for () {}
+
+// So that an observer can be lent to an engine and looked at again once the
+// engine's done with it.
+impl<T: ProcessorObserver + ?Sized> ProcessorObserver for &mut T {
+    fn on_event(&mut self, event_number: u64, timestamp: Option<Timestamp>) {
+        (**self).on_event(event_number, timestamp);
+    }
+
+    fn on_accepted(&mut self, event: &Event) {
+        (**self).on_accepted(event);
+    }
+
+    fn on_rejected(&mut self, event: &Event, error: &ProcessingError) {
+        (**self).on_rejected(event, error);
+    }
+
+    fn on_chargeback(
+        &mut self,
+        client_id: ClientID,
+        transaction_id: TransactionID,
+        transaction: &Transaction,
+    ) {
+        (**self).on_chargeback(client_id, transaction_id, transaction);
+    }
+
+    fn on_lock(&mut self, client_id: ClientID, client: &Client) {
+        (**self).on_lock(client_id, client);
+    }
+
+    fn on_flag(&mut self, client_id: ClientID, client: &Client) {
+        (**self).on_flag(client_id, client);
+    }
+
+    fn on_dispute_lapsed(&mut self, resolve: &Event) {
+        (**self).on_dispute_lapsed(resolve);
+    }
+}
+
+impl<T: ProcessorObserver + ?Sized> ProcessorObserver for Box<T> {
+    fn on_event(&mut self, event_number: u64, timestamp: Option<Timestamp>) {
+        (**self).on_event(event_number, timestamp);
+    }
+
+    fn on_accepted(&mut self, event: &Event) {
+        (**self).on_accepted(event);
+    }
+
+    fn on_rejected(&mut self, event: &Event, error: &ProcessingError) {
+        (**self).on_rejected(event, error);
+    }
+
+    fn on_chargeback(
+        &mut self,
+        client_id: ClientID,
+        transaction_id: TransactionID,
+        transaction: &Transaction,
+    ) {
+        (**self).on_chargeback(client_id, transaction_id, transaction);
+    }
+
+    fn on_lock(&mut self, client_id: ClientID, client: &Client) {
+        (**self).on_lock(client_id, client);
+    }
+
+    fn on_flag(&mut self, client_id: ClientID, client: &Client) {
+        (**self).on_flag(client_id, client);
+    }
+
+    fn on_dispute_lapsed(&mut self, resolve: &Event) {
+        (**self).on_dispute_lapsed(resolve);
+    }
+}
+
+// Tells each of them in turn, for when there's more than one thing watching.
+impl<T: ProcessorObserver> ProcessorObserver for Vec<T> {
+    fn on_event(&mut self, event_number: u64, timestamp: Option<Timestamp>) {
+        for observer in self {
+            observer.on_event(event_number, timestamp);
+        }
+    }
+
+    fn on_accepted(&mut self, event: &Event) {
+        for observer in self {
+            observer.on_accepted(event);
+        }
+    }
+
+    fn on_rejected(&mut self, event: &Event, error: &ProcessingError) {
+        for observer in self {
+            observer.on_rejected(event, error);
+        }
+    }
+
+    fn on_chargeback(
+        &mut self,
+        client_id: ClientID,
+        transaction_id: TransactionID,
+        transaction: &Transaction,
+    ) {
+        for observer in self {
+            observer.on_chargeback(client_id, transaction_id, transaction);
+        }
+    }
+
+    fn on_lock(&mut self, client_id: ClientID, client: &Client) {
+        for observer in self {
+            observer.on_lock(client_id, client);
+        }
+    }
+
+    fn on_flag(&mut self, client_id: ClientID, client: &Client) {
+        for observer in self {
+            observer.on_flag(client_id, client);
+        }
+    }
+
+    fn on_dispute_lapsed(&mut self, resolve: &Event) {
+        for observer in self {
+            observer.on_dispute_lapsed(resolve);
+        }
+    }
+}
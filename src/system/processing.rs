@@ -1,33 +1,482 @@
-use super::processor::Processor;
-use crate::model::{Client, ClientID, Event};
+use super::{
+    digest::{state_digest, transaction_line},
+    processor::Processor,
+    reorder::ReorderBuffer,
+    snapshot::SnapshotTimer,
+    EngineConfig, LimitExceeded, ProcessorObserver, Rejection, RejectionLogger, SnapshotInterval,
+};
+use crate::{
+    format::error::ParseError,
+    model::{
+        Adjustment, Amount, Client, ClientID, Conversion, Currency, DisputeStatus, Event,
+        ProcessingError, Source, SourcedEvent, Transaction, TransactionID,
+    },
+};
+
+use std::{collections::HashMap, error::Error, io};
+use thiserror::Error;
+
+// Why processing a run's events stopped partway, as opposed to an event being
+// rejected (see `ProcessingError`). The input's and the processor's own errors
+// are kept as they are, so that nothing's boxed on the way through. Anything
+// else (a snapshot that couldn't be written, say, or an input of the caller's
+// own) is boxed, since it only happens the once.
+#[derive(Debug, Error)]
+pub enum ProcessEventsError {
+    #[error(transparent)]
+    Parse(#[from] ParseError),
+    // e.g. the journal or the rejections couldn't be written
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error(transparent)]
+    Other(Box<dyn Error>),
+}
+
+impl From<Box<dyn Error>> for ProcessEventsError {
+    fn from(e: Box<dyn Error>) -> Self {
+        ProcessEventsError::Other(e)
+    }
+}
+
+// The state of the system once every event has been processed.
+#[derive(Default)]
+pub struct FinalState {
+    pub clients_by_id: HashMap<ClientID, Client>,
+    pub transactions_by_id: HashMap<TransactionID, Transaction>,
+    // The receiving side of each transfer. The sending side is in
+    // `transactions_by_id` under the same ID, as a withdrawal.
+    pub transfer_credits_by_id: HashMap<TransactionID, Transaction>,
+    // Conversions can't be disputed, so unlike transactions they're only kept
+    // for the reconciliation.
+    pub conversions_by_id: HashMap<TransactionID, Conversion>,
+    // Likewise for adjustments, which are also kept so they can be traced.
+    pub adjustments_by_id: HashMap<TransactionID, Adjustment>,
+    // How much was withdrawn in each currency by the withdrawals that weren't
+    // kept under `EngineConfig::disputable_only`, for the reconciliation.
+    pub discarded_withdrawals: HashMap<Currency, Amount>,
+    // Who was on the other side of each deposit and withdrawal that said, for
+    // the counterparty report.
+    pub counterparties_by_id: HashMap<TransactionID, String>,
+    pub event_counts: EventCounts,
+}
+
+impl FinalState {
+    // Every transaction, including both sides of each transfer.
+    pub fn all_transactions(&self) -> impl Iterator<Item = (TransactionID, &Transaction)> {
+        self.transactions_by_id
+            .iter()
+            .chain(&self.transfer_credits_by_id)
+            .map(|(transaction_id, transaction)| (*transaction_id, transaction))
+    }
+
+    // The client's transactions that are under dispute right now, in order of
+    // ID. The receiving side of a transfer counts as the receiver's.
+    pub fn open_disputes(&self, client_id: ClientID) -> Vec<(TransactionID, &Transaction)> {
+        let mut open_disputes = self
+            .all_transactions()
+            .filter(|(_, transaction)| {
+                transaction.client_id() == client_id
+                    && transaction.dispute_status() == DisputeStatus::Disputed
+            })
+            .collect::<Vec<_>>();
+        open_disputes.sort_by_key(|(transaction_id, _)| *transaction_id);
+        open_disputes
+    }
+
+    // A hash of the clients and transactions that's the same for the same
+    // state, however it was arrived at, so that two runs can be compared
+    // cheaply. See `digest.rs` for exactly what goes into it.
+    pub fn state_digest(&self) -> String {
+        let transaction_lines = self
+            .transactions_by_id
+            .iter()
+            .map(|(transaction_id, transaction)| {
+                transaction_line(*transaction_id, transaction, false)
+            })
+            .chain(
+                self.transfer_credits_by_id
+                    .iter()
+                    .map(|(transaction_id, transaction)| {
+                        transaction_line(*transaction_id, transaction, true)
+                    }),
+            )
+            .collect();
+
+        state_digest(&self.clients_by_id, transaction_lines)
+    }
+}
+
+// How many events we saw and how many of those were rejected. Events that fail
+// to parse abort the run, so they're not counted here.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct EventCounts {
+    pub processed: u64,
+    pub rejected: u64,
+    // Broken down by the kind of event and by rejection code respectively, in
+    // the order each was first seen. There are only ever a handful of each so
+    // a vector does the job.
+    pub processed_by_kind: Vec<(&'static str, u64)>,
+    pub rejected_by_code: Vec<(&'static str, u64)>,
+}
+
+pub(crate) fn increment(counts: &mut Vec<(&'static str, u64)>, key: &'static str) {
+    match counts.iter_mut().find(|(existing, _)| *existing == key) {
+        Some((_, count)) => *count += 1,
+        None => counts.push((key, 1)),
+    }
+}
+
+impl EventCounts {
+    pub(crate) fn count_processed(&mut self, event: &Event) {
+        self.processed += 1;
+        increment(&mut self.processed_by_kind, event.kind_name());
+    }
+
+    pub(crate) fn count_rejected(&mut self, code: &'static str) {
+        self.rejected += 1;
+        increment(&mut self.rejected_by_code, code);
+    }
+}
+
+fn reject(
+    event_counts: &mut EventCounts,
+    error_logger: &mut (impl RejectionLogger + ?Sized),
+    source: Option<&Source>,
+    error: &ProcessingError,
+) -> io::Result<()> {
+    event_counts.count_rejected(error.code());
+    error_logger.log_rejection(&Rejection { source, error })
+}
 
-use std::{collections::HashMap, error::Error, io::Write};
+// Takes an events iterator and processes each event, logging any rejected
+// events. Events may come with their source (e.g. a line number) or not, in
+// which case they're just plain `Event`s. Returns the final state of the
+// clients and transactions. The iterator's errors can be anything that can be
+// told apart as a `ProcessEventsError`, e.g. a `ParseError` as it comes.
+pub fn process_events<E: Into<SourcedEvent>, Err: Into<ProcessEventsError>>(
+    events_iter: impl Iterator<Item = Result<E, Err>>,
+    error_logger: &mut (impl RejectionLogger + ?Sized),
+) -> Result<FinalState, ProcessEventsError> {
+    process_events_with_snapshots(
+        events_iter,
+        error_logger,
+        &EngineConfig::default(),
+        None,
+        |_, _| Ok(()),
+    )
+}
 
-// Takes an events iterator and processes each event. Returns the final state
-// of the clients.
-pub fn process_events(
-    events_iter: impl Iterator<Item = Result<Event, Box<dyn Error>>>,
-    error_logger: &mut impl Write,
-) -> Result<HashMap<ClientID, Client>, Box<dyn Error>> {
-    let mut processor = Processor::new();
+// Like `process_events`, but with the given engine config, and every so often
+// (if an interval is given) it hands the clients as they currently stand to
+// `take_snapshot`, so that long runs can be checked on before they finish.
+pub fn process_events_with_snapshots<E: Into<SourcedEvent>, Err: Into<ProcessEventsError>>(
+    events_iter: impl Iterator<Item = Result<E, Err>>,
+    error_logger: &mut (impl RejectionLogger + ?Sized),
+    config: &EngineConfig,
+    snapshot_interval: Option<SnapshotInterval>,
+    take_snapshot: impl FnMut(&HashMap<ClientID, Client>, &EventCounts) -> Result<(), Box<dyn Error>>,
+) -> Result<FinalState, ProcessEventsError> {
+    process_events_observed(
+        events_iter,
+        error_logger,
+        config,
+        snapshot_interval,
+        take_snapshot,
+        &mut (),
+    )
+}
 
+// Like `process_events_with_snapshots`, but `observer` is told about each
+// event as it's accepted or rejected, and about chargebacks and the accounts
+// they lock.
+pub fn process_events_observed<E: Into<SourcedEvent>, Err: Into<ProcessEventsError>>(
+    events_iter: impl Iterator<Item = Result<E, Err>>,
+    error_logger: &mut (impl RejectionLogger + ?Sized),
+    config: &EngineConfig,
+    snapshot_interval: Option<SnapshotInterval>,
+    mut take_snapshot: impl FnMut(
+        &HashMap<ClientID, Client>,
+        &EventCounts,
+    ) -> Result<(), Box<dyn Error>>,
+    observer: &mut (impl ProcessorObserver + ?Sized),
+) -> Result<FinalState, ProcessEventsError> {
+    let mut processor = Processor::new(config);
+    let mut event_counts = EventCounts::default();
+    let mut snapshot_timer = snapshot_interval.map(SnapshotTimer::new);
+
+    let events_iter = ReorderBuffer::new(
+        events_iter.map(|event| event.map(Into::into).map_err(Into::into)),
+        config.reorder_window,
+    );
     for event in events_iter {
-        if let Err(e) = processor.process_event(event?) {
-            error_logger.write_all(format!("{}\n", e).as_bytes())?;
+        process_sourced_event(
+            &mut processor,
+            &mut event_counts,
+            error_logger,
+            observer,
+            event?,
+        )?;
+
+        if let Some(snapshot_timer) = snapshot_timer.as_mut() {
+            if snapshot_timer.tick() {
+                take_snapshot(processor.clients_by_id(), &event_counts)?;
+            }
+        }
+    }
+
+    finish_processing(&mut processor, &mut event_counts, error_logger, observer)?;
+
+    Ok(processor.into_final_state(event_counts)?)
+}
+
+// What became of a single input event. `index` is where it came in, counting
+// from zero, whichever order it was actually processed in (it may have been
+// reordered, or queued until its client was unlocked).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EventOutcome {
+    pub index: u64,
+    pub event: Event,
+    pub result: Result<(), ProcessingError>,
+}
+
+// Like `process_events_with_snapshots` without the snapshots, but hands an
+// `EventOutcome` for every event to `on_outcome` as soon as it's known, so that
+// there's a record of exactly what was accepted and what wasn't. Rejections
+// are still logged as well. Queued events get their outcome once they're
+// finally processed, so the outcomes aren't necessarily in input order. The
+// resolves of disputes that lapse get an outcome too, with the index of the
+// event that they lapsed just before.
+pub fn process_events_with_outcomes<E: Into<SourcedEvent>, Err: Into<ProcessEventsError>>(
+    events_iter: impl Iterator<Item = Result<E, Err>>,
+    error_logger: &mut (impl RejectionLogger + ?Sized),
+    config: &EngineConfig,
+    mut on_outcome: impl FnMut(EventOutcome) -> Result<(), Box<dyn Error>>,
+) -> Result<FinalState, ProcessEventsError> {
+    let mut processor = Processor::new(config);
+    let mut event_counts = EventCounts::default();
+    // the input index of everything the processor has queued, in the same
+    // order as its queue
+    let mut queued: Vec<(u64, SourcedEvent)> = Vec::new();
+    let mut lapsed = LapsedDisputes::default();
+
+    let mut events_iter = ReorderBuffer::new(
+        events_iter.map(|event| event.map(Into::into).map_err(Into::into)),
+        config.reorder_window,
+    );
+    while let Some(indexed_event) = events_iter.next_indexed() {
+        let (index, sourced_event) = indexed_event?;
+        event_counts.count_processed(&sourced_event.event);
+        let source = sourced_event.source.clone();
+        let mut pending = vec![(index, sourced_event)];
+        while !pending.is_empty() {
+            for (index, sourced_event) in pending {
+                let queued_before = processor.queued_event_count();
+                let result = processor.process_event(
+                    sourced_event.event.clone(),
+                    sourced_event.timestamp,
+                    sourced_event.source.as_ref(),
+                    &mut lapsed,
+                );
+                for resolve in lapsed.0.drain(..) {
+                    on_outcome(EventOutcome {
+                        index,
+                        event: resolve,
+                        result: Ok(()),
+                    })?;
+                }
+                if processor.queued_event_count() > queued_before {
+                    queued.push((index, sourced_event));
+                    continue;
+                }
+                if let Err(e) = &result {
+                    reject(
+                        &mut event_counts,
+                        error_logger,
+                        sourced_event.source.as_ref(),
+                        e,
+                    )?;
+                }
+                on_outcome(EventOutcome {
+                    index,
+                    event: sourced_event.event,
+                    result,
+                })?;
+            }
+
+            // whatever this unlocked is processed straight away, and may be
+            // queued again if it locks someone else in turn
+            pending = processor
+                .take_unlocked_events()
+                .into_iter()
+                .map(|unlocked| {
+                    let position = queued
+                        .iter()
+                        .position(|(_, queued_event)| *queued_event == unlocked)
+                        .expect("Every queued event is tracked");
+                    queued.remove(position)
+                })
+                .collect();
+        }
+
+        check_processor(&mut processor, &event_counts, error_logger, source)?;
+    }
+
+    for (queued_event, error) in reject_still_queued(&mut processor) {
+        reject(
+            &mut event_counts,
+            error_logger,
+            queued_event.source.as_ref(),
+            &error,
+        )?;
+        let position = queued
+            .iter()
+            .position(|(_, tracked)| *tracked == queued_event)
+            .expect("Every queued event is tracked");
+        let (index, _) = queued.remove(position);
+        on_outcome(EventOutcome {
+            index,
+            event: queued_event.event,
+            result: Err(error),
+        })?;
+    }
+    processor.flush_journal()?;
+    error_logger.flush_rejections()?;
+
+    Ok(processor.into_final_state(event_counts)?)
+}
+
+// Collects the resolves of lapsed disputes, so that they get outcomes too.
+#[derive(Default)]
+struct LapsedDisputes(Vec<Event>);
+
+impl ProcessorObserver for LapsedDisputes {
+    fn on_dispute_lapsed(&mut self, resolve: &Event) {
+        self.0.push(resolve.clone());
+    }
+}
+
+// Processes a single event, followed by anything it unlocked, counting it and
+// logging it if it's rejected.
+pub(crate) fn process_sourced_event(
+    processor: &mut Processor,
+    event_counts: &mut EventCounts,
+    error_logger: &mut (impl RejectionLogger + ?Sized),
+    observer: &mut (impl ProcessorObserver + ?Sized),
+    sourced_event: SourcedEvent,
+) -> io::Result<()> {
+    let SourcedEvent {
+        event,
+        source,
+        timestamp,
+    } = sourced_event;
+    event_counts.count_processed(&event);
+    if let Err(e) = processor.process_event(event, timestamp, source.as_ref(), observer) {
+        reject(event_counts, error_logger, source.as_ref(), &e)?;
+    }
+
+    // whatever was queued for a client that's just been unlocked is
+    // processed straight after whatever unlocked them
+    for queued in processor.take_unlocked_events() {
+        let source = queued.source.as_ref();
+        if let Err(e) = processor.process_event(queued.event, queued.timestamp, source, observer) {
+            reject(event_counts, error_logger, source, &e)?;
         }
     }
 
-    Ok(processor.clients_by_id())
+    check_processor(processor, event_counts, error_logger, source)
+}
+
+// Checked after each event, to give up on the run if the store has failed or
+// one of the limits has been gone over.
+fn check_processor(
+    processor: &mut Processor,
+    event_counts: &EventCounts,
+    error_logger: &mut (impl RejectionLogger + ?Sized),
+    source: Option<Source>,
+) -> io::Result<()> {
+    if let Some(e) = processor.take_store_error() {
+        return Err(e);
+    }
+    if let Some(e) = processor.take_journal_error() {
+        return Err(e);
+    }
+
+    // the rejections so far are logged before giving up, since they're the
+    // likeliest explanation
+    if let Err(limit) = processor.check_limits(event_counts.rejected) {
+        error_logger.flush_rejections()?;
+        return Err(io::Error::other(LimitExceeded { limit, source }));
+    }
+
+    Ok(())
+}
+
+// Called once there are no more events, to reject whatever never got
+// processed and flush the journal and the logger.
+pub(crate) fn finish_processing(
+    processor: &mut Processor,
+    event_counts: &mut EventCounts,
+    error_logger: &mut (impl RejectionLogger + ?Sized),
+    observer: &mut (impl ProcessorObserver + ?Sized),
+) -> io::Result<()> {
+    for (queued, error) in reject_still_queued(processor) {
+        observer.on_rejected(&queued.event, &error);
+        reject(event_counts, error_logger, queued.source.as_ref(), &error)?;
+    }
+
+    processor.flush_journal()?;
+    error_logger.flush_rejections()
+}
+
+// Anything still queued belongs to a client who was never unlocked, so it
+// never got processed. This takes it all, along with why it's rejected.
+fn reject_still_queued(processor: &mut Processor) -> Vec<(SourcedEvent, ProcessingError)> {
+    processor
+        .take_queued_events()
+        .into_iter()
+        .filter_map(|queued| {
+            let Event::DisputeStep {
+                transaction_id,
+                client_id,
+                ..
+            } = queued.event
+            else {
+                return None;
+            };
+            let error = ProcessingError::StillLocked {
+                client_id,
+                kind: queued.event.kind_name(),
+                id: transaction_id,
+            };
+            Some((queued, error))
+        })
+        .collect()
 }
 
 #[cfg(test)]
 mod test {
-    use crate::model::{DisputeStepKind, TransactionKind};
+    use crate::model::{
+        Amount, ClientProfile, Currency, DisputeStatus, DisputeStepKind, Event, Source,
+        TransactionKind,
+    };
+    use crate::system::{
+        reconcile, ChargebackLimitAction, ClosedAccountPolicy, DuplicateTransactionPolicy, Fee,
+        FeeSchedule, LockedAccountPolicy, LockedDepositPolicy, UndisputedChargebackPolicy,
+        WithdrawalDisputePolicy, DISPUTE_WINDOW_EXPIRED_CODE, INSUFFICIENT_FUNDS_CODE,
+        NOT_DISPUTED_CODE,
+    };
 
     use super::*;
     use pretty_assertions::assert_eq;
     use rust_decimal_macros::dec;
-    use std::io;
+    use std::{io, time::Duration};
+
+    // these tests are mostly about balances, so this drops the audit counters
+    // and keeps just the balance in the unnamed currency
+    fn balances_only(client: &Client) -> Client {
+        let balance = client.balance(Currency::default());
+        Client::create(balance.held(), balance.total(), client.locked())
+    }
 
     // helper method for when we just want to provide an input and assert on the
     // output
@@ -44,7 +493,15 @@ mod test {
         let error_str = String::from_utf8(error_logger).expect("Not UTF-8");
         let errors = error_str.lines().collect::<Vec<_>>();
 
-        assert_eq!(expected_clients_by_id, result);
+        // these tests are about balances, so we compare against clients built
+        // from their balances alone; the audit counters have their own tests
+        let clients_by_id = result
+            .clients_by_id
+            .into_iter()
+            .map(|(client_id, client)| (client_id, balances_only(&client)))
+            .collect::<HashMap<_, _>>();
+
+        assert_eq!(expected_clients_by_id, clients_by_id);
         assert_eq!(expected_errors, errors);
     }
 
@@ -63,7 +520,9 @@ mod test {
                 kind: TransactionKind::Deposit,
                 client_id,
                 transaction_id: 1,
+                currency: Currency::default(),
                 amount: deposit_amount,
+                counterparty: None,
             })],
             HashMap::from([(client_id, Client::create(dec!(0), deposit_amount, false))]),
             vec![],
@@ -80,7 +539,9 @@ mod test {
                 kind: TransactionKind::Deposit,
                 client_id,
                 transaction_id: 1,
+                currency: Currency::default(),
                 amount: deposit_amount,
+                counterparty: None,
             })],
             HashMap::from([(client_id, Client::create(dec!(0), deposit_amount, false))]),
             vec![],
@@ -99,13 +560,17 @@ mod test {
                     kind: TransactionKind::Deposit,
                     client_id,
                     transaction_id: 1,
+                    currency: Currency::default(),
                     amount: first_deposit_amount,
+                    counterparty: None,
                 }),
                 Ok(Event::Transaction {
                     kind: TransactionKind::Deposit,
                     client_id,
                     transaction_id: 2,
+                    currency: Currency::default(),
                     amount: second_deposit_amount,
+                    counterparty: None,
                 }),
             ],
             HashMap::from([(
@@ -128,13 +593,17 @@ mod test {
                     kind: TransactionKind::Deposit,
                     client_id,
                     transaction_id,
+                    currency: Currency::default(),
                     amount: first_deposit_amount,
+                    counterparty: None,
                 }),
                 Ok(Event::Transaction {
                     kind: TransactionKind::Deposit,
                     client_id,
                     transaction_id,
+                    currency: Currency::default(),
                     amount: dec!(20),
+                    counterparty: None,
                 }),
             ],
             HashMap::from([(
@@ -149,19 +618,23 @@ mod test {
     fn test_error_event() {
         let client_id = 1;
         let deposit_amount = dec!(100);
-        let input_events = vec![
+        let input_events: Vec<Result<Event, Box<dyn Error>>> = vec![
             Ok(Event::Transaction {
                 kind: TransactionKind::Deposit,
                 client_id,
                 transaction_id: 1,
+                currency: Currency::default(),
                 amount: deposit_amount,
+                counterparty: None,
             }),
             Err("Test".into()),
             Ok(Event::Transaction {
                 kind: TransactionKind::Deposit,
                 client_id,
                 transaction_id: 2,
+                currency: Currency::default(),
                 amount: dec!(10),
+                counterparty: None,
             }),
         ];
 
@@ -182,13 +655,17 @@ mod test {
                     kind: TransactionKind::Deposit,
                     client_id,
                     transaction_id: 1,
+                    currency: Currency::default(),
                     amount: deposit_amount,
+                    counterparty: None,
                 }),
                 Ok(Event::Transaction {
                     kind: TransactionKind::Withdrawal,
                     client_id,
                     transaction_id: 2,
+                    currency: Currency::default(),
                     amount: withdrawal_amount,
+                    counterparty: None,
                 }),
             ],
             HashMap::from([(
@@ -211,13 +688,17 @@ mod test {
                     kind: TransactionKind::Deposit,
                     client_id,
                     transaction_id: 1,
+                    currency: Currency::default(),
                     amount: deposit_amount,
+                    counterparty: None,
                 }),
                 Ok(Event::Transaction {
                     kind: TransactionKind::Withdrawal,
                     client_id,
                     transaction_id: 2,
+                    currency: Currency::default(),
                     amount: withdrawal_amount,
+                    counterparty: None,
                 }),
             ],
             HashMap::from([(client_id, Client::create(dec!(0), deposit_amount, false))]),
@@ -236,13 +717,17 @@ mod test {
                     kind: TransactionKind::Deposit,
                     client_id,
                     transaction_id: 1,
+                    currency: Currency::default(),
                     amount: deposit_amount,
+                    counterparty: None,
                 }),
                 Ok(Event::Transaction {
                     kind: TransactionKind::Withdrawal,
                     client_id,
                     transaction_id: 1,
+                    currency: Currency::default(),
                     amount: dec!(100),
+                    counterparty: None,
                 }),
             ],
             HashMap::from([(client_id, Client::create(dec!(0), deposit_amount, false))]),
@@ -262,12 +747,15 @@ mod test {
                     kind: TransactionKind::Deposit,
                     client_id,
                     transaction_id: deposit_transaction_id,
+                    currency: Currency::default(),
                     amount: deposit_amount,
+                    counterparty: None,
                 }),
                 Ok(Event::DisputeStep {
                     kind: DisputeStepKind::Dispute,
                     client_id,
                     transaction_id: deposit_transaction_id,
+                    amount: None,
                 }),
             ],
             HashMap::from([(
@@ -290,12 +778,15 @@ mod test {
                     kind: TransactionKind::Deposit,
                     client_id,
                     transaction_id: deposit_transaction_id,
+                    currency: Currency::default(),
                     amount: deposit_amount,
+                    counterparty: None,
                 }),
                 Ok(Event::DisputeStep {
                     kind: DisputeStepKind::Dispute,
                     client_id,
                     transaction_id: 3,
+                    amount: None,
                 }),
             ],
             HashMap::from([(client_id, Client::create(dec!(0), deposit_amount, false))]),
@@ -315,12 +806,15 @@ mod test {
                     kind: TransactionKind::Deposit,
                     client_id,
                     transaction_id: deposit_transaction_id,
+                    currency: Currency::default(),
                     amount: deposit_amount,
+                    counterparty: None,
                 }),
                 Ok(Event::DisputeStep {
                     kind: DisputeStepKind::Dispute,
                     client_id: 3,
                     transaction_id: deposit_transaction_id,
+                    amount: None,
                 }),
             ],
             HashMap::from([(client_id, Client::create(dec!(0), deposit_amount, false))]),
@@ -342,17 +836,21 @@ mod test {
                     kind: TransactionKind::Deposit,
                     client_id,
                     transaction_id: deposit_transaction_id,
+                    currency: Currency::default(),
                     amount: deposit_amount,
+                    counterparty: None,
                 }),
                 Ok(Event::DisputeStep {
                     kind: DisputeStepKind::Dispute,
                     client_id,
                     transaction_id: deposit_transaction_id,
+                    amount: None,
                 }),
                 Ok(Event::DisputeStep {
                     kind: DisputeStepKind::Dispute,
                     client_id,
                     transaction_id: deposit_transaction_id,
+                    amount: None,
                 }),
             ],
             HashMap::from([(
@@ -375,22 +873,27 @@ mod test {
                     kind: TransactionKind::Deposit,
                     client_id,
                     transaction_id: deposit_transaction_id,
+                    currency: Currency::default(),
                     amount: deposit_amount,
+                    counterparty: None,
                 }),
                 Ok(Event::DisputeStep {
                     kind: DisputeStepKind::Dispute,
                     client_id,
                     transaction_id: deposit_transaction_id,
+                    amount: None,
                 }),
                 Ok(Event::DisputeStep {
                     kind: DisputeStepKind::Chargeback,
                     client_id,
                     transaction_id: deposit_transaction_id,
+                    amount: None,
                 }),
                 Ok(Event::DisputeStep {
                     kind: DisputeStepKind::Dispute,
                     client_id,
                     transaction_id: deposit_transaction_id,
+                    amount: None,
                 }),
             ],
             HashMap::from([(client_id, Client::create(dec!(0), dec!(0), true))]),
@@ -410,22 +913,27 @@ mod test {
                     kind: TransactionKind::Deposit,
                     client_id,
                     transaction_id: deposit_transaction_id,
+                    currency: Currency::default(),
                     amount: deposit_amount,
+                    counterparty: None,
                 }),
                 Ok(Event::DisputeStep {
                     kind: DisputeStepKind::Dispute,
                     client_id,
                     transaction_id: deposit_transaction_id,
+                    amount: None,
                 }),
                 Ok(Event::DisputeStep {
                     kind: DisputeStepKind::Resolve,
                     client_id,
                     transaction_id: deposit_transaction_id,
+                    amount: None,
                 }),
                 Ok(Event::DisputeStep {
                     kind: DisputeStepKind::Dispute,
                     client_id,
                     transaction_id: deposit_transaction_id,
+                    amount: None,
                 }),
             ],
             HashMap::from([(
@@ -448,12 +956,15 @@ mod test {
                     kind: TransactionKind::Withdrawal,
                     client_id,
                     transaction_id: withdrawal_transaction_id,
+                    currency: Currency::default(),
                     amount: dec!(10),
+                    counterparty: None,
                 }),
                 Ok(Event::DisputeStep {
                     kind: DisputeStepKind::Dispute,
                     client_id,
                     transaction_id: withdrawal_transaction_id,
+                    amount: None,
                 }),
             ],
             HashMap::from([(client_id, Client::create(dec!(0), dec!(0), false))]),
@@ -476,17 +987,21 @@ mod test {
                     kind: TransactionKind::Deposit,
                     client_id,
                     transaction_id: deposit_transaction_id,
+                    currency: Currency::default(),
                     amount: deposit_amount,
+                    counterparty: None,
                 }),
                 Ok(Event::DisputeStep {
                     kind: DisputeStepKind::Dispute,
                     client_id,
                     transaction_id: deposit_transaction_id,
+                    amount: None,
                 }),
                 Ok(Event::DisputeStep {
                     kind: DisputeStepKind::Resolve,
                     client_id,
                     transaction_id: deposit_transaction_id,
+                    amount: None,
                 }),
             ],
             HashMap::from([(client_id, Client::create(dec!(0), deposit_amount, false))]),
@@ -508,23 +1023,29 @@ mod test {
                     kind: TransactionKind::Deposit,
                     client_id,
                     transaction_id: deposit_transaction_id,
+                    currency: Currency::default(),
                     amount: deposit_amount,
+                    counterparty: None,
                 }),
                 Ok(Event::Transaction {
                     kind: TransactionKind::Withdrawal,
                     client_id,
                     transaction_id: withdrawal_transaction_id,
+                    currency: Currency::default(),
                     amount: withdrawal_amount,
+                    counterparty: None,
                 }),
                 Ok(Event::DisputeStep {
                     kind: DisputeStepKind::Dispute,
                     client_id,
                     transaction_id: withdrawal_transaction_id,
+                    amount: None,
                 }),
                 Ok(Event::DisputeStep {
                     kind: DisputeStepKind::Resolve,
                     client_id,
                     transaction_id: withdrawal_transaction_id,
+                    amount: None,
                 }),
             ],
             HashMap::from([(
@@ -547,12 +1068,15 @@ mod test {
                     kind: TransactionKind::Deposit,
                     client_id,
                     transaction_id: deposit_transaction_id,
+                    currency: Currency::default(),
                     amount: deposit_amount,
+                    counterparty: None,
                 }),
                 Ok(Event::DisputeStep {
                     kind: DisputeStepKind::Resolve,
                     client_id,
                     transaction_id: deposit_transaction_id,
+                    amount: None,
                 }),
             ],
             HashMap::from([(client_id, Client::create(dec!(0), deposit_amount, false))]),
@@ -572,22 +1096,27 @@ mod test {
                     kind: TransactionKind::Deposit,
                     client_id,
                     transaction_id: deposit_transaction_id,
+                    currency: Currency::default(),
                     amount: deposit_amount,
+                    counterparty: None,
                 }),
                 Ok(Event::DisputeStep {
                     kind: DisputeStepKind::Dispute,
                     client_id,
                     transaction_id: deposit_transaction_id,
+                    amount: None,
                 }),
                 Ok(Event::DisputeStep {
                     kind: DisputeStepKind::Resolve,
                     client_id,
                     transaction_id: deposit_transaction_id,
+                    amount: None,
                 }),
                 Ok(Event::DisputeStep {
                     kind: DisputeStepKind::Resolve,
                     client_id,
                     transaction_id: deposit_transaction_id,
+                    amount: None,
                 }),
             ],
             HashMap::from([(client_id, Client::create(dec!(0), deposit_amount, false))]),
@@ -607,17 +1136,21 @@ mod test {
                     kind: TransactionKind::Deposit,
                     client_id,
                     transaction_id: deposit_transaction_id,
+                    currency: Currency::default(),
                     amount: deposit_amount,
+                    counterparty: None,
                 }),
                 Ok(Event::DisputeStep {
                     kind: DisputeStepKind::Dispute,
                     client_id,
                     transaction_id: deposit_transaction_id,
+                    amount: None,
                 }),
                 Ok(Event::DisputeStep {
                     kind: DisputeStepKind::Resolve,
                     client_id,
                     transaction_id: 3,
+                    amount: None,
                 }),
             ],
             HashMap::from([(
@@ -640,17 +1173,21 @@ mod test {
                     kind: TransactionKind::Deposit,
                     client_id,
                     transaction_id: deposit_transaction_id,
+                    currency: Currency::default(),
                     amount: deposit_amount,
+                    counterparty: None,
                 }),
                 Ok(Event::DisputeStep {
                     kind: DisputeStepKind::Dispute,
                     client_id,
                     transaction_id: deposit_transaction_id,
+                    amount: None,
                 }),
                 Ok(Event::DisputeStep {
                     kind: DisputeStepKind::Chargeback,
                     client_id,
                     transaction_id: deposit_transaction_id,
+                    amount: None,
                 }),
             ],
             HashMap::from([(client_id, Client::create(dec!(0), dec!(0), true))]),
@@ -672,23 +1209,29 @@ mod test {
                     kind: TransactionKind::Deposit,
                     client_id,
                     transaction_id: deposit_transaction_id,
+                    currency: Currency::default(),
                     amount: deposit_amount,
+                    counterparty: None,
                 }),
                 Ok(Event::Transaction {
                     kind: TransactionKind::Withdrawal,
                     client_id,
                     transaction_id: withdrawal_transaction_id,
+                    currency: Currency::default(),
                     amount: withdrawal_amount,
+                    counterparty: None,
                 }),
                 Ok(Event::DisputeStep {
                     kind: DisputeStepKind::Dispute,
                     client_id,
                     transaction_id: withdrawal_transaction_id,
+                    amount: None,
                 }),
                 Ok(Event::DisputeStep {
                     kind: DisputeStepKind::Chargeback,
                     client_id,
                     transaction_id: withdrawal_transaction_id,
+                    amount: None,
                 }),
             ],
             HashMap::from([(client_id, Client::create(dec!(0), deposit_amount, true))]),
@@ -708,12 +1251,15 @@ mod test {
                     kind: TransactionKind::Deposit,
                     client_id,
                     transaction_id: deposit_transaction_id,
+                    currency: Currency::default(),
                     amount: deposit_amount,
+                    counterparty: None,
                 }),
                 Ok(Event::DisputeStep {
                     kind: DisputeStepKind::Chargeback,
                     client_id,
                     transaction_id: deposit_transaction_id,
+                    amount: None,
                 }),
             ],
             HashMap::from([(client_id, Client::create(dec!(0), deposit_amount, false))]),
@@ -733,12 +1279,15 @@ mod test {
                     kind: TransactionKind::Deposit,
                     client_id,
                     transaction_id: deposit_transaction_id,
+                    currency: Currency::default(),
                     amount: deposit_amount,
+                    counterparty: None,
                 }),
                 Ok(Event::DisputeStep {
                     kind: DisputeStepKind::Chargeback,
                     client_id,
                     transaction_id: 3,
+                    amount: None,
                 }),
             ],
             HashMap::from([(client_id, Client::create(dec!(0), deposit_amount, false))]),
@@ -758,12 +1307,15 @@ mod test {
                     kind: TransactionKind::Deposit,
                     client_id,
                     transaction_id: deposit_transaction_id,
+                    currency: Currency::default(),
                     amount: deposit_amount,
+                    counterparty: None,
                 }),
                 Ok(Event::DisputeStep {
                     kind: DisputeStepKind::Chargeback,
                     client_id: 3,
                     transaction_id: deposit_transaction_id,
+                    amount: None,
                 }),
             ],
             HashMap::from([(client_id, Client::create(dec!(0), deposit_amount, false))]),
@@ -786,18 +1338,23 @@ mod test {
                     kind: TransactionKind::Deposit,
                     client_id,
                     transaction_id: deposit_transaction_id,
+                    currency: Currency::default(),
                     amount: deposit_amount,
+                    counterparty: None,
                 }),
                 Ok(Event::Transaction {
                     kind: TransactionKind::Withdrawal,
                     client_id,
                     transaction_id: withdrawal_transaction_id,
+                    currency: Currency::default(),
                     amount: deposit_amount,
+                    counterparty: None,
                 }),
                 Ok(Event::DisputeStep {
                     kind: DisputeStepKind::Dispute,
                     client_id,
                     transaction_id: deposit_transaction_id,
+                    amount: None,
                 }),
             ],
             HashMap::from([(client_id, Client::create(deposit_amount, dec!(0), false))]),
@@ -818,22 +1375,2102 @@ mod test {
                     kind: TransactionKind::Deposit,
                     client_id,
                     transaction_id: deposit_transaction_id,
+                    currency: Currency::default(),
                     amount: deposit_amount,
+                    counterparty: None,
                 }),
                 Ok(Event::Transaction {
                     kind: TransactionKind::Withdrawal,
                     client_id,
                     transaction_id: withdrawal_transaction_id,
+                    currency: Currency::default(),
                     amount: deposit_amount,
+                    counterparty: None,
                 }),
                 Ok(Event::DisputeStep {
                     kind: DisputeStepKind::Dispute,
                     client_id,
                     transaction_id: withdrawal_transaction_id,
+                    amount: None,
                 }),
             ],
             HashMap::from([(client_id, Client::create(deposit_amount, dec!(0), false))]),
             vec![],
         );
     }
+
+    #[test]
+    fn test_final_state_includes_dispute_statuses() {
+        let client_id = 1;
+        let input_events: Vec<Result<Event, ProcessEventsError>> = vec![
+            Ok(Event::Transaction {
+                kind: TransactionKind::Deposit,
+                client_id,
+                transaction_id: 1,
+                currency: Currency::default(),
+                amount: dec!(10),
+                counterparty: None,
+            }),
+            Ok(Event::Transaction {
+                kind: TransactionKind::Deposit,
+                client_id,
+                transaction_id: 2,
+                currency: Currency::default(),
+                amount: dec!(20),
+                counterparty: None,
+            }),
+            Ok(Event::Transaction {
+                kind: TransactionKind::Deposit,
+                client_id,
+                transaction_id: 3,
+                currency: Currency::default(),
+                amount: dec!(30),
+                counterparty: None,
+            }),
+            Ok(Event::DisputeStep {
+                kind: DisputeStepKind::Dispute,
+                client_id,
+                transaction_id: 2,
+                amount: None,
+            }),
+            Ok(Event::DisputeStep {
+                kind: DisputeStepKind::Dispute,
+                client_id,
+                transaction_id: 3,
+                amount: None,
+            }),
+            Ok(Event::DisputeStep {
+                kind: DisputeStepKind::Chargeback,
+                client_id,
+                transaction_id: 3,
+                amount: None,
+            }),
+        ];
+
+        let result = process_events(input_events.into_iter(), &mut io::sink())
+            .expect("Unexpectedly failed to process events.");
+
+        let dispute_status = |transaction_id| {
+            result
+                .transactions_by_id
+                .get(&transaction_id)
+                .map(Transaction::dispute_status)
+        };
+        assert_eq!(Some(DisputeStatus::Undisputed), dispute_status(1));
+        assert_eq!(Some(DisputeStatus::Disputed), dispute_status(2));
+        assert_eq!(Some(DisputeStatus::ChargedBack), dispute_status(3));
+    }
+
+    #[test]
+    fn test_open_disputes() {
+        let input_events = vec![
+            deposit(1, 3, dec!(5)),
+            deposit(1, 1, dec!(5)),
+            deposit(1, 2, dec!(5)),
+            deposit(2, 4, dec!(5)),
+            dispute_step(DisputeStepKind::Dispute, 1, 3),
+            dispute_step(DisputeStepKind::Dispute, 1, 1),
+            dispute_step(DisputeStepKind::Dispute, 1, 2),
+            dispute_step(DisputeStepKind::Resolve, 1, 2),
+            dispute_step(DisputeStepKind::Dispute, 2, 4),
+        ];
+
+        let result = process_events(input_events.into_iter(), &mut io::sink())
+            .expect("Unexpectedly failed to process events.");
+
+        let open_disputes = |client_id| {
+            result
+                .open_disputes(client_id)
+                .into_iter()
+                .map(|(transaction_id, _)| transaction_id)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(vec![1, 3], open_disputes(1));
+        assert_eq!(vec![4], open_disputes(2));
+        assert_eq!(Vec::<TransactionID>::new(), open_disputes(3));
+    }
+
+    fn deposit(
+        client_id: ClientID,
+        transaction_id: TransactionID,
+        amount: Amount,
+    ) -> Result<Event, Box<dyn Error>> {
+        Ok(Event::Transaction {
+            kind: TransactionKind::Deposit,
+            client_id,
+            transaction_id,
+            currency: Currency::default(),
+            amount,
+            counterparty: None,
+        })
+    }
+
+    fn transfer(
+        from_client_id: ClientID,
+        to_client_id: ClientID,
+        transaction_id: TransactionID,
+        amount: Amount,
+    ) -> Result<Event, Box<dyn Error>> {
+        Ok(Event::Transfer {
+            transaction_id,
+            from_client_id,
+            to_client_id,
+            currency: Currency::default(),
+            amount,
+        })
+    }
+
+    fn dispute_step(
+        kind: DisputeStepKind,
+        client_id: ClientID,
+        transaction_id: TransactionID,
+    ) -> Result<Event, Box<dyn Error>> {
+        Ok(Event::DisputeStep {
+            kind,
+            client_id,
+            transaction_id,
+            amount: None,
+        })
+    }
+
+    #[test]
+    fn test_successful_transfer() {
+        assert_results(
+            vec![deposit(1, 1, dec!(10)), transfer(1, 2, 2, dec!(4))],
+            HashMap::from([
+                (1, Client::create(dec!(0), dec!(6), false)),
+                (2, Client::create(dec!(0), dec!(4), false)),
+            ]),
+            vec![],
+        );
+    }
+
+    #[test]
+    fn test_unsuccessful_transfer_due_to_insufficient_funds() {
+        assert_results(
+            vec![deposit(1, 1, dec!(10)), transfer(1, 2, 2, dec!(11))],
+            HashMap::from([
+                (1, Client::create(dec!(0), dec!(10), false)),
+                (2, Client::create(dec!(0), dec!(0), false)),
+            ]),
+            vec![String::from("Insufficient funds.")],
+        );
+    }
+
+    #[test]
+    fn test_unsuccessful_transfer_due_to_locked_recipient() {
+        // the sender keeps their money rather than it vanishing
+        assert_results(
+            vec![
+                deposit(1, 1, dec!(10)),
+                deposit(2, 2, dec!(5)),
+                dispute_step(DisputeStepKind::Dispute, 2, 2),
+                dispute_step(DisputeStepKind::Chargeback, 2, 2),
+                transfer(1, 2, 3, dec!(4)),
+            ],
+            HashMap::from([
+                (1, Client::create(dec!(0), dec!(10), false)),
+                (2, Client::create(dec!(0), dec!(0), true)),
+            ]),
+            vec![String::from("Cannot deposit when account is locked.")],
+        );
+    }
+
+    #[test]
+    fn test_deposits_into_locked_account() {
+        let (result, errors) = process_events_with_config(
+            vec![
+                deposit(1, 1, dec!(10)),
+                deposit(2, 2, dec!(5)),
+                dispute_step(DisputeStepKind::Dispute, 2, 2),
+                dispute_step(DisputeStepKind::Chargeback, 2, 2),
+                deposit(2, 3, dec!(3)),
+                transfer(1, 2, 4, dec!(4)),
+                // money still can't leave
+                withdrawal(2, 5, dec!(1)),
+                transfer(2, 1, 6, dec!(1)),
+            ],
+            EngineConfig {
+                locked_account_deposits: LockedDepositPolicy::Accept,
+                ..EngineConfig::default()
+            },
+        );
+
+        assert_eq!(
+            vec![
+                "Cannot withdraw when account is locked.",
+                "Cannot withdraw when account is locked.",
+            ],
+            errors
+        );
+        assert_eq!(
+            Client::create(dec!(0), dec!(6), false),
+            balances_only(&result.clients_by_id[&1])
+        );
+        assert_eq!(
+            Client::create(dec!(0), dec!(7), true),
+            balances_only(&result.clients_by_id[&2])
+        );
+    }
+
+    #[test]
+    fn test_unsuccessful_transfer_to_self() {
+        assert_results(
+            vec![deposit(1, 1, dec!(10)), transfer(1, 1, 2, dec!(4))],
+            HashMap::from([(1, Client::create(dec!(0), dec!(10), false))]),
+            vec![String::from("Cannot transfer from client 1 to itself.")],
+        );
+    }
+
+    #[test]
+    fn test_unsuccessful_transfer_due_to_existing_transaction() {
+        assert_results(
+            vec![deposit(1, 1, dec!(10)), transfer(1, 2, 1, dec!(4))],
+            HashMap::from([(1, Client::create(dec!(0), dec!(10), false))]),
+            vec![String::from("Transaction already exists with id 1.")],
+        );
+    }
+
+    #[test]
+    fn test_disputed_transfer_both_sides() {
+        // each client disputes their own side of the transfer: the sender's
+        // side is treated like a withdrawal and the recipient's like a deposit
+        assert_results(
+            vec![
+                deposit(1, 1, dec!(10)),
+                transfer(1, 2, 2, dec!(4)),
+                dispute_step(DisputeStepKind::Dispute, 1, 2),
+                dispute_step(DisputeStepKind::Dispute, 2, 2),
+                dispute_step(DisputeStepKind::Chargeback, 2, 2),
+            ],
+            HashMap::from([
+                (1, Client::create(dec!(4), dec!(6), false)),
+                (2, Client::create(dec!(0), dec!(0), true)),
+            ]),
+            vec![],
+        );
+    }
+
+    #[test]
+    fn test_transfer_dispute_by_uninvolved_client() {
+        assert_results(
+            vec![
+                deposit(1, 1, dec!(10)),
+                transfer(1, 2, 2, dec!(4)),
+                dispute_step(DisputeStepKind::Dispute, 3, 2),
+            ],
+            HashMap::from([
+                (1, Client::create(dec!(0), dec!(6), false)),
+                (2, Client::create(dec!(0), dec!(4), false)),
+            ]),
+            vec![String::from(
+                "Client id 3 does not match transaction client id 1.",
+            )],
+        );
+    }
+
+    fn fee(
+        client_id: ClientID,
+        transaction_id: TransactionID,
+        amount: Amount,
+    ) -> Result<Event, Box<dyn Error>> {
+        Ok(Event::Fee {
+            transaction_id,
+            client_id,
+            currency: Currency::default(),
+            amount,
+        })
+    }
+
+    fn withdrawal(
+        client_id: ClientID,
+        transaction_id: TransactionID,
+        amount: Amount,
+    ) -> Result<Event, Box<dyn Error>> {
+        Ok(Event::Transaction {
+            kind: TransactionKind::Withdrawal,
+            client_id,
+            transaction_id,
+            currency: Currency::default(),
+            amount,
+            counterparty: None,
+        })
+    }
+
+    fn process_events_with_config(
+        input_events: Vec<Result<Event, Box<dyn Error>>>,
+        config: EngineConfig,
+    ) -> (FinalState, Vec<String>) {
+        let mut error_logger = Vec::new();
+
+        // so that every test of a policy doubles as a check of the invariants
+        let result = process_events_with_snapshots(
+            input_events.into_iter(),
+            &mut error_logger,
+            &EngineConfig {
+                check_invariants: true,
+                ..config
+            },
+            None,
+            |_, _| Ok(()),
+        )
+        .expect("Unexpectedly failed to process events.");
+
+        let error_str = String::from_utf8(error_logger).expect("Not UTF-8");
+        (result, error_str.lines().map(String::from).collect())
+    }
+
+    #[test]
+    fn test_fee_event() {
+        let (result, errors) = process_events_with_config(
+            vec![
+                deposit(1, 1, dec!(10)),
+                fee(1, 2, dec!(1.5)),
+                // more than what's left
+                fee(1, 3, dec!(9)),
+                // clashes with the deposit
+                fee(1, 1, dec!(1)),
+                // clashes with the first fee, either way round
+                fee(1, 2, dec!(1)),
+                deposit(1, 2, dec!(1)),
+            ],
+            EngineConfig::default(),
+        );
+
+        assert_eq!(
+            HashMap::from([(
+                1,
+                Client::create(dec!(0), dec!(8.5), false)
+                    .with_fees(dec!(1.5))
+                    .with_last_transaction_id(1)
+            )]),
+            result.clients_by_id
+        );
+        assert_eq!(
+            vec![
+                "Insufficient funds.",
+                "Transaction already exists with id 1.",
+                "Transaction already exists with id 2.",
+                "Transaction already exists with id 2.",
+            ],
+            errors
+        );
+    }
+
+    #[test]
+    fn test_fee_schedule() {
+        let (result, errors) = process_events_with_config(
+            vec![
+                deposit(1, 1, dec!(100)),
+                withdrawal(1, 2, dec!(50)),
+                // the fee takes this over what's available
+                withdrawal(1, 3, dec!(48)),
+                // the fee can't be more than the deposit itself
+                deposit(2, 4, dec!(0.5)),
+                // transfers are free
+                transfer(1, 2, 5, dec!(10)),
+            ],
+            EngineConfig {
+                fee_schedule: FeeSchedule {
+                    deposit: Some(Fee {
+                        flat: dec!(1),
+                        percent: dec!(0),
+                    }),
+                    withdrawal: Some(Fee {
+                        flat: dec!(0),
+                        percent: dec!(2),
+                    }),
+                },
+                ..EngineConfig::default()
+            },
+        );
+
+        assert_eq!(
+            HashMap::from([
+                (
+                    1,
+                    Client::create(dec!(0), dec!(38), false)
+                        .with_fees(dec!(2))
+                        .with_last_transaction_id(5)
+                ),
+                (
+                    2,
+                    Client::create(dec!(0), dec!(10), false)
+                        .with_fees(dec!(0.5))
+                        .with_last_transaction_id(5)
+                ),
+            ]),
+            result.clients_by_id
+        );
+        assert_eq!(vec!["Insufficient funds."], errors);
+    }
+
+    fn process_withdrawal_dispute(
+        policy: WithdrawalDisputePolicy,
+        last_step: DisputeStepKind,
+    ) -> (Client, Vec<String>) {
+        let (mut result, errors) = process_events_with_config(
+            vec![
+                deposit(1, 1, dec!(100)),
+                withdrawal(1, 2, dec!(20)),
+                dispute_step(DisputeStepKind::Dispute, 1, 2),
+                dispute_step(last_step, 1, 2),
+            ],
+            EngineConfig {
+                withdrawal_disputes: policy,
+                ..EngineConfig::default()
+            },
+        );
+        let client = result.clients_by_id.remove(&1).expect("Expected client 1.");
+
+        (balances_only(&client), errors)
+    }
+
+    #[test]
+    fn test_withdrawal_disputes_rejected() {
+        assert_eq!(
+            (
+                Client::create(dec!(0), dec!(80), false),
+                vec![
+                    String::from("Only deposits can be disputed."),
+                    String::from("Transaction is not disputed."),
+                ]
+            ),
+            process_withdrawal_dispute(
+                WithdrawalDisputePolicy::Reject,
+                DisputeStepKind::Chargeback
+            )
+        );
+    }
+
+    #[test]
+    fn test_disputable_only() {
+        let config = |withdrawal_disputes| EngineConfig {
+            withdrawal_disputes,
+            disputable_only: true,
+            ..EngineConfig::default()
+        };
+        let events = || {
+            vec![
+                deposit(1, 1, dec!(10)),
+                withdrawal(1, 2, dec!(3)),
+                // the ID is still taken
+                deposit(1, 2, dec!(1)),
+                dispute_step(DisputeStepKind::Dispute, 1, 2),
+                reversal(1, 2),
+            ]
+        };
+
+        let (result, errors) =
+            process_events_with_config(events(), config(WithdrawalDisputePolicy::Reject));
+        assert_eq!(
+            vec![
+                "Transaction already exists with id 2.",
+                "Withdrawal 2 was not kept, so it cannot be disputed or reversed.",
+                "Withdrawal 2 was not kept, so it cannot be disputed or reversed.",
+            ],
+            errors
+        );
+        assert_eq!(
+            vec![1],
+            result
+                .transactions_by_id
+                .keys()
+                .copied()
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            Client::create(dec!(0), dec!(7), false),
+            balances_only(&result.clients_by_id[&1])
+        );
+        assert_eq!(
+            HashMap::from([(Currency::default(), dec!(3))]),
+            result.discarded_withdrawals
+        );
+        assert!(reconcile(&result, &config(WithdrawalDisputePolicy::Reject))
+            .iter()
+            .all(|reconciliation| reconciliation.discrepancy().is_zero()));
+
+        // withdrawals that can be disputed are still kept
+        let (result, _) =
+            process_events_with_config(events(), config(WithdrawalDisputePolicy::Hold));
+        assert_eq!(2, result.transactions_by_id.len());
+        assert!(result.discarded_withdrawals.is_empty());
+    }
+
+    #[test]
+    fn test_amount_overflow() {
+        let adjustment = |transaction_id, amount| {
+            Ok(Event::Adjustment {
+                transaction_id,
+                client_id: 1,
+                currency: Currency::default(),
+                amount,
+                reason: String::from("Correction"),
+            })
+        };
+        let (result, errors) = process_events_with_config(
+            vec![
+                deposit(1, 1, Amount::MAX),
+                deposit(1, 2, dec!(1)),
+                // the withdrawal would go through, but not the deposit
+                deposit(2, 3, dec!(5)),
+                transfer(2, 1, 4, dec!(5)),
+                dispute_step(DisputeStepKind::Dispute, 1, 1),
+                adjustment(5, -Amount::MAX),
+                // the total would still fit, but the available funds wouldn't
+                adjustment(6, dec!(-1)),
+                Ok(Event::Conversion {
+                    transaction_id: 7,
+                    client_id: 2,
+                    from_currency: Currency::default(),
+                    to_currency: "EUR".parse().expect("Expected a valid currency."),
+                    amount: dec!(5),
+                    rate: Amount::MAX,
+                }),
+            ],
+            EngineConfig::default(),
+        );
+
+        assert_eq!(vec!["Amount is too large to process."; 4], errors);
+        assert_eq!(
+            Client::create(Amount::MAX, dec!(0), false),
+            balances_only(&result.clients_by_id[&1])
+        );
+        assert_eq!(
+            Client::create(dec!(0), dec!(5), false),
+            balances_only(&result.clients_by_id[&2])
+        );
+    }
+
+    #[test]
+    fn test_identical_duplicates_ignored() {
+        let config = EngineConfig {
+            duplicate_transactions: DuplicateTransactionPolicy::IgnoreIdentical,
+            ..EngineConfig::default()
+        };
+        let (result, errors) = process_events_with_config(
+            vec![
+                deposit(1, 1, dec!(10)),
+                withdrawal(1, 2, dec!(3)),
+                transfer(1, 2, 3, dec!(2)),
+                deposit(1, 1, dec!(10)),
+                withdrawal(1, 2, dec!(3.0)),
+                // reusing an ID for anything else is still an error
+                deposit(1, 1, dec!(11)),
+                deposit(2, 1, dec!(10)),
+                withdrawal(1, 1, dec!(10)),
+                withdrawal(1, 3, dec!(2)),
+            ],
+            config,
+        );
+        assert_eq!(
+            vec![
+                "Transaction already exists with id 1.",
+                "Transaction already exists with id 1.",
+                "Transaction already exists with id 1.",
+                "Transaction already exists with id 3.",
+            ],
+            errors
+        );
+        assert_eq!(9, result.event_counts.processed);
+        assert_eq!(4, result.event_counts.rejected);
+        assert_eq!(
+            Client::create(dec!(0), dec!(5), false),
+            balances_only(&result.clients_by_id[&1])
+        );
+
+        // by default even an identical one is rejected
+        let (_, errors) = process_events_with_config(
+            vec![deposit(1, 1, dec!(10)), deposit(1, 1, dec!(10))],
+            EngineConfig::default(),
+        );
+        assert_eq!(vec!["Transaction already exists with id 1."], errors);
+    }
+
+    #[test]
+    fn test_withdrawal_disputes_credited_as_held() {
+        let (result, _) = process_events_with_config(
+            vec![
+                deposit(1, 1, dec!(100)),
+                withdrawal(1, 2, dec!(20)),
+                dispute_step(DisputeStepKind::Dispute, 1, 2),
+            ],
+            EngineConfig {
+                withdrawal_disputes: WithdrawalDisputePolicy::CreditHeld,
+                ..EngineConfig::default()
+            },
+        );
+        // the available funds are what they were before the dispute
+        assert_eq!(
+            dec!(80),
+            result.clients_by_id[&1]
+                .balance(Currency::default())
+                .available()
+        );
+        assert_eq!(
+            dec!(20),
+            result.clients_by_id[&1].balance(Currency::default()).held()
+        );
+
+        assert_eq!(
+            (Client::create(dec!(0), dec!(80), false), vec![]),
+            process_withdrawal_dispute(
+                WithdrawalDisputePolicy::CreditHeld,
+                DisputeStepKind::Resolve
+            )
+        );
+        assert_eq!(
+            (Client::create(dec!(0), dec!(100), true), vec![]),
+            process_withdrawal_dispute(
+                WithdrawalDisputePolicy::CreditHeld,
+                DisputeStepKind::Chargeback
+            )
+        );
+    }
+
+    #[test]
+    fn test_redispute_after_chargeback() {
+        let config = EngineConfig {
+            allow_redispute: true,
+            ..EngineConfig::default()
+        };
+        let (result, errors) = process_events_with_config(
+            vec![
+                deposit(1, 1, dec!(100)),
+                dispute_step(DisputeStepKind::Dispute, 1, 1),
+                dispute_step(DisputeStepKind::Chargeback, 1, 1),
+                dispute_step(DisputeStepKind::Dispute, 1, 1),
+                // the second presentment succeeds, so the client keeps the
+                // money but stays locked
+                dispute_step(DisputeStepKind::Resolve, 1, 1),
+                deposit(2, 2, dec!(50)),
+                withdrawal(2, 3, dec!(20)),
+                dispute_step(DisputeStepKind::Dispute, 2, 3),
+                dispute_step(DisputeStepKind::Chargeback, 2, 3),
+                dispute_step(DisputeStepKind::Dispute, 2, 3),
+            ],
+            config,
+        );
+
+        assert_eq!(
+            HashMap::from([
+                (
+                    1,
+                    Client::create(dec!(0), dec!(100), true)
+                        .with_disputed_count(2)
+                        .with_chargeback_count(1)
+                        .with_last_transaction_id(1)
+                ),
+                (
+                    2,
+                    Client::create(dec!(20), dec!(30), true)
+                        .with_disputed_count(2)
+                        .with_chargeback_count(1)
+                        .with_last_transaction_id(3)
+                ),
+            ]),
+            result.clients_by_id
+        );
+        assert_eq!(Vec::<String>::new(), errors);
+    }
+
+    #[test]
+    fn test_undisputed_chargebacks() {
+        let input_events = || {
+            vec![
+                deposit(1, 1, dec!(100)),
+                deposit(1, 2, dec!(10)),
+                dispute_step(DisputeStepKind::Chargeback, 1, 1),
+                // someone else's transaction is still rejected
+                dispute_step(DisputeStepKind::Chargeback, 2, 2),
+            ]
+        };
+
+        let (result, errors) = process_events_with_config(input_events(), EngineConfig::default());
+        assert_eq!(
+            Client::create(dec!(0), dec!(110), false),
+            balances_only(&result.clients_by_id[&1])
+        );
+        assert_eq!(
+            vec![
+                "Transaction is not disputed.",
+                "Client id 2 does not match transaction client id 1.",
+            ],
+            errors
+        );
+
+        let (result, errors) = process_events_with_config(
+            input_events(),
+            EngineConfig {
+                undisputed_chargebacks: UndisputedChargebackPolicy::ImplicitDispute,
+                ..EngineConfig::default()
+            },
+        );
+        assert_eq!(
+            Client::create(dec!(0), dec!(10), true)
+                .with_disputed_count(1)
+                .with_chargeback_count(1)
+                .with_last_transaction_id(2),
+            result.clients_by_id[&1]
+        );
+        assert_eq!(
+            DisputeStatus::ChargedBack,
+            result.transactions_by_id[&1].dispute_status()
+        );
+        assert_eq!(
+            vec!["Client id 2 does not match transaction client id 1."],
+            errors
+        );
+    }
+
+    #[test]
+    fn test_failed_chargeback_undoes_implicit_dispute() {
+        let (result, errors) = process_events_with_config(
+            vec![
+                deposit(1, 1, Amount::MAX),
+                withdrawal(1, 2, dec!(5)),
+                deposit(1, 3, dec!(5)),
+                // the dispute goes through, but giving the withdrawal back
+                // doesn't fit
+                dispute_step(DisputeStepKind::Chargeback, 1, 2),
+            ],
+            EngineConfig {
+                undisputed_chargebacks: UndisputedChargebackPolicy::ImplicitDispute,
+                ..EngineConfig::default()
+            },
+        );
+
+        assert_eq!(vec!["Amount is too large to process."], errors);
+        assert_eq!(
+            Client::create(dec!(0), Amount::MAX, false).with_last_transaction_id(3),
+            result.clients_by_id[&1]
+        );
+        assert_eq!(
+            DisputeStatus::Undisputed,
+            result.transactions_by_id[&2].dispute_status()
+        );
+    }
+
+    fn process_disputes_on_locked_account(policy: LockedAccountPolicy) -> (Client, Vec<String>) {
+        let (mut result, errors) = process_events_with_config(
+            vec![
+                deposit(1, 1, dec!(100)),
+                deposit(1, 2, dec!(10)),
+                dispute_step(DisputeStepKind::Dispute, 1, 1),
+                dispute_step(DisputeStepKind::Chargeback, 1, 1),
+                dispute_step(DisputeStepKind::Dispute, 1, 2),
+            ],
+            EngineConfig {
+                locked_account_disputes: policy,
+                ..EngineConfig::default()
+            },
+        );
+        let client = result.clients_by_id.remove(&1).expect("Expected client 1.");
+
+        (balances_only(&client), errors)
+    }
+
+    #[test]
+    fn test_disputes_on_locked_account() {
+        assert_eq!(
+            (Client::create(dec!(10), dec!(10), true), vec![]),
+            process_disputes_on_locked_account(LockedAccountPolicy::Process)
+        );
+        assert_eq!(
+            (
+                Client::create(dec!(0), dec!(10), true),
+                vec![String::from("Cannot dispute when account is locked.")]
+            ),
+            process_disputes_on_locked_account(LockedAccountPolicy::Reject)
+        );
+        assert_eq!(
+            (
+                Client::create(dec!(0), dec!(10), true),
+                vec![String::from(
+                    "Client 1 was still locked at the end of the run, so the dispute of transaction 2 was never processed."
+                )]
+            ),
+            process_disputes_on_locked_account(LockedAccountPolicy::Queue)
+        );
+    }
+
+    #[test]
+    fn test_event_outcomes() {
+        let input_events = vec![
+            deposit(1, 1, dec!(100)),
+            deposit(1, 2, dec!(10)),
+            withdrawal(1, 3, dec!(500)),
+            dispute_step(DisputeStepKind::Dispute, 1, 1),
+            dispute_step(DisputeStepKind::Chargeback, 1, 1),
+            // queued until the chargeback is reversed
+            dispute_step(DisputeStepKind::Dispute, 1, 2),
+            Ok(Event::ChargebackReversal {
+                transaction_id: 1,
+                client_id: 1,
+            }),
+            deposit(2, 4, dec!(10)),
+            deposit(2, 5, dec!(10)),
+            dispute_step(DisputeStepKind::Dispute, 2, 4),
+            dispute_step(DisputeStepKind::Chargeback, 2, 4),
+            // queued and never unlocked
+            dispute_step(DisputeStepKind::Dispute, 2, 5),
+        ];
+        let expected_events = input_events
+            .iter()
+            .map(|event| event.as_ref().expect("Expected an event").clone())
+            .collect::<Vec<_>>();
+
+        let mut error_logger = Vec::new();
+        let mut outcomes = Vec::new();
+        let result = process_events_with_outcomes(
+            input_events.into_iter(),
+            &mut error_logger,
+            &EngineConfig {
+                locked_account_disputes: LockedAccountPolicy::Queue,
+                unlock_on_chargeback_reversal: true,
+                ..EngineConfig::default()
+            },
+            |outcome| {
+                outcomes.push(outcome);
+                Ok(())
+            },
+        )
+        .expect("Unexpectedly failed to process events.");
+
+        // each outcome is for the event at its index, and they come out in
+        // the order the events were processed
+        assert!(outcomes
+            .iter()
+            .all(|outcome| outcome.event == expected_events[outcome.index as usize]));
+        assert_eq!(
+            vec![0, 1, 2, 3, 4, 6, 5, 7, 8, 9, 10, 11],
+            outcomes
+                .iter()
+                .map(|outcome| outcome.index)
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            vec![
+                (2, ProcessingError::InsufficientFunds),
+                (
+                    11,
+                    ProcessingError::StillLocked {
+                        client_id: 2,
+                        kind: "dispute",
+                        id: 5
+                    }
+                )
+            ],
+            outcomes
+                .into_iter()
+                .filter_map(|outcome| outcome.result.err().map(|e| (outcome.index, e)))
+                .collect::<Vec<_>>()
+        );
+
+        // rejections are still logged and counted as usual
+        let error_str = String::from_utf8(error_logger).expect("Not UTF-8");
+        assert_eq!(2, error_str.lines().count());
+        assert_eq!(12, result.event_counts.processed);
+        assert_eq!(2, result.event_counts.rejected);
+        assert_eq!(
+            Client::create(dec!(10), dec!(110), false),
+            balances_only(&result.clients_by_id[&1])
+        );
+    }
+
+    #[test]
+    fn test_chargeback_reversal() {
+        let chargeback_reversal = |transaction_id| {
+            Ok(Event::ChargebackReversal {
+                transaction_id,
+                client_id: 1,
+            })
+        };
+        let input_events = || {
+            vec![
+                deposit(1, 1, dec!(100)),
+                deposit(1, 2, dec!(10)),
+                deposit(1, 3, dec!(5)),
+                dispute_step(DisputeStepKind::Dispute, 1, 1),
+                dispute_step(DisputeStepKind::Dispute, 1, 3),
+                dispute_step(DisputeStepKind::Chargeback, 1, 1),
+                // these are queued, because the account is now locked
+                dispute_step(DisputeStepKind::Chargeback, 1, 3),
+                dispute_step(DisputeStepKind::Dispute, 1, 2),
+                // if that unlocks the account, the queued chargeback locks it
+                // again and the queued dispute is queued again
+                chargeback_reversal(1),
+                chargeback_reversal(1),
+                chargeback_reversal(3),
+            ]
+        };
+        let config = |unlock_on_chargeback_reversal| EngineConfig {
+            locked_account_disputes: LockedAccountPolicy::Queue,
+            unlock_on_chargeback_reversal,
+            ..EngineConfig::default()
+        };
+
+        let (result, errors) = process_events_with_config(input_events(), config(false));
+        assert_eq!(
+            Client::create(dec!(5), dec!(115), true),
+            balances_only(&result.clients_by_id[&1])
+        );
+        assert_eq!(
+            DisputeStatus::Undisputed,
+            result.transactions_by_id[&1].dispute_status()
+        );
+        assert_eq!(
+            vec![
+                "Transaction has not just been charged back.",
+                "Transaction has not just been charged back.",
+                "Client 1 was still locked at the end of the run, so the chargeback of transaction 3 was never processed.",
+                "Client 1 was still locked at the end of the run, so the dispute of transaction 2 was never processed.",
+            ],
+            errors
+        );
+
+        let (result, errors) = process_events_with_config(input_events(), config(true));
+        assert_eq!(
+            Client::create(dec!(10), dec!(115), false),
+            balances_only(&result.clients_by_id[&1])
+        );
+        assert_eq!(vec!["Transaction has not just been charged back."], errors);
+    }
+
+    #[test]
+    fn test_chargeback_limit() {
+        let chargeback_reversal = |client_id, transaction_id| {
+            Ok(Event::ChargebackReversal {
+                transaction_id,
+                client_id,
+            })
+        };
+        let input_events = || {
+            vec![
+                deposit(1, 1, dec!(10)),
+                deposit(1, 2, dec!(10)),
+                deposit(2, 3, dec!(10)),
+                dispute_step(DisputeStepKind::Dispute, 1, 1),
+                dispute_step(DisputeStepKind::Chargeback, 1, 1),
+                chargeback_reversal(1, 1),
+                // reversed chargebacks still count towards the limit
+                dispute_step(DisputeStepKind::Dispute, 1, 2),
+                dispute_step(DisputeStepKind::Chargeback, 1, 2),
+                chargeback_reversal(1, 2),
+                dispute_step(DisputeStepKind::Dispute, 2, 3),
+                dispute_step(DisputeStepKind::Chargeback, 2, 3),
+                chargeback_reversal(2, 3),
+            ]
+        };
+        let config = |chargeback_limit_action| EngineConfig {
+            unlock_on_chargeback_reversal: true,
+            chargeback_limit: Some(2),
+            chargeback_limit_action,
+            ..EngineConfig::default()
+        };
+
+        let (result, errors) =
+            process_events_with_config(input_events(), config(ChargebackLimitAction::Lock));
+        assert!(errors.is_empty());
+        let client = &result.clients_by_id[&1];
+        assert_eq!(
+            (true, true, 2),
+            (client.locked(), client.flagged(), client.chargeback_count())
+        );
+        let client = &result.clients_by_id[&2];
+        assert_eq!((false, false), (client.locked(), client.flagged()));
+
+        let (result, errors) =
+            process_events_with_config(input_events(), config(ChargebackLimitAction::Flag));
+        assert!(errors.is_empty());
+        let client = &result.clients_by_id[&1];
+        assert_eq!((false, true), (client.locked(), client.flagged()));
+    }
+
+    #[test]
+    fn test_credit_limit() {
+        let (result, errors) = process_events_with_config(
+            vec![
+                deposit(1, 1, dec!(10)),
+                withdrawal(1, 2, dec!(50)),
+                // over the limit
+                withdrawal(1, 3, dec!(60.0001)),
+                deposit(2, 4, dec!(10)),
+                withdrawal(2, 5, dec!(11)),
+            ],
+            EngineConfig {
+                credit_limits: HashMap::from([(1, dec!(100))]),
+                ..EngineConfig::default()
+            },
+        );
+
+        assert_eq!(
+            dec!(-40),
+            result.clients_by_id[&1]
+                .balance(Currency::default())
+                .total()
+        );
+        assert_eq!(
+            dec!(40),
+            result.clients_by_id[&1]
+                .balance(Currency::default())
+                .overdrawn()
+        );
+        assert_eq!(
+            dec!(10),
+            result.clients_by_id[&2]
+                .balance(Currency::default())
+                .total()
+        );
+        assert_eq!(
+            dec!(0),
+            result.clients_by_id[&2]
+                .balance(Currency::default())
+                .overdrawn()
+        );
+        assert_eq!(vec!["Insufficient funds.", "Insufficient funds."], errors);
+    }
+
+    #[test]
+    fn test_multiple_currencies() {
+        let euros = "EUR".parse().expect("Expected a valid currency.");
+        let in_euros = |event: Result<Event, Box<dyn Error>>| match event {
+            Ok(Event::Transaction {
+                kind,
+                transaction_id,
+                client_id,
+                amount,
+                ..
+            }) => Ok(Event::Transaction {
+                kind,
+                transaction_id,
+                client_id,
+                currency: euros,
+                amount,
+                counterparty: None,
+            }),
+            event => event,
+        };
+
+        let (result, errors) = process_events_with_config(
+            vec![
+                deposit(1, 1, dec!(10)),
+                in_euros(deposit(1, 2, dec!(5))),
+                // there aren't enough euros, whatever else the client has
+                in_euros(withdrawal(1, 3, dec!(6))),
+                // the dispute acts on the transaction's currency
+                dispute_step(DisputeStepKind::Dispute, 1, 2),
+            ],
+            EngineConfig::default(),
+        );
+
+        assert_eq!(
+            HashMap::from([(
+                1,
+                Client::create(dec!(0), dec!(10), false)
+                    .with_balance(euros, dec!(5), dec!(5))
+                    .with_disputed_count(1)
+                    .with_last_transaction_id(2)
+            )]),
+            result.clients_by_id
+        );
+        assert_eq!(vec!["Insufficient funds."], errors);
+    }
+
+    fn reversal(
+        client_id: ClientID,
+        transaction_id: TransactionID,
+    ) -> Result<Event, Box<dyn Error>> {
+        Ok(Event::Reversal {
+            transaction_id,
+            client_id,
+        })
+    }
+
+    #[test]
+    fn test_reversal() {
+        assert_results(
+            vec![
+                deposit(1, 1, dec!(10)),
+                deposit(1, 2, dec!(5)),
+                withdrawal(1, 3, dec!(3)),
+                reversal(1, 2),
+                reversal(1, 3),
+                reversal(1, 3),
+                // a reversal is as final as a chargeback
+                dispute_step(DisputeStepKind::Dispute, 1, 2),
+                deposit(1, 4, dec!(1)),
+                dispute_step(DisputeStepKind::Dispute, 1, 4),
+                reversal(1, 4),
+                reversal(2, 1),
+            ],
+            HashMap::from([(1, Client::create(dec!(1), dec!(11), false))]),
+            vec![
+                String::from("Transaction has already been reversed."),
+                String::from("Transaction has already been reversed."),
+                String::from("Cannot reverse a transaction while it's disputed."),
+                String::from("Client id 2 does not match transaction client id 1."),
+            ],
+        );
+    }
+
+    #[test]
+    fn test_partial_dispute() {
+        let partial_dispute = |transaction_id, amount| {
+            Ok(Event::DisputeStep {
+                kind: DisputeStepKind::Dispute,
+                client_id: 1,
+                transaction_id,
+                amount: Some(amount),
+            })
+        };
+
+        let (result, errors) = process_events_with_config(
+            vec![
+                deposit(1, 1, dec!(10)),
+                partial_dispute(1, dec!(4)),
+                dispute_step(DisputeStepKind::Chargeback, 1, 1),
+                partial_dispute(1, dec!(7)),
+                partial_dispute(1, dec!(0)),
+                // the rest of it can still be disputed, and resolving that
+                // leaves the chargeback in place
+                dispute_step(DisputeStepKind::Dispute, 1, 1),
+                dispute_step(DisputeStepKind::Resolve, 1, 1),
+                reversal(1, 1),
+            ],
+            EngineConfig::default(),
+        );
+
+        assert_eq!(
+            Client::create(dec!(0), dec!(6), true),
+            balances_only(&result.clients_by_id[&1])
+        );
+        let transaction = &result.transactions_by_id[&1];
+        assert_eq!(
+            (DisputeStatus::PartiallyChargedBack, dec!(4), dec!(6)),
+            (
+                transaction.dispute_status(),
+                transaction.charged_back_amount(),
+                transaction.disputable_amount()
+            )
+        );
+        assert_eq!(
+            vec![
+                "Cannot dispute 7 of the 6 left to dispute.",
+                "Cannot dispute 0 of the 6 left to dispute.",
+                "Cannot reverse a transaction that has been partially charged back.",
+            ],
+            errors
+        );
+        assert_eq!(
+            dec!(0),
+            reconcile(&result, &EngineConfig::default())[0].discrepancy()
+        );
+    }
+
+    #[test]
+    fn test_reversed_transfer() {
+        // the recipient asks for it, but both sides are reversed
+        assert_results(
+            vec![
+                deposit(1, 1, dec!(10)),
+                transfer(1, 2, 2, dec!(4)),
+                reversal(2, 2),
+            ],
+            HashMap::from([
+                (1, Client::create(dec!(0), dec!(10), false)),
+                (2, Client::create(dec!(0), dec!(0), false)),
+            ]),
+            vec![],
+        );
+    }
+
+    #[test]
+    fn test_adjustment() {
+        let adjustment = |transaction_id, amount| {
+            Ok(Event::Adjustment {
+                transaction_id,
+                client_id: 1,
+                currency: Currency::default(),
+                amount,
+                reason: String::from("correction"),
+            })
+        };
+
+        let (result, errors) = process_events_with_config(
+            vec![
+                deposit(1, 1, dec!(10)),
+                dispute_step(DisputeStepKind::Dispute, 1, 1),
+                dispute_step(DisputeStepKind::Chargeback, 1, 1),
+                // goes through even though the account is locked and the
+                // client has nothing left
+                adjustment(2, dec!(-3)),
+                adjustment(3, dec!(1)),
+                adjustment(1, dec!(1)),
+                reversal(1, 2),
+            ],
+            EngineConfig::default(),
+        );
+
+        assert_eq!(
+            Client::create(dec!(0), dec!(-2), true),
+            balances_only(&result.clients_by_id[&1])
+        );
+        assert_eq!(
+            Some("correction"),
+            result.adjustments_by_id.get(&2).map(Adjustment::reason)
+        );
+        assert_eq!(
+            vec![
+                "Transaction already exists with id 1.",
+                "Adjustment 2 cannot be disputed or reversed.",
+            ],
+            errors
+        );
+    }
+
+    #[test]
+    fn test_account_closure() {
+        let close = |client_id| Ok(Event::AccountClosure { client_id });
+        let input_events = || {
+            vec![
+                deposit(1, 1, dec!(10)),
+                deposit(2, 2, dec!(10)),
+                close(1),
+                close(1),
+                close(3),
+                deposit(1, 3, dec!(1)),
+                withdrawal(1, 4, dec!(1)),
+                transfer(2, 1, 5, dec!(1)),
+                transfer(1, 2, 6, dec!(1)),
+                // still allowed, being about what happened before it closed
+                dispute_step(DisputeStepKind::Dispute, 1, 1),
+            ]
+        };
+
+        let (result, errors) = process_events_with_config(input_events(), EngineConfig::default());
+        assert!(result.clients_by_id[&1].closed());
+        assert_eq!(
+            Client::create(dec!(10), dec!(10), false),
+            balances_only(&result.clients_by_id[&1])
+        );
+        assert_eq!(
+            vec![
+                "Account is already closed.",
+                "Client 3 does not exist.",
+                "Cannot process deposit when account is closed.",
+                "Cannot process withdrawal when account is closed.",
+                "Cannot process transfer when account is closed.",
+                "Cannot process transfer when account is closed.",
+            ],
+            errors
+        );
+
+        let (result, errors) = process_events_with_config(
+            input_events(),
+            EngineConfig {
+                closed_accounts: ClosedAccountPolicy::AllowWithdrawals,
+                ..EngineConfig::default()
+            },
+        );
+        assert_eq!(
+            Client::create(dec!(10), dec!(8), false),
+            balances_only(&result.clients_by_id[&1])
+        );
+        assert_eq!(
+            vec![
+                "Account is already closed.",
+                "Client 3 does not exist.",
+                "Cannot process deposit when account is closed.",
+                "Cannot process transfer when account is closed.",
+            ],
+            errors
+        );
+    }
+
+    #[test]
+    fn test_client_registration() {
+        let euros = "EUR".parse().expect("Expected a valid currency.");
+        let register = |client_id, name: &str, tier: Option<&str>| {
+            Ok(Event::ClientRegistration {
+                client_id,
+                name: String::from(name),
+                tier: tier.map(String::from),
+                currency: euros,
+            })
+        };
+        let input_events = vec![
+            // creates the client, with nothing in their account
+            register(1, "Ada Lovelace", Some("gold")),
+            deposit(2, 1, dec!(10)),
+            Ok(Event::AccountClosure { client_id: 2 }),
+            // doesn't move any money, so it's fine on a closed account
+            register(2, "Charles Babbage", Some("silver")),
+            // replaces the profile, tier and all
+            register(1, "Ada King", None),
+        ];
+
+        let (result, errors) = process_events_with_config(input_events, EngineConfig::default());
+        assert_eq!(Vec::<String>::new(), errors);
+        assert_eq!(
+            Client::create(dec!(0), dec!(0), false),
+            balances_only(&result.clients_by_id[&1])
+        );
+        assert_eq!(
+            Some(&ClientProfile::new(String::from("Ada King"), None, euros)),
+            result.clients_by_id[&1].profile()
+        );
+        assert_eq!(
+            Some(&ClientProfile::new(
+                String::from("Charles Babbage"),
+                Some(String::from("silver")),
+                euros
+            )),
+            result.clients_by_id[&2].profile()
+        );
+    }
+
+    #[test]
+    fn test_interest() {
+        let interest = |client_id, transaction_id| {
+            Ok(Event::Interest {
+                transaction_id,
+                client_id,
+                currency: Currency::default(),
+                rate: dec!(0.001),
+            })
+        };
+
+        let (result, errors) = process_events_with_config(
+            vec![
+                deposit(1, 1, dec!(100)),
+                deposit(1, 2, dec!(10)),
+                dispute_step(DisputeStepKind::Dispute, 1, 2),
+                // paid on the 100 available but not the 10 held
+                interest(1, 3),
+                // and then on the 100.1 available, so it compounds
+                interest(1, 4),
+                interest(2, 5),
+            ],
+            EngineConfig::default(),
+        );
+
+        assert_eq!(
+            HashMap::from([(
+                1,
+                Client::create(dec!(10), dec!(110.2001), false)
+                    .with_interest(dec!(0.2001))
+                    .with_disputed_count(1)
+                    .with_last_transaction_id(2)
+            )]),
+            result.clients_by_id
+        );
+        assert_eq!(vec!["Client 2 does not exist."], errors);
+    }
+
+    #[test]
+    fn test_pending_deposits() {
+        let deposit_pending = |transaction_id, amount| {
+            Ok(Event::PendingDeposit {
+                transaction_id,
+                client_id: 1,
+                currency: Currency::default(),
+                amount,
+                counterparty: None,
+            })
+        };
+        let settle = |client_id, transaction_id| {
+            Ok(Event::Settlement {
+                transaction_id,
+                client_id,
+            })
+        };
+
+        let (result, errors) = process_events_with_config(
+            vec![
+                deposit(1, 1, dec!(10)),
+                deposit_pending(2, dec!(20)),
+                deposit_pending(3, dec!(5)),
+                // only what's settled can be spent or disputed
+                withdrawal(1, 4, dec!(15)),
+                dispute_step(DisputeStepKind::Dispute, 1, 2),
+                settle(2, 2),
+                settle(1, 2),
+                settle(1, 2),
+                settle(1, 1),
+                settle(1, 9),
+                withdrawal(1, 4, dec!(15)),
+                dispute_step(DisputeStepKind::Dispute, 1, 2),
+                // one that's returned unsettled takes what's pending with it
+                reversal(1, 3),
+            ],
+            EngineConfig::default(),
+        );
+
+        assert_eq!(
+            HashMap::from([(
+                1,
+                Client::create(dec!(20), dec!(15), false)
+                    .with_disputed_count(1)
+                    .with_last_transaction_id(4)
+            )]),
+            result.clients_by_id
+        );
+        assert_eq!(
+            vec![
+                "Insufficient funds.",
+                "Deposit 2 has not settled yet, so it cannot be disputed.",
+                "Client id 2 does not match transaction client id 1.",
+                "Transaction 2 is not a pending deposit.",
+                "Transaction 1 is not a pending deposit.",
+                "Transaction 9 not found.",
+            ],
+            errors
+        );
+    }
+
+    #[test]
+    fn test_conversion() {
+        let euros = "EUR".parse().expect("Expected a valid currency.");
+        let convert = |transaction_id, to_currency, amount| {
+            Ok(Event::Conversion {
+                transaction_id,
+                client_id: 1,
+                from_currency: Currency::default(),
+                to_currency,
+                amount,
+                rate: dec!(0.9),
+            })
+        };
+
+        let (result, errors) = process_events_with_config(
+            vec![
+                deposit(1, 1, dec!(10)),
+                convert(2, euros, dec!(4)),
+                // there isn't enough left to convert
+                convert(3, euros, dec!(7)),
+                convert(4, Currency::default(), dec!(1)),
+                // conversions share IDs with transactions
+                deposit(1, 2, dec!(1)),
+                // but can't be disputed
+                dispute_step(DisputeStepKind::Dispute, 1, 2),
+            ],
+            EngineConfig::default(),
+        );
+
+        assert_eq!(
+            HashMap::from([(
+                1,
+                Client::create(dec!(0), dec!(6), false)
+                    .with_balance(euros, dec!(0), dec!(3.6))
+                    .with_last_transaction_id(2)
+            )]),
+            result.clients_by_id
+        );
+        assert_eq!(
+            vec![
+                "Insufficient funds.",
+                "Cannot convert a currency into itself.",
+                "Transaction already exists with id 2.",
+                "Conversion 2 cannot be disputed or reversed.",
+            ],
+            errors
+        );
+    }
+
+    #[test]
+    fn test_dispute_window() {
+        let day = 24 * 60 * 60;
+        let at = |event: Result<Event, Box<dyn Error>>, timestamp| {
+            event.map(|event| SourcedEvent {
+                event,
+                source: None,
+                timestamp,
+            })
+        };
+        let input_events = vec![
+            at(deposit(1, 1, dec!(10)), Some(0)),
+            at(deposit(1, 2, dec!(10)), Some(0)),
+            at(deposit(1, 3, dec!(10)), None),
+            at(transfer(1, 2, 4, dec!(5)), Some(day)),
+            // right on the edge of the window is still in it
+            at(dispute_step(DisputeStepKind::Dispute, 1, 1), Some(90 * day)),
+            at(
+                dispute_step(DisputeStepKind::Dispute, 1, 2),
+                Some(90 * day + 1),
+            ),
+            // we can't tell how old it is without both timestamps
+            at(
+                dispute_step(DisputeStepKind::Dispute, 1, 3),
+                Some(90 * day + 1),
+            ),
+            at(
+                dispute_step(DisputeStepKind::Dispute, 2, 4),
+                Some(91 * day + 1),
+            ),
+        ];
+        let config = EngineConfig {
+            dispute_window: Some(Duration::from_secs(90 * day)),
+            ..EngineConfig::default()
+        };
+        let mut error_logger = Vec::new();
+
+        let result = process_events_with_snapshots(
+            input_events.into_iter(),
+            &mut error_logger,
+            &config,
+            None,
+            |_, _| Ok(()),
+        )
+        .expect("Unexpectedly failed to process events.");
+
+        let statuses = [1, 2, 3]
+            .map(|transaction_id| result.transactions_by_id[&transaction_id].dispute_status());
+        assert_eq!(
+            [
+                DisputeStatus::Disputed,
+                DisputeStatus::Undisputed,
+                DisputeStatus::Disputed
+            ],
+            statuses
+        );
+        assert_eq!(
+            DisputeStatus::Undisputed,
+            result.transfer_credits_by_id[&4].dispute_status()
+        );
+        assert_eq!(
+            vec![(DISPUTE_WINDOW_EXPIRED_CODE, 2)],
+            result.event_counts.rejected_by_code
+        );
+        assert_eq!(
+            "Transaction 2 is too old to be disputed.\nTransaction 4 is too old to be disputed.\n",
+            String::from_utf8(error_logger).expect("Not UTF-8")
+        );
+    }
+
+    #[test]
+    fn test_dispute_expiry() {
+        let day = 24 * 60 * 60;
+        let at = |event: Result<Event, Box<dyn Error>>, timestamp| {
+            event.map(|event| SourcedEvent {
+                event,
+                source: None,
+                timestamp,
+            })
+        };
+        let input_events = vec![
+            at(deposit(1, 1, dec!(10)), Some(0)),
+            at(deposit(1, 2, dec!(10)), Some(0)),
+            at(deposit(1, 3, dec!(10)), Some(0)),
+            at(dispute_step(DisputeStepKind::Dispute, 1, 1), Some(day)),
+            at(dispute_step(DisputeStepKind::Dispute, 1, 2), Some(2 * day)),
+            // there's no telling when this one was raised, so it never lapses
+            at(dispute_step(DisputeStepKind::Dispute, 1, 3), None),
+            // right on the edge it's still open
+            at(deposit(1, 4, dec!(10)), Some(31 * day)),
+            // but after that it lapses, just before the chargeback
+            at(
+                dispute_step(DisputeStepKind::Chargeback, 1, 1),
+                Some(31 * day + 1),
+            ),
+            at(deposit(1, 5, dec!(10)), Some(40 * day)),
+        ];
+        let config = EngineConfig {
+            dispute_expiry: Some(Duration::from_secs(30 * day)),
+            ..EngineConfig::default()
+        };
+        let mut error_logger = Vec::new();
+        let mut outcomes = Vec::new();
+
+        let result = process_events_with_outcomes(
+            input_events.into_iter(),
+            &mut error_logger,
+            &config,
+            |outcome| {
+                outcomes.push(outcome);
+                Ok(())
+            },
+        )
+        .expect("Unexpectedly failed to process events.");
+
+        let statuses = [1, 2, 3]
+            .map(|transaction_id| result.transactions_by_id[&transaction_id].dispute_status());
+        assert_eq!(
+            [
+                DisputeStatus::Undisputed,
+                DisputeStatus::Undisputed,
+                DisputeStatus::Disputed
+            ],
+            statuses
+        );
+        assert_eq!(
+            Client::create(dec!(10), dec!(50), false),
+            balances_only(&result.clients_by_id[&1])
+        );
+        assert_eq!(
+            "Transaction is not disputed.\n",
+            String::from_utf8(error_logger).expect("Not UTF-8")
+        );
+
+        // the lapses get outcomes of their own, with the index of the event
+        // they lapsed just before, but they aren't counted as events
+        let resolve = |transaction_id| Event::DisputeStep {
+            kind: DisputeStepKind::Resolve,
+            transaction_id,
+            client_id: 1,
+            amount: None,
+        };
+        assert_eq!(
+            vec![(7, resolve(1)), (8, resolve(2))],
+            outcomes
+                .into_iter()
+                .filter(|outcome| {
+                    matches!(
+                        outcome.event,
+                        Event::DisputeStep {
+                            kind: DisputeStepKind::Resolve,
+                            ..
+                        }
+                    )
+                })
+                .map(|outcome| (outcome.index, outcome.event))
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(9, result.event_counts.processed);
+    }
+
+    #[test]
+    fn test_reorder_window() {
+        let input_events = || {
+            vec![
+                (deposit(1, 1, dec!(10)), 1),
+                (dispute_step(DisputeStepKind::Dispute, 1, 2), 3),
+                (deposit(1, 2, dec!(5)), 2),
+            ]
+            .into_iter()
+            .map(|(event, timestamp)| {
+                event.map(|event| SourcedEvent {
+                    event,
+                    source: None,
+                    timestamp: Some(timestamp),
+                })
+            })
+        };
+        let process = |reorder_window| {
+            let mut error_logger = Vec::new();
+            let result = process_events_with_snapshots(
+                input_events(),
+                &mut error_logger,
+                &EngineConfig {
+                    reorder_window,
+                    ..EngineConfig::default()
+                },
+                None,
+                |_, _| Ok(()),
+            )
+            .expect("Unexpectedly failed to process events.");
+            (
+                balances_only(&result.clients_by_id[&1]),
+                String::from_utf8(error_logger).expect("Not UTF-8"),
+            )
+        };
+
+        assert_eq!(
+            (
+                Client::create(dec!(0), dec!(15), false),
+                String::from("Transaction 2 not found.\n")
+            ),
+            process(0)
+        );
+        assert_eq!(
+            (Client::create(dec!(5), dec!(15), false), String::new()),
+            process(1)
+        );
+    }
+
+    #[test]
+    fn test_disputed_count() {
+        let client_id = 1;
+        let input_events: Vec<Result<Event, ProcessEventsError>> = vec![
+            Ok(Event::Transaction {
+                kind: TransactionKind::Deposit,
+                client_id,
+                transaction_id: 1,
+                currency: Currency::default(),
+                amount: dec!(10),
+                counterparty: None,
+            }),
+            Ok(Event::DisputeStep {
+                kind: DisputeStepKind::Dispute,
+                client_id,
+                transaction_id: 1,
+                amount: None,
+            }),
+            Ok(Event::DisputeStep {
+                kind: DisputeStepKind::Resolve,
+                client_id,
+                transaction_id: 1,
+                amount: None,
+            }),
+            Ok(Event::DisputeStep {
+                kind: DisputeStepKind::Dispute,
+                client_id,
+                transaction_id: 1,
+                amount: None,
+            }),
+            // rejected because it's already disputed, so it doesn't count
+            Ok(Event::DisputeStep {
+                kind: DisputeStepKind::Dispute,
+                client_id,
+                transaction_id: 1,
+                amount: None,
+            }),
+        ];
+
+        let result = process_events(input_events.into_iter(), &mut io::sink())
+            .expect("Unexpectedly failed to process events.");
+
+        assert_eq!(
+            HashMap::from([(
+                client_id,
+                Client::create(dec!(10), dec!(10), false)
+                    .with_disputed_count(2)
+                    .with_last_transaction_id(1)
+            )]),
+            result.clients_by_id
+        );
+    }
+
+    #[test]
+    fn test_chargeback_count_and_last_transaction_id() {
+        let client_id = 1;
+        let input_events: Vec<Result<Event, ProcessEventsError>> = vec![
+            Ok(Event::Transaction {
+                kind: TransactionKind::Deposit,
+                client_id,
+                transaction_id: 1,
+                currency: Currency::default(),
+                amount: dec!(10),
+                counterparty: None,
+            }),
+            Ok(Event::Transaction {
+                kind: TransactionKind::Deposit,
+                client_id,
+                transaction_id: 2,
+                currency: Currency::default(),
+                amount: dec!(5),
+                counterparty: None,
+            }),
+            // rejected, so it isn't the last transaction
+            Ok(Event::Transaction {
+                kind: TransactionKind::Withdrawal,
+                client_id,
+                transaction_id: 3,
+                currency: Currency::default(),
+                amount: dec!(100),
+                counterparty: None,
+            }),
+            Ok(Event::DisputeStep {
+                kind: DisputeStepKind::Dispute,
+                client_id,
+                transaction_id: 1,
+                amount: None,
+            }),
+            Ok(Event::DisputeStep {
+                kind: DisputeStepKind::Chargeback,
+                client_id,
+                transaction_id: 1,
+                amount: None,
+            }),
+        ];
+
+        let result = process_events(input_events.into_iter(), &mut io::sink())
+            .expect("Unexpectedly failed to process events.");
+
+        assert_eq!(
+            HashMap::from([(
+                client_id,
+                Client::create(dec!(0), dec!(5), true)
+                    .with_disputed_count(1)
+                    .with_chargeback_count(1)
+                    .with_last_transaction_id(2)
+            )]),
+            result.clients_by_id
+        );
+    }
+
+    #[test]
+    fn test_snapshots() {
+        let client_id = 1;
+        let input_events: Vec<Result<Event, Box<dyn Error>>> = (1..=5)
+            .map(|transaction_id| {
+                Ok(Event::Transaction {
+                    kind: TransactionKind::Deposit,
+                    client_id,
+                    transaction_id,
+                    currency: Currency::default(),
+                    amount: dec!(10),
+                    counterparty: None,
+                })
+            })
+            .collect();
+        let mut snapshots = Vec::new();
+
+        process_events_with_snapshots(
+            input_events.into_iter(),
+            &mut io::sink(),
+            &EngineConfig::default(),
+            Some(SnapshotInterval::Events(2)),
+            |clients_by_id, event_counts| {
+                snapshots.push((
+                    event_counts.processed,
+                    clients_by_id[&client_id]
+                        .balance(Currency::default())
+                        .total(),
+                ));
+                Ok(())
+            },
+        )
+        .expect("Unexpectedly failed to process events.");
+
+        assert_eq!(vec![(2, dec!(20)), (4, dec!(40))], snapshots);
+    }
+
+    #[test]
+    fn test_event_counts() {
+        let client_id = 1;
+        let input_events: Vec<Result<Event, Box<dyn Error>>> = vec![
+            Ok(Event::Transaction {
+                kind: TransactionKind::Deposit,
+                client_id,
+                transaction_id: 1,
+                currency: Currency::default(),
+                amount: dec!(10),
+                counterparty: None,
+            }),
+            Ok(Event::Transaction {
+                kind: TransactionKind::Withdrawal,
+                client_id,
+                transaction_id: 2,
+                currency: Currency::default(),
+                amount: dec!(20),
+                counterparty: None,
+            }),
+            Ok(Event::DisputeStep {
+                kind: DisputeStepKind::Resolve,
+                client_id,
+                transaction_id: 1,
+                amount: None,
+            }),
+        ];
+
+        let result = process_events(input_events.into_iter(), &mut io::sink())
+            .expect("Unexpectedly failed to process events.");
+
+        assert_eq!(
+            EventCounts {
+                processed: 3,
+                rejected: 2,
+                processed_by_kind: vec![("deposit", 1), ("withdrawal", 1), ("resolve", 1)],
+                rejected_by_code: vec![(INSUFFICIENT_FUNDS_CODE, 1), (NOT_DISPUTED_CODE, 1)],
+            },
+            result.event_counts
+        );
+    }
+
+    #[test]
+    fn test_rejections_include_source() {
+        struct RecordingLogger(Vec<(Option<Source>, String)>);
+
+        impl RejectionLogger for RecordingLogger {
+            fn log_rejection(&mut self, rejection: &Rejection) -> io::Result<()> {
+                self.0
+                    .push((rejection.source.cloned(), rejection.error.to_string()));
+                Ok(())
+            }
+
+            fn flush_rejections(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let source = Source {
+            line: 3,
+            byte: 42,
+            record: Some(String::from("withdrawal,1,2,5")),
+        };
+        let input_events: Vec<Result<SourcedEvent, Box<dyn Error>>> = vec![Ok(SourcedEvent {
+            event: Event::Transaction {
+                kind: TransactionKind::Withdrawal,
+                client_id: 1,
+                transaction_id: 2,
+                currency: Currency::default(),
+                amount: dec!(5),
+                counterparty: None,
+            },
+            source: Some(source.clone()),
+            timestamp: None,
+        })];
+        let mut logger = RecordingLogger(Vec::new());
+
+        process_events(input_events.into_iter(), &mut logger)
+            .expect("Unexpectedly failed to process events.");
+
+        assert_eq!(
+            vec![(Some(source), String::from("Insufficient funds."))],
+            logger.0
+        );
+    }
+
+    #[test]
+    fn test_observer() {
+        #[derive(Default)]
+        struct RecordingObserver(Vec<String>);
+
+        impl ProcessorObserver for RecordingObserver {
+            fn on_accepted(&mut self, event: &Event) {
+                self.0.push(format!("accepted {}", event.kind_name()));
+            }
+
+            fn on_rejected(&mut self, event: &Event, error: &ProcessingError) {
+                self.0
+                    .push(format!("rejected {}: {}", event.kind_name(), error));
+            }
+
+            fn on_chargeback(
+                &mut self,
+                client_id: ClientID,
+                transaction_id: TransactionID,
+                transaction: &Transaction,
+            ) {
+                self.0.push(format!(
+                    "chargeback of {} on transaction {} for client {}",
+                    transaction.charged_back_amount(),
+                    transaction_id,
+                    client_id
+                ));
+            }
+
+            fn on_lock(&mut self, client_id: ClientID, client: &Client) {
+                self.0.push(format!(
+                    "locked client {} with {} chargebacks",
+                    client_id,
+                    client.chargeback_count()
+                ));
+            }
+        }
+
+        let deposit = |transaction_id| {
+            Ok(Event::Transaction {
+                kind: TransactionKind::Deposit,
+                client_id: 1,
+                transaction_id,
+                currency: Currency::default(),
+                amount: dec!(10),
+                counterparty: None,
+            })
+        };
+        let dispute_step = |kind, transaction_id| {
+            Ok(Event::DisputeStep {
+                kind,
+                client_id: 1,
+                transaction_id,
+                amount: None,
+            })
+        };
+        let input_events: Vec<Result<Event, Box<dyn Error>>> = vec![
+            deposit(1),
+            deposit(2),
+            dispute_step(DisputeStepKind::Chargeback, 1),
+            dispute_step(DisputeStepKind::Dispute, 1),
+            dispute_step(DisputeStepKind::Dispute, 2),
+            dispute_step(DisputeStepKind::Chargeback, 1),
+            // the account is already locked by now
+            dispute_step(DisputeStepKind::Chargeback, 2),
+        ];
+        let mut observer = RecordingObserver::default();
+
+        process_events_observed(
+            input_events.into_iter(),
+            &mut io::sink(),
+            &EngineConfig::default(),
+            None,
+            |_, _| Ok(()),
+            &mut observer,
+        )
+        .expect("Unexpectedly failed to process events.");
+
+        assert_eq!(
+            vec![
+                "accepted deposit",
+                "accepted deposit",
+                "rejected chargeback: Transaction is not disputed.",
+                "accepted dispute",
+                "accepted dispute",
+                "accepted chargeback",
+                "chargeback of 10 on transaction 1 for client 1",
+                "locked client 1 with 1 chargebacks",
+                "accepted chargeback",
+                "chargeback of 10 on transaction 2 for client 1",
+            ],
+            observer.0
+        );
+    }
+
+    #[test]
+    fn test_queued_rejections_include_source() {
+        let sourced = |line, event| {
+            Ok(SourcedEvent {
+                event,
+                source: Some(Source {
+                    line,
+                    byte: line * 10,
+                    record: None,
+                }),
+                timestamp: None,
+            })
+        };
+        let deposit = |transaction_id| Event::Transaction {
+            kind: TransactionKind::Deposit,
+            client_id: 1,
+            transaction_id,
+            currency: Currency::default(),
+            amount: dec!(10),
+            counterparty: None,
+        };
+        let dispute_step = |kind, transaction_id| Event::DisputeStep {
+            kind,
+            client_id: 1,
+            transaction_id,
+            amount: None,
+        };
+        let input_events: Vec<Result<SourcedEvent, Box<dyn Error>>> = vec![
+            sourced(2, deposit(1)),
+            sourced(3, deposit(2)),
+            sourced(4, dispute_step(DisputeStepKind::Dispute, 1)),
+            sourced(5, dispute_step(DisputeStepKind::Chargeback, 1)),
+            // queued, and the client is never unlocked
+            sourced(6, dispute_step(DisputeStepKind::Dispute, 2)),
+        ];
+        let config = EngineConfig {
+            locked_account_disputes: LockedAccountPolicy::Queue,
+            ..EngineConfig::default()
+        };
+        let mut errors = Vec::new();
+
+        process_events_with_snapshots(
+            input_events.into_iter(),
+            &mut errors,
+            &config,
+            None,
+            |_, _| Ok(()),
+        )
+        .expect("Unexpectedly failed to process events.");
+
+        assert_eq!(
+            "line 6 (byte 60): Client 1 was still locked at the end of the run, so the dispute of transaction 2 was never processed.\n",
+            String::from_utf8(errors).expect("Not UTF-8")
+        );
+    }
 }
@@ -1,69 +1,1171 @@
+use super::{
+    digest::{state_digest, transaction_line},
+    invariants::{check_invariants, ClientsBefore},
+    journal::{Journal, JournalReader, ReplayPoint},
+    BatchResult, ChargebackLimitAction, ClosedAccountPolicy, DeltaTracker,
+    DuplicateTransactionPolicy, EngineConfig, EventCounts, FinalState, Limit, LockedAccountPolicy,
+    LockedDepositPolicy, MemoryStore, ProcessorObserver, StateDelta, StateStore,
+    UndisputedChargebackPolicy, WithdrawalDisputePolicy,
+};
 use crate::model::{
-    Amount, Client, ClientID, DisputeStatus, DisputeStepKind, Event, Transaction, TransactionID,
-    TransactionKind,
+    Adjustment, Amount, Client, ClientID, ClientProfile, Conversion, Currency, DisputeStatus,
+    DisputeStepKind, Event, ProcessingError, Source, SourcedEvent, Timestamp, Transaction,
+    TransactionID, TransactionKind,
+};
+
+use serde::{de::DeserializeOwned, Serialize};
+use std::{
+    collections::{hash_map::Entry, HashMap, HashSet},
+    fmt::Display,
+    hash::Hash,
+    io::{self, Read, Write},
+    iter,
 };
 
-use std::collections::HashMap;
+// Written at the start of every snapshot, so that anything else handed to
+// `Processor::restore` is turned away up front.
+const SNAPSHOT_MAGIC: &[u8; 8] = b"CHLGSNAP";
+// Bumped whenever what goes into a snapshot changes. There's no migrating old
+// snapshots: they're for resuming a run, not for keeping.
+const SNAPSHOT_VERSION: u32 = 8;
 
 // This maintains the state of the system (clients and transactions) and
-// processes new events. We're not testing it directly because it's an
-// implementation detail.
+// processes new events. Most of the time it's driven by `process_events`, but
+// it's public so that an application embedding the engine can feed it events
+// itself and look at the state as it goes, e.g. to answer balance queries
+// mid-stream. Whatever drives it should process `take_unlocked_events` after
+// each event, and deal with whatever `take_queued_events` leaves at the end,
+// as `process_events` does.
 pub struct Processor {
+    // The clients and transactions, in memory unless we're given somewhere
+    // else to keep them.
+    store: Box<dyn StateStore>,
+    // A transfer is two transactions under one ID, so the receiving side lives
+    // here while the sending side lives in the store.
+    transfer_credits_by_id: HashMap<TransactionID, Transaction>,
+    // Conversions share their IDs with transactions, but can't be disputed so
+    // they're kept apart.
+    conversions_by_id: HashMap<TransactionID, Conversion>,
+    // Likewise for adjustments.
+    adjustments_by_id: HashMap<TransactionID, Adjustment>,
+    // The withdrawals that weren't kept under `EngineConfig::disputable_only`.
+    // Their IDs still can't be reused, and the reconciliation still needs to
+    // know how much they took out, but that's all we hold onto.
+    discarded_withdrawal_ids: HashSet<TransactionID>,
+    discarded_withdrawals: HashMap<Currency, Amount>,
+    // Fees and interest aren't kept at all, but their IDs can't be reused
+    // either.
+    fee_and_interest_ids: HashSet<TransactionID>,
+    // Deposits that haven't settled yet, and how much of each is pending
+    // (what was deposited less any fee). They're stored as deposits like any
+    // other, so this is all that tells them apart.
+    pending_deposits: HashMap<TransactionID, Amount>,
+    // Who was on the other side of each deposit and withdrawal, for those
+    // that said. Most don't, so it's kept apart from the transactions rather
+    // than making every one of them bigger.
+    counterparties_by_id: HashMap<TransactionID, String>,
+    // When each open dispute was raised, keyed by the transaction and the
+    // client who raised it (which tells the sides of a transfer apart), for
+    // lapsing them under `EngineConfig::dispute_expiry`. Only kept when that's
+    // set, and only for disputes with a timestamp.
+    open_disputes: HashMap<(TransactionID, ClientID), Timestamp>,
+    // Dispute steps on locked accounts, set aside under
+    // `LockedAccountPolicy::Queue` in the order they came in, along with when
+    // they happened and where they were read from.
+    queued_events: Vec<SourcedEvent>,
+    // Whether an account has been unlocked since the queue was last checked.
+    unlocked_since_last_check: bool,
+    // How many chargebacks each client has that haven't been reversed or
+    // re-disputed, which is what keeps their account locked.
+    outstanding_chargebacks: HashMap<ClientID, u32>,
+    // When the event being processed happened, if known, which is stamped on
+    // any transactions it creates.
+    now: Option<Timestamp>,
+    // How many events have been processed, including the one being processed
+    // now, which is how transactions' dispute histories refer to events. An
+    // event that was queued is numbered again when it's finally processed,
+    // since that's when it took effect.
+    event_number: u64,
+    // Where accepted events are written, if anywhere.
+    journal: Option<Journal>,
+    config: EngineConfig,
+}
+
+// What `Processor::into_parts` takes out of a processor, which unlike the
+// processor itself can be sent between threads.
+pub(crate) struct ProcessorParts {
     clients_by_id: HashMap<ClientID, Client>,
     transactions_by_id: HashMap<TransactionID, Transaction>,
+    transfer_credits_by_id: HashMap<TransactionID, Transaction>,
+    conversions_by_id: HashMap<TransactionID, Conversion>,
+    adjustments_by_id: HashMap<TransactionID, Adjustment>,
+    discarded_withdrawal_ids: HashSet<TransactionID>,
+    discarded_withdrawals: HashMap<Currency, Amount>,
+    fee_and_interest_ids: HashSet<TransactionID>,
+    pending_deposits: HashMap<TransactionID, Amount>,
+    counterparties_by_id: HashMap<TransactionID, String>,
+    open_disputes: HashMap<(TransactionID, ClientID), Timestamp>,
+    queued_events: Vec<SourcedEvent>,
+    unlocked_since_last_check: bool,
+    outstanding_chargebacks: HashMap<ClientID, u32>,
+    event_number: u64,
 }
 
 impl Processor {
-    pub fn new() -> Self {
+    pub fn new(config: &EngineConfig) -> Self {
+        Self::with_store(config, Box::new(MemoryStore::default()))
+    }
+
+    pub fn with_store(config: &EngineConfig, store: Box<dyn StateStore>) -> Self {
         Self {
-            clients_by_id: HashMap::new(),
-            transactions_by_id: HashMap::new(),
+            store,
+            transfer_credits_by_id: HashMap::new(),
+            conversions_by_id: HashMap::new(),
+            adjustments_by_id: HashMap::new(),
+            discarded_withdrawal_ids: HashSet::new(),
+            discarded_withdrawals: HashMap::new(),
+            fee_and_interest_ids: HashSet::new(),
+            pending_deposits: HashMap::new(),
+            counterparties_by_id: HashMap::new(),
+            open_disputes: HashMap::new(),
+            queued_events: Vec::new(),
+            unlocked_since_last_check: false,
+            outstanding_chargebacks: HashMap::new(),
+            now: None,
+            event_number: 0,
+            journal: None,
+            config: config.clone(),
         }
     }
 
     // Expected to be called once all the events have been processed, hence taking
     // ownership of `self`.
-    pub fn clients_by_id(self) -> HashMap<ClientID, Client> {
-        self.clients_by_id
+    pub fn into_final_state(self, event_counts: EventCounts) -> io::Result<FinalState> {
+        let (clients_by_id, transactions_by_id) = self.store.into_maps()?;
+        Ok(FinalState {
+            clients_by_id,
+            transactions_by_id,
+            transfer_credits_by_id: self.transfer_credits_by_id,
+            conversions_by_id: self.conversions_by_id,
+            adjustments_by_id: self.adjustments_by_id,
+            discarded_withdrawals: self.discarded_withdrawals,
+            counterparties_by_id: self.counterparties_by_id,
+            event_counts,
+        })
+    }
+
+    // Writes everything the processor knows, apart from its config, so that a
+    // long run can be checkpointed now and then and resumed from the last
+    // checkpoint after a crash. Transactions are streamed out of the store one
+    // at a time rather than gathered up first.
+    pub fn snapshot(&mut self, mut writer: impl Write) -> io::Result<()> {
+        writer.write_all(SNAPSHOT_MAGIC)?;
+        writer.write_all(&SNAPSHOT_VERSION.to_le_bytes())?;
+
+        write_snapshot_part(&mut writer, self.store.clients())?;
+        self.store
+            .for_each_transaction(&mut |transaction_id, transaction| {
+                write_snapshot_part(&mut writer, &Some((transaction_id, transaction)))
+            })?;
+        write_snapshot_part(&mut writer, &None::<(TransactionID, &Transaction)>)?;
+
+        write_snapshot_part(&mut writer, &self.transfer_credits_by_id)?;
+        write_snapshot_part(&mut writer, &self.conversions_by_id)?;
+        write_snapshot_part(&mut writer, &self.adjustments_by_id)?;
+        write_snapshot_part(&mut writer, &self.discarded_withdrawal_ids)?;
+        write_snapshot_part(&mut writer, &self.discarded_withdrawals)?;
+        write_snapshot_part(&mut writer, &self.fee_and_interest_ids)?;
+        write_snapshot_part(&mut writer, &self.pending_deposits)?;
+        write_snapshot_part(&mut writer, &self.counterparties_by_id)?;
+        write_snapshot_part(&mut writer, &self.open_disputes)?;
+        write_snapshot_part(&mut writer, &self.queued_events)?;
+        write_snapshot_part(&mut writer, &self.unlocked_since_last_check)?;
+        write_snapshot_part(&mut writer, &self.outstanding_chargebacks)?;
+        write_snapshot_part(&mut writer, &self.now)?;
+        write_snapshot_part(&mut writer, &self.event_number)?;
+        writer.flush()
+    }
+
+    // Picks up from a snapshot written by `snapshot`. The config isn't part of
+    // the snapshot, so it's up to the caller to resume with the same one.
+    pub fn restore(config: &EngineConfig, reader: impl Read) -> io::Result<Self> {
+        Self::restore_with_store(config, Box::new(MemoryStore::default()), reader)
+    }
+
+    // Like `restore`, but loads the transactions into the given store, which
+    // is expected to be empty.
+    pub fn restore_with_store(
+        config: &EngineConfig,
+        store: Box<dyn StateStore>,
+        mut reader: impl Read,
+    ) -> io::Result<Self> {
+        let mut magic = [0; SNAPSHOT_MAGIC.len()];
+        reader.read_exact(&mut magic)?;
+        if &magic != SNAPSHOT_MAGIC {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Not a processor snapshot.",
+            ));
+        }
+        let mut version = [0; 4];
+        reader.read_exact(&mut version)?;
+        let version = u32::from_le_bytes(version);
+        if version != SNAPSHOT_VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "Snapshot is version {}, but only version {} is supported.",
+                    version, SNAPSHOT_VERSION
+                ),
+            ));
+        }
+
+        let mut processor = Self::with_store(config, store);
+        *processor.store.clients_mut() = read_snapshot_part(&mut reader)?;
+        while let Some((transaction_id, transaction)) =
+            read_snapshot_part::<Option<(TransactionID, Transaction)>>(&mut reader)?
+        {
+            processor
+                .store
+                .insert_transaction(transaction_id, transaction);
+        }
+        if let Some(e) = processor.store.take_error() {
+            return Err(e);
+        }
+
+        processor.transfer_credits_by_id = read_snapshot_part(&mut reader)?;
+        processor.conversions_by_id = read_snapshot_part(&mut reader)?;
+        processor.adjustments_by_id = read_snapshot_part(&mut reader)?;
+        processor.discarded_withdrawal_ids = read_snapshot_part(&mut reader)?;
+        processor.discarded_withdrawals = read_snapshot_part(&mut reader)?;
+        processor.fee_and_interest_ids = read_snapshot_part(&mut reader)?;
+        processor.pending_deposits = read_snapshot_part(&mut reader)?;
+        processor.counterparties_by_id = read_snapshot_part(&mut reader)?;
+        processor.open_disputes = read_snapshot_part(&mut reader)?;
+        processor.queued_events = read_snapshot_part(&mut reader)?;
+        processor.unlocked_since_last_check = read_snapshot_part(&mut reader)?;
+        processor.outstanding_chargebacks = read_snapshot_part(&mut reader)?;
+        processor.now = read_snapshot_part(&mut reader)?;
+        processor.event_number = read_snapshot_part(&mut reader)?;
+        Ok(processor)
+    }
+
+    // For when this processor only sees some of the events, so that the next
+    // one is still numbered by where it came in overall.
+    pub(crate) fn set_events_before(&mut self, events_before: u64) {
+        self.event_number = events_before;
+    }
+
+    // Everything the processor knows, for handing to another thread to be
+    // absorbed into another processor there.
+    pub(crate) fn into_parts(self) -> io::Result<ProcessorParts> {
+        let (clients_by_id, transactions_by_id) = self.store.into_maps()?;
+        Ok(ProcessorParts {
+            clients_by_id,
+            transactions_by_id,
+            transfer_credits_by_id: self.transfer_credits_by_id,
+            conversions_by_id: self.conversions_by_id,
+            adjustments_by_id: self.adjustments_by_id,
+            discarded_withdrawal_ids: self.discarded_withdrawal_ids,
+            discarded_withdrawals: self.discarded_withdrawals,
+            fee_and_interest_ids: self.fee_and_interest_ids,
+            pending_deposits: self.pending_deposits,
+            counterparties_by_id: self.counterparties_by_id,
+            open_disputes: self.open_disputes,
+            queued_events: self.queued_events,
+            unlocked_since_last_check: self.unlocked_since_last_check,
+            outstanding_chargebacks: self.outstanding_chargebacks,
+            event_number: self.event_number,
+        })
+    }
+
+    // Takes on everything another processor with the same config knew, for
+    // when the clients were split between several (see
+    // `process_events_sharded`) and have to be brought back together. Each
+    // client and transaction ID only ever went to one of them, so a clash
+    // means that's gone wrong and the result can't be trusted.
+    pub(crate) fn absorb(&mut self, other: ProcessorParts) -> io::Result<()> {
+        absorb_by_id(self.store.clients_mut(), other.clients_by_id, "client")?;
+        for (transaction_id, transaction) in other.transactions_by_id {
+            if self.store.contains_transaction(transaction_id) {
+                return Err(clash("transaction", transaction_id));
+            }
+            self.store.insert_transaction(transaction_id, transaction);
+        }
+        absorb_by_id(
+            &mut self.transfer_credits_by_id,
+            other.transfer_credits_by_id,
+            "transfer",
+        )?;
+        absorb_by_id(
+            &mut self.conversions_by_id,
+            other.conversions_by_id,
+            "conversion",
+        )?;
+        absorb_by_id(
+            &mut self.adjustments_by_id,
+            other.adjustments_by_id,
+            "adjustment",
+        )?;
+        absorb_by_id(
+            &mut self.pending_deposits,
+            other.pending_deposits,
+            "pending deposit",
+        )?;
+        absorb_by_id(
+            &mut self.counterparties_by_id,
+            other.counterparties_by_id,
+            "counterparty for transaction",
+        )?;
+        absorb_by_id(
+            &mut self.outstanding_chargebacks,
+            other.outstanding_chargebacks,
+            "chargebacks for client",
+        )?;
+        for (ids, other_ids, what) in [
+            (
+                &mut self.discarded_withdrawal_ids,
+                other.discarded_withdrawal_ids,
+                "withdrawal",
+            ),
+            (
+                &mut self.fee_and_interest_ids,
+                other.fee_and_interest_ids,
+                "fee or interest",
+            ),
+        ] {
+            for id in other_ids {
+                if !ids.insert(id) {
+                    return Err(clash(what, id));
+                }
+            }
+        }
+        for (currency, amount) in other.discarded_withdrawals {
+            *self.discarded_withdrawals.entry(currency).or_default() += amount;
+        }
+        // keyed by transaction as well as client, so they can't clash if the
+        // transactions didn't
+        self.open_disputes.extend(other.open_disputes);
+        self.queued_events.extend(other.queued_events);
+        self.unlocked_since_last_check |= other.unlocked_since_last_check;
+        self.event_number = self.event_number.max(other.event_number);
+        Ok(())
+    }
+
+    // See `StateStore::take_error`.
+    pub fn take_store_error(&mut self) -> Option<io::Error> {
+        self.store.take_error()
+    }
+
+    // Writes every event accepted from now on to the journal. It isn't part
+    // of a snapshot, so it has to be set again on a restored processor.
+    pub fn set_journal(&mut self, journal: Journal) {
+        self.journal = Some(journal);
+    }
+
+    // The first error from writing to the journal, if there's been one since
+    // this was last called.
+    pub fn take_journal_error(&mut self) -> Option<io::Error> {
+        self.journal.as_mut().and_then(Journal::take_error)
+    }
+
+    pub fn flush_journal(&mut self) -> io::Result<()> {
+        self.journal.as_mut().map_or(Ok(()), Journal::flush)
+    }
+
+    // Processes the events from a journal again, to get back to the state the
+    // processor was in when it was written. Events this processor has already
+    // got past (e.g. because it was restored from a snapshot taken partway
+    // through) are skipped, so a snapshot plus the journal since then is
+    // enough. Every event should be accepted again as long as the config is
+    // the same, so one that isn't means the journal doesn't belong with this
+    // state. Returns how many events were replayed. They aren't journaled
+    // again, since they're already in a journal.
+    pub fn replay_journal(&mut self, reader: impl Read) -> io::Result<u64> {
+        self.replay(reader, None)
+    }
+
+    // Like `replay_journal`, but stops at the given point, to see how things
+    // stood back then. Restoring from a snapshot taken before that point
+    // first saves replaying everything up to the snapshot; one taken after it
+    // is an error, since there's no going back.
+    pub fn replay_journal_until(
+        &mut self,
+        reader: impl Read,
+        until: ReplayPoint,
+    ) -> io::Result<u64> {
+        let already_past = match until {
+            ReplayPoint::AfterEvent(event_number) => self.event_number > event_number,
+            ReplayPoint::AtTime(time) => self.now.is_some_and(|now| now > time),
+        };
+        if already_past {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "The state is already past that point.",
+            ));
+        }
+
+        self.replay(reader, Some(until))
+    }
+
+    fn replay(&mut self, reader: impl Read, until: Option<ReplayPoint>) -> io::Result<u64> {
+        let journal = self.journal.take();
+        let replayed = self.replay_entries(reader, until);
+        self.journal = journal;
+        replayed
+    }
+
+    fn replay_entries(&mut self, reader: impl Read, until: Option<ReplayPoint>) -> io::Result<u64> {
+        let mut replayed = 0;
+        for entry in JournalReader::new(reader)? {
+            let entry = entry?;
+            if entry.event_number <= self.event_number {
+                continue;
+            }
+            if until.is_some_and(|until| until.is_past(&entry)) {
+                break;
+            }
+
+            // anything queued was journaled once it was finally processed, so
+            // it's taken out of the queue rather than processed twice
+            if let Some(position) = self
+                .queued_events
+                .iter()
+                .position(|queued| queued.event == entry.event)
+            {
+                self.queued_events.remove(position);
+            }
+
+            self.event_number = entry.event_number - 1;
+            let queued_before = self.queued_events.len();
+            let problem = match self.process_event(entry.event, entry.timestamp, None, &mut ()) {
+                Err(e) => Some(e.to_string()),
+                Ok(()) if self.queued_events.len() > queued_before => {
+                    Some(String::from("It was queued."))
+                }
+                Ok(()) => None,
+            };
+            if let Some(problem) = problem {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "Event {} from the journal wasn't accepted again. {}",
+                        entry.event_number, problem
+                    ),
+                ));
+            }
+            replayed += 1;
+        }
+
+        Ok(replayed)
+    }
+
+    pub fn clients_by_id(&self) -> &HashMap<ClientID, Client> {
+        self.store.clients()
+    }
+
+    // How many transaction IDs are held onto, whatever they were used for. The
+    // receiving side of a transfer shares its ID with the sending side, so it
+    // isn't counted separately.
+    pub fn transaction_count(&self) -> usize {
+        self.store.transaction_count()
+            + self.conversions_by_id.len()
+            + self.adjustments_by_id.len()
+            + self.discarded_withdrawal_ids.len()
+            + self.fee_and_interest_ids.len()
+    }
+
+    // Whether the run has taken on more than `EngineConfig::limits` allows,
+    // given how many events have been rejected so far.
+    pub fn check_limits(&self, rejected: u64) -> Result<(), Limit> {
+        self.config.limits.check(
+            self.store.clients().len(),
+            self.transaction_count(),
+            rejected,
+        )
+    }
+
+    pub fn client(&self, client_id: ClientID) -> Option<&Client> {
+        self.store.clients().get(&client_id)
+    }
+
+    // Every client, in no particular order.
+    pub fn clients(&self) -> impl Iterator<Item = (ClientID, &Client)> {
+        self.store
+            .clients()
+            .iter()
+            .map(|(client_id, client)| (*client_id, client))
+    }
+
+    // Both sides of a transfer share its ID, so for a transfer this is the
+    // sending side. This takes `&mut self` because the store may have to load
+    // the transaction to find it.
+    pub fn transaction(&mut self, transaction_id: TransactionID) -> Option<&Transaction> {
+        self.store
+            .transaction_mut(transaction_id)
+            .map(|transaction| &*transaction)
+    }
+
+    // A hash of the clients and transactions, the same as
+    // `FinalState::state_digest` gives once the run is over. This takes
+    // `&mut self` because the store may have to load the transactions.
+    pub fn state_digest(&mut self) -> io::Result<String> {
+        let mut transaction_lines = Vec::new();
+        self.store
+            .for_each_transaction(&mut |transaction_id, transaction| {
+                transaction_lines.push(transaction_line(transaction_id, transaction, false));
+                Ok(())
+            })?;
+        transaction_lines.extend(self.transfer_credits_by_id.iter().map(
+            |(transaction_id, transaction)| transaction_line(*transaction_id, transaction, true),
+        ));
+
+        Ok(state_digest(self.store.clients(), transaction_lines))
+    }
+
+    pub fn queued_event_count(&self) -> usize {
+        self.queued_events.len()
+    }
+
+    // Takes whatever is still queued, e.g. once there are no more events to
+    // process.
+    pub fn take_queued_events(&mut self) -> Vec<SourcedEvent> {
+        std::mem::take(&mut self.queued_events)
+    }
+
+    // Takes whatever was queued for clients that have since been unlocked, so
+    // that it can be processed now, in the order it came in.
+    pub fn take_unlocked_events(&mut self) -> Vec<SourcedEvent> {
+        if !std::mem::take(&mut self.unlocked_since_last_check) {
+            return Vec::new();
+        }
+
+        let (unlocked, still_locked) = std::mem::take(&mut self.queued_events)
+            .into_iter()
+            .partition(|queued| match queued.event {
+                Event::DisputeStep { client_id, .. } => self
+                    .store
+                    .clients()
+                    .get(&client_id)
+                    .is_none_or(|client| !client.locked()),
+                _ => true,
+            });
+        self.queued_events = still_locked;
+        unlocked
+    }
+
+    // Like `process_event`, but also returns how each client the event touched
+    // changed, for embedders that mirror balances somewhere else and would
+    // rather not diff the clients themselves. Clients that didn't change are
+    // left out, so an event that's queued comes back with nothing.
+    pub fn process_event_with_deltas(
+        &mut self,
+        event: Event,
+        timestamp: Option<Timestamp>,
+        source: Option<&Source>,
+        observer: &mut (impl ProcessorObserver + ?Sized),
+    ) -> Result<Vec<StateDelta>, ProcessingError> {
+        let mut tracker = DeltaTracker::default();
+        let client_ids = self.touched_client_ids(&event);
+        tracker.capture(self.store.clients(), &client_ids);
+
+        self.process_event(event, timestamp, source, observer)?;
+        Ok(tracker.finish(self.store.clients()))
+    }
+
+    // Processes a batch of events, along with anything each of them unlocks,
+    // for embedders that get their events a batch at a time. Rejections aren't
+    // logged or reported to an observer, just counted, and whoever's calling
+    // still has to check `take_store_error` afterwards.
+    pub fn process_batch(&mut self, events: &[Event]) -> BatchResult {
+        let mut result = BatchResult::default();
+        let mut tracker = DeltaTracker::default();
+        for event in events {
+            self.process_batched(event.clone(), None, None, &mut result, &mut tracker);
+            for queued in self.take_unlocked_events() {
+                self.process_batched(
+                    queued.event,
+                    queued.timestamp,
+                    queued.source.as_ref(),
+                    &mut result,
+                    &mut tracker,
+                );
+            }
+        }
+
+        result.deltas = tracker.finish(self.store.clients());
+        result
+    }
+
+    fn process_batched(
+        &mut self,
+        event: Event,
+        timestamp: Option<Timestamp>,
+        source: Option<&Source>,
+        result: &mut BatchResult,
+        tracker: &mut DeltaTracker,
+    ) {
+        let client_ids = self.touched_client_ids(&event);
+        tracker.capture(self.store.clients(), &client_ids);
+
+        let queued_before = self.queued_events.len();
+        match self.process_event(event, timestamp, source, &mut ()) {
+            Ok(()) if self.queued_events.len() > queued_before => result.queued += 1,
+            Ok(()) => result.accepted += 1,
+            Err(_) => result.rejected += 1,
+        }
+    }
+
+    // The source is only needed if the event ends up queued, so that it can
+    // still be pointed at once it's processed. Queued events aren't reported
+    // to the observer until they're processed.
+    pub fn process_event(
+        &mut self,
+        event: Event,
+        timestamp: Option<Timestamp>,
+        source: Option<&Source>,
+        observer: &mut (impl ProcessorObserver + ?Sized),
+    ) -> Result<(), ProcessingError> {
+        self.now = timestamp;
+        self.event_number += 1;
+        observer.on_event(self.event_number, self.now);
+        self.lapse_disputes(observer);
+        let result = self
+            .check_dispute_window(&event)
+            .and_then(|()| self.should_queue(&event));
+        match result {
+            Ok(true) => {
+                self.queued_events.push(SourcedEvent {
+                    event,
+                    source: source.cloned(),
+                    timestamp: self.now,
+                });
+                return Ok(());
+            }
+            Ok(false) => {}
+            Err(e) => {
+                observer.on_rejected(&event, &e);
+                return Err(e);
+            }
+        }
+
+        let was_locked = self.chargeback_client(&event).is_some_and(Client::locked);
+        let was_flagged = self.chargeback_client(&event).is_some_and(Client::flagged);
+        if let Err(e) = self.apply_checked(&event) {
+            observer.on_rejected(&event, &e);
+            return Err(e);
+        }
+        observer.on_accepted(&event);
+        if let Some(journal) = self.journal.as_mut() {
+            journal.append(self.event_number, self.now, &event);
+        }
+        self.track_open_dispute(&event);
+
+        if let Event::DisputeStep {
+            kind: DisputeStepKind::Chargeback,
+            transaction_id,
+            client_id,
+            ..
+        } = event
+        {
+            if let Some(transaction) = self.find_transaction(transaction_id, client_id) {
+                observer.on_chargeback(client_id, transaction_id, transaction);
+            }
+            if let Some(client) = self.store.clients().get(&client_id) {
+                if client.locked() && !was_locked {
+                    observer.on_lock(client_id, client);
+                }
+                if client.flagged() && !was_flagged {
+                    observer.on_flag(client_id, client);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    // Applies the event, checking the invariants afterwards if asked to, and
+    // telling the store what changed if it wants to know.
+    fn apply_checked(&mut self, event: &Event) -> Result<(), ProcessingError> {
+        let clients_before = self.config.check_invariants.then(|| {
+            let client_ids = self.touched_client_ids(event);
+            ClientsBefore::capture(self.store.clients(), &client_ids)
+        });
+        let mirrored_ids = self
+            .store
+            .mirrors_clients()
+            .then(|| self.touched_client_ids(event));
+        let result = self.apply_event(event);
+        if let Some(clients_before) = clients_before {
+            self.check_invariants(event, result.is_ok(), &clients_before);
+        }
+        if let (Ok(()), Some(client_ids)) = (&result, mirrored_ids) {
+            self.store.clients_changed(&client_ids);
+        }
+        result
+    }
+
+    fn track_open_dispute(&mut self, event: &Event) {
+        let (Some(_), Some(now)) = (self.config.dispute_expiry, self.now) else {
+            return;
+        };
+        let Event::DisputeStep {
+            ref kind,
+            transaction_id,
+            client_id,
+            ..
+        } = *event
+        else {
+            return;
+        };
+        match kind {
+            DisputeStepKind::Dispute => {
+                self.open_disputes.insert((transaction_id, client_id), now);
+            }
+            DisputeStepKind::Resolve | DisputeStepKind::Chargeback => {
+                self.open_disputes.remove(&(transaction_id, client_id));
+            }
+        }
+    }
+
+    // Resolves every dispute that's been open for longer than
+    // `EngineConfig::dispute_expiry`, oldest first. The event being processed
+    // is the first we know of that much time having passed, so they're
+    // resolved just before it and share its number. They aren't journaled,
+    // since replaying the journal lapses them all over again. A dispute on a
+    // locked account whose dispute steps are being queued or rejected stays
+    // open until that no longer applies.
+    fn lapse_disputes(&mut self, observer: &mut (impl ProcessorObserver + ?Sized)) {
+        let (Some(expiry), Some(now)) = (self.config.dispute_expiry, self.now) else {
+            return;
+        };
+        let mut lapsed = self
+            .open_disputes
+            .iter()
+            .filter(|(_, disputed_at)| now.saturating_sub(**disputed_at) > expiry.as_secs())
+            .map(|(key, disputed_at)| (*disputed_at, *key))
+            .collect::<Vec<_>>();
+        lapsed.sort_unstable();
+
+        for (_, (transaction_id, client_id)) in lapsed {
+            let resolve = Event::DisputeStep {
+                kind: DisputeStepKind::Resolve,
+                transaction_id,
+                client_id,
+                amount: None,
+            };
+            if !matches!(self.should_queue(&resolve), Ok(false)) {
+                continue;
+            }
+            self.open_disputes.remove(&(transaction_id, client_id));
+            if self.apply_checked(&resolve).is_ok() {
+                observer.on_dispute_lapsed(&resolve);
+            }
+        }
     }
 
-    pub fn process_event(&mut self, event: Event) -> Result<(), String> {
+    // Every client the event could change, including the other side of a
+    // transfer that's being reversed.
+    pub(crate) fn touched_client_ids(&mut self, event: &Event) -> Vec<ClientID> {
+        match *event {
+            Event::Transfer {
+                from_client_id,
+                to_client_id,
+                ..
+            } => vec![from_client_id, to_client_id],
+            Event::Reversal {
+                transaction_id,
+                client_id,
+            } => iter::once(client_id)
+                .chain(
+                    self.transfer_credits_by_id
+                        .get(&transaction_id)
+                        .map(Transaction::client_id),
+                )
+                .chain(
+                    self.store
+                        .transaction_mut(transaction_id)
+                        .map(|transaction| transaction.client_id()),
+                )
+                .collect(),
+            Event::Transaction { client_id, .. }
+            | Event::DisputeStep { client_id, .. }
+            | Event::PendingDeposit { client_id, .. }
+            | Event::Settlement { client_id, .. }
+            | Event::Fee { client_id, .. }
+            | Event::Conversion { client_id, .. }
+            | Event::ChargebackReversal { client_id, .. }
+            | Event::Adjustment { client_id, .. }
+            | Event::AccountClosure { client_id }
+            | Event::ClientRegistration { client_id, .. }
+            | Event::Interest { client_id, .. } => vec![client_id],
+        }
+    }
+
+    // Panics if the event left the state inconsistent, since that can only be
+    // a bug in here.
+    fn check_invariants(&mut self, event: &Event, accepted: bool, clients_before: &ClientsBefore) {
+        let transaction_id = match *event {
+            Event::Transaction { transaction_id, .. }
+            | Event::DisputeStep { transaction_id, .. }
+            | Event::Transfer { transaction_id, .. }
+            | Event::PendingDeposit { transaction_id, .. }
+            | Event::Settlement { transaction_id, .. }
+            | Event::Reversal { transaction_id, .. }
+            | Event::ChargebackReversal { transaction_id, .. } => Some(transaction_id),
+            _ => None,
+        };
+        let (transaction, clients_by_id) = match transaction_id {
+            Some(transaction_id) => self.store.transaction_and_clients_mut(transaction_id),
+            None => (None, self.store.clients_mut()),
+        };
+        let transactions = transaction
+            .map(|transaction| &*transaction)
+            .into_iter()
+            .chain(
+                transaction_id
+                    .and_then(|transaction_id| self.transfer_credits_by_id.get(&transaction_id)),
+            )
+            .collect::<Vec<_>>();
+
+        if let Err(violation) = check_invariants(
+            event,
+            accepted,
+            clients_before,
+            clients_by_id,
+            &transactions,
+            &self.config,
+        ) {
+            panic!("Invariant violated by {:?}: {}.", event, violation);
+        }
+    }
+
+    // Only chargebacks lock or flag accounts, so that's all we need to know
+    // about.
+    fn chargeback_client(&self, event: &Event) -> Option<&Client> {
         match event {
+            Event::DisputeStep {
+                kind: DisputeStepKind::Chargeback,
+                client_id,
+                ..
+            } => self.store.clients().get(client_id),
+            _ => None,
+        }
+    }
+
+    // Whether the event is a dispute step on a locked account that should be
+    // set aside until the account is unlocked.
+    fn should_queue(&self, event: &Event) -> Result<bool, ProcessingError> {
+        let Event::DisputeStep { client_id, .. } = event else {
+            return Ok(false);
+        };
+        if !self
+            .store
+            .clients()
+            .get(client_id)
+            .is_some_and(Client::locked)
+        {
+            return Ok(false);
+        }
+
+        match self.config.locked_account_disputes {
+            LockedAccountPolicy::Process => Ok(false),
+            LockedAccountPolicy::Queue => Ok(true),
+            LockedAccountPolicy::Reject => Err(ProcessingError::AccountLocked {
+                action: event.kind_name(),
+            }),
+        }
+    }
+
+    fn apply_event(&mut self, event: &Event) -> Result<(), ProcessingError> {
+        // checked first, since a re-delivery changes nothing whatever state
+        // the account is in now
+        if self.is_identical_resubmission(event) {
+            return Ok(());
+        }
+        self.check_accounts_open(event)?;
+
+        match *event {
             Event::Transaction {
-                kind,
+                ref kind,
                 transaction_id,
                 client_id,
+                currency,
                 amount,
-            } => match kind {
-                TransactionKind::Deposit => self.deposit(transaction_id, client_id, amount),
-                TransactionKind::Withdrawal => self.withdraw(transaction_id, client_id, amount),
-            },
+                ref counterparty,
+            } => {
+                let counterparty = counterparty.as_deref();
+                match kind {
+                    TransactionKind::Deposit => {
+                        self.deposit(transaction_id, client_id, currency, amount, counterparty)
+                    }
+                    TransactionKind::Withdrawal => {
+                        self.withdraw(transaction_id, client_id, currency, amount, counterparty)
+                    }
+                }
+            }
             Event::DisputeStep {
-                kind,
+                ref kind,
                 transaction_id,
                 client_id,
-            } => match kind {
-                DisputeStepKind::Dispute => self.dispute(transaction_id, client_id),
-                DisputeStepKind::Resolve => self.resolve(transaction_id, client_id),
-                DisputeStepKind::Chargeback => self.chargeback(transaction_id, client_id),
-            },
+                amount,
+            } => {
+                if self.pending_deposits.contains_key(&transaction_id) {
+                    return Err(ProcessingError::DepositPending { id: transaction_id });
+                }
+                match kind {
+                    DisputeStepKind::Dispute => self.dispute(transaction_id, client_id, amount),
+                    DisputeStepKind::Resolve => self.resolve(transaction_id, client_id),
+                    DisputeStepKind::Chargeback => self.chargeback(transaction_id, client_id),
+                }
+            }
+            Event::PendingDeposit {
+                transaction_id,
+                client_id,
+                currency,
+                amount,
+                ref counterparty,
+            } => self.deposit_pending(
+                transaction_id,
+                client_id,
+                currency,
+                amount,
+                counterparty.as_deref(),
+            ),
+            Event::Settlement {
+                transaction_id,
+                client_id,
+            } => self.settle(transaction_id, client_id),
+            Event::Transfer {
+                transaction_id,
+                from_client_id,
+                to_client_id,
+                currency,
+                amount,
+            } => self.transfer(
+                transaction_id,
+                from_client_id,
+                to_client_id,
+                currency,
+                amount,
+            ),
+            Event::Fee {
+                transaction_id,
+                client_id,
+                currency,
+                amount,
+            } => self.charge_fee(transaction_id, client_id, currency, amount),
+            Event::Conversion {
+                transaction_id,
+                client_id,
+                from_currency,
+                to_currency,
+                amount,
+                rate,
+            } => self.convert(
+                transaction_id,
+                Conversion::new(client_id, from_currency, to_currency, amount, rate)?,
+            ),
+            Event::Reversal {
+                transaction_id,
+                client_id,
+            } => self.reverse(transaction_id, client_id),
+            Event::ChargebackReversal {
+                transaction_id,
+                client_id,
+            } => self.reverse_chargeback(transaction_id, client_id),
+            Event::Adjustment {
+                transaction_id,
+                client_id,
+                currency,
+                amount,
+                ref reason,
+            } => self.adjust(
+                transaction_id,
+                Adjustment::new(client_id, currency, amount, reason.clone()),
+            ),
+            Event::AccountClosure { client_id } => self
+                .store
+                .clients_mut()
+                .get_mut(&client_id)
+                .ok_or(ProcessingError::ClientNotFound { client_id })?
+                .close(),
+            Event::ClientRegistration {
+                client_id,
+                ref name,
+                ref tier,
+                currency,
+            } => {
+                self.find_or_create_client(client_id)
+                    .register(ClientProfile::new(name.clone(), tier.clone(), currency));
+                Ok(())
+            }
+            Event::Interest {
+                transaction_id,
+                client_id,
+                currency,
+                rate,
+            } => self.credit_interest(transaction_id, client_id, currency, rate),
         }
     }
 
+    // Disputes have to be raised within the configured window of the
+    // transaction they're about. We can only tell when both have a timestamp,
+    // so anything without one is let through.
+    fn check_dispute_window(&mut self, event: &Event) -> Result<(), ProcessingError> {
+        let Event::DisputeStep {
+            kind: DisputeStepKind::Dispute,
+            transaction_id,
+            client_id,
+            ..
+        } = event
+        else {
+            return Ok(());
+        };
+        let (Some(window), Some(now)) = (self.config.dispute_window, self.now) else {
+            return Ok(());
+        };
+        let Some(timestamp) = self
+            .find_transaction(*transaction_id, *client_id)
+            .and_then(Transaction::timestamp)
+        else {
+            return Ok(());
+        };
+
+        if now.saturating_sub(timestamp) > window.as_secs() {
+            return Err(ProcessingError::DisputeWindowExpired {
+                id: *transaction_id,
+            });
+        }
+
+        Ok(())
+    }
+
+    // Rejects events that would move money in or out of a closed account at
+    // the client's request, or pay it interest.
+    fn check_accounts_open(&self, event: &Event) -> Result<(), ProcessingError> {
+        let allow_withdrawals =
+            self.config.closed_accounts == ClosedAccountPolicy::AllowWithdrawals;
+        let closed = |client_id: &ClientID| {
+            self.store
+                .clients()
+                .get(client_id)
+                .is_some_and(Client::closed)
+        };
+        let rejected = match event {
+            Event::Transaction {
+                kind: TransactionKind::Withdrawal,
+                client_id,
+                ..
+            } => closed(client_id) && !allow_withdrawals,
+            Event::Transfer {
+                from_client_id,
+                to_client_id,
+                ..
+            } => (closed(from_client_id) && !allow_withdrawals) || closed(to_client_id),
+            Event::Transaction { client_id, .. }
+            | Event::PendingDeposit { client_id, .. }
+            | Event::Fee { client_id, .. }
+            | Event::Conversion { client_id, .. }
+            | Event::Interest { client_id, .. } => closed(client_id),
+            Event::DisputeStep { .. }
+            | Event::Settlement { .. }
+            | Event::Reversal { .. }
+            | Event::ChargebackReversal { .. }
+            | Event::Adjustment { .. }
+            | Event::AccountClosure { .. }
+            | Event::ClientRegistration { .. } => false,
+        };
+
+        if rejected {
+            return Err(ProcessingError::AccountClosed {
+                kind: event.kind_name(),
+            });
+        }
+
+        Ok(())
+    }
+
     fn deposit(
         &mut self,
         transaction_id: TransactionID,
         client_id: ClientID,
+        currency: Currency,
+        amount: Amount,
+        counterparty: Option<&str>,
+    ) -> Result<(), ProcessingError> {
+        self.check_transaction_does_not_exist(transaction_id)?;
+
+        // a deposit fee comes out of the deposit itself, so it can never take
+        // more than was deposited, and it never adds to it either
+        let fee = self
+            .config
+            .fee_schedule
+            .deposit
+            .map_or(Ok(Amount::ZERO), |fee| fee.amount_for(amount))?
+            .min(amount)
+            .max(Amount::ZERO);
+        let allow_locked = self.config.locked_account_deposits == LockedDepositPolicy::Accept;
+        let client = self.find_or_create_client(client_id);
+        client.deposit_with_fee(currency, amount, fee, allow_locked)?;
+        client.record_transaction(transaction_id);
+        self.create_transaction(
+            transaction_id,
+            Transaction::new(client_id, currency, amount, TransactionKind::Deposit),
+        );
+        self.record_counterparty(transaction_id, counterparty);
+
+        Ok(())
+    }
+
+    // Just like `deposit`, apart from keeping track of what's pending.
+    fn deposit_pending(
+        &mut self,
+        transaction_id: TransactionID,
+        client_id: ClientID,
+        currency: Currency,
         amount: Amount,
-    ) -> Result<(), String> {
+        counterparty: Option<&str>,
+    ) -> Result<(), ProcessingError> {
         self.check_transaction_does_not_exist(transaction_id)?;
 
+        let fee = self
+            .config
+            .fee_schedule
+            .deposit
+            .map_or(Ok(Amount::ZERO), |fee| fee.amount_for(amount))?
+            .min(amount)
+            .max(Amount::ZERO);
+        let allow_locked = self.config.locked_account_deposits == LockedDepositPolicy::Accept;
         let client = self.find_or_create_client(client_id);
-        client.deposit(amount)?;
+        client.deposit_pending(currency, amount, fee, allow_locked)?;
+        client.record_transaction(transaction_id);
         self.create_transaction(
             transaction_id,
-            Transaction::new(client_id, amount, TransactionKind::Deposit),
+            Transaction::new(client_id, currency, amount, TransactionKind::Deposit),
         );
+        self.record_counterparty(transaction_id, counterparty);
+        self.pending_deposits.insert(transaction_id, amount - fee);
+
+        Ok(())
+    }
+
+    fn settle(
+        &mut self,
+        transaction_id: TransactionID,
+        client_id: ClientID,
+    ) -> Result<(), ProcessingError> {
+        let Some(pending) = self.pending_deposits.get(&transaction_id).copied() else {
+            return Err(match self.find_transaction(transaction_id, client_id) {
+                Some(_) => ProcessingError::NotPending { id: transaction_id },
+                None => ProcessingError::TransactionNotFound { id: transaction_id },
+            });
+        };
+        let (transaction, client) = self.get_transaction_and_client(transaction_id, client_id)?;
+        Self::check_client_owns_transaction(client_id, transaction)?;
+
+        client.settle(transaction.currency(), pending)?;
+        self.pending_deposits.remove(&transaction_id);
 
         Ok(())
     }
@@ -72,33 +1174,240 @@ impl Processor {
         &mut self,
         transaction_id: TransactionID,
         client_id: ClientID,
+        currency: Currency,
         amount: Amount,
-    ) -> Result<(), String> {
+        counterparty: Option<&str>,
+    ) -> Result<(), ProcessingError> {
         self.check_transaction_does_not_exist(transaction_id)?;
 
+        let fee = self
+            .config
+            .fee_schedule
+            .withdrawal
+            .map_or(Ok(Amount::ZERO), |fee| fee.amount_for(amount))?;
+        let discard = self.config.disputable_only
+            && self.config.withdrawal_disputes == WithdrawalDisputePolicy::Reject;
+        // worked out first so that we never withdraw without keeping track
+        let discarded = if discard {
+            let discarded = self.discarded_withdrawals.get(&currency).copied();
+            discarded
+                .unwrap_or_default()
+                .checked_add(amount)
+                .ok_or(ProcessingError::AmountOverflow)?
+        } else {
+            Amount::ZERO
+        };
         let client = self.find_or_create_client(client_id);
-        client.withdraw(amount)?;
+        client.withdraw_with_fee(currency, amount, fee)?;
+        client.record_transaction(transaction_id);
+        if discard {
+            self.discarded_withdrawal_ids.insert(transaction_id);
+            self.discarded_withdrawals.insert(currency, discarded);
+        } else {
+            self.create_transaction(
+                transaction_id,
+                Transaction::new(client_id, currency, amount, TransactionKind::Withdrawal),
+            );
+            self.record_counterparty(transaction_id, counterparty);
+        }
+
+        Ok(())
+    }
+
+    // Each side of a transfer is recorded as its own transaction (a withdrawal
+    // from one client and a deposit to the other) so that either client can
+    // dispute their side of it.
+    fn transfer(
+        &mut self,
+        transaction_id: TransactionID,
+        from_client_id: ClientID,
+        to_client_id: ClientID,
+        currency: Currency,
+        amount: Amount,
+    ) -> Result<(), ProcessingError> {
+        self.check_transaction_does_not_exist(transaction_id)?;
+
+        if from_client_id == to_client_id {
+            return Err(ProcessingError::SelfTransfer {
+                client_id: from_client_id,
+            });
+        }
+
+        // we check everything that could make the deposit fail before making
+        // the withdrawal, so that we never end up doing only one of the two
+        let allow_locked = self.config.locked_account_deposits == LockedDepositPolicy::Accept;
+        self.find_or_create_client(to_client_id).check_can_deposit(
+            currency,
+            amount,
+            allow_locked,
+        )?;
+
+        let from_client = self.find_or_create_client(from_client_id);
+        from_client.withdraw(currency, amount)?;
+        from_client.record_transaction(transaction_id);
+
+        let to_client = self.find_or_create_client(to_client_id);
+        to_client.deposit(currency, amount, allow_locked)?;
+        to_client.record_transaction(transaction_id);
+
         self.create_transaction(
             transaction_id,
-            Transaction::new(client_id, amount, TransactionKind::Withdrawal),
+            Transaction::new(
+                from_client_id,
+                currency,
+                amount,
+                TransactionKind::Withdrawal,
+            ),
+        );
+        self.transfer_credits_by_id.insert(
+            transaction_id,
+            Transaction::new(to_client_id, currency, amount, TransactionKind::Deposit)
+                .with_timestamp(self.now),
         );
 
         Ok(())
     }
 
+    // Fee events take a transaction ID like any other event, so we still reject
+    // one that clashes with an existing transaction, and hold onto the ID so
+    // that nothing else reuses it, but there's nothing else to store because
+    // fees can't be disputed.
+    fn charge_fee(
+        &mut self,
+        transaction_id: TransactionID,
+        client_id: ClientID,
+        currency: Currency,
+        amount: Amount,
+    ) -> Result<(), ProcessingError> {
+        self.check_transaction_does_not_exist(transaction_id)?;
+
+        self.find_or_create_client(client_id)
+            .charge_fee(currency, amount)?;
+        self.fee_and_interest_ids.insert(transaction_id);
+
+        Ok(())
+    }
+
+    // Like fees, interest isn't stored as a transaction, but what each client
+    // has been paid is kept on their balance. There's nothing to pay a client
+    // we've never heard of, so unlike deposits this doesn't create one.
+    fn credit_interest(
+        &mut self,
+        transaction_id: TransactionID,
+        client_id: ClientID,
+        currency: Currency,
+        rate: Amount,
+    ) -> Result<(), ProcessingError> {
+        self.check_transaction_does_not_exist(transaction_id)?;
+
+        let allow_locked = self.config.locked_account_deposits == LockedDepositPolicy::Accept;
+        self.store
+            .clients_mut()
+            .get_mut(&client_id)
+            .ok_or(ProcessingError::ClientNotFound { client_id })?
+            .credit_interest(currency, rate, allow_locked)?;
+        self.fee_and_interest_ids.insert(transaction_id);
+
+        Ok(())
+    }
+
+    fn adjust(
+        &mut self,
+        transaction_id: TransactionID,
+        adjustment: Adjustment,
+    ) -> Result<(), ProcessingError> {
+        self.check_transaction_does_not_exist(transaction_id)?;
+
+        self.find_or_create_client(adjustment.client_id())
+            .adjust(adjustment.currency(), adjustment.amount())?;
+        self.adjustments_by_id.insert(transaction_id, adjustment);
+
+        Ok(())
+    }
+
+    fn convert(
+        &mut self,
+        transaction_id: TransactionID,
+        conversion: Conversion,
+    ) -> Result<(), ProcessingError> {
+        self.check_transaction_does_not_exist(transaction_id)?;
+
+        if conversion.from_currency() == conversion.to_currency() {
+            return Err(ProcessingError::SameCurrencyConversion);
+        }
+
+        let client = self.find_or_create_client(conversion.client_id());
+        client.convert(
+            conversion.from_currency(),
+            conversion.to_currency(),
+            conversion.amount(),
+            conversion.converted_amount(),
+        )?;
+        client.record_transaction(transaction_id);
+        self.conversions_by_id.insert(transaction_id, conversion);
+
+        Ok(())
+    }
+
+    // Without an amount, whatever hasn't already been charged back is
+    // disputed.
     fn dispute(
         &mut self,
         transaction_id: TransactionID,
         client_id: ClientID,
-    ) -> Result<(), String> {
-        let (transaction, client) = self.get_transaction_and_client(transaction_id)?;
+        amount: Option<Amount>,
+    ) -> Result<(), ProcessingError> {
+        let policy = self.config.withdrawal_disputes;
+        let allow_redispute = self.config.allow_redispute;
+        let event_number = self.event_number;
+        let (transaction, client) = self.get_transaction_and_client(transaction_id, client_id)?;
         Self::check_client_owns_transaction(client_id, transaction)?;
 
-        transaction.validate_dispute_status_transition(DisputeStatus::Disputed)?;
+        transaction.validate_dispute_status_transition(DisputeStatus::Disputed, allow_redispute)?;
 
-        client.hold(transaction.amount());
+        // a re-dispute undoes the chargeback, which leaves the client where
+        // they were while the transaction was first disputed
+        let redispute = transaction.dispute_status() == DisputeStatus::ChargedBack;
+        let amount = if redispute {
+            if amount.is_some_and(|amount| amount != transaction.disputed_amount()) {
+                return Err(ProcessingError::RedisputeAmountMismatch);
+            }
+            transaction.disputed_amount()
+        } else {
+            let disputable = transaction.disputable_amount();
+            let amount = amount.unwrap_or(disputable);
+            if amount <= Amount::ZERO || amount > disputable {
+                return Err(ProcessingError::InvalidDisputeAmount { amount, disputable });
+            }
+            amount
+        };
 
-        transaction.set_dispute_status(DisputeStatus::Disputed);
+        match (transaction.kind(), policy) {
+            (TransactionKind::Deposit, _) if redispute => {
+                client.redispute_deposit(transaction.currency(), amount)?;
+            }
+            (TransactionKind::Withdrawal, WithdrawalDisputePolicy::CreditHeld) if redispute => {
+                client.hold(transaction.currency(), amount)?;
+            }
+            (TransactionKind::Withdrawal, _) if redispute => {
+                client.redispute_withdrawal(transaction.currency(), amount)?;
+            }
+            (TransactionKind::Withdrawal, WithdrawalDisputePolicy::Reject) => {
+                return Err(ProcessingError::WithdrawalDisputesRejected);
+            }
+            (TransactionKind::Withdrawal, WithdrawalDisputePolicy::CreditHeld) => {
+                client.credit_held(transaction.currency(), amount)?;
+            }
+            _ => client.hold(transaction.currency(), amount)?,
+        }
+        client.record_dispute();
+
+        transaction.dispute(amount, event_number);
+        // the account stays locked either way, since unlocking is a separate
+        // decision
+        if redispute {
+            self.settle_chargeback(client_id);
+        }
 
         Ok(())
     }
@@ -107,15 +1416,24 @@ impl Processor {
         &mut self,
         transaction_id: TransactionID,
         client_id: ClientID,
-    ) -> Result<(), String> {
-        let (transaction, client) = self.get_transaction_and_client(transaction_id)?;
+    ) -> Result<(), ProcessingError> {
+        let policy = self.config.withdrawal_disputes;
+        let allow_redispute = self.config.allow_redispute;
+        let event_number = self.event_number;
+        let (transaction, client) = self.get_transaction_and_client(transaction_id, client_id)?;
         Self::check_client_owns_transaction(client_id, transaction)?;
 
-        transaction.validate_dispute_status_transition(DisputeStatus::Undisputed)?;
+        transaction
+            .validate_dispute_status_transition(DisputeStatus::Undisputed, allow_redispute)?;
 
-        client.hold(-transaction.amount());
+        match (transaction.kind(), policy) {
+            (TransactionKind::Withdrawal, WithdrawalDisputePolicy::CreditHeld) => {
+                client.credit_held(transaction.currency(), -transaction.disputed_amount())?;
+            }
+            _ => client.hold(transaction.currency(), -transaction.disputed_amount())?,
+        }
 
-        transaction.set_dispute_status(DisputeStatus::Undisputed);
+        transaction.resolve(event_number);
 
         Ok(())
     }
@@ -124,23 +1442,191 @@ impl Processor {
         &mut self,
         transaction_id: TransactionID,
         client_id: ClientID,
-    ) -> Result<(), String> {
-        let (transaction, client) = self.get_transaction_and_client(transaction_id)?;
+    ) -> Result<(), ProcessingError> {
+        let undisputed = self
+            .find_transaction(transaction_id, client_id)
+            .is_some_and(|transaction| {
+                matches!(
+                    transaction.dispute_status(),
+                    DisputeStatus::Undisputed | DisputeStatus::PartiallyChargedBack
+                )
+            });
+        if !undisputed
+            || self.config.undisputed_chargebacks != UndisputedChargebackPolicy::ImplicitDispute
+        {
+            return self.charge_back_disputed(transaction_id, client_id);
+        }
+
+        // the implicit dispute has to be undone if the chargeback then fails,
+        // or the rejected chargeback would leave the money held. Only the
+        // transaction and its client are touched by a first dispute, so
+        // putting those back is enough.
+        let before = self
+            .get_transaction_and_client(transaction_id, client_id)
+            .map(|(transaction, client)| (transaction.clone(), client.clone()))?;
+        self.dispute(transaction_id, client_id, None)?;
+        self.charge_back_disputed(transaction_id, client_id)
+            .inspect_err(|_| {
+                if let Ok((transaction, client)) =
+                    self.get_transaction_and_client(transaction_id, client_id)
+                {
+                    (*transaction, *client) = before;
+                }
+            })
+    }
+
+    fn charge_back_disputed(
+        &mut self,
+        transaction_id: TransactionID,
+        client_id: ClientID,
+    ) -> Result<(), ProcessingError> {
+        let policy = self.config.withdrawal_disputes;
+        let allow_redispute = self.config.allow_redispute;
+        let limit = self.config.chargeback_limit;
+        let event_number = self.event_number;
+        let (transaction, client) = self.get_transaction_and_client(transaction_id, client_id)?;
         Self::check_client_owns_transaction(client_id, transaction)?;
 
-        transaction.validate_dispute_status_transition(DisputeStatus::ChargedBack)?;
+        transaction
+            .validate_dispute_status_transition(DisputeStatus::ChargedBack, allow_redispute)?;
+
+        match (transaction.kind(), policy) {
+            (TransactionKind::Deposit, _) => {
+                client.chargeback_deposit(transaction.currency(), transaction.disputed_amount())?;
+            }
+
+            (TransactionKind::Withdrawal, WithdrawalDisputePolicy::CreditHeld) => {
+                client.chargeback_held(transaction.currency(), transaction.disputed_amount())?;
+            }
+
+            (TransactionKind::Withdrawal, _) => {
+                client
+                    .chargeback_withdrawal(transaction.currency(), transaction.disputed_amount())?;
+            }
+        };
+
+        transaction.charge_back(event_number);
+        if limit.is_some_and(|limit| client.chargeback_count() >= limit) {
+            client.flag();
+        }
+        *self.outstanding_chargebacks.entry(client_id).or_default() += 1;
+
+        Ok(())
+    }
+
+    // Gives back what the most recent chargeback took. The account stays
+    // locked unless the config says otherwise and nothing else is keeping it
+    // locked, including having gone over the chargeback limit.
+    fn reverse_chargeback(
+        &mut self,
+        transaction_id: TransactionID,
+        client_id: ClientID,
+    ) -> Result<(), ProcessingError> {
+        let (transaction, client) = self.get_transaction_and_client(transaction_id, client_id)?;
+        Self::check_client_owns_transaction(client_id, transaction)?;
+
+        transaction.validate_chargeback_reversal()?;
 
         match transaction.kind() {
             TransactionKind::Deposit => {
-                client.chargeback_deposit(transaction.amount());
+                client.reverse_deposit_chargeback(
+                    transaction.currency(),
+                    transaction.disputed_amount(),
+                )?;
             }
-
             TransactionKind::Withdrawal => {
-                client.chargeback_withdrawal(transaction.amount());
+                client.reverse_withdrawal_chargeback(
+                    transaction.currency(),
+                    transaction.disputed_amount(),
+                )?;
+            }
+        }
+
+        transaction.reverse_chargeback();
+        let locked_for_good =
+            client.flagged() && self.config.chargeback_limit_action == ChargebackLimitAction::Lock;
+        if self.settle_chargeback(client_id)
+            && self.config.unlock_on_chargeback_reversal
+            && !locked_for_good
+        {
+            if let Some(client) = self.store.clients_mut().get_mut(&client_id) {
+                client.unlock();
+                self.unlocked_since_last_check = true;
             }
+        }
+
+        Ok(())
+    }
+
+    // Called when one of the client's chargebacks no longer stands. Returns
+    // whether that was the last of them.
+    fn settle_chargeback(&mut self, client_id: ClientID) -> bool {
+        let outstanding = self.outstanding_chargebacks.entry(client_id).or_default();
+        *outstanding = outstanding.saturating_sub(1);
+        *outstanding == 0
+    }
+
+    // Either client can ask for a transfer to be reversed, but both sides are
+    // reversed together so that the money goes back where it came from.
+    // Reversals aren't dispute steps, so the locked account policy doesn't
+    // apply and operators can correct a locked account too.
+    fn reverse(
+        &mut self,
+        transaction_id: TransactionID,
+        client_id: ClientID,
+    ) -> Result<(), ProcessingError> {
+        let allow_redispute = self.config.allow_redispute;
+        // a deposit that never settled takes what's pending with it
+        let pending = self.pending_deposits.get(&transaction_id).copied();
+        let (debit, clients_by_id) = self.store.transaction_and_clients_mut(transaction_id);
+        let debit = debit.ok_or_else(|| {
+            transaction_not_found(
+                &self.conversions_by_id,
+                &self.adjustments_by_id,
+                &self.discarded_withdrawal_ids,
+                transaction_id,
+            )
+        })?;
+        let sides = iter::once(debit)
+            .chain(self.transfer_credits_by_id.get_mut(&transaction_id))
+            .collect::<Vec<_>>();
+
+        if sides.iter().all(|side| side.client_id() != client_id) {
+            Self::check_client_owns_transaction(client_id, sides[0])?;
+        }
+        // both sides are checked before either is reversed, so that we never
+        // reverse only one of them
+        let reversed_amount = |side: &Transaction| match side.kind() {
+            TransactionKind::Deposit => -side.amount(),
+            TransactionKind::Withdrawal => side.amount(),
         };
+        for side in &sides {
+            side.validate_dispute_status_transition(DisputeStatus::Reversed, allow_redispute)?;
+            clients_by_id
+                .get(&side.client_id())
+                .ok_or(ProcessingError::ClientNotFound {
+                    client_id: side.client_id(),
+                })?
+                .check_can_adjust(side.currency(), reversed_amount(side))?;
+        }
 
-        transaction.set_dispute_status(DisputeStatus::ChargedBack);
+        for side in sides {
+            if let Some(client) = clients_by_id.get_mut(&side.client_id()) {
+                match (side.kind(), pending) {
+                    (TransactionKind::Deposit, Some(pending)) => {
+                        client.reverse_pending_deposit(side.currency(), side.amount(), pending)?;
+                    }
+                    (TransactionKind::Deposit, None) => {
+                        client.reverse_deposit(side.currency(), side.amount())?;
+                    }
+                    (TransactionKind::Withdrawal, _) => {
+                        client.reverse_withdrawal(side.currency(), side.amount())?;
+                    }
+                }
+            }
+            side.set_dispute_status(DisputeStatus::Reversed);
+        }
+        self.pending_deposits.remove(&transaction_id);
 
         Ok(())
     }
@@ -148,59 +1634,748 @@ impl Processor {
     fn check_client_owns_transaction(
         client_id: ClientID,
         transaction: &Transaction,
-    ) -> Result<(), String> {
+    ) -> Result<(), ProcessingError> {
         if client_id != transaction.client_id() {
-            return Err(format!(
-                "Client id {} does not match transaction client id {}.",
+            return Err(ProcessingError::ClientMismatch {
                 client_id,
-                transaction.client_id()
-            ));
+                transaction_client_id: transaction.client_id(),
+            });
         }
 
         Ok(())
     }
 
+    // Whether the event is a deposit or withdrawal that's already been
+    // processed, which `DuplicateTransactionPolicy::IgnoreIdentical` lets
+    // through as a no-op. The sending side of a transfer is stored like a
+    // withdrawal, but it isn't one, and a withdrawal that wasn't kept can't be
+    // compared, so neither counts.
+    fn is_identical_resubmission(&mut self, event: &Event) -> bool {
+        if self.config.duplicate_transactions != DuplicateTransactionPolicy::IgnoreIdentical {
+            return false;
+        }
+        let Event::Transaction {
+            ref kind,
+            transaction_id,
+            client_id,
+            currency,
+            amount,
+            ..
+        } = *event
+        else {
+            return false;
+        };
+        if self.transfer_credits_by_id.contains_key(&transaction_id) {
+            return false;
+        }
+
+        self.store
+            .transaction_mut(transaction_id)
+            .is_some_and(|transaction| {
+                transaction.kind() == kind
+                    && transaction.client_id() == client_id
+                    && transaction.currency() == currency
+                    && transaction.amount() == amount
+            })
+    }
+
     fn check_transaction_does_not_exist(
         &self,
         transaction_id: TransactionID,
-    ) -> Result<(), String> {
-        if self.transactions_by_id.contains_key(&transaction_id) {
-            return Err(format!(
-                "Transaction already exists with id {}.",
-                transaction_id,
-            ));
+    ) -> Result<(), ProcessingError> {
+        if self.store.contains_transaction(transaction_id)
+            || self.conversions_by_id.contains_key(&transaction_id)
+            || self.adjustments_by_id.contains_key(&transaction_id)
+            || self.discarded_withdrawal_ids.contains(&transaction_id)
+            || self.fee_and_interest_ids.contains(&transaction_id)
+        {
+            return Err(ProcessingError::TransactionExists { id: transaction_id });
         }
 
         Ok(())
     }
 
     fn find_or_create_client(&mut self, client_id: ClientID) -> &mut Client {
-        self.clients_by_id
+        let credit_limits = &self.config.credit_limits;
+        self.store
+            .clients_mut()
             .entry(client_id)
-            .or_insert_with(Client::new)
+            .or_insert_with(|| {
+                Client::new().with_credit_limit(credit_limits.get(&client_id).copied())
+            })
     }
 
     fn create_transaction(&mut self, transaction_id: TransactionID, transaction: Transaction) {
-        self.transactions_by_id.insert(transaction_id, transaction);
+        self.store
+            .insert_transaction(transaction_id, transaction.with_timestamp(self.now));
     }
 
+    fn record_counterparty(&mut self, transaction_id: TransactionID, counterparty: Option<&str>) {
+        if let Some(counterparty) = counterparty {
+            self.counterparties_by_id
+                .insert(transaction_id, String::from(counterparty));
+        }
+    }
+
+    // Like `get_transaction_and_client`, for when we only need to look.
+    fn find_transaction(
+        &mut self,
+        transaction_id: TransactionID,
+        client_id: ClientID,
+    ) -> Option<&Transaction> {
+        match self.transfer_credits_by_id.get(&transaction_id) {
+            Some(credit) if credit.client_id() == client_id => Some(credit),
+            _ => self
+                .store
+                .transaction_mut(transaction_id)
+                .map(|transaction| &*transaction),
+        }
+    }
+
+    // The client ID is only used to pick which side of a transfer is meant;
+    // callers still need to check that the client owns the transaction.
     fn get_transaction_and_client(
         &mut self,
         transaction_id: TransactionID,
-    ) -> Result<(&mut Transaction, &mut Client), String> {
-        let transaction = self
-            .transactions_by_id
-            .get_mut(&transaction_id)
-            .ok_or(format!("Transaction {} not found.", transaction_id))?;
+        client_id: ClientID,
+    ) -> Result<(&mut Transaction, &mut Client), ProcessingError> {
+        let (transaction, clients_by_id) =
+            match self.transfer_credits_by_id.get_mut(&transaction_id) {
+                Some(credit) if credit.client_id() == client_id => {
+                    (Some(credit), self.store.clients_mut())
+                }
+                _ => self.store.transaction_and_clients_mut(transaction_id),
+            };
+        let transaction = transaction.ok_or_else(|| {
+            transaction_not_found(
+                &self.conversions_by_id,
+                &self.adjustments_by_id,
+                &self.discarded_withdrawal_ids,
+                transaction_id,
+            )
+        })?;
 
-        let client = self
-            .clients_by_id
-            .get_mut(&transaction.client_id())
-            .ok_or(format!(
-                "Client {} does not exist.",
-                transaction.client_id()
-            ))?;
+        let client = clients_by_id.get_mut(&transaction.client_id()).ok_or(
+            ProcessingError::ClientNotFound {
+                client_id: transaction.client_id(),
+            },
+        )?;
 
         Ok((transaction, client))
     }
 }
+
+// Conversions and adjustments share IDs with transactions, so an ID that isn't
+// a transaction may still be one of those, or a withdrawal that wasn't kept.
+fn transaction_not_found(
+    conversions_by_id: &HashMap<TransactionID, Conversion>,
+    adjustments_by_id: &HashMap<TransactionID, Adjustment>,
+    discarded_withdrawal_ids: &HashSet<TransactionID>,
+    transaction_id: TransactionID,
+) -> ProcessingError {
+    if conversions_by_id.contains_key(&transaction_id) {
+        ProcessingError::ConversionNotDisputable { id: transaction_id }
+    } else if adjustments_by_id.contains_key(&transaction_id) {
+        ProcessingError::AdjustmentNotDisputable { id: transaction_id }
+    } else if discarded_withdrawal_ids.contains(&transaction_id) {
+        ProcessingError::WithdrawalNotKept { id: transaction_id }
+    } else {
+        ProcessingError::TransactionNotFound { id: transaction_id }
+    }
+}
+
+fn absorb_by_id<K: Eq + Hash + Display, V>(
+    ids: &mut HashMap<K, V>,
+    other_ids: HashMap<K, V>,
+    what: &str,
+) -> io::Result<()> {
+    for (id, value) in other_ids {
+        match ids.entry(id) {
+            Entry::Occupied(entry) => return Err(clash(what, entry.key())),
+            Entry::Vacant(entry) => {
+                entry.insert(value);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn clash(what: &str, id: impl Display) -> io::Error {
+    io::Error::other(format!("More than one shard has {} {}.", what, id))
+}
+
+fn write_snapshot_part(writer: &mut impl Write, part: &impl Serialize) -> io::Result<()> {
+    bincode::serialize_into(writer, part).map_err(|e| snapshot_error(*e))
+}
+
+fn read_snapshot_part<T: DeserializeOwned>(reader: &mut impl Read) -> io::Result<T> {
+    bincode::deserialize_from(reader).map_err(|e| snapshot_error(*e))
+}
+
+// Anything other than an IO error means the snapshot itself is bad.
+fn snapshot_error(e: bincode::ErrorKind) -> io::Error {
+    match e {
+        bincode::ErrorKind::Io(e) => e,
+        e => io::Error::new(io::ErrorKind::InvalidData, e),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::system::AccountState;
+    use pretty_assertions::assert_eq;
+    use rust_decimal_macros::dec;
+    use std::{collections::BTreeMap, io::Seek, time::Duration};
+
+    #[test]
+    fn test_query_state() {
+        let mut processor = Processor::new(&EngineConfig::default());
+        let deposit = |client_id, transaction_id| Event::Transaction {
+            kind: TransactionKind::Deposit,
+            client_id,
+            transaction_id,
+            currency: Currency::default(),
+            amount: dec!(10),
+            counterparty: None,
+        };
+
+        processor
+            .process_event(deposit(1, 1), None, None, &mut ())
+            .expect("Expected no errors.");
+        assert_eq!(
+            Some(dec!(10)),
+            processor
+                .client(1)
+                .map(|client| client.balance(Currency::default()).total())
+        );
+        assert_eq!(None, processor.client(2));
+
+        processor
+            .process_event(deposit(2, 2), None, None, &mut ())
+            .expect("Expected no errors.");
+        processor
+            .process_event(
+                Event::DisputeStep {
+                    kind: DisputeStepKind::Dispute,
+                    client_id: 2,
+                    transaction_id: 2,
+                    amount: None,
+                },
+                None,
+                None,
+                &mut (),
+            )
+            .expect("Expected no errors.");
+
+        assert_eq!(
+            Some(DisputeStatus::Disputed),
+            processor.transaction(2).map(Transaction::dispute_status)
+        );
+        assert!(processor.transaction(3).is_none());
+
+        let mut held = processor
+            .clients()
+            .map(|(client_id, client)| (client_id, client.balance(Currency::default()).held()))
+            .collect::<Vec<_>>();
+        held.sort();
+        assert_eq!(vec![(1, dec!(0)), (2, dec!(10))], held);
+    }
+
+    #[test]
+    fn test_process_batch() {
+        let mut processor = Processor::new(&EngineConfig {
+            locked_account_disputes: LockedAccountPolicy::Queue,
+            ..EngineConfig::default()
+        });
+        let transaction = |kind, client_id, transaction_id, amount| Event::Transaction {
+            kind,
+            client_id,
+            transaction_id,
+            currency: Currency::default(),
+            amount,
+            counterparty: None,
+        };
+        let dispute_step = |kind, transaction_id| Event::DisputeStep {
+            kind,
+            client_id: 1,
+            transaction_id,
+            amount: None,
+        };
+        let account = |total, locked| AccountState {
+            balances: BTreeMap::from([(
+                Currency::default(),
+                *Client::create(dec!(0), total, locked).balance(Currency::default()),
+            )]),
+            locked,
+        };
+
+        let result = processor.process_batch(&[
+            transaction(TransactionKind::Deposit, 1, 1, dec!(10)),
+            transaction(TransactionKind::Deposit, 1, 2, dec!(5)),
+            transaction(TransactionKind::Deposit, 2, 3, dec!(5)),
+            transaction(TransactionKind::Withdrawal, 2, 4, dec!(50)),
+        ]);
+        assert_eq!(
+            BatchResult {
+                accepted: 3,
+                rejected: 1,
+                queued: 0,
+                deltas: vec![
+                    StateDelta {
+                        client_id: 1,
+                        before: AccountState::default(),
+                        after: account(dec!(15), false),
+                    },
+                    StateDelta {
+                        client_id: 2,
+                        before: AccountState::default(),
+                        after: account(dec!(5), false),
+                    },
+                ],
+            },
+            result
+        );
+
+        // the queued dispute isn't counted until it's processed, and client 2
+        // wasn't touched so they're left out
+        assert_eq!(
+            BatchResult {
+                accepted: 2,
+                rejected: 0,
+                queued: 1,
+                deltas: vec![StateDelta {
+                    client_id: 1,
+                    before: account(dec!(15), false),
+                    after: account(dec!(5), true),
+                }],
+            },
+            processor.process_batch(&[
+                dispute_step(DisputeStepKind::Dispute, 1),
+                dispute_step(DisputeStepKind::Chargeback, 1),
+                dispute_step(DisputeStepKind::Dispute, 2),
+            ])
+        );
+        assert_eq!(BatchResult::default(), processor.process_batch(&[]));
+    }
+
+    #[test]
+    fn test_process_event_with_deltas() {
+        let mut processor = Processor::new(&EngineConfig::default());
+        let account = |held, total, locked| AccountState {
+            balances: BTreeMap::from([(
+                Currency::default(),
+                *Client::create(held, total, locked).balance(Currency::default()),
+            )]),
+            locked,
+        };
+        let mut process = |event| processor.process_event_with_deltas(event, None, None, &mut ());
+
+        assert_eq!(
+            Ok(vec![StateDelta {
+                client_id: 1,
+                before: AccountState::default(),
+                after: account(dec!(0), dec!(10), false),
+            }]),
+            process(Event::Transaction {
+                kind: TransactionKind::Deposit,
+                client_id: 1,
+                transaction_id: 1,
+                currency: Currency::default(),
+                amount: dec!(10),
+                counterparty: None,
+            })
+        );
+        assert_eq!(
+            Ok(vec![
+                StateDelta {
+                    client_id: 1,
+                    before: account(dec!(0), dec!(10), false),
+                    after: account(dec!(0), dec!(6), false),
+                },
+                StateDelta {
+                    client_id: 2,
+                    before: AccountState::default(),
+                    after: account(dec!(0), dec!(4), false),
+                },
+            ]),
+            process(Event::Transfer {
+                from_client_id: 1,
+                to_client_id: 2,
+                transaction_id: 2,
+                currency: Currency::default(),
+                amount: dec!(4),
+            })
+        );
+        assert_eq!(
+            Ok(vec![StateDelta {
+                client_id: 1,
+                before: account(dec!(0), dec!(6), false),
+                after: account(dec!(10), dec!(6), false),
+            }]),
+            process(Event::DisputeStep {
+                kind: DisputeStepKind::Dispute,
+                client_id: 1,
+                transaction_id: 1,
+                amount: None,
+            })
+        );
+        assert_eq!(
+            Ok(vec![StateDelta {
+                client_id: 1,
+                before: account(dec!(10), dec!(6), false),
+                after: account(dec!(0), dec!(-4), true),
+            }]),
+            process(Event::DisputeStep {
+                kind: DisputeStepKind::Chargeback,
+                client_id: 1,
+                transaction_id: 1,
+                amount: None,
+            })
+        );
+        assert_eq!(
+            Err(ProcessingError::InsufficientFunds),
+            process(Event::Transaction {
+                kind: TransactionKind::Withdrawal,
+                client_id: 2,
+                transaction_id: 3,
+                currency: Currency::default(),
+                amount: dec!(5),
+                counterparty: None,
+            })
+        );
+    }
+
+    #[test]
+    fn test_snapshot_and_restore() {
+        let config = EngineConfig {
+            locked_account_disputes: LockedAccountPolicy::Queue,
+            ..EngineConfig::default()
+        };
+        let mut processor = Processor::new(&config);
+        let events = vec![
+            Event::Transaction {
+                kind: TransactionKind::Deposit,
+                client_id: 1,
+                transaction_id: 1,
+                currency: Currency::default(),
+                amount: dec!(10),
+                counterparty: None,
+            },
+            Event::Transaction {
+                kind: TransactionKind::Deposit,
+                client_id: 1,
+                transaction_id: 2,
+                currency: Currency::default(),
+                amount: dec!(5),
+                counterparty: None,
+            },
+            Event::DisputeStep {
+                kind: DisputeStepKind::Dispute,
+                client_id: 1,
+                transaction_id: 1,
+                amount: None,
+            },
+            Event::DisputeStep {
+                kind: DisputeStepKind::Chargeback,
+                client_id: 1,
+                transaction_id: 1,
+                amount: None,
+            },
+            // queued, since the account is now locked
+            Event::DisputeStep {
+                kind: DisputeStepKind::Dispute,
+                client_id: 1,
+                transaction_id: 2,
+                amount: None,
+            },
+        ];
+        for (timestamp, event) in events.into_iter().enumerate() {
+            processor
+                .process_event(event, Some(timestamp as Timestamp), None, &mut ())
+                .expect("Expected no errors.");
+        }
+
+        let mut snapshot = Vec::new();
+        processor
+            .snapshot(&mut snapshot)
+            .expect("Failed to write snapshot");
+        let mut restored =
+            Processor::restore(&config, snapshot.as_slice()).expect("Failed to restore snapshot");
+
+        assert_eq!(processor.clients_by_id(), restored.clients_by_id());
+        assert_eq!(
+            Some(DisputeStatus::ChargedBack),
+            restored.transaction(1).map(Transaction::dispute_status)
+        );
+        assert_eq!(
+            Some(Some(0)),
+            restored.transaction(1).map(Transaction::timestamp)
+        );
+        assert_eq!(processor.now, restored.now);
+        assert_eq!(
+            processor.outstanding_chargebacks,
+            restored.outstanding_chargebacks
+        );
+        let queued = processor.take_queued_events();
+        assert_eq!(1, queued.len());
+        assert_eq!(queued, restored.take_queued_events());
+
+        // a transaction ID can't be reused after a restore either
+        assert!(restored
+            .process_event(
+                Event::Transaction {
+                    kind: TransactionKind::Deposit,
+                    client_id: 2,
+                    transaction_id: 2,
+                    currency: Currency::default(),
+                    amount: dec!(1),
+                    counterparty: None,
+                },
+                None,
+                None,
+                &mut (),
+            )
+            .is_err());
+
+        // anything that isn't a snapshot of the current version is turned away
+        snapshot[SNAPSHOT_MAGIC.len()] += 1;
+        let error = Processor::restore(&config, snapshot.as_slice())
+            .err()
+            .expect("Expected an error");
+        assert_eq!(io::ErrorKind::InvalidData, error.kind());
+        assert!(Processor::restore(&config, &b"id,amount"[..]).is_err());
+    }
+
+    #[test]
+    fn test_replay_journal() {
+        let config = EngineConfig {
+            locked_account_disputes: LockedAccountPolicy::Queue,
+            unlock_on_chargeback_reversal: true,
+            ..EngineConfig::default()
+        };
+        let transaction = |kind, transaction_id, amount| Event::Transaction {
+            kind,
+            client_id: 1,
+            transaction_id,
+            currency: Currency::default(),
+            amount,
+            counterparty: None,
+        };
+        let dispute_step = |kind, transaction_id| Event::DisputeStep {
+            kind,
+            client_id: 1,
+            transaction_id,
+            amount: None,
+        };
+        let process = |processor: &mut Processor, events: Vec<Event>| {
+            for (timestamp, event) in events.into_iter().enumerate() {
+                // rejections aren't journaled, and so aren't replayed
+                let _ = processor.process_event(event, Some(timestamp as Timestamp), None, &mut ());
+                for queued in processor.take_unlocked_events() {
+                    let _ = processor.process_event(queued.event, queued.timestamp, None, &mut ());
+                }
+            }
+        };
+
+        let mut journal_file = tempfile::tempfile().expect("Failed to create temp file");
+        let mut processor = Processor::new(&config);
+        processor.set_journal(
+            Journal::new(journal_file.try_clone().expect("Failed to clone file"))
+                .expect("Failed to start journal"),
+        );
+        process(
+            &mut processor,
+            vec![
+                transaction(TransactionKind::Deposit, 1, dec!(10)),
+                transaction(TransactionKind::Deposit, 2, dec!(5)),
+                transaction(TransactionKind::Withdrawal, 3, dec!(50)),
+                dispute_step(DisputeStepKind::Dispute, 1),
+                dispute_step(DisputeStepKind::Chargeback, 1),
+                // queued until the chargeback is reversed
+                dispute_step(DisputeStepKind::Dispute, 2),
+            ],
+        );
+        let mut snapshot = Vec::new();
+        processor
+            .snapshot(&mut snapshot)
+            .expect("Failed to write snapshot");
+        process(
+            &mut processor,
+            vec![
+                Event::ChargebackReversal {
+                    transaction_id: 1,
+                    client_id: 1,
+                },
+                transaction(TransactionKind::Deposit, 4, dec!(1)),
+            ],
+        );
+        processor.flush_journal().expect("Failed to flush journal");
+
+        let mut replay = |mut replayed: Processor| {
+            journal_file
+                .seek(io::SeekFrom::Start(0))
+                .expect("Failed to seek");
+            let count = replayed
+                .replay_journal(&journal_file)
+                .expect("Failed to replay journal");
+
+            assert_eq!(processor.clients_by_id(), replayed.clients_by_id());
+            assert_eq!(
+                processor.state_digest().expect("Expected a digest"),
+                replayed.state_digest().expect("Expected a digest")
+            );
+            for transaction_id in [1, 2] {
+                let summary = |processor: &mut Processor| {
+                    processor.transaction(transaction_id).map(|transaction| {
+                        (transaction.dispute_status(), transaction.dispute_history())
+                    })
+                };
+                assert_eq!(summary(&mut processor), summary(&mut replayed));
+            }
+            assert!(replayed.take_queued_events().is_empty());
+            count
+        };
+
+        // from scratch, every accepted event is replayed
+        assert_eq!(7, replay(Processor::new(&config)));
+        // and from the snapshot, only those after it, including the dispute
+        // that was queued when it was taken
+        assert_eq!(
+            3,
+            replay(Processor::restore(&config, snapshot.as_slice()).expect("Failed to restore"))
+        );
+    }
+
+    #[test]
+    fn test_replay_lapsed_disputes() {
+        let config = EngineConfig {
+            dispute_expiry: Some(Duration::from_secs(10)),
+            ..EngineConfig::default()
+        };
+        let deposit = |transaction_id| Event::Transaction {
+            kind: TransactionKind::Deposit,
+            client_id: 1,
+            transaction_id,
+            currency: Currency::default(),
+            amount: dec!(10),
+            counterparty: None,
+        };
+        let dispute = |transaction_id| Event::DisputeStep {
+            kind: DisputeStepKind::Dispute,
+            client_id: 1,
+            transaction_id,
+            amount: None,
+        };
+
+        let mut journal_file = tempfile::tempfile().expect("Failed to create temp file");
+        let mut processor = Processor::new(&config);
+        processor.set_journal(
+            Journal::new(journal_file.try_clone().expect("Failed to clone file"))
+                .expect("Failed to start journal"),
+        );
+        for (event, timestamp) in [
+            (deposit(1), 0),
+            (dispute(1), 1),
+            (deposit(2), 5),
+            // the dispute lapses just before this
+            (deposit(3), 12),
+            (dispute(3), 13),
+        ] {
+            processor
+                .process_event(event, Some(timestamp), None, &mut ())
+                .expect("Expected no errors.");
+        }
+        processor.flush_journal().expect("Failed to flush journal");
+        assert_eq!(
+            Some(DisputeStatus::Undisputed),
+            processor.transaction(1).map(Transaction::dispute_status)
+        );
+
+        // the lapse isn't in the journal, but it happens again on replay
+        journal_file
+            .seek(io::SeekFrom::Start(0))
+            .expect("Failed to seek");
+        let mut replayed = Processor::new(&config);
+        assert_eq!(
+            5,
+            replayed
+                .replay_journal(&journal_file)
+                .expect("Failed to replay journal")
+        );
+        assert_eq!(
+            processor.state_digest().expect("Expected a digest"),
+            replayed.state_digest().expect("Expected a digest")
+        );
+    }
+
+    #[test]
+    fn test_replay_journal_until() {
+        let config = EngineConfig::default();
+        let mut journal_file = tempfile::tempfile().expect("Failed to create temp file");
+        let mut processor = Processor::new(&config);
+        processor.set_journal(
+            Journal::new(journal_file.try_clone().expect("Failed to clone file"))
+                .expect("Failed to start journal"),
+        );
+        for (transaction_id, timestamp) in [(1, 10), (2, 20), (3, 30)] {
+            processor
+                .process_event(
+                    Event::Transaction {
+                        kind: TransactionKind::Deposit,
+                        client_id: 1,
+                        transaction_id,
+                        currency: Currency::default(),
+                        amount: dec!(10),
+                        counterparty: None,
+                    },
+                    Some(timestamp),
+                    None,
+                    &mut (),
+                )
+                .expect("Expected no errors.");
+        }
+        processor.flush_journal().expect("Failed to flush journal");
+        let mut snapshot = Vec::new();
+        processor
+            .snapshot(&mut snapshot)
+            .expect("Failed to write snapshot");
+
+        let mut replay_until = |mut replayed: Processor, until| {
+            journal_file
+                .seek(io::SeekFrom::Start(0))
+                .expect("Failed to seek");
+            replayed
+                .replay_journal_until(&journal_file, until)
+                .map(|_| {
+                    replayed
+                        .client(1)
+                        .map(|client| client.balance(Currency::default()).total())
+                })
+        };
+
+        assert_eq!(
+            Some(dec!(20)),
+            replay_until(Processor::new(&config), ReplayPoint::AfterEvent(2))
+                .expect("Failed to replay journal")
+        );
+        assert_eq!(
+            Some(dec!(20)),
+            replay_until(Processor::new(&config), ReplayPoint::AtTime(25))
+                .expect("Failed to replay journal")
+        );
+        assert_eq!(
+            None,
+            replay_until(Processor::new(&config), ReplayPoint::AtTime(5))
+                .expect("Failed to replay journal")
+        );
+        // there's no going back from a snapshot taken later on
+        let restored = Processor::restore(&config, snapshot.as_slice()).expect("Failed to restore");
+        assert_eq!(
+            io::ErrorKind::InvalidInput,
+            replay_until(restored, ReplayPoint::AfterEvent(2))
+                .expect_err("Expected an error")
+                .kind()
+        );
+    }
+}
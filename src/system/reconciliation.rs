@@ -0,0 +1,392 @@
+use super::{EngineConfig, FinalState, WithdrawalDisputePolicy};
+use crate::model::{Amount, Currency, DisputeStatus, TransactionKind};
+
+use std::collections::BTreeMap;
+
+// A conservation-of-money check: the money that came in minus the money that
+// went out should be exactly what the clients are holding between them. The
+// expected side is worked out from the stored transactions and the actual
+// side from the client balances, which are maintained separately, so a bug
+// that double counts an event in one shows up as a discrepancy. Money in
+// different currencies can't be added up, so there's one of these for each,
+// with conversions counting as money coming into one and going out of another.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Reconciliation {
+    pub currency: Currency,
+    pub deposits: Amount,
+    pub withdrawals: Amount,
+    // The net amount reversed by chargebacks, which can be part of a
+    // transaction rather than all of it. Charging back a deposit takes
+    // money out but charging back a withdrawal puts it back, so the latter
+    // counts negatively. Withdrawals that are credited back as soon as they're
+    // disputed count as soon as they're disputed.
+    pub charged_back: Amount,
+    // The net amount reversed by operators, which counts the same way as
+    // chargebacks but is kept apart from them.
+    pub reversed: Amount,
+    // Fees aren't stored as transactions, so this comes from what the clients
+    // have been charged instead.
+    pub fees: Amount,
+    // Likewise, interest comes from what the clients have been paid.
+    pub interest: Amount,
+    // The net amount of the manual adjustments, which is negative if they took
+    // more out than they put in.
+    pub adjusted: Amount,
+    // The net amount converted into this currency from others, which is
+    // negative if more was converted out of it.
+    pub converted: Amount,
+    pub actual_total: Amount,
+}
+
+impl Reconciliation {
+    fn new(currency: Currency) -> Self {
+        Self {
+            currency,
+            deposits: Amount::ZERO,
+            withdrawals: Amount::ZERO,
+            charged_back: Amount::ZERO,
+            reversed: Amount::ZERO,
+            fees: Amount::ZERO,
+            interest: Amount::ZERO,
+            adjusted: Amount::ZERO,
+            converted: Amount::ZERO,
+            actual_total: Amount::ZERO,
+        }
+    }
+
+    pub fn expected_total(&self) -> Amount {
+        self.deposits - self.withdrawals - self.charged_back - self.reversed - self.fees
+            + self.interest
+            + self.adjusted
+            + self.converted
+    }
+
+    // How far the clients' totals are from what the transactions say they
+    // should be. Anything other than zero is a bug.
+    pub fn discrepancy(&self) -> Amount {
+        self.actual_total - self.expected_total()
+    }
+}
+
+fn reconciliation(
+    reconciliations: &mut BTreeMap<Currency, Reconciliation>,
+    currency: Currency,
+) -> &mut Reconciliation {
+    reconciliations
+        .entry(currency)
+        .or_insert_with(|| Reconciliation::new(currency))
+}
+
+// Reconciles each currency separately, in currency order. There's always at
+// least one, even if there was nothing to reconcile.
+pub fn reconcile(final_state: &FinalState, config: &EngineConfig) -> Vec<Reconciliation> {
+    let credits_disputed_withdrawals =
+        config.withdrawal_disputes == WithdrawalDisputePolicy::CreditHeld;
+    let mut reconciliations = BTreeMap::new();
+
+    for client in final_state.clients_by_id.values() {
+        for (currency, balance) in client.balances() {
+            let reconciliation = reconciliation(&mut reconciliations, currency);
+            reconciliation.fees += balance.fees();
+            reconciliation.interest += balance.interest();
+            reconciliation.actual_total += balance.total();
+        }
+    }
+
+    // failed deposits and withdrawals are never stored, so every transaction
+    // here actually moved money. Transfers count as a withdrawal from one
+    // client and a deposit to the other, which cancel out.
+    for (_, transaction) in final_state.all_transactions() {
+        let reconciliation = reconciliation(&mut reconciliations, transaction.currency());
+        let reversed = transaction.dispute_status() == DisputeStatus::Reversed;
+        match transaction.kind() {
+            TransactionKind::Deposit => {
+                reconciliation.deposits += transaction.amount();
+                reconciliation.charged_back += transaction.charged_back_amount();
+                if reversed {
+                    reconciliation.reversed += transaction.amount();
+                }
+            }
+            TransactionKind::Withdrawal => {
+                reconciliation.withdrawals += transaction.amount();
+                reconciliation.charged_back -= transaction.charged_back_amount();
+                if credits_disputed_withdrawals
+                    && transaction.dispute_status() == DisputeStatus::Disputed
+                {
+                    reconciliation.charged_back -= transaction.disputed_amount();
+                }
+                if reversed {
+                    reconciliation.reversed -= transaction.amount();
+                }
+            }
+        }
+    }
+
+    for (currency, amount) in &final_state.discarded_withdrawals {
+        reconciliation(&mut reconciliations, *currency).withdrawals += amount;
+    }
+
+    for adjustment in final_state.adjustments_by_id.values() {
+        reconciliation(&mut reconciliations, adjustment.currency()).adjusted += adjustment.amount();
+    }
+
+    for conversion in final_state.conversions_by_id.values() {
+        reconciliation(&mut reconciliations, conversion.from_currency()).converted -=
+            conversion.amount();
+        reconciliation(&mut reconciliations, conversion.to_currency()).converted +=
+            conversion.converted_amount();
+    }
+
+    if reconciliations.is_empty() {
+        reconciliation(&mut reconciliations, Currency::default());
+    }
+    reconciliations.into_values().collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        model::{Client, DisputeStepKind, Event, Transaction},
+        system::{process_events, process_events_with_snapshots},
+    };
+    use pretty_assertions::assert_eq;
+    use rust_decimal_macros::dec;
+    use std::{collections::HashMap, error::Error, io};
+
+    #[test]
+    fn test_reconcile() {
+        let deposit = |transaction_id, amount| {
+            Ok(Event::Transaction {
+                kind: TransactionKind::Deposit,
+                client_id: 1,
+                transaction_id,
+                currency: Currency::default(),
+                amount,
+                counterparty: None,
+            })
+        };
+        let withdrawal = |transaction_id, amount| {
+            Ok(Event::Transaction {
+                kind: TransactionKind::Withdrawal,
+                client_id: 1,
+                transaction_id,
+                currency: Currency::default(),
+                amount,
+                counterparty: None,
+            })
+        };
+        let dispute_step = |kind, transaction_id| {
+            Ok(Event::DisputeStep {
+                kind,
+                client_id: 1,
+                transaction_id,
+                amount: None,
+            })
+        };
+        let input_events: Vec<Result<Event, Box<dyn Error>>> = vec![
+            deposit(1, dec!(100)),
+            deposit(2, dec!(20)),
+            withdrawal(3, dec!(30)),
+            // rejected, so it doesn't count
+            withdrawal(4, dec!(1000)),
+            Ok(Event::Fee {
+                client_id: 1,
+                transaction_id: 5,
+                currency: Currency::default(),
+                amount: dec!(2),
+            }),
+            // 1% of the 88 available
+            Ok(Event::Interest {
+                client_id: 1,
+                transaction_id: 6,
+                currency: Currency::default(),
+                rate: dec!(0.01),
+            }),
+            deposit(7, dec!(10)),
+            Ok(Event::Reversal {
+                client_id: 1,
+                transaction_id: 7,
+            }),
+            dispute_step(DisputeStepKind::Dispute, 2),
+            dispute_step(DisputeStepKind::Chargeback, 2),
+            dispute_step(DisputeStepKind::Dispute, 3),
+            dispute_step(DisputeStepKind::Chargeback, 3),
+            Ok(Event::Adjustment {
+                client_id: 1,
+                transaction_id: 8,
+                currency: Currency::default(),
+                amount: dec!(-1.5),
+                reason: String::from("correction"),
+            }),
+        ];
+
+        let final_state = process_events(input_events.into_iter(), &mut io::sink())
+            .expect("Unexpectedly failed to process events.");
+        let reconciliations = reconcile(&final_state, &EngineConfig::default());
+
+        assert_eq!(
+            vec![Reconciliation {
+                currency: Currency::default(),
+                deposits: dec!(130),
+                withdrawals: dec!(30),
+                charged_back: dec!(-10),
+                reversed: dec!(10),
+                fees: dec!(2),
+                interest: dec!(0.88),
+                adjusted: dec!(-1.5),
+                converted: dec!(0),
+                actual_total: dec!(97.38),
+            }],
+            reconciliations
+        );
+        assert_eq!(dec!(97.38), reconciliations[0].expected_total());
+        assert_eq!(dec!(0), reconciliations[0].discrepancy());
+    }
+
+    #[test]
+    fn test_reconcile_credited_withdrawal_dispute() {
+        let input_events: Vec<Result<Event, Box<dyn Error>>> = vec![
+            Ok(Event::Transaction {
+                kind: TransactionKind::Deposit,
+                client_id: 1,
+                transaction_id: 1,
+                currency: Currency::default(),
+                amount: dec!(100),
+                counterparty: None,
+            }),
+            Ok(Event::Transaction {
+                kind: TransactionKind::Withdrawal,
+                client_id: 1,
+                transaction_id: 2,
+                currency: Currency::default(),
+                amount: dec!(30),
+                counterparty: None,
+            }),
+            Ok(Event::DisputeStep {
+                kind: DisputeStepKind::Dispute,
+                client_id: 1,
+                transaction_id: 2,
+                amount: None,
+            }),
+        ];
+        let config = EngineConfig {
+            withdrawal_disputes: WithdrawalDisputePolicy::CreditHeld,
+            ..EngineConfig::default()
+        };
+
+        let final_state = process_events_with_snapshots(
+            input_events.into_iter(),
+            &mut io::sink(),
+            &config,
+            None,
+            |_, _| Ok(()),
+        )
+        .expect("Unexpectedly failed to process events.");
+
+        assert_eq!(dec!(0), reconcile(&final_state, &config)[0].discrepancy());
+    }
+
+    #[test]
+    fn test_reconcile_conversion() {
+        let euros = "EUR".parse().expect("Expected a valid currency.");
+        let input_events: Vec<Result<Event, Box<dyn Error>>> = vec![
+            Ok(Event::Transaction {
+                kind: TransactionKind::Deposit,
+                client_id: 1,
+                transaction_id: 1,
+                currency: Currency::default(),
+                amount: dec!(100),
+                counterparty: None,
+            }),
+            Ok(Event::Conversion {
+                transaction_id: 2,
+                client_id: 1,
+                from_currency: Currency::default(),
+                to_currency: euros,
+                amount: dec!(40),
+                rate: dec!(0.9),
+            }),
+        ];
+
+        let final_state = process_events(input_events.into_iter(), &mut io::sink())
+            .expect("Unexpectedly failed to process events.");
+        let converted = reconcile(&final_state, &EngineConfig::default())
+            .iter()
+            .map(|reconciliation| {
+                (
+                    reconciliation.currency,
+                    reconciliation.converted,
+                    reconciliation.discrepancy(),
+                )
+            })
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            vec![
+                (Currency::default(), dec!(-40), dec!(0)),
+                (euros, dec!(36), dec!(0)),
+            ],
+            converted
+        );
+    }
+
+    #[test]
+    fn test_reconcile_per_currency() {
+        let euros = "EUR".parse().expect("Expected a valid currency.");
+        let final_state = FinalState {
+            clients_by_id: HashMap::from([(
+                1,
+                Client::create(dec!(0), dec!(10), false).with_balance(euros, dec!(0), dec!(4)),
+            )]),
+            transactions_by_id: HashMap::from([
+                (
+                    1,
+                    Transaction::new(1, Currency::default(), dec!(10), TransactionKind::Deposit),
+                ),
+                (
+                    2,
+                    Transaction::new(1, euros, dec!(5), TransactionKind::Deposit),
+                ),
+            ]),
+            transfer_credits_by_id: HashMap::new(),
+            conversions_by_id: HashMap::new(),
+            adjustments_by_id: HashMap::new(),
+            discarded_withdrawals: HashMap::new(),
+            event_counts: Default::default(),
+            counterparties_by_id: HashMap::new(),
+        };
+
+        let discrepancies = reconcile(&final_state, &EngineConfig::default())
+            .iter()
+            .map(|reconciliation| (reconciliation.currency, reconciliation.discrepancy()))
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            vec![(Currency::default(), dec!(0)), (euros, dec!(-1))],
+            discrepancies
+        );
+    }
+
+    #[test]
+    fn test_reconcile_discrepancy() {
+        let final_state = FinalState {
+            clients_by_id: HashMap::from([(1, Client::create(dec!(0), dec!(15), false))]),
+            transactions_by_id: HashMap::from([(
+                1,
+                Transaction::new(1, Currency::default(), dec!(10), TransactionKind::Deposit),
+            )]),
+            transfer_credits_by_id: HashMap::new(),
+            conversions_by_id: HashMap::new(),
+            adjustments_by_id: HashMap::new(),
+            discarded_withdrawals: HashMap::new(),
+            event_counts: Default::default(),
+            counterparties_by_id: HashMap::new(),
+        };
+
+        assert_eq!(
+            dec!(5),
+            reconcile(&final_state, &EngineConfig::default())[0].discrepancy()
+        );
+    }
+}
@@ -0,0 +1,274 @@
+use super::StateStore;
+use crate::model::{Client, ClientID, Transaction, TransactionID};
+
+use redis::{Commands, ConnectionLike};
+use std::{
+    collections::{HashMap, HashSet},
+    io,
+};
+
+// A store that keeps its clients and transactions in Redis as well as in
+// memory, so that they outlive the process: whichever instance opens the store
+// next (say, the same serve restarted on another host) carries on from them.
+// Everything's loaded when the store's opened, and from then on each change is
+// written through as it's made, a client and the transaction it went with
+// together in one `MULTI`, so Redis is never more than the event in progress
+// behind.
+//
+// The clients are a hash at `<prefix>:clients`, of client IDs to the client
+// as bincode (like in a snapshot), and the transactions a hash at
+// `<prefix>:transactions`, of transaction IDs to `Transaction::to_bytes`.
+//
+// It's persistence for a single writer, not shared state. Each instance works
+// from the copy it loaded and writes over whatever's there, so two taking
+// events under the same prefix at once would each overwrite the other's
+// clients with their own. It's also only the clients and transactions, not the
+// rest of what the processor keeps track of (what's queued behind a lock, when
+// disputes were opened, which transfers and conversions can still be
+// reversed).
+pub struct RedisStore<C = redis::Connection> {
+    connection: C,
+    clients_key: String,
+    transactions_key: String,
+    clients_by_id: HashMap<ClientID, Client>,
+    transactions_by_id: HashMap<TransactionID, Transaction>,
+    // the transactions that have been inserted or handed out to change since
+    // the last write, which go with the next one
+    dirty_transactions: HashSet<TransactionID>,
+    error: Option<io::Error>,
+}
+
+impl RedisStore {
+    // Connects to the Redis at `url` (e.g. `redis://127.0.0.1/`), with the
+    // keys starting with `prefix`.
+    pub fn open(url: &str, prefix: &str) -> io::Result<Self> {
+        let connection = redis::Client::open(url)
+            .and_then(|client| client.get_connection())
+            .map_err(redis_error)?;
+        Self::with_connection(connection, prefix)
+    }
+}
+
+impl<C: ConnectionLike> RedisStore<C> {
+    pub fn with_connection(mut connection: C, prefix: &str) -> io::Result<Self> {
+        let clients_key = format!("{}:clients", prefix);
+        let transactions_key = format!("{}:transactions", prefix);
+
+        let encoded_clients: HashMap<ClientID, Vec<u8>> =
+            connection.hgetall(&clients_key).map_err(redis_error)?;
+        let clients_by_id = encoded_clients
+            .into_iter()
+            .map(|(client_id, bytes)| {
+                let client = bincode::deserialize(&bytes).map_err(|e| corrupt(e, "client"))?;
+                Ok((client_id, client))
+            })
+            .collect::<io::Result<_>>()?;
+        let encoded_transactions: HashMap<TransactionID, Vec<u8>> =
+            connection.hgetall(&transactions_key).map_err(redis_error)?;
+        let transactions_by_id = encoded_transactions
+            .into_iter()
+            .map(|(transaction_id, bytes)| {
+                let transaction = bytes
+                    .try_into()
+                    .ok()
+                    .and_then(|bytes| Transaction::from_bytes(&bytes))
+                    .ok_or_else(|| corrupt("Wrong length or bad field.", "transaction"))?;
+                Ok((transaction_id, transaction))
+            })
+            .collect::<io::Result<_>>()?;
+
+        Ok(Self {
+            connection,
+            clients_key,
+            transactions_key,
+            clients_by_id,
+            transactions_by_id,
+            dirty_transactions: HashSet::new(),
+            error: None,
+        })
+    }
+
+    fn write(&mut self, client_ids: &[ClientID]) -> io::Result<()> {
+        let mut pipe = redis::pipe();
+        pipe.atomic();
+        for client_id in client_ids {
+            if let Some(client) = self.clients_by_id.get(client_id) {
+                let bytes = bincode::serialize(client).map_err(io::Error::other)?;
+                pipe.hset(&self.clients_key, client_id, bytes).ignore();
+            }
+        }
+        for transaction_id in self.dirty_transactions.drain() {
+            if let Some(transaction) = self.transactions_by_id.get(&transaction_id) {
+                pipe.hset(
+                    &self.transactions_key,
+                    transaction_id,
+                    transaction.to_bytes().as_slice(),
+                )
+                .ignore();
+            }
+        }
+        pipe.query::<()>(&mut self.connection).map_err(redis_error)
+    }
+}
+
+impl<C: ConnectionLike> StateStore for RedisStore<C> {
+    fn clients(&self) -> &HashMap<ClientID, Client> {
+        &self.clients_by_id
+    }
+
+    fn clients_mut(&mut self) -> &mut HashMap<ClientID, Client> {
+        &mut self.clients_by_id
+    }
+
+    fn contains_transaction(&self, transaction_id: TransactionID) -> bool {
+        self.transactions_by_id.contains_key(&transaction_id)
+    }
+
+    fn transaction_count(&self) -> usize {
+        self.transactions_by_id.len()
+    }
+
+    fn transaction_mut(&mut self, transaction_id: TransactionID) -> Option<&mut Transaction> {
+        let transaction = self.transactions_by_id.get_mut(&transaction_id)?;
+        self.dirty_transactions.insert(transaction_id);
+        Some(transaction)
+    }
+
+    fn transaction_and_clients_mut(
+        &mut self,
+        transaction_id: TransactionID,
+    ) -> (Option<&mut Transaction>, &mut HashMap<ClientID, Client>) {
+        let transaction = self.transactions_by_id.get_mut(&transaction_id);
+        if transaction.is_some() {
+            self.dirty_transactions.insert(transaction_id);
+        }
+        (transaction, &mut self.clients_by_id)
+    }
+
+    fn insert_transaction(&mut self, transaction_id: TransactionID, transaction: Transaction) {
+        self.transactions_by_id.insert(transaction_id, transaction);
+        self.dirty_transactions.insert(transaction_id);
+    }
+
+    fn for_each_transaction(
+        &mut self,
+        f: &mut dyn FnMut(TransactionID, &Transaction) -> io::Result<()>,
+    ) -> io::Result<()> {
+        self.transactions_by_id
+            .iter()
+            .try_for_each(|(transaction_id, transaction)| f(*transaction_id, transaction))
+    }
+
+    fn take_error(&mut self) -> Option<io::Error> {
+        self.error.take()
+    }
+
+    fn mirrors_clients(&self) -> bool {
+        true
+    }
+
+    fn clients_changed(&mut self, client_ids: &[ClientID]) {
+        if let Err(e) = self.write(client_ids) {
+            self.error.get_or_insert(e);
+        }
+    }
+
+    fn into_maps(
+        self: Box<Self>,
+    ) -> io::Result<(
+        HashMap<ClientID, Client>,
+        HashMap<TransactionID, Transaction>,
+    )> {
+        Ok((self.clients_by_id, self.transactions_by_id))
+    }
+}
+
+fn redis_error(e: redis::RedisError) -> io::Error {
+    io::Error::other(e)
+}
+
+fn corrupt(e: impl ToString, what: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("A {} in Redis is corrupt: {}", what, e.to_string()),
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::model::{Currency, DisputeStatus, TransactionKind};
+    use pretty_assertions::assert_eq;
+    use redis::{cmd, pipe, Value};
+    use redis_test::{MockCmd, MockRedisConnection};
+    use rust_decimal_macros::dec;
+
+    #[test]
+    fn test_redis_store() {
+        let client = Client::create(dec!(0), dec!(1.5), false);
+        let transaction =
+            Transaction::new(1, Currency::default(), dec!(1.5), TransactionKind::Deposit);
+        let hash = |field: &str, bytes: Vec<u8>| {
+            Value::Array(vec![
+                Value::BulkString(field.as_bytes().to_vec()),
+                Value::BulkString(bytes),
+            ])
+        };
+        let mut disputed =
+            Transaction::from_bytes(&transaction.to_bytes()).expect("Expected a valid transaction");
+        disputed.dispute(dec!(1.5), 2);
+        let held = Client::create(dec!(1.5), dec!(1.5), false);
+        let connection = MockRedisConnection::new(vec![
+            MockCmd::new(
+                cmd("HGETALL").arg("test:clients"),
+                Ok(hash(
+                    "1",
+                    bincode::serialize(&client).expect("Expected no errors."),
+                )),
+            ),
+            MockCmd::new(
+                cmd("HGETALL").arg("test:transactions"),
+                Ok(hash("1", transaction.to_bytes().to_vec())),
+            ),
+            // the client and transaction are written together
+            MockCmd::with_values(
+                pipe()
+                    .atomic()
+                    .hset(
+                        "test:clients",
+                        1,
+                        bincode::serialize(&held).expect("Expected no errors."),
+                    )
+                    .ignore()
+                    .hset("test:transactions", 1, disputed.to_bytes().as_slice())
+                    .ignore(),
+                Ok(vec![Value::Array(vec![Value::Int(0), Value::Int(0)])]),
+            ),
+        ]);
+
+        let mut store =
+            RedisStore::with_connection(connection, "test").expect("Expected no errors.");
+        assert_eq!(Some(&client), store.clients().get(&1));
+        assert!(store.contains_transaction(1));
+
+        let (transaction, clients_by_id) = store.transaction_and_clients_mut(1);
+        transaction
+            .expect("Expected transaction 1")
+            .dispute(dec!(1.5), 2);
+        *clients_by_id.get_mut(&1).expect("Expected client 1") = held;
+        store.clients_changed(&[1]);
+        assert!(store.take_error().is_none());
+        assert_eq!(
+            DisputeStatus::Disputed,
+            store
+                .transaction_mut(1)
+                .expect("Expected transaction 1")
+                .dispute_status()
+        );
+
+        // the mock's expecting nothing more, so the next write fails, and the
+        // error's held onto until it's asked for
+        store.clients_changed(&[1]);
+        assert!(store.take_error().is_some());
+    }
+}
@@ -0,0 +1,509 @@
+use crate::model::{ProcessingError, Source};
+
+use std::io::{self, Write};
+
+// An event that failed processing, along with why. The error is only turned
+// into a message by loggers that write one, so rejecting an event doesn't
+// allocate.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Rejection<'a> {
+    pub source: Option<&'a Source>,
+    pub error: &'a ProcessingError,
+}
+
+impl Rejection<'_> {
+    // A stable identifier for the kind of failure, for automated triage.
+    pub fn code(&self) -> &'static str {
+        self.error.code()
+    }
+}
+
+// Parse errors abort the run rather than being logged, so every rejection we
+// log is a processing error, and each kind of processing error has a code of
+// its own. Once a code's been used it's kept as it is, since rejection files
+// get filtered and counted by them.
+pub const INSUFFICIENT_FUNDS_CODE: &str = "insufficient_funds";
+pub const AMOUNT_OVERFLOW_CODE: &str = "amount_overflow";
+pub const ACCOUNT_LOCKED_CODE: &str = "account_locked";
+pub const ACCOUNT_CLOSED_CODE: &str = "account_closed";
+pub const ACCOUNT_ALREADY_CLOSED_CODE: &str = "account_already_closed";
+pub const CLIENT_NOT_FOUND_CODE: &str = "client_not_found";
+pub const CLIENT_MISMATCH_CODE: &str = "client_mismatch";
+pub const TRANSACTION_EXISTS_CODE: &str = "transaction_exists";
+pub const TRANSACTION_NOT_FOUND_CODE: &str = "transaction_not_found";
+pub const CONVERSION_NOT_DISPUTABLE_CODE: &str = "conversion_not_disputable";
+pub const ADJUSTMENT_NOT_DISPUTABLE_CODE: &str = "adjustment_not_disputable";
+pub const SELF_TRANSFER_CODE: &str = "self_transfer";
+pub const SAME_CURRENCY_CONVERSION_CODE: &str = "same_currency_conversion";
+pub const WITHDRAWAL_DISPUTES_REJECTED_CODE: &str = "withdrawal_disputes_rejected";
+pub const WITHDRAWAL_NOT_KEPT_CODE: &str = "withdrawal_not_kept";
+pub const DEPOSIT_PENDING_CODE: &str = "deposit_pending";
+pub const NOT_PENDING_CODE: &str = "not_pending";
+pub const NOT_DISPUTED_CODE: &str = "not_disputed";
+pub const ALREADY_DISPUTED_CODE: &str = "already_disputed";
+pub const ALREADY_CHARGED_BACK_CODE: &str = "already_charged_back";
+pub const ALREADY_REVERSED_CODE: &str = "already_reversed";
+pub const NOT_CHARGED_BACK_CODE: &str = "not_charged_back";
+pub const REVERSAL_WHILE_DISPUTED_CODE: &str = "reversal_while_disputed";
+pub const REVERSAL_AFTER_PARTIAL_CHARGEBACK_CODE: &str = "reversal_after_partial_chargeback";
+pub const INVALID_DISPUTE_AMOUNT_CODE: &str = "invalid_dispute_amount";
+pub const REDISPUTE_AMOUNT_MISMATCH_CODE: &str = "redispute_amount_mismatch";
+pub const DISPUTE_WINDOW_EXPIRED_CODE: &str = "dispute_window_expired";
+pub const STILL_LOCKED_CODE: &str = "still_locked";
+
+impl ProcessingError {
+    // The code a rejection for this error is logged with.
+    pub fn code(&self) -> &'static str {
+        match self {
+            ProcessingError::InsufficientFunds => INSUFFICIENT_FUNDS_CODE,
+            ProcessingError::AmountOverflow => AMOUNT_OVERFLOW_CODE,
+            ProcessingError::AccountLocked { .. } => ACCOUNT_LOCKED_CODE,
+            ProcessingError::AccountClosed { .. } => ACCOUNT_CLOSED_CODE,
+            ProcessingError::AccountAlreadyClosed => ACCOUNT_ALREADY_CLOSED_CODE,
+            ProcessingError::ClientNotFound { .. } => CLIENT_NOT_FOUND_CODE,
+            ProcessingError::ClientMismatch { .. } => CLIENT_MISMATCH_CODE,
+            ProcessingError::TransactionExists { .. } => TRANSACTION_EXISTS_CODE,
+            ProcessingError::TransactionNotFound { .. } => TRANSACTION_NOT_FOUND_CODE,
+            ProcessingError::ConversionNotDisputable { .. } => CONVERSION_NOT_DISPUTABLE_CODE,
+            ProcessingError::AdjustmentNotDisputable { .. } => ADJUSTMENT_NOT_DISPUTABLE_CODE,
+            ProcessingError::SelfTransfer { .. } => SELF_TRANSFER_CODE,
+            ProcessingError::SameCurrencyConversion => SAME_CURRENCY_CONVERSION_CODE,
+            ProcessingError::WithdrawalDisputesRejected => WITHDRAWAL_DISPUTES_REJECTED_CODE,
+            ProcessingError::WithdrawalNotKept { .. } => WITHDRAWAL_NOT_KEPT_CODE,
+            ProcessingError::DepositPending { .. } => DEPOSIT_PENDING_CODE,
+            ProcessingError::NotPending { .. } => NOT_PENDING_CODE,
+            ProcessingError::NotDisputed => NOT_DISPUTED_CODE,
+            ProcessingError::AlreadyDisputed => ALREADY_DISPUTED_CODE,
+            ProcessingError::AlreadyChargedBack => ALREADY_CHARGED_BACK_CODE,
+            ProcessingError::AlreadyReversed => ALREADY_REVERSED_CODE,
+            ProcessingError::NotChargedBack => NOT_CHARGED_BACK_CODE,
+            ProcessingError::ReversalWhileDisputed => REVERSAL_WHILE_DISPUTED_CODE,
+            ProcessingError::ReversalAfterPartialChargeback => {
+                REVERSAL_AFTER_PARTIAL_CHARGEBACK_CODE
+            }
+            ProcessingError::InvalidDisputeAmount { .. } => INVALID_DISPUTE_AMOUNT_CODE,
+            ProcessingError::RedisputeAmountMismatch => REDISPUTE_AMOUNT_MISMATCH_CODE,
+            ProcessingError::DisputeWindowExpired { .. } => DISPUTE_WINDOW_EXPIRED_CODE,
+            ProcessingError::StillLocked { .. } => STILL_LOCKED_CODE,
+        }
+    }
+}
+
+// What a rejection code means, for whoever's looking at a file full of them
+// and wondering what to do (see `challenge explain`).
+#[derive(Debug, PartialEq, Eq)]
+pub struct CodeExplanation {
+    pub code: &'static str,
+    pub meaning: &'static str,
+    pub causes: &'static [&'static str],
+    // what we do with the event, and what that means for the run
+    pub behavior: &'static str,
+}
+
+// What happens to most rejected events.
+const SKIPPED: &str = "The event is skipped, so nothing about the account changes, and the run \
+                       carries on. It counts towards --max-rejections.";
+
+// Every code a rejection can be logged with. The message a rejection is
+// logged with has the specifics, e.g. which transaction it was.
+pub const REJECTION_CODES: &[CodeExplanation] = &[
+    CodeExplanation {
+        code: INSUFFICIENT_FUNDS_CODE,
+        meaning: "The client doesn't have enough available in the currency to cover the event.",
+        causes: &[
+            "A withdrawal, transfer, fee or conversion for more than the client has available, \
+             including any fee charged on it.",
+            "Funds held by an open dispute or a pending deposit, which aren't available until it's \
+             resolved or settled.",
+            "A credit limit smaller than the overdraft the event would take the client into.",
+        ],
+        behavior: SKIPPED,
+    },
+    CodeExplanation {
+        code: AMOUNT_OVERFLOW_CODE,
+        meaning: "The event would take an amount beyond what can be represented.",
+        causes: &[
+            "An amount in the input that's absurdly large, usually from a corrupt or hostile input.",
+        ],
+        behavior: SKIPPED,
+    },
+    CodeExplanation {
+        code: ACCOUNT_LOCKED_CODE,
+        meaning: "The client's account is locked, and the account policies don't let the event through.",
+        causes: &[
+            "A withdrawal, transfer, fee or conversion after a chargeback locked the account, \
+             or a deposit or interest unless --locked-deposits accept.",
+            "A dispute step on a locked account with --locked-disputes reject.",
+        ],
+        behavior: SKIPPED,
+    },
+    CodeExplanation {
+        code: ACCOUNT_CLOSED_CODE,
+        meaning: "The client's account has been closed.",
+        causes: &[
+            "A deposit, withdrawal, transfer, fee, conversion or interest after a close_account \
+             event, other than the withdrawals --closed-accounts allow-withdrawals lets through.",
+        ],
+        behavior: SKIPPED,
+    },
+    CodeExplanation {
+        code: ACCOUNT_ALREADY_CLOSED_CODE,
+        meaning: "A close_account for an account that's already closed.",
+        causes: &[
+            "The same close_account sent twice.",
+        ],
+        behavior: SKIPPED,
+    },
+    CodeExplanation {
+        code: CLIENT_NOT_FOUND_CODE,
+        meaning: "The event needs a client that doesn't exist.",
+        causes: &[
+            "Interest for a client that's never had anything deposited or registered.",
+        ],
+        behavior: SKIPPED,
+    },
+    CodeExplanation {
+        code: CLIENT_MISMATCH_CODE,
+        meaning: "The event names a different client to the transaction it's about.",
+        causes: &[
+            "A dispute, resolve, chargeback, settle or reversal with the wrong client ID.",
+            "Transaction IDs that were reused across clients upstream.",
+        ],
+        behavior: SKIPPED,
+    },
+    CodeExplanation {
+        code: TRANSACTION_EXISTS_CODE,
+        meaning: "The event's transaction ID has been used before.",
+        causes: &[
+            "The same event sent twice, or an ID reused by an upstream system.",
+            "Any two of deposits, withdrawals, transfers, fees, conversions, adjustments and interest \
+             sharing an ID, since they all take IDs from the same pool.",
+        ],
+        behavior: SKIPPED,
+    },
+    CodeExplanation {
+        code: TRANSACTION_NOT_FOUND_CODE,
+        meaning: "The event is about a transaction that doesn't exist.",
+        causes: &[
+            "A dispute, resolve, chargeback, settle or reversal of an ID that was never used, or \
+             whose deposit or withdrawal was itself rejected.",
+            "A dispute step arriving before the transaction it's about.",
+        ],
+        behavior: SKIPPED,
+    },
+    CodeExplanation {
+        code: CONVERSION_NOT_DISPUTABLE_CODE,
+        meaning: "A dispute or reversal of a conversion.",
+        causes: &[
+            "Conversions can't be disputed or reversed, since there's nobody to dispute them with.",
+        ],
+        behavior: SKIPPED,
+    },
+    CodeExplanation {
+        code: ADJUSTMENT_NOT_DISPUTABLE_CODE,
+        meaning: "A dispute or reversal of an adjustment.",
+        causes: &[
+            "Adjustments are corrected with another adjustment rather than disputed or reversed.",
+        ],
+        behavior: SKIPPED,
+    },
+    CodeExplanation {
+        code: SELF_TRANSFER_CODE,
+        meaning: "A transfer from a client to themselves.",
+        causes: &[
+            "The same client ID in both the client and to_client columns.",
+        ],
+        behavior: SKIPPED,
+    },
+    CodeExplanation {
+        code: SAME_CURRENCY_CONVERSION_CODE,
+        meaning: "A conversion from a currency into itself.",
+        causes: &[
+            "The same currency in both the currency and to_currency columns.",
+        ],
+        behavior: SKIPPED,
+    },
+    CodeExplanation {
+        code: WITHDRAWAL_DISPUTES_REJECTED_CODE,
+        meaning: "A dispute of a withdrawal, which only deposits can be.",
+        causes: &[
+            "--withdrawal-disputes reject, which is the default.",
+        ],
+        behavior: SKIPPED,
+    },
+    CodeExplanation {
+        code: WITHDRAWAL_NOT_KEPT_CODE,
+        meaning: "A dispute or reversal of a withdrawal that --disputable-only didn't keep.",
+        causes: &[
+            "A withdrawal dropped under --disputable-only being reversed.",
+        ],
+        behavior: SKIPPED,
+    },
+    CodeExplanation {
+        code: DEPOSIT_PENDING_CODE,
+        meaning: "A dispute of a deposit that hasn't settled yet.",
+        causes: &[
+            "A dispute of a deposit_pending before its settle.",
+        ],
+        behavior: SKIPPED,
+    },
+    CodeExplanation {
+        code: NOT_PENDING_CODE,
+        meaning: "A settle of a transaction that isn't a pending deposit.",
+        causes: &[
+            "A settle sent twice, or for a plain deposit.",
+        ],
+        behavior: SKIPPED,
+    },
+    CodeExplanation {
+        code: NOT_DISPUTED_CODE,
+        meaning: "A resolve or chargeback of a transaction that isn't disputed.",
+        causes: &[
+            "A resolve or chargeback without a dispute before it, or sent twice.",
+        ],
+        behavior: SKIPPED,
+    },
+    CodeExplanation {
+        code: ALREADY_DISPUTED_CODE,
+        meaning: "A dispute of a transaction that's already disputed.",
+        causes: &[
+            "The same dispute sent twice.",
+        ],
+        behavior: SKIPPED,
+    },
+    CodeExplanation {
+        code: ALREADY_CHARGED_BACK_CODE,
+        meaning: "A dispute step for a transaction that's already been charged back in full.",
+        causes: &[
+            "Anything but a re-dispute after a chargeback, or a re-dispute without \
+             --allow-redispute.",
+        ],
+        behavior: SKIPPED,
+    },
+    CodeExplanation {
+        code: ALREADY_REVERSED_CODE,
+        meaning: "A dispute step or reversal of a transaction that's been reversed.",
+        causes: &[
+            "A reversal sent twice, or a dispute of a reversed transaction.",
+        ],
+        behavior: SKIPPED,
+    },
+    CodeExplanation {
+        code: NOT_CHARGED_BACK_CODE,
+        meaning: "A chargeback_reversal of a transaction that hasn't just been charged back.",
+        causes: &[
+            "A chargeback_reversal without a chargeback before it, or sent twice.",
+        ],
+        behavior: SKIPPED,
+    },
+    CodeExplanation {
+        code: REVERSAL_WHILE_DISPUTED_CODE,
+        meaning: "A reversal of a transaction that's disputed.",
+        causes: &[
+            "The dispute needs resolving or charging back before the transaction can be reversed.",
+        ],
+        behavior: SKIPPED,
+    },
+    CodeExplanation {
+        code: REVERSAL_AFTER_PARTIAL_CHARGEBACK_CODE,
+        meaning: "A reversal of a transaction that's been partially charged back.",
+        causes: &[
+            "Part of it has already been taken back, so reversing all of it would take that part twice.",
+        ],
+        behavior: SKIPPED,
+    },
+    CodeExplanation {
+        code: INVALID_DISPUTE_AMOUNT_CODE,
+        meaning: "A dispute for more than is left to dispute, or for zero or less.",
+        causes: &[
+            "A partial dispute's amount being bigger than the transaction, less anything already \
+             charged back.",
+        ],
+        behavior: SKIPPED,
+    },
+    CodeExplanation {
+        code: REDISPUTE_AMOUNT_MISMATCH_CODE,
+        meaning: "A re-dispute for a different amount to what was charged back.",
+        causes: &[
+            "A re-dispute under --allow-redispute with an amount that doesn't match the chargeback.",
+        ],
+        behavior: SKIPPED,
+    },
+    CodeExplanation {
+        code: DISPUTE_WINDOW_EXPIRED_CODE,
+        meaning: "A dispute came in longer after its transaction than --dispute-window allows.",
+        causes: &[
+            "A client disputing a transaction long after the fact.",
+            "Timestamps in the input that aren't in seconds since the Unix epoch, or a dispute \
+             stamped with when it was reprocessed rather than when it was raised.",
+        ],
+        behavior: "The dispute is skipped, so nothing is held, and any resolve or chargeback \
+                   of it is then rejected as not_disputed. It's only checked when both the \
+                   dispute and the transaction have a timestamp. It counts towards \
+                   --max-rejections.",
+    },
+    CodeExplanation {
+        code: STILL_LOCKED_CODE,
+        meaning: "A dispute step queued behind a lock was still queued at the end of the run.",
+        causes: &[
+            "--locked-disputes queue, with nothing unlocking the account before the \
+             input ran out.",
+        ],
+        behavior: "The dispute step is never applied. It's logged once everything else has \
+                   been processed, and counts towards --max-rejections.",
+    },
+];
+
+pub fn explain_code(code: &str) -> Option<&'static CodeExplanation> {
+    REJECTION_CODES
+        .iter()
+        .find(|explanation| explanation.code == code)
+}
+
+// Receives every rejected event. Any writer can be used as a logger, in which
+// case each rejection is written as a line of free text, starting with where
+// the event was read from if we know; other
+// implementations (e.g. `format::json::rejections::JsonRejectionLogger`) can
+// write something more structured.
+pub trait RejectionLogger {
+    fn log_rejection(&mut self, rejection: &Rejection) -> io::Result<()>;
+
+    // Called once every event has been processed.
+    fn flush_rejections(&mut self) -> io::Result<()>;
+}
+
+impl<W: Write> RejectionLogger for W {
+    fn log_rejection(&mut self, rejection: &Rejection) -> io::Result<()> {
+        match rejection.source {
+            Some(source) => writeln!(self, "{}: {}", source, rejection.error),
+            None => writeln!(self, "{}", rejection.error),
+        }
+    }
+
+    fn flush_rejections(&mut self) -> io::Result<()> {
+        self.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::model::Amount;
+    use pretty_assertions::assert_eq;
+    use std::collections::HashSet;
+
+    // One of every kind of processing error.
+    fn every_error() -> Vec<ProcessingError> {
+        vec![
+            ProcessingError::InsufficientFunds,
+            ProcessingError::AmountOverflow,
+            ProcessingError::AccountLocked { action: "withdraw" },
+            ProcessingError::AccountClosed { kind: "deposit" },
+            ProcessingError::AccountAlreadyClosed,
+            ProcessingError::ClientNotFound { client_id: 1 },
+            ProcessingError::ClientMismatch {
+                client_id: 1,
+                transaction_client_id: 2,
+            },
+            ProcessingError::TransactionExists { id: 1 },
+            ProcessingError::TransactionNotFound { id: 1 },
+            ProcessingError::ConversionNotDisputable { id: 1 },
+            ProcessingError::AdjustmentNotDisputable { id: 1 },
+            ProcessingError::SelfTransfer { client_id: 1 },
+            ProcessingError::SameCurrencyConversion,
+            ProcessingError::WithdrawalDisputesRejected,
+            ProcessingError::WithdrawalNotKept { id: 1 },
+            ProcessingError::DepositPending { id: 1 },
+            ProcessingError::NotPending { id: 1 },
+            ProcessingError::NotDisputed,
+            ProcessingError::AlreadyDisputed,
+            ProcessingError::AlreadyChargedBack,
+            ProcessingError::AlreadyReversed,
+            ProcessingError::NotChargedBack,
+            ProcessingError::ReversalWhileDisputed,
+            ProcessingError::ReversalAfterPartialChargeback,
+            ProcessingError::InvalidDisputeAmount {
+                amount: Amount::ONE,
+                disputable: Amount::ZERO,
+            },
+            ProcessingError::RedisputeAmountMismatch,
+            ProcessingError::DisputeWindowExpired { id: 1 },
+            ProcessingError::StillLocked {
+                client_id: 1,
+                kind: "dispute",
+                id: 1,
+            },
+        ]
+    }
+
+    #[test]
+    fn test_every_error_has_a_code() {
+        // a new variant won't compile here until it's given a number, at which
+        // point this fails until it's added to `every_error` too
+        let mut covered = [false; 28];
+        for error in every_error() {
+            let variant = match error {
+                ProcessingError::InsufficientFunds => 0,
+                ProcessingError::AmountOverflow => 1,
+                ProcessingError::AccountLocked { .. } => 2,
+                ProcessingError::AccountClosed { .. } => 3,
+                ProcessingError::AccountAlreadyClosed => 4,
+                ProcessingError::ClientNotFound { .. } => 5,
+                ProcessingError::ClientMismatch { .. } => 6,
+                ProcessingError::TransactionExists { .. } => 7,
+                ProcessingError::TransactionNotFound { .. } => 8,
+                ProcessingError::ConversionNotDisputable { .. } => 9,
+                ProcessingError::AdjustmentNotDisputable { .. } => 10,
+                ProcessingError::SelfTransfer { .. } => 11,
+                ProcessingError::SameCurrencyConversion => 12,
+                ProcessingError::WithdrawalDisputesRejected => 13,
+                ProcessingError::WithdrawalNotKept { .. } => 14,
+                ProcessingError::DepositPending { .. } => 15,
+                ProcessingError::NotPending { .. } => 16,
+                ProcessingError::NotDisputed => 17,
+                ProcessingError::AlreadyDisputed => 18,
+                ProcessingError::AlreadyChargedBack => 19,
+                ProcessingError::AlreadyReversed => 20,
+                ProcessingError::NotChargedBack => 21,
+                ProcessingError::ReversalWhileDisputed => 22,
+                ProcessingError::ReversalAfterPartialChargeback => 23,
+                ProcessingError::InvalidDisputeAmount { .. } => 24,
+                ProcessingError::RedisputeAmountMismatch => 25,
+                ProcessingError::DisputeWindowExpired { .. } => 26,
+                ProcessingError::StillLocked { .. } => 27,
+            };
+            covered[variant] = true;
+
+            let explanation = explain_code(error.code()).expect("Expected an explanation");
+            assert_eq!(error.code(), explanation.code);
+        }
+        assert!(covered.iter().all(|covered| *covered));
+
+        let codes = REJECTION_CODES
+            .iter()
+            .map(|explanation| explanation.code)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            codes.len(),
+            codes.iter().collect::<HashSet<_>>().len(),
+            "Expected each code to be explained once"
+        );
+        assert_eq!(
+            every_error()
+                .iter()
+                .map(ProcessingError::code)
+                .collect::<HashSet<_>>(),
+            codes.into_iter().collect::<HashSet<_>>(),
+            "Expected every explained code to be used"
+        );
+    }
+
+    #[test]
+    fn test_explain_code() {
+        assert_eq!(
+            Some(INSUFFICIENT_FUNDS_CODE),
+            explain_code("insufficient_funds").map(|explanation| explanation.code)
+        );
+        assert_eq!(None, explain_code("E011"));
+    }
+}
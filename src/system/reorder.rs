@@ -0,0 +1,164 @@
+use crate::model::{SourcedEvent, Timestamp};
+
+use std::{cmp::Ordering, cmp::Reverse, collections::BinaryHeap};
+
+// Puts events back into timestamp order, for sources that occasionally deliver
+// them a little out of order (e.g. a dispute a few records before its
+// deposit). Up to `window` events are held back at a time, and the earliest is
+// let go whenever another one comes in, so an event can only be moved ahead of
+// the `window` events before it. Events with the same timestamp keep the order
+// they came in, and events without one keep their place behind the events
+// before them. A window of zero leaves the order alone.
+pub(crate) struct ReorderBuffer<I> {
+    events_iter: I,
+    window: usize,
+    buffer: BinaryHeap<Reverse<BufferedEvent>>,
+    latest_timestamp: Option<Timestamp>,
+    events_seen: u64,
+}
+
+struct BufferedEvent {
+    // what we're ordering by: the timestamp, then the order it came in
+    key: (Option<Timestamp>, u64),
+    event: SourcedEvent,
+}
+
+impl PartialEq for BufferedEvent {
+    fn eq(&self, other: &Self) -> bool {
+        self.key == other.key
+    }
+}
+
+impl Eq for BufferedEvent {}
+
+impl PartialOrd for BufferedEvent {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for BufferedEvent {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.key.cmp(&other.key)
+    }
+}
+
+impl<I> ReorderBuffer<I> {
+    pub fn new(events_iter: I, window: usize) -> Self {
+        Self {
+            events_iter,
+            window,
+            buffer: BinaryHeap::new(),
+            latest_timestamp: None,
+            events_seen: 0,
+        }
+    }
+
+    // Whether an event has to be let go before another is held back.
+    pub fn is_full(&self) -> bool {
+        self.buffer.len() > self.window
+    }
+
+    // For feeding events in by hand rather than from `events_iter`, e.g. from
+    // an async stream.
+    pub fn push(&mut self, event: SourcedEvent) {
+        self.latest_timestamp = self.latest_timestamp.max(event.timestamp);
+        let timestamp = event.timestamp.or(self.latest_timestamp);
+        self.buffer.push(Reverse(BufferedEvent {
+            key: (timestamp, self.events_seen),
+            event,
+        }));
+        self.events_seen += 1;
+    }
+
+    pub fn pop(&mut self) -> Option<SourcedEvent> {
+        self.pop_indexed().map(|(_, event)| event)
+    }
+
+    // Like `pop`, along with where the event came in, counting from zero.
+    pub fn pop_indexed(&mut self) -> Option<(u64, SourcedEvent)> {
+        self.buffer
+            .pop()
+            .map(|Reverse(buffered)| (buffered.key.1, buffered.event))
+    }
+}
+
+impl<I, E> ReorderBuffer<I>
+where
+    I: Iterator<Item = Result<SourcedEvent, E>>,
+{
+    // Like `next`, along with where the event came in, counting from zero.
+    pub fn next_indexed(&mut self) -> Option<Result<(u64, SourcedEvent), E>> {
+        if let Err(e) = self.fill() {
+            return Some(Err(e));
+        }
+        self.pop_indexed().map(Ok)
+    }
+
+    // Holds back as many events as the window allows.
+    fn fill(&mut self) -> Result<(), E> {
+        while !self.is_full() {
+            match self.events_iter.next() {
+                Some(Ok(event)) => self.push(event),
+                // errors abort the run, so there's no point holding them back
+                Some(Err(e)) => return Err(e),
+                None => break,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<I, E> Iterator for ReorderBuffer<I>
+where
+    I: Iterator<Item = Result<SourcedEvent, E>>,
+{
+    type Item = Result<SourcedEvent, E>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Err(e) = self.fill() {
+            return Some(Err(e));
+        }
+        self.pop().map(Ok)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::model::{Event, TransactionID};
+    use pretty_assertions::assert_eq;
+
+    fn reorder(timestamps: Vec<Option<Timestamp>>, window: usize) -> Vec<TransactionID> {
+        let events_iter = timestamps
+            .into_iter()
+            .enumerate()
+            .map(|(index, timestamp)| {
+                Ok::<_, ()>(SourcedEvent {
+                    event: Event::Reversal {
+                        transaction_id: index as TransactionID,
+                        client_id: 1,
+                    },
+                    source: None,
+                    timestamp,
+                })
+            });
+
+        ReorderBuffer::new(events_iter, window)
+            .map(|result| match result.expect("Expected no errors.").event {
+                Event::Reversal { transaction_id, .. } => transaction_id,
+                event => panic!("Unexpected event: {:?}", event),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_reorder() {
+        let timestamps = vec![Some(1), Some(3), Some(2), None, Some(3), Some(1), Some(0)];
+
+        assert_eq!(vec![0, 1, 2, 3, 4, 5, 6], reorder(timestamps.clone(), 0));
+        // event 5 is too far out of place to be moved ahead of event 1
+        assert_eq!(vec![0, 2, 1, 5, 6, 3, 4], reorder(timestamps.clone(), 2));
+        assert_eq!(vec![6, 0, 5, 2, 1, 3, 4], reorder(timestamps, 10));
+    }
+}
@@ -0,0 +1,719 @@
+use super::{
+    finish_processing, process_sourced_event, EngineConfig, EventCounts, FinalState, LimitExceeded,
+    LockedAccountPolicy, ProcessEventsError, Processor, ProcessorParts, Rejection, RejectionLogger,
+    ReorderBuffer, ResourceLimits,
+};
+use crate::model::{ClientID, Event, ProcessingError, Source, SourcedEvent, TransactionID};
+
+use std::{
+    collections::HashMap,
+    io, panic,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        mpsc::{self, Receiver, SyncSender},
+    },
+    thread,
+};
+
+// How many events can be waiting for a shard before reading the input waits
+// for it to catch up.
+const SHARD_QUEUE_LEN: usize = 1024;
+
+// Like `process_events_with_snapshots` without the snapshots, but spread over
+// `shard_count` threads. Clients mostly don't affect each other, so each
+// thread (shard) gets its own processor and the clients whose ID modulo
+// `shard_count` is its index.
+//
+// That lasts until an event comes in that one shard can't deal with on its
+// own: a transfer between clients in different shards, or a transaction ID
+// that's already been used in another shard. The shards are merged into one
+// processor there and the rest of the events are processed one after another,
+// so the result is always the same as processing them all that way. The one
+// difference is that the shards' rejections are only logged once they've all
+// finished. `EngineConfig::limits` apply to the shards together, but since
+// each shard only checks the totals after its own events, the run can be
+// stopped on a different event than it would have been otherwise. Under `EngineConfig::dispute_expiry` or
+// `LockedAccountPolicy::Queue` what happens to one client depends on when the
+// others' events came in, so the whole run is processed one event after
+// another.
+pub fn process_events_sharded<E: Into<SourcedEvent>, Err: Into<ProcessEventsError>>(
+    events_iter: impl Iterator<Item = Result<E, Err>>,
+    error_logger: &mut (impl RejectionLogger + ?Sized),
+    config: &EngineConfig,
+    shard_count: usize,
+) -> Result<FinalState, ProcessEventsError> {
+    let mut events_iter = (0..).zip(ReorderBuffer::new(
+        events_iter.map(|event| event.map(Into::into).map_err(Into::into)),
+        config.reorder_window,
+    ));
+    let mut processor = Processor::new(config);
+    let mut event_counts = EventCounts::default();
+
+    let can_shard = config.dispute_expiry.is_none()
+        && config.locked_account_disputes != LockedAccountPolicy::Queue;
+    let mut unshardable = None;
+    if shard_count > 1 && can_shard {
+        let mut router = Router::new(shard_count);
+        let totals = SharedTotals::default();
+        let (routed, shards) = thread::scope(|scope| {
+            let (senders, handles): (Vec<_>, Vec<_>) = (0..shard_count)
+                .map(|_| {
+                    let (sender, receiver) = mpsc::sync_channel(SHARD_QUEUE_LEN);
+                    let totals = &totals;
+                    (
+                        sender,
+                        scope.spawn(move || run_shard(receiver, config, totals)),
+                    )
+                })
+                .unzip();
+
+            let routed = router.route_events(&mut events_iter, &senders, &mut event_counts);
+            // hanging up is what tells the shards there are no more events
+            drop(senders);
+            let shards = handles
+                .into_iter()
+                .map(|handle| handle.join().unwrap_or_else(|e| panic::resume_unwind(e)))
+                .collect::<Vec<_>>();
+
+            (routed, shards)
+        });
+
+        // the shards only ever saw events from before the one they couldn't
+        // deal with, so their rejections all come before anything after it
+        // and can be logged now, counted in order as they go
+        let mut rejections = Vec::new();
+        let mut result = Ok(());
+        for shard in shards {
+            rejections.extend(shard.rejections);
+            if result.is_ok() {
+                result = shard
+                    .result
+                    .and_then(|()| processor.absorb(shard.processor?));
+            }
+        }
+        rejections.sort_by_key(|rejection| rejection.position);
+        for rejection in rejections {
+            event_counts.count_rejected(rejection.error.code());
+            error_logger.log_rejection(&Rejection {
+                source: rejection.source.as_ref(),
+                error: &rejection.error,
+            })?;
+        }
+
+        // a shard that fails stops taking events, which is all the router
+        // sees of it, so the shard's error is the one worth returning
+        if let Err(e) = result {
+            error_logger.flush_rejections()?;
+            return Err(e.into());
+        }
+        unshardable = routed?;
+    }
+
+    let rest = unshardable
+        .map(Ok)
+        .into_iter()
+        .chain(events_iter.map(|(position, event)| event.map(|event| (position, event))));
+    for (i, event) in rest.enumerate() {
+        let (position, event) = event?;
+        if i == 0 {
+            processor.set_events_before(position);
+        }
+        process_sourced_event(
+            &mut processor,
+            &mut event_counts,
+            error_logger,
+            &mut (),
+            event,
+        )?;
+    }
+    finish_processing(&mut processor, &mut event_counts, error_logger, &mut ())?;
+
+    Ok(processor.into_final_state(event_counts)?)
+}
+
+// Decides which shard each event goes to, from the thread reading the input.
+struct Router {
+    shard_count: usize,
+    // Which shard each new transaction ID went to, so that anything that
+    // refers to it later goes to the same one, even if it names another
+    // client. That's rejected either way, but it should be rejected for the
+    // right reason. It's also how an ID that's reused by a client in another
+    // shard is caught, which the shards can't see for themselves.
+    shards_by_transaction_id: HashMap<TransactionID, usize>,
+}
+
+impl Router {
+    fn new(shard_count: usize) -> Self {
+        Self {
+            shard_count,
+            shards_by_transaction_id: HashMap::new(),
+        }
+    }
+
+    // Sends the events to their shards until one comes in that can't go to
+    // any of them, which is returned along with where it came in.
+    fn route_events(
+        &mut self,
+        events_iter: &mut impl Iterator<Item = (u64, Result<SourcedEvent, ProcessEventsError>)>,
+        senders: &[SyncSender<(u64, SourcedEvent)>],
+        event_counts: &mut EventCounts,
+    ) -> Result<Option<(u64, SourcedEvent)>, ProcessEventsError> {
+        for (position, event) in events_iter {
+            let event = event?;
+            let Some(shard) = self.shard_for(&event.event) else {
+                return Ok(Some((position, event)));
+            };
+
+            event_counts.count_processed(&event.event);
+            if senders[shard].send((position, event)).is_err() {
+                return Err(ProcessEventsError::Other(
+                    "A shard stopped before every event was processed.".into(),
+                ));
+            }
+        }
+
+        Ok(None)
+    }
+
+    // `None` if the event involves more than one shard.
+    fn shard_for(&mut self, event: &Event) -> Option<usize> {
+        let shard_for_client = |client_id: ClientID| usize::from(client_id) % self.shard_count;
+
+        let (transaction_id, shard) = match *event {
+            Event::Transaction {
+                transaction_id,
+                client_id,
+                ..
+            }
+            | Event::PendingDeposit {
+                transaction_id,
+                client_id,
+                ..
+            }
+            | Event::Conversion {
+                transaction_id,
+                client_id,
+                ..
+            }
+            | Event::Adjustment {
+                transaction_id,
+                client_id,
+                ..
+            }
+            | Event::Fee {
+                transaction_id,
+                client_id,
+                ..
+            }
+            | Event::Interest {
+                transaction_id,
+                client_id,
+                ..
+            } => (transaction_id, shard_for_client(client_id)),
+            Event::Transfer {
+                transaction_id,
+                from_client_id,
+                to_client_id,
+                ..
+            } => {
+                let shard = shard_for_client(from_client_id);
+                if shard != shard_for_client(to_client_id) {
+                    return None;
+                }
+                (transaction_id, shard)
+            }
+            Event::DisputeStep {
+                transaction_id,
+                client_id,
+                ..
+            }
+            | Event::Reversal {
+                transaction_id,
+                client_id,
+            }
+            | Event::ChargebackReversal {
+                transaction_id,
+                client_id,
+            }
+            | Event::Settlement {
+                transaction_id,
+                client_id,
+            } => {
+                return Some(
+                    self.shards_by_transaction_id
+                        .get(&transaction_id)
+                        .copied()
+                        .unwrap_or_else(|| shard_for_client(client_id)),
+                )
+            }
+            Event::AccountClosure { client_id } | Event::ClientRegistration { client_id, .. } => {
+                return Some(shard_for_client(client_id))
+            }
+        };
+
+        // reusing an ID in the same shard is left to the shard, which knows
+        // whether the first use was rejected and whether the second is an
+        // identical resubmission
+        match *self
+            .shards_by_transaction_id
+            .entry(transaction_id)
+            .or_insert(shard)
+        {
+            earlier_shard if earlier_shard != shard => None,
+            _ => Some(shard),
+        }
+    }
+}
+
+// What a shard leaves behind, for merging with the others.
+struct Shard {
+    processor: io::Result<ProcessorParts>,
+    rejections: Vec<BufferedRejection>,
+    // Whether it got through all its events.
+    result: io::Result<()>,
+}
+
+fn run_shard(
+    receiver: Receiver<(u64, SourcedEvent)>,
+    config: &EngineConfig,
+    totals: &SharedTotals,
+) -> Shard {
+    let mut processor = Processor::new(config);
+    // only kept because the processor needs them; the router has the real ones
+    let mut event_counts = EventCounts::default();
+    let mut rejections = RejectionBuffer::default();
+    let mut added = Totals::default();
+    let check_totals = config.limits != ResourceLimits::default();
+
+    let mut result = Ok(());
+    for (position, event) in receiver {
+        rejections.position = position;
+        processor.set_events_before(position);
+        // the record isn't part of the message, so there's no need to copy it
+        let source = event.source.as_ref().map(|source| Source {
+            record: None,
+            ..*source
+        });
+        result = process_sourced_event(
+            &mut processor,
+            &mut event_counts,
+            &mut rejections,
+            &mut (),
+            event,
+        );
+        if result.is_ok() && check_totals {
+            let shard_totals = Totals {
+                clients: processor.clients_by_id().len(),
+                transactions: processor.transaction_count(),
+                rejected: event_counts.rejected,
+            };
+            let totals = totals.add(&mut added, shard_totals);
+            if let Err(limit) =
+                config
+                    .limits
+                    .check(totals.clients, totals.transactions, totals.rejected)
+            {
+                result = Err(io::Error::other(LimitExceeded { limit, source }));
+            }
+        }
+        if result.is_err() {
+            break;
+        }
+    }
+
+    Shard {
+        processor: processor.into_parts(),
+        rejections: rejections.rejections,
+        result,
+    }
+}
+
+// What all the shards have taken on between them, so that
+// `EngineConfig::limits` apply to the run rather than to each shard's share of
+// it.
+#[derive(Default)]
+struct SharedTotals {
+    clients: AtomicUsize,
+    transactions: AtomicUsize,
+    rejected: AtomicU64,
+}
+
+#[derive(Default, Clone, Copy)]
+struct Totals {
+    clients: usize,
+    transactions: usize,
+    rejected: u64,
+}
+
+impl SharedTotals {
+    // Adds the difference between a shard's totals and what it `added` last
+    // time, returning the totals across the shards.
+    fn add(&self, added: &mut Totals, shard: Totals) -> Totals {
+        let add = |total: &AtomicUsize, before: usize, after: usize| {
+            let difference = after.wrapping_sub(before);
+            total
+                .fetch_add(difference, Ordering::Relaxed)
+                .wrapping_add(difference)
+        };
+        let rejected = shard.rejected - added.rejected;
+        let totals = Totals {
+            clients: add(&self.clients, added.clients, shard.clients),
+            transactions: add(&self.transactions, added.transactions, shard.transactions),
+            rejected: self.rejected.fetch_add(rejected, Ordering::Relaxed) + rejected,
+        };
+        *added = shard;
+        totals
+    }
+}
+
+// A rejection held onto until it can be logged in order.
+struct BufferedRejection {
+    // Where the event it's for came in, counting from zero.
+    position: u64,
+    source: Option<Source>,
+    error: ProcessingError,
+}
+
+// Holds onto each shard's rejections, since only one thread can log them.
+#[derive(Default)]
+struct RejectionBuffer {
+    // Where the event being processed came in.
+    position: u64,
+    rejections: Vec<BufferedRejection>,
+}
+
+impl RejectionLogger for RejectionBuffer {
+    fn log_rejection(&mut self, rejection: &Rejection) -> io::Result<()> {
+        self.rejections.push(BufferedRejection {
+            position: self.position,
+            source: rejection.source.cloned(),
+            error: rejection.error.clone(),
+        });
+        Ok(())
+    }
+
+    fn flush_rejections(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        model::{Currency, DisputeStepKind, TransactionKind},
+        system::{process_events_with_snapshots, Limit},
+    };
+    use pretty_assertions::assert_eq;
+    use rust_decimal_macros::dec;
+    use std::{collections::HashSet, error::Error};
+
+    fn events() -> Vec<Result<Event, Box<dyn Error>>> {
+        let transaction = |kind, client_id, transaction_id, amount| {
+            Ok(Event::Transaction {
+                kind,
+                client_id,
+                transaction_id,
+                currency: Currency::default(),
+                amount,
+                counterparty: None,
+            })
+        };
+        let dispute_step = |kind, client_id, transaction_id| {
+            Ok(Event::DisputeStep {
+                kind,
+                client_id,
+                transaction_id,
+                amount: None,
+            })
+        };
+        let transfer = |from_client_id, to_client_id, transaction_id, amount| {
+            Ok(Event::Transfer {
+                transaction_id,
+                from_client_id,
+                to_client_id,
+                currency: Currency::default(),
+                amount,
+            })
+        };
+
+        let mut events = Vec::new();
+        for client_id in 1..=6 {
+            let transaction_id = u32::from(client_id) * 10;
+            events.extend([
+                transaction(
+                    TransactionKind::Deposit,
+                    client_id,
+                    transaction_id,
+                    dec!(10),
+                ),
+                transaction(
+                    TransactionKind::Withdrawal,
+                    client_id,
+                    transaction_id + 1,
+                    dec!(20),
+                ),
+                transaction(
+                    TransactionKind::Withdrawal,
+                    client_id,
+                    transaction_id + 2,
+                    dec!(2),
+                ),
+            ]);
+        }
+        events.extend([
+            dispute_step(DisputeStepKind::Dispute, 1, 10),
+            // goes to client 1's shard, to be rejected for the right reason
+            dispute_step(DisputeStepKind::Dispute, 2, 10),
+            dispute_step(DisputeStepKind::Chargeback, 1, 10),
+            // queued until the end of the run, since the account is locked
+            dispute_step(DisputeStepKind::Dispute, 1, 12),
+            dispute_step(DisputeStepKind::Dispute, 5, 50),
+            dispute_step(DisputeStepKind::Resolve, 5, 50),
+            // clients 2 and 5 share a shard
+            transfer(2, 5, 70, dec!(1)),
+            // rejected either way, so that the clients end up the same
+            transfer(3, 5, 71, dec!(100)),
+            transaction(TransactionKind::Deposit, 4, 80, dec!(1)),
+            transaction(TransactionKind::Deposit, 4, 80, dec!(1)),
+        ]);
+        events
+    }
+
+    // Processes the events both ways, checking that they come out the same,
+    // and returns the rejections.
+    fn assert_same_as_serial(
+        events: impl Fn() -> Vec<Result<Event, Box<dyn Error>>>,
+        config: &EngineConfig,
+        shard_count: usize,
+    ) -> String {
+        let mut expected_errors = Vec::new();
+        let expected = process_events_with_snapshots(
+            events().into_iter(),
+            &mut expected_errors,
+            config,
+            None,
+            |_, _| Ok(()),
+        )
+        .expect("Unexpectedly failed to process events.");
+        let mut errors = Vec::new();
+        let result = process_events_sharded(events().into_iter(), &mut errors, config, shard_count)
+            .expect("Unexpectedly failed to process events.");
+
+        let errors = String::from_utf8(errors).expect("Not UTF-8");
+        assert_eq!(
+            String::from_utf8(expected_errors).expect("Not UTF-8"),
+            errors
+        );
+        assert_eq!(expected.event_counts, result.event_counts);
+        assert_eq!(expected.clients_by_id, result.clients_by_id);
+        let ids = |transactions: &HashMap<TransactionID, _>| {
+            transactions.keys().copied().collect::<HashSet<_>>()
+        };
+        assert_eq!(
+            ids(&expected.transactions_by_id),
+            ids(&result.transactions_by_id)
+        );
+        assert_eq!(
+            ids(&expected.transfer_credits_by_id),
+            ids(&result.transfer_credits_by_id)
+        );
+        assert_eq!(expected.discarded_withdrawals, result.discarded_withdrawals);
+        errors
+    }
+
+    #[test]
+    fn test_sharded_processing() {
+        let mut expected_errors = vec!["Insufficient funds."; 6];
+        expected_errors.extend([
+            "Client id 2 does not match transaction client id 1.",
+            "Cannot dispute when account is locked.",
+            "Insufficient funds.",
+            "Transaction already exists with id 80.",
+        ]);
+        let expected_errors = expected_errors.join("\n") + "\n";
+
+        let config = EngineConfig {
+            locked_account_disputes: LockedAccountPolicy::Reject,
+            ..EngineConfig::default()
+        };
+        assert_eq!(expected_errors, assert_same_as_serial(events, &config, 3));
+        // one shard is just processing them one after another
+        assert_eq!(expected_errors, assert_same_as_serial(events, &config, 1));
+    }
+
+    #[test]
+    fn test_sharded_processing_with_queued_disputes() {
+        let config = EngineConfig {
+            locked_account_disputes: LockedAccountPolicy::Queue,
+            ..EngineConfig::default()
+        };
+        assert!(assert_same_as_serial(events, &config, 3).ends_with(
+            "Client 1 was still locked at the end of the run, so the dispute of transaction 12 was never processed.\n"
+        ));
+    }
+
+    #[test]
+    fn test_sharded_processing_with_cross_shard_transfers() {
+        let events = || -> Vec<Result<Event, Box<dyn Error>>> {
+            let mut events = Vec::new();
+            for client_id in 1..=4 {
+                events.push(Ok(Event::Transaction {
+                    kind: TransactionKind::Deposit,
+                    client_id,
+                    transaction_id: u32::from(client_id),
+                    currency: Currency::default(),
+                    amount: dec!(10),
+                    counterparty: None,
+                }));
+            }
+            let transfer = |from_client_id, to_client_id, transaction_id, amount| {
+                Ok(Event::Transfer {
+                    transaction_id,
+                    from_client_id,
+                    to_client_id,
+                    currency: Currency::default(),
+                    amount,
+                })
+            };
+            events.extend([
+                // clients 1 and 2 are in different shards
+                transfer(1, 2, 10, dec!(10)),
+                // only possible after the first one
+                transfer(2, 3, 11, dec!(15)),
+                Ok(Event::Transaction {
+                    kind: TransactionKind::Withdrawal,
+                    client_id: 1,
+                    transaction_id: 12,
+                    currency: Currency::default(),
+                    amount: dec!(1),
+                    counterparty: None,
+                }),
+                Ok(Event::DisputeStep {
+                    kind: DisputeStepKind::Dispute,
+                    client_id: 4,
+                    transaction_id: 4,
+                    amount: None,
+                }),
+            ]);
+            events
+        };
+
+        assert_eq!(
+            "Insufficient funds.\n",
+            assert_same_as_serial(events, &EngineConfig::default(), 2)
+        );
+    }
+
+    #[test]
+    fn test_sharded_processing_with_limits() {
+        let events = || -> Vec<Result<Event, Box<dyn Error>>> {
+            let mut events = Vec::new();
+            for client_id in 1..=4 {
+                let transaction_id = u32::from(client_id) * 10;
+                events.push(Ok(Event::Transaction {
+                    kind: TransactionKind::Deposit,
+                    client_id,
+                    transaction_id,
+                    currency: Currency::default(),
+                    amount: dec!(10),
+                    counterparty: None,
+                }));
+                // one rejection per client
+                events.push(Ok(Event::Transaction {
+                    kind: TransactionKind::Withdrawal,
+                    client_id,
+                    transaction_id: transaction_id + 1,
+                    currency: Currency::default(),
+                    amount: dec!(20),
+                    counterparty: None,
+                }));
+            }
+            events
+        };
+
+        // each shard has a client on its own, so only the totals go over
+        for (limits, expected_limit) in [
+            (
+                ResourceLimits {
+                    max_clients: Some(2),
+                    ..ResourceLimits::default()
+                },
+                Limit::Clients(2),
+            ),
+            (
+                ResourceLimits {
+                    max_transactions: Some(2),
+                    ..ResourceLimits::default()
+                },
+                Limit::Transactions(2),
+            ),
+            (
+                ResourceLimits {
+                    max_errors: Some(2),
+                    ..ResourceLimits::default()
+                },
+                Limit::Errors(2),
+            ),
+        ] {
+            let config = EngineConfig {
+                limits,
+                ..EngineConfig::default()
+            };
+            let error = process_events_sharded(events().into_iter(), &mut io::sink(), &config, 4)
+                .err()
+                .expect("Expected the run to be stopped");
+            let ProcessEventsError::Io(error) = error else {
+                panic!("Expected an I/O error, got {error}");
+            };
+            let exceeded = error
+                .get_ref()
+                .and_then(|error| error.downcast_ref::<LimitExceeded>())
+                .expect("Expected a limit to be exceeded");
+            assert_eq!(expected_limit, exceeded.limit);
+
+            // and nothing's stopped with room to spare
+            let config = EngineConfig {
+                limits: ResourceLimits {
+                    max_clients: Some(4),
+                    max_transactions: Some(8),
+                    max_errors: Some(4),
+                },
+                ..EngineConfig::default()
+            };
+            process_events_sharded(events().into_iter(), &mut io::sink(), &config, 4)
+                .expect("Unexpectedly failed to process events.");
+        }
+    }
+
+    #[test]
+    fn test_sharded_processing_with_reused_transaction_id() {
+        let deposit = |client_id, transaction_id, amount| {
+            Ok(Event::Transaction {
+                kind: TransactionKind::Deposit,
+                client_id,
+                transaction_id,
+                currency: Currency::default(),
+                amount,
+                counterparty: None,
+            })
+        };
+        let events = || -> Vec<Result<Event, Box<dyn Error>>> {
+            vec![
+                deposit(1, 1, dec!(10)),
+                // client 2 is in another shard
+                deposit(2, 1, dec!(5)),
+                Ok(Event::DisputeStep {
+                    kind: DisputeStepKind::Dispute,
+                    client_id: 1,
+                    transaction_id: 1,
+                    amount: None,
+                }),
+            ]
+        };
+        assert_eq!(
+            "Transaction already exists with id 1.\n",
+            assert_same_as_serial(events, &EngineConfig::default(), 3)
+        );
+    }
+}
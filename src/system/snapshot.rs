@@ -0,0 +1,93 @@
+use std::{
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+// How often to take a snapshot of the clients in the middle of a run: either
+// every so many events or every so often.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SnapshotInterval {
+    Events(u64),
+    Duration(Duration),
+}
+
+// Parses either a number of events (`100000`) or a number of seconds (`30s`).
+impl FromStr for SnapshotInterval {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid snapshot interval: {}.", s);
+
+        let interval = match s.strip_suffix('s') {
+            Some(seconds) => SnapshotInterval::Duration(Duration::from_secs(
+                seconds.parse().map_err(|_| invalid())?,
+            )),
+            None => SnapshotInterval::Events(s.parse().map_err(|_| invalid())?),
+        };
+
+        // a zero interval would mean a snapshot after every event, which is
+        // never what anyone wants
+        match interval {
+            SnapshotInterval::Events(0) => Err(invalid()),
+            SnapshotInterval::Duration(duration) if duration.is_zero() => Err(invalid()),
+            interval => Ok(interval),
+        }
+    }
+}
+
+// Keeps track of when the next snapshot is due.
+pub(crate) struct SnapshotTimer {
+    interval: SnapshotInterval,
+    events_since_last: u64,
+    last: Instant,
+}
+
+impl SnapshotTimer {
+    pub fn new(interval: SnapshotInterval) -> Self {
+        Self {
+            interval,
+            events_since_last: 0,
+            last: Instant::now(),
+        }
+    }
+
+    // Expected to be called once per event. Returns whether a snapshot should
+    // be taken now, assuming it will be.
+    pub fn tick(&mut self) -> bool {
+        self.events_since_last += 1;
+
+        let due = match self.interval {
+            SnapshotInterval::Events(events) => self.events_since_last >= events,
+            SnapshotInterval::Duration(duration) => self.last.elapsed() >= duration,
+        };
+        if due {
+            self.events_since_last = 0;
+            self.last = Instant::now();
+        }
+
+        due
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_from_str() {
+        assert_eq!(Ok(SnapshotInterval::Events(1000)), "1000".parse());
+        assert_eq!(
+            Ok(SnapshotInterval::Duration(Duration::from_secs(30))),
+            "30s".parse()
+        );
+        assert_eq!(
+            Err(String::from("Invalid snapshot interval: 0.")),
+            "0".parse::<SnapshotInterval>()
+        );
+        assert_eq!(
+            Err(String::from("Invalid snapshot interval: 5m.")),
+            "5m".parse::<SnapshotInterval>()
+        );
+    }
+}
@@ -0,0 +1,319 @@
+use super::StateStore;
+use crate::model::{Client, ClientID, Transaction, TransactionID};
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::File,
+    io::{self, Read, Seek, SeekFrom, Write},
+    mem,
+};
+
+// Roughly what keeping a transaction in memory costs, counting the maps it's
+// in and leaving room for the slack hash maps keep. It doesn't need to be
+// exact for a budget.
+const BYTES_PER_TRANSACTION: usize = 2
+    * (mem::size_of::<(TransactionID, (Transaction, u64))>()
+        + mem::size_of::<(u64, TransactionID)>());
+
+// A store that keeps the most recently used transactions in memory and spills
+// the rest to a temporary file once there are more than the memory budget
+// allows, so that memory use stays flat however long the input is. Where each
+// spilled transaction is in the file is still kept in memory, but that's a
+// fraction of the size of the transaction itself. Clients are kept in memory
+// as usual.
+//
+// Every transaction takes the same number of bytes in the file, so the file is
+// a series of slots, and the slot a transaction is loaded back out of is
+// reused for the next one that's spilled.
+pub struct SpillingStore {
+    clients_by_id: HashMap<ClientID, Client>,
+    // The transactions in memory, along with when each was last used.
+    hot: HashMap<TransactionID, (Transaction, u64)>,
+    // The transactions in memory by when they were last used, so the least
+    // recently used one is first.
+    recency: BTreeMap<u64, TransactionID>,
+    uses: u64,
+    max_hot: usize,
+    // The slot each spilled transaction is in.
+    cold: HashMap<TransactionID, u64>,
+    free_slots: Vec<u64>,
+    slot_count: u64,
+    file: File,
+    error: Option<io::Error>,
+}
+
+impl SpillingStore {
+    // Keeps roughly `memory_budget` bytes of transactions in memory, and at
+    // least one.
+    pub fn new(memory_budget: usize) -> io::Result<Self> {
+        Self::with_max_in_memory((memory_budget / BYTES_PER_TRANSACTION).max(1))
+    }
+
+    pub fn with_max_in_memory(max_hot: usize) -> io::Result<Self> {
+        Ok(Self {
+            clients_by_id: HashMap::new(),
+            hot: HashMap::new(),
+            recency: BTreeMap::new(),
+            uses: 0,
+            max_hot: max_hot.max(1),
+            cold: HashMap::new(),
+            free_slots: Vec::new(),
+            slot_count: 0,
+            file: tempfile::tempfile()?,
+            error: None,
+        })
+    }
+
+    // Makes sure the transaction is in memory if it exists at all, and marks
+    // it as just used. Returns whether it exists.
+    fn load(&mut self, transaction_id: TransactionID) -> bool {
+        if let Some((_, last_used)) = self.hot.get_mut(&transaction_id) {
+            self.recency.remove(last_used);
+            self.uses += 1;
+            *last_used = self.uses;
+            self.recency.insert(self.uses, transaction_id);
+            return true;
+        }
+
+        let Some(slot) = self.cold.remove(&transaction_id) else {
+            return false;
+        };
+        match self.read_slot(slot) {
+            Ok(transaction) => {
+                self.free_slots.push(slot);
+                self.insert_hot(transaction_id, transaction);
+                true
+            }
+            Err(e) => {
+                self.cold.insert(transaction_id, slot);
+                self.error.get_or_insert(e);
+                false
+            }
+        }
+    }
+
+    fn insert_hot(&mut self, transaction_id: TransactionID, transaction: Transaction) {
+        while self.hot.len() >= self.max_hot {
+            if let Err(e) = self.spill_least_recently_used() {
+                // better to go over the budget than to lose a transaction
+                self.error.get_or_insert(e);
+                break;
+            }
+        }
+
+        self.uses += 1;
+        self.hot.insert(transaction_id, (transaction, self.uses));
+        self.recency.insert(self.uses, transaction_id);
+    }
+
+    fn spill_least_recently_used(&mut self) -> io::Result<()> {
+        let Some((&last_used, &transaction_id)) = self.recency.iter().next() else {
+            return Ok(());
+        };
+        let Some((transaction, _)) = self.hot.remove(&transaction_id) else {
+            return Ok(());
+        };
+        let slot = self.free_slots.pop().unwrap_or(self.slot_count);
+        if let Err(e) = self.write_slot(slot, &transaction) {
+            if slot != self.slot_count {
+                self.free_slots.push(slot);
+            }
+            self.hot.insert(transaction_id, (transaction, last_used));
+            return Err(e);
+        }
+
+        if slot == self.slot_count {
+            self.slot_count += 1;
+        }
+        self.recency.remove(&last_used);
+        self.cold.insert(transaction_id, slot);
+        Ok(())
+    }
+
+    fn slot_offset(slot: u64) -> u64 {
+        slot * Transaction::ENCODED_LEN as u64
+    }
+
+    fn write_slot(&mut self, slot: u64, transaction: &Transaction) -> io::Result<()> {
+        self.file.seek(SeekFrom::Start(Self::slot_offset(slot)))?;
+        self.file.write_all(&transaction.to_bytes())
+    }
+
+    fn read_slot(&mut self, slot: u64) -> io::Result<Transaction> {
+        let mut bytes = [0; Transaction::ENCODED_LEN];
+        self.file.seek(SeekFrom::Start(Self::slot_offset(slot)))?;
+        self.file.read_exact(&mut bytes)?;
+        Transaction::from_bytes(&bytes).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Spilled transaction is corrupt.",
+            )
+        })
+    }
+}
+
+impl StateStore for SpillingStore {
+    fn clients(&self) -> &HashMap<ClientID, Client> {
+        &self.clients_by_id
+    }
+
+    fn clients_mut(&mut self) -> &mut HashMap<ClientID, Client> {
+        &mut self.clients_by_id
+    }
+
+    fn contains_transaction(&self, transaction_id: TransactionID) -> bool {
+        self.hot.contains_key(&transaction_id) || self.cold.contains_key(&transaction_id)
+    }
+
+    fn transaction_count(&self) -> usize {
+        self.hot.len() + self.cold.len()
+    }
+
+    fn transaction_mut(&mut self, transaction_id: TransactionID) -> Option<&mut Transaction> {
+        if !self.load(transaction_id) {
+            return None;
+        }
+        self.hot
+            .get_mut(&transaction_id)
+            .map(|(transaction, _)| transaction)
+    }
+
+    fn transaction_and_clients_mut(
+        &mut self,
+        transaction_id: TransactionID,
+    ) -> (Option<&mut Transaction>, &mut HashMap<ClientID, Client>) {
+        if !self.load(transaction_id) {
+            return (None, &mut self.clients_by_id);
+        }
+        (
+            self.hot
+                .get_mut(&transaction_id)
+                .map(|(transaction, _)| transaction),
+            &mut self.clients_by_id,
+        )
+    }
+
+    fn insert_transaction(&mut self, transaction_id: TransactionID, transaction: Transaction) {
+        if let Some(slot) = self.cold.remove(&transaction_id) {
+            self.free_slots.push(slot);
+        }
+        if let Some((_, last_used)) = self.hot.remove(&transaction_id) {
+            self.recency.remove(&last_used);
+        }
+        self.insert_hot(transaction_id, transaction);
+    }
+
+    // Spilled transactions are read back one at a time and left where they
+    // are, so this doesn't disturb what's in memory.
+    fn for_each_transaction(
+        &mut self,
+        f: &mut dyn FnMut(TransactionID, &Transaction) -> io::Result<()>,
+    ) -> io::Result<()> {
+        for (transaction_id, (transaction, _)) in &self.hot {
+            f(*transaction_id, transaction)?;
+        }
+        let cold = self
+            .cold
+            .iter()
+            .map(|(transaction_id, slot)| (*transaction_id, *slot))
+            .collect::<Vec<_>>();
+        for (transaction_id, slot) in cold {
+            f(transaction_id, &self.read_slot(slot)?)?;
+        }
+        Ok(())
+    }
+
+    fn take_error(&mut self) -> Option<io::Error> {
+        self.error.take()
+    }
+
+    // The reports need every transaction, so this loads all of them back into
+    // memory.
+    fn into_maps(
+        mut self: Box<Self>,
+    ) -> io::Result<(
+        HashMap<ClientID, Client>,
+        HashMap<TransactionID, Transaction>,
+    )> {
+        if let Some(e) = self.error.take() {
+            return Err(e);
+        }
+
+        let mut transactions_by_id = mem::take(&mut self.hot)
+            .into_iter()
+            .map(|(transaction_id, (transaction, _))| (transaction_id, transaction))
+            .collect::<HashMap<_, _>>();
+        for (transaction_id, slot) in mem::take(&mut self.cold) {
+            transactions_by_id.insert(transaction_id, self.read_slot(slot)?);
+        }
+
+        Ok((self.clients_by_id, transactions_by_id))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::model::{Currency, DisputeHistory, DisputeStatus, TransactionKind};
+    use pretty_assertions::assert_eq;
+    use rust_decimal_macros::dec;
+
+    fn deposit(client_id: ClientID) -> Transaction {
+        Transaction::new(
+            client_id,
+            Currency::default(),
+            dec!(1.5),
+            TransactionKind::Deposit,
+        )
+    }
+
+    #[test]
+    fn test_spilling_store() {
+        let mut store =
+            SpillingStore::with_max_in_memory(2).expect("Failed to create spilling store");
+        for transaction_id in 1..=5 {
+            store.insert_transaction(transaction_id, deposit(transaction_id as ClientID));
+        }
+        assert_eq!(2, store.hot.len());
+        assert_eq!(3, store.cold.len());
+        assert!((1..=5).all(|transaction_id| store.contains_transaction(transaction_id)));
+        assert!(!store.contains_transaction(6));
+
+        // loading a spilled transaction spills another to make room, into the
+        // slot it came out of
+        let transaction = store.transaction_mut(1).expect("Expected transaction 1");
+        transaction.dispute(dec!(1.5), 6);
+        transaction.resolve(7);
+        assert_eq!(2, store.hot.len());
+        assert_eq!(3, store.slot_count);
+        assert!(store.hot.contains_key(&1));
+
+        // and it comes back as it was left
+        for transaction_id in 2..=5 {
+            store.transaction_mut(transaction_id);
+        }
+        assert!(!store.hot.contains_key(&1));
+        let transaction = store.transaction_mut(1).expect("Expected transaction 1");
+        assert_eq!(DisputeStatus::Undisputed, transaction.dispute_status());
+        assert_eq!(
+            DisputeHistory {
+                disputed_at: Some(6),
+                resolved_at: Some(7),
+                charged_back_at: None,
+            },
+            transaction.dispute_history()
+        );
+        assert_eq!(1, transaction.client_id());
+        assert_eq!(dec!(1.5), transaction.amount());
+        assert!(store.transaction_mut(6).is_none());
+        assert!(store.take_error().is_none());
+
+        let (_, transactions_by_id) = Box::new(store).into_maps().expect("Expected no errors.");
+        let mut client_ids = transactions_by_id
+            .iter()
+            .map(|(transaction_id, transaction)| (*transaction_id, transaction.client_id()))
+            .collect::<Vec<_>>();
+        client_ids.sort();
+        assert_eq!(vec![(1, 1), (2, 2), (3, 3), (4, 4), (5, 5)], client_ids);
+    }
+}
@@ -0,0 +1,186 @@
+use super::processing::increment;
+use crate::{
+    format::error::ParseError,
+    model::{Amount, Event, SourcedEvent, TransactionID},
+};
+
+use std::collections::HashSet;
+
+// The upper bounds of the amount buckets (in the unnamed unit, whatever the
+// currency), each ten times the last. Anything from the last one up goes in a
+// bucket of its own.
+pub const AMOUNT_BUCKET_BOUNDS: [u64; 7] = [1, 10, 100, 1_000, 10_000, 100_000, 1_000_000];
+
+// What's in an input, worked out without processing any of it, so that a run
+// can be sized (and anything odd about the input spotted) before it happens.
+#[derive(Debug, Default, PartialEq)]
+pub struct InputStats {
+    pub events: u64,
+    // in the order each kind was first seen, like `EventCounts`
+    pub events_by_kind: Vec<(&'static str, u64)>,
+    // Rows that couldn't be parsed. These would abort a run, but here they're
+    // counted and skipped.
+    pub malformed: u64,
+    pub clients: usize,
+    pub transaction_ids: Option<(TransactionID, TransactionID)>,
+    pub amounts: AmountStats,
+}
+
+// Amounts are taken as they are, i.e. a debit adjustment's is negative, but
+// they're bucketed by their size.
+#[derive(Debug, Default, PartialEq)]
+pub struct AmountStats {
+    pub count: u64,
+    pub min: Option<Amount>,
+    pub max: Option<Amount>,
+    pub sum: Amount,
+    // one per bound in `AMOUNT_BUCKET_BOUNDS`, plus one for everything above
+    pub buckets: [u64; AMOUNT_BUCKET_BOUNDS.len() + 1],
+}
+
+impl AmountStats {
+    pub fn mean(&self) -> Option<Amount> {
+        (self.count > 0).then(|| self.sum / Amount::from(self.count))
+    }
+
+    fn add(&mut self, amount: Amount) {
+        self.count += 1;
+        self.min = Some(self.min.map_or(amount, |min| min.min(amount)));
+        self.max = Some(self.max.map_or(amount, |max| max.max(amount)));
+        self.sum += amount;
+        let bucket = AMOUNT_BUCKET_BOUNDS
+            .iter()
+            .position(|bound| amount.abs() < Amount::from(*bound))
+            .unwrap_or(AMOUNT_BUCKET_BOUNDS.len());
+        self.buckets[bucket] += 1;
+    }
+}
+
+// Goes through the events once, keeping only the client IDs as it goes, which
+// is a few hundred KiB at most. Rows that fail to parse are counted rather than
+// stopping it, but anything else (e.g. the input not being readable) does.
+pub fn input_stats(
+    events: impl Iterator<Item = Result<SourcedEvent, ParseError>>,
+) -> Result<InputStats, ParseError> {
+    let mut stats = InputStats::default();
+    let mut client_ids = HashSet::new();
+
+    for event in events {
+        let event = match event {
+            Ok(event) => event.event,
+            Err(e) if e.is_malformed_row() => {
+                stats.malformed += 1;
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+
+        stats.events += 1;
+        increment(&mut stats.events_by_kind, event.kind_name());
+        client_ids.extend(event.client_ids().into_iter().flatten());
+        let (transaction_id, amount) = describe(&event);
+        if let Some(transaction_id) = transaction_id {
+            stats.transaction_ids = Some(match stats.transaction_ids {
+                Some((min, max)) => (min.min(transaction_id), max.max(transaction_id)),
+                None => (transaction_id, transaction_id),
+            });
+        }
+        if let Some(amount) = amount {
+            stats.amounts.add(amount);
+        }
+    }
+
+    stats.clients = client_ids.len();
+    Ok(stats)
+}
+
+// The event's transaction ID if it has one, and its amount if it has one.
+fn describe(event: &Event) -> (Option<TransactionID>, Option<Amount>) {
+    match *event {
+        Event::Transaction {
+            transaction_id,
+            amount,
+            ..
+        }
+        | Event::PendingDeposit {
+            transaction_id,
+            amount,
+            ..
+        }
+        | Event::Fee {
+            transaction_id,
+            amount,
+            ..
+        }
+        | Event::Conversion {
+            transaction_id,
+            amount,
+            ..
+        }
+        | Event::Adjustment {
+            transaction_id,
+            amount,
+            ..
+        }
+        | Event::Transfer {
+            transaction_id,
+            amount,
+            ..
+        } => (Some(transaction_id), Some(amount)),
+        Event::DisputeStep {
+            transaction_id,
+            amount,
+            ..
+        } => (Some(transaction_id), amount),
+        Event::Settlement { transaction_id, .. }
+        | Event::Reversal { transaction_id, .. }
+        | Event::ChargebackReversal { transaction_id, .. }
+        | Event::Interest { transaction_id, .. } => (Some(transaction_id), None),
+        Event::AccountClosure { .. } | Event::ClientRegistration { .. } => (None, None),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::format::csv::input::parse_events;
+    use pretty_assertions::assert_eq;
+    use rust_decimal_macros::dec;
+
+    #[test]
+    fn test_input_stats() {
+        let input = concat!(
+            "type,client,tx,amount,to_client\n",
+            "deposit,1,5,2000,\n",
+            "deposit,2,3,0.5,\n",
+            "withdrawal,1,9,abc,\n",
+            "transfer,2,4,15,3\n",
+            "dispute,1,5,,\n",
+            "deposit,1,6,1,2,extra\n",
+        );
+
+        let stats = input_stats(parse_events(input.as_bytes())).expect("Expected no errors.");
+
+        assert_eq!(
+            InputStats {
+                events: 4,
+                events_by_kind: vec![("deposit", 2), ("transfer", 1), ("dispute", 1)],
+                malformed: 2,
+                clients: 3,
+                transaction_ids: Some((3, 5)),
+                amounts: AmountStats {
+                    count: 3,
+                    min: Some(dec!(0.5)),
+                    max: Some(dec!(2000)),
+                    sum: dec!(2015.5),
+                    buckets: [1, 0, 1, 0, 1, 0, 0, 0],
+                },
+            },
+            stats
+        );
+        assert_eq!(
+            Some(dec!(671.83333333333333333333333333)),
+            stats.amounts.mean()
+        );
+    }
+}
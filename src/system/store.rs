@@ -0,0 +1,251 @@
+use crate::model::{Client, ClientID, Transaction, TransactionID};
+
+use std::{collections::HashMap, io};
+
+// Where the processor keeps its clients and transactions, so that they can be
+// kept somewhere other than in memory (e.g. a database) without touching the
+// processing logic. There can be at most as many clients as there are client
+// IDs, which comfortably fits in memory, so every store hands them over as a
+// map; it's the transactions, which are only bounded by the input, that a
+// store gets to keep however it likes.
+pub trait StateStore {
+    fn clients(&self) -> &HashMap<ClientID, Client>;
+
+    fn clients_mut(&mut self) -> &mut HashMap<ClientID, Client>;
+
+    fn contains_transaction(&self, transaction_id: TransactionID) -> bool;
+
+    fn transaction_count(&self) -> usize;
+
+    // Finding a transaction may mean loading it from wherever it's kept, hence
+    // needing `&mut self` even just to look at it.
+    fn transaction_mut(&mut self, transaction_id: TransactionID) -> Option<&mut Transaction>;
+
+    // For when the transaction and its client both need changing at once.
+    fn transaction_and_clients_mut(
+        &mut self,
+        transaction_id: TransactionID,
+    ) -> (Option<&mut Transaction>, &mut HashMap<ClientID, Client>);
+
+    fn insert_transaction(&mut self, transaction_id: TransactionID, transaction: Transaction);
+
+    // Visits every transaction without handing them all over at once, e.g. to
+    // snapshot them. Stops at the first error.
+    fn for_each_transaction(
+        &mut self,
+        f: &mut dyn FnMut(TransactionID, &Transaction) -> io::Result<()>,
+    ) -> io::Result<()>;
+
+    // A store that can fail (e.g. one backed by a file) has nowhere to report
+    // that from the methods above, so it holds onto the error until this is
+    // called, which happens after every event. Whatever was being processed
+    // at the time will probably have been rejected too, but the run stops
+    // there anyway.
+    fn take_error(&mut self) -> Option<io::Error> {
+        None
+    }
+
+    // A store that keeps its clients somewhere else as well as in the map
+    // (e.g. Redis) says so here, and is then told which clients each change
+    // could have touched, straight after it's been made. Working that out
+    // costs a lookup or two, so the other stores don't ask.
+    fn mirrors_clients(&self) -> bool {
+        false
+    }
+
+    fn clients_changed(&mut self, _client_ids: &[ClientID]) {}
+
+    // Hands everything over once processing is done, e.g. for the reports.
+    #[allow(clippy::type_complexity)]
+    fn into_maps(
+        self: Box<Self>,
+    ) -> io::Result<(
+        HashMap<ClientID, Client>,
+        HashMap<TransactionID, Transaction>,
+    )>;
+}
+
+// The default store, which keeps everything in memory.
+#[derive(Default)]
+pub struct MemoryStore {
+    clients_by_id: HashMap<ClientID, Client>,
+    transactions_by_id: HashMap<TransactionID, Transaction>,
+}
+
+impl StateStore for MemoryStore {
+    fn clients(&self) -> &HashMap<ClientID, Client> {
+        &self.clients_by_id
+    }
+
+    fn clients_mut(&mut self) -> &mut HashMap<ClientID, Client> {
+        &mut self.clients_by_id
+    }
+
+    fn contains_transaction(&self, transaction_id: TransactionID) -> bool {
+        self.transactions_by_id.contains_key(&transaction_id)
+    }
+
+    fn transaction_count(&self) -> usize {
+        self.transactions_by_id.len()
+    }
+
+    fn transaction_mut(&mut self, transaction_id: TransactionID) -> Option<&mut Transaction> {
+        self.transactions_by_id.get_mut(&transaction_id)
+    }
+
+    fn transaction_and_clients_mut(
+        &mut self,
+        transaction_id: TransactionID,
+    ) -> (Option<&mut Transaction>, &mut HashMap<ClientID, Client>) {
+        (
+            self.transactions_by_id.get_mut(&transaction_id),
+            &mut self.clients_by_id,
+        )
+    }
+
+    fn insert_transaction(&mut self, transaction_id: TransactionID, transaction: Transaction) {
+        self.transactions_by_id.insert(transaction_id, transaction);
+    }
+
+    fn for_each_transaction(
+        &mut self,
+        f: &mut dyn FnMut(TransactionID, &Transaction) -> io::Result<()>,
+    ) -> io::Result<()> {
+        self.transactions_by_id
+            .iter()
+            .try_for_each(|(transaction_id, transaction)| f(*transaction_id, transaction))
+    }
+
+    fn into_maps(
+        self: Box<Self>,
+    ) -> io::Result<(
+        HashMap<ClientID, Client>,
+        HashMap<TransactionID, Transaction>,
+    )> {
+        Ok((self.clients_by_id, self.transactions_by_id))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        model::{Currency, DisputeStepKind, Event, TransactionKind},
+        system::{EngineConfig, Processor},
+    };
+    use pretty_assertions::assert_eq;
+    use rust_decimal_macros::dec;
+    use std::{cell::RefCell, rc::Rc};
+
+    // Keeps everything in memory like the default, but records which
+    // transactions were looked up along the way.
+    struct RecordingStore {
+        store: MemoryStore,
+        lookups: Rc<RefCell<Vec<TransactionID>>>,
+    }
+
+    impl StateStore for RecordingStore {
+        fn clients(&self) -> &HashMap<ClientID, Client> {
+            self.store.clients()
+        }
+
+        fn clients_mut(&mut self) -> &mut HashMap<ClientID, Client> {
+            self.store.clients_mut()
+        }
+
+        fn contains_transaction(&self, transaction_id: TransactionID) -> bool {
+            self.store.contains_transaction(transaction_id)
+        }
+
+        fn transaction_count(&self) -> usize {
+            self.store.transaction_count()
+        }
+
+        fn transaction_mut(&mut self, transaction_id: TransactionID) -> Option<&mut Transaction> {
+            self.lookups.borrow_mut().push(transaction_id);
+            self.store.transaction_mut(transaction_id)
+        }
+
+        fn transaction_and_clients_mut(
+            &mut self,
+            transaction_id: TransactionID,
+        ) -> (Option<&mut Transaction>, &mut HashMap<ClientID, Client>) {
+            self.lookups.borrow_mut().push(transaction_id);
+            self.store.transaction_and_clients_mut(transaction_id)
+        }
+
+        fn insert_transaction(&mut self, transaction_id: TransactionID, transaction: Transaction) {
+            self.store.insert_transaction(transaction_id, transaction);
+        }
+
+        fn for_each_transaction(
+            &mut self,
+            f: &mut dyn FnMut(TransactionID, &Transaction) -> io::Result<()>,
+        ) -> io::Result<()> {
+            self.store.for_each_transaction(f)
+        }
+
+        fn into_maps(
+            self: Box<Self>,
+        ) -> io::Result<(
+            HashMap<ClientID, Client>,
+            HashMap<TransactionID, Transaction>,
+        )> {
+            Box::new(self.store).into_maps()
+        }
+    }
+
+    #[test]
+    fn test_custom_store() {
+        let lookups = Rc::new(RefCell::new(Vec::new()));
+        let store = RecordingStore {
+            store: MemoryStore::default(),
+            lookups: Rc::clone(&lookups),
+        };
+        let mut processor = Processor::with_store(&EngineConfig::default(), Box::new(store));
+
+        for transaction_id in 1..=2 {
+            processor
+                .process_event(
+                    Event::Transaction {
+                        kind: TransactionKind::Deposit,
+                        client_id: 1,
+                        transaction_id,
+                        currency: Currency::default(),
+                        amount: dec!(5),
+                        counterparty: None,
+                    },
+                    None,
+                    None,
+                    &mut (),
+                )
+                .expect("Expected no errors.");
+        }
+        processor
+            .process_event(
+                Event::DisputeStep {
+                    kind: DisputeStepKind::Dispute,
+                    client_id: 1,
+                    transaction_id: 2,
+                    amount: None,
+                },
+                None,
+                None,
+                &mut (),
+            )
+            .expect("Expected no errors.");
+
+        assert_eq!(vec![2], *lookups.borrow());
+
+        let final_state = processor
+            .into_final_state(Default::default())
+            .expect("Expected no errors.");
+        assert_eq!(2, final_state.transactions_by_id.len());
+        assert_eq!(
+            dec!(5),
+            final_state.clients_by_id[&1]
+                .balance(Currency::default())
+                .held()
+        );
+    }
+}
@@ -0,0 +1,104 @@
+use super::EventCounts;
+
+use std::{fmt, str::FromStr};
+
+// How many rejected events we tolerate before calling a run unclean, either as
+// an absolute number or as a percentage of the events processed. The report is
+// still produced either way; this only decides whether the run counts as a
+// success, so that whatever runs us can tell a clean run from a garbage-in one.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RejectionThreshold {
+    Count(u64),
+    Percent(f64),
+}
+
+impl RejectionThreshold {
+    pub fn is_exceeded(&self, event_counts: &EventCounts) -> bool {
+        match *self {
+            RejectionThreshold::Count(count) => event_counts.rejected > count,
+            RejectionThreshold::Percent(percent) => {
+                // no events means nothing was rejected, rather than dividing by
+                // zero
+                event_counts.processed > 0
+                    && event_counts.rejected as f64 * 100.0 / event_counts.processed as f64
+                        > percent
+            }
+        }
+    }
+}
+
+// Parses either `N` or `N%`, e.g. `10` or `2.5%`.
+impl FromStr for RejectionThreshold {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid rejection threshold: {}.", s);
+
+        match s.strip_suffix('%') {
+            Some(percent) => {
+                let percent = percent.trim().parse::<f64>().map_err(|_| invalid())?;
+                if !(0.0..=100.0).contains(&percent) {
+                    return Err(invalid());
+                }
+                Ok(RejectionThreshold::Percent(percent))
+            }
+            None => Ok(RejectionThreshold::Count(
+                s.trim().parse().map_err(|_| invalid())?,
+            )),
+        }
+    }
+}
+
+impl fmt::Display for RejectionThreshold {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RejectionThreshold::Count(count) => write!(f, "{}", count),
+            RejectionThreshold::Percent(percent) => write!(f, "{}%", percent),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn counts(processed: u64, rejected: u64) -> EventCounts {
+        EventCounts {
+            processed,
+            rejected,
+            ..EventCounts::default()
+        }
+    }
+
+    #[test]
+    fn test_from_str() {
+        assert_eq!(Ok(RejectionThreshold::Count(10)), "10".parse());
+        assert_eq!(Ok(RejectionThreshold::Percent(2.5)), "2.5%".parse());
+        assert_eq!(
+            Err(String::from("Invalid rejection threshold: ten.")),
+            "ten".parse::<RejectionThreshold>()
+        );
+        assert_eq!(
+            Err(String::from("Invalid rejection threshold: 150%.")),
+            "150%".parse::<RejectionThreshold>()
+        );
+    }
+
+    #[test]
+    fn test_count_threshold() {
+        let threshold = RejectionThreshold::Count(2);
+
+        assert!(!threshold.is_exceeded(&counts(10, 2)));
+        assert!(threshold.is_exceeded(&counts(10, 3)));
+    }
+
+    #[test]
+    fn test_percent_threshold() {
+        let threshold = RejectionThreshold::Percent(10.0);
+
+        assert!(!threshold.is_exceeded(&counts(0, 0)));
+        assert!(!threshold.is_exceeded(&counts(20, 2)));
+        assert!(threshold.is_exceeded(&counts(20, 3)));
+    }
+}
@@ -0,0 +1,248 @@
+// POSTs a notification to a webhook whenever a chargeback's processed or an
+// account's locked (see `format::json::notification`), so that fraud ops can
+// be paged rather than find out from the next report. Notifications are sent
+// from a thread of their own, so processing only waits on the webhook if it's
+// fallen so far behind that the queue's full, and a notification that still
+// can't be delivered after the retries is logged and given up on rather than
+// failing the run. Dropping the notifier waits for whatever's queued to go.
+
+use challenge::{
+    format::{
+        json::notification::{write_notification, Notification, NotificationKind},
+        ReportConfig,
+    },
+    model::{Client, ClientID, Timestamp, Transaction, TransactionID},
+    system::ProcessorObserver,
+};
+use clap::Args;
+use log::{debug, warn};
+use std::{
+    error::Error,
+    sync::mpsc::{self, Receiver, SyncSender},
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+// How many notifications can be waiting to be sent before processing waits
+// for them.
+const QUEUE_LEN: usize = 1024;
+
+// How long a single attempt gets before it counts as failed.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Args)]
+pub struct WebhookOptions {
+    #[arg(
+        long,
+        value_name = "URL",
+        help = "POST a JSON notification here for each chargeback and locked account.",
+        help_heading = "Notifications"
+    )]
+    webhook_url: Option<String>,
+    #[arg(
+        long,
+        value_name = "N",
+        default_value = "5",
+        help = "How many times to retry a notification the webhook didn't take.",
+        help_heading = "Notifications"
+    )]
+    webhook_retries: u32,
+    #[arg(
+        long,
+        value_name = "MS",
+        default_value = "500",
+        help = "How long to wait before the first retry, doubling each time after that.",
+        help_heading = "Notifications"
+    )]
+    webhook_backoff: u64,
+}
+
+impl WebhookOptions {
+    // The notifier, if there's a webhook to notify. Amounts are written at the
+    // report's scale, so that they match what the report says.
+    pub fn notifier(&self, report_config: &ReportConfig) -> Option<WebhookNotifier> {
+        let url = self.webhook_url.clone()?;
+        Some(WebhookNotifier::new(
+            url,
+            self.webhook_retries,
+            Duration::from_millis(self.webhook_backoff),
+            report_config.clone(),
+        ))
+    }
+
+    // Nothing's sent, e.g. for a dry run.
+    pub fn disable(&mut self) {
+        self.webhook_url = None;
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.webhook_url.is_some()
+    }
+}
+
+pub struct WebhookNotifier {
+    sender: Option<SyncSender<Vec<u8>>>,
+    sender_thread: Option<JoinHandle<()>>,
+    report_config: ReportConfig,
+    // about the event that's being processed
+    event_number: u64,
+    timestamp: Option<Timestamp>,
+}
+
+impl WebhookNotifier {
+    fn new(url: String, retries: u32, backoff: Duration, report_config: ReportConfig) -> Self {
+        let (sender, receiver) = mpsc::sync_channel(QUEUE_LEN);
+        let sender_thread = thread::spawn(move || send_all(receiver, &url, retries, backoff));
+        Self {
+            sender: Some(sender),
+            sender_thread: Some(sender_thread),
+            report_config,
+            event_number: 0,
+            timestamp: None,
+        }
+    }
+
+    fn notify(&mut self, kind: NotificationKind) {
+        let notification = Notification {
+            event_number: self.event_number,
+            timestamp: self.timestamp,
+            kind,
+        };
+        let mut payload = Vec::new();
+        if let Err(e) = write_notification(&notification, &mut payload, &self.report_config) {
+            warn!("Couldn't write a webhook notification: {}", e);
+            return;
+        }
+        // the sender thread only stops once this does
+        if let Some(sender) = &self.sender {
+            let _ = sender.send(payload);
+        }
+    }
+}
+
+impl ProcessorObserver for WebhookNotifier {
+    fn on_event(&mut self, event_number: u64, timestamp: Option<Timestamp>) {
+        self.event_number = event_number;
+        self.timestamp = timestamp;
+    }
+
+    fn on_chargeback(
+        &mut self,
+        client_id: ClientID,
+        transaction_id: TransactionID,
+        transaction: &Transaction,
+    ) {
+        self.notify(NotificationKind::Chargeback {
+            client_id,
+            transaction_id,
+            transaction,
+        });
+    }
+
+    fn on_lock(&mut self, client_id: ClientID, client: &Client) {
+        self.notify(NotificationKind::AccountLocked { client_id, client });
+    }
+}
+
+impl Drop for WebhookNotifier {
+    fn drop(&mut self) {
+        self.sender = None;
+        if let Some(sender_thread) = self.sender_thread.take() {
+            let _ = sender_thread.join();
+        }
+    }
+}
+
+fn send_all(receiver: Receiver<Vec<u8>>, url: &str, retries: u32, backoff: Duration) {
+    let agent = ureq::AgentBuilder::new().timeout(REQUEST_TIMEOUT).build();
+    for payload in receiver {
+        if let Err(e) = send(&agent, url, &payload, retries, backoff) {
+            warn!(
+                "Gave up on a webhook notification: {} ({})",
+                e,
+                String::from_utf8_lossy(&payload)
+            );
+        }
+    }
+}
+
+// Retries whatever might go through on another go: anything that didn't get
+// an answer, and answers saying to try again later. A 4xx other than 429 is
+// taken to mean the notification itself is wrong, so it isn't retried.
+fn send(
+    agent: &ureq::Agent,
+    url: &str,
+    payload: &[u8],
+    retries: u32,
+    backoff: Duration,
+) -> Result<(), Box<dyn Error>> {
+    let mut wait = backoff;
+    let mut attempt = 0;
+    loop {
+        let result = agent
+            .post(url)
+            .set("Content-Type", "application/json")
+            .send_bytes(payload);
+        let retryable = match &result {
+            Ok(_) => return Ok(()),
+            Err(ureq::Error::Status(status, _)) => *status == 429 || *status >= 500,
+            Err(ureq::Error::Transport(_)) => true,
+        };
+        if !retryable || attempt == retries {
+            return result.map(|_| ()).map_err(Into::into);
+        }
+        debug!("Retrying a webhook notification in {}ms.", wait.as_millis());
+        thread::sleep(wait);
+        wait = wait.saturating_mul(2);
+        attempt += 1;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use challenge::model::{Currency, TransactionKind};
+    use pretty_assertions::assert_eq;
+    use rust_decimal_macros::dec;
+
+    #[test]
+    fn test_webhook_notifier() {
+        let server = tiny_http::Server::http("127.0.0.1:0").expect("Expected no errors.");
+        let url = format!("http://{}/", server.server_addr());
+        let receiver = thread::spawn(move || {
+            let mut received = Vec::new();
+            // the first attempt fails, so the notification comes again
+            for status in [503, 200] {
+                let mut request = server.recv().expect("Expected a request");
+                let mut body = String::new();
+                request
+                    .as_reader()
+                    .read_to_string(&mut body)
+                    .expect("Expected no errors.");
+                received.push(body);
+                request
+                    .respond(tiny_http::Response::empty(status))
+                    .expect("Expected no errors.");
+            }
+            received
+        });
+
+        // amounts are at the report's scale
+        let report_config = ReportConfig {
+            scale: 2,
+            ..ReportConfig::default()
+        };
+        let mut notifier = WebhookNotifier::new(url, 1, Duration::from_millis(1), report_config);
+        let transaction =
+            Transaction::new(1, Currency::default(), dec!(2), TransactionKind::Deposit);
+        notifier.on_event(4, None);
+        notifier.on_chargeback(1, 9, &transaction);
+        drop(notifier);
+
+        let received = receiver.join().expect("Expected no panics.");
+        assert_eq!(2, received.len());
+        assert_eq!(received[0], received[1]);
+        assert!(received[0].starts_with(r#"{"type":"chargeback","event":4,"#));
+        assert!(received[0].contains(r#""amount":"2.00","#));
+    }
+}
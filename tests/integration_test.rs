@@ -1,9 +1,9 @@
 extern crate challenge;
 
-use assert_cmd::Command;
+use assert_cmd::{cargo::CommandCargoExt, Command};
 use pretty_assertions::assert_eq;
 
-use std::fs;
+use std::{fs, process::Stdio};
 
 #[test]
 fn test_successful_run() {
@@ -18,8 +18,8 @@ fn test_successful_run() {
     );
     let expected_output = concat!(
         "client,available,held,total,locked\n",
-        "1,1.61111,0,1.61111,false\n",
-        "2,2.0,0,2.0,false\n"
+        "1,1.6111,0.0000,1.6111,false\n",
+        "2,2.0000,0.0000,2.0000,false\n"
     );
     let tmp_file = tempfile::NamedTempFile::new().expect("Failed to create temp file");
     fs::write(tmp_file.path(), input).expect("Failed to write to temp file");
@@ -51,6 +51,21 @@ fn test_invalid_args() {
     );
 }
 
+#[test]
+fn test_help() {
+    let mut cmd = Command::cargo_bin("challenge").expect("Expected to find binary");
+    let output = cmd.arg("--help").output().expect("Expected no errors");
+
+    assert_eq!(Some(0), output.status.code());
+
+    let output_str = String::from_utf8(output.stdout).expect("Not UTF-8");
+    assert!(
+        output_str.contains("--output-format <FORMAT>") && output_str.contains("diff"),
+        "Expected help message, got: {}",
+        output_str
+    );
+}
+
 #[test]
 fn test_file_not_found() {
     let mut cmd = Command::cargo_bin("challenge").expect("Expected to find binary");
@@ -92,3 +107,1633 @@ fn test_malformed_csv() {
     let output_str = String::from_utf8(output.stderr).expect("Not UTF-8");
     assert_eq!("Error: \"CSV error: record 1 (line: 2, byte: 26): found record with 5 fields, but the previous record has 4 fields\"\n",output_str);
 }
+
+#[test]
+fn test_json_output() {
+    let input = concat!(
+        "type,client,tx,    amount\n",
+        "deposit,1, 1, 1.11111\n",
+        "deposit,2,2,2.0\n",
+    );
+    let expected_output = concat!(
+        r#"[{"client":1,"available":"1.1111","held":"0.0000","total":"1.1111","locked":false},"#,
+        r#"{"client":2,"available":"2.0000","held":"0.0000","total":"2.0000","locked":false}]"#,
+        "\n",
+    );
+    let tmp_file = tempfile::NamedTempFile::new().expect("Failed to create temp file");
+    fs::write(tmp_file.path(), input).expect("Failed to write to temp file");
+
+    let mut cmd = Command::cargo_bin("challenge").expect("Expected to find binary");
+    let output = cmd
+        .arg("--output-format")
+        .arg("json")
+        .arg(tmp_file.path())
+        .output()
+        .expect("Expected no errors");
+
+    assert_eq!(Some(0), output.status.code());
+
+    let output_str = String::from_utf8(output.stdout).expect("Not UTF-8");
+    assert_eq!(expected_output, output_str);
+}
+
+#[test]
+fn test_sort_and_precision() {
+    let input = concat!(
+        "type,client,tx,amount\n",
+        "deposit,3,1,1.125\n",
+        "deposit,1,2,2\n",
+        "deposit,2,3,3.5\n",
+    );
+    let tmp_file = tempfile::NamedTempFile::new().expect("Failed to create temp file");
+    fs::write(tmp_file.path(), input).expect("Failed to write to temp file");
+
+    let run = |args: &[&str]| {
+        let mut cmd = Command::cargo_bin("challenge").expect("Expected to find binary");
+        let output = cmd
+            .args(args)
+            .arg(tmp_file.path())
+            .output()
+            .expect("Expected no errors");
+        assert_eq!(Some(0), output.status.code());
+        String::from_utf8(output.stdout).expect("Not UTF-8")
+    };
+
+    let sorted = run(&["--precision", "2", "--sort", "client_id"]);
+    assert_eq!(
+        concat!(
+            "client,available,held,total,locked\n",
+            "1,2.00,0.00,2.00,false\n",
+            "2,3.50,0.00,3.50,false\n",
+            "3,1.12,0.00,1.12,false\n",
+        ),
+        sorted
+    );
+
+    // the same rows in whatever order
+    let unsorted = run(&["--precision", "2", "--sort", "unsorted"]);
+    let mut unsorted_lines = unsorted.lines().collect::<Vec<_>>();
+    unsorted_lines[1..].sort_unstable();
+    assert_eq!(sorted.lines().collect::<Vec<_>>(), unsorted_lines);
+
+    assert!(run(&["--precision", "0"]).ends_with("3,1,0,1,false\n"));
+}
+
+#[test]
+fn test_json_lines() {
+    let input = concat!(
+        r#"{"type":"deposit","client":1,"tx":1,"amount":"1.5"}"#,
+        "\n",
+        r#"{"type":"deposit","client":2,"tx":2,"amount":"2"}"#,
+        "\n",
+        r#"{"type":"dispute","client":2,"tx":2}"#,
+        "\n",
+    );
+    let expected_output = concat!(
+        r#"{"client":1,"available":"1.5000","held":"0.0000","total":"1.5000","locked":false}"#,
+        "\n",
+        r#"{"client":2,"available":"0.0000","held":"2.0000","total":"2.0000","locked":false}"#,
+        "\n",
+    );
+    let tmp_file = tempfile::NamedTempFile::new().expect("Failed to create temp file");
+    fs::write(tmp_file.path(), input).expect("Failed to write to temp file");
+
+    let mut cmd = Command::cargo_bin("challenge").expect("Expected to find binary");
+    let output = cmd
+        .arg("--input-format")
+        .arg("jsonl")
+        .arg("--output-format")
+        .arg("jsonl")
+        .arg(tmp_file.path())
+        .output()
+        .expect("Expected no errors");
+
+    assert_eq!(Some(0), output.status.code());
+
+    let output_str = String::from_utf8(output.stdout).expect("Not UTF-8");
+    assert_eq!(expected_output, output_str);
+}
+
+#[test]
+fn test_config_file() {
+    let input = concat!(
+        "type,client,tx,amount\n",
+        "deposit,1,1,1.11111\n",
+        "withdrawal,1,2,5\n",
+    );
+    let tmp_file = tempfile::NamedTempFile::new().expect("Failed to create temp file");
+    fs::write(tmp_file.path(), input).expect("Failed to write to temp file");
+    let config_dir = tempfile::tempdir().expect("Failed to create temp dir");
+    let config_path = config_dir.path().join("challenge.toml");
+    fs::write(
+        &config_path,
+        concat!(
+            "output-format = \"jsonl\"\n",
+            "max-rejections = 0\n",
+            "errors = \"none\"\n",
+            "compact = true\n",
+            "pretty = false\n",
+        ),
+    )
+    .expect("Failed to write config");
+
+    let run = |args: &[&str]| {
+        Command::cargo_bin("challenge")
+            .expect("Expected to find binary")
+            .args(args)
+            .arg(tmp_file.path())
+            .output()
+            .expect("Expected no errors")
+    };
+
+    let expected_output = concat!(
+        r#"{"client":1,"available":"1.1111","held":"0.0000","total":"1.1111","locked":false}"#,
+        "\n"
+    );
+
+    let output = run(&["--config", config_path.to_str().expect("Not UTF-8")]);
+    assert_eq!(Some(2), output.status.code());
+    assert_eq!(
+        expected_output,
+        String::from_utf8(output.stdout).expect("Not UTF-8")
+    );
+    assert_eq!(
+        "Rejected 1 of 2 events, which is more than the 0 allowed.\n",
+        String::from_utf8(output.stderr).expect("Not UTF-8")
+    );
+
+    // the command line wins over the file
+    let config_arg = format!("--config={}", config_path.display());
+    let output = run(&["--max-rejections", "5", &config_arg, "--errors", "stderr"]);
+    assert_eq!(Some(0), output.status.code());
+    assert_eq!(
+        expected_output,
+        String::from_utf8(output.stdout).expect("Not UTF-8")
+    );
+    assert_eq!(
+        "line 3 (byte 42): Insufficient funds.\n",
+        String::from_utf8(output.stderr).expect("Not UTF-8")
+    );
+
+    for config in ["colour = true\n", "compact = 2\n", "not toml\n"] {
+        fs::write(&config_path, config).expect("Failed to write config");
+        let output = run(&[&config_arg]);
+        assert_eq!(Some(1), output.status.code());
+        let errors = String::from_utf8(output.stderr).expect("Not UTF-8");
+        assert!(errors.contains("config"), "{}", errors);
+    }
+}
+
+#[test]
+fn test_output_to_file() {
+    let input = concat!("type,client,tx,amount\n", "deposit,1,1,2.5\n");
+    let tmp_file = tempfile::NamedTempFile::new().expect("Failed to create temp file");
+    fs::write(tmp_file.path(), input).expect("Failed to write to temp file");
+    let output_dir = tempfile::tempdir().expect("Failed to create temp dir");
+    let output_path = output_dir.path().join("report.csv");
+
+    let mut cmd = Command::cargo_bin("challenge").expect("Expected to find binary");
+    let output = cmd
+        .arg("--output")
+        .arg(&output_path)
+        .arg(tmp_file.path())
+        .output()
+        .expect("Expected no errors");
+
+    assert_eq!(Some(0), output.status.code());
+    assert!(output.stdout.is_empty());
+
+    let report = fs::read_to_string(&output_path).expect("Expected report file");
+    assert_eq!(
+        concat!(
+            "client,available,held,total,locked\n",
+            "1,2.5000,0.0000,2.5000,false\n"
+        ),
+        report
+    );
+    // the temp file we wrote to should have been renamed, not left behind
+    assert_eq!(
+        1,
+        fs::read_dir(output_dir.path())
+            .expect("Failed to read dir")
+            .count()
+    );
+}
+
+// Run through a shell, which is the only way to give the binary a umask.
+#[cfg(unix)]
+fn run_with_umask(umask: &str, args: &[&std::ffi::OsStr]) -> std::process::Output {
+    std::process::Command::new("sh")
+        .arg("-c")
+        .arg(format!("umask {} && exec \"$0\" \"$@\"", umask))
+        .arg(assert_cmd::cargo::cargo_bin("challenge"))
+        .args(args)
+        .output()
+        .expect("Expected no errors")
+}
+
+#[cfg(unix)]
+#[test]
+fn test_output_file_mode() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let tmp_file = tempfile::NamedTempFile::new().expect("Failed to create temp file");
+    fs::write(tmp_file.path(), "type,client,tx,amount\ndeposit,1,1,2.5\n")
+        .expect("Failed to write to temp file");
+    let output_dir = tempfile::tempdir().expect("Failed to create temp dir");
+    let mode = |path: &std::path::Path| {
+        fs::metadata(path)
+            .expect("Expected report file")
+            .permissions()
+            .mode()
+            & 0o777
+    };
+
+    // a new file gets what the umask leaves, like any other
+    for (umask, expected_mode) in [("022", 0o644), ("027", 0o640)] {
+        let output_path = output_dir.path().join(format!("report-{}.csv", umask));
+        let output = run_with_umask(
+            umask,
+            &[
+                "--output".as_ref(),
+                output_path.as_ref(),
+                tmp_file.path().as_ref(),
+            ],
+        );
+        assert_eq!(Some(0), output.status.code());
+        assert_eq!(expected_mode, mode(&output_path), "umask {}", umask);
+    }
+
+    // and one that's replaced keeps its mode
+    let output_path = output_dir.path().join("report-022.csv");
+    fs::set_permissions(&output_path, fs::Permissions::from_mode(0o604))
+        .expect("Failed to set permissions");
+    let output = run_with_umask(
+        "022",
+        &[
+            "--output".as_ref(),
+            output_path.as_ref(),
+            tmp_file.path().as_ref(),
+        ],
+    );
+    assert_eq!(Some(0), output.status.code());
+    assert_eq!(0o604, mode(&output_path));
+}
+
+// node-exporter's textfile collector usually runs as a user of its own, so it
+// can only read the metrics if the umask lets it.
+#[cfg(unix)]
+#[test]
+fn test_metrics_file_mode() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let tmp_file = tempfile::NamedTempFile::new().expect("Failed to create temp file");
+    fs::write(tmp_file.path(), "type,client,tx,amount\ndeposit,1,1,2.5\n")
+        .expect("Failed to write to temp file");
+    let metrics_dir = tempfile::tempdir().expect("Failed to create temp dir");
+
+    for (umask, expected_mode) in [("022", 0o644), ("077", 0o600)] {
+        let metrics_path = metrics_dir.path().join(format!("challenge-{}.prom", umask));
+        let output = run_with_umask(
+            umask,
+            &[
+                "--metrics".as_ref(),
+                metrics_path.as_ref(),
+                tmp_file.path().as_ref(),
+            ],
+        );
+        assert_eq!(Some(0), output.status.code());
+        assert!(fs::read_to_string(&metrics_path)
+            .expect("Expected metrics file")
+            .contains("challenge_events_processed"));
+        assert_eq!(
+            expected_mode,
+            fs::metadata(&metrics_path)
+                .expect("Expected metrics file")
+                .permissions()
+                .mode()
+                & 0o777,
+            "umask {}",
+            umask
+        );
+    }
+}
+
+#[test]
+fn test_dispute_report() {
+    let input = concat!(
+        "type,client,tx,amount\n",
+        "deposit,1,1,10\n",
+        "deposit,1,2,20\n",
+        "dispute,1,1,\n",
+        "dispute,1,2,\n",
+        "chargeback,1,2,\n",
+    );
+    let tmp_file = tempfile::NamedTempFile::new().expect("Failed to create temp file");
+    fs::write(tmp_file.path(), input).expect("Failed to write to temp file");
+    let output_dir = tempfile::tempdir().expect("Failed to create temp dir");
+    let dispute_report_path = output_dir.path().join("disputes.csv");
+
+    let mut cmd = Command::cargo_bin("challenge").expect("Expected to find binary");
+    let output = cmd
+        .arg("--dispute-report")
+        .arg(&dispute_report_path)
+        .arg(tmp_file.path())
+        .output()
+        .expect("Expected no errors");
+
+    assert_eq!(Some(0), output.status.code());
+
+    let dispute_report = fs::read_to_string(&dispute_report_path).expect("Expected report file");
+    assert_eq!(
+        concat!(
+            "tx,client,type,amount,currency,status,disputed,charged_back,disputed_at,resolved_at,charged_back_at\n",
+            "1,1,deposit,10.0000,,disputed,10.0000,0.0000,3,,\n",
+            "2,1,deposit,20.0000,,charged_back,0.0000,20.0000,4,,5\n"
+        ),
+        dispute_report
+    );
+}
+
+#[test]
+fn test_counterparty_report() {
+    let input = concat!(
+        "type,client,tx,amount,counterparty\n",
+        "deposit,1,1,10,acme\n",
+        "deposit,1,2,20,acme\n",
+        "deposit,2,3,5,globex\n",
+        "deposit,2,4,5,\n",
+        "dispute,1,1,,\n",
+        "dispute,1,2,,\n",
+        "chargeback,1,2,,\n",
+    );
+    let tmp_file = tempfile::NamedTempFile::new().expect("Failed to create temp file");
+    fs::write(tmp_file.path(), input).expect("Failed to write to temp file");
+    let output_dir = tempfile::tempdir().expect("Failed to create temp dir");
+    let counterparty_report_path = output_dir.path().join("counterparties.csv");
+
+    let mut cmd = Command::cargo_bin("challenge").expect("Expected to find binary");
+    let output = cmd
+        .arg("--counterparty-report")
+        .arg(&counterparty_report_path)
+        .arg(tmp_file.path())
+        .output()
+        .expect("Expected no errors");
+
+    assert_eq!(Some(0), output.status.code());
+
+    let counterparty_report =
+        fs::read_to_string(&counterparty_report_path).expect("Expected report file");
+    assert_eq!(
+        concat!(
+            "counterparty,currency,transactions,volume,disputed_transactions,disputed,charged_back_transactions,charged_back\n",
+            "acme,,2,30.0000,2,10.0000,1,20.0000\n",
+            "globex,,1,5.0000,0,0.0000,0,0.0000\n"
+        ),
+        counterparty_report
+    );
+}
+
+#[test]
+fn test_client_profiles() {
+    let input = concat!(
+        "type,client,tx,amount,currency,name,tier\n",
+        "client,1,,,EUR,Ada Lovelace,gold\n",
+        "deposit,1,1,10,,,\n",
+        "deposit,2,2,5,,,\n",
+        "client,3,,,,Charles Babbage,\n",
+    );
+    let tmp_file = tempfile::NamedTempFile::new().expect("Failed to create temp file");
+    fs::write(tmp_file.path(), input).expect("Failed to write to temp file");
+
+    let mut cmd = Command::cargo_bin("challenge").expect("Expected to find binary");
+    let output = cmd
+        .arg("--columns")
+        .arg("client,name,tier,home_currency,available")
+        .arg(tmp_file.path())
+        .output()
+        .expect("Expected no errors");
+
+    assert_eq!(Some(0), output.status.code());
+    assert_eq!(
+        concat!(
+            "client,name,tier,home_currency,available\n",
+            "1,Ada Lovelace,gold,EUR,10.0000\n",
+            "2,,,,5.0000\n",
+            "3,Charles Babbage,,,0.0000\n",
+        ),
+        String::from_utf8_lossy(&output.stdout)
+    );
+}
+
+#[test]
+fn test_errors_to_stderr() {
+    let input = concat!(
+        "type,client,tx,amount\n",
+        "deposit,1,1,10\n",
+        "withdrawal,1,2,20\n",
+    );
+    let tmp_file = tempfile::NamedTempFile::new().expect("Failed to create temp file");
+    fs::write(tmp_file.path(), input).expect("Failed to write to temp file");
+
+    // stderr is the default
+    for (args, expected_errors) in [
+        (
+            vec!["--errors", "stderr"],
+            "line 3 (byte 37): Insufficient funds.\n",
+        ),
+        (vec![], "line 3 (byte 37): Insufficient funds.\n"),
+        (vec!["--errors", "none"], ""),
+    ] {
+        let mut cmd = Command::cargo_bin("challenge").expect("Expected to find binary");
+        let output = cmd
+            .args(args)
+            .arg(tmp_file.path())
+            .output()
+            .expect("Expected no errors");
+
+        assert_eq!(Some(0), output.status.code());
+
+        let errors = String::from_utf8(output.stderr).expect("Not UTF-8");
+        assert_eq!(expected_errors, errors);
+    }
+}
+
+#[test]
+fn test_verbosity() {
+    let input = concat!(
+        "type,client,tx,amount\n",
+        "deposit,1,1,10\n",
+        "withdrawal,1,2,20\n",
+    );
+    let tmp_file = tempfile::NamedTempFile::new().expect("Failed to create temp file");
+    fs::write(tmp_file.path(), input).expect("Failed to write to temp file");
+
+    let run = |args: &[&str]| {
+        let mut cmd = Command::cargo_bin("challenge").expect("Expected to find binary");
+        let output = cmd
+            .args(args)
+            .arg(tmp_file.path())
+            .output()
+            .expect("Expected no errors");
+        (
+            output.status.code(),
+            String::from_utf8(output.stderr).expect("Not UTF-8"),
+        )
+    };
+
+    // quiet leaves out the rejections as well, but not if they're asked for
+    assert_eq!((Some(2), String::new()), run(&["--quiet", "--strict"]));
+    assert_eq!(
+        (
+            Some(0),
+            String::from("line 3 (byte 37): Insufficient funds.\n")
+        ),
+        run(&["-q", "--errors", "stderr"])
+    );
+
+    // by default there's only the warning about the rejections
+    assert_eq!(
+        (
+            Some(2),
+            String::from(concat!(
+                "line 3 (byte 37): Insufficient funds.\n",
+                "Rejected 1 of 2 events, which is more than the 0 allowed.\n",
+            ))
+        ),
+        run(&["--strict"])
+    );
+
+    let (code, errors) = run(&["-v", "--errors", "none"]);
+    assert_eq!(Some(0), code);
+    assert!(
+        errors.starts_with("Processed 2 events, rejecting 1, in "),
+        "{}",
+        errors
+    );
+
+    assert_eq!(Some(1), run(&["-v", "--quiet"]).0);
+}
+
+#[test]
+fn test_profile() {
+    let tmp_file = tempfile::NamedTempFile::new().expect("Failed to create temp file");
+    fs::write(tmp_file.path(), "type,client,tx,amount\ndeposit,1,1,10\n")
+        .expect("Failed to write to temp file");
+
+    // even when quiet, since it was asked for
+    let mut cmd = Command::cargo_bin("challenge").expect("Expected to find binary");
+    let output = cmd
+        .arg("--profile")
+        .arg("--quiet")
+        .arg(tmp_file.path())
+        .output()
+        .expect("Expected no errors");
+    assert_eq!(Some(0), output.status.code());
+
+    let profile = String::from_utf8(output.stderr).expect("Not UTF-8");
+    let stages = profile
+        .lines()
+        .map(|line| line.split("  ").next().unwrap_or_default())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        vec![
+            "Reading",
+            "Parsing",
+            "Processing",
+            "Writing rejections",
+            "Writing reports",
+            "Total",
+            "Peak memory"
+        ],
+        stages
+    );
+}
+
+#[test]
+fn test_json_error_codes() {
+    let input = concat!(
+        "type,client,tx,amount\n",
+        "deposit,1,1,10\n",
+        "deposit,2,1,5\n",
+        "dispute,2,1,\n",
+        "resolve,1,1,\n",
+        "dispute,1,1,\n",
+        "dispute,1,1,\n",
+        "chargeback,1,1,\n",
+        "chargeback,1,1,\n",
+        "deposit,1,2,5\n",
+        "withdrawal,3,3,1\n",
+    );
+    let tmp_file = tempfile::NamedTempFile::new().expect("Failed to create temp file");
+    fs::write(tmp_file.path(), input).expect("Failed to write to temp file");
+    let output_dir = tempfile::tempdir().expect("Failed to create temp dir");
+    let errors_path = output_dir.path().join("errors.jsonl");
+
+    let mut cmd = Command::cargo_bin("challenge").expect("Expected to find binary");
+    let output = cmd
+        .arg("--errors")
+        .arg(&errors_path)
+        .arg("--error-format")
+        .arg("json")
+        .arg(tmp_file.path())
+        .output()
+        .expect("Expected no errors");
+
+    assert_eq!(Some(0), output.status.code());
+
+    let errors = fs::read_to_string(&errors_path).expect("Expected errors file");
+    let codes = errors
+        .lines()
+        .map(|line| {
+            let rejection: serde_json::Value =
+                serde_json::from_str(line).expect("Expected valid JSON");
+            (
+                rejection["line"].as_u64().expect("Expected a line"),
+                String::from(rejection["code"].as_str().expect("Expected a code")),
+            )
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(
+        vec![
+            (3, String::from("transaction_exists")),
+            (4, String::from("client_mismatch")),
+            (5, String::from("not_disputed")),
+            (7, String::from("already_disputed")),
+            (9, String::from("already_charged_back")),
+            (10, String::from("account_locked")),
+            (11, String::from("insufficient_funds")),
+        ],
+        codes
+    );
+}
+
+#[test]
+fn test_json_errors_to_file() {
+    let input = concat!(
+        "type,client,tx,amount\n",
+        "deposit,1,1,10\n",
+        "withdrawal,1,2,20\n",
+        "dispute,1,3,\n",
+    );
+    let tmp_file = tempfile::NamedTempFile::new().expect("Failed to create temp file");
+    fs::write(tmp_file.path(), input).expect("Failed to write to temp file");
+    let output_dir = tempfile::tempdir().expect("Failed to create temp dir");
+    let errors_path = output_dir.path().join("errors.jsonl");
+
+    let mut cmd = Command::cargo_bin("challenge").expect("Expected to find binary");
+    let output = cmd
+        .arg("--errors")
+        .arg(&errors_path)
+        .arg("--error-format")
+        .arg("json")
+        .arg(tmp_file.path())
+        .output()
+        .expect("Expected no errors");
+
+    assert_eq!(Some(0), output.status.code());
+    assert!(output.stderr.is_empty());
+
+    let errors = fs::read_to_string(&errors_path).expect("Expected errors file");
+    assert_eq!(
+        concat!(
+            r#"{"line":3,"byte":37,"record":"withdrawal,1,2,20","code":"insufficient_funds","message":"Insufficient funds."}"#,
+            "\n",
+            r#"{"line":4,"byte":55,"record":"dispute,1,3,","code":"transaction_not_found","message":"Transaction 3 not found."}"#,
+            "\n",
+        ),
+        errors
+    );
+}
+
+#[test]
+fn test_too_many_rejections() {
+    let input = concat!(
+        "type,client,tx,amount\n",
+        "deposit,1,1,10\n",
+        "withdrawal,1,2,20\n",
+        "withdrawal,1,3,30\n",
+        "deposit,2,4,5\n",
+    );
+    let expected_output = concat!(
+        "client,available,held,total,locked\n",
+        "1,10.0000,0.0000,10.0000,false\n",
+        "2,5.0000,0.0000,5.0000,false\n"
+    );
+    let tmp_file = tempfile::NamedTempFile::new().expect("Failed to create temp file");
+    fs::write(tmp_file.path(), input).expect("Failed to write to temp file");
+
+    for (threshold, expected_code) in [("2", 0), ("1", 2), ("50%", 0), ("25%", 2)] {
+        let mut cmd = Command::cargo_bin("challenge").expect("Expected to find binary");
+        let output = cmd
+            .arg("--max-rejections")
+            .arg(threshold)
+            .arg(tmp_file.path())
+            .output()
+            .expect("Expected no errors");
+
+        assert_eq!(Some(expected_code), output.status.code());
+
+        // the report is written regardless
+        let output_str = String::from_utf8(output.stdout).expect("Not UTF-8");
+        assert_eq!(expected_output, output_str);
+    }
+
+    let mut cmd = Command::cargo_bin("challenge").expect("Expected to find binary");
+    let output = cmd
+        .arg("--strict")
+        .arg(tmp_file.path())
+        .output()
+        .expect("Expected no errors");
+    assert_eq!(Some(2), output.status.code());
+}
+
+#[test]
+fn test_dry_run() {
+    let input = concat!(
+        "type,client,tx,amount\n",
+        "deposit,1,1,10\n",
+        "withdrawal,1,2,20\n",
+    );
+    let tmp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+    let input_path = tmp_dir.path().join("input.csv");
+    fs::write(&input_path, input).expect("Failed to write to temp file");
+    let output_path = tmp_dir.path().join("report.csv");
+    let journal_path = tmp_dir.path().join("journal");
+    let state_path = tmp_dir.path().join("state");
+    let manifest_path = tmp_dir.path().join("manifest.json");
+
+    let mut cmd = Command::cargo_bin("challenge").expect("Expected to find binary");
+    let output = cmd
+        .arg("--dry-run")
+        .arg("--strict")
+        .arg("--output")
+        .arg(&output_path)
+        .arg("--journal")
+        .arg(&journal_path)
+        .arg("--save-state")
+        .arg(&state_path)
+        .arg("--manifest")
+        .arg(&manifest_path)
+        .arg(&input_path)
+        .output()
+        .expect("Expected no errors");
+
+    // the rejections are reported and counted as usual...
+    assert_eq!(Some(2), output.status.code());
+    assert_eq!(
+        concat!(
+            "line 3 (byte 37): Insufficient funds.\n",
+            "Rejected 1 of 2 events, which is more than the 0 allowed.\n",
+        ),
+        String::from_utf8(output.stderr).expect("Not UTF-8")
+    );
+    // ...but nothing else is written
+    assert!(output.stdout.is_empty());
+    assert_eq!(
+        vec![String::from("input.csv")],
+        fs::read_dir(tmp_dir.path())
+            .expect("Expected to read temp dir")
+            .map(|entry| entry
+                .expect("Expected an entry")
+                .file_name()
+                .to_string_lossy()
+                .into_owned())
+            .collect::<Vec<_>>()
+    );
+}
+
+#[test]
+fn test_client_filter() {
+    let input = concat!(
+        "type,client,tx,amount,to_client\n",
+        "deposit,1,1,10,\n",
+        "deposit,2,2,20,\n",
+        "withdrawal,3,3,5,\n",
+        "transfer,2,4,5,1\n",
+        "dispute,1,1,,\n",
+    );
+    let tmp_file = tempfile::NamedTempFile::new().expect("Failed to create temp file");
+    fs::write(tmp_file.path(), input).expect("Failed to write to temp file");
+
+    // only the report is filtered, so client 3's rejection is still there...
+    let mut cmd = Command::cargo_bin("challenge").expect("Expected to find binary");
+    let output = cmd
+        .arg("--client")
+        .arg("1,4")
+        .arg(tmp_file.path())
+        .output()
+        .expect("Expected no errors");
+    assert_eq!(Some(0), output.status.code());
+    assert_eq!(
+        concat!(
+            "client,available,held,total,locked\n",
+            "1,5.0000,10.0000,15.0000,false\n",
+        ),
+        String::from_utf8(output.stdout).expect("Not UTF-8")
+    );
+    assert_eq!(
+        "line 4 (byte 64): Insufficient funds.\n",
+        String::from_utf8(output.stderr).expect("Not UTF-8")
+    );
+
+    // ...unless only their events are processed, in which case the transfer
+    // still is, since it names client 1
+    let mut cmd = Command::cargo_bin("challenge").expect("Expected to find binary");
+    let output = cmd
+        .arg("--client")
+        .arg("1,2")
+        .arg("--client-events-only")
+        .arg("--strict")
+        .arg(tmp_file.path())
+        .output()
+        .expect("Expected no errors");
+    assert_eq!(Some(0), output.status.code());
+    assert_eq!(
+        concat!(
+            "client,available,held,total,locked\n",
+            "1,5.0000,10.0000,15.0000,false\n",
+            "2,15.0000,0.0000,15.0000,false\n",
+        ),
+        String::from_utf8(output.stdout).expect("Not UTF-8")
+    );
+    assert!(output.stderr.is_empty());
+
+    let mut cmd = Command::cargo_bin("challenge").expect("Expected to find binary");
+    let output = cmd
+        .arg("--client-events-only")
+        .arg(tmp_file.path())
+        .output()
+        .expect("Expected no errors");
+    assert_eq!(Some(1), output.status.code());
+}
+
+#[test]
+fn test_diff() {
+    let old_report = concat!(
+        "client,available,held,total,locked\n",
+        "1,10.0000,0.0000,10.0000,false\n",
+        "2,5.0000,3.0000,8.0000,false\n",
+    );
+    let new_report = concat!(
+        "client,available,held,total,locked\n",
+        "1,10.0000,0.0000,10.0000,false\n",
+        "2,5.0000,0.0000,5.0000,true\n",
+        "3,1.5000,0.0000,1.5000,false\n",
+    );
+    let expected_output = concat!(
+        "client,available_change,held_change,total_change,newly_locked\n",
+        "2,0.0000,-3.0000,-3.0000,true\n",
+        "3,1.5000,0.0000,1.5000,false\n",
+    );
+    let old_file = tempfile::NamedTempFile::new().expect("Failed to create temp file");
+    fs::write(old_file.path(), old_report).expect("Failed to write to temp file");
+    let new_file = tempfile::NamedTempFile::new().expect("Failed to create temp file");
+    fs::write(new_file.path(), new_report).expect("Failed to write to temp file");
+
+    let mut cmd = Command::cargo_bin("challenge").expect("Expected to find binary");
+    let output = cmd
+        .arg("diff")
+        .arg(old_file.path())
+        .arg(new_file.path())
+        .output()
+        .expect("Expected no errors");
+
+    assert_eq!(Some(0), output.status.code());
+
+    let output_str = String::from_utf8(output.stdout).expect("Not UTF-8");
+    assert_eq!(expected_output, output_str);
+}
+
+#[test]
+fn test_stats() {
+    let input = concat!(
+        "type,client,tx,amount\n",
+        "deposit,1,1,10\n",
+        "deposit,2,2,0.5\n",
+        "withdrawal,1,3,oops\n",
+        "dispute,2,2,\n",
+    );
+    let tmp_file = tempfile::NamedTempFile::new().expect("Failed to create temp file");
+    fs::write(tmp_file.path(), input).expect("Failed to write to temp file");
+
+    let mut cmd = Command::cargo_bin("challenge").expect("Expected to find binary");
+    let output = cmd
+        .arg("stats")
+        .arg(tmp_file.path())
+        .output()
+        .expect("Expected no errors");
+
+    assert_eq!(Some(0), output.status.code());
+
+    let stats: serde_json::Value =
+        serde_json::from_slice(&output.stdout).expect("Expected valid JSON");
+    assert_eq!(3, stats["events"]);
+    assert_eq!(1, stats["malformed"]);
+    assert_eq!(2, stats["clients"]);
+    assert_eq!(
+        serde_json::json!({ "min": 1, "max": 2 }),
+        stats["transaction_ids"]
+    );
+    assert_eq!("0.5", stats["amounts"]["min"]);
+    assert_eq!("10", stats["amounts"]["max"]);
+}
+
+#[test]
+fn test_explain() {
+    let mut cmd = Command::cargo_bin("challenge").expect("Expected to find binary");
+    let output = cmd
+        .arg("explain")
+        .arg("dispute_window_expired")
+        .output()
+        .expect("Expected no errors");
+    assert_eq!(Some(0), output.status.code());
+    let explanation = String::from_utf8(output.stdout).expect("Not UTF-8");
+    assert!(
+        explanation.starts_with("dispute_window_expired: A dispute came in longer after"),
+        "{}",
+        explanation
+    );
+    assert!(explanation.contains("\nTypical causes:\n- "));
+
+    let mut cmd = Command::cargo_bin("challenge").expect("Expected to find binary");
+    let output = cmd.arg("explain").output().expect("Expected no errors");
+    let codes = String::from_utf8(output.stdout).expect("Not UTF-8");
+    let codes = codes
+        .lines()
+        .map(|line| line.split(' ').next().unwrap_or_default())
+        .collect::<Vec<_>>();
+    assert_eq!(Some(&"insufficient_funds"), codes.first());
+    assert!(codes.contains(&"reversal_after_partial_chargeback"));
+    assert!(codes.contains(&"dispute_window_expired"));
+
+    let mut cmd = Command::cargo_bin("challenge").expect("Expected to find binary");
+    let output = cmd
+        .arg("explain")
+        .arg("E011")
+        .output()
+        .expect("Expected no errors");
+    assert_eq!(Some(1), output.status.code());
+    assert!(String::from_utf8(output.stderr)
+        .expect("Not UTF-8")
+        .contains("Unknown rejection code: E011."));
+}
+
+#[test]
+fn test_query() {
+    let input = concat!(
+        "type,client,tx,amount\n",
+        "deposit,1,1,10\n",
+        "deposit,1,2,5\n",
+        "deposit,2,3,1\n",
+        "dispute,1,2,\n",
+    );
+    let tmp_file = tempfile::NamedTempFile::new().expect("Failed to create temp file");
+    fs::write(tmp_file.path(), input).expect("Failed to write to temp file");
+    let output_dir = tempfile::tempdir().expect("Failed to create temp dir");
+    let state_path = output_dir.path().join("state.bin");
+    let journal_path = output_dir.path().join("journal.bin");
+
+    let mut cmd = Command::cargo_bin("challenge").expect("Expected to find binary");
+    let output = cmd
+        .arg("--save-state")
+        .arg(&state_path)
+        .arg("--journal")
+        .arg(&journal_path)
+        .arg(tmp_file.path())
+        .output()
+        .expect("Expected no errors");
+    assert_eq!(Some(0), output.status.code());
+
+    // either one tells us the same thing
+    for (flag, path) in [("--state", &state_path), ("--journal", &journal_path)] {
+        let mut cmd = Command::cargo_bin("challenge").expect("Expected to find binary");
+        let output = cmd
+            .args(["query", "--client", "1", flag])
+            .arg(path)
+            .output()
+            .expect("Expected no errors");
+
+        assert_eq!(Some(0), output.status.code());
+
+        let output_str = String::from_utf8(output.stdout).expect("Not UTF-8");
+        assert_eq!(
+            concat!(
+                "client  available    held    total  locked\n",
+                "------  ---------  ------  -------  ------\n",
+                "     1    10.0000  5.0000  15.0000  false\n",
+                "\n",
+                "tx  type     currency  amount  disputed\n",
+                "--  -------  --------  ------  --------\n",
+                " 2  deposit            5.0000    5.0000\n",
+            ),
+            output_str
+        );
+    }
+
+    let mut cmd = Command::cargo_bin("challenge").expect("Expected to find binary");
+    let output = cmd
+        .args(["query", "--client", "2", "--state"])
+        .arg(&state_path)
+        .output()
+        .expect("Expected no errors");
+    let output_str = String::from_utf8(output.stdout).expect("Not UTF-8");
+    assert!(
+        output_str.ends_with("\nNo open disputes.\n"),
+        "{}",
+        output_str
+    );
+
+    let mut cmd = Command::cargo_bin("challenge").expect("Expected to find binary");
+    let output = cmd
+        .args(["query", "--client", "3", "--state"])
+        .arg(&state_path)
+        .output()
+        .expect("Expected no errors");
+    assert_eq!(Some(1), output.status.code());
+}
+
+#[test]
+fn test_snapshots() {
+    let input = concat!(
+        "type,client,tx,amount\n",
+        "deposit,1,1,10\n",
+        "deposit,1,2,10\n",
+        "deposit,1,3,10\n",
+        "deposit,1,4,10\n",
+        "deposit,1,5,10\n",
+    );
+    let tmp_file = tempfile::NamedTempFile::new().expect("Failed to create temp file");
+    fs::write(tmp_file.path(), input).expect("Failed to write to temp file");
+    let snapshot_dir = tempfile::tempdir().expect("Failed to create temp dir");
+
+    let mut cmd = Command::cargo_bin("challenge").expect("Expected to find binary");
+    let output = cmd
+        .arg("--snapshot-every")
+        .arg("2")
+        .arg("--snapshot-dir")
+        .arg(snapshot_dir.path())
+        .arg(tmp_file.path())
+        .output()
+        .expect("Expected no errors");
+
+    assert_eq!(Some(0), output.status.code());
+
+    // the file names start with a timestamp so we go by their contents instead
+    let mut snapshots = fs::read_dir(snapshot_dir.path())
+        .expect("Expected snapshot dir")
+        .map(|entry| {
+            fs::read_to_string(entry.expect("Expected entry").path()).expect("Expected snapshot")
+        })
+        .collect::<Vec<_>>();
+    snapshots.sort();
+    assert_eq!(
+        vec![
+            "client,available,held,total,locked\n1,20.0000,0.0000,20.0000,false\n",
+            "client,available,held,total,locked\n1,40.0000,0.0000,40.0000,false\n",
+        ],
+        snapshots
+    );
+}
+
+#[test]
+fn test_manifest() {
+    let input = concat!("type,client,tx,amount\n", "deposit,1,1,10\n");
+    let tmp_file = tempfile::NamedTempFile::new().expect("Failed to create temp file");
+    fs::write(tmp_file.path(), input).expect("Failed to write to temp file");
+    let output_dir = tempfile::tempdir().expect("Failed to create temp dir");
+    let manifest_path = output_dir.path().join("manifest.json");
+
+    let mut cmd = Command::cargo_bin("challenge").expect("Expected to find binary");
+    let output = cmd
+        .arg("--manifest")
+        .arg(&manifest_path)
+        .arg(tmp_file.path())
+        .output()
+        .expect("Expected no errors");
+
+    assert_eq!(Some(0), output.status.code());
+
+    let manifest: serde_json::Value =
+        serde_json::from_str(&fs::read_to_string(&manifest_path).expect("Expected manifest"))
+            .expect("Expected valid JSON");
+    // the sha256 of the input above
+    assert_eq!(
+        "b61a7bfa5a26ea6ba2b7bd31eee3dc834afd4de6ffa4608232f35d6955f481ac",
+        manifest["input"]["sha256"]
+    );
+    assert_eq!(1, manifest["rows"]["processed"]);
+
+    // the same state comes out with the same digest, however it was processed
+    let state_sha256 = "83bdb664bec0c63b5c8df1886f943b1538ddf0df108ec032e5e68ec7c986ad9d";
+    assert_eq!(state_sha256, manifest["state"]["sha256"]);
+    let output = Command::cargo_bin("challenge")
+        .expect("Expected to find binary")
+        .arg("--threads")
+        .arg("2")
+        .arg("--manifest")
+        .arg(&manifest_path)
+        .arg(tmp_file.path())
+        .output()
+        .expect("Expected no errors");
+    assert_eq!(Some(0), output.status.code());
+    let manifest: serde_json::Value =
+        serde_json::from_str(&fs::read_to_string(&manifest_path).expect("Expected manifest"))
+            .expect("Expected valid JSON");
+    assert_eq!(state_sha256, manifest["state"]["sha256"]);
+}
+
+#[test]
+fn test_threads() {
+    let input = concat!(
+        "type,client,tx,amount\n",
+        "deposit,1,1,10\n",
+        "deposit,2,2,20\n",
+        "withdrawal,1,3,4\n",
+    );
+    let tmp_file = tempfile::NamedTempFile::new().expect("Failed to create temp file");
+    fs::write(tmp_file.path(), input).expect("Failed to write to temp file");
+
+    let run = |args: &[&str]| {
+        Command::cargo_bin("challenge")
+            .expect("Expected to find binary")
+            .args(args)
+            .arg(tmp_file.path())
+            .output()
+            .expect("Expected no errors")
+    };
+    let journal_dir = tempfile::tempdir().expect("Failed to create temp dir");
+    let journal_path = journal_dir.path().join("journal");
+    let journal_path = journal_path.to_str().expect("Not UTF-8");
+
+    // however many threads auto comes to, which is the default, the report's
+    // the same, and it's the same again on the one thread options like
+    // --journal need
+    let expected = concat!(
+        "client,available,held,total,locked\n",
+        "1,6.0000,0.0000,6.0000,false\n",
+        "2,20.0000,0.0000,20.0000,false\n",
+    );
+    for args in [
+        &["--threads", "auto"][..],
+        &[],
+        &["--threads", "1"],
+        &["--threads", "3"],
+        &["--journal", journal_path],
+    ] {
+        let output = run(args);
+        assert_eq!(Some(0), output.status.code(), "{:?}", args);
+        assert_eq!(
+            expected,
+            String::from_utf8(output.stdout).expect("Not UTF-8")
+        );
+    }
+
+    assert_eq!(Some(1), run(&["--threads", "0"]).status.code());
+    assert_eq!(Some(1), run(&["--threads", "lots"]).status.code());
+    // asking for threads as well is a mistake, though
+    let output = run(&["--threads", "2", "--journal", journal_path]);
+    assert_eq!(Some(1), output.status.code());
+    assert!(String::from_utf8(output.stderr)
+        .expect("Not UTF-8")
+        .contains("--threads can't be used with"));
+}
+
+#[test]
+fn test_threads_with_transfers() {
+    let input = concat!(
+        "type,client,tx,amount,to_client\n",
+        "deposit,1,1,10,\n",
+        "deposit,2,2,20,\n",
+        "deposit,3,3,30,\n",
+        "transfer,1,4,10,2\n",
+        "transfer,2,5,25,3\n",
+        "withdrawal,1,6,1,\n",
+        "dispute,3,5,,\n",
+        "deposit,4,7,5,\n",
+    );
+    let tmp_file = tempfile::NamedTempFile::new().expect("Failed to create temp file");
+    fs::write(tmp_file.path(), input).expect("Failed to write to temp file");
+
+    let run = |threads: &str| {
+        Command::cargo_bin("challenge")
+            .expect("Expected to find binary")
+            .arg("--threads")
+            .arg(threads)
+            .arg("--errors")
+            .arg("stderr")
+            .arg(tmp_file.path())
+            .output()
+            .expect("Expected no errors")
+    };
+
+    // the transfers are between clients on different threads, which makes
+    // no difference to what comes out
+    let expected = run("1");
+    assert_eq!(Some(0), expected.status.code());
+    assert_eq!(
+        concat!(
+            "client,available,held,total,locked\n",
+            "1,0.0000,0.0000,0.0000,false\n",
+            "2,5.0000,0.0000,5.0000,false\n",
+            "3,30.0000,25.0000,55.0000,false\n",
+            "4,5.0000,0.0000,5.0000,false\n",
+        ),
+        String::from_utf8_lossy(&expected.stdout)
+    );
+    for threads in ["2", "4"] {
+        let output = run(threads);
+        assert_eq!(Some(0), output.status.code());
+        assert_eq!(expected.stdout, output.stdout);
+        assert_eq!(expected.stderr, output.stderr);
+    }
+}
+
+#[test]
+fn test_stdout_closed_early() {
+    // far more than a pipe holds, so the run's still writing when it's closed
+    let mut input = String::from("type,client,tx,amount\n");
+    for id in 1..=50_000 {
+        input.push_str(&format!("deposit,{},{},1\n", id, id));
+    }
+    let tmp_file = tempfile::NamedTempFile::new().expect("Failed to create temp file");
+    fs::write(tmp_file.path(), input).expect("Failed to write to temp file");
+
+    let mut child = std::process::Command::cargo_bin("challenge")
+        .expect("Expected to find binary")
+        .arg(tmp_file.path())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("Expected to start");
+    drop(child.stdout.take());
+    let output = child.wait_with_output().expect("Expected no errors");
+
+    assert_eq!(Some(0), output.status.code());
+    assert_eq!("", String::from_utf8_lossy(&output.stderr));
+}
+
+#[test]
+fn test_partitioned_output() {
+    let input = concat!(
+        "type,client,tx,amount\n",
+        "deposit,1,1,10\n",
+        "deposit,40000,2,20\n",
+        "deposit,2,3,30\n",
+    );
+    let tmp_file = tempfile::NamedTempFile::new().expect("Failed to create temp file");
+    fs::write(tmp_file.path(), input).expect("Failed to write to temp file");
+    let output_dir = tempfile::tempdir().expect("Failed to create temp dir");
+
+    let mut cmd = Command::cargo_bin("challenge").expect("Expected to find binary");
+    let output = cmd
+        .arg("--partitions")
+        .arg("2")
+        .arg("--partition-by")
+        .arg("range")
+        .arg("--output")
+        .arg(output_dir.path().join("report.csv"))
+        .arg(tmp_file.path())
+        .output()
+        .expect("Expected no errors");
+
+    assert_eq!(Some(0), output.status.code());
+    assert!(!output_dir.path().join("report.csv").exists());
+
+    let first = fs::read_to_string(output_dir.path().join("report-0.csv"))
+        .expect("Expected first partition");
+    assert_eq!(
+        concat!(
+            "client,available,held,total,locked\n",
+            "1,10.0000,0.0000,10.0000,false\n",
+            "2,30.0000,0.0000,30.0000,false\n",
+        ),
+        first
+    );
+    let second = fs::read_to_string(output_dir.path().join("report-1.csv"))
+        .expect("Expected second partition");
+    assert_eq!(
+        concat!(
+            "client,available,held,total,locked\n",
+            "40000,20.0000,0.0000,20.0000,false\n",
+        ),
+        second
+    );
+}
+
+#[test]
+fn test_memory_budget() {
+    // a budget of nothing still keeps one transaction in memory, so every
+    // dispute here is on a transaction that was spilled to disk
+    let input = concat!(
+        "type,client,tx,amount\n",
+        "deposit,1,1,10\n",
+        "deposit,1,2,20\n",
+        "deposit,2,3,5\n",
+        "dispute,1,1,\n",
+        "dispute,1,2,\n",
+        "chargeback,1,2,\n",
+        "resolve,1,1,\n",
+    );
+    let tmp_file = tempfile::NamedTempFile::new().expect("Failed to create temp file");
+    fs::write(tmp_file.path(), input).expect("Failed to write to temp file");
+    let output_dir = tempfile::tempdir().expect("Failed to create temp dir");
+    let dispute_report_path = output_dir.path().join("disputes.csv");
+
+    let mut cmd = Command::cargo_bin("challenge").expect("Expected to find binary");
+    let output = cmd
+        .arg("--memory-budget")
+        .arg("0")
+        .arg("--dispute-report")
+        .arg(&dispute_report_path)
+        .arg(tmp_file.path())
+        .output()
+        .expect("Expected no errors");
+
+    assert_eq!(Some(0), output.status.code());
+    assert_eq!(
+        concat!(
+            "client,available,held,total,locked\n",
+            "1,10.0000,0.0000,10.0000,true\n",
+            "2,5.0000,0.0000,5.0000,false\n"
+        ),
+        String::from_utf8(output.stdout).expect("Not UTF-8")
+    );
+
+    let dispute_report = fs::read_to_string(&dispute_report_path).expect("Expected report file");
+    assert_eq!(
+        concat!(
+            "tx,client,type,amount,currency,status,disputed,charged_back,disputed_at,resolved_at,charged_back_at\n",
+            "1,1,deposit,10.0000,,undisputed,0.0000,0.0000,4,7,\n",
+            "2,1,deposit,20.0000,,charged_back,0.0000,20.0000,5,,6\n"
+        ),
+        dispute_report
+    );
+}
+
+#[test]
+fn test_resume_from_saved_state() {
+    let output_dir = tempfile::tempdir().expect("Failed to create temp dir");
+    let state_path = output_dir.path().join("state.bin");
+    let yesterday_path = output_dir.path().join("yesterday.csv");
+    let today_path = output_dir.path().join("today.csv");
+    fs::write(
+        &yesterday_path,
+        concat!(
+            "type,client,tx,amount\n",
+            "deposit,1,1,10\n",
+            "deposit,2,2,5\n",
+        ),
+    )
+    .expect("Failed to write to temp file");
+    // today's events refer to yesterday's transactions
+    fs::write(
+        &today_path,
+        concat!(
+            "type,client,tx,amount\n",
+            "dispute,1,1,\n",
+            "deposit,2,2,5\n",
+            "withdrawal,2,3,2\n",
+        ),
+    )
+    .expect("Failed to write to temp file");
+
+    let output = Command::cargo_bin("challenge")
+        .expect("Expected to find binary")
+        .arg("--save-state")
+        .arg(&state_path)
+        .arg(&yesterday_path)
+        .output()
+        .expect("Expected no errors");
+    assert_eq!(Some(0), output.status.code());
+
+    // the state can be resumed from and saved back over in the same run
+    let output = Command::cargo_bin("challenge")
+        .expect("Expected to find binary")
+        .arg("--resume-from")
+        .arg(&state_path)
+        .arg("--save-state")
+        .arg(&state_path)
+        .arg("--errors")
+        .arg("stderr")
+        .arg(&today_path)
+        .output()
+        .expect("Expected no errors");
+    assert_eq!(Some(0), output.status.code());
+    assert_eq!(
+        concat!(
+            "client,available,held,total,locked\n",
+            "1,0.0000,10.0000,10.0000,false\n",
+            "2,3.0000,0.0000,3.0000,false\n"
+        ),
+        String::from_utf8(output.stdout).expect("Not UTF-8")
+    );
+    assert_eq!(
+        "line 3 (byte 35): Transaction already exists with id 2.\n",
+        String::from_utf8(output.stderr).expect("Not UTF-8")
+    );
+
+    let output = Command::cargo_bin("challenge")
+        .expect("Expected to find binary")
+        .arg("--resume-from")
+        .arg(&yesterday_path)
+        .arg(&today_path)
+        .output()
+        .expect("Expected no errors");
+    assert_eq!(Some(1), output.status.code());
+    assert!(String::from_utf8(output.stderr)
+        .expect("Not UTF-8")
+        .contains("Not a processor snapshot."));
+}
+
+#[test]
+fn test_journal_replay() {
+    let output_dir = tempfile::tempdir().expect("Failed to create temp dir");
+    let journal_path = output_dir.path().join("journal.bin");
+    let yesterday_path = output_dir.path().join("yesterday.csv");
+    let today_path = output_dir.path().join("today.csv");
+    let empty_path = output_dir.path().join("empty.csv");
+    fs::write(
+        &yesterday_path,
+        concat!(
+            "type,client,tx,amount\n",
+            "deposit,1,1,10\n",
+            "deposit,2,2,5\n",
+            "withdrawal,2,3,50\n",
+        ),
+    )
+    .expect("Failed to write to temp file");
+    fs::write(
+        &today_path,
+        concat!(
+            "type,client,tx,amount\n",
+            "dispute,1,1,\n",
+            "deposit,2,4,1\n",
+        ),
+    )
+    .expect("Failed to write to temp file");
+    fs::write(&empty_path, "type,client,tx,amount\n").expect("Failed to write to temp file");
+    let expected_output = concat!(
+        "client,available,held,total,locked\n",
+        "1,0.0000,10.0000,10.0000,false\n",
+        "2,6.0000,0.0000,6.0000,false\n"
+    );
+
+    // the second run carries on from the first, and appends to its journal
+    let output = Command::cargo_bin("challenge")
+        .expect("Expected to find binary")
+        .arg("--journal")
+        .arg(&journal_path)
+        .arg(&yesterday_path)
+        .output()
+        .expect("Expected no errors");
+    assert_eq!(Some(0), output.status.code());
+    let output = Command::cargo_bin("challenge")
+        .expect("Expected to find binary")
+        .arg("--replay-journal")
+        .arg(&journal_path)
+        .arg("--journal")
+        .arg(&journal_path)
+        .arg(&today_path)
+        .output()
+        .expect("Expected no errors");
+    assert_eq!(Some(0), output.status.code());
+    assert_eq!(
+        expected_output,
+        String::from_utf8(output.stdout).expect("Not UTF-8")
+    );
+
+    // and the journal alone is enough to get back to where that left off
+    let output = Command::cargo_bin("challenge")
+        .expect("Expected to find binary")
+        .arg("--replay-journal")
+        .arg(&journal_path)
+        .arg(&empty_path)
+        .output()
+        .expect("Expected no errors");
+    assert_eq!(Some(0), output.status.code());
+    assert_eq!(
+        expected_output,
+        String::from_utf8(output.stdout).expect("Not UTF-8")
+    );
+
+    // or to see how things stood before the second run, without an input
+    let output = Command::cargo_bin("challenge")
+        .expect("Expected to find binary")
+        .arg("--replay-journal")
+        .arg(&journal_path)
+        .arg("--as-of-event")
+        .arg("2")
+        .output()
+        .expect("Expected no errors");
+    assert_eq!(Some(0), output.status.code());
+    assert_eq!(
+        concat!(
+            "client,available,held,total,locked\n",
+            "1,10.0000,0.0000,10.0000,false\n",
+            "2,5.0000,0.0000,5.0000,false\n"
+        ),
+        String::from_utf8(output.stdout).expect("Not UTF-8")
+    );
+
+    let output = Command::cargo_bin("challenge")
+        .expect("Expected to find binary")
+        .arg("--replay-journal")
+        .arg(&yesterday_path)
+        .arg(&empty_path)
+        .output()
+        .expect("Expected no errors");
+    assert_eq!(Some(1), output.status.code());
+    assert!(String::from_utf8(output.stderr)
+        .expect("Not UTF-8")
+        .contains("Not an event journal."));
+}
+
+#[test]
+fn test_resource_limits() {
+    let input = concat!(
+        "type,client,tx,amount\n",
+        "deposit,1,1,10\n",
+        "withdrawal,1,2,20\n",
+        "deposit,2,3,10\n",
+        "deposit,3,4,10\n",
+    );
+    let tmp_file = tempfile::NamedTempFile::new().expect("Failed to create temp file");
+    fs::write(tmp_file.path(), input).expect("Failed to write to temp file");
+    let output_dir = tempfile::tempdir().expect("Failed to create temp dir");
+    let output_path = output_dir.path().join("output.csv");
+    let errors_path = output_dir.path().join("errors.txt");
+
+    let output = Command::cargo_bin("challenge")
+        .expect("Expected to find binary")
+        .arg("--max-clients")
+        .arg("2")
+        .arg("--output")
+        .arg(&output_path)
+        .arg("--errors")
+        .arg(&errors_path)
+        .arg(tmp_file.path())
+        .output()
+        .expect("Expected no errors");
+
+    assert_eq!(Some(1), output.status.code());
+    let output_str = String::from_utf8(output.stderr).expect("Not UTF-8");
+    assert!(
+        output_str.contains("Stopped at line 5 (byte 70) with more than the 2 clients allowed."),
+        "Expected limit message, got: {}",
+        output_str
+    );
+    // no report for a run that was cut short, but the rejections up to then
+    // are kept
+    assert!(!output_path.exists());
+    assert_eq!(
+        "line 3 (byte 37): Insufficient funds.\n",
+        fs::read_to_string(&errors_path).expect("Expected errors file")
+    );
+
+    let output = Command::cargo_bin("challenge")
+        .expect("Expected to find binary")
+        .arg("--max-errors")
+        .arg("0")
+        .arg(tmp_file.path())
+        .output()
+        .expect("Expected no errors");
+    assert_eq!(Some(1), output.status.code());
+    let output_str = String::from_utf8(output.stderr).expect("Not UTF-8");
+    assert!(
+        output_str
+            .contains("Stopped at line 3 (byte 37) with more than the 0 rejected events allowed."),
+        "Expected limit message, got: {}",
+        output_str
+    );
+}
+
+#[test]
+fn test_resource_limits_with_threads() {
+    let input = concat!(
+        "type,client,tx,amount\n",
+        "deposit,1,1,10\n",
+        "withdrawal,1,2,20\n",
+        "deposit,2,3,10\n",
+        "withdrawal,2,4,20\n",
+        "deposit,3,5,10\n",
+        "withdrawal,3,6,20\n",
+    );
+    let tmp_file = tempfile::NamedTempFile::new().expect("Failed to create temp file");
+    fs::write(tmp_file.path(), input).expect("Failed to write to temp file");
+
+    // every client is on a thread of their own, so it's only the totals that
+    // go over
+    for (args, expected) in [
+        (
+            ["--max-clients", "2", "--threads", "2"],
+            "more than the 2 clients allowed",
+        ),
+        (
+            ["--max-errors", "2", "--threads", "4"],
+            "more than the 2 rejected events allowed",
+        ),
+        (
+            ["--max-transactions", "2", "--threads", "4"],
+            "more than the 2 transactions allowed",
+        ),
+    ] {
+        let output = Command::cargo_bin("challenge")
+            .expect("Expected to find binary")
+            .args(args)
+            .arg("--errors")
+            .arg("none")
+            .arg(tmp_file.path())
+            .output()
+            .expect("Expected no errors");
+        assert_eq!(Some(1), output.status.code(), "{:?}", args);
+        let output_str = String::from_utf8(output.stderr).expect("Not UTF-8");
+        assert!(
+            output_str.contains(expected),
+            "Expected limit message, got: {}",
+            output_str
+        );
+    }
+}
+
+#[test]
+fn test_large_input() {
+    // enough to go through the pipeline in several chunks and batches, with
+    // records split across chunks
+    let mut input = String::from("type,client,tx,amount\n");
+    for transaction_id in 1..=20_000 {
+        let client_id = transaction_id % 7;
+        input.push_str(&format!("deposit,{},{},1.5\n", client_id, transaction_id));
+        input.push_str(&format!(
+            "withdrawal,{},{},2\n",
+            client_id,
+            transaction_id + 100_000
+        ));
+    }
+    let tmp_file = tempfile::NamedTempFile::new().expect("Failed to create temp file");
+    fs::write(tmp_file.path(), input).expect("Failed to write to temp file");
+    let output_dir = tempfile::tempdir().expect("Failed to create temp dir");
+    let errors_path = output_dir.path().join("errors.txt");
+
+    let mut cmd = Command::cargo_bin("challenge").expect("Expected to find binary");
+    let output = cmd
+        .arg("--errors")
+        .arg(&errors_path)
+        .arg(tmp_file.path())
+        .output()
+        .expect("Expected no errors");
+
+    assert_eq!(Some(0), output.status.code());
+    let output_str = String::from_utf8(output.stdout).expect("Not UTF-8");
+    assert_eq!(8, output_str.lines().count());
+    assert!(output_str.contains("\n3,1.5000,0.0000,1.5000,false\n"));
+
+    // a client's balance goes 1.5, 3, 1, 2.5, 0.5, 2, 0 and round again, so
+    // about one withdrawal in four fails
+    let errors = fs::read_to_string(&errors_path).expect("Expected errors file");
+    assert_eq!(5005, errors.lines().count());
+    assert_eq!(
+        "line 3 (byte 38): Insufficient funds.",
+        errors.lines().next().expect("Expected an error")
+    );
+}